@@ -0,0 +1,260 @@
+//! A tiny protocol on top of the wormhole core, as a template for building your own
+//!
+//! Run `cargo run --example ping` on one machine. It prints a code, which you pass to
+//! `cargo run --example ping -- <code>` on another one. The side that allocated the code
+//! then pings the other side a few times and measures the round-trip times.
+//!
+//! This shows everything an application needs, without any file transfer or transit:
+//!
+//! - An [`AppID`] of its own. Only clients with the same ID can connect to each other, so pick
+//!   one that is unique to your application, for example using a domain that you own.
+//! - Version negotiation. The `app_version` of the [`AppConfig`] is sent to the other side
+//!   during the handshake, and theirs is available as [`Wormhole::peer_version`]. Here it
+//!   contains a list of abilities, and both sides use the ones they have in common.
+//! - Exchanging messages. Every message is encrypted and sent in its own phase, the
+//!   `send_json` and `receive_json` helpers take care of the serialization.
+//! - Deriving keys. The wormhole key must not be used directly, but it is the source for
+//!   keys of any other purpose. Here, one is used to show a short session ID on both sides.
+
+use magic_wormhole::{rendezvous, AppConfig, AppID, Code, Key, KeyPurpose, Wormhole};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    time::{Duration, Instant, SystemTime},
+};
+
+const APPID: &str = "example.com/wormhole/ping";
+
+/** Every ping gets answered with a pong */
+const ABILITY_PING: &str = "ping-v1";
+/** Pings carry the time they were sent at, so that the other side can show the one-way delay */
+const ABILITY_TIMESTAMPS: &str = "timestamps";
+
+/** What we send in our `app_version` */
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct AppVersion {
+    /* Older peers might not send this at all, so it needs a default */
+    #[serde(default)]
+    abilities: Vec<String>,
+}
+
+impl AppVersion {
+    fn ours() -> Self {
+        Self {
+            abilities: vec![ABILITY_PING.into(), ABILITY_TIMESTAMPS.into()],
+        }
+    }
+}
+
+fn app_config() -> AppConfig<AppVersion> {
+    AppConfig {
+        id: AppID::new(APPID),
+        rendezvous_url: Cow::Borrowed(rendezvous::DEFAULT_RENDEZVOUS_SERVER),
+        app_version: AppVersion::ours(),
+        rendezvous_tap: None,
+        resolver: None,
+        proxy: None,
+        events: None,
+        wordlist: None,
+        keepalive: Some(rendezvous::DEFAULT_KEEPALIVE),
+    }
+}
+
+/**
+ * The abilities both sides have in common
+ *
+ * Unknown or malformed versions are treated like empty ones, so that future versions of the
+ * protocol may change them.
+ */
+fn negotiate(ours: &AppVersion, theirs: &serde_json::Value) -> Vec<String> {
+    let theirs: AppVersion = serde_json::from_value(theirs.clone()).unwrap_or_default();
+    ours.abilities
+        .iter()
+        .filter(|ability| theirs.abilities.contains(ability))
+        .cloned()
+        .collect()
+}
+
+/** The messages exchanged over the wormhole, one per phase */
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Message {
+    Ping {
+        sequence: u32,
+        /** Milliseconds since the UNIX epoch, if both sides support [`ABILITY_TIMESTAMPS`] */
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sent_at: Option<u128>,
+    },
+    Pong {
+        sequence: u32,
+    },
+    /** Sent by the pinging side once it is done */
+    Bye,
+}
+
+/** Keys derived from the wormhole key get a purpose of their own */
+#[derive(Debug)]
+struct SessionIdKey;
+impl KeyPurpose for SessionIdKey {}
+
+/**
+ * A short ID of the session, which is the same on both sides
+ *
+ * The purpose string is prefixed with the AppID, so that other protocols derive different keys.
+ */
+fn session_id(wormhole: &Wormhole) -> String {
+    let key: Key<SessionIdKey> = wormhole
+        .key()
+        .derive_subkey_from_purpose(&format!("{}/session-id", APPID));
+    key.to_hex()[..8].to_owned()
+}
+
+/**
+ * Run the protocol on an established wormhole
+ *
+ * The `leader` sends `pings` pings and returns their round-trip times. The other side answers
+ * them, and returns an empty list.
+ */
+async fn run(mut wormhole: Wormhole, leader: bool, pings: u32) -> eyre::Result<Vec<Duration>> {
+    let abilities = negotiate(&AppVersion::ours(), &wormhole.peer_version);
+    eyre::ensure!(
+        abilities.iter().any(|ability| ability == ABILITY_PING),
+        "The other side does not speak our protocol"
+    );
+    let timestamps = abilities
+        .iter()
+        .any(|ability| ability == ABILITY_TIMESTAMPS);
+
+    let mut rtts = Vec::new();
+    if leader {
+        for sequence in 0..pings {
+            let start = Instant::now();
+            let sent_at = if timestamps { Some(now()) } else { None };
+            wormhole
+                .send_json(&Message::Ping { sequence, sent_at })
+                .await?;
+            match wormhole.receive_json().await?? {
+                Message::Pong { sequence: pong } if pong == sequence => rtts.push(start.elapsed()),
+                other => eyre::bail!("Expected pong {}, but got {:?}", sequence, other),
+            }
+        }
+        wormhole.send_json(&Message::Bye).await?;
+    } else {
+        loop {
+            match wormhole.receive_json().await?? {
+                Message::Ping { sequence, sent_at } => {
+                    if let Some(sent_at) = sent_at {
+                        log::info!(
+                            "Ping {} took {} ms",
+                            sequence,
+                            now().saturating_sub(sent_at)
+                        );
+                    }
+                    wormhole.send_json(&Message::Pong { sequence }).await?;
+                },
+                Message::Bye => break,
+                other => eyre::bail!("Expected a ping, but got {:?}", other),
+            }
+        }
+    }
+
+    wormhole.close().await?;
+    Ok(rtts)
+}
+
+fn now() -> u128 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+#[async_std::main]
+async fn main() -> eyre::Result<()> {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .init();
+
+    let (wormhole, leader) = match std::env::args().nth(1) {
+        Some(code) => {
+            let code: Code = code.parse()?;
+            let (_welcome, wormhole) = Wormhole::connect_with_code(app_config(), code).await?;
+            (wormhole, false)
+        },
+        None => {
+            let (welcome, connector) = Wormhole::connect_without_code(app_config(), 2).await?;
+            println!("This wormhole's code is: {}", welcome.code);
+            println!(
+                "On the other side, run: cargo run --example ping -- {}",
+                welcome.code
+            );
+            (connector.await?, true)
+        },
+    };
+    println!("Connected, session ID: {}", session_id(&wormhole));
+
+    for (sequence, rtt) in run(wormhole, leader, 5).await?.iter().enumerate() {
+        println!("Ping {}: {:.1} ms", sequence, rtt.as_secs_f64() * 1000.0);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        let ours = AppVersion::ours();
+        let all = negotiate(
+            &ours,
+            &serde_json::json!({"abilities": ["timestamps", "ping-v1"]}),
+        );
+        assert_eq!(all, vec![ABILITY_PING, ABILITY_TIMESTAMPS]);
+        let some = negotiate(
+            &ours,
+            &serde_json::json!({"abilities": ["ping-v1", "ping-v2"]}),
+        );
+        assert_eq!(some, vec![ABILITY_PING]);
+        /* Peers that don't know about abilities, or send garbage */
+        assert!(negotiate(&ours, &serde_json::json!({})).is_empty());
+        assert!(negotiate(&ours, &serde_json::json!("garbage")).is_empty());
+    }
+
+    #[test]
+    fn test_messages() {
+        /* Optional fields are left out, so that older peers don't trip over them */
+        let ping = Message::Ping {
+            sequence: 1,
+            sent_at: None,
+        };
+        assert_eq!(
+            serde_json::to_value(&ping).unwrap(),
+            serde_json::json!({"ping": {"sequence": 1}})
+        );
+        let bye: Message = serde_json::from_str(r#""bye""#).unwrap();
+        assert_eq!(bye, Message::Bye);
+    }
+
+    /** Both sides of the protocol, over the public rendezvous server */
+    #[async_std::test]
+    #[ignore = "needs network access"]
+    async fn test_ping() -> eyre::Result<()> {
+        let (welcome, connector) = Wormhole::connect_without_code(app_config(), 2).await?;
+        let follower = async_std::task::spawn(async move {
+            let (_welcome, wormhole) =
+                Wormhole::connect_with_code(app_config(), welcome.code).await?;
+            let session_id = session_id(&wormhole);
+            run(wormhole, false, 3).await?;
+            eyre::Result::<_>::Ok(session_id)
+        });
+
+        let wormhole = connector.await?;
+        let session_id = session_id(&wormhole);
+        let rtts = run(wormhole, true, 3).await?;
+        assert_eq!(rtts.len(), 3);
+        assert_eq!(follower.await?, session_id);
+        Ok(())
+    }
+}
@@ -0,0 +1,59 @@
+/*
+ * C bindings of magic-wormhole.rs, see the `ffi` module for details.
+ *
+ * Build the library with `cargo rustc --release --lib --features ffi --crate-type cdylib`.
+ */
+#ifndef WORMHOLE_H
+#define WORMHOLE_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define WORMHOLE_OK 0
+/* A required pointer is NULL, a string is not UTF-8 or the code is malformed */
+#define WORMHOLE_ERROR_INVALID_ARGUMENT 1
+/* Connecting to the servers or to the other side failed */
+#define WORMHOLE_ERROR_CONNECTION 2
+/* The other side rejected or cancelled the transfer */
+#define WORMHOLE_ERROR_PEER 3
+/* Reading or writing the file failed */
+#define WORMHOLE_ERROR_IO 4
+/* The received file already exists, it was not overwritten */
+#define WORMHOLE_ERROR_EXISTS 5
+/* Anything else, like protocol errors, a corrupted transfer or a bug in this library */
+#define WORMHOLE_ERROR_TRANSFER 6
+
+/* Called with the number of bytes transferred so far, and the total */
+typedef void (*wormhole_progress_callback)(uint64_t done, uint64_t total, void *user_data);
+/* Called with the code to give to the other side */
+typedef void (*wormhole_code_callback)(const char *code, void *user_data);
+
+/*
+ * A description of the last error on this thread. The string stays valid until
+ * the next call of a wormhole_* function on the same thread.
+ */
+const char *wormhole_last_error(void);
+
+/*
+ * Send a file or folder. If code is NULL, a new code gets allocated and passed to
+ * code_callback. The callbacks may be NULL. Blocks until the transfer is done.
+ */
+int wormhole_send_file(const char *path, const char *code,
+                       wormhole_code_callback code_callback,
+                       wormhole_progress_callback progress_callback, void *user_data);
+
+/*
+ * Receive a file into target_dir, under the name chosen by the sender. Existing files
+ * are never overwritten. progress_callback may be NULL. Blocks until the transfer is done.
+ */
+int wormhole_request_file(const char *code, const char *target_dir,
+                          wormhole_progress_callback progress_callback, void *user_data);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* WORMHOLE_H */
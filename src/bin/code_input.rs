@@ -0,0 +1,73 @@
+use color_eyre::eyre;
+use console::{Key, Term};
+use magic_wormhole::Nameplate;
+
+/**
+ * Read a code from the terminal, with tab completion like the Python client
+ *
+ * The nameplate is completed from the ones currently in use on the server, and the password
+ * from the wordlist. Pressing tab twice lists all candidates.
+ */
+pub fn enter_code(term: &Term, nameplates: &[Nameplate]) -> eyre::Result<String> {
+    const PROMPT: &str = "Enter code: ";
+
+    let wordlist = magic_wormhole::default_wordlist(2);
+    let mut input = String::new();
+    term.write_str(PROMPT)?;
+    loop {
+        match term.read_key()? {
+            Key::Enter => {
+                term.write_line("")?;
+                return Ok(input);
+            },
+            Key::Backspace => {
+                if input.pop().is_some() {
+                    term.clear_chars(1)?;
+                }
+                continue;
+            },
+            Key::Char(c) if !c.is_control() => {
+                input.push(c);
+                term.write_str(&c.to_string())?;
+                continue;
+            },
+            Key::Tab => (),
+            _ => continue,
+        }
+
+        let completions = wordlist.complete_code(&input, nameplates);
+        let completed = common_prefix(&completions);
+        if completed.len() > input.len() {
+            term.write_str(&completed[input.len()..])?;
+            input = completed.to_owned();
+        } else if completions.len() > 1 {
+            /* Nothing more to complete, so show what's possible */
+            term.write_line("")?;
+            term.write_line(&completions.join("  "))?;
+            term.write_str(PROMPT)?;
+            term.write_str(&input)?;
+        }
+    }
+}
+
+/** The longest prefix all completions have in common */
+fn common_prefix(completions: &[String]) -> &str {
+    let first = match completions.first() {
+        Some(first) => first,
+        None => return "",
+    };
+    let length = completions[1..].iter().fold(first.len(), |length, other| {
+        first[..length]
+            .char_indices()
+            .zip(other.chars())
+            .find(|((_, a), b)| a != b)
+            .map(|((index, _), _)| index)
+            .unwrap_or_else(|| std::cmp::min(length, other.len()))
+    });
+    &first[..length]
+}
+
+#[allow(dead_code)]
+fn main() {
+    panic!("This ought to be a helper module, no idea why Rust thinks it's a crate");
+}
@@ -0,0 +1,128 @@
+use color_eyre::eyre;
+use serde_derive::Deserialize;
+use std::path::{Path, PathBuf};
+
+/**
+ * Defaults for the command line arguments, for users of self-hosted servers
+ *
+ * They are read from `$XDG_CONFIG_HOME/wormhole/config.toml` (`~/.config/wormhole/config.toml`),
+ * and each of them can be overridden with an environment variable, like `WORMHOLE_RENDEZVOUS_SERVER`
+ * for `rendezvous-server`. Command line arguments override both.
+ */
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    pub rendezvous_server: Option<String>,
+    /** Like `--relay-server`. In the environment variable, they are separated by spaces */
+    #[serde(default)]
+    pub relay_servers: Vec<String>,
+    #[serde(default)]
+    pub no_default_relay: bool,
+    pub out_dir: Option<PathBuf>,
+    pub listen_port: Option<String>,
+    #[serde(default)]
+    pub no_listen: bool,
+    #[serde(default)]
+    pub port_mapping: bool,
+    pub connect_timeout: Option<u64>,
+    pub proxy: Option<String>,
+    /** Record all transfers in the history, like `--history` */
+    #[serde(default)]
+    pub history: bool,
+}
+
+impl Config {
+    /**
+     * Read the configuration file, if there is one, and apply the environment variables
+     *
+     * A file that was given explicitly must exist.
+     */
+    pub fn load(path: Option<&std::ffi::OsStr>) -> eyre::Result<Self> {
+        let mut config = match path {
+            Some(path) => read(Path::new(path))?,
+            None => match default_path() {
+                Some(path) if path.exists() => read(&path)?,
+                _ => Config::default(),
+            },
+        };
+
+        if let Some(rendezvous_server) = env("RENDEZVOUS_SERVER") {
+            config.rendezvous_server = Some(rendezvous_server);
+        }
+        if let Some(relay_servers) = env("RELAY_SERVERS") {
+            config.relay_servers = relay_servers.split_whitespace().map(Into::into).collect();
+        }
+        if let Some(no_default_relay) = env_flag("NO_DEFAULT_RELAY")? {
+            config.no_default_relay = no_default_relay;
+        }
+        if let Some(out_dir) = env("OUT_DIR") {
+            config.out_dir = Some(out_dir.into());
+        }
+        if let Some(listen_port) = env("LISTEN_PORT") {
+            config.listen_port = Some(listen_port);
+        }
+        if let Some(no_listen) = env_flag("NO_LISTEN")? {
+            config.no_listen = no_listen;
+        }
+        if let Some(port_mapping) = env_flag("PORT_MAPPING")? {
+            config.port_mapping = port_mapping;
+        }
+        if let Some(connect_timeout) = env("CONNECT_TIMEOUT") {
+            config.connect_timeout = Some(connect_timeout.parse().map_err(|err| {
+                eyre::format_err!(
+                    "Invalid WORMHOLE_CONNECT_TIMEOUT '{}': {}",
+                    connect_timeout,
+                    err
+                )
+            })?);
+        }
+        if let Some(proxy) = env("PROXY") {
+            config.proxy = Some(proxy);
+        }
+        if let Some(history) = env_flag("HISTORY")? {
+            config.history = history;
+        }
+        Ok(config)
+    }
+}
+
+fn read(path: &Path) -> eyre::Result<Config> {
+    let content = std::fs::read_to_string(path).map_err(|err| {
+        eyre::format_err!("Could not read the config file {}: {}", path.display(), err)
+    })?;
+    toml::from_str(&content)
+        .map_err(|err| eyre::format_err!("Invalid config file {}: {}", path.display(), err))
+}
+
+fn default_path() -> Option<PathBuf> {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .map(|dir| dir.join("wormhole").join("config.toml"))
+}
+
+/** `WORMHOLE_{name}`, if it is set and not empty */
+fn env(name: &str) -> Option<String> {
+    std::env::var(format!("WORMHOLE_{}", name))
+        .ok()
+        .filter(|value| !value.is_empty())
+}
+
+fn env_flag(name: &str) -> eyre::Result<Option<bool>> {
+    match env(name).as_deref() {
+        None => Ok(None),
+        Some("1") | Some("true") | Some("yes") => Ok(Some(true)),
+        Some("0") | Some("false") | Some("no") => Ok(Some(false)),
+        Some(value) => eyre::bail!(
+            "Invalid WORMHOLE_{} '{}', expected true or false",
+            name,
+            value
+        ),
+    }
+}
+
+#[allow(dead_code)]
+fn main() {
+    panic!("This ought to be a helper module, no idea why Rust thinks it's a crate");
+}
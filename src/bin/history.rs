@@ -0,0 +1,156 @@
+use color_eyre::eyre;
+use magic_wormhole::{transfer, transit};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    io::{BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+/**
+ * A local journal of all transfers, for auditing what was sent where
+ *
+ * It is stored as JSON lines, one object per transfer, so that it can easily be processed
+ * with other tools as well. The journal never contains any codes.
+ */
+#[derive(Clone, Debug)]
+pub struct History {
+    /** `None` if disabled */
+    path: Option<PathBuf>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Direction {
+    Send,
+    Receive,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Entry {
+    /** RFC 3339 */
+    pub timestamp: String,
+    pub direction: Direction,
+    pub file_name: String,
+    /** Of the transferred content, or of the file on disk if the transfer failed */
+    pub size: Option<u64>,
+    /**
+     * The sum both sides agreed on, only if the transfer succeeded. For folders, this is the sum
+     * of the tar archive
+     */
+    pub sha256: Option<String>,
+    /** Whether the connection was direct or via a relay, only if the transfer succeeded */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub direct: Option<bool>,
+    /** `None` if the transfer succeeded */
+    pub error: Option<String>,
+}
+
+impl Entry {
+    /** Describe a finished transfer of the local file `path`, which is `None` for pipes */
+    pub async fn new(
+        direction: Direction,
+        path: Option<&Path>,
+        file_name: &std::ffi::OsStr,
+        result: &eyre::Result<transfer::TransferResult>,
+    ) -> Self {
+        let (size, sha256) = match result {
+            Ok(result) => (Some(result.size), Some(result.sha256.clone())),
+            /* Hashing the file now would not tell what got transferred, if anything */
+            Err(_) => match path {
+                Some(path) => match async_std::fs::metadata(path).await {
+                    Ok(metadata) if metadata.is_file() => (Some(metadata.len()), None),
+                    _ => (None, None),
+                },
+                None => (None, None),
+            },
+        };
+        Entry {
+            timestamp: chrono::Local::now().to_rfc3339(),
+            direction,
+            file_name: file_name.to_string_lossy().into_owned(),
+            size,
+            sha256,
+            direct: result
+                .as_ref()
+                .ok()
+                .map(|result| result.transit.conn_type == transit::ConnectionType::Direct),
+            error: result.as_ref().err().map(|error| error.to_string()),
+        }
+    }
+}
+
+impl History {
+    /**
+     * Use the given journal file, or the default location
+     * `$XDG_DATA_HOME/wormhole-rs/history.jsonl` if there is none.
+     */
+    pub fn new(path: Option<&std::ffi::OsStr>) -> Self {
+        let path = path.map(PathBuf::from).or_else(|| {
+            std::env::var_os("XDG_DATA_HOME")
+                .filter(|dir| !dir.is_empty())
+                .map(PathBuf::from)
+                .or_else(|| {
+                    std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/share"))
+                })
+                .map(|dir| dir.join("wormhole-rs").join("history.jsonl"))
+        });
+        if path.is_none() {
+            log::warn!("Could not determine where to store the transfer history, it is disabled");
+        }
+        History { path }
+    }
+
+    pub fn disabled() -> Self {
+        History { path: None }
+    }
+
+    /** Append an entry. Failing to do so is not fatal to the transfer, so only warn */
+    pub fn record(&self, entry: &Entry) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let result = (|| {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let mut file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?;
+            writeln!(file, "{}", serde_json::to_string(entry)?)?;
+            eyre::Result::<_>::Ok(())
+        })();
+        if let Err(error) = result {
+            log::warn!(
+                "Could not write the transfer history to {}: {}",
+                path.display(),
+                error
+            );
+        }
+    }
+
+    /** Read all entries, oldest first */
+    pub fn entries(&self) -> eyre::Result<Vec<Entry>> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => eyre::bail!("The transfer history is disabled"),
+        };
+        let file = match std::fs::File::open(path) {
+            Ok(file) => file,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(error) => return Err(error.into()),
+        };
+        std::io::BufReader::new(file)
+            .lines()
+            .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+            .map(|line| Ok(serde_json::from_str(&line?)?))
+            .collect()
+    }
+}
+
+#[allow(dead_code)]
+fn main() {
+    panic!("This ought to be a helper module, no idea why Rust thinks it's a crate");
+}
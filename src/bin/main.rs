@@ -1,3 +1,7 @@
+mod code_input;
+mod config;
+mod history;
+mod transit_test;
 mod util;
 
 use std::{
@@ -5,14 +9,16 @@ use std::{
     time::{Duration, Instant},
 };
 
-use async_std::{fs::OpenOptions, sync::Arc};
+use async_std::sync::Arc;
 use clap::{crate_description, crate_name, crate_version, App, AppSettings, Arg, SubCommand};
 use color_eyre::eyre;
 use console::{style, Term};
 use indicatif::{MultiProgress, ProgressBar};
 use std::io::Write;
 
-use magic_wormhole::{transfer, Wormhole};
+use config::Config;
+use history::History;
+use magic_wormhole::{transfer, transit, Wormhole};
 use std::str::FromStr;
 
 #[async_std::main]
@@ -23,11 +29,59 @@ async fn main() -> eyre::Result<()> {
 
     let relay_server_arg = Arg::with_name("relay-server")
         .long("relay-server")
-        .visible_alias("relay")
+        .visible_aliases(&["relay", "transit-helper"])
         .takes_value(true)
         .multiple(true)
+        .number_of_values(1)
         .value_name("tcp://HOSTNAME:PORT")
-        .help("Use a custom relay server (specify multiple times for multiple relays)");
+        .help("Use a custom relay server (specify multiple times for multiple relays). The tcp:HOSTNAME:PORT syntax of the Python client is accepted as well. Append :priority=NUMBER to prefer some relays over others, and :name=NAME to name the relay.");
+    let no_default_relay_arg = Arg::with_name("no-default-relay")
+        .long("no-default-relay")
+        .help("Don't fall back to the default relay server if no other relay is known");
+    let listen_port_arg = Arg::with_name("listen-port")
+        .long("listen-port")
+        .takes_value(true)
+        .value_name("PORT|FROM-TO")
+        .help("Listen for direct connections on that port (or the first free port of that range) instead of a random one. Useful for forwarding the port on a firewall.");
+    let no_listen_arg = Arg::with_name("no-listen")
+        .long("no-listen")
+        .conflicts_with("listen-port")
+        .help("Don't open any listening sockets, only connect outwards (directly or via a relay). For sandboxes and firewalls that complain about listening.");
+    let port_mapping_arg = Arg::with_name("port-mapping")
+        .long("port-mapping")
+        .conflicts_with("no-listen")
+        .help("Ask the router to forward a port to us (with UPnP or NAT-PMP), so that the other side can connect directly. Needs the port-mapping feature.");
+    let connect_timeout_arg = Arg::with_name("connect-timeout")
+        .long("connect-timeout")
+        .takes_value(true)
+        .value_name("SECONDS")
+        .help("How long to try connecting to the peer before giving up [default: 60]. Raise it on slow or high-latency networks.");
+    let force_direct_arg = Arg::with_name("force-direct")
+        .long("force-direct")
+        .conflicts_with("force-relay")
+        .help("Never use a relay server, so that your data never passes through a third party. Fails if no direct connection is possible.");
+    let force_relay_arg = Arg::with_name("force-relay")
+        .long("force-relay")
+        .help("Never connect directly to the peer, so that it does not learn your IP address.");
+    let proxy_arg = Arg::with_name("proxy")
+        .long("proxy")
+        .takes_value(true)
+        .value_name("HOSTNAME:PORT or URL")
+        .help("Connect to the rendezvous server and the relays through this SOCKS5 proxy, or an HTTP proxy given as http://HOSTNAME:PORT. Direct connections to the peer don't use it. Without it, the proxy in HTTPS_PROXY or HTTP_PROXY is used for the rendezvous server.");
+    let tor_arg = Arg::with_name("tor")
+        .long("tor")
+        .conflicts_with("force-direct")
+        .help("Connect through Tor, listening on 127.0.0.1:9050 unless --proxy is given. Only a relay will be used, so that neither the servers nor the peer learn your IP address.");
+    let rate_limit_arg = Arg::with_name("rate-limit")
+        .long("rate-limit")
+        .takes_value(true)
+        .value_name("KiB/s")
+        .help("Don't transfer faster than that, to leave some bandwidth for others");
+    let parallel_connections_arg = Arg::with_name("parallel-connections")
+        .long("parallel-connections")
+        .takes_value(true)
+        .value_name("N")
+        .help("Use up to N connections through the relay, if the other side supports it too. This is faster on links with a high latency");
     let rendezvous_server_arg = Arg::with_name("rendezvous-server")
         .long("rendezvous-server")
         .takes_value(true)
@@ -36,14 +90,66 @@ async fn main() -> eyre::Result<()> {
     let log_arg = Arg::with_name("log")
         .long("log")
         .help("Enable logging to stdout, for debugging purposes");
+    let history_file_arg = Arg::with_name("history-file")
+        .long("history-file")
+        .takes_value(true)
+        .value_name("PATH")
+        .help("Where to keep the transfer history, if it is enabled. Defaults to $XDG_DATA_HOME/wormhole-rs/history.jsonl");
+    let config_arg = Arg::with_name("config")
+        .long("config")
+        .takes_value(true)
+        .value_name("PATH")
+        .help("Read defaults for the server, relay and transit options from this file. Defaults to $XDG_CONFIG_HOME/wormhole/config.toml. They can be overridden with WORMHOLE_* environment variables too, like WORMHOLE_RENDEZVOUS_SERVER.");
+    let history_arg = Arg::with_name("history")
+        .long("history")
+        .help("Record this transfer in the transfer history, see `wormhole history`");
+    let no_qr_arg = Arg::with_name("no-qr")
+        .long("no-qr")
+        .help("Don't show the code as QR code, which the mobile apps can scan");
     let code_length_arg = Arg::with_name("code-length")
         .short("c")
         .long("code-length")
         .takes_value(true)
         .value_name("NUMWORDS")
         .default_value("2")
-        .help("Length of code (in bytes/words)");
+        .validator(|length| match length.parse::<usize>() {
+            Ok(length) if length > 0 => Ok(()),
+            _ => Err("must be a positive number of words".into()),
+        })
+        .help("Length of code (in bytes/words). More words make it harder to guess");
+    let code_from_env_arg = Arg::with_name("code-from-env")
+        .long("code-from-env")
+        .takes_value(true)
+        .value_name("VARIABLE")
+        .conflicts_with_all(&["code", "code-file"])
+        .help("Read the code from that environment variable. Unlike passing it as argument, this doesn't leak it into process listings and the shell history.");
+    let code_file_arg = Arg::with_name("code-file")
+        .long("code-file")
+        .takes_value(true)
+        .value_name("PATH")
+        .conflicts_with("code")
+        .help("Read the code from the first line of that file, or from stdin if PATH is '-'");
+    let verify_arg = Arg::with_name("verify")
+        .long("verify")
+        .help("Show the verifier and ask whether it is the same as on the other side, before anything gets transferred. Compare them by other means, like over the phone, to make sure nobody is in the middle.");
     /* Use in send commands */
+    let offer_timeout_arg = Arg::with_name("offer-timeout")
+        .long("offer-timeout")
+        .takes_value(true)
+        .value_name("MINUTES")
+        .help("Give up if the receiver does not accept or reject the file within that time. Waits forever by default.");
+    let compress_arg = Arg::with_name("compress")
+        .long("compress")
+        .takes_value(true)
+        .possible_values(&["auto", "always", "never"])
+        .default_value("auto")
+        .help("Whether to compress the file, if the receiver supports it. 'auto' skips content that is already compressed (archives, images, videos …)");
+    let special_files_arg = Arg::with_name("special-files")
+        .long("special-files")
+        .takes_value(true)
+        .possible_values(&["follow", "skip", "preserve", "error"])
+        .default_value("preserve")
+        .help("What to do with symlinks in folders: send what they point to, skip them, send them as links, or fail. Other special files (FIFOs, device nodes …) are always skipped, unless this is 'error'");
     let file_name = Arg::with_name("file-name")
         .long("rename")
         .visible_alias("name")
@@ -69,8 +175,9 @@ async fn main() -> eyre::Result<()> {
 
     let send_command = SubCommand::with_name("send")
         .visible_alias("tx")
-        .about("Send a file or a folder")
+        .about("Send a file, a folder or a text message")
         .arg(code_length_arg.clone())
+        .arg(no_qr_arg.clone())
         .arg(
             Arg::with_name("code")
                 .long("code")
@@ -78,15 +185,41 @@ async fn main() -> eyre::Result<()> {
                 .value_name("CODE")
                 .help("Enter a code instead of generating one automatically"),
         )
+        .arg(code_from_env_arg.clone())
+        .arg(code_file_arg.clone())
         .arg(relay_server_arg.clone())
+        .arg(no_default_relay_arg.clone())
+        .arg(listen_port_arg.clone())
+        .arg(no_listen_arg.clone())
+        .arg(port_mapping_arg.clone())
+        .arg(connect_timeout_arg.clone())
+        .arg(force_direct_arg.clone())
+        .arg(force_relay_arg.clone())
+        .arg(proxy_arg.clone())
+        .arg(tor_arg.clone())
         .arg(rendezvous_server_arg.clone())
+        .arg(offer_timeout_arg.clone())
+        .arg(compress_arg.clone())
+        .arg(special_files_arg.clone())
+        .arg(rate_limit_arg.clone())
+        .arg(parallel_connections_arg.clone())
+        .arg(verify_arg.clone())
         .arg(file_name.clone())
+        .arg(
+            Arg::with_name("text")
+                .long("text")
+                .takes_value(true)
+                .value_name("TEXT")
+                .conflicts_with_all(&["file", "file-name"])
+                .help("Send a text message instead of a file"),
+        )
         .arg(
             Arg::with_name("file")
                 .index(1)
-                .required(true)
+                .required_unless("text")
+                .multiple(true)
                 .value_name("FILENAME|DIRNAME")
-                .help("The file or directory to send"),
+                .help("The files or directories to send. The receiver can accept or reject each of them. Use - to send what comes from the standard input, which needs --name"),
         );
     let send_many_command = SubCommand::with_name("send-many")
         .about("Send a file to many recipients. READ HELP PAGE FIRST!")
@@ -99,7 +232,8 @@ async fn main() -> eyre::Result<()> {
             number of sent files, whichever comes first. It will always try to send
             at least one file, regardless of the limits.",
         )
-        .arg(code_length_arg.default_value("4"))
+        .arg(code_length_arg.clone().default_value("4"))
+        .arg(no_qr_arg)
         .arg(
             Arg::with_name("code")
                 .long("code")
@@ -107,8 +241,25 @@ async fn main() -> eyre::Result<()> {
                 .value_name("CODE")
                 .help("Enter a code instead of generating one automatically"),
         )
+        .arg(code_from_env_arg.clone())
+        .arg(code_file_arg.clone())
         .arg(relay_server_arg.clone())
+        .arg(no_default_relay_arg.clone())
+        .arg(listen_port_arg.clone())
+        .arg(no_listen_arg.clone())
+        .arg(port_mapping_arg.clone())
+        .arg(connect_timeout_arg.clone())
+        .arg(force_direct_arg.clone())
+        .arg(force_relay_arg.clone())
+        .arg(proxy_arg.clone())
+        .arg(tor_arg.clone())
         .arg(rendezvous_server_arg.clone())
+        .arg(offer_timeout_arg)
+        .arg(compress_arg)
+        .arg(special_files_arg)
+        .arg(rate_limit_arg.clone())
+        .arg(parallel_connections_arg.clone())
+        .arg(verify_arg.clone())
         .arg(file_name)
         .arg(
             Arg::with_name("file")
@@ -135,6 +286,260 @@ async fn main() -> eyre::Result<()> {
                 .default_value("60")
                 .help("Automatically stop providing the file after a certain amount of time."),
         );
+    let transit_test_command = SubCommand::with_name("transit-test")
+        .about("Measure the connection to another machine, without sending a file")
+        .after_help(
+            "Run this on both machines, once without a code and once with the code it prints. \
+            Both sides then show how they are connected (directly or via a relay), the \
+            round-trip time and the throughput in each direction.",
+        )
+        .arg(
+            Arg::with_name("code")
+                .index(1)
+                .value_name("CODE")
+                .help("The code printed by the other side. If not given, a new code is allocated"),
+        )
+        .arg(code_length_arg.clone())
+        .arg(
+            Arg::with_name("size")
+                .long("size")
+                .takes_value(true)
+                .value_name("MiB")
+                .default_value("16")
+                .help("How much data to send in each direction. Only the value of the side without a code is used"),
+        )
+        .arg(
+            Arg::with_name("pings")
+                .long("pings")
+                .takes_value(true)
+                .value_name("N")
+                .default_value("10")
+                .help("How often to measure the round-trip time. Only the value of the side without a code is used"),
+        )
+        .arg(relay_server_arg.clone())
+        .arg(no_default_relay_arg.clone())
+        .arg(listen_port_arg.clone())
+        .arg(no_listen_arg.clone())
+        .arg(port_mapping_arg.clone())
+        .arg(connect_timeout_arg.clone())
+        .arg(force_direct_arg.clone())
+        .arg(force_relay_arg.clone())
+        .arg(proxy_arg.clone())
+        .arg(tor_arg.clone())
+        .arg(rendezvous_server_arg.clone());
+    let bench_command = SubCommand::with_name("bench")
+        .about("Measure how fast data can be encrypted and transferred, for different record sizes")
+        .after_help(
+            "By default, both ends of the transfer run on this machine, which shows how fast \
+            it can encrypt and decrypt. With --remote, run this on two machines instead, once \
+            without a code and once with the code it prints. The side without a code sends.",
+        )
+        .arg(
+            Arg::with_name("code")
+                .index(1)
+                .value_name("CODE")
+                .help("The code printed by the other side, implies --remote"),
+        )
+        .arg(
+            Arg::with_name("remote")
+                .long("remote")
+                .help("Measure the transfer to another machine instead of this one"),
+        )
+        .arg(code_length_arg.clone())
+        .arg(
+            Arg::with_name("size")
+                .long("size")
+                .takes_value(true)
+                .value_name("MiB")
+                .default_value("64")
+                .help("How much data to send for each record size. Only the value of the side without a code is used"),
+        )
+        .arg(
+            Arg::with_name("record-sizes")
+                .long("record-sizes")
+                .takes_value(true)
+                .value_name("BYTES,…")
+                .default_value("1024,4096,16384,65536")
+                .help("The record sizes to measure, separated by commas. Only the value of the side without a code is used"),
+        )
+        .arg(relay_server_arg.clone())
+        .arg(no_default_relay_arg.clone())
+        .arg(listen_port_arg.clone())
+        .arg(no_listen_arg.clone())
+        .arg(port_mapping_arg.clone())
+        .arg(connect_timeout_arg.clone())
+        .arg(force_direct_arg.clone())
+        .arg(force_relay_arg.clone())
+        .arg(proxy_arg.clone())
+        .arg(tor_arg.clone())
+        .arg(rendezvous_server_arg.clone());
+    let forward_command = SubCommand::with_name("forward")
+        .about("Forward ports from one machine to another")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .after_help(
+            "Run `forward serve` on the machine with the services, and `forward connect` \
+            with the code it prints on the other one. Every service then gets a local port \
+            there, and connections to it are forwarded until one side stops.",
+        )
+        .subcommand(
+            SubCommand::with_name("serve")
+                .about("Make some addresses of this machine available to the other side")
+                .arg(
+                    Arg::with_name("targets")
+                        .index(1)
+                        .multiple(true)
+                        .required_unless("socks")
+                        .value_name("[NAME=]ADDRESS")
+                        .help("The addresses to forward to, like localhost:22, udp:localhost:53 or unix:/run/app.sock. Prefix one with a name like ssh=localhost:22 so that the other side can refer to it by that name"),
+                )
+                .arg(
+                    Arg::with_name("socks")
+                        .long("socks")
+                        .help("Allow the other side to connect to any address through a SOCKS5 proxy. Only use this if you trust them with access to your network!"),
+                )
+                .arg(code_length_arg.clone())
+                .arg(
+                    Arg::with_name("code")
+                        .long("code")
+                        .takes_value(true)
+                        .value_name("CODE")
+                        .help("Enter a code instead of generating one automatically"),
+                )
+                .arg(code_from_env_arg.clone())
+                .arg(code_file_arg.clone())
+                .arg(relay_server_arg.clone())
+                .arg(no_default_relay_arg.clone())
+                .arg(listen_port_arg.clone())
+                .arg(no_listen_arg.clone())
+                .arg(port_mapping_arg.clone())
+                .arg(connect_timeout_arg.clone())
+                .arg(force_direct_arg.clone())
+                .arg(force_relay_arg.clone())
+                .arg(proxy_arg.clone())
+                .arg(tor_arg.clone())
+                .arg(rendezvous_server_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("connect")
+                .about("Get local ports for the addresses the other side serves")
+                .arg(
+                    Arg::with_name("port")
+                        .long("port")
+                        .short("p")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("SERVICE=PORT")
+                        .help("Bind the service with that name or address to that local port. The others get a free port"),
+                )
+                .arg(
+                    Arg::with_name("socks-port")
+                        .long("socks-port")
+                        .takes_value(true)
+                        .value_name("PORT")
+                        .help("Bind the SOCKS5 proxy to that local port, if the other side offers one"),
+                )
+                .arg(
+                    Arg::with_name("bind")
+                        .long("bind")
+                        .takes_value(true)
+                        .value_name("ADDRESS")
+                        .default_value("127.0.0.1")
+                        .help("The local address to bind the ports to. The SOCKS5 proxy is only bound to loopback addresses"),
+                )
+                .arg(
+                    Arg::with_name("noconfirm")
+                        .long("noconfirm")
+                        .visible_alias("yes")
+                        .help("Start forwarding without asking for confirmation"),
+                )
+                .arg(
+                    Arg::with_name("code")
+                        .index(1)
+                        .value_name("CODE")
+                        .help("Provide the code now rather than typing it interactively"),
+                )
+                .arg(code_from_env_arg.clone())
+                .arg(code_file_arg.clone())
+                .arg(relay_server_arg.clone())
+                .arg(no_default_relay_arg.clone())
+                .arg(listen_port_arg.clone())
+                .arg(no_listen_arg.clone())
+                .arg(port_mapping_arg.clone())
+                .arg(connect_timeout_arg.clone())
+                .arg(force_direct_arg.clone())
+                .arg(force_relay_arg.clone())
+                .arg(proxy_arg.clone())
+                .arg(tor_arg.clone())
+                .arg(rendezvous_server_arg.clone()),
+        );
+    let ssh_command = SubCommand::with_name("ssh")
+        .about("Add someone's SSH public key to your authorized_keys, like `wormhole ssh` of the Python version")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .after_help(
+            "Run `ssh invite` on the machine that should grant access, and `ssh accept` \
+            with the code it prints on the machine with the key. The key then gets appended \
+            to ~/.ssh/authorized_keys of the inviting side.",
+        )
+        .subcommand(
+            SubCommand::with_name("invite")
+                .about("Wait for the other side's public key and add it to your authorized_keys")
+                .arg(
+                    Arg::with_name("authorized-keys")
+                        .long("authorized-keys")
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .conflicts_with("print")
+                        .help("Append the key to this file. Defaults to ~/.ssh/authorized_keys"),
+                )
+                .arg(
+                    Arg::with_name("print")
+                        .long("print")
+                        .help("Only print the received key, instead of adding it anywhere"),
+                )
+                .arg(code_length_arg.clone())
+                .arg(
+                    Arg::with_name("code")
+                        .long("code")
+                        .takes_value(true)
+                        .value_name("CODE")
+                        .help("Enter a code instead of generating one automatically"),
+                )
+                .arg(code_from_env_arg.clone())
+                .arg(code_file_arg.clone())
+                .arg(proxy_arg.clone())
+                .arg(tor_arg.clone())
+                .arg(rendezvous_server_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("accept")
+                .about("Send your public key to the side that invited you")
+                .arg(
+                    Arg::with_name("key-file")
+                        .long("key-file")
+                        .short("F")
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .help("The public key to send. Defaults to the one in ~/.ssh/id_*.pub, if there is only one"),
+                )
+                .arg(
+                    Arg::with_name("noconfirm")
+                        .long("noconfirm")
+                        .visible_alias("yes")
+                        .help("Send the key without asking for confirmation"),
+                )
+                .arg(
+                    Arg::with_name("code")
+                        .index(1)
+                        .value_name("CODE")
+                        .help("Provide the code now rather than typing it interactively"),
+                )
+                .arg(code_from_env_arg.clone())
+                .arg(code_file_arg.clone())
+                .arg(proxy_arg.clone())
+                .arg(tor_arg.clone())
+                .arg(rendezvous_server_arg.clone()),
+        );
     let receive_command = SubCommand::with_name("receive")
         .visible_alias("rx")
         .about("Receive a file or a folder")
@@ -144,16 +549,111 @@ async fn main() -> eyre::Result<()> {
                 .visible_alias("yes")
                 .help("Accept file transfer without asking for confirmation"),
         )
+        .arg(
+            Arg::with_name("confirm-connection")
+                .long("confirm-connection")
+                .help("Once connected, show whether the connection is direct or relayed and to which address, and ask again before receiving"),
+        )
+        .arg(
+            Arg::with_name("checksum-file")
+                .long("checksum-file")
+                .help("Write the SHA-256 sum of the received file next to it (as FILE.sha256), to be checked later with `sha256sum -c`"),
+        )
+        .arg(
+            Arg::with_name("sync")
+                .long("sync")
+                .help("Only report success once the received file (and its directory entry) have been written to disk"),
+        )
+        .arg(
+            Arg::with_name("preserve-metadata")
+                .long("preserve-metadata")
+                .help("Give the received file the modification time and executable bit it had on the sender's side"),
+        )
+        .arg(
+            Arg::with_name("expect-sha256")
+                .long("expect-sha256")
+                .takes_value(true)
+                .value_name("HEX")
+                .validator(|hash| {
+                    if hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                        Ok(())
+                    } else {
+                        Err("must be 64 hexadecimal digits".into())
+                    }
+                })
+                .help("Fail if the received file does not have this SHA-256 sum, which you got from the sender by other means. The file is deleted then, unless --keep-mismatched is given"),
+        )
+        .arg(
+            Arg::with_name("keep-mismatched")
+                .long("keep-mismatched")
+                .requires("expect-sha256")
+                .help("Keep the received file even if it does not match --expect-sha256"),
+        )
+        .arg(
+            Arg::with_name("keep-partial")
+                .long("keep-partial")
+                .help("Keep the data received so far if the other side goes away mid-transfer, and continue from there when receiving the same file again"),
+        )
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .takes_value(true)
+                .value_name("-")
+                .possible_values(&["-"])
+                .conflicts_with_all(&["file-name", "confirm-connection", "checksum-file", "sync", "preserve-metadata", "expect-sha256", "keep-partial"])
+                .help("Write the received file to the standard output instead of a file (folders come as tar archive). All other output goes to the standard error then"),
+        )
+        .arg(rate_limit_arg)
+        .arg(parallel_connections_arg)
+        .arg(verify_arg)
         .arg(file_rename)
         .arg(file_path)
         .arg(
             Arg::with_name("code")
                 .index(1)
+                .multiple(true)
                 .value_name("CODE")
-                .help("Provide the code now rather than typing it interactively"),
+                .help("Provide the code now rather than typing it interactively, either as is or as wormhole-transfer: URI. If several codes are given, the files are received from all senders at once, without asking (existing files are never overwritten)"),
         )
+        .arg(code_from_env_arg)
+        .arg(code_file_arg)
         .arg(relay_server_arg)
+        .arg(no_default_relay_arg)
+        .arg(listen_port_arg)
+        .arg(no_listen_arg)
+        .arg(port_mapping_arg)
+        .arg(connect_timeout_arg)
+        .arg(force_direct_arg)
+        .arg(force_relay_arg)
+        .arg(proxy_arg)
+        .arg(tor_arg)
         .arg(rendezvous_server_arg);
+    let history_command = SubCommand::with_name("history")
+        .about("Show past transfers")
+        .after_help(
+            "With --history (or `history = true` in the config file), completed and \
+            failed transfers are recorded locally, together with the size and the \
+            SHA256 sum of the files. Codes are never recorded.",
+        )
+        .arg(
+            Arg::with_name("limit")
+                .long("limit")
+                .short("n")
+                .takes_value(true)
+                .value_name("N")
+                .default_value("20")
+                .help("Only show the last N transfers"),
+        )
+        .arg(
+            Arg::with_name("failed")
+                .long("failed")
+                .help("Only show failed transfers"),
+        )
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .help("Print the raw JSON entries, one per line"),
+        );
 
     /* The Clap application */
     let clap = App::new(crate_name!())
@@ -173,11 +673,23 @@ async fn main() -> eyre::Result<()> {
         .subcommand(send_command)
         .subcommand(send_many_command)
         .subcommand(receive_command)
+        .subcommand(history_command)
+        .subcommand(transit_test_command)
+        .subcommand(bench_command)
+        .subcommand(forward_command)
+        .subcommand(ssh_command)
         .subcommand(SubCommand::with_name("help").setting(AppSettings::Hidden))
-        .arg(log_arg);
+        .arg(log_arg)
+        .arg(config_arg)
+        .arg(history_file_arg)
+        .arg(history_arg);
     let matches = clap.get_matches();
 
-    let mut term = Term::stdout();
+    /* Keep the standard output clean if the received file goes there */
+    let mut term = match matches.subcommand_matches("receive") {
+        Some(matches) if matches.is_present("output") => Term::stderr(),
+        _ => Term::stdout(),
+    };
 
     if matches.is_present("log") {
         env_logger::builder()
@@ -189,6 +701,16 @@ async fn main() -> eyre::Result<()> {
         log::debug!("Logging enabled.");
     }
 
+    let config = Config::load(matches.value_of_os("config"))?;
+
+    /* Only keep a record if asked to, a log of what went where is sensitive */
+    let history_file = matches.value_of_os("history-file");
+    let history = if matches.is_present("history") || config.history {
+        History::new(history_file)
+    } else {
+        History::disabled()
+    };
+
     let file_name = |file_path| {
         // TODO this has gotten out of hand (it ugly)
         // The correct solution would be to make `file_name` an Option everywhere and
@@ -214,53 +736,199 @@ async fn main() -> eyre::Result<()> {
             })
     };
 
+    let send_options = |matches: &clap::ArgMatches<'_>| -> eyre::Result<_> {
+        let mut options = transfer::SendOptions::default()
+            .transit_config(parse_transit_config(matches, &config)?);
+        if let Some(minutes) = matches.value_of("offer-timeout") {
+            options = options.offer_timeout(Duration::from_secs(u64::from_str(minutes)? * 60));
+        }
+        if matches.is_present("verify") {
+            options = options.verifier_check(ask_verifier());
+        }
+        options = options.compression(match matches.value_of("compress") {
+            Some("always") => transfer::Compression::Always,
+            Some("never") => transfer::Compression::Never,
+            _ => transfer::Compression::Auto,
+        });
+        options = options.folder(transfer::FolderSendOptions::default().special_files(
+            match matches.value_of("special-files") {
+                Some("follow") => transfer::SpecialFiles::Follow,
+                Some("skip") => transfer::SpecialFiles::Skip,
+                Some("error") => transfer::SpecialFiles::Error,
+                _ => transfer::SpecialFiles::PreserveAsLink,
+            },
+        ));
+        Ok(options)
+    };
+
     /* Handling of the argument matches (one branch per subcommand) */
 
-    if let Some(matches) = matches.subcommand_matches("send") {
-        let file_path = matches.value_of_os("file").unwrap();
-        let file_name = file_name(file_path)?;
+    if let Some((matches, text)) = matches
+        .subcommand_matches("send")
+        .and_then(|matches| Some((matches, matches.value_of("text")?)))
+    {
+        let (wormhole, _code, _relay_hints) =
+            parse_and_connect(&mut term, matches, &config, transfer::APP_CONFIG, true).await?;
+        transfer::send_text(wormhole, text).await?;
+        writeln!(&mut term, "Text message sent")?;
+    } else if let Some(matches) = matches.subcommand_matches("send") {
+        let file_paths = matches.values_of_os("file").unwrap().collect::<Vec<_>>();
+        let options = send_options(matches)?;
 
-        eyre::ensure!(
-            std::path::Path::new(file_path).exists(),
-            "{:?} does not exist",
-            file_path
-        );
+        for file_path in &file_paths {
+            eyre::ensure!(
+                *file_path == "-" || std::path::Path::new(file_path).exists(),
+                "{:?} does not exist",
+                file_path
+            );
+        }
+        if file_paths.contains(&std::ffi::OsStr::new("-")) {
+            eyre::ensure!(
+                file_paths.len() == 1,
+                "The standard input can't be sent together with other files"
+            );
+            eyre::ensure!(
+                matches.is_present("file-name"),
+                "Sending from the standard input needs a file name, please use --name"
+            );
+            eyre::ensure!(
+                !matches.is_present("verify"),
+                "--verify can't be used when sending from the standard input"
+            );
+        }
+
+        if file_paths.len() > 1 {
+            eyre::ensure!(
+                !matches.is_present("file-name"),
+                "--rename can't be used when sending several files"
+            );
+            let files = file_paths
+                .iter()
+                .map(|&file_path| Ok((file_path, file_name(file_path)?)))
+                .collect::<eyre::Result<Vec<_>>>()?;
 
-        let (wormhole, _code, relay_server) = parse_and_connect(&mut term, matches, true).await?;
+            let (wormhole, _code, relay_hints) =
+                parse_and_connect(&mut term, matches, &config, transfer::APP_CONFIG, true).await?;
+            return send_several(wormhole, relay_hints, files, options, &history).await;
+        }
 
-        send(wormhole, relay_server, file_path, &file_name).await?;
+        let file_path = file_paths[0];
+        let file_name = file_name(file_path)?;
+        let (wormhole, _code, relay_hints) =
+            parse_and_connect(&mut term, matches, &config, transfer::APP_CONFIG, true).await?;
+
+        let result = send(wormhole, relay_hints, file_path, &file_name, options).await;
+        history.record(
+            &history::Entry::new(
+                history::Direction::Send,
+                Some(file_path.as_ref()).filter(|_| file_path != "-"),
+                &file_name,
+                &result,
+            )
+            .await,
+        );
+        result?;
     } else if let Some(matches) = matches.subcommand_matches("send-many") {
-        let (wormhole, code, relay_server) = parse_and_connect(&mut term, matches, true).await?;
+        let (wormhole, code, relay_hints) =
+            parse_and_connect(&mut term, matches, &config, transfer::APP_CONFIG, true).await?;
         let timeout =
             Duration::from_secs(u64::from_str(matches.value_of("timeout").unwrap())? * 60);
         let max_tries = u64::from_str(matches.value_of("tries").unwrap())?;
+        let options = send_options(matches)?;
 
         let file_path = matches.value_of_os("file").unwrap();
         let file_name = file_name(file_path)?;
 
         send_many(
-            relay_server,
+            relay_hints,
             &code,
             file_path,
             &file_name,
+            options,
             max_tries,
             timeout,
             wormhole,
             &mut term,
+            history,
         )
         .await?;
     } else if let Some(matches) = matches.subcommand_matches("receive") {
-        let file_path = matches.value_of_os("file-path").unwrap();
+        /* The argument has a default value, so only an explicit one overrides the config */
+        let file_path = match &config.out_dir {
+            Some(out_dir) if matches.occurrences_of("file-path") == 0 => out_dir.as_os_str(),
+            _ => matches.value_of_os("file-path").unwrap(),
+        };
+        let options = transfer::ReceiveOptions::default()
+            .transit_config(parse_transit_config(matches, &config)?)
+            .checksum_file(matches.is_present("checksum-file"))
+            .preserve_metadata(matches.is_present("preserve-metadata"))
+            .keep_mismatched(matches.is_present("keep-mismatched"))
+            .partial_files(if matches.is_present("keep-partial") {
+                transfer::PartialFiles::KeepOnDisconnect
+            } else {
+                transfer::PartialFiles::Delete
+            })
+            .resume(matches.is_present("keep-partial"))
+            .durability(if matches.is_present("sync") {
+                transfer::Durability::FileAndDirectory
+            } else {
+                transfer::Durability::None
+            });
+        let options = match matches.value_of("expect-sha256") {
+            Some(sha256) => options.expected_sha256(sha256),
+            None => options,
+        };
+        let options = match matches.is_present("verify") {
+            true => options.verifier_check(ask_verifier()),
+            false => options,
+        };
+
+        let codes = matches.values_of("code").into_iter().flatten();
+        if codes.clone().count() > 1 {
+            eyre::ensure!(
+                !matches.is_present("file-name")
+                    && !matches.is_present("confirm-connection")
+                    && !matches.is_present("expect-sha256")
+                    && !matches.is_present("verify")
+                    && !matches.is_present("output"),
+                "--rename, --confirm-connection, --expect-sha256, --verify and --output can't be used when receiving from several codes"
+            );
+            return receive_many(
+                codes.map(str::to_owned).collect(),
+                matches,
+                &config,
+                options,
+                file_path,
+                &history,
+            )
+            .await;
+        }
 
-        let (wormhole, _code, relay_server) = parse_and_connect(&mut term, matches, false).await?;
+        let (wormhole, _code, relay_hints) =
+            parse_and_connect(&mut term, matches, &config, transfer::APP_CONFIG, false).await?;
 
         receive(
             wormhole,
-            relay_server,
-            file_path,
+            relay_hints,
+            options,
+            Some(file_path).filter(|_| !matches.is_present("output")),
             matches.value_of_os("file-name"),
+            matches.is_present("confirm-connection"),
+            &history,
         )
         .await?;
+    } else if let Some(matches) = matches.subcommand_matches("history") {
+        /* Showing it works without enabling it */
+        let history = History::new(history_file);
+        print_history(&mut term, &history, matches)?;
+    } else if let Some(matches) = matches.subcommand_matches("transit-test") {
+        transit_test(&mut term, matches, &config).await?;
+    } else if let Some(matches) = matches.subcommand_matches("bench") {
+        bench(&mut term, matches, &config).await?;
+    } else if let Some(matches) = matches.subcommand_matches("forward") {
+        forward(&mut term, matches, &config).await?;
+    } else if let Some(matches) = matches.subcommand_matches("ssh") {
+        ssh(&mut term, matches, &config).await?;
     } else if let Some(_matches) = matches.subcommand_matches("help") {
         println!("Use --help to get help");
         std::process::exit(1);
@@ -281,57 +949,339 @@ async fn main() -> eyre::Result<()> {
 async fn parse_and_connect(
     term: &mut Term,
     matches: &clap::ArgMatches<'_>,
+    config: &Config,
+    app_config: magic_wormhole::AppConfig<impl serde::Serialize>,
     is_send: bool,
-) -> eyre::Result<(Wormhole, magic_wormhole::Code, url::Url)> {
-    let relay_server: url::Url = matches
-        .value_of("relay-server")
-        .unwrap_or(magic_wormhole::transit::DEFAULT_RELAY_SERVER)
-        .parse()
-        .unwrap();
-    let rendezvous_server = matches
-        .value_of("rendezvous-server")
-        .unwrap_or(magic_wormhole::rendezvous::DEFAULT_RENDEZVOUS_SERVER)
-        .to_string();
-    let code = matches
-        .value_of("code")
-        .map(ToOwned::to_owned)
-        .or_else(|| (!is_send).then(|| enter_code().expect("TODO handle this gracefully")))
-        .map(magic_wormhole::Code);
+) -> eyre::Result<(Wormhole, magic_wormhole::Code, Vec<transit::RelayHint>)> {
+    let mut relay_hints = parse_relay_hints(matches, config)?;
+    let mut rendezvous_server = parse_rendezvous_server(matches, config);
+    let proxy = parse_proxy(matches, config)?;
+    let code = match code_from_args(matches)? {
+        /* Like from a scanned QR code */
+        Some(code) if code.starts_with("wormhole-transfer:") => {
+            let uri = code.parse::<transfer::WormholeTransferUri>()?;
+            eyre::ensure!(
+                uri.is_leader == is_send,
+                "This wormhole-transfer URI is meant for {}",
+                if uri.is_leader {
+                    "sending"
+                } else {
+                    "receiving"
+                }
+            );
+            if let Some(server) = uri.rendezvous_server {
+                if !matches.is_present("rendezvous-server") {
+                    rendezvous_server = server.into();
+                }
+            }
+            Some(uri.code.into())
+        },
+        Some(code) => Some(code),
+        None if !is_send => Some(enter_code(term, &rendezvous_server, proxy.clone()).await?),
+        None => None,
+    }
+    .map(|code| code.parse::<magic_wormhole::Code>())
+    .transpose()?;
+    if let (Some(code), false) = (&code, is_send) {
+        if let Err(error) = code.check_wordlist() {
+            writeln!(
+                term,
+                "Warning: {}. If this is not a custom code, please check it for typos.",
+                error
+            )?;
+        }
+    }
+    /* Only the file transfer apps can make something of the QR code */
+    let show_qr = app_config.id == transfer::APPID
+        && !matches.is_present("no-qr")
+        && term.features().is_attended();
+    let uri = |code: &magic_wormhole::Code| {
+        let uri = transfer::WormholeTransferUri::new(code.clone());
+        match rendezvous_server.as_str() {
+            magic_wormhole::rendezvous::DEFAULT_RENDEZVOUS_SERVER => Ok(uri),
+            server => server.parse().map(|server| uri.rendezvous_server(server)),
+        }
+    };
+    /* What the other side has to run */
+    let command = if app_config.id == magic_wormhole::ssh::APPID {
+        "wormhole ssh accept"
+    } else {
+        "wormhole receive"
+    };
+    let mut app_config = app_config.rendezvous_url(rendezvous_server.clone().into());
+    if let Some(proxy) = proxy {
+        app_config = app_config.proxy(proxy);
+    }
     let (wormhole, code) = match code {
-        Some(code) => {
-            if is_send {
-                sender_print_code(term, &code)?;
+        Some(code) if is_send => {
+            let (server_welcome, connector) =
+                magic_wormhole::Wormhole::connect_with_fixed_code(app_config, code).await?;
+            print_welcome(term, &server_welcome)?;
+            sender_print_code(term, &server_welcome.code, command)?;
+            if show_qr {
+                print_qr_code(term, &uri(&server_welcome.code)?)?;
             }
-            let (server_welcome, wormhole) = magic_wormhole::Wormhole::connect_with_code(
-                transfer::APP_CONFIG.rendezvous_url(rendezvous_server.into()),
-                code,
-            )
-            .await?;
+            let wormhole = connector.await?;
+            (wormhole, server_welcome.code)
+        },
+        Some(code) => {
+            let (server_welcome, wormhole) =
+                magic_wormhole::Wormhole::connect_with_code(app_config, code).await?;
             print_welcome(term, &server_welcome)?;
             (wormhole, server_welcome.code)
         },
         None => {
-            let numwords = matches
-                .value_of("code-length")
-                .unwrap()
-                .parse()
-                .expect("TODO error handling");
-
-            let (server_welcome, connector) = magic_wormhole::Wormhole::connect_without_code(
-                transfer::APP_CONFIG.rendezvous_url(rendezvous_server.into()),
-                numwords,
-            )
-            .await?;
+            let numwords = matches.value_of("code-length").unwrap().parse().unwrap();
+
+            let (server_welcome, connector) =
+                magic_wormhole::Wormhole::connect_without_code(app_config, numwords).await?;
             print_welcome(term, &server_welcome)?;
             if is_send {
-                sender_print_code(term, &server_welcome.code)?;
+                sender_print_code(term, &server_welcome.code, command)?;
+                if show_qr {
+                    print_qr_code(term, &uri(&server_welcome.code)?)?;
+                }
             }
             let wormhole = connector.await?;
             (wormhole, server_welcome.code)
         },
     };
     writeln!(term, "Successfully connected to peer.")?;
-    eyre::Result::<_>::Ok((wormhole, code, relay_server))
+    add_default_relay(matches, config, &wormhole, &mut relay_hints);
+    eyre::Result::<_>::Ok((wormhole, code, relay_hints))
+}
+
+/** `--relay-server`, or the ones from the config if there are none */
+fn parse_relay_hints(
+    matches: &clap::ArgMatches<'_>,
+    config: &Config,
+) -> eyre::Result<Vec<transit::RelayHint>> {
+    match matches.values_of("relay-server") {
+        Some(relays) => relays.map(parse_relay_hint).collect(),
+        None => config
+            .relay_servers
+            .iter()
+            .map(|relay| parse_relay_hint(relay))
+            .collect(),
+    }
+}
+
+fn parse_rendezvous_server(matches: &clap::ArgMatches<'_>, config: &Config) -> String {
+    matches
+        .value_of("rendezvous-server")
+        .or(config.rendezvous_server.as_deref())
+        .unwrap_or(magic_wormhole::rendezvous::DEFAULT_RENDEZVOUS_SERVER)
+        .to_string()
+}
+
+/**
+ * The relays recommended by the rendezvous server will be used anyways,
+ * so our default one is only needed if there are none.
+ */
+fn add_default_relay(
+    matches: &clap::ArgMatches<'_>,
+    config: &Config,
+    wormhole: &Wormhole,
+    relay_hints: &mut Vec<transit::RelayHint>,
+) {
+    if relay_hints.is_empty()
+        && wormhole.transit_relays().is_empty()
+        && !matches.is_present("no-default-relay")
+        && !config.no_default_relay
+    {
+        relay_hints.push(transit::RelayHint::from_url(
+            transit::DEFAULT_RELAY_SERVER.parse().unwrap(),
+        ));
+    }
+}
+
+/**
+ * Get the code from the command line, an environment variable or a file, if it was given in any way
+ */
+fn code_from_args(matches: &clap::ArgMatches<'_>) -> eyre::Result<Option<String>> {
+    use eyre::WrapErr;
+
+    let code = if let Some(code) = matches.value_of("code") {
+        code.to_owned()
+    } else if let Some(variable) = matches.value_of_os("code-from-env") {
+        std::env::var(variable).wrap_err_with(|| {
+            format!(
+                "Could not read the code from the environment variable {}",
+                variable.to_string_lossy()
+            )
+        })?
+    } else if let Some(path) = matches.value_of_os("code-file") {
+        let mut line = String::new();
+        if path == "-" {
+            std::io::stdin().read_line(&mut line)?;
+        } else {
+            let file = std::fs::File::open(path).wrap_err_with(|| {
+                format!(
+                    "Could not read the code from {}",
+                    std::path::Path::new(path).display()
+                )
+            })?;
+            std::io::BufRead::read_line(&mut std::io::BufReader::new(file), &mut line)?;
+        }
+        line
+    } else {
+        return Ok(None);
+    };
+    eyre::ensure!(!code.trim().is_empty(), "The given code is empty");
+    Ok(Some(code))
+}
+
+/**
+ * Parse a relay server argument, which may have a priority suffix like in the Python client
+ * (`tcp:HOSTNAME:PORT:priority=2.5`) and a name (`:name=NAME`), in any order
+ */
+fn parse_relay_hint(relay: &str) -> eyre::Result<transit::RelayHint> {
+    let mut relay = relay;
+    let mut priority = 0.0;
+    let mut name = None;
+    while let Some((rest, option)) = relay.rsplit_once(':') {
+        if let Some(value) = option.strip_prefix("priority=") {
+            priority = f32::from_str(value)
+                .map_err(|err| eyre::format_err!("Invalid relay priority '{}': {}", value, err))?;
+        } else if let Some(value) = option.strip_prefix("name=") {
+            name = Some(value);
+        } else {
+            break;
+        }
+        relay = rest;
+    }
+    let hint = transit::RelayHint::from_url(parse_relay_url(relay)?).priority(priority);
+    Ok(match name {
+        Some(name) => hint.name(name),
+        None => hint,
+    })
+}
+
+/**
+ * Parse a relay server address, either as URL or in the `tcp:HOSTNAME:PORT` notation
+ * that the Python client uses.
+ */
+fn parse_relay_url(relay: &str) -> eyre::Result<url::Url> {
+    let url: url::Url = match relay.strip_prefix("tcp:") {
+        Some(endpoint) if !endpoint.starts_with("//") => format!("tcp://{}", endpoint).parse(),
+        _ => relay.parse(),
+    }
+    .map_err(|err| eyre::format_err!("Invalid relay server '{}': {}", relay, err))?;
+    if url.scheme() == "tcp" {
+        eyre::ensure!(
+            url.host_str().is_some() && url.port().is_some(),
+            "Invalid relay server '{}': TCP relays need a host name and a port",
+            relay
+        );
+    }
+    Ok(url)
+}
+
+/**
+ * Parse the transit options shared by all subcommands. `--listen-port` accepts either
+ * a single port or an inclusive range like `4000-4010`.
+ *
+ * Options that are not given fall back to the config.
+ */
+fn parse_transit_config(
+    matches: &clap::ArgMatches<'_>,
+    config: &Config,
+) -> eyre::Result<transit::TransitConfig> {
+    let mut transit_config = transit::TransitConfig::default();
+    if matches.is_present("force-direct") {
+        transit_config = transit_config.policy(transit::ConnectionPolicy::DirectOnly);
+    } else if matches.is_present("force-relay") {
+        transit_config = transit_config.policy(transit::ConnectionPolicy::RelayOnly);
+    }
+    /* The listener options on the command line beat the ones in the config */
+    let listen_on_cli = matches.is_present("listen-port") || matches.is_present("port-mapping");
+    let no_listen = matches.is_present("no-listen") || (config.no_listen && !listen_on_cli);
+    if no_listen {
+        transit_config = transit_config.no_listen(true);
+    }
+    if matches.is_present("port-mapping") || (config.port_mapping && !no_listen) {
+        transit_config = transit_config.port_mapping(true);
+    }
+    if let Some(proxy) = parse_proxy(matches, config)? {
+        transit_config = transit_config.proxy(proxy);
+    }
+    let connect_timeout = matches
+        .value_of("connect-timeout")
+        .map(|timeout| {
+            u64::from_str(timeout)
+                .map_err(|err| eyre::format_err!("Invalid connect timeout '{}': {}", timeout, err))
+        })
+        .transpose()?
+        .or(config.connect_timeout);
+    if let Some(seconds) = connect_timeout {
+        transit_config = transit_config.timeouts(
+            transit::TransitTimeouts::default().connect(std::time::Duration::from_secs(seconds)),
+        );
+    }
+    if let Some(rate_limit) = matches.value_of("rate-limit") {
+        let bytes_per_second = u64::from_str(rate_limit)
+            .map_err(|err| eyre::format_err!("Invalid rate limit '{}': {}", rate_limit, err))?
+            .checked_mul(1024)
+            .filter(|&bytes_per_second| bytes_per_second > 0)
+            .ok_or_else(|| {
+                eyre::format_err!(
+                    "Invalid rate limit '{}', it must be between 1 and {} KiB/s",
+                    rate_limit,
+                    u64::MAX / 1024
+                )
+            })?;
+        transit_config = transit_config.rate_limit(transit::RateLimit::new(bytes_per_second));
+    }
+    if let Some(connections) = matches.value_of("parallel-connections") {
+        let connections = u16::from_str(connections).map_err(|err| {
+            eyre::format_err!("Invalid number of connections '{}': {}", connections, err)
+        })?;
+        transit_config = transit_config.parallel_connections(connections);
+    }
+    let listen_port = matches
+        .value_of("listen-port")
+        .or(config.listen_port.as_deref().filter(|_| !no_listen));
+    if let Some(ports) = listen_port {
+        let parse_port = |port: &str| {
+            u16::from_str(port.trim())
+                .map_err(|err| eyre::format_err!("Invalid listen port '{}': {}", ports, err))
+        };
+        transit_config = match ports.split_once('-') {
+            Some((from, to)) => {
+                let (from, to) = (parse_port(from)?, parse_port(to)?);
+                eyre::ensure!(from <= to, "Invalid listen port range '{}'", ports);
+                transit_config.listen_ports(from..=to)
+            },
+            None => transit_config.listen_port(parse_port(ports)?),
+        };
+    }
+    Ok(transit_config)
+}
+
+/** `--proxy` and `--tor`, or the proxy from the config */
+fn parse_proxy(
+    matches: &clap::ArgMatches<'_>,
+    config: &Config,
+) -> eyre::Result<Option<magic_wormhole::proxy::Proxy>> {
+    use magic_wormhole::proxy::Proxy;
+
+    let proxy = match matches.value_of("proxy").or(config.proxy.as_deref()) {
+        Some(url) if url.contains("://") => Some(Proxy::from_url(url)?),
+        Some(address) => {
+            let (host, port) = address
+                .rsplit_once(':')
+                .ok_or_else(|| eyre::format_err!("Proxy '{}' must be HOSTNAME:PORT", address))?;
+            let port = u16::from_str(port)
+                .map_err(|err| eyre::format_err!("Invalid proxy port '{}': {}", port, err))?;
+            Some(Proxy::socks5(
+                host.trim_start_matches('[').trim_end_matches(']'),
+                port,
+            ))
+        },
+        None if matches.is_present("tor") => Some(Proxy::local_tor()),
+        None => None,
+    };
+    Ok(proxy.map(|proxy| proxy.tor(matches.is_present("tor"))))
 }
 
 fn create_progress_bar(file_size: u64) -> ProgressBar {
@@ -347,13 +1297,34 @@ fn create_progress_bar(file_size: u64) -> ProgressBar {
     pb
 }
 
-fn enter_code() -> eyre::Result<String> {
+async fn enter_code(
+    term: &Term,
+    rendezvous_server: &str,
+    proxy: Option<magic_wormhole::proxy::Proxy>,
+) -> eyre::Result<String> {
     use dialoguer::Input;
 
-    Input::new()
-        .with_prompt("Enter code")
-        .interact_text()
-        .map_err(From::from)
+    if !term.features().is_attended() {
+        return Input::new()
+            .with_prompt("Enter code")
+            .interact_text()
+            .map_err(From::from);
+    }
+
+    /* Completing the nameplate is only a convenience, so don't fail if the server won't tell */
+    let mut config = transfer::APP_CONFIG.rendezvous_url(rendezvous_server.to_owned().into());
+    if let Some(proxy) = proxy {
+        config = config.proxy(proxy);
+    }
+    let nameplates = match magic_wormhole::Wormhole::list_nameplates(config).await {
+        Ok(nameplates) => nameplates,
+        Err(error) => {
+            log::warn!("Could not list the nameplates for completion: {}", error);
+            Vec::new()
+        },
+    };
+    let term = term.clone();
+    async_std::task::spawn_blocking(move || code_input::enter_code(&term, &nameplates)).await
 }
 
 fn print_welcome(term: &mut Term, welcome: &magic_wormhole::WormholeWelcome) -> eyre::Result<()> {
@@ -363,28 +1334,109 @@ fn print_welcome(term: &mut Term, welcome: &magic_wormhole::WormholeWelcome) ->
     Ok(())
 }
 
-fn sender_print_code(term: &mut Term, code: &magic_wormhole::Code) -> eyre::Result<()> {
+/** Show the verifier and let the user confirm that the other side has the same */
+fn ask_verifier() -> transfer::VerifierCheck {
+    transfer::VerifierCheck::new(|verifier| {
+        Box::pin(async move {
+            /* Not stdout, which may be where the received file goes */
+            eprintln!("Verifier {}.", verifier);
+            util::ask_user("Does it match the other side's?".into(), false).await
+        })
+    })
+}
+
+fn sender_print_code(
+    term: &mut Term,
+    code: &magic_wormhole::Code,
+    command: &str,
+) -> eyre::Result<()> {
     writeln!(term, "This wormhole's code is: {}", &code)?;
     writeln!(term, "On the other computer, please run:\n")?;
-    writeln!(term, "wormhole receive {}\n", &code)?;
+    writeln!(term, "{} {}\n", command, &code)?;
+    Ok(())
+}
+
+/** Show the code as `wormhole-transfer:` URI for scanning. Inverted, to look right on dark terminals */
+fn print_qr_code(term: &mut Term, uri: &transfer::WormholeTransferUri) -> eyre::Result<()> {
+    use qrcode::render::unicode::Dense1x2;
+
+    let qr_code = qrcode::QrCode::new(uri.to_string())?
+        .render::<Dense1x2>()
+        .dark_color(Dense1x2::Light)
+        .light_color(Dense1x2::Dark)
+        .build();
+    writeln!(term, "{}\n", qr_code)?;
     Ok(())
 }
 
 async fn send(
     wormhole: Wormhole,
-    relay_server: url::Url,
+    relay_hints: Vec<transit::RelayHint>,
     file_path: &std::ffi::OsStr,
     file_name: &std::ffi::OsStr,
+    options: transfer::SendOptions,
+) -> eyre::Result<transfer::TransferResult> {
+    let pb = create_progress_bar(0);
+    let pb2 = pb.clone();
+    let progress_handler = move |sent, total| {
+        if sent == 0 {
+            pb.reset_elapsed();
+            pb.set_length(total);
+            pb.enable_steady_tick(250);
+        }
+        pb.set_position(sent);
+    };
+    let result = if file_path == "-" {
+        transfer::send_stream(
+            wormhole,
+            relay_hints,
+            &mut async_std::io::stdin(),
+            file_name,
+            options,
+            progress_handler,
+        )
+        .await?
+    } else {
+        transfer::send_file_or_folder(
+            wormhole,
+            relay_hints,
+            file_path,
+            file_name,
+            options,
+            progress_handler,
+        )
+        .await?
+    };
+    pb2.finish();
+    Ok(result)
+}
+
+/**
+ * Send several files over the same wormhole, one after another
+ *
+ * The receiver gets asked about each of them, so rejected ones count as failed.
+ */
+async fn send_several(
+    wormhole: Wormhole,
+    relay_hints: Vec<transit::RelayHint>,
+    files: Vec<(&std::ffi::OsStr, std::ffi::OsString)>,
+    options: transfer::SendOptions,
+    history: &History,
 ) -> eyre::Result<()> {
     let pb = create_progress_bar(0);
     let pb2 = pb.clone();
-    transfer::send_file_or_folder(
+    let file_names = files
+        .iter()
+        .map(|(_, file_name)| file_name.to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    let results = transfer::send_multiple(
         wormhole,
-        relay_server,
-        file_path,
-        file_name,
-        move |sent, total| {
+        relay_hints,
+        files.clone(),
+        options,
+        move |index, sent, total| {
             if sent == 0 {
+                pb.println(format!("Sending {}", file_names[index]));
                 pb.reset_elapsed();
                 pb.set_length(total);
                 pb.enable_steady_tick(250);
@@ -394,18 +1446,44 @@ async fn send(
     )
     .await?;
     pb2.finish();
+
+    let mut failed = files.len() - results.len();
+    for ((file_path, file_name), result) in files.iter().zip(results) {
+        let result = result.map_err(eyre::Report::from);
+        if let Err(error) = &result {
+            failed += 1;
+            log::warn!("Failed to send {:?}: {}", file_path, error);
+        }
+        history.record(
+            &history::Entry::new(
+                history::Direction::Send,
+                Some(file_path.as_ref()),
+                file_name,
+                &result,
+            )
+            .await,
+        );
+    }
+    eyre::ensure!(
+        failed == 0,
+        "{} of {} transfers failed",
+        failed,
+        files.len()
+    );
     Ok(())
 }
 
 async fn send_many(
-    relay_server: url::Url,
+    relay_hints: Vec<transit::RelayHint>,
     code: &magic_wormhole::Code,
     file_path: &std::ffi::OsStr,
     file_name: &std::ffi::OsStr,
+    options: transfer::SendOptions,
     max_tries: u64,
     timeout: Duration,
     wormhole: Wormhole,
     term: &mut Term,
+    history: History,
 ) -> eyre::Result<()> {
     /* Progress bar is commented out for now. See the issues about threading/async in
      * the Indicatif repository for more information. Multiple progress bars are not usable
@@ -417,17 +1495,19 @@ async fn send_many(
     let file_path = Arc::new(file_path.to_owned());
     let file_name = Arc::new(file_name.to_owned());
     // TODO go back to reference counting again
-    //let url = Arc::new(relay_server);
+    //let url = Arc::new(relay_hints);
 
     let time = Instant::now();
 
     /* Special-case the first send with reusing the existing connection */
     send_in_background(
-        relay_server.clone(),
+        relay_hints.clone(),
         Arc::clone(&file_path),
         Arc::clone(&file_name),
+        options.clone(),
         wormhole,
         term.clone(),
+        history.clone(),
         // &mp,
     )
     .await?;
@@ -449,25 +1529,33 @@ async fn send_many(
             break;
         }
 
+        let mut app_config = transfer::APP_CONFIG;
+        if let Some(proxy) = &options.transit_config.proxy {
+            app_config = app_config.proxy(proxy.clone());
+        }
         let (_server_welcome, wormhole) =
-            magic_wormhole::Wormhole::connect_with_code(transfer::APP_CONFIG, code.clone()).await?;
+            magic_wormhole::Wormhole::connect_with_code(app_config, code.clone()).await?;
         send_in_background(
-            relay_server.clone(),
+            relay_hints.clone(),
             Arc::clone(&file_path),
             Arc::clone(&file_name),
+            options.clone(),
             wormhole,
             term.clone(),
+            history.clone(),
             // &mp,
         )
         .await?;
     }
 
     async fn send_in_background(
-        url: url::Url,
-        file_name: Arc<std::ffi::OsString>,
+        relay_hints: Vec<transit::RelayHint>,
         file_path: Arc<std::ffi::OsString>,
+        file_name: Arc<std::ffi::OsString>,
+        options: transfer::SendOptions,
         wormhole: Wormhole,
         mut term: Term,
+        history: History,
         // mp: &MultiProgress,
     ) -> eyre::Result<()> {
         writeln!(&mut term, "Sending file to peer").unwrap();
@@ -475,12 +1563,13 @@ async fn send_many(
         // let pb = mp.add(pb);
         async_std::task::spawn(async move {
             // let pb2 = pb.clone();
-            let result = async move {
+            let result = async {
                 transfer::send_file_or_folder(
                     wormhole,
-                    url,
+                    relay_hints,
                     file_path.deref(),
                     file_name.deref(),
+                    options,
                     move |_sent, _total| {
                         // if sent == 0 {
                         //     pb2.reset_elapsed();
@@ -489,10 +1578,20 @@ async fn send_many(
                         // pb2.set_position(sent);
                     },
                 )
-                .await?;
-                eyre::Result::<_>::Ok(())
-            };
-            match result.await {
+                .await
+                .map_err(eyre::Report::from)
+            }
+            .await;
+            history.record(
+                &history::Entry::new(
+                    history::Direction::Send,
+                    Some(file_path.as_ref().as_ref()),
+                    &file_name,
+                    &result,
+                )
+                .await,
+            );
+            match result {
                 Ok(_) => {
                     // pb.finish();
                     writeln!(&mut term, "Successfully sent file to peer").unwrap();
@@ -511,69 +1610,704 @@ async fn send_many(
 
 async fn receive(
     wormhole: Wormhole,
-    relay_server: url::Url,
-    target_dir: &std::ffi::OsStr,
+    relay_hints: Vec<transit::RelayHint>,
+    options: transfer::ReceiveOptions,
+    target_dir: Option<&std::ffi::OsStr>,
     file_name: Option<&std::ffi::OsStr>,
+    confirm_connection: bool,
+    history: &History,
 ) -> eyre::Result<()> {
-    let req = transfer::request_file(wormhole, relay_server).await?;
+    use futures::StreamExt;
+
+    /* The sender may offer several files, --rename only applies to the first one */
+    let mut file_name = file_name;
+    let offers = transfer::request_multiple(wormhole, relay_hints, options.transit_config.clone());
+    futures::pin_mut!(offers);
+    while let Some(offer) = offers.next().await {
+        match offer? {
+            transfer::ReceiveOffer::Text(text) => println!("{}", text),
+            transfer::ReceiveOffer::File(req) => {
+                receive_file(
+                    req,
+                    &options,
+                    target_dir,
+                    file_name.take(),
+                    confirm_connection,
+                    history,
+                )
+                .await?
+            },
+        }
+    }
+    Ok(())
+}
 
+async fn receive_file(
+    req: transfer::ReceiveRequest,
+    options: &transfer::ReceiveOptions,
+    /* `None` for the standard output */
+    target_dir: Option<&std::ffi::OsStr>,
+    file_name: Option<&std::ffi::OsStr>,
+    confirm_connection: bool,
+    history: &History,
+) -> eyre::Result<()> {
     /*
      * Control flow is a bit tricky here:
      * - First of all, we ask if we want to receive the file at all
      * - Then, we check if the file already exists
      * - If it exists, ask whether to overwrite and act accordingly
-     * - If it doesn't, directly accept, but DON'T overwrite any files
+     * - Connect, and optionally ask again now that we know how
+     * - Only then receive the file, into a temporary one that replaces the existing file once complete
      */
 
-    if !util::ask_user(
-        format!(
-            "Receive file '{}' (size: {} bytes)?",
+    let question = match req.directory {
+        Some(directory) => format!(
+            "Receive folder '{}' ({} files, {}, as an archive of {} bytes)?",
             req.filename.display(),
+            directory.numfiles,
+            indicatif::HumanBytes(directory.numbytes),
             req.filesize
         ),
-        true,
-    )
-    .await
-    {
+        None => format!(
+            "Receive file '{}' (size: {} bytes{})?",
+            req.filename.display(),
+            req.filesize,
+            req.metadata
+                .mime_type
+                .as_ref()
+                .map(|mime_type| format!(", type: {}", mime_type))
+                .unwrap_or_default()
+        ),
+    };
+    if !util::ask_user(question, true).await {
         return Ok(req.reject().await?);
     }
+    let target_dir = match target_dir {
+        Some(target_dir) => target_dir,
+        None => return receive_to_stdout(req, options, history).await,
+    };
 
     let file_name = file_name
-        .or_else(|| req.filename.file_name())
+        .map(std::ffi::OsStr::to_owned)
+        .or_else(|| req.safe_file_name().map(std::path::PathBuf::into_os_string))
         .ok_or_else(|| eyre::format_err!("The sender did not specify a valid file name, and neither did you. Try using --rename."))?;
-    let file_path = std::path::Path::new(target_dir).join(file_name);
+    let file_path = std::path::Path::new(target_dir).join(&file_name);
+
+    /* If there is a collision, ask whether to overwrite */
+    if file_path.exists()
+        && !util::ask_user(
+            format!("Override existing file {}?", file_path.display()),
+            false,
+        )
+        .await
+    {
+        return Ok(req.reject().await?);
+    }
+
+    let req = req.connect_with_path(&file_path, options).await?;
+    if confirm_connection {
+        print_transit_info(req.transit_info());
+        if !util::ask_user(
+            format!(
+                "Write '{}' (size: {} bytes) to {}?",
+                req.filename.display(),
+                req.filesize,
+                file_path.display()
+            ),
+            false,
+        )
+        .await
+        {
+            return Ok(req.cancel("transfer rejected").await?);
+        }
+    }
 
     let pb = create_progress_bar(req.filesize);
+    receive_to_file(req, &file_path, &file_name, options, pb, history).await
+}
+
+/**
+ * Receive from several codes at once, with one progress bar each
+ *
+ * There is no way to answer questions for multiple transfers at the same time, so all offers
+ * are accepted, unless the file already exists.
+ */
+async fn receive_many(
+    codes: Vec<String>,
+    matches: &clap::ArgMatches<'_>,
+    config: &Config,
+    options: transfer::ReceiveOptions,
+    target_dir: &std::ffi::OsStr,
+    history: &History,
+) -> eyre::Result<()> {
+    use indicatif::ProgressStyle;
+
+    let relay_hints = parse_relay_hints(matches, config)?;
+    let rendezvous_server = parse_rendezvous_server(matches, config);
+    let proxy = parse_proxy(matches, config)?;
+
+    /* All bars need to be added before joining, otherwise it returns early */
+    let mp = MultiProgress::new();
+    let bars = codes
+        .iter()
+        .map(|code| {
+            let pb = mp.add(ProgressBar::new(0));
+            pb.set_style(
+                ProgressStyle::default_bar()
+                    .template("{prefix} [{wide_bar}] {bytes}/{total_bytes} {msg}")
+                    .progress_chars("#>-"),
+            );
+            pb.set_prefix(code.clone());
+            pb.set_message("waiting for the sender");
+            pb.tick();
+            pb
+        })
+        .collect::<Vec<_>>();
+    let drawing = async_std::task::spawn_blocking(move || mp.join());
+
+    let receive_one = |code: String, pb: ProgressBar| {
+        let relay_hints = relay_hints.clone();
+        let options = options.clone();
+        let rendezvous_server = rendezvous_server.clone();
+        let proxy = proxy.clone();
+        async move {
+            let mut app_config = transfer::APP_CONFIG.rendezvous_url(rendezvous_server.into());
+            if let Some(proxy) = proxy {
+                app_config = app_config.proxy(proxy);
+            }
+            let (_server_welcome, wormhole) =
+                magic_wormhole::Wormhole::connect_with_code(app_config, code.parse()?).await?;
+            let mut relay_hints = relay_hints;
+            add_default_relay(matches, config, &wormhole, &mut relay_hints);
+            let req = transfer::request_file(wormhole, relay_hints, options.transit_config.clone())
+                .await?;
+
+            let file_name = req
+                .safe_file_name()
+                .map(std::path::PathBuf::into_os_string)
+                .ok_or_else(|| eyre::format_err!("The sender did not specify a valid file name"))?;
+            let file_path = std::path::Path::new(target_dir).join(&file_name);
+            if file_path.exists() {
+                req.reject().await?;
+                eyre::bail!("{} already exists", file_path.display());
+            }
+
+            pb.set_message(file_name.to_string_lossy().into_owned());
+            pb.set_length(req.filesize);
+            let req = req.connect_with_path(&file_path, &options).await?;
+            receive_to_file(req, &file_path, &file_name, &options, pb.clone(), history).await
+        }
+    };
+
+    let results = futures::future::join_all(codes.into_iter().zip(bars).map(|(code, pb)| {
+        let result = receive_one(code, pb.clone());
+        async move {
+            let result = result.await;
+            match &result {
+                Ok(()) => pb.finish_with_message("done"),
+                Err(error) => pb.abandon_with_message(format!("failed: {}", error)),
+            }
+            result
+        }
+    }))
+    .await;
+    drawing.await?;
+
+    let failed = results.iter().filter(|result| result.is_err()).count();
+    eyre::ensure!(
+        failed == 0,
+        "{} of {} transfers failed",
+        failed,
+        results.len()
+    );
+    Ok(())
+}
+
+/**
+ * Write the file of an accepted offer, and record the outcome in the history
+ *
+ * Until the transfer is complete, the data goes into a temporary file, see
+ * [`transfer::ConnectedReceiveRequest::accept_with_path`]. The checksum file gets written
+ * after recording the transfer, so that failing to write it does not hide the received file.
+ */
+async fn receive_to_file(
+    req: transfer::ConnectedReceiveRequest,
+    file_path: &std::path::Path,
+    file_name: &std::ffi::OsStr,
+    options: &transfer::ReceiveOptions,
+    pb: ProgressBar,
+    history: &History,
+) -> eyre::Result<()> {
+    use eyre::WrapErr;
 
     let on_progress = move |received, _total| {
         pb.set_position(received);
     };
 
-    /* Then, accept if the file exists */
-    if !file_path.exists() {
-        let mut file = OpenOptions::new()
-            .write(true)
-            .create_new(true)
-            .open(&file_path)
+    let checksum_file = options.checksum_file;
+    let options = &options.clone().checksum_file(false);
+    let result = req
+        .accept_with_path(on_progress, file_path, options)
+        .await
+        .map_err(|error| match error {
+            transfer::TransferError::UnexpectedChecksum { .. } if options.keep_mismatched => {
+                eyre::format_err!("{}. It was kept as {}.part", error, file_path.display())
+            },
+            transfer::TransferError::PeerDisconnected(_)
+                if options.partial_files == transfer::PartialFiles::KeepOnDisconnect =>
+            {
+                eyre::format_err!(
+                    "{}. The data received so far was kept as {}.part",
+                    error,
+                    file_path.display()
+                )
+            },
+            error => error.into(),
+        });
+    history.record(
+        &history::Entry::new(
+            history::Direction::Receive,
+            Some(file_path),
+            file_name,
+            &result,
+        )
+        .await,
+    );
+    let result = result?;
+    if checksum_file {
+        transfer::write_checksum_file(file_path, &result.sha256)
+            .await
+            .wrap_err("Failed to write the checksum file")?;
+    }
+    Ok(())
+}
+
+async fn receive_to_stdout(
+    req: transfer::ReceiveRequest,
+    options: &transfer::ReceiveOptions,
+    history: &History,
+) -> eyre::Result<()> {
+    use async_std::io::WriteExt;
+
+    let file_name = req.filename.clone().into_os_string();
+    let result = async {
+        let req = req.connect_with_options(options).await?;
+        let pb = create_progress_bar(req.filesize);
+        let mut stdout = async_std::io::stdout();
+        let result = req
+            .accept(
+                move |received, _total| pb.set_position(received),
+                &mut stdout,
+            )
             .await?;
-        return Ok(req.accept(on_progress, &mut file).await?);
+        stdout.flush().await?;
+        Ok(result)
     }
+    .await;
+    history
+        .record(&history::Entry::new(history::Direction::Receive, None, &file_name, &result).await);
+    result.map(|_| ())
+}
 
-    /* If there is a collision, ask whether to overwrite */
-    if !util::ask_user(
-        format!("Override existing file {}?", file_path.display()),
-        false,
-    )
-    .await
-    {
-        return Ok(req.reject().await?);
+fn print_transit_info(info: &transit::TransitInfo) {
+    match info.conn_type {
+        transit::ConnectionType::Direct => println!(
+            "Connected directly to {}{}{}",
+            info.peer_addr,
+            if info.quic { " over QUIC" } else { "" },
+            info.rtt
+                .map(|rtt| format!(" (round-trip time: {:?})", rtt))
+                .unwrap_or_default()
+        ),
+        transit::ConnectionType::Relay => match &info.relay {
+            Some(relay) => println!(
+                "Connected via the relay server {}{} ({})",
+                relay
+                    .name
+                    .as_ref()
+                    .map(|name| format!("{} at ", name))
+                    .unwrap_or_default(),
+                relay.hint,
+                info.peer_addr
+            ),
+            None => println!("Connected via the relay server at {}", info.peer_addr),
+        },
+    }
+}
+
+async fn transit_test(
+    term: &mut Term,
+    matches: &clap::ArgMatches<'_>,
+    config: &Config,
+) -> eyre::Result<()> {
+    use indicatif::HumanBytes;
+
+    let bytes = parse_size(matches)?;
+    let pings = u32::from_str(matches.value_of("pings").unwrap())?;
+    let transit_config = parse_transit_config(matches, config)?;
+    let leader = !matches.is_present("code");
+
+    let (wormhole, _code, relay_hints) =
+        parse_and_connect(term, matches, config, transit_test::app_config(), leader).await?;
+    writeln!(term, "Measuring the connection…")?;
+    let report =
+        transit_test::run(wormhole, relay_hints, transit_config, leader, bytes, pings).await?;
+
+    print_transit_info(&report.info);
+    if let (Some(min), Some(max)) = (report.rtts.iter().min(), report.rtts.iter().max()) {
+        let average = report.rtts.iter().sum::<Duration>() / report.rtts.len() as u32;
+        println!(
+            "Round-trip time: {:?} on average (min {:?}, max {:?}, {} pings)",
+            average,
+            min,
+            max,
+            report.rtts.len()
+        );
+    }
+    let throughput = |elapsed: Duration| {
+        HumanBytes((report.bytes as f64 / elapsed.as_secs_f64().max(1e-6)) as u64)
+    };
+    println!(
+        "Upload: {} in {:?} ({}/s)",
+        HumanBytes(report.bytes),
+        report.upload,
+        throughput(report.upload)
+    );
+    println!(
+        "Download: {} in {:?} ({}/s)",
+        HumanBytes(report.bytes),
+        report.download,
+        throughput(report.download)
+    );
+    Ok(())
+}
+
+/** Parse the `--size` of `transit-test` and `bench`, which is in MiB */
+fn parse_size(matches: &clap::ArgMatches<'_>) -> eyre::Result<u64> {
+    let size = matches.value_of("size").unwrap();
+    u64::from_str(size)
+        .map_err(|err| eyre::format_err!("Invalid size '{}': {}", size, err))?
+        .checked_mul(1024 * 1024)
+        .filter(|&bytes| bytes <= transit_test::MAX_BYTES)
+        .ok_or_else(|| {
+            eyre::format_err!(
+                "Invalid size '{}', it must be at most {} MiB",
+                size,
+                transit_test::MAX_BYTES / 1024 / 1024
+            )
+        })
+}
+
+async fn bench(
+    term: &mut Term,
+    matches: &clap::ArgMatches<'_>,
+    config: &Config,
+) -> eyre::Result<()> {
+    use indicatif::HumanBytes;
+
+    let bytes = parse_size(matches)?;
+    let record_sizes = matches
+        .value_of("record-sizes")
+        .unwrap()
+        .split(',')
+        .map(|size| match usize::from_str(size.trim()) {
+            Ok(size) if size > 0 && size <= transit::MAX_RECORD_SIZE => Ok(size),
+            _ => Err(eyre::format_err!(
+                "Invalid record size '{}', it must be between 1 and {}",
+                size,
+                transit::MAX_RECORD_SIZE
+            )),
+        })
+        .collect::<eyre::Result<Vec<_>>>()?;
+    let transit_config = parse_transit_config(matches, config)?;
+
+    let report = if matches.is_present("remote") || matches.is_present("code") {
+        let leader = !matches.is_present("code");
+        let (wormhole, _code, relay_hints) = parse_and_connect(
+            term,
+            matches,
+            config,
+            transit_test::bench_app_config(),
+            leader,
+        )
+        .await?;
+        writeln!(term, "Measuring…")?;
+        let report = transit_test::bench_remote(
+            wormhole,
+            relay_hints,
+            transit_config,
+            leader,
+            bytes,
+            record_sizes,
+        )
+        .await?;
+        print_transit_info(&report.info);
+        report
+    } else {
+        writeln!(term, "Measuring on this machine…")?;
+        transit_test::bench_loopback(transit_config, bytes, record_sizes).await?
+    };
+
+    let throughput = |elapsed: Duration| {
+        format!(
+            "{}/s",
+            HumanBytes((report.bytes as f64 / elapsed.as_secs_f64().max(1e-6)) as u64)
+        )
+    };
+    println!(
+        "{} per record size. Encryption is measured in memory, the transfer includes everything.",
+        HumanBytes(report.bytes)
+    );
+    print!("{:>12} {:>9}", "Record size", "Overhead");
+    if let Some(measurement) = report.measurements.first() {
+        for (cipher, _) in &measurement.encryption {
+            print!(" {:>20}", format!("{:?}", cipher));
+        }
+    }
+    println!(" {:>12}", "Transfer");
+    for measurement in &report.measurements {
+        print!(
+            "{:>12} {:>8.2}%",
+            HumanBytes(measurement.record_size as u64).to_string(),
+            measurement.overhead() * 100.0
+        );
+        for (_, elapsed) in &measurement.encryption {
+            print!(" {:>20}", throughput(*elapsed));
+        }
+        println!(" {:>12}", throughput(measurement.transfer));
     }
+    Ok(())
+}
+
+async fn forward(
+    term: &mut Term,
+    matches: &clap::ArgMatches<'_>,
+    config: &Config,
+) -> eyre::Result<()> {
+    use magic_wormhole::forwarding;
+
+    if let Some(matches) = matches.subcommand_matches("serve") {
+        let services = matches
+            .values_of("targets")
+            .into_iter()
+            .flatten()
+            .map(|target| match target.split_once('=') {
+                /* Addresses contain a colon, names don't */
+                Some((name, address)) if !name.contains(':') => {
+                    forwarding::Service::new(address).name(name)
+                },
+                _ => forwarding::Service::new(target),
+            })
+            .collect::<Vec<_>>();
+        let transit_config = parse_transit_config(matches, config)?;
 
-    let mut file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(&file_path)
+        let (wormhole, _code, relay_hints) =
+            parse_and_connect(term, matches, config, forwarding::APP_CONFIG, true).await?;
+        writeln!(term, "Forwarding until the other side disconnects")?;
+        forwarding::serve(
+            wormhole,
+            relay_hints,
+            transit_config,
+            services,
+            matches.is_present("socks"),
+            forwarding::ForwardingState::new(),
+        )
         .await?;
-    Ok(req.accept(on_progress, &mut file).await?)
+    } else if let Some(matches) = matches.subcommand_matches("connect") {
+        let bind_address = std::net::IpAddr::from_str(matches.value_of("bind").unwrap())?;
+        let ports = matches
+            .values_of("port")
+            .into_iter()
+            .flatten()
+            .map(|port| match port.rsplit_once('=') {
+                Some((selector, port)) => Ok((selector, u16::from_str(port)?)),
+                None => eyre::bail!("Expected SERVICE=PORT, but got '{}'", port),
+            })
+            .collect::<eyre::Result<Vec<_>>>()?;
+        let socks_port = matches
+            .value_of("socks-port")
+            .map(u16::from_str)
+            .transpose()?;
+        let transit_config = parse_transit_config(matches, config)?;
+
+        let (wormhole, _code, relay_hints) =
+            parse_and_connect(term, matches, config, forwarding::APP_CONFIG, false).await?;
+        let mut offer =
+            forwarding::connect(wormhole, relay_hints, transit_config, Some(bind_address)).await?;
+        for (selector, port) in ports {
+            offer.bind(selector, port).await?;
+        }
+        offer.bind_all().await?;
+        for (port, service) in offer.bound() {
+            match service.name {
+                Some(name) => writeln!(
+                    term,
+                    "{} ({}): {}:{}",
+                    name, service.address, bind_address, port
+                )?,
+                None => writeln!(term, "{}: {}:{}", service.address, bind_address, port)?,
+            }
+        }
+        if offer.socks && bind_address.is_loopback() {
+            let port = offer.bind_socks(socks_port.unwrap_or(0)).await?;
+            writeln!(term, "SOCKS5 proxy: {}:{}", bind_address, port)?;
+        } else if offer.socks {
+            writeln!(
+                term,
+                "Not binding the SOCKS5 proxy to {}, it may only be bound to a loopback address",
+                bind_address
+            )?;
+        }
+
+        if !matches.is_present("noconfirm")
+            && !util::ask_user("Start forwarding?".into(), true).await
+        {
+            return Ok(offer.reject().await?);
+        }
+        offer.accept(forwarding::ForwardingState::new()).await?;
+    }
+    Ok(())
+}
+
+async fn ssh(term: &mut Term, matches: &clap::ArgMatches<'_>, config: &Config) -> eyre::Result<()> {
+    use magic_wormhole::ssh;
+    use std::path::PathBuf;
+
+    let ssh_dir = || {
+        std::env::var_os("HOME")
+            .map(|home| PathBuf::from(home).join(".ssh"))
+            .ok_or_else(|| eyre::format_err!("Could not find your home directory"))
+    };
+
+    if let Some(matches) = matches.subcommand_matches("invite") {
+        let path = match matches.value_of_os("authorized-keys") {
+            Some(path) => PathBuf::from(path),
+            None => ssh_dir()?.join("authorized_keys"),
+        };
+
+        let (wormhole, _code, _relay_hints) =
+            parse_and_connect(term, matches, config, ssh::APP_CONFIG, true).await?;
+        let key = ssh::receive_key(wormhole).await?;
+        let keyid = key.comment().unwrap_or("unknown");
+        if matches.is_present("print") {
+            writeln!(term, "{}", key)?;
+        } else if ssh::add_authorized_key(&path, &key).await? {
+            writeln!(
+                term,
+                "Appended key type '{}' keyid '{}' to {}",
+                key.kind(),
+                keyid,
+                path.display()
+            )?;
+        } else {
+            writeln!(term, "Key '{}' is in {} already", keyid, path.display())?;
+        }
+    } else if let Some(matches) = matches.subcommand_matches("accept") {
+        let path = match matches.value_of_os("key-file") {
+            Some(path) => PathBuf::from(path),
+            None => find_public_key(&ssh_dir()?)?,
+        };
+        let key = std::fs::read_to_string(&path)
+            .map_err(|err| eyre::format_err!("Could not read {}: {}", path.display(), err))?
+            .parse::<ssh::PublicKey>()?;
+        let keyid = key.comment().unwrap_or("unknown");
+
+        writeln!(
+            term,
+            "Sending public key type '{}' keyid '{}'",
+            key.kind(),
+            keyid
+        )?;
+        if !matches.is_present("noconfirm")
+            && !util::ask_user(format!("Really send public key '{}'?", keyid), false).await
+        {
+            return Ok(());
+        }
+        let (wormhole, _code, _relay_hints) =
+            parse_and_connect(term, matches, config, ssh::APP_CONFIG, false).await?;
+        ssh::send_key(wormhole, &key).await?;
+        writeln!(term, "Key sent.")?;
+    }
+    Ok(())
+}
+
+/** The public key in `~/.ssh` to send, if there is exactly one */
+fn find_public_key(ssh_dir: &std::path::Path) -> eyre::Result<std::path::PathBuf> {
+    let mut keys = std::fs::read_dir(ssh_dir)
+        .map_err(|err| eyre::format_err!("Could not read {}: {}", ssh_dir.display(), err))?
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            name.starts_with("id_") && name.ends_with(".pub")
+        })
+        .collect::<Vec<_>>();
+    keys.sort();
+    match keys.len() {
+        0 => eyre::bail!(
+            "There is no public key in {}, please choose one with --key-file",
+            ssh_dir.display()
+        ),
+        1 => Ok(keys.remove(0)),
+        _ => eyre::bail!(
+            "There are several public keys in {} ({}), please choose one with --key-file",
+            ssh_dir.display(),
+            keys.iter()
+                .map(|path| path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+fn print_history(
+    term: &mut Term,
+    history: &History,
+    matches: &clap::ArgMatches<'_>,
+) -> eyre::Result<()> {
+    let limit = usize::from_str(matches.value_of("limit").unwrap())?;
+    let mut entries = history.entries()?;
+    if matches.is_present("failed") {
+        entries.retain(|entry| entry.error.is_some());
+    }
+    let entries = &entries[entries.len().saturating_sub(limit)..];
+
+    if matches.is_present("json") {
+        for entry in entries {
+            writeln!(term, "{}", serde_json::to_string(entry)?)?;
+        }
+        return Ok(());
+    }
+
+    if entries.is_empty() {
+        writeln!(term, "No transfers yet.")?;
+    }
+    for entry in entries {
+        writeln!(
+            term,
+            "{}  {}  {}",
+            entry.timestamp,
+            match entry.direction {
+                history::Direction::Send => "sent    ",
+                history::Direction::Receive => "received",
+            },
+            style(&entry.file_name).bold(),
+        )?;
+        if let Some(size) = entry.size {
+            writeln!(term, "    size:   {} bytes", size)?;
+        }
+        if let Some(sha256) = &entry.sha256 {
+            writeln!(term, "    sha256: {}", sha256)?;
+        }
+        if let Some(direct) = entry.direct {
+            writeln!(
+                term,
+                "    via:    {}",
+                if direct { "direct" } else { "relay" }
+            )?;
+        }
+        if let Some(error) = &entry.error {
+            writeln!(term, "    {} {}", style("failed:").red(), error)?;
+        }
+    }
+    Ok(())
 }
@@ -0,0 +1,438 @@
+use color_eyre::eyre;
+use magic_wormhole::{transfer, transit, AppConfig, AppID, Key, Wormhole};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    convert::TryFrom,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use xsalsa20poly1305::aead::{generic_array::GenericArray, Aead, NewAead};
+
+/**
+ * The configuration for `transit-test`
+ *
+ * It uses its own app ID, so that it can't accidentally be paired with a file transfer.
+ */
+pub fn app_config() -> AppConfig<transfer::AppVersion> {
+    transfer::APP_CONFIG.id(AppID::new("piegames.de/wormhole/transit-test"))
+}
+
+/** Use the same record size as file transfers, so that the throughput is comparable */
+const RECORD_SIZE: usize = 4096;
+
+/** The most data a measurement may send in each direction, also when the peer asks for more */
+pub const MAX_BYTES: u64 = 4 * 1024 * 1024 * 1024;
+
+/**
+ * The only message exchanged over the wormhole
+ *
+ * Besides what is needed for the transit connection, it carries the parameters of the
+ * measurement. Only those of the side that allocated the code are used.
+ */
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Handshake<T> {
+    abilities_v1: transit::Abilities,
+    hints_v1: transit::Hints,
+    #[serde(flatten)]
+    parameters: T,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Parameters {
+    bytes: u64,
+    pings: u32,
+}
+
+/** What has been measured */
+pub struct Report {
+    pub info: transit::TransitInfo,
+    /** The round-trip times of all pings */
+    pub rtts: Vec<Duration>,
+    pub bytes: u64,
+    /** How long it took to send `bytes` bytes to the peer */
+    pub upload: Duration,
+    /** How long it took to receive `bytes` bytes from the peer */
+    pub download: Duration,
+}
+
+/**
+ * Set up a transit connection to the peer, without any file offer
+ *
+ * Both sides send their `parameters` along, the ones of the `leader` (the side that allocated
+ * the code) are returned.
+ */
+async fn connect<T: serde::Serialize + serde::de::DeserializeOwned>(
+    mut wormhole: Wormhole,
+    relay_hints: Vec<transit::RelayHint>,
+    transit_config: &transit::TransitConfig,
+    leader: bool,
+    parameters: T,
+) -> eyre::Result<(transit::Transit, T)> {
+    let connector = transit::init(
+        transit::Abilities::ALL_ABILITIES,
+        None,
+        relay_hints,
+        transit_config,
+    )
+    .await?;
+    wormhole
+        .send_json(&Handshake {
+            abilities_v1: *connector.our_abilities(),
+            hints_v1: (**connector.our_hints()).clone(),
+            parameters: &parameters,
+        })
+        .await?;
+    let theirs: Handshake<T> = wormhole.receive_json().await??;
+
+    let transit_key = wormhole.next_transit_key();
+    let their_hints = Arc::new(theirs.hints_v1);
+    let transit = if leader {
+        connector
+            .leader_connect(transit_key, theirs.abilities_v1, their_hints)
+            .await?
+    } else {
+        connector
+            .follower_connect(transit_key, theirs.abilities_v1, their_hints)
+            .await?
+    };
+    wormhole.close().await?;
+
+    let parameters = if leader {
+        parameters
+    } else {
+        theirs.parameters
+    };
+    Ok((transit, parameters))
+}
+
+/**
+ * Connect to the peer and measure the connection, without transferring any file
+ *
+ * Both sides first send `pings` records to each other which get echoed, and then `bytes` bytes.
+ * The receiving side measures how long the latter took, and tells it to the sending one.
+ * The `leader` is the side that allocated the code.
+ */
+pub async fn run(
+    wormhole: Wormhole,
+    relay_hints: Vec<transit::RelayHint>,
+    transit_config: transit::TransitConfig,
+    leader: bool,
+    bytes: u64,
+    pings: u32,
+) -> eyre::Result<Report> {
+    let (mut transit, Parameters { bytes, pings }) = connect(
+        wormhole,
+        relay_hints,
+        &transit_config,
+        leader,
+        Parameters { bytes, pings },
+    )
+    .await?;
+    eyre::ensure!(
+        bytes <= MAX_BYTES,
+        "The peer asked to send {} bytes, which is more than {}",
+        bytes,
+        MAX_BYTES
+    );
+
+    /* The leader goes first each time, so that both sides never wait for each other */
+    let (rtts, upload, download) = if leader {
+        let rtts = ping(&mut transit, pings).await?;
+        pong(&mut transit, pings).await?;
+        let upload = send_bulk(&mut transit, bytes, RECORD_SIZE).await?;
+        let download = receive_bulk(&mut transit, bytes).await?;
+        (rtts, upload, download)
+    } else {
+        pong(&mut transit, pings).await?;
+        let rtts = ping(&mut transit, pings).await?;
+        let download = receive_bulk(&mut transit, bytes).await?;
+        let upload = send_bulk(&mut transit, bytes, RECORD_SIZE).await?;
+        (rtts, upload, download)
+    };
+
+    Ok(Report {
+        info: transit.info().clone(),
+        rtts,
+        bytes,
+        upload,
+        download,
+    })
+}
+
+/** Send `pings` small records, and measure how long it takes until they come back */
+async fn ping(transit: &mut transit::Transit, pings: u32) -> eyre::Result<Vec<Duration>> {
+    let mut rtts = Vec::new();
+    for ping in 0..pings {
+        let start = Instant::now();
+        transit.send_record(&ping.to_be_bytes()).await?;
+        transit.flush().await?;
+        let pong = transit.receive_record().await?;
+        eyre::ensure!(*pong == ping.to_be_bytes(), "Got a wrong pong");
+        rtts.push(start.elapsed());
+    }
+    Ok(rtts)
+}
+
+/** Send the peer's pings back */
+async fn pong(transit: &mut transit::Transit, pings: u32) -> eyre::Result<()> {
+    for _ in 0..pings {
+        let ping = transit.receive_record().await?;
+        transit.send_record(&ping).await?;
+        transit.flush().await?;
+    }
+    Ok(())
+}
+
+/**
+ * Send `bytes` bytes in records of `record_size`, and wait for the peer to tell how long
+ * receiving them took
+ */
+async fn send_bulk(
+    transit: &mut transit::Transit,
+    bytes: u64,
+    record_size: usize,
+) -> eyre::Result<Duration> {
+    let record = vec![0; record_size];
+    let mut sent = 0;
+    while sent < bytes {
+        let length = std::cmp::min(record_size as u64, bytes - sent);
+        transit.send_record(&record[..length as usize]).await?;
+        sent += length;
+    }
+    transit.flush().await?;
+    let elapsed = transit.receive_record().await?;
+    /* Take the receiver's view, which does not include our buffering */
+    let elapsed = <[u8; 8]>::try_from(&*elapsed)
+        .map_err(|_| eyre::format_err!("Got a malformed confirmation"))?;
+    Ok(Duration::from_nanos(u64::from_be_bytes(elapsed)))
+}
+
+/** Receive `bytes` bytes, and confirm them with the time it took */
+async fn receive_bulk(transit: &mut transit::Transit, bytes: u64) -> eyre::Result<Duration> {
+    let mut start = None;
+    let mut received = 0;
+    while received < bytes {
+        let record = transit.receive_record().await?;
+        start.get_or_insert_with(Instant::now);
+        received += record.len() as u64;
+    }
+    eyre::ensure!(received == bytes, "Got more data than expected");
+    let elapsed = start.map(|start| start.elapsed()).unwrap_or_default();
+    transit
+        .send_record(&(elapsed.as_nanos() as u64).to_be_bytes())
+        .await?;
+    transit.flush().await?;
+    Ok(elapsed)
+}
+
+/**
+ * The configuration for `bench` between two machines
+ *
+ * Like `transit-test`, it uses its own app ID so that it can't be paired with anything else.
+ */
+pub fn bench_app_config() -> AppConfig<transfer::AppVersion> {
+    transfer::APP_CONFIG.id(AppID::new("piegames.de/wormhole/bench"))
+}
+
+/** Every record carries a length prefix, a nonce and an authentication tag besides its data */
+pub const RECORD_OVERHEAD: usize = 4 + 24 + 16;
+
+/** What the side that allocated the code wants to measure */
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct BenchParameters {
+    bytes: u64,
+    record_sizes: Vec<usize>,
+}
+
+/** The results for one record size */
+pub struct Measurement {
+    pub record_size: usize,
+    /** How long encrypting all data in memory took, for each cipher */
+    pub encryption: Vec<(transit::RecordCipher, Duration)>,
+    /** How long it took the receiving side to get all data */
+    pub transfer: Duration,
+}
+
+impl Measurement {
+    /** The share of the bytes on the wire that is not payload */
+    pub fn overhead(&self) -> f64 {
+        RECORD_OVERHEAD as f64 / (self.record_size + RECORD_OVERHEAD) as f64
+    }
+}
+
+pub struct BenchReport {
+    pub info: transit::TransitInfo,
+    /** How much data was sent for each record size */
+    pub bytes: u64,
+    pub measurements: Vec<Measurement>,
+}
+
+/**
+ * Transfer synthetic data to ourselves
+ *
+ * Both ends of the transit connection run in this process and connect over a local address,
+ * so this measures how fast this machine can encrypt, decrypt and shovel records around.
+ */
+pub async fn bench_loopback(
+    transit_config: transit::TransitConfig,
+    bytes: u64,
+    record_sizes: Vec<usize>,
+) -> eyre::Result<BenchReport> {
+    /* Never leave this machine */
+    let transit_config = transit_config.policy(transit::ConnectionPolicy::DirectOnly);
+    let (leader, follower) = futures::try_join!(
+        transit::init(
+            transit::Abilities::ALL_ABILITIES,
+            None,
+            Vec::new(),
+            &transit_config
+        ),
+        transit::init(
+            transit::Abilities::ALL_ABILITIES,
+            None,
+            Vec::new(),
+            &transit_config
+        ),
+    )?;
+    let key = rand::random::<[u8; 32]>();
+    let key = || Key::new(Box::new(GenericArray::clone_from_slice(&key)));
+    let (leader_abilities, leader_hints) = (*leader.our_abilities(), leader.our_hints().clone());
+    let (follower_abilities, follower_hints) =
+        (*follower.our_abilities(), follower.our_hints().clone());
+    let (mut sender, mut receiver) = futures::try_join!(
+        leader.leader_connect(key(), follower_abilities, follower_hints),
+        follower.follower_connect(key(), leader_abilities, leader_hints),
+    )?;
+
+    /* Receive on another thread, so that both ends don't compete for the same one */
+    let receiving = record_sizes.len();
+    let receiver = async_std::task::spawn(async move {
+        for _ in 0..receiving {
+            receive_bulk(&mut receiver, bytes).await?;
+        }
+        eyre::Result::<_>::Ok(())
+    });
+    let mut measurements = Vec::new();
+    for record_size in record_sizes {
+        measurements.push(Measurement {
+            record_size,
+            encryption: encryption(record_size, bytes),
+            transfer: send_bulk(&mut sender, bytes, record_size).await?,
+        });
+    }
+    receiver.await?;
+
+    Ok(BenchReport {
+        info: sender.info().clone(),
+        bytes,
+        measurements,
+    })
+}
+
+/**
+ * Transfer synthetic data to another machine
+ *
+ * The side that allocated the code (the `leader`) decides on the parameters and sends,
+ * the other side receives. Both get the same transfer times, but measure the encryption
+ * on their own machine.
+ */
+pub async fn bench_remote(
+    wormhole: Wormhole,
+    relay_hints: Vec<transit::RelayHint>,
+    transit_config: transit::TransitConfig,
+    leader: bool,
+    bytes: u64,
+    record_sizes: Vec<usize>,
+) -> eyre::Result<BenchReport> {
+    let (
+        mut transit,
+        BenchParameters {
+            bytes,
+            record_sizes,
+        },
+    ) = connect(
+        wormhole,
+        relay_hints,
+        &transit_config,
+        leader,
+        BenchParameters {
+            bytes,
+            record_sizes,
+        },
+    )
+    .await?;
+    eyre::ensure!(
+        bytes <= MAX_BYTES,
+        "The peer asked to send {} bytes, which is more than {}",
+        bytes,
+        MAX_BYTES
+    );
+    if let Some(record_size) = record_sizes
+        .iter()
+        .find(|&&size| size == 0 || size > transit::MAX_RECORD_SIZE)
+    {
+        eyre::bail!(
+            "The peer asked for records of {} bytes, which must be between 1 and {}",
+            record_size,
+            transit::MAX_RECORD_SIZE
+        );
+    }
+
+    let mut measurements = Vec::new();
+    for record_size in record_sizes {
+        let transfer = if leader {
+            send_bulk(&mut transit, bytes, record_size).await?
+        } else {
+            receive_bulk(&mut transit, bytes).await?
+        };
+        measurements.push(Measurement {
+            record_size,
+            encryption: encryption(record_size, bytes),
+            transfer,
+        });
+    }
+
+    Ok(BenchReport {
+        info: transit.info().clone(),
+        bytes,
+        measurements,
+    })
+}
+
+/** Encrypt `bytes` bytes in records of `record_size` with all supported ciphers, without any I/O */
+fn encryption(record_size: usize, bytes: u64) -> Vec<(transit::RecordCipher, Duration)> {
+    vec![
+        (
+            transit::RecordCipher::XSalsa20Poly1305,
+            encrypt::<xsalsa20poly1305::XSalsa20Poly1305>(record_size, bytes),
+        ),
+        (
+            transit::RecordCipher::XChaCha20Poly1305,
+            encrypt::<chacha20poly1305::XChaCha20Poly1305>(record_size, bytes),
+        ),
+    ]
+}
+
+fn encrypt<C: NewAead + Aead>(record_size: usize, bytes: u64) -> Duration {
+    let cipher = C::new(&GenericArray::default());
+    let nonce = GenericArray::default();
+    let record = vec![0; record_size];
+    let start = Instant::now();
+    let mut encrypted = 0;
+    while encrypted < bytes {
+        let length = std::cmp::min(record_size as u64, bytes - encrypted);
+        /* Like the transit connection, allocate a new buffer for every record */
+        cipher
+            .encrypt(&nonce, &record[..length as usize])
+            .expect("Encryption cannot fail");
+        encrypted += length;
+    }
+    start.elapsed()
+}
+
+#[allow(dead_code)]
+fn main() {
+    panic!("This ought to be a helper module, no idea why Rust thinks it's a crate");
+}
@@ -8,13 +8,14 @@ pub async fn ask_user(message: String, default_answer: bool) -> bool {
         if default_answer { "n" } else { "N" }
     );
 
-    let mut stdout = io::stdout();
+    /* Not stdout, which may be where the received file goes */
+    let mut stderr = io::stderr();
     let stdin = io::stdin();
 
     loop {
-        stdout.write(message.as_bytes()).await.unwrap();
+        stderr.write(message.as_bytes()).await.unwrap();
 
-        stdout.flush().await.unwrap();
+        stderr.flush().await.unwrap();
 
         let mut answer = String::new();
         stdin.read_line(&mut answer).await.unwrap();
@@ -24,11 +25,11 @@ pub async fn ask_user(message: String, default_answer: bool) -> bool {
             "n" | "no" => break false,
             "" => break default_answer,
             _ => {
-                stdout
+                stderr
                     .write("Please type y or n!\n".as_bytes())
                     .await
                     .unwrap();
-                stdout.flush().await.unwrap();
+                stderr.flush().await.unwrap();
                 continue;
             },
         };
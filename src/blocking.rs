@@ -0,0 +1,100 @@
+//! Synchronous wrappers for applications without an async runtime
+//!
+//! Each function blocks the current thread until the async function of the same name has finished,
+//! on async-std's executor. This is meant for small tools and for calling from other languages, don't use
+//! it from within async code: it would block the runtime's thread.
+//!
+//! Only the most common operations are wrapped. Everything else can be driven the same way with
+//! [`async_std::task::block_on`].
+
+use crate::{
+    transfer::{self, ReceiveOptions, ReceiveRequest, SendOptions, TransferError, TransferResult},
+    transit, AppConfig, Code, Wormhole, WormholeError, WormholeWelcome,
+};
+use futures::future::BoxFuture;
+use std::path::Path;
+
+/** See [`Wormhole::connect_with_code`] */
+pub fn connect_with_code(
+    config: AppConfig<impl serde::Serialize>,
+    code: Code,
+) -> Result<(WormholeWelcome, Wormhole), WormholeError> {
+    async_std::task::block_on(Wormhole::connect_with_code(config, code))
+}
+
+/**
+ * See [`Wormhole::connect_without_code`]
+ *
+ * This returns as soon as the code is known, give it to the other side and then
+ * [`wait`](PendingWormhole::wait) for it to connect.
+ */
+pub fn connect_without_code(
+    config: AppConfig<impl serde::Serialize + 'static>,
+    code_length: usize,
+) -> Result<(WormholeWelcome, PendingWormhole), WormholeError> {
+    let (welcome, connector) =
+        async_std::task::block_on(Wormhole::connect_without_code(config, code_length))?;
+    Ok((welcome, PendingWormhole(Box::pin(connector))))
+}
+
+/** A [`Wormhole`] that waits for the other side to show up */
+pub struct PendingWormhole(BoxFuture<'static, Result<Wormhole, WormholeError>>);
+
+impl PendingWormhole {
+    pub fn wait(self) -> Result<Wormhole, WormholeError> {
+        async_std::task::block_on(self.0)
+    }
+}
+
+impl std::fmt::Debug for PendingWormhole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PendingWormhole(..)")
+    }
+}
+
+/** See [`transfer::send_file_or_folder`] */
+pub fn send_file_or_folder(
+    wormhole: Wormhole,
+    relay_hints: Vec<transit::RelayHint>,
+    file_path: impl AsRef<Path>,
+    file_name: impl AsRef<Path>,
+    options: SendOptions,
+    progress_handler: impl FnMut(u64, u64) + 'static,
+) -> Result<TransferResult, TransferError> {
+    async_std::task::block_on(transfer::send_file_or_folder(
+        wormhole,
+        relay_hints,
+        file_path,
+        file_name,
+        options,
+        progress_handler,
+    ))
+}
+
+/** See [`transfer::request_file`] */
+pub fn request_file(
+    wormhole: Wormhole,
+    relay_hints: Vec<transit::RelayHint>,
+    transit_config: transit::TransitConfig,
+) -> Result<ReceiveRequest, TransferError> {
+    async_std::task::block_on(transfer::request_file(
+        wormhole,
+        relay_hints,
+        transit_config,
+    ))
+}
+
+/** See [`ReceiveRequest::accept_with_path`] */
+pub fn accept_with_path(
+    request: ReceiveRequest,
+    progress_handler: impl FnMut(u64, u64) + 'static,
+    path: impl AsRef<Path>,
+    options: &ReceiveOptions,
+) -> Result<TransferResult, TransferError> {
+    async_std::task::block_on(request.accept_with_path(progress_handler, path, options))
+}
+
+/** See [`ReceiveRequest::reject`] */
+pub fn reject(request: ReceiveRequest) -> Result<(), TransferError> {
+    async_std::task::block_on(request.reject())
+}
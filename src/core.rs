@@ -1,15 +1,22 @@
+pub(super) mod error_code;
+pub(super) mod events;
 pub(super) mod key;
 pub mod rendezvous;
 mod server_messages;
 #[cfg(test)]
 mod test;
-mod wordlist;
+pub(super) mod wordlist;
 
 use serde_derive::{Deserialize, Serialize};
-use std::borrow::Cow;
+use std::{
+    borrow::Cow,
+    pin::Pin,
+    task::{Context, Poll},
+};
 
-use self::rendezvous::*;
 pub(self) use self::server_messages::EncryptedMessage;
+use self::{error_code::*, events::*, rendezvous::*};
+use crate::util::Span;
 use log::*;
 
 use xsalsa20poly1305 as secretbox;
@@ -42,6 +49,12 @@ pub enum WormholeError {
     PakeFailed,
     #[error("Cannot decrypt a received message")]
     Crypto,
+    /// The other half of a [split](Wormhole::split) wormhole is gone
+    #[error("The wormhole has already been closed")]
+    Closed,
+    /// Codes need at least one word, see [`Wormhole::connect_without_code`]
+    #[error("Codes need at least one word")]
+    InvalidCodeLength,
 }
 
 impl WormholeError {
@@ -49,6 +62,29 @@ impl WormholeError {
     pub fn is_scared(&self) -> bool {
         matches!(self, Self::PakeFailed)
     }
+
+    /** What went wrong, in a way that stays stable across versions */
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::ServerError(error) => match error {
+                RendezvousError::IO(_) | RendezvousError::Unresponsive => {
+                    ErrorCode::ServerConnection
+                },
+                RendezvousError::Crowded => ErrorCode::Crowded,
+                RendezvousError::NameplateInUse(_) => ErrorCode::NameplateInUse,
+                RendezvousError::InvalidNameplate(_) => ErrorCode::InvalidNameplate,
+                RendezvousError::PermissionDenied(_) | RendezvousError::Login(_) => {
+                    ErrorCode::PermissionDenied
+                },
+                _ => ErrorCode::Server,
+            },
+            Self::ProtocolJson(_) | Self::Protocol(_) => ErrorCode::Protocol,
+            Self::PakeFailed => ErrorCode::WrongCode,
+            Self::Crypto => ErrorCode::Crypto,
+            Self::Closed => ErrorCode::Closed,
+            Self::InvalidCodeLength => ErrorCode::InvalidCode,
+        }
+    }
 }
 
 impl From<std::convert::Infallible> for WormholeError {
@@ -59,6 +95,9 @@ impl From<std::convert::Infallible> for WormholeError {
 
 /**
  * The result of the client-server handshake
+ *
+ * If the server does not want us to connect at all, for example because it is shutting down,
+ * connecting fails with a [`RendezvousError::Welcome`] carrying its message for the user instead.
  */
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct WormholeWelcome {
@@ -79,7 +118,12 @@ pub struct WormholeWelcome {
  *
  * # Clean shutdown
  *
- * TODO
+ * Call [`close`](Self::close) once you are done. A wormhole that gets dropped instead (for example
+ * because the future using it got cancelled) still closes its mailbox in the background,
+ * on a best-effort basis. The peer does not get told, this is up to the application protocol.
+ *
+ * [`receive`](Self::receive) is cancellation safe: dropping its future does not lose messages.
+ * [`send`](Self::send) is not: if its future gets dropped, the message may or may not have been sent.
  */
 /* TODO
  * Maybe a better way to handle application level protocols is to create a trait for them and then
@@ -88,9 +132,21 @@ pub struct WormholeWelcome {
 #[derive(Debug)]
 pub struct Wormhole {
     server: RendezvousServer,
+    /* Kept for diagnostics, the server forgets it once released */
+    nameplate: Option<Nameplate>,
     phase: u64,
+    /* Received messages of phases nobody asked for yet, in order, at most `MAX_PENDING_MESSAGES` */
+    pending: std::collections::VecDeque<(Phase, Vec<u8>)>,
+    /* How many messages have been sent and received in each named phase, see `send_message` */
+    named_sent: NamedPhases,
+    named_received: NamedPhases,
     key: key::Key<key::WormholeKey>,
+    /* How many transit keys have been handed out, see `next_transit_key` */
+    transit_keys: u64,
     appid: AppID,
+    events: WormholeEvents,
+    /* Transfers over this wormhole get child spans of it */
+    span: Span,
     /**
      * If you're paranoid, let both sides check that they calculated the same verifier.
      *
@@ -116,11 +172,19 @@ impl Wormhole {
     /**
      * Generate a code and connect to the rendezvous server.
      *
+     * The password part of the code has `code_length` words, which must be at least one, or this
+     * fails with [`WormholeError::InvalidCodeLength`]. Two are the usual choice, each additional
+     * word makes the code about 256 times harder to guess. The other side can enter codes of any
+     * length.
+     *
      * # Returns
      *
      * A tuple with a [`WormholeWelcome`] and a [`std::future::Future`] that will
      * do the rest of the client-client handshake and yield the [`Wormhole`] object
      * on success.
+     *
+     * The nameplate (and thus the code) gets released as soon as the other side connected,
+     * which is logged. From then on, the code cannot be entered anymore, even if the handshake fails.
      */
     pub async fn connect_without_code(
         config: AppConfig<impl serde::Serialize>,
@@ -131,28 +195,111 @@ impl Wormhole {
             impl std::future::Future<Output = Result<Self, WormholeError>>,
         ),
         WormholeError,
+    > {
+        ensure!(code_length > 0, WormholeError::InvalidCodeLength);
+        Self::connect_sender(config, None, code_length).await
+    }
+
+    /**
+     * Connect with a code chosen by the caller, instead of one allocated by the server
+     *
+     * This is for the side that would otherwise use [`connect_without_code`](Self::connect_without_code),
+     * and works the same way. If the other side entered the code first, we join it. Fails with
+     * [`RendezvousError::NameplateInUse`] if two others use the nameplate of the code already. If
+     * only one other side with a different code waits there, the key exchange fails instead. Only use
+     * this with codes of enough entropy, a fixed code can be guessed by anybody who knows it is
+     * being used.
+     */
+    pub async fn connect_with_fixed_code(
+        config: AppConfig<impl serde::Serialize>,
+        code: Code,
+    ) -> Result<
+        (
+            WormholeWelcome,
+            impl std::future::Future<Output = Result<Self, WormholeError>>,
+        ),
+        WormholeError,
+    > {
+        Self::connect_sender(config, Some(code), 0).await
+    }
+
+    /** Claim the nameplate of `code`, or allocate one for a new code of `code_length` words */
+    async fn connect_sender(
+        config: AppConfig<impl serde::Serialize>,
+        code: Option<Code>,
+        code_length: usize,
+    ) -> Result<
+        (
+            WormholeWelcome,
+            impl std::future::Future<Output = Result<Self, WormholeError>>,
+        ),
+        WormholeError,
     > {
         let AppConfig {
             id: appid,
             rendezvous_url,
             app_version: versions,
+            rendezvous_tap,
+            resolver,
+            proxy,
+            events,
+            wordlist,
+            keepalive,
         } = config;
+        let events = events.unwrap_or_default();
         let versions = serde_json::to_value(versions).unwrap();
-        let (mut server, welcome) = RendezvousServer::connect(&appid, &rendezvous_url).await?;
-        let (nameplate, mailbox) = server.allocate_claim_open().await?;
-        log::debug!("Connected to mailbox {}", mailbox);
-
-        let code = Code::new(
-            &nameplate,
-            &wordlist::default_wordlist(code_length).choose_words(),
-        );
+        let span = span!("wormhole", appid = %appid, nameplate = tracing::field::Empty);
+        events.emit(WormholeEvent::ConnectingToRendezvous);
+        let (server, welcome, code) = span
+            .instrument(async {
+                let (mut server, welcome) = RendezvousServer::connect_custom(
+                    &appid,
+                    &rendezvous_url,
+                    rendezvous_tap,
+                    resolver.as_ref(),
+                    proxy.as_ref(),
+                    keepalive,
+                )
+                .await?;
+                let code = match code {
+                    Some(code) => {
+                        let nameplate = code.nameplate();
+                        /* Checking the nameplates beforehand would race with the others, and keep
+                         * us from joining a receiver that was faster. So we leave it to the server.
+                         */
+                        let mailbox = server.claim_open(nameplate.clone()).await.map_err(
+                            |error| match error {
+                                RendezvousError::Crowded => {
+                                    RendezvousError::NameplateInUse(nameplate)
+                                },
+                                error => error,
+                            },
+                        )?;
+                        log::debug!("Connected to mailbox {}", mailbox);
+                        code
+                    },
+                    None => {
+                        let (nameplate, mailbox) = server.allocate_claim_open().await?;
+                        log::debug!("Connected to mailbox {}", mailbox);
+                        let mut wordlist =
+                            wordlist.unwrap_or_else(|| wordlist::default_wordlist(code_length));
+                        wordlist.num_words = code_length;
+                        Code::new(&nameplate, &wordlist.choose_words())
+                    },
+                };
+                Result::<_, WormholeError>::Ok((server, welcome, code))
+            })
+            .await?;
+        span.record("nameplate", code.nameplate());
 
         Ok((
             WormholeWelcome {
                 welcome,
                 code: code.clone(),
             },
-            Self::connect_custom(server, appid, code.0, versions),
+            span.clone().instrument(Self::connect_with_events(
+                server, appid, code.0, versions, events, span,
+            )),
         ))
     }
 
@@ -167,23 +314,70 @@ impl Wormhole {
             id: appid,
             rendezvous_url,
             app_version: versions,
+            rendezvous_tap,
+            resolver,
+            proxy,
+            events,
+            wordlist: _,
+            keepalive,
         } = config;
+        let events = events.unwrap_or_default();
         let versions = serde_json::to_value(versions).unwrap();
-        let (mut server, welcome) = RendezvousServer::connect(&appid, &rendezvous_url).await?;
-
-        let nameplate = code.nameplate();
-        let mailbox = server.claim_open(nameplate).await?;
-        log::debug!("Connected to mailbox {}", mailbox);
+        let span = span!("wormhole", appid = %appid, nameplate = %code.nameplate());
+        events.emit(WormholeEvent::ConnectingToRendezvous);
+        let (server, welcome) = span
+            .instrument(async {
+                let (mut server, welcome) = RendezvousServer::connect_custom(
+                    &appid,
+                    &rendezvous_url,
+                    rendezvous_tap,
+                    resolver.as_ref(),
+                    proxy.as_ref(),
+                    keepalive,
+                )
+                .await?;
+                let mailbox = server.claim_open(code.nameplate()).await?;
+                log::debug!("Connected to mailbox {}", mailbox);
+                Result::<_, WormholeError>::Ok((server, welcome))
+            })
+            .await?;
 
         Ok((
             WormholeWelcome {
                 welcome,
                 code: code.clone(),
             },
-            Self::connect_custom(server, appid, code.0, versions).await?,
+            span.clone()
+                .instrument(Self::connect_with_events(
+                    server, appid, code.0, versions, events, span,
+                ))
+                .await?,
         ))
     }
 
+    /**
+     * List the nameplates that are currently in use on the rendezvous server
+     *
+     * This is meant for completing codes that are typed in interactively. It uses a connection of
+     * its own, which gets closed again right away.
+     */
+    pub async fn list_nameplates(
+        config: AppConfig<impl serde::Serialize>,
+    ) -> Result<Vec<Nameplate>, WormholeError> {
+        let (mut server, _welcome) = RendezvousServer::connect_custom(
+            &config.id,
+            &config.rendezvous_url,
+            config.rendezvous_tap,
+            config.resolver.as_ref(),
+            config.proxy.as_ref(),
+            config.keepalive,
+        )
+        .await?;
+        let nameplates = server.list_nameplates().await?;
+        server.shutdown(Mood::Happy).await?;
+        Ok(nameplates)
+    }
+
     /** TODO */
     pub async fn connect_with_seed() {
         todo!()
@@ -198,17 +392,67 @@ impl Wormhole {
     /// If the [`RendezvousServer`] is not properly initialized, i.e. if the
     /// mailbox is not open.
     pub async fn connect_custom(
+        server: RendezvousServer,
+        appid: AppID,
+        password: String,
+        app_versions: impl serde::Serialize,
+    ) -> Result<Self, WormholeError> {
+        let span = span!("wormhole", appid = %appid, nameplate = tracing::field::Empty);
+        if let Some(nameplate) = server.nameplate() {
+            span.record("nameplate", nameplate);
+        }
+        let events = WormholeEvents::new();
+        span.clone()
+            .instrument(Self::connect_with_events(
+                server,
+                appid,
+                password,
+                app_versions,
+                events,
+                span,
+            ))
+            .await
+    }
+
+    async fn connect_with_events(
         mut server: RendezvousServer,
         appid: AppID,
         password: String,
         app_versions: impl serde::Serialize,
+        events: WormholeEvents,
+        span: Span,
     ) -> Result<Self, WormholeError> {
+        let nameplate = server.nameplate().cloned();
+        let mailbox = server
+            .mailbox()
+            .cloned()
+            .expect("The mailbox must be open already");
+        log::debug!(
+            "Connecting to peer: nameplate {}, mailbox {}",
+            nameplate
+                .as_ref()
+                .map(ToString::to_string)
+                .unwrap_or_else(|| "(none)".into()),
+            mailbox
+        );
+
+        events.emit(WormholeEvent::WaitingForPeer);
+
         /* Send PAKE */
         let (pake_state, pake_msg_ser) = key::make_pake(&password, &appid);
         server.send_peer_message(Phase::PAKE, pake_msg_ser).await?;
 
         /* Receive PAKE */
         let peer_pake = key::extract_pake_msg(&server.next_peer_message_some().await?.body)?;
+
+        /* The peer has opened the mailbox too, so the nameplate is not needed anymore. Release it
+         * right away (as the spec says) instead of holding it until the end of the session.
+         */
+        if server.needs_nameplate_release() {
+            server.release_nameplate().await?;
+            events.emit(WormholeEvent::NameplateReleased);
+        }
+
         let key = pake_state
             .finish(&peer_pake)
             .map_err(|_| WormholeError::PakeFailed)
@@ -231,18 +475,22 @@ impl Wormhole {
 
         let peer_version = versions.app_versions;
 
-        if server.needs_nameplate_release() {
-            server.release_nameplate().await?;
-        }
-
-        log::info!("Connected to peer (PAKE successful)");
+        log::info!("Connected to peer (PAKE successful) in mailbox {}", mailbox);
+        events.emit(WormholeEvent::KeyEstablished);
 
         /* We are now fully initialized! Up and running! :tada: */
         Ok(Self {
             server,
+            nameplate,
             appid,
+            events,
+            span,
             phase: 0,
+            pending: Default::default(),
+            named_sent: Default::default(),
+            named_received: Default::default(),
             key: key::Key::new(key.into()),
+            transit_keys: 0,
             verifier: Box::new(key::derive_verifier(&key)),
             peer_version,
         })
@@ -250,13 +498,15 @@ impl Wormhole {
 
     /** Send an encrypted message to peer */
     pub async fn send(&mut self, plaintext: Vec<u8>) -> Result<(), WormholeError> {
-        let phase_string = Phase::numeric(self.phase);
+        let phase = Phase::numeric(self.phase);
         self.phase += 1;
-        let data_key = key::derive_phase_key(self.server.side(), &self.key, &phase_string);
+        self.send_phase(phase, plaintext).await
+    }
+
+    async fn send_phase(&mut self, phase: Phase, plaintext: Vec<u8>) -> Result<(), WormholeError> {
+        let data_key = key::derive_phase_key(self.server.side(), &self.key, &phase);
         let (_nonce, encrypted) = key::encrypt_data(&data_key, &plaintext);
-        self.server
-            .send_peer_message(phase_string, encrypted)
-            .await?;
+        self.server.send_peer_message(phase, encrypted).await?;
         Ok(())
     }
 
@@ -277,28 +527,92 @@ impl Wormhole {
         self.send(serde_json::to_vec(message).unwrap()).await
     }
 
-    /** Receive an encrypted message from peer */
+    /**
+     * Receive an encrypted message from peer
+     *
+     * Messages sent with [`send_message`](Self::send_message) are kept for
+     * [`receive_message`](Self::receive_message) instead.
+     */
     pub async fn receive(&mut self) -> Result<Vec<u8>, WormholeError> {
+        let (_phase, message) = self.receive_phase(|phase| phase.to_num().is_some()).await?;
+        Ok(message)
+    }
+
+    /** Receive the next message of a phase that `wanted`, and keep all others for later */
+    async fn receive_phase(
+        &mut self,
+        wanted: impl Fn(&Phase) -> bool,
+    ) -> Result<(Phase, Vec<u8>), WormholeError> {
+        if let Some(index) = self.pending.iter().position(|(phase, _)| wanted(phase)) {
+            return Ok(self.pending.remove(index).unwrap());
+        }
         loop {
             let peer_message = match self.server.next_peer_message().await? {
                 Some(peer_message) => peer_message,
                 None => continue,
             };
-            if peer_message.phase.to_num().is_none() {
-                // TODO: log and ignore, for future expansion
-                todo!("log and ignore, for future expansion");
-            }
 
             // TODO maybe reorder incoming messages by phase numeral?
             let decrypted_message = peer_message
                 .decrypt(&self.key)
                 .ok_or(WormholeError::Crypto)?;
-
-            // Send to client
-            return Ok(decrypted_message);
+            if wanted(&peer_message.phase) {
+                return Ok((peer_message.phase, decrypted_message));
+            }
+            log::debug!(
+                "Keeping a message of phase '{}' for later",
+                peer_message.phase
+            );
+            keep_pending(&mut self.pending, (peer_message.phase, decrypted_message))?;
         }
     }
 
+    /**
+     * Serialize and send a message in a phase of its own
+     *
+     * Other than with [`send_json`](Self::send_json), the other side gets it with
+     * [`receive_message`](Self::receive_message) by the name of its phase, regardless of the
+     * order in which it receives messages. This way, protocols don't need to keep track of which
+     * message comes when. Several messages may be sent in the same phase, they are received in order.
+     * On the wire, each of them has a phase of its own, `"{phase}-{n}"` with `n` counting from zero.
+     *
+     * ## Panics
+     *
+     * If the serialization fails, or the phase name is a number or `version` or `pake`, which
+     * are used by the protocol itself.
+     */
+    pub async fn send_message<T: serde::Serialize>(
+        &mut self,
+        phase: &str,
+        message: &T,
+    ) -> Result<(), WormholeError> {
+        self.send_named(phase, serde_json::to_vec(message).unwrap())
+            .await
+    }
+
+    async fn send_named(&mut self, name: &str, plaintext: Vec<u8>) -> Result<(), WormholeError> {
+        /* Counted even if sending fails, the server drops messages whose phase it already knows */
+        let phase = self.named_sent.next(name);
+        self.send_phase(phase, plaintext).await
+    }
+
+    /**
+     * Receive and deserialize the next message the peer sent in `phase`
+     *
+     * See [`send_message`](Self::send_message). Messages of other phases are kept until they
+     * are asked for. A message that does not deserialize fails with
+     * [`WormholeError::ProtocolJson`].
+     */
+    pub async fn receive_message<T>(&mut self, phase: &str) -> Result<T, WormholeError>
+    where
+        T: for<'a> serde::Deserialize<'a>,
+    {
+        let wanted = self.named_received.peek(phase);
+        let (_phase, message) = self.receive_phase(|other| other == &wanted).await?;
+        self.named_received.next(phase);
+        Ok(serde_json::from_slice(&message)?)
+    }
+
     /**
      * Receive an encrypted message from peer
      *
@@ -316,7 +630,23 @@ impl Wormhole {
     }
 
     pub async fn close(self) -> Result<(), WormholeError> {
-        self.server.shutdown(Mood::Happy).await.map_err(Into::into)
+        let result = self.server.shutdown(Mood::Happy).await;
+        self.events.emit(WormholeEvent::Closed);
+        result.map_err(Into::into)
+    }
+
+    /** Follow the rest of this wormhole's life, see [`WormholeEvents::subscribe`] */
+    pub fn subscribe(&self) -> impl futures::Stream<Item = WormholeEvent> {
+        self.events.subscribe()
+    }
+
+    /** Where this wormhole's events go, for the protocols built upon it */
+    pub fn events(&self) -> &WormholeEvents {
+        &self.events
+    }
+
+    pub(crate) fn span(&self) -> &Span {
+        &self.span
     }
 
     /**
@@ -334,6 +664,386 @@ impl Wormhole {
     pub fn key(&self) -> &key::Key<key::WormholeKey> {
         &self.key
     }
+
+    /**
+     * The key for the next transit connection with the peer
+     *
+     * Each call returns a different key (see [`Key::derive_nth_transit_key`](key::Key::derive_nth_transit_key)),
+     * as no two connections may use the same one. Both sides must thus make their connections in the
+     * same order.
+     */
+    pub fn next_transit_key(&mut self) -> key::Key<crate::transit::TransitKey> {
+        let index = self.transit_keys;
+        self.transit_keys += 1;
+        self.key.derive_nth_transit_key(&self.appid, index)
+    }
+
+    /**
+     * Derive `length` bytes of key material for an application protocol
+     *
+     * Both sides get the same bytes for the same `purpose`, nobody else can. Use a different
+     * purpose for every key. See [`Key::derive_bytes`](key::Key::derive_bytes).
+     */
+    pub fn derive_key(&self, purpose: &str, length: usize) -> Vec<u8> {
+        self.key.derive_bytes(purpose, length)
+    }
+
+    /**
+     * The verifier, to be compared with the other side's (see the [field](Wormhole#structfield.verifier))
+     *
+     * Display it hex encoded, so that both sides show the same string.
+     */
+    pub fn verifier(&self) -> &secretbox::Key {
+        &self.verifier
+    }
+
+    /**
+     * The nameplate that was used to connect
+     *
+     * This is the number at the start of the code. It is `None` if the mailbox was opened directly.
+     * Together with the [`mailbox`](Self::mailbox), this allows matching a session in the logs
+     * of a (self-hosted) rendezvous server.
+     */
+    pub fn nameplate(&self) -> Option<&Nameplate> {
+        self.nameplate.as_ref()
+    }
+
+    /** The mailbox on the rendezvous server that this wormhole communicates through */
+    pub fn mailbox(&self) -> &Mailbox {
+        self.server
+            .mailbox()
+            .expect("The mailbox stays open for the lifetime of the wormhole")
+    }
+
+    /**
+     * Transit relay servers recommended by the rendezvous server.
+     * Protocols using transit should consider these in addition to the relays configured by the user.
+     */
+    pub fn transit_relays(&self) -> &[url::Url] {
+        self.server.transit_relays()
+    }
+
+    /**
+     * Split the wormhole into a sending and a receiving half
+     *
+     * This allows waiting for incoming messages while sending others at the same time.
+     * Both halves are backed by a background task that owns the connection.
+     * Query everything else you need (like the [`key`](Self::key) or the [`verifier`](Self::verifier))
+     * before splitting, because the halves only provide sending and receiving.
+     *
+     * The wormhole gets closed by calling [`WormholeSender::close`], or in the background once
+     * both halves are gone. Any receive error ends the connection too. Messages in named phases
+     * (see [`send_message`](Self::send_message)) work with the halves as well.
+     */
+    pub fn split(mut self) -> (WormholeSender, WormholeReceiver) {
+        let (commands_tx, commands_rx) = futures::channel::mpsc::unbounded();
+        let (incoming_tx, incoming_rx) = futures::channel::mpsc::unbounded();
+        let named_received = std::mem::take(&mut self.named_received);
+        async_std::task::spawn(run_split(self, commands_rx, incoming_tx));
+        (
+            WormholeSender {
+                commands: commands_tx,
+                in_flight: None,
+                closing: false,
+            },
+            WormholeReceiver {
+                incoming: incoming_rx,
+                pending: Default::default(),
+                named_received,
+            },
+        )
+    }
+}
+
+enum SplitCommand {
+    /** Send in the named phase, or the next numbered one */
+    Send(
+        Option<String>,
+        Vec<u8>,
+        futures::channel::oneshot::Sender<Result<(), WormholeError>>,
+    ),
+    Close(futures::channel::oneshot::Sender<Result<(), WormholeError>>),
+}
+
+/** Background task of a split wormhole: owns the connection and serves both halves */
+async fn run_split(
+    mut wormhole: Wormhole,
+    mut commands: futures::channel::mpsc::UnboundedReceiver<SplitCommand>,
+    incoming: futures::channel::mpsc::UnboundedSender<Result<(Phase, Vec<u8>), WormholeError>>,
+) {
+    use futures::{future::Either, StreamExt};
+
+    let mut sender_alive = true;
+    loop {
+        /* Receiving is cancellation safe, sending is not. So we only ever interrupt the
+         * former, and run the latter to completion once we got a command.
+         */
+        let event = if sender_alive {
+            let receive = Box::pin(wormhole.receive_phase(|_| true));
+            match futures::future::select(commands.next(), receive).await {
+                Either::Left((command, _)) => Either::Left(command),
+                Either::Right((message, _)) => Either::Right(message),
+            }
+        } else {
+            Either::Right(wormhole.receive_phase(|_| true).await)
+        };
+
+        match event {
+            Either::Left(Some(SplitCommand::Send(phase, message, reply))) => {
+                let result = match phase {
+                    Some(name) => wormhole.send_named(&name, message).await,
+                    None => wormhole.send(message).await,
+                };
+                let _ = reply.send(result);
+            },
+            Either::Left(Some(SplitCommand::Close(reply))) => {
+                let _ = reply.send(wormhole.close().await);
+                break;
+            },
+            Either::Left(None) => {
+                sender_alive = false;
+                if incoming.is_closed() {
+                    break;
+                }
+            },
+            Either::Right(message) => {
+                let failed = message.is_err();
+                /* If the receiving half is gone, the message gets dropped */
+                if (incoming.unbounded_send(message).is_err() && !sender_alive) || failed {
+                    break;
+                }
+            },
+        }
+    }
+}
+
+/**
+ * The sending half of a [`Wormhole`], see [`Wormhole::split`]
+ *
+ * Besides [`send`](Self::send), messages can go through its [`Sink`](futures::Sink)
+ * implementation, to be used with [`SinkExt`](futures::SinkExt) and friends. Closing the sink
+ * closes the wormhole.
+ */
+#[derive(Debug)]
+pub struct WormholeSender {
+    commands: futures::channel::mpsc::UnboundedSender<SplitCommand>,
+    /** The reply to the last message that went through the sink, or to closing it */
+    in_flight: Option<futures::channel::oneshot::Receiver<Result<(), WormholeError>>>,
+    closing: bool,
+}
+
+impl WormholeSender {
+    /** Send an encrypted message to peer */
+    pub async fn send(&mut self, plaintext: Vec<u8>) -> Result<(), WormholeError> {
+        self.send_phase(None, plaintext).await
+    }
+
+    async fn send_phase(
+        &mut self,
+        phase: Option<String>,
+        plaintext: Vec<u8>,
+    ) -> Result<(), WormholeError> {
+        let (reply_tx, reply_rx) = futures::channel::oneshot::channel();
+        self.commands
+            .unbounded_send(SplitCommand::Send(phase, plaintext, reply_tx))
+            .map_err(|_| WormholeError::Closed)?;
+        reply_rx.await.map_err(|_| WormholeError::Closed)?
+    }
+
+    /**
+     * Serialize and send an encrypted message to peer
+     *
+     * See [`Wormhole::send_json`].
+     */
+    pub async fn send_json<T: serde::Serialize>(
+        &mut self,
+        message: &T,
+    ) -> Result<(), WormholeError> {
+        self.send(serde_json::to_vec(message).unwrap()).await
+    }
+
+    /**
+     * Serialize and send a message in a phase of its own
+     *
+     * See [`Wormhole::send_message`].
+     */
+    pub async fn send_message<T: serde::Serialize>(
+        &mut self,
+        phase: &str,
+        message: &T,
+    ) -> Result<(), WormholeError> {
+        Phase::check_name(phase);
+        let message = serde_json::to_vec(message).unwrap();
+        self.send_phase(Some(phase.to_owned()), message).await
+    }
+
+    /** Close the wormhole. The receiving half won't get any messages afterwards. */
+    pub async fn close(self) -> Result<(), WormholeError> {
+        let (reply_tx, reply_rx) = futures::channel::oneshot::channel();
+        self.commands
+            .unbounded_send(SplitCommand::Close(reply_tx))
+            .map_err(|_| WormholeError::Closed)?;
+        reply_rx.await.map_err(|_| WormholeError::Closed)?
+    }
+
+    /** Wait until the last message that went through the sink is sent */
+    fn poll_in_flight(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), WormholeError>> {
+        use futures::FutureExt;
+        if let Some(reply) = &mut self.in_flight {
+            let result =
+                futures::ready!(reply.poll_unpin(cx)).unwrap_or(Err(WormholeError::Closed));
+            self.in_flight = None;
+            result?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/**
+ * Messages are sent one at a time, so that errors show up at the next message or on flushing
+ */
+impl futures::Sink<Vec<u8>> for WormholeSender {
+    type Error = WormholeError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().poll_in_flight(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, plaintext: Vec<u8>) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        let (reply_tx, reply_rx) = futures::channel::oneshot::channel();
+        this.commands
+            .unbounded_send(SplitCommand::Send(None, plaintext, reply_tx))
+            .map_err(|_| WormholeError::Closed)?;
+        this.in_flight = Some(reply_rx);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().poll_in_flight(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        if !this.closing {
+            futures::ready!(this.poll_in_flight(cx))?;
+            let (reply_tx, reply_rx) = futures::channel::oneshot::channel();
+            this.commands
+                .unbounded_send(SplitCommand::Close(reply_tx))
+                .map_err(|_| WormholeError::Closed)?;
+            this.in_flight = Some(reply_rx);
+            this.closing = true;
+        }
+        this.poll_in_flight(cx)
+    }
+}
+
+/**
+ * The receiving half of a [`Wormhole`], see [`Wormhole::split`]
+ *
+ * Besides [`receive`](Self::receive), the messages can be taken from its
+ * [`Stream`](futures::Stream) implementation, to be used with [`StreamExt`](futures::StreamExt)
+ * and friends.
+ */
+#[derive(Debug)]
+pub struct WormholeReceiver {
+    incoming: futures::channel::mpsc::UnboundedReceiver<Result<(Phase, Vec<u8>), WormholeError>>,
+    /* Like `Wormhole::pending` */
+    pending: std::collections::VecDeque<(Phase, Vec<u8>)>,
+    named_received: NamedPhases,
+}
+
+impl WormholeReceiver {
+    /** Receive an encrypted message from peer */
+    pub async fn receive(&mut self) -> Result<Vec<u8>, WormholeError> {
+        self.receive_phase(|phase| phase.to_num().is_some()).await
+    }
+
+    /** See [`Wormhole::receive_phase`] */
+    async fn receive_phase(
+        &mut self,
+        wanted: impl Fn(&Phase) -> bool,
+    ) -> Result<Vec<u8>, WormholeError> {
+        use futures::StreamExt;
+        if let Some(index) = self.pending.iter().position(|(phase, _)| wanted(phase)) {
+            return Ok(self.pending.remove(index).unwrap().1);
+        }
+        loop {
+            let (phase, message) = self
+                .incoming
+                .next()
+                .await
+                .unwrap_or(Err(WormholeError::Closed))?;
+            if wanted(&phase) {
+                return Ok(message);
+            }
+            keep_pending(&mut self.pending, (phase, message))?;
+        }
+    }
+
+    /**
+     * Receive and deserialize the next message the peer sent in `phase`
+     *
+     * See [`Wormhole::receive_message`].
+     */
+    pub async fn receive_message<T>(&mut self, phase: &str) -> Result<T, WormholeError>
+    where
+        T: for<'a> serde::Deserialize<'a>,
+    {
+        let wanted = self.named_received.peek(phase);
+        let message = self.receive_phase(|other| other == &wanted).await?;
+        self.named_received.next(phase);
+        Ok(serde_json::from_slice(&message)?)
+    }
+
+    /**
+     * Receive an encrypted message from peer
+     *
+     * See [`Wormhole::receive_json`].
+     */
+    pub async fn receive_json<T>(&mut self) -> Result<Result<T, serde_json::Error>, WormholeError>
+    where
+        T: for<'a> serde::Deserialize<'a>,
+    {
+        self.receive()
+            .await
+            .map(|data: Vec<u8>| serde_json::from_slice(&data))
+    }
+}
+
+/**
+ * The same messages as from [`receive`](WormholeReceiver::receive). The stream ends when the
+ * wormhole gets closed, and nothing follows after an error.
+ */
+impl futures::Stream for WormholeReceiver {
+    type Item = Result<Vec<u8>, WormholeError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        use futures::StreamExt;
+        let this = self.get_mut();
+        if let Some(index) = this
+            .pending
+            .iter()
+            .position(|(phase, _)| phase.to_num().is_some())
+        {
+            let (_phase, message) = this.pending.remove(index).unwrap();
+            return Poll::Ready(Some(Ok(message)));
+        }
+        loop {
+            match futures::ready!(this.incoming.poll_next_unpin(cx)) {
+                Some(Ok((phase, message))) if phase.to_num().is_some() => {
+                    return Poll::Ready(Some(Ok(message)))
+                },
+                Some(Ok(other)) => {
+                    if let Err(error) = keep_pending(&mut this.pending, other) {
+                        return Poll::Ready(Some(Err(error)));
+                    }
+                },
+                Some(Err(error)) => return Poll::Ready(Some(Err(error))),
+                None => return Poll::Ready(None),
+            }
+        }
+    }
 }
 
 // the serialized forms of these variants are part of the wire protocol, so
@@ -368,6 +1078,25 @@ pub struct AppConfig<V: serde::Serialize> {
     pub id: AppID,
     pub rendezvous_url: Cow<'static, str>,
     pub app_version: V,
+    /** Mirror all messages exchanged with the rendezvous server, for debugging */
+    pub rendezvous_tap: Option<rendezvous::MessageTap>,
+    /** Look up the rendezvous server's host name with this instead of the system resolver */
+    pub resolver: Option<crate::resolver::Resolver>,
+    /** Connect to the rendezvous server through this proxy, instead of the one from the [environment](crate::proxy::Proxy::from_env) */
+    pub proxy: Option<crate::proxy::Proxy>,
+    /** Report how far the connection got, see [`WormholeEvents`] */
+    pub events: Option<WormholeEvents>,
+    /** Generate codes from these words instead of the [default ones](wordlist::default_wordlist) */
+    pub wordlist: Option<wordlist::Wordlist>,
+    /**
+     * Ping the rendezvous server after this much silence, to keep the connection alive
+     *
+     * NATs and proxies tend to drop idle connections without telling anyone, which would leave us
+     * waiting for the peer forever. If the server does not answer the ping within the same time,
+     * we reconnect, and fail with [`RendezvousError::Unresponsive`] if that does not work either.
+     * `None` disables it, see [`rendezvous::DEFAULT_KEEPALIVE`] for a sensible value.
+     */
+    pub keepalive: Option<std::time::Duration>,
 }
 
 impl<V: serde::Serialize> AppConfig<V> {
@@ -385,6 +1114,36 @@ impl<V: serde::Serialize> AppConfig<V> {
         self.app_version = app_version;
         self
     }
+
+    pub fn rendezvous_tap(mut self, rendezvous_tap: rendezvous::MessageTap) -> Self {
+        self.rendezvous_tap = Some(rendezvous_tap);
+        self
+    }
+
+    pub fn resolver(mut self, resolver: crate::resolver::Resolver) -> Self {
+        self.resolver = Some(resolver);
+        self
+    }
+
+    pub fn proxy(mut self, proxy: crate::proxy::Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    pub fn events(mut self, events: WormholeEvents) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    pub fn wordlist(mut self, wordlist: wordlist::Wordlist) -> Self {
+        self.wordlist = Some(wordlist);
+        self
+    }
+
+    pub fn keepalive(mut self, keepalive: Option<std::time::Duration>) -> Self {
+        self.keepalive = keepalive;
+        self
+    }
 }
 
 /// Newtype wrapper for application IDs
@@ -485,6 +1244,60 @@ impl Phase {
     pub fn to_num(&self) -> Option<u64> {
         self.0.parse().ok()
     }
+
+    /** The `index`th phase named `name`, see [`Wormhole::send_message`] */
+    fn named(name: &str, index: u64) -> Self {
+        Self::check_name(name);
+        Phase(format!("{}-{}", name, index).into())
+    }
+
+    /** Panic on the names of phases the protocol uses itself */
+    fn check_name(name: &str) {
+        let phase = Phase(name.to_owned().into());
+        assert!(
+            phase.to_num().is_none() && !phase.is_version() && !phase.is_pake(),
+            "The phase '{}' is reserved",
+            phase
+        );
+    }
+}
+
+/** How many messages went through each named phase */
+#[derive(Debug, Default)]
+struct NamedPhases(std::collections::HashMap<String, u64>);
+
+impl NamedPhases {
+    /** The wire phase of the next message named `name` */
+    fn peek(&self, name: &str) -> Phase {
+        Phase::named(name, self.0.get(name).copied().unwrap_or(0))
+    }
+
+    /** Like `peek`, and count the message */
+    fn next(&mut self, name: &str) -> Phase {
+        let phase = self.peek(name);
+        *self.0.entry(name.to_owned()).or_default() += 1;
+        phase
+    }
+}
+
+/** How many received messages we keep for later, while waiting for the ones of another phase */
+const MAX_PENDING_MESSAGES: usize = 256;
+
+fn keep_pending(
+    pending: &mut std::collections::VecDeque<(Phase, Vec<u8>)>,
+    message: (Phase, Vec<u8>),
+) -> Result<(), WormholeError> {
+    if pending.len() >= MAX_PENDING_MESSAGES {
+        return Err(WormholeError::Protocol(
+            format!(
+                "Received more than {} messages that nobody asked for",
+                MAX_PENDING_MESSAGES
+            )
+            .into(),
+        ));
+    }
+    pending.push_back(message);
+    Ok(())
 }
 
 #[derive(PartialEq, Eq, Clone, Debug, Deserialize, Serialize, derive_more::Display)]
@@ -515,10 +1328,13 @@ impl Into<String> for Nameplate {
  * The part until the first dash is called the "nameplate" and is purely numeric.
  * The rest is the password and may be arbitrary, although dash-joining words from
  * a wordlist is a common convention.
+ *
+ * Codes entered by users should be parsed with [`str::parse`], which validates and
+ * normalizes them.
  */
 #[derive(PartialEq, Eq, Clone, Debug, derive_more::Display, derive_more::Deref)]
 #[display(fmt = "{}", _0)]
-pub struct Code(pub String);
+pub struct Code(String);
 
 impl Code {
     pub fn new(nameplate: &Nameplate, password: &str) -> Self {
@@ -526,13 +1342,114 @@ impl Code {
     }
 
     pub fn split(&self) -> (Nameplate, String) {
-        let mut iter = self.0.splitn(2, '-');
-        let nameplate = Nameplate::new(iter.next().unwrap());
-        let password = iter.next().unwrap();
-        (nameplate, password.to_string())
+        (self.nameplate(), self.password().to_string())
     }
 
     pub fn nameplate(&self) -> Nameplate {
         Nameplate::new(self.0.splitn(2, '-').next().unwrap())
     }
+
+    /** Everything after the nameplate */
+    pub fn password(&self) -> &str {
+        self.0
+            .split_once('-')
+            .map(|(_nameplate, password)| password)
+            .unwrap_or("")
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /**
+     * Check that the password consists of words from the default wordlist
+     *
+     * Custom passwords are perfectly valid codes, but for generated ones a word that is not
+     * in the list is a good hint for a typo.
+     */
+    pub fn check_wordlist(&self) -> Result<(), ParseCodeError> {
+        self.check_words(&wordlist::default_wordlist(2))
+    }
+
+    /** Like [`check_wordlist`](Self::check_wordlist), for codes generated from another wordlist */
+    pub fn check_words(&self, wordlist: &wordlist::Wordlist) -> Result<(), ParseCodeError> {
+        match self
+            .password()
+            .split('-')
+            .find(|word| wordlist.canonical_word(word).is_none())
+        {
+            Some(word) => Err(ParseCodeError::UnknownWord(word.into())),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::str::FromStr for Code {
+    type Err = ParseCodeError;
+
+    /**
+     * Parse and normalize a code
+     *
+     * Whitespace around the code is ignored. Spaces and the various Unicode dashes (which some
+     * keyboards insert automatically) are accepted as separators, and words from the wordlist
+     * are lowercased. Custom passwords are left untouched otherwise.
+     */
+    fn from_str(code: &str) -> Result<Self, Self::Err> {
+        Self::parse_with_wordlist(code, &wordlist::default_wordlist(2))
+    }
+}
+
+impl Code {
+    /** Like [`str::parse`], but lowercasing the words of another wordlist */
+    pub fn parse_with_wordlist(
+        code: &str,
+        wordlist: &wordlist::Wordlist,
+    ) -> Result<Self, ParseCodeError> {
+        let is_separator = |c: char| {
+            c == '-' || c.is_whitespace() || matches!(c, '\u{2010}'..='\u{2015}' | '\u{2212}')
+        };
+        let mut parts = code
+            .trim()
+            .split(is_separator)
+            .filter(|part| !part.is_empty());
+
+        let nameplate = parts.next().ok_or(ParseCodeError::Empty)?;
+        ensure!(
+            nameplate.chars().all(|c| c.is_ascii_digit()),
+            ParseCodeError::InvalidNameplate(nameplate.into())
+        );
+
+        let password = parts
+            .map(|word| wordlist.canonical_word(word).unwrap_or(word))
+            .collect::<Vec<_>>()
+            .join("-");
+        ensure!(!password.is_empty(), ParseCodeError::MissingPassword);
+
+        Ok(Code::new(&Nameplate::new(nameplate), &password))
+    }
+}
+
+impl From<Code> for String {
+    fn from(code: Code) -> Self {
+        code.0
+    }
+}
+
+impl AsRef<str> for Code {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+#[non_exhaustive]
+pub enum ParseCodeError {
+    #[error("The code is empty")]
+    Empty,
+    #[error("The code must start with a number, not '{}'", _0)]
+    InvalidNameplate(Box<str>),
+    #[error("The code is missing its password (the part after the number)")]
+    MissingPassword,
+    #[error("'{}' is not in the wordlist", _0)]
+    UnknownWord(Box<str>),
 }
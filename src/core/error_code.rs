@@ -0,0 +1,199 @@
+//! Telling errors apart without parsing their messages
+//!
+//! The error types are `#[non_exhaustive]` and their messages may change at any time. Their
+//! [`ErrorCode`]s stay the same, which makes them suitable for handling errors programmatically,
+//! for example to decide whether to retry or to show a localized message.
+
+/**
+ * A stable identifier of what went wrong
+ *
+ * Get it from [`WormholeError::code`](super::WormholeError::code) or
+ * [`TransferError::code`](crate::transfer::TransferError::code). New codes may get added, but
+ * existing ones keep their meaning and their [string form](Self::as_str).
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorCode {
+    /** The code we were asked to use or generate is not valid */
+    InvalidCode,
+    /** The rendezvous server could not be reached, or the connection to it broke down */
+    ServerConnection,
+    /** The rendezvous server sent an error or something we did not understand */
+    Server,
+    /** The server did not let us in */
+    PermissionDenied,
+    /** Somebody else is using the same code */
+    Crowded,
+    /** Somebody else is using the nameplate of a fixed code */
+    NameplateInUse,
+    /** The nameplate of the code does not exist (anymore) */
+    InvalidNameplate,
+    /** Both sides did not agree on the key, most likely because the code was mistyped */
+    WrongCode,
+    /** A message could not be decrypted */
+    Crypto,
+    /** The other side did not follow the protocol */
+    Protocol,
+    /** The wormhole has already been closed */
+    Closed,
+    /** No transit connection could be established in time */
+    TransitTimeout,
+    /** Establishing the transit connection failed for other reasons */
+    TransitConnect,
+    /** The transit connection broke down */
+    Transit,
+    /** The other side went away mid-transfer */
+    PeerDisconnected,
+    /** The receiver did not want the offered file */
+    Rejected,
+    /** The other side had an error */
+    PeerError,
+    /** The other side cancelled the transfer */
+    PeerCancelled,
+    /** We cancelled the transfer */
+    Cancelled,
+    /** The receiver did not answer the offer in time */
+    OfferExpired,
+    /** The other side offered something we can't receive */
+    UnsupportedOffer,
+    /** The received data got corrupted on the way */
+    Checksum,
+    /** The received data is intact, but not what was expected */
+    UnexpectedChecksum,
+    /** The sent file was smaller or larger than announced */
+    FileSize,
+    /** The files got modified while they were being sent */
+    FilesystemSkew,
+    /** Reading or writing local files failed */
+    IO,
+}
+
+/** Whose side an error is on */
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorOrigin {
+    /** Our side, for example the file system or the user */
+    Local,
+    /** The other side, or its user */
+    Peer,
+    /** The rendezvous server */
+    Server,
+    /** The network in between */
+    Network,
+}
+
+impl ErrorCode {
+    /** The string form, like `ERR_CROWDED`, for logs and scripts */
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::InvalidCode => "ERR_INVALID_CODE",
+            ErrorCode::ServerConnection => "ERR_SERVER_CONNECTION",
+            ErrorCode::Server => "ERR_SERVER",
+            ErrorCode::PermissionDenied => "ERR_PERMISSION_DENIED",
+            ErrorCode::Crowded => "ERR_CROWDED",
+            ErrorCode::NameplateInUse => "ERR_NAMEPLATE_IN_USE",
+            ErrorCode::InvalidNameplate => "ERR_INVALID_NAMEPLATE",
+            ErrorCode::WrongCode => "ERR_WRONG_CODE",
+            ErrorCode::Crypto => "ERR_CRYPTO",
+            ErrorCode::Protocol => "ERR_PROTOCOL",
+            ErrorCode::Closed => "ERR_CLOSED",
+            ErrorCode::TransitTimeout => "ERR_TRANSIT_TIMEOUT",
+            ErrorCode::TransitConnect => "ERR_TRANSIT_CONNECT",
+            ErrorCode::Transit => "ERR_TRANSIT",
+            ErrorCode::PeerDisconnected => "ERR_PEER_DISCONNECTED",
+            ErrorCode::Rejected => "ERR_REJECTED",
+            ErrorCode::PeerError => "ERR_PEER_ERROR",
+            ErrorCode::PeerCancelled => "ERR_PEER_CANCELLED",
+            ErrorCode::Cancelled => "ERR_CANCELLED",
+            ErrorCode::OfferExpired => "ERR_OFFER_EXPIRED",
+            ErrorCode::UnsupportedOffer => "ERR_UNSUPPORTED_OFFER",
+            ErrorCode::Checksum => "ERR_CHECKSUM",
+            ErrorCode::UnexpectedChecksum => "ERR_UNEXPECTED_CHECKSUM",
+            ErrorCode::FileSize => "ERR_FILE_SIZE",
+            ErrorCode::FilesystemSkew => "ERR_FILESYSTEM_SKEW",
+            ErrorCode::IO => "ERR_IO",
+        }
+    }
+
+    /**
+     * Whether simply trying again may work
+     *
+     * The other errors won't go away without changing something first, like the code, the
+     * file or the software on either side.
+     */
+    pub fn is_retryable(self) -> bool {
+        matches!(
+            self,
+            ErrorCode::ServerConnection
+                | ErrorCode::TransitTimeout
+                | ErrorCode::TransitConnect
+                | ErrorCode::Transit
+                | ErrorCode::PeerDisconnected
+                | ErrorCode::OfferExpired
+                | ErrorCode::Checksum
+                | ErrorCode::FilesystemSkew
+        )
+    }
+
+    pub fn origin(self) -> ErrorOrigin {
+        match self {
+            ErrorCode::Server
+            | ErrorCode::PermissionDenied
+            | ErrorCode::Crowded
+            | ErrorCode::NameplateInUse
+            | ErrorCode::InvalidNameplate => ErrorOrigin::Server,
+            ErrorCode::ServerConnection
+            | ErrorCode::TransitTimeout
+            | ErrorCode::TransitConnect
+            | ErrorCode::Transit
+            | ErrorCode::PeerDisconnected
+            | ErrorCode::Checksum => ErrorOrigin::Network,
+            ErrorCode::WrongCode
+            | ErrorCode::Crypto
+            | ErrorCode::Protocol
+            | ErrorCode::Rejected
+            | ErrorCode::PeerError
+            | ErrorCode::PeerCancelled
+            | ErrorCode::OfferExpired
+            | ErrorCode::UnsupportedOffer
+            | ErrorCode::UnexpectedChecksum
+            | ErrorCode::FileSize => ErrorOrigin::Peer,
+            ErrorCode::InvalidCode
+            | ErrorCode::Closed
+            | ErrorCode::Cancelled
+            | ErrorCode::FilesystemSkew
+            | ErrorCode::IO => ErrorOrigin::Local,
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{rendezvous::RendezvousError, WormholeError};
+
+    #[test]
+    fn test_error_codes() {
+        let crowded = WormholeError::ServerError(RendezvousError::Crowded).code();
+        assert_eq!(crowded, ErrorCode::Crowded);
+        assert_eq!(crowded.to_string(), "ERR_CROWDED");
+        assert_eq!(crowded.origin(), ErrorOrigin::Server);
+        assert!(!crowded.is_retryable());
+
+        let wrong_code = WormholeError::PakeFailed.code();
+        assert_eq!(wrong_code.as_str(), "ERR_WRONG_CODE");
+        assert_eq!(wrong_code.origin(), ErrorOrigin::Peer);
+
+        let unreachable = std::io::Error::from(std::io::ErrorKind::ConnectionRefused);
+        let unreachable = async_tungstenite::tungstenite::Error::Io(unreachable);
+        let unreachable = WormholeError::ServerError(RendezvousError::IO(unreachable)).code();
+        assert_eq!(unreachable, ErrorCode::ServerConnection);
+        assert!(unreachable.is_retryable());
+    }
+}
@@ -0,0 +1,98 @@
+//! Telling applications how far the connection got
+//!
+//! Subscribe to the [`WormholeEvents`] to follow a wormhole through its life, for example to show
+//! the user what it is waiting for. Only the state changes get reported, errors still come as the
+//! results of the functions that failed.
+
+use futures::channel::mpsc::UnboundedSender;
+use std::sync::{Arc, Mutex};
+
+/** A step in the life of a [`Wormhole`](super::Wormhole), in the order they happen */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum WormholeEvent {
+    /** Connecting to the rendezvous server */
+    ConnectingToRendezvous,
+    /** The mailbox is open, waiting for the peer to join it */
+    WaitingForPeer,
+    /**
+     * The peer opened the mailbox too, so we released the nameplate. The code can't be entered
+     * anymore from now on, and reconnecting to the rendezvous server only opens the mailbox again.
+     */
+    NameplateReleased,
+    /** The peer joined, and both sides agreed on the key */
+    KeyEstablished,
+    /** Connecting to the peer for the actual transfer, directly or via a relay */
+    TransitConnecting,
+    /** The transit connection is established, and the data is being sent */
+    Transferring,
+    /** The wormhole got closed. Whether the transfer succeeded is up to the function doing it */
+    Closed,
+}
+
+/**
+ * Where the [`WormholeEvent`]s of a wormhole go
+ *
+ * Put it into the [`AppConfig`](super::AppConfig) to also get the events from before the
+ * [`Wormhole`](super::Wormhole) exists. This is cheap to clone, all clones share the same subscribers.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct WormholeEvents(Arc<Mutex<Vec<UnboundedSender<WormholeEvent>>>>);
+
+impl WormholeEvents {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /**
+     * Get all events from now on
+     *
+     * The stream ends once the wormhole and all clones of this are gone.
+     */
+    pub fn subscribe(&self) -> impl futures::Stream<Item = WormholeEvent> {
+        let (sender, receiver) = futures::channel::mpsc::unbounded();
+        self.0.lock().unwrap().push(sender);
+        receiver
+    }
+
+    pub(crate) fn emit(&self, event: WormholeEvent) {
+        log::trace!("{:?}", event);
+        self.0
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.unbounded_send(event).is_ok());
+    }
+}
+
+/** Two event sinks are only equal if they are clones of each other */
+impl PartialEq for WormholeEvents {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for WormholeEvents {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::StreamExt;
+
+    #[async_std::test]
+    async fn test_events() {
+        let events = WormholeEvents::new();
+        events.emit(WormholeEvent::ConnectingToRendezvous);
+        let subscriber = events.subscribe();
+        let dropped = events.subscribe();
+        std::mem::drop(dropped);
+        events.emit(WormholeEvent::WaitingForPeer);
+        events.clone().emit(WormholeEvent::KeyEstablished);
+        assert_eq!(events.0.lock().unwrap().len(), 1);
+
+        std::mem::drop(events);
+        assert_eq!(
+            subscriber.collect::<Vec<_>>().await,
+            vec![WormholeEvent::WaitingForPeer, WormholeEvent::KeyEstablished]
+        );
+    }
+}
@@ -59,6 +59,25 @@ impl Key<WormholeKey> {
         );
         derived_key
     }
+
+    /**
+     * Derive the sub-key of the `index`th transit connection made over the same wormhole
+     *
+     * The records of each connection are numbered from zero, so every connection needs a key of
+     * its own. The first one is the [`derive_transit_key`](Self::derive_transit_key), like the
+     * Python implementation (which only ever makes one) uses it. The others are derived with the
+     * `"{appid}/transit-key/{index}"` purpose.
+     */
+    pub fn derive_nth_transit_key(
+        &self,
+        appid: &AppID,
+        index: u64,
+    ) -> Key<crate::transit::TransitKey> {
+        if index == 0 {
+            return self.derive_transit_key(appid);
+        }
+        self.derive_subkey_from_purpose(&format!("{}/transit-key/{}", appid, index))
+    }
 }
 
 impl<P: KeyPurpose> Key<P> {
@@ -79,6 +98,22 @@ impl<P: KeyPurpose> Key<P> {
             std::marker::PhantomData,
         )
     }
+
+    /**
+     * Derive `length` bytes of key material for some purpose
+     *
+     * Unlike [`derive_subkey_from_purpose`](Self::derive_subkey_from_purpose), this is not limited
+     * to keys of the size used by the wormhole. It gives the same result as `derive_key` of the
+     * Python implementation. The same purpose always gives the same bytes, so use a different one
+     * for everything. Panics if `length` is more than 8160 bytes.
+     */
+    pub fn derive_bytes(&self, purpose: &str, length: usize) -> Vec<u8> {
+        let mut bytes = vec![0; length];
+        Hkdf::<Sha256>::new(None, &self.0)
+            .expand(purpose.as_bytes(), &mut bytes)
+            .expect("Can't derive that many bytes");
+        bytes
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -244,11 +279,24 @@ mod test {
             "835b5df80ce9ca46908e8524fb308649122cfbcefbeaa7e65061c6ef08ee1b2a"
         );
 
-        /* The API doesn't support non-standard length keys anymore.
-         * But we may want to add that back in in the future.
-         */
-        // let dk2 = derive_key(&main, b"purpose2", 10);
-        // assert_eq!(hex::encode(dk2), "f2238e84315b47eb6279");
+        let main = Key::<WormholeKey>::new(Box::new(main));
+        let dk2 = main.derive_bytes("purpose2", 10);
+        assert_eq!(hex::encode(dk2), "f2238e84315b47eb6279");
+        assert_eq!(main.derive_bytes("purpose1", 32), dk1.to_vec());
+    }
+
+    #[test]
+    fn test_derive_nth_transit_key() {
+        let main = Key::<WormholeKey>::new(Box::new(secretbox::Key::from([7; 32])));
+        let appid = AppID::new("lothar.com/wormhole/text-or-file-xfer");
+        let first = main.derive_nth_transit_key(&appid, 0);
+        let second = main.derive_nth_transit_key(&appid, 1);
+        assert_eq!(first.to_hex(), main.derive_transit_key(&appid).to_hex());
+        assert_ne!(first.to_hex(), second.to_hex());
+        assert_ne!(
+            second.to_hex(),
+            main.derive_nth_transit_key(&appid, 2).to_hex()
+        );
     }
 
     #[test]
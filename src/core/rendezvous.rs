@@ -6,16 +6,39 @@ use async_tungstenite::tungstenite as ws2;
 use futures::prelude::*;
 use std::collections::VecDeque;
 
-use crate::core::{
-    server_messages::{InboundMessage, OutboundMessage, PermissionRequired, SubmitPermission},
-    AppID, EncryptedMessage, Mailbox, Mood, MySide, Nameplate, Phase,
+use crate::{
+    core::{
+        server_messages::{
+            InboundMessage, OutboundMessage, PermissionRequired, SubmitPermission, WelcomeMessage,
+        },
+        AppID, EncryptedMessage, Mailbox, Mood, MySide, Nameplate, Phase,
+    },
+    proxy::Proxy,
+    resolver::Resolver,
 };
 
+#[cfg(feature = "mailbox-server")]
+pub mod mailbox_server;
+
 /// Some rendezvous server you might use.
 ///
 /// Two applications that want to communicate with each other *must* use the same rendezvous server.
 pub const DEFAULT_RENDEZVOUS_SERVER: &str = "ws://relay.magic-wormhole.io:4000/v1";
 
+/** How often a server may send us elsewhere while connecting, to break loops */
+const MAX_REDIRECTS: usize = 3;
+
+/** How many seconds to wait before each attempt to reconnect, after the connection broke down */
+const RECONNECT_DELAYS: [u64; 7] = [0, 1, 2, 4, 8, 16, 30];
+
+/**
+ * A keepalive interval that works well with common NATs and proxies
+ *
+ * Many of them drop idle TCP connections after a few minutes, without telling either side.
+ * See [`AppConfig::keepalive`](crate::AppConfig::keepalive).
+ */
+pub const DEFAULT_KEEPALIVE: std::time::Duration = std::time::Duration::from_secs(60);
+
 #[derive(Debug, thiserror::Error)]
 #[non_exhaustive]
 pub enum RendezvousError {
@@ -30,14 +53,38 @@ pub enum RendezvousError {
     /// the server sent some bullshit message order
     #[error("Protocol error: {}", _0)]
     Protocol(Box<str>),
-    /// The server sent us an error message
+    /// The server sent us an error message not covered by the more specific variants
     #[error("Received error message from server: {}", _0)]
     Server(Box<str>),
+    /// Two other clients are already using this mailbox, probably somebody else is using (or guessed) the same code.
+    /// Get a new code and try again
+    #[error("The mailbox is crowded: somebody else is using the same code")]
+    Crowded,
+    /// The nameplate of a fixed code is already in use, see [`Wormhole::connect_with_fixed_code`](crate::Wormhole::connect_with_fixed_code)
+    #[error("The nameplate {} is already in use", _0)]
+    NameplateInUse(Nameplate),
+    /// The server did not accept the nameplate, for example because we released it already
+    #[error("Invalid nameplate: {}", _0)]
+    InvalidNameplate(Box<str>),
+    /// The server requires a permission we failed to get
+    #[error("Permission denied by the server: {}", _0)]
+    PermissionDenied(Box<str>),
+    /// The server turned us away right in its welcome, for example because it shut down or
+    /// because our version is too old. The message is meant for the user
+    #[error("The server does not want us: {}", _0)]
+    Welcome(Box<str>),
+    /// We sent a command before binding to the server, this is a bug
+    #[error("The server expected us to bind first")]
+    Unbound,
     #[error(
         "Server wants one of {:?} for permissions, but we don't suppport any of these",
         _0
     )]
     Login(Vec<String>),
+    /// The server did not answer our keepalive ping, so the connection probably died silently.
+    /// See [`AppConfig::keepalive`](crate::AppConfig::keepalive)
+    #[error("The rendezvous server stopped responding, the connection seems to be dead")]
+    Unresponsive,
     #[error("Websocket IO error")]
     IO(
         #[from]
@@ -58,27 +105,232 @@ impl RendezvousError {
         ))
     }
 
+    /**
+     * Map the error messages of the server to the more specific variants where possible
+     *
+     * Only the exact messages of the Python server (and our own mailbox server) are recognized,
+     * everything else is kept as it is in [`Server`](Self::Server).
+     */
     pub(self) fn server(error: impl Into<Box<str>>) -> Self {
-        Self::Server(error.into())
+        let error = error.into();
+        match &*error {
+            "crowded" => Self::Crowded,
+            "must bind first" => Self::Unbound,
+            "reclaimed"
+            | "claim requires 'nameplate'"
+            | "release and claim must use same nameplate"
+            | "release without nameplate must follow claim"
+            | "release without a nameplate" => Self::InvalidNameplate(error),
+            "submit-permission failed" => Self::PermissionDenied(error),
+            _ => Self::Server(error),
+        }
     }
 }
 
 type MessageQueue = VecDeque<EncryptedMessage>;
 
+/**
+ * Only follow redirects that keep the scheme of the current connection
+ *
+ * Otherwise, a server (or somebody in between) could send us from `wss://` to plain `ws://`.
+ */
+fn check_redirect(current: &url::Url, redirect: &url::Url) -> Result<(), RendezvousError> {
+    if current.scheme() != redirect.scheme() {
+        return Err(RendezvousError::protocol(format!(
+            "Refusing to follow the redirect from {} to {}, it changes the scheme",
+            current, redirect
+        )));
+    }
+    Ok(())
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TapDirection {
+    Sent,
+    Received,
+}
+
+/**
+ * A hook that gets a copy of every raw message exchanged with the rendezvous server
+ *
+ * This is meant for debugging interoperability problems with servers. The encrypted
+ * message bodies are redacted, everything else is passed through as-is.
+ */
+#[derive(Clone)]
+pub struct MessageTap(std::sync::Arc<dyn Fn(TapDirection, &str) + Send + Sync>);
+
+impl MessageTap {
+    pub fn new(tap: impl Fn(TapDirection, &str) + Send + Sync + 'static) -> Self {
+        MessageTap(std::sync::Arc::new(tap))
+    }
+
+    fn mirror(&self, direction: TapDirection, message: &str) {
+        (self.0)(direction, &redact(message))
+    }
+}
+
+impl std::fmt::Debug for MessageTap {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt.write_str("MessageTap")
+    }
+}
+
+/* Two taps are only equal if they are the same instance */
+impl PartialEq for MessageTap {
+    fn eq(&self, other: &Self) -> bool {
+        std::sync::Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for MessageTap {}
+
+/** Replace the (encrypted) body of peer messages */
+fn redact(message: &str) -> String {
+    let mut value = match serde_json::from_str::<serde_json::Value>(message) {
+        Ok(value) => value,
+        Err(_) => return message.to_owned(),
+    };
+    match value.get_mut("body") {
+        Some(body @ serde_json::Value::String(_)) => {
+            let length = body.as_str().unwrap().len() / 2;
+            *body = format!("<redacted, {} bytes>", length).into();
+            value.to_string()
+        },
+        _ => message.to_owned(),
+    }
+}
+
 struct WsConnection {
     connection: async_tungstenite::WebSocketStream<async_tungstenite::async_std::ConnectStream>,
+    tap: Option<MessageTap>,
+    /** Ping the server after this much silence, see [`WsConnection::receive_message`] */
+    keepalive: Option<std::time::Duration>,
+    /** Where the server asked us to reconnect to, see [`RendezvousServer::follow_redirect`] */
+    redirect: Option<url::Url>,
 }
 
 impl WsConnection {
+    /**
+     * Connect to the server, and do the welcome and permission part
+     *
+     * If the welcome message tells us that the server moved, we go there instead.
+     */
+    async fn open(
+        relay_url: &str,
+        tap: Option<MessageTap>,
+        resolver: Option<&Resolver>,
+        proxy: Option<&Proxy>,
+        keepalive: Option<std::time::Duration>,
+    ) -> Result<(Self, WelcomeMessage), RendezvousError> {
+        let mut relay_url = relay_url.to_owned();
+        for _ in 0..=MAX_REDIRECTS {
+            let url = url::Url::parse(&relay_url).map_err(|_| {
+                ws2::Error::Url(ws2::error::UrlError::UnableToConnect(relay_url.clone()))
+            })?;
+            let host = url
+                .host_str()
+                .ok_or_else(|| ws2::Error::Url(ws2::error::UrlError::NoHostName))?;
+            let port = url
+                .port_or_known_default()
+                .ok_or_else(|| ws2::Error::Url(ws2::error::UrlError::UnsupportedUrlScheme))?;
+            /* An explicit proxy wins over the one from the environment */
+            let env_proxy = match proxy {
+                Some(_) => None,
+                None => Proxy::from_env(&url),
+            };
+            let socket = crate::proxy::connect(proxy.or(env_proxy.as_ref()), resolver, host, port)
+                .await
+                .map_err(ws2::Error::Io)?;
+            let (connection, _) =
+                async_tungstenite::async_std::client_async_tls_with_connector_and_config(
+                    relay_url.as_str(),
+                    socket,
+                    None,
+                    None,
+                )
+                .await?;
+            let mut connection = WsConnection {
+                connection,
+                tap: tap.clone(),
+                keepalive,
+                redirect: None,
+            };
+
+            let welcome = match connection.receive_message_some().await? {
+                InboundMessage::Welcome { welcome } => welcome,
+                other => {
+                    return Err(RendezvousError::protocol(format!(
+                        "First message server sends must be 'welcome', but was '{}'",
+                        other
+                    )))
+                },
+            };
+
+            if let Some(error) = &welcome.error {
+                return Err(RendezvousError::Welcome(error.as_str().into()));
+            }
+
+            if let Some(redirect) = &welcome.redirect {
+                check_redirect(&url, redirect)?;
+                log::info!("The server moved to {}, connecting there instead", redirect);
+                relay_url = redirect.to_string();
+                continue;
+            }
+
+            match &welcome.permission_required {
+                /* Prefer not having to do any work */
+                Some(PermissionRequired { none: true, .. }) => (),
+                Some(PermissionRequired {
+                    hashcash: Some(hashcash),
+                    ..
+                }) => {
+                    log::info!(
+                        "The server requires a hashcash stamp with {} bits, minting one",
+                        hashcash.bits
+                    );
+                    /* This may take a while, don't block the executor meanwhile */
+                    let (resource, bits) = (hashcash.resource.clone(), hashcash.bits);
+                    let token = async_std::task::spawn_blocking(move || {
+                        crate::util::hashcash(resource, bits)
+                    })
+                    .await;
+                    connection
+                        .send_message(
+                            &OutboundMessage::SubmitPermission(SubmitPermission::Hashcash {
+                                stamp: token,
+                            }),
+                            None,
+                        )
+                        .await?;
+                },
+                Some(PermissionRequired { other, .. }) => {
+                    /* We can't actually log in :/ */
+                    return Err(RendezvousError::Login(
+                        // TODO use `into_keys` once stable and remove the `cloned`
+                        other.keys().cloned().collect(),
+                    ));
+                },
+                None => (),
+            }
+
+            return Ok((connection, welcome));
+        }
+        Err(RendezvousError::protocol(
+            "The server redirected us too often",
+        ))
+    }
+
     async fn send_message(
         &mut self,
         message: &OutboundMessage,
         queue: Option<&mut MessageQueue>,
     ) -> Result<(), RendezvousError> {
         log::debug!("Sending {}", message);
-        self.connection
-            .send(ws2::Message::Text(serde_json::to_string(message).unwrap()))
-            .await?;
+        let message = serde_json::to_string(message).unwrap();
+        if let Some(tap) = &self.tap {
+            tap.mirror(TapDirection::Sent, &message);
+        }
+        self.connection.send(ws2::Message::Text(message)).await?;
         self.receive_ack(queue).await?;
         Ok(())
     }
@@ -120,6 +372,9 @@ impl WsConnection {
         loop {
             let message = self.receive_message().await?;
             match message {
+                Some(InboundMessage::Nameplates { nameplates }) => {
+                    break Ok(RendezvousReply::Nameplates(nameplates))
+                },
                 Some(InboundMessage::Allocated { nameplate }) => {
                     break Ok(RendezvousReply::Allocated(nameplate))
                 },
@@ -139,7 +394,7 @@ impl WsConnection {
                     },
                 },
                 Some(InboundMessage::Error { error, orig: _ }) => {
-                    break Err(RendezvousError::Server(error.into()));
+                    break Err(RendezvousError::server(error));
                 },
                 Some(other) => {
                     break Err(RendezvousError::protocol(format!(
@@ -160,14 +415,36 @@ impl WsConnection {
         }
     }
 
+    /**
+     * Receive the next message from the server, `None` for the ones that need no handling
+     *
+     * With a keepalive, we ping the server after that much silence. If it does not answer
+     * within the same time, the connection is considered dead.
+     */
     async fn receive_message(&mut self) -> Result<Option<InboundMessage>, RendezvousError> {
-        let message = self
-            .connection
-            .next()
-            .await
-            .expect("TODO this should always be Some")?;
+        let message = match self.keepalive {
+            None => self.connection.next().await,
+            Some(keepalive) => {
+                let mut pinged = false;
+                loop {
+                    match async_std::future::timeout(keepalive, self.connection.next()).await {
+                        Ok(message) => break message,
+                        Err(_) if !pinged => {
+                            log::debug!("Nothing from the server for {:?}, pinging it", keepalive);
+                            self.connection.send(ws2::Message::Ping(Vec::new())).await?;
+                            pinged = true;
+                        },
+                        Err(_) => return Err(RendezvousError::Unresponsive),
+                    }
+                }
+            },
+        }
+        .ok_or(ws2::Error::ConnectionClosed)??;
         match message {
             ws2::Message::Text(message_plain) => {
+                if let Some(tap) = &self.tap {
+                    tap.mirror(TapDirection::Received, &message_plain);
+                }
                 let message = serde_json::from_str(&message_plain)?;
                 log::debug!("Received {}", message);
                 match message {
@@ -176,13 +453,19 @@ impl WsConnection {
                         Ok(None)
                     },
                     InboundMessage::Error { error, orig: _ } => Err(RendezvousError::server(error)),
+                    /* Finish what we're doing here first */
+                    InboundMessage::Redirect { url } => {
+                        log::info!("The server is moving to {}", url);
+                        self.redirect = Some(url);
+                        Ok(None)
+                    },
                     message => Ok(Some(message)),
                 }
             },
             ws2::Message::Binary(_) => Err(RendezvousError::protocol(
                 "WebSocket messages must be UTF-8 encoded text",
             )),
-            /* Ignore ping pong for now */
+            /* The pongs only need to arrive, and tungstenite answers the pings */
             ws2::Message::Ping(_) => Ok(None),
             ws2::Message::Pong(_) => Ok(None),
             ws2::Message::Close(_) => {
@@ -195,6 +478,8 @@ impl WsConnection {
 
 #[derive(Clone, Debug, derive_more::Display)]
 enum RendezvousReply {
+    #[display(fmt = "Nameplates({:?})", _0)]
+    Nameplates(Vec<Nameplate>),
     Allocated(Nameplate),
     Released,
     Claimed(Mailbox),
@@ -231,10 +516,33 @@ impl MailboxMachine {
     }
 }
 
+/**
+ * A connection to the rendezvous server
+ *
+ * Use [`shutdown`](Self::shutdown) to close it. If it gets dropped instead while a mailbox is
+ * still open, the mailbox gets closed in the background on a best-effort basis, so that the
+ * server can clean up (see [`Drop`](#impl-Drop)).
+ *
+ * If the server announces that it moved (with a `redirect` message), we transparently reconnect
+ * to the new address before the next operation, and open our mailbox there again.
+ *
+ * The same happens if the connection breaks down while sending or receiving peer messages, for
+ * example because the WiFi is flaky or the laptop went to sleep. We keep trying for about a
+ * minute before giving up. The server sends us all messages of the mailbox again, and both
+ * sides ignore the ones they already got, so the peer does not notice.
+ */
 pub struct RendezvousServer {
-    connection: WsConnection,
+    /* Only `None` once dropped */
+    connection: Option<WsConnection>,
     state: Option<MailboxMachine>,
     side: MySide,
+    /* For reconnecting */
+    url: String,
+    appid: AppID,
+    resolver: Option<Resolver>,
+    proxy: Option<Proxy>,
+    keepalive: Option<std::time::Duration>,
+    transit_relays: Vec<url::Url>,
 }
 
 impl std::fmt::Debug for RendezvousServer {
@@ -242,6 +550,7 @@ impl std::fmt::Debug for RendezvousServer {
         fmt.debug_struct("RendezvousServer")
             .field("state", &self.state)
             .field("side", &self.side)
+            .field("transit_relays", &self.transit_relays)
             .finish()
     }
 }
@@ -252,50 +561,32 @@ impl RendezvousServer {
      *
      * This does the permission negotiation part if required and binds the
      * connection to the given `appid`.
+     *
+     * Connecting to `wss://` URLs requires the `tls` feature.
      */
     pub async fn connect(
         appid: &AppID,
         relay_url: &str,
     ) -> Result<(Self, Option<String>), RendezvousError> {
-        let side = MySide::generate();
-        let (connection, _) = async_tungstenite::async_std::connect_async(relay_url).await?;
-        let mut connection = WsConnection { connection };
-
-        let welcome = match connection.receive_message_some().await? {
-            InboundMessage::Welcome { welcome } => welcome,
-            other => {
-                return Err(RendezvousError::protocol(format!(
-                    "First message server sends must be 'welcome', but was '{}'",
-                    other
-                )))
-            },
-        };
+        Self::connect_custom(appid, relay_url, None, None, None, None).await
+    }
 
-        match welcome.permission_required {
-            Some(PermissionRequired {
-                hashcash: Some(hashcash),
-                ..
-            }) => {
-                let token = crate::util::hashcash(hashcash.resource, hashcash.bits);
-                connection
-                    .send_message(
-                        &OutboundMessage::SubmitPermission(SubmitPermission::Hashcash {
-                            stamp: token.to_string(),
-                        }),
-                        None,
-                    )
-                    .await?;
-            },
-            Some(PermissionRequired { none: true, .. }) => (),
-            Some(PermissionRequired { other, .. }) => {
-                /* We can't actually log in :/ */
-                return Err(RendezvousError::Login(
-                    // TODO use `into_keys` once stable and remove the `cloned`
-                    other.keys().cloned().collect(),
-                ));
-            },
-            None => (),
-        }
+    /**
+     * Like [`connect`](Self::connect), but optionally mirror all messages to a [`MessageTap`],
+     * look up the server's host name with a custom [`Resolver`], connect through a [`Proxy`]
+     * or detect dead connections with a keepalive (see [`AppConfig::keepalive`](crate::AppConfig::keepalive))
+     */
+    pub async fn connect_custom(
+        appid: &AppID,
+        relay_url: &str,
+        tap: Option<MessageTap>,
+        resolver: Option<&Resolver>,
+        proxy: Option<&Proxy>,
+        keepalive: Option<std::time::Duration>,
+    ) -> Result<(Self, Option<String>), RendezvousError> {
+        let side = MySide::generate();
+        let (mut connection, welcome) =
+            WsConnection::open(relay_url, tap, resolver, proxy, keepalive).await?;
 
         connection
             .send_message(&OutboundMessage::bind(appid.clone(), side.clone()), None)
@@ -305,9 +596,15 @@ impl RendezvousServer {
 
         Ok((
             Self {
-                connection,
+                connection: Some(connection),
                 state: None,
                 side,
+                url: relay_url.to_owned(),
+                appid: appid.clone(),
+                resolver: resolver.cloned(),
+                proxy: proxy.cloned(),
+                keepalive,
+                transit_relays: welcome.transit_relays,
             },
             welcome.motd,
         ))
@@ -318,14 +615,140 @@ impl RendezvousServer {
         &self.side
     }
 
+    /** Transit relay servers the server recommends in its welcome message (if any) */
+    pub fn transit_relays(&self) -> &[url::Url] {
+        &self.transit_relays
+    }
+
+    /**
+     * Reconnect to the new address, if the server told us that it moved
+     *
+     * The redirect is only forgotten together with the old connection once we got through, so
+     * that the next operation tries again if this one got cancelled or failed.
+     */
+    async fn follow_redirect(&mut self) -> Result<(), RendezvousError> {
+        let url = match &self.connection.as_ref().unwrap().redirect {
+            Some(url) => url.clone(),
+            None => return Ok(()),
+        };
+        let current = url::Url::parse(&self.url).map_err(|_| {
+            ws2::Error::Url(ws2::error::UrlError::UnableToConnect(self.url.clone()))
+        })?;
+        if let Err(error) = check_redirect(&current, &url) {
+            /* Stay where we are, and don't complain again */
+            self.connection.as_mut().unwrap().redirect = None;
+            return Err(error);
+        }
+        log::info!("Reconnecting to {}", url);
+        self.reopen(url.to_string()).await?;
+        log::info!("Reconnected to {}", url);
+        Ok(())
+    }
+
+    /**
+     * Try to get the connection back after it broke down, before giving up with `error`
+     *
+     * This only applies to an open mailbox, everything else can simply be done again.
+     * If this gets cancelled, the broken connection stays in place, and the next operation
+     * runs into the error again and recovers then.
+     */
+    async fn recover(&mut self, mut error: RendezvousError) -> Result<(), RendezvousError> {
+        if !matches!(
+            error,
+            RendezvousError::IO(_) | RendezvousError::Unresponsive
+        ) || self.state.is_none()
+        {
+            return Err(error);
+        }
+        log::warn!("Lost the connection to the rendezvous server: {}", error);
+        for delay in RECONNECT_DELAYS.iter() {
+            async_std::task::sleep(std::time::Duration::from_secs(*delay)).await;
+            match self.reopen(self.url.clone()).await {
+                Ok(()) => {
+                    log::info!("Reconnected to {}", self.url);
+                    return Ok(());
+                },
+                Err(next_error @ RendezvousError::IO(_)) => {
+                    log::debug!("Reconnecting failed: {}", next_error);
+                    /* That one tells more about what went wrong in the first place */
+                    if !matches!(error, RendezvousError::Unresponsive) {
+                        error = next_error;
+                    }
+                },
+                Err(next_error) => return Err(next_error),
+            }
+        }
+        Err(error)
+    }
+
+    /**
+     * Connect to `url` again, in place of the current connection
+     *
+     * The server is expected to have our nameplate and mailbox. We bind with the same side,
+     * claim the nameplate (if we still hold it) and open the mailbox again. The server then
+     * sends us all messages of the mailbox again, duplicates get filtered out as usual.
+     *
+     * Nothing changes until the new connection is ready, so this is cancellation safe: peer
+     * messages received on the way are kept in the queue of the mailbox.
+     */
+    async fn reopen(&mut self, url: String) -> Result<(), RendezvousError> {
+        let tap = self.connection.as_ref().unwrap().tap.clone();
+        let (mut connection, welcome) = WsConnection::open(
+            &url,
+            tap,
+            self.resolver.as_ref(),
+            self.proxy.as_ref(),
+            self.keepalive,
+        )
+        .await?;
+        connection
+            .send_message(
+                &OutboundMessage::bind(self.appid.clone(), self.side.clone()),
+                None,
+            )
+            .await?;
+        if let Some(state) = &mut self.state {
+            if let Some(nameplate) = &state.nameplate {
+                connection
+                    .send_message(
+                        &OutboundMessage::claim(nameplate.clone()),
+                        Some(&mut state.queue),
+                    )
+                    .await?;
+                match connection.receive_reply(Some(&mut state.queue)).await? {
+                    RendezvousReply::Claimed(mailbox) if mailbox == state.mailbox => (),
+                    other => return Err(RendezvousError::invalid_message("claimed", other)),
+                }
+            }
+            connection
+                .send_message(
+                    &OutboundMessage::open(state.mailbox.clone()),
+                    Some(&mut state.queue),
+                )
+                .await?;
+        }
+
+        /* Simply hang up on the old connection, without closing the mailbox which lives on */
+        self.connection = Some(connection);
+        self.url = url;
+        if !welcome.transit_relays.is_empty() {
+            self.transit_relays = welcome.transit_relays;
+        }
+        Ok(())
+    }
+
     async fn send_message(&mut self, message: &OutboundMessage) -> Result<(), RendezvousError> {
         self.connection
+            .as_mut()
+            .unwrap()
             .send_message(message, self.state.as_mut().map(|state| &mut state.queue))
             .await
     }
 
     async fn receive_reply(&mut self) -> Result<RendezvousReply, RendezvousError> {
         self.connection
+            .as_mut()
+            .unwrap()
             .receive_reply(self.state.as_mut().map(|state| &mut state.queue))
             .await
     }
@@ -335,8 +758,16 @@ impl RendezvousServer {
         phase: Phase,
         body: Vec<u8>,
     ) -> Result<(), RendezvousError> {
-        self.send_message(&OutboundMessage::Add { body, phase })
-            .await
+        self.follow_redirect().await?;
+        let message = OutboundMessage::Add { body, phase };
+        match self.send_message(&message).await {
+            Err(error) => {
+                self.recover(error).await?;
+                /* The server may have gotten it already, but the peer ignores duplicates */
+                self.send_message(&message).await
+            },
+            ok => ok,
+        }
     }
 
     pub async fn next_peer_message_some(&mut self) -> Result<EncryptedMessage, RendezvousError> {
@@ -347,7 +778,14 @@ impl RendezvousServer {
         }
     }
 
+    /**
+     * Receive the next message from the peer, `None` for duplicates and messages that need no handling
+     *
+     * This is cancellation safe: no message gets lost if the future is dropped before it completes,
+     * not even while following a redirect or recovering a broken connection.
+     */
     pub async fn next_peer_message(&mut self) -> Result<Option<EncryptedMessage>, RendezvousError> {
+        self.follow_redirect().await?;
         let machine = &mut self
             .state
             .as_mut()
@@ -359,7 +797,15 @@ impl RendezvousServer {
                 return Ok(None);
             }
         }
-        match self.connection.receive_message().await? {
+        let message = match self.connection.as_mut().unwrap().receive_message().await {
+            Ok(message) => message,
+            Err(error) => {
+                self.recover(error).await?;
+                return Ok(None);
+            },
+        };
+        let machine = self.state.as_mut().unwrap();
+        match message {
             Some(InboundMessage::Message(message)) => {
                 if machine.receive_message(&message, &self.side) {
                     Ok(Some(message))
@@ -375,12 +821,23 @@ impl RendezvousServer {
         }
     }
 
+    /** List the nameplates that are currently in use on the server */
+    pub async fn list_nameplates(&mut self) -> Result<Vec<Nameplate>, RendezvousError> {
+        self.follow_redirect().await?;
+        self.send_message(&OutboundMessage::List).await?;
+        match self.receive_reply().await? {
+            RendezvousReply::Nameplates(nameplates) => Ok(nameplates),
+            other => Err(RendezvousError::invalid_message("nameplates", other)),
+        }
+    }
+
     /** Allocate a nameplate, claim the mailbox and open it */
     pub async fn allocate_claim_open(&mut self) -> Result<(Nameplate, Mailbox), RendezvousError> {
         assert!(
             self.state.is_none(),
             "Can only call in initial state, and only once"
         );
+        self.follow_redirect().await?;
 
         self.send_message(&OutboundMessage::Allocate).await?;
         let nameplate = match self.receive_reply().await? {
@@ -413,6 +870,7 @@ impl RendezvousServer {
             self.state.is_none(),
             "Can only call in initial state, and only once"
         );
+        self.follow_redirect().await?;
 
         self.send_message(&OutboundMessage::claim(nameplate.clone()))
             .await?;
@@ -433,6 +891,18 @@ impl RendezvousServer {
         Ok(mailbox)
     }
 
+    /** The nameplate we claimed, until it gets released */
+    pub fn nameplate(&self) -> Option<&Nameplate> {
+        self.state
+            .as_ref()
+            .and_then(|state| state.nameplate.as_ref())
+    }
+
+    /** The mailbox we opened, if any */
+    pub fn mailbox(&self) -> Option<&Mailbox> {
+        self.state.as_ref().map(|state| &state.mailbox)
+    }
+
     pub fn needs_nameplate_release(&self) -> bool {
         self.state
             .as_ref()
@@ -446,6 +916,7 @@ impl RendezvousServer {
             .as_mut()
             .and_then(|state| state.nameplate.clone())
             .expect("Can only release an allocated nameplate, and only once");
+        self.follow_redirect().await?;
 
         use std::ops::Deref;
         self.send_message(&OutboundMessage::release(nameplate.deref().deref()))
@@ -455,6 +926,10 @@ impl RendezvousServer {
             other => return Err(RendezvousError::invalid_message("released", other)),
         };
         self.state.as_mut().unwrap().nameplate = None;
+        log::info!(
+            "Released nameplate {}, the code cannot be entered anymore",
+            nameplate
+        );
         Ok(())
     }
 
@@ -468,6 +943,7 @@ impl RendezvousServer {
             self.state.is_none(),
             "Can only call in initial state, and only once"
         );
+        self.follow_redirect().await?;
         self.send_message(&OutboundMessage::open(mailbox.clone()))
             .await?;
         self.state = Some(MailboxMachine {
@@ -480,33 +956,214 @@ impl RendezvousServer {
     }
 
     pub async fn shutdown(mut self, mood: Mood) -> Result<(), RendezvousError> {
-        if let Some(MailboxMachine {
-            nameplate,
-            mailbox,
-            mut queue,
-            ..
-        }) = self.state
-        {
-            if let Some(nameplate) = nameplate {
-                self.connection
-                    .send_message(&OutboundMessage::release(nameplate), Some(&mut queue))
-                    .await?;
-                match self.connection.receive_reply(Some(&mut queue)).await? {
-                    RendezvousReply::Released => (),
-                    other => return Err(RendezvousError::invalid_message("released", other)),
-                };
-            }
+        self.follow_redirect().await?;
+        let connection = self.connection.take().unwrap();
+        close(connection, self.state.take(), mood).await
+    }
+}
 
-            self.connection
-                .send_message(&OutboundMessage::close(mailbox, mood), Some(&mut queue))
+/**
+ * Close the mailbox in the background
+ *
+ * The nameplate gets released if it is still claimed, and the mailbox closed with a "lonely"
+ * mood if the peer never showed up, "errory" otherwise. This needs a running async-std
+ * executor, and may not finish if the program exits right away. Errors only get logged.
+ */
+impl Drop for RendezvousServer {
+    fn drop(&mut self) {
+        if let (Some(connection), Some(state)) = (self.connection.take(), self.state.take()) {
+            let mood = if state.nameplate.is_some() {
+                Mood::Lonely
+            } else {
+                Mood::Errory
+            };
+            log::debug!(
+                "Dropped the connection without shutting it down, closing mailbox {} in the background",
+                state.mailbox
+            );
+            async_std::task::spawn(async move {
+                if let Err(error) = close(connection, Some(state), mood).await {
+                    log::debug!("Failed to close the mailbox: {}", error);
+                }
+            });
+        }
+    }
+}
+
+async fn close(
+    mut connection: WsConnection,
+    state: Option<MailboxMachine>,
+    mood: Mood,
+) -> Result<(), RendezvousError> {
+    if let Some(MailboxMachine {
+        nameplate,
+        mailbox,
+        mut queue,
+        ..
+    }) = state
+    {
+        if let Some(nameplate) = nameplate {
+            connection
+                .send_message(&OutboundMessage::release(nameplate), Some(&mut queue))
                 .await?;
-            match self.connection.receive_reply(Some(&mut queue)).await? {
-                RendezvousReply::Closed => (),
-                other => return Err(RendezvousError::invalid_message("closed", other)),
+            match connection.receive_reply(Some(&mut queue)).await? {
+                RendezvousReply::Released => (),
+                other => return Err(RendezvousError::invalid_message("released", other)),
             };
         }
 
-        self.connection.connection.close(None).await?;
-        Ok(())
+        connection
+            .send_message(&OutboundMessage::close(mailbox, mood), Some(&mut queue))
+            .await?;
+        match connection.receive_reply(Some(&mut queue)).await? {
+            RendezvousReply::Closed => (),
+            other => return Err(RendezvousError::invalid_message("closed", other)),
+        };
+    }
+
+    connection.connection.close(None).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(
+            redact(r#"{"type":"add","phase":"pake","body":"deadbeef"}"#),
+            r#"{"body":"<redacted, 4 bytes>","phase":"pake","type":"add"}"#
+        );
+        let welcome = r#"{"type": "welcome", "welcome": {}}"#;
+        assert_eq!(redact(welcome), welcome);
+        assert_eq!(redact("garbage"), "garbage");
+    }
+
+    #[async_std::test]
+    async fn test_welcome_error() {
+        use futures::SinkExt;
+
+        let listener = async_std::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let url = format!("ws://{}/v1", listener.local_addr().unwrap());
+        let server = async_std::task::spawn(async move {
+            let socket = listener.accept().await.unwrap().0;
+            let mut connection = async_tungstenite::accept_async(socket).await.unwrap();
+            let welcome = r#"{"type": "welcome", "welcome": {"error": "Please upgrade"}}"#;
+            connection
+                .send(ws2::Message::Text(welcome.into()))
+                .await
+                .unwrap();
+        });
+
+        let appid = AppID::new("lothar.com/wormhole/text-or-file-xfer");
+        let error = RendezvousServer::connect(&appid, &url).await.unwrap_err();
+        assert!(matches!(error, RendezvousError::Welcome(error) if &*error == "Please upgrade"));
+        server.await;
+    }
+
+    #[async_std::test]
+    async fn test_keepalive() {
+        use futures::SinkExt;
+
+        let listener = async_std::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let url = format!("ws://{}/v1", listener.local_addr().unwrap());
+        let (pinged_sender, pinged) = futures::channel::oneshot::channel();
+        async_std::task::spawn(async move {
+            let socket = listener.accept().await.unwrap().0;
+            let mut connection = async_tungstenite::accept_async(socket).await.unwrap();
+            for message in [
+                r#"{"type": "welcome", "welcome": {}}"#,
+                r#"{"type": "ack"}"#,
+            ] {
+                connection
+                    .send(ws2::Message::Text(message.into()))
+                    .await
+                    .unwrap();
+            }
+            /* Bind, then the list that never gets answered */
+            connection.next().await.unwrap().unwrap();
+            connection.next().await.unwrap().unwrap();
+            let ping = connection.next().await.unwrap().unwrap();
+            pinged_sender.send(ping).unwrap();
+            /* Hang, like a connection that silently died */
+            futures::future::pending::<()>().await;
+        });
+
+        let appid = AppID::new("lothar.com/wormhole/text-or-file-xfer");
+        let keepalive = std::time::Duration::from_millis(100);
+        let (mut server, _welcome) =
+            RendezvousServer::connect_custom(&appid, &url, None, None, None, Some(keepalive))
+                .await
+                .unwrap();
+        let error = server.list_nameplates().await.unwrap_err();
+        assert!(matches!(error, RendezvousError::Unresponsive));
+        assert!(pinged.await.unwrap().is_ping());
+    }
+
+    #[async_std::test]
+    async fn test_redirect_scheme() {
+        use futures::SinkExt;
+
+        let https = url::Url::parse("wss://relay.example.org/v1").unwrap();
+        check_redirect(&https, &"wss://other.example.org:4000/v1".parse().unwrap()).unwrap();
+        assert!(check_redirect(&https, &"ws://other.example.org/v1".parse().unwrap()).is_err());
+
+        let listener = async_std::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let url = format!("ws://{}/v1", listener.local_addr().unwrap());
+        let server = async_std::task::spawn(async move {
+            let socket = listener.accept().await.unwrap().0;
+            let mut connection = async_tungstenite::accept_async(socket).await.unwrap();
+            let welcome =
+                r#"{"type": "welcome", "welcome": {"redirect": "wss://other.example.org/v1"}}"#;
+            connection
+                .send(ws2::Message::Text(welcome.into()))
+                .await
+                .unwrap();
+        });
+
+        let appid = AppID::new("lothar.com/wormhole/text-or-file-xfer");
+        let error = RendezvousServer::connect(&appid, &url).await.unwrap_err();
+        assert!(matches!(error, RendezvousError::Protocol(_)));
+        server.await;
+    }
+
+    #[test]
+    fn test_server_errors() {
+        assert!(matches!(
+            RendezvousError::server("crowded"),
+            RendezvousError::Crowded
+        ));
+        assert!(matches!(
+            RendezvousError::server("must bind first"),
+            RendezvousError::Unbound
+        ));
+        assert!(matches!(
+            RendezvousError::server("reclaimed"),
+            RendezvousError::InvalidNameplate(_)
+        ));
+        assert!(matches!(
+            RendezvousError::server("claim requires 'nameplate'"),
+            RendezvousError::InvalidNameplate(_)
+        ));
+        assert!(matches!(
+            RendezvousError::server("submit-permission failed"),
+            RendezvousError::PermissionDenied(_)
+        ));
+        assert!(matches!(
+            RendezvousError::server("something else"),
+            RendezvousError::Server(error) if &*error == "something else"
+        ));
+        /* Only exact matches count */
+        assert!(matches!(
+            RendezvousError::server("no permission to use this nameplate"),
+            RendezvousError::Server(_)
+        ));
     }
 }
@@ -0,0 +1,759 @@
+//! A rendezvous server, for hosting one yourself
+//!
+//! This implements the client protocol of the Python `magic-wormhole-mailbox-server`, so that
+//! clients of all implementations can use it. Clients connect over a WebSocket and bind to an
+//! [`AppID`](crate::AppID). They allocate or claim nameplates, which point to mailboxes. All
+//! messages added to a mailbox get sent to every client that opened it, including the ones that
+//! open it later. The server can't read them, as they are encrypted with the wormhole key.
+//!
+//! At most two sides may use a nameplate or a mailbox, others get a `crowded` error. Nameplates
+//! are deleted once all of their sides released them, and mailboxes once all sides closed them.
+//! The ones nobody cares about anymore expire after a while.
+//!
+//! Everything is kept in memory. Optionally, the state gets written to a JSON file after each change
+//! and loaded from it on start, which is fine for small deployments. Permissions (hashcash) and the
+//! usage database of the Python server are not supported.
+
+use async_std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use async_tungstenite::tungstenite as ws2;
+use futures::{channel::mpsc::UnboundedSender, SinkExt, StreamExt};
+use rand::seq::SliceRandom;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+/** Settings of a [`MailboxServer`] */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MailboxServerConfig {
+    /** A message of the day, which clients may show to their users */
+    pub motd: Option<String>,
+    /**
+     * How long nameplates and mailboxes are kept without any activity
+     *
+     * Mailboxes that are opened by a connected client are kept in any case. Defaults to 11 hours.
+     */
+    pub expiry: Duration,
+    /** Keep the state in this file, so that it survives restarts */
+    pub state_file: Option<PathBuf>,
+}
+
+impl Default for MailboxServerConfig {
+    fn default() -> Self {
+        MailboxServerConfig {
+            motd: None,
+            expiry: Duration::from_secs(11 * 60 * 60),
+            state_file: None,
+        }
+    }
+}
+
+impl MailboxServerConfig {
+    pub fn motd(mut self, motd: impl Into<String>) -> Self {
+        self.motd = Some(motd.into());
+        self
+    }
+
+    pub fn expiry(mut self, expiry: Duration) -> Self {
+        self.expiry = expiry;
+        self
+    }
+
+    pub fn state_file(mut self, state_file: impl Into<PathBuf>) -> Self {
+        self.state_file = Some(state_file.into());
+        self
+    }
+}
+
+/** Seconds since the Unix epoch, like the Python server uses them */
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+#[derive(Serialize, Deserialize)]
+struct NameplateState {
+    mailbox: String,
+    /** The sides that claimed it and did not release it yet */
+    sides: Vec<String>,
+    updated: f64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MailboxState {
+    /** As they get sent to the clients */
+    messages: Vec<serde_json::Value>,
+    /** The sides that opened it */
+    sides: Vec<String>,
+    /** The sides that opened it and closed it again */
+    closed: Vec<String>,
+    updated: f64,
+    /** The connected clients that opened it, by client ID */
+    #[serde(skip)]
+    listeners: HashMap<u64, UnboundedSender<String>>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct App {
+    nameplates: HashMap<String, NameplateState>,
+    mailboxes: HashMap<String, MailboxState>,
+}
+
+impl App {
+    fn new_mailbox(&mut self) -> String {
+        let id = hex::encode(rand::random::<[u8; 8]>());
+        self.mailboxes.insert(
+            id.clone(),
+            MailboxState {
+                messages: Vec::new(),
+                sides: Vec::new(),
+                closed: Vec::new(),
+                updated: now(),
+                listeners: HashMap::new(),
+            },
+        );
+        id
+    }
+
+    /** Pick a random free nameplate, with as few digits as possible */
+    fn free_nameplate(&self) -> Option<String> {
+        let mut start = 1;
+        while start < 1_000_000 {
+            let free: Vec<u32> = (start..start * 10)
+                .filter(|nameplate| !self.nameplates.contains_key(&nameplate.to_string()))
+                .collect();
+            if let Some(nameplate) = free.choose(&mut rand::thread_rng()) {
+                return Some(nameplate.to_string());
+            }
+            start *= 10;
+        }
+        None
+    }
+
+    /** Claim a nameplate for `side`, creating it if necessary, and return its mailbox */
+    fn claim(&mut self, nameplate: &str, side: &str) -> Result<String, &'static str> {
+        let mailbox = match self.nameplates.get(nameplate) {
+            Some(existing) => existing.mailbox.clone(),
+            None => self.new_mailbox(),
+        };
+        let state = self
+            .nameplates
+            .entry(nameplate.to_owned())
+            .or_insert_with(|| NameplateState {
+                mailbox: mailbox.clone(),
+                sides: Vec::new(),
+                updated: now(),
+            });
+        if !state.sides.iter().any(|other| other == side) {
+            if state.sides.len() >= 2 {
+                return Err("crowded");
+            }
+            state.sides.push(side.to_owned());
+        }
+        state.updated = now();
+        Ok(mailbox)
+    }
+
+    fn release(&mut self, nameplate: &str, side: &str) {
+        if let Some(state) = self.nameplates.get_mut(nameplate) {
+            state.sides.retain(|other| other != side);
+            if state.sides.is_empty() {
+                self.nameplates.remove(nameplate);
+            }
+        }
+    }
+
+    /** Open a mailbox, creating it if necessary, and return the messages in it */
+    fn open(
+        &mut self,
+        mailbox: &str,
+        side: &str,
+        client: u64,
+        sender: &UnboundedSender<String>,
+    ) -> Result<Vec<serde_json::Value>, &'static str> {
+        let state = self
+            .mailboxes
+            .entry(mailbox.to_owned())
+            .or_insert_with(|| MailboxState {
+                messages: Vec::new(),
+                sides: Vec::new(),
+                closed: Vec::new(),
+                updated: now(),
+                listeners: HashMap::new(),
+            });
+        if !state.sides.iter().any(|other| other == side) {
+            if state.sides.len() >= 2 {
+                return Err("crowded");
+            }
+            state.sides.push(side.to_owned());
+        }
+        state.listeners.insert(client, sender.clone());
+        state.updated = now();
+        Ok(state.messages.clone())
+    }
+
+    fn add(&mut self, mailbox: &str, message: serde_json::Value) -> Result<(), &'static str> {
+        let state = self
+            .mailboxes
+            .get_mut(mailbox)
+            .ok_or("the mailbox has been closed")?;
+        let text = message.to_string();
+        state
+            .listeners
+            .retain(|_, listener| listener.unbounded_send(text.clone()).is_ok());
+        state.messages.push(message);
+        state.updated = now();
+        Ok(())
+    }
+
+    fn close(&mut self, mailbox: &str, side: &str, client: u64) {
+        if let Some(state) = self.mailboxes.get_mut(mailbox) {
+            state.listeners.remove(&client);
+            if !state.closed.iter().any(|other| other == side) {
+                state.closed.push(side.to_owned());
+            }
+            if state.sides.iter().all(|other| state.closed.contains(other)) {
+                self.mailboxes.remove(mailbox);
+            }
+        }
+    }
+
+    /** Remove everything that has not been used since `deadline` */
+    fn prune(&mut self, deadline: f64) {
+        self.mailboxes.retain(|_, state| {
+            state.listeners.retain(|_, listener| !listener.is_closed());
+            !state.listeners.is_empty() || state.updated >= deadline
+        });
+        let mailboxes = &self.mailboxes;
+        self.nameplates
+            .retain(|_, state| state.updated >= deadline || mailboxes.contains_key(&state.mailbox));
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /** By AppID */
+    apps: HashMap<String, App>,
+    next_client: u64,
+    /** Counts the changes, so that an older state never overwrites a newer one in the file */
+    version: u64,
+}
+
+/** The state at some point, to write to the state file */
+struct Snapshot {
+    version: u64,
+    json: Vec<u8>,
+}
+
+struct Shared {
+    config: MailboxServerConfig,
+    state: Mutex<State>,
+    /** The version in the state file. Only held while writing it, never together with `state` */
+    saved: Mutex<u64>,
+}
+
+impl Shared {
+    /** Serialize the changed state, if there is a state file. Then [`save`](Self::save) it */
+    fn snapshot(&self, state: &mut State) -> Option<Snapshot> {
+        self.config.state_file.as_ref()?;
+        state.version += 1;
+        match serde_json::to_vec(&state.apps) {
+            Ok(json) => Some(Snapshot {
+                version: state.version,
+                json,
+            }),
+            Err(error) => {
+                log::warn!("Could not serialize the state: {}", error);
+                None
+            },
+        }
+    }
+
+    /** Write a snapshot to the state file, without blocking the clients meanwhile */
+    fn save(&self, snapshot: Option<Snapshot>) {
+        let (path, snapshot) = match (&self.config.state_file, snapshot) {
+            (Some(path), Some(snapshot)) => (path, snapshot),
+            _ => return,
+        };
+        let mut saved = self.saved.lock().unwrap();
+        if *saved >= snapshot.version {
+            return;
+        }
+        let temporary = path.with_extension("tmp");
+        let result = std::fs::write(&temporary, snapshot.json)
+            .and_then(|()| std::fs::rename(&temporary, path));
+        match result {
+            Ok(()) => *saved = snapshot.version,
+            Err(error) => {
+                log::warn!("Could not save the state to {}: {}", path.display(), error);
+            },
+        }
+    }
+}
+
+/** What the client sends, apart from the `id` that every message carries */
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", tag = "type")]
+enum ClientMessage {
+    Bind { appid: String, side: String },
+    List,
+    Allocate,
+    Claim { nameplate: String },
+    Release { nameplate: Option<String> },
+    Open { mailbox: String },
+    Add { phase: String, body: String },
+    Close { mailbox: Option<String> },
+    Ping { ping: u64 },
+}
+
+/** A connected client */
+struct Client {
+    id: u64,
+    sender: UnboundedSender<String>,
+    /** The AppID and side it bound to */
+    bound: Option<(String, String)>,
+    nameplate: Option<String>,
+    mailbox: Option<String>,
+}
+
+impl Client {
+    fn send(&self, message: serde_json::Value) {
+        let _ = self.sender.unbounded_send(message.to_string());
+    }
+}
+
+/**
+ * A rendezvous server
+ *
+ * Clients connect to it at `ws://host:port/v1`, although the path is not checked.
+ *
+ * ```no_run
+ * # async_std::task::block_on(async {
+ * use magic_wormhole::rendezvous::mailbox_server::{MailboxServer, MailboxServerConfig};
+ *
+ * let config = MailboxServerConfig::default().motd("Welcome!");
+ * let server = MailboxServer::bind("[::]:4000", config).await?;
+ * server.run().await;
+ * # Ok::<_, std::io::Error>(())
+ * # });
+ * ```
+ */
+pub struct MailboxServer {
+    listener: TcpListener,
+    shared: Arc<Shared>,
+}
+
+impl MailboxServer {
+    /** Listen on `address`, and load the state file if there is one */
+    pub async fn bind(
+        address: impl ToSocketAddrs,
+        config: MailboxServerConfig,
+    ) -> io::Result<Self> {
+        let apps = match &config.state_file {
+            Some(path) if path.exists() => serde_json::from_slice(&std::fs::read(path)?)?,
+            _ => HashMap::new(),
+        };
+        Ok(MailboxServer {
+            listener: TcpListener::bind(address).await?,
+            shared: Arc::new(Shared {
+                config,
+                state: Mutex::new(State {
+                    apps,
+                    next_client: 0,
+                    version: 0,
+                }),
+                saved: Mutex::new(0),
+            }),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<std::net::SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /**
+     * Accept and serve clients, forever
+     *
+     * Each client gets a task of its own. Dropping this future stops accepting new clients and
+     * expiring old nameplates and mailboxes, but the connected clients get served until they
+     * disconnect.
+     */
+    pub async fn run(&self) {
+        let accept = async {
+            loop {
+                match self.listener.accept().await {
+                    Ok((socket, _)) => {
+                        async_std::task::spawn(serve(self.shared.clone(), socket));
+                    },
+                    Err(error) => log::warn!("Could not accept a client: {}", error),
+                }
+            }
+        };
+        let expire = async {
+            let expiry = self.shared.config.expiry;
+            loop {
+                async_std::task::sleep(expiry.min(Duration::from_secs(10 * 60))).await;
+                let snapshot = {
+                    let mut state = self.shared.state.lock().unwrap();
+                    let deadline = now() - expiry.as_secs_f64();
+                    for app in state.apps.values_mut() {
+                        app.prune(deadline);
+                    }
+                    state
+                        .apps
+                        .retain(|_, app| !app.nameplates.is_empty() || !app.mailboxes.is_empty());
+                    self.shared.snapshot(&mut state)
+                };
+                self.shared.save(snapshot);
+            }
+        };
+        futures::join!(accept, expire);
+    }
+}
+
+async fn serve(shared: Arc<Shared>, socket: TcpStream) {
+    let connection = match async_tungstenite::accept_async(socket).await {
+        Ok(connection) => connection,
+        Err(error) => {
+            log::debug!("WebSocket handshake failed: {}", error);
+            return;
+        },
+    };
+    let (mut outgoing, mut incoming) = connection.split();
+    let (sender, receiver) = futures::channel::mpsc::unbounded();
+    let mut client = Client {
+        id: {
+            let mut state = shared.state.lock().unwrap();
+            state.next_client += 1;
+            state.next_client
+        },
+        sender,
+        bound: None,
+        nameplate: None,
+        mailbox: None,
+    };
+
+    let mut welcome = json!({});
+    if let Some(motd) = &shared.config.motd {
+        welcome["motd"] = json!(motd);
+    }
+    client.send(json!({"type": "welcome", "welcome": welcome, "server_tx": now()}));
+
+    let reading = async {
+        while let Some(Ok(message)) = incoming.next().await {
+            match message {
+                ws2::Message::Text(text) => handle(&shared, &mut client, &text),
+                ws2::Message::Close(_) => break,
+                _ => (),
+            }
+        }
+    };
+    let writing = async {
+        let mut receiver = receiver;
+        while let Some(message) = receiver.next().await {
+            if outgoing.send(ws2::Message::Text(message)).await.is_err() {
+                break;
+            }
+        }
+    };
+    futures::pin_mut!(reading, writing);
+    futures::future::select(reading, writing).await;
+
+    /* The mailbox stays open, the client may come back */
+    if let (Some(mailbox), Some((appid, _))) = (&client.mailbox, &client.bound) {
+        let mut state = shared.state.lock().unwrap();
+        if let Some(mailbox) = state
+            .apps
+            .get_mut(appid)
+            .and_then(|app| app.mailboxes.get_mut(mailbox))
+        {
+            mailbox.listeners.remove(&client.id);
+        }
+    }
+}
+
+fn handle(shared: &Shared, client: &mut Client, text: &str) {
+    let message: serde_json::Value = match serde_json::from_str(text) {
+        Ok(message @ serde_json::Value::Object(_)) => message,
+        _ => {
+            let error = "the message must be a JSON object";
+            client.send(json!({"type": "error", "error": error, "orig": text}));
+            return;
+        },
+    };
+    client.send(json!({"type": "ack", "id": message.get("id"), "server_tx": now()}));
+    if let Err(error) = handle_message(shared, client, &message) {
+        client.send(json!({"type": "error", "error": error, "orig": message}));
+    }
+}
+
+fn handle_message(
+    shared: &Shared,
+    client: &mut Client,
+    message: &serde_json::Value,
+) -> Result<(), &'static str> {
+    let parsed: ClientMessage =
+        serde_json::from_value(message.clone()).map_err(|_| "malformed or unknown message")?;
+    let (appid, side) = match (&parsed, &client.bound) {
+        (ClientMessage::Ping { ping }, _) => {
+            client.send(json!({"type": "pong", "pong": ping}));
+            return Ok(());
+        },
+        (ClientMessage::Bind { .. }, Some(_)) => return Err("already bound"),
+        (ClientMessage::Bind { appid, side }, None) => {
+            client.bound = Some((appid.clone(), side.clone()));
+            return Ok(());
+        },
+        (_, None) => return Err("must bind first"),
+        (_, Some((appid, side))) => (appid.clone(), side.clone()),
+    };
+
+    let mut state = shared.state.lock().unwrap();
+    let app = state.apps.entry(appid).or_default();
+    match parsed {
+        ClientMessage::List => {
+            let nameplates: Vec<_> = app
+                .nameplates
+                .keys()
+                .map(|nameplate| json!({ "id": nameplate }))
+                .collect();
+            client.send(json!({"type": "nameplates", "nameplates": nameplates}));
+            return Ok(());
+        },
+        ClientMessage::Allocate => {
+            let nameplate = app.free_nameplate().ok_or("no nameplates left")?;
+            app.claim(&nameplate, &side)?;
+            client.send(json!({"type": "allocated", "nameplate": nameplate}));
+        },
+        ClientMessage::Claim { nameplate } => {
+            let mailbox = app.claim(&nameplate, &side)?;
+            client.nameplate = Some(nameplate);
+            client.send(json!({"type": "claimed", "mailbox": mailbox}));
+        },
+        ClientMessage::Release { nameplate } => {
+            let nameplate = nameplate
+                .or_else(|| client.nameplate.take())
+                .ok_or("release without a nameplate")?;
+            app.release(&nameplate, &side);
+            client.send(json!({"type": "released"}));
+        },
+        ClientMessage::Open { mailbox } => {
+            if client.mailbox.is_some() {
+                return Err("only one open per connection");
+            }
+            for message in app.open(&mailbox, &side, client.id, &client.sender)? {
+                client.send(message);
+            }
+            client.mailbox = Some(mailbox);
+        },
+        ClientMessage::Add { phase, body } => {
+            let mailbox = client
+                .mailbox
+                .as_ref()
+                .ok_or("must open mailbox before adding")?;
+            app.add(
+                mailbox,
+                json!({
+                    "type": "message",
+                    "side": side,
+                    "phase": phase,
+                    "body": body,
+                    "id": message.get("id"),
+                    "server_rx": now(),
+                }),
+            )?;
+        },
+        ClientMessage::Close { mailbox } => {
+            let mailbox = mailbox
+                .or_else(|| client.mailbox.take())
+                .ok_or("close without a mailbox")?;
+            app.close(&mailbox, &side, client.id);
+            client.send(json!({"type": "closed"}));
+        },
+        ClientMessage::Bind { .. } | ClientMessage::Ping { .. } => unreachable!(),
+    }
+    let snapshot = shared.snapshot(&mut state);
+    drop(state);
+    shared.save(snapshot);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        core::{
+            rendezvous::{RendezvousError, RendezvousServer},
+            Mood, Phase,
+        },
+        AppID,
+    };
+
+    const APPID: AppID = AppID(std::borrow::Cow::Borrowed(
+        "example.com/mailbox-server-test",
+    ));
+
+    /** Run the server while `test` runs */
+    async fn with_server<T>(
+        server: &MailboxServer,
+        test: impl std::future::Future<Output = T>,
+    ) -> T {
+        use futures::FutureExt;
+        futures::select! {
+            () = server.run().fuse() => unreachable!(),
+            result = test.fuse() => result,
+        }
+    }
+
+    #[async_std::test]
+    async fn test_mailbox_server() {
+        let config = MailboxServerConfig::default().motd("Hello");
+        let server = MailboxServer::bind("127.0.0.1:0", config).await.unwrap();
+        let url = format!("ws://{}/v1", server.local_addr().unwrap());
+
+        with_server(&server, async {
+            let (mut first, motd) = RendezvousServer::connect(&APPID, &url).await.unwrap();
+            assert_eq!(motd.as_deref(), Some("Hello"));
+            let (nameplate, _) = first.allocate_claim_open().await.unwrap();
+            let (mut second, _) = RendezvousServer::connect(&APPID, &url).await.unwrap();
+            assert_eq!(
+                second.list_nameplates().await.unwrap(),
+                vec![nameplate.clone()]
+            );
+            first
+                .send_peer_message(Phase::PAKE, b"hello".to_vec())
+                .await
+                .unwrap();
+            /* Gets the messages from before it joined */
+            second.claim_open(nameplate.clone()).await.unwrap();
+            assert_eq!(
+                second.next_peer_message_some().await.unwrap().body,
+                b"hello"
+            );
+            second
+                .send_peer_message(Phase::PAKE, b"bye".to_vec())
+                .await
+                .unwrap();
+            assert_eq!(first.next_peer_message_some().await.unwrap().body, b"bye");
+
+            /* A third side can't join */
+            let (mut third, _) = RendezvousServer::connect(&APPID, &url).await.unwrap();
+            assert!(matches!(
+                third.claim_open(nameplate).await,
+                Err(RendezvousError::Crowded)
+            ));
+
+            first.release_nameplate().await.unwrap();
+            second.release_nameplate().await.unwrap();
+            assert!(third.list_nameplates().await.unwrap().is_empty());
+        })
+        .await;
+    }
+
+    #[async_std::test]
+    async fn test_reconnect() {
+        let server = MailboxServer::bind("127.0.0.1:0", MailboxServerConfig::default())
+            .await
+            .unwrap();
+        let server_address = server.local_addr().unwrap();
+        /* Forwards the connections of the first client, so that we can cut them */
+        let forwarder = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/v1", forwarder.local_addr().unwrap());
+        let connections = Arc::new(Mutex::new(Vec::new()));
+        async_std::task::spawn({
+            let connections = connections.clone();
+            async move {
+                loop {
+                    let client = forwarder.accept().await.unwrap().0;
+                    let server = TcpStream::connect(server_address).await.unwrap();
+                    connections.lock().unwrap().push(client.clone());
+                    let (mut reader, mut writer) = (client.clone(), server.clone());
+                    async_std::task::spawn(async move {
+                        futures::io::copy(&mut reader, &mut writer).await
+                    });
+                    let (mut reader, mut writer) = (server, client);
+                    async_std::task::spawn(async move {
+                        futures::io::copy(&mut reader, &mut writer).await
+                    });
+                }
+            }
+        });
+
+        with_server(&server, async {
+            let (mut first, _) = RendezvousServer::connect(&APPID, &url).await.unwrap();
+            let nameplate = first.allocate_claim_open().await.unwrap().0;
+            let direct = format!("ws://{}/v1", server_address);
+            let (mut second, _) = RendezvousServer::connect(&APPID, &direct).await.unwrap();
+            second.claim_open(nameplate).await.unwrap();
+            second
+                .send_peer_message(Phase::PAKE, b"hello".to_vec())
+                .await
+                .unwrap();
+            assert_eq!(first.next_peer_message_some().await.unwrap().body, b"hello");
+
+            for connection in connections.lock().unwrap().drain(..) {
+                connection.shutdown(std::net::Shutdown::Both).unwrap();
+            }
+            second
+                .send_peer_message(Phase::VERSION, b"still there?".to_vec())
+                .await
+                .unwrap();
+            /* The PAKE message gets sent again, but only the new one comes through */
+            let message = first.next_peer_message_some().await.unwrap();
+            assert_eq!(message.phase, Phase::VERSION);
+            assert_eq!(message.body, b"still there?");
+
+            for connection in connections.lock().unwrap().drain(..) {
+                connection.shutdown(std::net::Shutdown::Both).unwrap();
+            }
+            first
+                .send_peer_message(Phase::VERSION, b"yes".to_vec())
+                .await
+                .unwrap();
+            assert_eq!(second.next_peer_message_some().await.unwrap().body, b"yes");
+        })
+        .await;
+    }
+
+    #[async_std::test]
+    async fn test_state_file() {
+        let path = std::env::temp_dir().join(format!(
+            "wormhole-test-mailbox-server-{}.json",
+            std::process::id()
+        ));
+        let config = MailboxServerConfig::default().state_file(&path);
+
+        let server = MailboxServer::bind("127.0.0.1:0", config.clone())
+            .await
+            .unwrap();
+        let url = format!("ws://{}/v1", server.local_addr().unwrap());
+        let (client, nameplate) = with_server(&server, async {
+            let (mut client, _) = RendezvousServer::connect(&APPID, &url).await.unwrap();
+            let nameplate = client.allocate_claim_open().await.unwrap().0;
+            (client, nameplate)
+        })
+        .await;
+        /* The client stays connected to the old server, which keeps serving it */
+        std::mem::drop(server);
+
+        let server = MailboxServer::bind("127.0.0.1:0", config).await.unwrap();
+        let url = format!("ws://{}/v1", server.local_addr().unwrap());
+        let nameplates = with_server(&server, async {
+            let (mut client, _) = RendezvousServer::connect(&APPID, &url).await.unwrap();
+            client.list_nameplates().await.unwrap()
+        })
+        .await;
+        assert_eq!(nameplates, vec![nameplate]);
+
+        /* Wait for the release, so that it does not write the state file after removing it */
+        client.shutdown(Mood::Happy).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -41,10 +41,15 @@ pub struct WelcomeMessage {
     #[deprecated(note = "This is for the Python client")]
     pub current_cli_version: Option<String>,
     pub motd: Option<String>,
-    #[deprecated(note = "Servers should send a proper error message instead")]
+    /** The server does not want us to continue, for example because our version is too old */
     pub error: Option<String>,
     #[serde(rename = "permission-required")]
     pub permission_required: Option<PermissionRequired>,
+    /** Transit relay servers recommended for use together with this rendezvous server */
+    #[serde(rename = "transit-relays", default)]
+    pub transit_relays: Vec<url::Url>,
+    /** The server moved, and we should connect to this one instead */
+    pub redirect: Option<url::Url>,
 }
 
 impl std::fmt::Display for WelcomeMessage {
@@ -56,6 +61,12 @@ impl std::fmt::Display for WelcomeMessage {
         if let Some(permission_required) = &self.permission_required {
             write!(f, "permission_required: '{}', ", permission_required)?;
         }
+        if !self.transit_relays.is_empty() {
+            write!(f, "transit_relays: {:?}, ", self.transit_relays)?;
+        }
+        if let Some(redirect) = &self.redirect {
+            write!(f, "redirect: '{}', ", redirect)?;
+        }
         write!(f, ".. }}")?;
         Ok(())
     }
@@ -241,6 +252,11 @@ pub enum InboundMessage {
     Pong {
         pong: u64,
     },
+    /** The server is moving, reconnect to `url` (the mailboxes move along) */
+    #[display(fmt = "Redirect({})", url)]
+    Redirect {
+        url: url::Url,
+    },
     #[display(fmt = "Error {{ error: {:?}, .. }}", error)]
     Error {
         error: String,
@@ -370,9 +386,11 @@ mod test {
                     current_cli_version: None,
                     motd: None,
                     error: None,
-                    permission_required: None
+                    permission_required: None,
+                    ref transit_relays,
+                    redirect: None,
                 }
-            }
+            } if transit_relays.is_empty()
         ));
     }
 
@@ -388,9 +406,11 @@ mod test {
                     current_cli_version: None,
                     motd: None,
                     error: None,
-                    permission_required: None
+                    permission_required: None,
+                    ref transit_relays,
+                    redirect: None,
                 }
-            }
+            } if transit_relays.is_empty()
         ));
     }
 
@@ -402,7 +422,7 @@ mod test {
     fn test_welcome5() {
         let s = r#"{"type": "welcome", "welcome": { "motd": "hello world" }, "server_tx": 1234.56 }"#;
         let m = serde_json::from_str(s).unwrap();
-        assert!(matches!(m, InboundMessage::Welcome { welcome: WelcomeMessage { current_cli_version: None, motd: Some(_), error: None, permission_required: None }  }));
+        assert!(matches!(m, InboundMessage::Welcome { welcome: WelcomeMessage { current_cli_version: None, motd: Some(_), error: None, permission_required: None, ref transit_relays, redirect: None }  } if transit_relays.is_empty()));
     }
 
     /// Test permission_required field deserialization
@@ -429,11 +449,58 @@ mod test {
                     }),
                     current_cli_version: None,
                     error: None,
+                    transit_relays: vec![],
+                    redirect: None,
+                }
+            }
+        )
+    }
+
+    /// Test transit relay recommendations in the welcome message
+    #[test]
+    #[allow(deprecated)]
+    fn test_welcome_transit_relays() {
+        let s = r#"{"type": "welcome", "welcome": { "transit-relays": ["tcp://relay.example.org:4001", "tcp://relay2.example.org:4001"] } }"#;
+        let m: InboundMessage = serde_json::from_str(s).unwrap();
+        assert_eq!(
+            m,
+            InboundMessage::Welcome {
+                welcome: WelcomeMessage {
+                    transit_relays: vec![
+                        "tcp://relay.example.org:4001".parse().unwrap(),
+                        "tcp://relay2.example.org:4001".parse().unwrap(),
+                    ],
+                    ..WelcomeMessage::default()
                 }
             }
         )
     }
 
+    /// Test both ways a server can tell us that it moved
+    #[test]
+    fn test_redirect() {
+        let s = r#"{"type": "welcome", "welcome": { "redirect": "wss://new.example.org/v1" } }"#;
+        let m: InboundMessage = serde_json::from_str(s).unwrap();
+        assert_eq!(
+            m,
+            InboundMessage::Welcome {
+                welcome: WelcomeMessage {
+                    redirect: Some("wss://new.example.org/v1".parse().unwrap()),
+                    ..WelcomeMessage::default()
+                }
+            }
+        );
+
+        let s = r#"{"type": "redirect", "url": "wss://new.example.org/v1", "server_tx": 1234.56}"#;
+        let m: InboundMessage = serde_json::from_str(s).unwrap();
+        assert_eq!(
+            m,
+            InboundMessage::Redirect {
+                url: "wss://new.example.org/v1".parse().unwrap()
+            }
+        );
+    }
+
     #[test]
     fn test_submit_permissions() {
         let m = OutboundMessage::SubmitPermission(SubmitPermission::Hashcash {
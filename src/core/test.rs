@@ -1,7 +1,7 @@
 use super::{Mood, Phase};
 use std::time::Duration;
 
-use crate::{self as magic_wormhole, transfer, transit, AppID, Code, Wormhole};
+use crate::{self as magic_wormhole, transfer, transit, AppID, Code, Wordlist, Wormhole};
 
 pub const TEST_APPID: AppID = AppID(std::borrow::Cow::Borrowed(
     "lothar.com/wormhole/rusty-wormhole-test",
@@ -20,6 +20,28 @@ fn init_logger() {
         .try_init();
 }
 
+fn default_relay_hints() -> Vec<transit::RelayHint> {
+    vec![transit::RelayHint::from_url(
+        transit::DEFAULT_RELAY_SERVER.parse().unwrap(),
+    )]
+}
+
+/** Run a mailbox server on localhost while `test` runs, for the tests that don't need the public one */
+#[cfg(feature = "mailbox-server")]
+async fn with_local_server<F: std::future::Future>(test: impl FnOnce(String) -> F) -> F::Output {
+    use futures::FutureExt;
+    use magic_wormhole::rendezvous::mailbox_server::{MailboxServer, MailboxServerConfig};
+
+    let server = MailboxServer::bind("127.0.0.1:0", MailboxServerConfig::default())
+        .await
+        .unwrap();
+    let url = format!("ws://{}/v1", server.local_addr().unwrap());
+    futures::select! {
+        () = server.run().fuse() => unreachable!(),
+        result = test(url).fuse() => result,
+    }
+}
+
 /** Send a file using the Rust implementation. This does not guarantee compatibility with Python! ;) */
 #[async_std::test]
 pub async fn test_file_rust2rust() -> eyre::Result<()> {
@@ -41,12 +63,13 @@ pub async fn test_file_rust2rust() -> eyre::Result<()> {
             eyre::Result::<_>::Ok(
                 transfer::send_file(
                     wormhole,
-                    transit::DEFAULT_RELAY_SERVER.parse().unwrap(),
+                    default_relay_hints(),
                     &mut async_std::fs::File::open("examples/example-file.bin").await?,
                     "example-file.bin",
                     std::fs::metadata("examples/example-file.bin")
                         .unwrap()
                         .len(),
+                    transfer::SendOptions::default(),
                     |_sent, _total| {},
                 )
                 .await?,
@@ -63,9 +86,12 @@ pub async fn test_file_rust2rust() -> eyre::Result<()> {
                 log::info!("Got welcome: {}", welcome);
             }
 
-            let req =
-                transfer::request_file(wormhole, transit::DEFAULT_RELAY_SERVER.parse().unwrap())
-                    .await?;
+            let req = transfer::request_file(
+                wormhole,
+                default_relay_hints(),
+                transit::TransitConfig::default(),
+            )
+            .await?;
 
             let mut buffer = Vec::<u8>::new();
             req.accept(|_received, _total| {}, &mut buffer).await?;
@@ -80,6 +106,335 @@ pub async fn test_file_rust2rust() -> eyre::Result<()> {
     Ok(())
 }
 
+/**
+ * Run a sender against a receiver that accepts the offer into memory
+ *
+ * Returns the received content, and the offered size. Both sides must report the same result.
+ */
+async fn transfer_rust2rust<F, Fut>(sender: F) -> eyre::Result<(Vec<u8>, u64)>
+where
+    F: FnOnce(Wormhole) -> Fut + Send + 'static,
+    Fut: std::future::Future<Output = Result<transfer::TransferResult, transfer::TransferError>>
+        + Send,
+{
+    use sha2::{Digest, Sha256};
+
+    let (welcome, connector) =
+        Wormhole::connect_without_code(transfer::APP_CONFIG.id(TEST_APPID), 2).await?;
+    let sender_task = async_std::task::spawn(async move { sender(connector.await?).await });
+
+    let (_welcome, wormhole) =
+        Wormhole::connect_with_code(transfer::APP_CONFIG.id(TEST_APPID), welcome.code).await?;
+    let req = transfer::request_file(
+        wormhole,
+        default_relay_hints(),
+        transit::TransitConfig::default(),
+    )
+    .await?;
+    let filesize = req.filesize;
+    let mut buffer = Vec::<u8>::new();
+    let received = req.accept(|_received, _total| {}, &mut buffer).await?;
+
+    let sent = sender_task.await?;
+    assert_eq!(received.sha256, hex::encode(Sha256::digest(&buffer)));
+    assert_eq!(received.size, buffer.len() as u64);
+    assert_eq!((&sent.sha256, sent.size), (&received.sha256, received.size));
+    Ok((buffer, filesize))
+}
+
+/** Empty files are sent without any records */
+#[async_std::test]
+pub async fn test_empty_file() -> eyre::Result<()> {
+    init_logger();
+
+    let (received, filesize) = transfer_rust2rust(|wormhole| async move {
+        transfer::send_file(
+            wormhole,
+            default_relay_hints(),
+            &mut futures::io::empty(),
+            "empty",
+            0,
+            transfer::SendOptions::default(),
+            |_sent, _total| {},
+        )
+        .await
+    })
+    .await?;
+
+    assert_eq!(filesize, 0);
+    assert!(received.is_empty());
+    Ok(())
+}
+
+/** Compressible content gets compressed, with the end marked by an empty record */
+#[async_std::test]
+pub async fn test_compressed_file() -> eyre::Result<()> {
+    init_logger();
+
+    let content = "All work and no play makes Jack a dull boy.\n".repeat(100_000);
+    let length = content.len() as u64;
+    let content2 = content.clone();
+    let (received, filesize) = transfer_rust2rust(move |wormhole| async move {
+        transfer::send_file(
+            wormhole,
+            default_relay_hints(),
+            &mut futures::io::Cursor::new(content2),
+            "jack.txt",
+            length,
+            transfer::SendOptions::default().compression(transfer::Compression::Always),
+            |_sent, _total| {},
+        )
+        .await
+    })
+    .await?;
+
+    assert_eq!(filesize, length);
+    assert_eq!(received, content.as_bytes());
+    Ok(())
+}
+
+/** Content of unknown size gets buffered, and then sent like a file */
+#[async_std::test]
+pub async fn test_send_stream() -> eyre::Result<()> {
+    init_logger();
+
+    let content = b"Hello from a pipe\n".repeat(1000);
+    let content2 = content.clone();
+    let (received, filesize) = transfer_rust2rust(move |wormhole| async move {
+        transfer::send_stream(
+            wormhole,
+            default_relay_hints(),
+            &mut futures::io::Cursor::new(content2),
+            "pipe.txt",
+            transfer::SendOptions::default(),
+            |_sent, _total| {},
+        )
+        .await
+    })
+    .await?;
+
+    assert_eq!(filesize, content.len() as u64);
+    assert_eq!(received, content);
+    Ok(())
+}
+
+/** Content in memory goes into memory on the other side, unless it is too large */
+#[async_std::test]
+pub async fn test_send_bytes() -> eyre::Result<()> {
+    init_logger();
+
+    let content = b"Hello from memory\n".repeat(100);
+    let content2 = content.clone();
+    let (received, _filesize) = transfer_rust2rust(move |wormhole| async move {
+        transfer::send_bytes(
+            wormhole,
+            default_relay_hints(),
+            "memory.txt",
+            content2,
+            transfer::SendOptions::default(),
+        )
+        .await
+    })
+    .await?;
+    assert_eq!(received, content);
+
+    let (welcome, connector) =
+        Wormhole::connect_without_code(transfer::APP_CONFIG.id(TEST_APPID), 2).await?;
+    let content2 = content.clone();
+    let sender_task = async_std::task::spawn(async move {
+        let wormhole = connector.await?;
+        eyre::Result::<_>::Ok(
+            transfer::send_bytes(
+                wormhole,
+                default_relay_hints(),
+                "memory.txt",
+                content2,
+                transfer::SendOptions::default(),
+            )
+            .await,
+        )
+    });
+    let (_welcome, wormhole) =
+        Wormhole::connect_with_code(transfer::APP_CONFIG.id(TEST_APPID), welcome.code).await?;
+    let req = transfer::request_file(
+        wormhole,
+        default_relay_hints(),
+        transit::TransitConfig::default(),
+    )
+    .await?;
+    assert!(req.accept_to_vec(content.len() as u64 - 1).await.is_err());
+    assert_eq!(
+        sender_task.await?.unwrap_err().code(),
+        crate::ErrorCode::Rejected
+    );
+    Ok(())
+}
+
+/** An empty folder still makes a valid tar file */
+#[async_std::test]
+pub async fn test_empty_folder() -> eyre::Result<()> {
+    init_logger();
+
+    let folder = std::env::temp_dir().join(format!("wormhole-empty-{}", std::process::id()));
+    std::fs::create_dir_all(&folder)?;
+
+    let folder2 = folder.clone();
+    let (received, filesize) = transfer_rust2rust(|wormhole| async move {
+        transfer::send_folder(
+            wormhole,
+            default_relay_hints(),
+            folder2,
+            "empty.tar",
+            transfer::SendOptions::default(),
+            |_sent, _total| {},
+        )
+        .await
+    })
+    .await?;
+    std::fs::remove_dir_all(&folder)?;
+
+    assert_eq!(received.len() as u64, filesize);
+    assert_eq!(tar::Archive::new(&received[..]).entries()?.count(), 0);
+    Ok(())
+}
+
+/** Like `test_file_rust2rust`, but with the one-call functions */
+#[async_std::test]
+pub async fn test_one_call() -> eyre::Result<()> {
+    init_logger();
+
+    let target_dir = std::env::temp_dir().join(format!("wormhole-test-{}", std::process::id()));
+    std::fs::create_dir_all(&target_dir)?;
+
+    let (code, sender) = transfer::send_to_new_code(
+        "examples/example-file.bin",
+        transfer::SendOptions::default(),
+    )
+    .await?;
+    let sender_task = async_std::task::spawn(sender);
+    let received = transfer::receive_from_code(
+        code,
+        target_dir.as_path(),
+        transfer::ReceiveOptions::default(),
+    )
+    .await?;
+    sender_task.await?;
+
+    assert_eq!(received, target_dir.join("example-file.bin"));
+    assert_eq!(
+        std::fs::read("examples/example-file.bin")?,
+        std::fs::read(&received)?,
+        "Files differ"
+    );
+    assert!(
+        !target_dir.join("example-file.bin.part").exists(),
+        "The temporary file was not renamed"
+    );
+    std::fs::remove_dir_all(&target_dir)?;
+    Ok(())
+}
+
+/** Like `test_file_rust2rust`, but receive using [`transfer::ReceiveRequest::accept_stream`] */
+#[async_std::test]
+pub async fn test_accept_stream() -> eyre::Result<()> {
+    use futures::AsyncReadExt;
+    init_logger();
+
+    let (welcome, connector) =
+        Wormhole::connect_without_code(transfer::APP_CONFIG.id(TEST_APPID), 2).await?;
+    let code = welcome.code;
+
+    let sender_task = async_std::task::spawn(async {
+        let wormhole = connector.await?;
+        eyre::Result::<_>::Ok(
+            transfer::send_file(
+                wormhole,
+                default_relay_hints(),
+                &mut async_std::fs::File::open("examples/example-file.bin").await?,
+                "example-file.bin",
+                std::fs::metadata("examples/example-file.bin")
+                    .unwrap()
+                    .len(),
+                transfer::SendOptions::default(),
+                |_sent, _total| {},
+            )
+            .await?,
+        )
+    });
+
+    let (_welcome, wormhole) =
+        Wormhole::connect_with_code(transfer::APP_CONFIG.id(TEST_APPID), code).await?;
+    let req = transfer::request_file(
+        wormhole,
+        default_relay_hints(),
+        transit::TransitConfig::default(),
+    )
+    .await?;
+    assert!(req.their_abilities().can_direct());
+    assert!(!req.their_hints().relay.is_empty());
+    let mut received = Vec::new();
+    req.accept_stream()
+        .await?
+        .read_to_end(&mut received)
+        .await?;
+
+    /* The sender only succeeds if it got our checksum */
+    sender_task.await?;
+    assert_eq!(std::fs::read("examples/example-file.bin")?, received);
+    Ok(())
+}
+
+/** A sender that only connects directly and a receiver that only uses relays can't meet */
+#[async_std::test]
+pub async fn test_connection_policy_mismatch() -> eyre::Result<()> {
+    init_logger();
+
+    let (welcome, connector) =
+        Wormhole::connect_without_code(transfer::APP_CONFIG.id(TEST_APPID), 2).await?;
+    let code = welcome.code;
+
+    let sender_task = async_std::task::spawn(async {
+        let wormhole = connector.await?;
+        let options = transfer::SendOptions::default().transit_config(
+            transit::TransitConfig::default().policy(transit::ConnectionPolicy::DirectOnly),
+        );
+        eyre::Result::<_>::Ok(
+            transfer::send_file(
+                wormhole,
+                default_relay_hints(),
+                &mut async_std::fs::File::open("examples/example-file.bin").await?,
+                "example-file.bin",
+                std::fs::metadata("examples/example-file.bin")
+                    .unwrap()
+                    .len(),
+                options,
+                |_sent, _total| {},
+            )
+            .await,
+        )
+    });
+
+    let (_welcome, wormhole) =
+        Wormhole::connect_with_code(transfer::APP_CONFIG.id(TEST_APPID), code).await?;
+    let req = transfer::request_file(
+        wormhole,
+        default_relay_hints(),
+        transit::TransitConfig::default().policy(transit::ConnectionPolicy::RelayOnly),
+    )
+    .await?;
+    assert!(!req.their_abilities().can_relay());
+    let result = req.accept(|_, _| {}, &mut Vec::new()).await;
+
+    assert!(matches!(
+        result,
+        Err(transfer::TransferError::TransitConnect(
+            transit::TransitConnectError::Protocol(_)
+        ))
+    ));
+    assert!(sender_task.await?.is_err());
+    Ok(())
+}
+
 /** Test the functionality used by the `send-many` subcommand. It logically builds upon the
  * `test_eventloop_exit` tests. We send us a file five times, and check if it arrived.
  */
@@ -106,7 +461,7 @@ pub async fn test_send_many() -> eyre::Result<()> {
             log::info!("Sending file #{}", 0);
             let wormhole = connector.await?;
             senders.push(async_std::task::spawn(async move {
-                let url = crate::transit::DEFAULT_RELAY_SERVER.parse().unwrap();
+                let url = default_relay_hints();
                 crate::transfer::send_file(
                     wormhole,
                     url,
@@ -115,6 +470,7 @@ pub async fn test_send_many() -> eyre::Result<()> {
                     std::fs::metadata("examples/example-file.bin")
                         .unwrap()
                         .len(),
+                    transfer::SendOptions::default(),
                     |_, _| {},
                 )
                 .await
@@ -129,7 +485,7 @@ pub async fn test_send_many() -> eyre::Result<()> {
             )
             .await?;
             senders.push(async_std::task::spawn(async move {
-                let url = crate::transit::DEFAULT_RELAY_SERVER.parse().unwrap();
+                let url = default_relay_hints();
                 crate::transfer::send_file(
                     wormhole,
                     url,
@@ -138,6 +494,7 @@ pub async fn test_send_many() -> eyre::Result<()> {
                     std::fs::metadata("examples/example-file.bin")
                         .unwrap()
                         .len(),
+                    transfer::SendOptions::default(),
                     |_, _| {},
                 )
                 .await
@@ -156,7 +513,8 @@ pub async fn test_send_many() -> eyre::Result<()> {
         log::info!("Got key: {}", &wormhole.key);
         let req = crate::transfer::request_file(
             wormhole,
-            crate::transit::DEFAULT_RELAY_SERVER.parse().unwrap(),
+            default_relay_hints(),
+            transit::TransitConfig::default(),
         )
         .await?;
 
@@ -172,6 +530,224 @@ pub async fn test_send_many() -> eyre::Result<()> {
     Ok(())
 }
 
+/** The inverse of `send_many`: two senders reuse the same code, one receiver takes both offers from a stream */
+#[async_std::test]
+pub async fn test_incoming_offers() -> eyre::Result<()> {
+    use futures::StreamExt;
+    init_logger();
+
+    let (welcome, connector) =
+        Wormhole::connect_without_code(transfer::APP_CONFIG.id(TEST_APPID), 2).await?;
+    let code = welcome.code;
+    log::info!("The code is {:?}", code);
+
+    let correct_data = std::fs::read("examples/example-file.bin")?;
+
+    let sender_code = code.clone();
+    let senders = async_std::task::spawn(async move {
+        let send = |wormhole| async move {
+            crate::transfer::send_file(
+                wormhole,
+                default_relay_hints(),
+                &mut async_std::fs::File::open("examples/example-file.bin").await?,
+                "example-file.bin",
+                std::fs::metadata("examples/example-file.bin")
+                    .unwrap()
+                    .len(),
+                transfer::SendOptions::default(),
+                |_, _| {},
+            )
+            .await
+        };
+
+        /* The first sender reuses the session that allocated the code, the second one claims it again */
+        log::info!("Sending file #{}", 0);
+        send(connector.await?).await?;
+        log::info!("Sending file #{}", 1);
+        let (_welcome, wormhole) =
+            Wormhole::connect_with_code(transfer::APP_CONFIG.id(TEST_APPID), sender_code).await?;
+        send(wormhole).await?;
+        eyre::Result::<_>::Ok(())
+    });
+
+    let mut offers = Box::pin(crate::transfer::incoming_offers(
+        transfer::APP_CONFIG.id(TEST_APPID),
+        code,
+        default_relay_hints(),
+        transit::TransitConfig::default(),
+    ));
+    for i in 0..2usize {
+        log::info!("Receiving file #{}", i);
+        let req = offers.next().await.unwrap()?;
+        let mut buffer = Vec::<u8>::new();
+        req.accept(|_, _| {}, &mut buffer).await?;
+        assert_eq!(correct_data, buffer, "Files #{} differ", i);
+    }
+
+    senders.await?;
+    Ok(())
+}
+
+/** Send a file to someone who never answers the offer, and check that the sender gives up */
+#[async_std::test]
+pub async fn test_offer_expired() -> eyre::Result<()> {
+    init_logger();
+
+    let (code_tx, code_rx) = futures::channel::oneshot::channel();
+    let (done_tx, done_rx) = futures::channel::oneshot::channel::<()>();
+
+    let sender_task = async_std::task::spawn(async {
+        let (welcome, connector) =
+            Wormhole::connect_without_code(transfer::APP_CONFIG.id(TEST_APPID), 2).await?;
+        code_tx.send(welcome.code).unwrap();
+        let wormhole = connector.await?;
+        let result = transfer::send_file(
+            wormhole,
+            default_relay_hints(),
+            &mut async_std::fs::File::open("examples/example-file.bin").await?,
+            "example-file.bin",
+            std::fs::metadata("examples/example-file.bin")
+                .unwrap()
+                .len(),
+            transfer::SendOptions::default().offer_timeout(Duration::from_secs(2)),
+            |_sent, _total| {},
+        )
+        .await;
+        done_tx.send(()).unwrap();
+        assert!(matches!(result, Err(transfer::TransferError::OfferExpired)));
+        eyre::Result::<_>::Ok(())
+    });
+    let receiver_task = async_std::task::spawn(async {
+        let code = code_rx.await?;
+        let (_welcome, wormhole) =
+            Wormhole::connect_with_code(transfer::APP_CONFIG.id(TEST_APPID), code).await?;
+        let req = transfer::request_file(
+            wormhole,
+            default_relay_hints(),
+            transit::TransitConfig::default(),
+        )
+        .await?;
+        /* Keep the offer pending without ever answering it */
+        done_rx.await?;
+        std::mem::drop(req);
+        eyre::Result::<_>::Ok(())
+    });
+
+    async_std::future::timeout(TIMEOUT, sender_task).await??;
+    async_std::future::timeout(TIMEOUT, receiver_task).await??;
+
+    Ok(())
+}
+
+/** Accept an offer only after it expired, which rejects it instead */
+#[async_std::test]
+pub async fn test_offer_expired_receiver() -> eyre::Result<()> {
+    init_logger();
+
+    let (code_tx, code_rx) = futures::channel::oneshot::channel();
+
+    let sender_task = async_std::task::spawn(async {
+        let (welcome, connector) =
+            Wormhole::connect_without_code(transfer::APP_CONFIG.id(TEST_APPID), 2).await?;
+        code_tx.send(welcome.code).unwrap();
+        let wormhole = connector.await?;
+        let result = transfer::send_file(
+            wormhole,
+            default_relay_hints(),
+            &mut async_std::fs::File::open("examples/example-file.bin").await?,
+            "example-file.bin",
+            std::fs::metadata("examples/example-file.bin")
+                .unwrap()
+                .len(),
+            transfer::SendOptions::default(),
+            |_sent, _total| {},
+        )
+        .await;
+        assert_eq!(
+            result.unwrap_err().code(),
+            magic_wormhole::ErrorCode::OfferExpired
+        );
+        eyre::Result::<_>::Ok(())
+    });
+    let receiver_task = async_std::task::spawn(async {
+        let code = code_rx.await?;
+        let (_welcome, wormhole) =
+            Wormhole::connect_with_code(transfer::APP_CONFIG.id(TEST_APPID), code).await?;
+        let req = transfer::request_file(
+            wormhole,
+            default_relay_hints(),
+            transit::TransitConfig::default(),
+        )
+        .await?
+        .expire_after(Duration::from_secs(1));
+        assert!(req.expires_in().is_some());
+        req.expired().await;
+        assert!(req.is_expired());
+        assert!(req.pending_for() >= Duration::from_secs(1));
+        let result = req.accept_stream().await;
+        assert!(matches!(result, Err(transfer::TransferError::OfferExpired)));
+        eyre::Result::<_>::Ok(())
+    });
+
+    async_std::future::timeout(TIMEOUT, sender_task).await??;
+    async_std::future::timeout(TIMEOUT, receiver_task).await??;
+
+    Ok(())
+}
+
+/** Drop an offer without answering it, which rejects it in the background */
+#[async_std::test]
+pub async fn test_drop_offer() -> eyre::Result<()> {
+    init_logger();
+
+    let (code_tx, code_rx) = futures::channel::oneshot::channel();
+
+    let sender_task = async_std::task::spawn(async {
+        let (welcome, connector) =
+            Wormhole::connect_without_code(transfer::APP_CONFIG.id(TEST_APPID), 2).await?;
+        code_tx.send(welcome.code).unwrap();
+        let wormhole = connector.await?;
+        let result = transfer::send_file(
+            wormhole,
+            default_relay_hints(),
+            &mut async_std::fs::File::open("examples/example-file.bin").await?,
+            "example-file.bin",
+            std::fs::metadata("examples/example-file.bin")
+                .unwrap()
+                .len(),
+            transfer::SendOptions::default(),
+            |_sent, _total| {},
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(transfer::TransferError::PeerError(transfer::PeerError {
+                code: transfer::PeerErrorCode::Rejected,
+                ..
+            }))
+        ));
+        eyre::Result::<_>::Ok(())
+    });
+    let receiver_task = async_std::task::spawn(async {
+        let code = code_rx.await?;
+        let (_welcome, wormhole) =
+            Wormhole::connect_with_code(transfer::APP_CONFIG.id(TEST_APPID), code).await?;
+        let req = transfer::request_file(
+            wormhole,
+            default_relay_hints(),
+            transit::TransitConfig::default(),
+        )
+        .await?;
+        std::mem::drop(req);
+        eyre::Result::<_>::Ok(())
+    });
+
+    async_std::future::timeout(TIMEOUT, sender_task).await??;
+    async_std::future::timeout(TIMEOUT, receiver_task).await??;
+
+    Ok(())
+}
+
 /// Try to send a file, but use a bad code, and see how it's handled
 #[async_std::test]
 pub async fn test_wrong_code() -> eyre::Result<()> {
@@ -218,6 +794,131 @@ pub async fn test_wrong_code() -> eyre::Result<()> {
     Ok(())
 }
 
+/** Send and receive messages concurrently over split wormholes */
+#[async_std::test]
+pub async fn test_split() -> eyre::Result<()> {
+    init_logger();
+
+    let (welcome, connector) =
+        Wormhole::connect_without_code(transfer::APP_CONFIG.id(TEST_APPID), 2).await?;
+    let (_welcome, wormhole2) =
+        Wormhole::connect_with_code(transfer::APP_CONFIG.id(TEST_APPID), welcome.code).await?;
+    let wormhole1 = connector.await?;
+
+    let (mut tx1, mut rx1) = wormhole1.split();
+    let (mut tx2, mut rx2) = wormhole2.split();
+
+    /* Both sides wait for a message first, which would deadlock without splitting */
+    let receiver1 = async_std::task::spawn(async move { rx1.receive().await });
+    let receiver2 = async_std::task::spawn(async move { rx2.receive().await });
+    tx1.send(b"hello from 1".to_vec()).await?;
+    tx2.send(b"hello from 2".to_vec()).await?;
+
+    assert_eq!(receiver1.await?, b"hello from 2");
+    assert_eq!(receiver2.await?, b"hello from 1");
+
+    tx1.close().await?;
+    tx2.close().await?;
+
+    Ok(())
+}
+
+/** The split halves work with the stream and sink combinators */
+#[async_std::test]
+pub async fn test_split_sink_stream() -> eyre::Result<()> {
+    use futures::{SinkExt, StreamExt};
+    init_logger();
+
+    let (welcome, connector) =
+        Wormhole::connect_without_code(transfer::APP_CONFIG.id(TEST_APPID), 2).await?;
+    let (_welcome, wormhole2) =
+        Wormhole::connect_with_code(transfer::APP_CONFIG.id(TEST_APPID), welcome.code).await?;
+    let wormhole1 = connector.await?;
+
+    let (mut tx1, _rx1) = wormhole1.split();
+    let (_tx2, rx2) = wormhole2.split();
+
+    /* Named phases don't show up in the stream */
+    tx1.send_message("other", &"not for the stream").await?;
+    let messages = vec![b"one".to_vec(), b"two".to_vec(), b"three".to_vec()];
+    tx1.send_all(&mut futures::stream::iter(messages.clone()).map(Ok))
+        .await?;
+    /* This closes the wormhole */
+    SinkExt::close(&mut tx1).await?;
+
+    let received = rx2.take(3).collect::<Vec<_>>().await;
+    assert_eq!(
+        received.into_iter().collect::<Result<Vec<_>, _>>()?,
+        messages
+    );
+
+    Ok(())
+}
+
+/** Both sides see the same nameplate and mailbox */
+#[async_std::test]
+pub async fn test_session_ids() -> eyre::Result<()> {
+    init_logger();
+
+    let (welcome, connector) =
+        Wormhole::connect_without_code(transfer::APP_CONFIG.id(TEST_APPID), 2).await?;
+    let nameplate = welcome.code.nameplate();
+    let (wormhole1, (_welcome, wormhole2)) = futures::try_join!(
+        connector,
+        Wormhole::connect_with_code(transfer::APP_CONFIG.id(TEST_APPID), welcome.code.clone()),
+    )?;
+
+    assert_eq!(wormhole1.nameplate(), Some(&nameplate));
+    assert_eq!(wormhole2.nameplate(), Some(&nameplate));
+    assert_eq!(wormhole1.mailbox(), wormhole2.mailbox());
+
+    wormhole1.close().await?;
+    wormhole2.close().await?;
+
+    Ok(())
+}
+
+/** The blocking API works without any runtime on the calling threads */
+#[test]
+pub fn test_blocking() -> eyre::Result<()> {
+    use magic_wormhole::blocking;
+    init_logger();
+
+    let (welcome, pending) =
+        blocking::connect_without_code(transfer::APP_CONFIG.id(TEST_APPID), 2)?;
+    let code = welcome.code;
+    let sender = std::thread::spawn(move || {
+        blocking::send_file_or_folder(
+            pending.wait()?,
+            default_relay_hints(),
+            "examples/example-file.bin",
+            "example-file.bin",
+            transfer::SendOptions::default(),
+            |_sent, _total| {},
+        )
+        .map_err(eyre::Error::from)
+    });
+
+    let (_welcome, wormhole) =
+        blocking::connect_with_code(transfer::APP_CONFIG.id(TEST_APPID), code)?;
+    let request = blocking::request_file(wormhole, default_relay_hints(), Default::default())?;
+    let path = std::env::temp_dir().join(format!("wormhole-blocking-{}", std::process::id()));
+    blocking::accept_with_path(
+        request,
+        |_received, _total| {},
+        &path,
+        &transfer::ReceiveOptions::default(),
+    )?;
+    sender.join().unwrap()?;
+
+    assert_eq!(
+        std::fs::read(&path)?,
+        std::fs::read("examples/example-file.bin")?
+    );
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
 /** Connect three people to the party and watch it explode … gracefully */
 #[async_std::test]
 pub async fn test_crowded() -> eyre::Result<()> {
@@ -235,16 +936,247 @@ pub async fn test_crowded() -> eyre::Result<()> {
 
     match futures::try_join!(connector1, connector2, connector3).unwrap_err() {
         magic_wormhole::WormholeError::ServerError(
-            magic_wormhole::rendezvous::RendezvousError::Server(error),
-        ) => {
-            assert_eq!(&*error, "crowded")
-        },
+            magic_wormhole::rendezvous::RendezvousError::Crowded,
+        ) => {},
         other => panic!("Got wrong error message: {}, wanted 'crowded'", other),
     }
 
     Ok(())
 }
 
+/** Check that the rendezvous tap sees the messages, but not the message bodies */
+#[async_std::test]
+pub async fn test_rendezvous_tap() -> eyre::Result<()> {
+    use magic_wormhole::rendezvous::{MessageTap, TapDirection};
+    use std::sync::{Arc, Mutex};
+    init_logger();
+
+    let messages = Arc::new(Mutex::new(Vec::new()));
+    let messages2 = messages.clone();
+    let config = transfer::APP_CONFIG
+        .id(TEST_APPID)
+        .rendezvous_tap(MessageTap::new(move |direction, message| {
+            messages2
+                .lock()
+                .unwrap()
+                .push((direction, message.to_owned()))
+        }));
+
+    let (welcome, connector1) = Wormhole::connect_without_code(config, 2).await?;
+    let connector2 = Wormhole::connect_with_code(transfer::APP_CONFIG.id(TEST_APPID), welcome.code);
+    let (wormhole1, (_welcome, wormhole2)) = futures::try_join!(connector1, connector2)?;
+    wormhole1.close().await?;
+    wormhole2.close().await?;
+
+    let messages = messages.lock().unwrap();
+    assert_eq!(messages[0].0, TapDirection::Received);
+    assert!(messages[0].1.contains("welcome"));
+    assert!(messages.iter().any(
+        |(direction, message)| *direction == TapDirection::Sent && message.contains("\"bind\"")
+    ));
+    assert!(messages
+        .iter()
+        .filter(|(_, message)| message.contains("\"body\""))
+        .all(|(_, message)| message.contains("<redacted")));
+    Ok(())
+}
+
+/** The nameplate of a waiting sender can be listed, for completing it */
+#[async_std::test]
+pub async fn test_list_nameplates() -> eyre::Result<()> {
+    init_logger();
+
+    let (welcome, connector) =
+        Wormhole::connect_without_code(transfer::APP_CONFIG.id(TEST_APPID), 2).await?;
+    let nameplates = Wormhole::list_nameplates(transfer::APP_CONFIG.id(TEST_APPID)).await?;
+    assert!(nameplates.contains(&welcome.code.nameplate()));
+
+    let connector2 = Wormhole::connect_with_code(transfer::APP_CONFIG.id(TEST_APPID), welcome.code);
+    let (wormhole1, (_welcome, wormhole2)) = futures::try_join!(connector, connector2)?;
+    wormhole1.close().await?;
+    wormhole2.close().await?;
+    Ok(())
+}
+
+/** Senders can choose the code, and join a receiver that entered it first */
+#[cfg(feature = "mailbox-server")]
+#[async_std::test]
+pub async fn test_connect_with_fixed_code() -> eyre::Result<()> {
+    use magic_wormhole::rendezvous::{RendezvousError, RendezvousServer};
+    init_logger();
+
+    with_local_server(|url| async move {
+        let config = transfer::APP_CONFIG
+            .id(TEST_APPID)
+            .rendezvous_url(url.clone().into());
+
+        /* Two others use it already */
+        let taken = Code::new(&magic_wormhole::Nameplate::new("7"), "purple-sausages");
+        let mut others = Vec::new();
+        for _ in 0..2 {
+            let (mut other, _motd) = RendezvousServer::connect(&TEST_APPID, &url).await?;
+            other.claim_open(taken.nameplate()).await?;
+            others.push(other);
+        }
+        match Wormhole::connect_with_fixed_code(config.clone(), taken).await {
+            Err(magic_wormhole::WormholeError::ServerError(RendezvousError::NameplateInUse(_))) => {
+            },
+            Err(other) => panic!("Got wrong error message: {}, wanted 'in use'", other),
+            Ok(_) => panic!("Connecting with a nameplate in use must fail"),
+        }
+
+        let code = Code::new(&magic_wormhole::Nameplate::new("8"), "purple-sausages");
+        let receiver =
+            async_std::task::spawn(Wormhole::connect_with_code(config.clone(), code.clone()));
+        let (mut observer, _motd) = RendezvousServer::connect(&TEST_APPID, &url).await?;
+        while !observer
+            .list_nameplates()
+            .await?
+            .contains(&code.nameplate())
+        {
+            async_std::task::sleep(Duration::from_millis(10)).await;
+        }
+        let (welcome, connector) = Wormhole::connect_with_fixed_code(config, code.clone()).await?;
+        assert_eq!(welcome.code, code);
+        let (wormhole1, (_welcome, wormhole2)) = futures::try_join!(connector, receiver)?;
+        wormhole1.close().await?;
+        wormhole2.close().await?;
+        Ok(())
+    })
+    .await
+}
+
+/** Named phases can be received in any order, without mixing them up with the numbered ones */
+#[async_std::test]
+pub async fn test_phase_messages() -> eyre::Result<()> {
+    init_logger();
+
+    let (welcome, connector1) =
+        Wormhole::connect_without_code(transfer::APP_CONFIG.id(TEST_APPID), 2).await?;
+    let connector2 = Wormhole::connect_with_code(transfer::APP_CONFIG.id(TEST_APPID), welcome.code);
+    let (mut wormhole1, (_welcome, mut wormhole2)) = futures::try_join!(connector1, connector2)?;
+
+    wormhole1.send_message("first", &1u32).await?;
+    wormhole1.send_json(&"numbered").await?;
+    wormhole1.send_message("second", &vec![2u32]).await?;
+    wormhole1.send_message("first", &3u32).await?;
+    wormhole1.send_message("first", &4u32).await?;
+
+    assert_eq!(
+        wormhole2.receive_message::<Vec<u32>>("second").await?,
+        vec![2]
+    );
+    assert_eq!(wormhole2.receive_json::<String>().await??, "numbered");
+    assert_eq!(wormhole2.receive_message::<u32>("first").await?, 1);
+    assert!(matches!(
+        wormhole2.receive_message::<String>("first").await,
+        Err(magic_wormhole::WormholeError::ProtocolJson(_))
+    ));
+    assert_eq!(wormhole2.receive_message::<u32>("first").await?, 4);
+
+    wormhole1.close().await?;
+    wormhole2.close().await?;
+    Ok(())
+}
+
+/** Use transit as encrypted pipe for an application protocol of our own */
+#[async_std::test]
+pub async fn test_transit_connect() -> eyre::Result<()> {
+    use futures::{AsyncReadExt, AsyncWriteExt};
+    init_logger();
+
+    let (welcome, connector1) =
+        Wormhole::connect_without_code(transfer::APP_CONFIG.id(TEST_APPID), 2).await?;
+    let connector2 = Wormhole::connect_with_code(transfer::APP_CONFIG.id(TEST_APPID), welcome.code);
+    let (mut wormhole1, (_welcome, mut wormhole2)) = futures::try_join!(connector1, connector2)?;
+
+    let config = transit::TransitConfig::default();
+    /* A second connection over the same wormhole gets a key of its own */
+    for message in [&b"ping"[..], &b"pong"[..]] {
+        let (transit1, transit2) = futures::try_join!(
+            transit::connect(&mut wormhole1, true, default_relay_hints(), &config),
+            transit::connect(&mut wormhole2, false, default_relay_hints(), &config),
+        )?;
+
+        let (mut stream1, mut stream2) = (transit1.into_stream(), transit2.into_stream());
+        stream1.write_all(message).await?;
+        stream1.close().await?;
+        let mut received = Vec::new();
+        stream2.read_to_end(&mut received).await?;
+        assert_eq!(received, message);
+    }
+    wormhole1.close().await?;
+    wormhole2.close().await?;
+    Ok(())
+}
+
+/** Two transfers over the same wormhole must not encrypt their records with the same key */
+#[async_std::test]
+pub async fn test_transit_keys_differ() -> eyre::Result<()> {
+    init_logger();
+
+    let (welcome, connector1) =
+        Wormhole::connect_without_code(transfer::APP_CONFIG.id(TEST_APPID), 2).await?;
+    let connector2 = Wormhole::connect_with_code(transfer::APP_CONFIG.id(TEST_APPID), welcome.code);
+    let (mut wormhole1, (_welcome, mut wormhole2)) = futures::try_join!(connector1, connector2)?;
+
+    let (first1, first2) = (wormhole1.next_transit_key(), wormhole2.next_transit_key());
+    let (second1, second2) = (wormhole1.next_transit_key(), wormhole2.next_transit_key());
+    assert_eq!(first1.to_hex(), first2.to_hex());
+    assert_eq!(second1.to_hex(), second2.to_hex());
+    assert_ne!(first1.to_hex(), second1.to_hex());
+
+    wormhole1.close().await?;
+    wormhole2.close().await?;
+    Ok(())
+}
+
+/** The nameplate gets released as soon as the peer joined, and the application learns about it */
+#[cfg(feature = "mailbox-server")]
+#[async_std::test]
+pub async fn test_nameplate_released() -> eyre::Result<()> {
+    use futures::StreamExt;
+    use magic_wormhole::{WormholeEvent, WormholeEvents};
+    init_logger();
+
+    with_local_server(|url| async move {
+        let config = transfer::APP_CONFIG
+            .id(TEST_APPID)
+            .rendezvous_url(url.into());
+        let (events1, events2) = (WormholeEvents::new(), WormholeEvents::new());
+        let (subscriber1, subscriber2) = (events1.subscribe(), events2.subscribe());
+
+        let (welcome, connector1) =
+            Wormhole::connect_without_code(config.clone().events(events1), 2).await?;
+        let connector2 = Wormhole::connect_with_code(config.events(events2), welcome.code);
+        let (wormhole1, (_welcome, wormhole2)) = futures::try_join!(connector1, connector2)?;
+        wormhole1.close().await?;
+        wormhole2.close().await?;
+
+        let expected = vec![
+            WormholeEvent::ConnectingToRendezvous,
+            WormholeEvent::WaitingForPeer,
+            WormholeEvent::NameplateReleased,
+            WormholeEvent::KeyEstablished,
+            WormholeEvent::Closed,
+        ];
+        assert_eq!(subscriber1.collect::<Vec<_>>().await, expected);
+        assert_eq!(subscriber2.collect::<Vec<_>>().await, expected);
+        Ok(())
+    })
+    .await
+}
+
+/** Codes without words are refused before connecting anywhere */
+#[async_std::test]
+pub async fn test_empty_code_length() {
+    let result = Wormhole::connect_without_code(transfer::APP_CONFIG.id(TEST_APPID), 0).await;
+    assert!(matches!(
+        result,
+        Err(magic_wormhole::WormholeError::InvalidCodeLength)
+    ));
+}
+
 #[test]
 fn test_phase() {
     let p = Phase::PAKE;
@@ -279,3 +1211,67 @@ fn test_mood() {
         serde_json::to_string(&Mood::Unwelcome).unwrap()
     );
 }
+
+#[test]
+fn test_code_parse() {
+    use crate::ParseCodeError;
+
+    let code: Code = "15-foo-bar".parse().unwrap();
+    assert_eq!(code.as_str(), "15-foo-bar");
+    assert_eq!(&*code.nameplate(), "15");
+    assert_eq!(code.password(), "foo-bar");
+
+    /* Normalization */
+    assert_eq!(
+        "  7 Armistice\u{2013}Baboon \n".parse::<Code>().unwrap(),
+        "7-armistice-baboon".parse().unwrap()
+    );
+    assert_eq!(
+        "7--armistice-baboon".parse::<Code>().unwrap().as_str(),
+        "7-armistice-baboon"
+    );
+    /* Custom passwords are not lowercased */
+    assert_eq!("7-Hunter2".parse::<Code>().unwrap().as_str(), "7-Hunter2");
+
+    assert_eq!("".parse::<Code>(), Err(ParseCodeError::Empty));
+    assert_eq!(" - ".parse::<Code>(), Err(ParseCodeError::Empty));
+    assert_eq!(
+        "foo-bar".parse::<Code>(),
+        Err(ParseCodeError::InvalidNameplate("foo".into()))
+    );
+    assert_eq!("15".parse::<Code>(), Err(ParseCodeError::MissingPassword));
+    assert_eq!("15-".parse::<Code>(), Err(ParseCodeError::MissingPassword));
+}
+
+#[test]
+fn test_code_wordlist() {
+    use crate::ParseCodeError;
+
+    let code: Code = "7-armistice-baboon".parse().unwrap();
+    assert_eq!(code.check_wordlist(), Ok(()));
+    let code: Code = "7-armistice-babon".parse().unwrap();
+    assert_eq!(
+        code.check_wordlist(),
+        Err(ParseCodeError::UnknownWord("babon".into()))
+    );
+
+    /* Longer codes for more entropy */
+    let password = crate::default_wordlist(5).choose_words();
+    assert_eq!(password.split('-').count(), 5);
+    let code: Code = format!("7-{}", password.to_uppercase()).parse().unwrap();
+    assert_eq!(code.password(), password);
+    assert_eq!(code.check_wordlist(), Ok(()));
+
+    /* Custom wordlists */
+    let words = vec![vec!["Rot".into(), "Blau".into()], vec!["Wurst".into()]];
+    let wordlist = Wordlist::new(3, words);
+    let password = wordlist.choose_words();
+    assert!(password.ends_with("-wurst-rot") || password.ends_with("-wurst-blau"));
+    let code = Code::parse_with_wordlist("7-BLAU-Wurst", &wordlist).unwrap();
+    assert_eq!(code.as_str(), "7-blau-wurst");
+    assert_eq!(code.check_words(&wordlist), Ok(()));
+    assert_eq!(
+        code.check_wordlist(),
+        Err(ParseCodeError::UnknownWord("blau".into()))
+    );
+}
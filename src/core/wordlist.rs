@@ -2,7 +2,16 @@ use rand::{rngs::OsRng, seq::SliceRandom};
 use serde_json::{self, Value};
 use std::fmt;
 
-#[derive(PartialEq)]
+use super::Nameplate;
+
+/**
+ * The words the passwords of generated codes are made of
+ *
+ * The default is the PGP wordlist (see [`default_wordlist`]). Others can be used by putting them
+ * into the [`AppConfig`](crate::AppConfig), for example for other languages. Both sides don't
+ * need to use the same list, but entering the code is easier if they do.
+ */
+#[derive(Clone, PartialEq, Eq)]
 pub struct Wordlist {
     pub num_words: usize,
     words: Vec<Vec<String>>,
@@ -15,12 +24,33 @@ impl fmt::Debug for Wordlist {
 }
 
 impl Wordlist {
-    #[cfg(test)]
+    /**
+     * A wordlist for passwords of `num_words` words
+     *
+     * The first word of a password is taken from the first list in `words`, the second one from
+     * the second list and so on, starting over with the first list once all have been used.
+     * Words are case-insensitive, and must not contain dashes or whitespace. Each word adds
+     * as much entropy as its list is long: the PGP wordlist has two lists of 256 words each.
+     *
+     * Panics if there are no lists or an empty one.
+     */
     pub fn new(num_words: usize, words: Vec<Vec<String>>) -> Wordlist {
+        assert!(
+            !words.is_empty() && words.iter().all(|words| !words.is_empty()),
+            "Wordlists must not be empty"
+        );
+        let words = words
+            .into_iter()
+            .map(|words| words.into_iter().map(|word| word.to_lowercase()).collect())
+            .collect();
         Wordlist { num_words, words }
     }
 
-    #[allow(dead_code)] // TODO make this API public one day
+    /**
+     * Complete the last word of a partially typed password (the part of the code after the nameplate)
+     *
+     * All candidates are returned in full, followed by a dash if more words are to come.
+     */
     pub fn get_completions(&self, prefix: &str) -> Vec<String> {
         let count_dashes = prefix.matches('-').count();
         let mut completions = Vec::new();
@@ -55,6 +85,43 @@ impl Wordlist {
         completions
     }
 
+    /**
+     * Complete a partially typed code, including its nameplate
+     *
+     * Nameplates are completed from `nameplates`, usually the ones in use on the server (see
+     * [`Wormhole::list_nameplates`](crate::Wormhole::list_nameplates)). The password is
+     * completed like with [`get_completions`](Self::get_completions). All candidates are
+     * returned in full, sorted.
+     */
+    pub fn complete_code(&self, input: &str, nameplates: &[Nameplate]) -> Vec<String> {
+        match input.split_once('-') {
+            None => {
+                let mut completions = nameplates
+                    .iter()
+                    .filter(|nameplate| nameplate.starts_with(input))
+                    .map(|nameplate| format!("{}-", nameplate))
+                    .collect::<Vec<_>>();
+                completions.sort();
+                completions
+            },
+            Some((nameplate, password)) => self
+                .get_completions(&password.to_lowercase())
+                .into_iter()
+                .map(|password| format!("{}-{}", nameplate, password))
+                .collect(),
+        }
+    }
+
+    /** Look up a word (case-insensitively), returning its canonical spelling */
+    pub fn canonical_word(&self, word: &str) -> Option<&str> {
+        let word = word.to_lowercase();
+        self.words
+            .iter()
+            .flatten()
+            .find(|candidate| **candidate == word)
+            .map(String::as_str)
+    }
+
     pub fn choose_words(&self) -> String {
         let mut rng = OsRng;
         let components: Vec<String>;
@@ -154,6 +221,27 @@ mod test {
         assert_eq!(w.get_completions("purple-sa"), vec!["purple-sausages"]);
     }
 
+    #[test]
+    fn test_complete_code() {
+        let words = vec![vecstrings("purple green"), vecstrings("sausages seltzer")];
+        let w = Wordlist::new(2, words);
+        let nameplates = [
+            Nameplate::new("12"),
+            Nameplate::new("3"),
+            Nameplate::new("1"),
+        ];
+        assert_eq!(w.complete_code("1", &nameplates), vec!["1-", "12-"]);
+        assert_eq!(w.complete_code("", &nameplates).len(), 3);
+        assert_eq!(
+            w.complete_code("12-", &nameplates),
+            vec!["12-green-", "12-purple-"]
+        );
+        assert_eq!(
+            w.complete_code("7-Purple-s", &[]),
+            vec!["7-purple-sausages", "7-purple-seltzer"]
+        );
+    }
+
     #[test]
     fn test_choose_words() {
         let few_words: Vec<Vec<String>> = vec![vecstrings("purple"), vecstrings("sausages")];
@@ -193,6 +281,15 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_canonical_word() {
+        let w = default_wordlist(2);
+        assert_eq!(w.canonical_word("armistice"), Some("armistice"));
+        assert_eq!(w.canonical_word("Zulu"), Some("zulu"));
+        assert_eq!(w.canonical_word("purple"), None);
+        assert_eq!(w.canonical_word(""), None);
+    }
+
     #[test]
     fn test_default_completions() {
         let w = default_wordlist(2);
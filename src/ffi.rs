@@ -0,0 +1,306 @@
+//! C bindings for sending and receiving files
+//!
+//! This is enabled with the `ffi` feature. Build the shared library with
+//! `cargo rustc --release --lib --features ffi --crate-type cdylib`, the declarations are in
+//! `include/wormhole.h`.
+//!
+//! All functions block until the transfer is done, so call them from a worker thread in GUI
+//! applications. They use the default rendezvous and relay servers. The callbacks are called on
+//! the calling thread, and get passed the `user_data` pointer unchanged.
+//!
+//! Strings are UTF-8 and NUL-terminated. The functions return one of the `WORMHOLE_*` codes, if it is
+//! not [`WORMHOLE_OK`], [`wormhole_last_error`] has a description for the user.
+
+#![allow(unsafe_code)]
+
+use crate::{
+    blocking,
+    transfer::{self, PeerErrorCode, ReceiveOptions, SendOptions, TransferError},
+    Code,
+};
+use std::{
+    cell::RefCell,
+    ffi::{CStr, CString},
+    os::raw::{c_char, c_int, c_void},
+    path::Path,
+};
+
+pub const WORMHOLE_OK: c_int = 0;
+/** A required pointer is NULL, a string is not UTF-8 or the code is malformed */
+pub const WORMHOLE_ERROR_INVALID_ARGUMENT: c_int = 1;
+/** Connecting to the servers or to the other side failed */
+pub const WORMHOLE_ERROR_CONNECTION: c_int = 2;
+/** The other side rejected or cancelled the transfer */
+pub const WORMHOLE_ERROR_PEER: c_int = 3;
+/** Reading or writing the file failed */
+pub const WORMHOLE_ERROR_IO: c_int = 4;
+/** The received file already exists, it was not overwritten */
+pub const WORMHOLE_ERROR_EXISTS: c_int = 5;
+/** Anything else, like protocol errors, a corrupted transfer or a bug in this library */
+pub const WORMHOLE_ERROR_TRANSFER: c_int = 6;
+
+/** Called with the number of bytes transferred so far, and the total */
+pub type ProgressCallback = extern "C" fn(done: u64, total: u64, user_data: *mut c_void);
+/** Called with the code to give to the other side */
+pub type CodeCallback = extern "C" fn(code: *const c_char, user_data: *mut c_void);
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+/**
+ * A description of the last error on this thread
+ *
+ * The string stays valid until the next call of a `wormhole_*` function on the same thread.
+ * It is empty if there was no error.
+ */
+#[no_mangle]
+pub extern "C" fn wormhole_last_error() -> *const c_char {
+    LAST_ERROR.with(|error| error.borrow().as_ptr())
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message.replace('\0', "")).unwrap();
+    LAST_ERROR.with(|error| *error.borrow_mut() = message);
+}
+
+fn fail(code: c_int, message: String) -> c_int {
+    set_last_error(message);
+    code
+}
+
+/** Return the error code, and remember the message with its causes */
+fn transfer_error(error: TransferError) -> c_int {
+    let code = match &error {
+        TransferError::Wormhole(_) | TransferError::TransitConnect(_) => WORMHOLE_ERROR_CONNECTION,
+        TransferError::PeerCancelled(_) => WORMHOLE_ERROR_PEER,
+        TransferError::PeerError(error) if error.code == PeerErrorCode::Rejected => {
+            WORMHOLE_ERROR_PEER
+        },
+        TransferError::IO(error) if error.kind() == std::io::ErrorKind::AlreadyExists => {
+            WORMHOLE_ERROR_EXISTS
+        },
+        TransferError::IO(_) => WORMHOLE_ERROR_IO,
+        _ => WORMHOLE_ERROR_TRANSFER,
+    };
+    let mut message = error.to_string();
+    let mut source = std::error::Error::source(&error);
+    while let Some(error) = source {
+        message += &format!(": {}", error);
+        source = error.source();
+    }
+    fail(code, message)
+}
+
+/** Run the body of a function, and turn a panic into an error, since it must not unwind into C */
+fn catch_panic(body: impl FnOnce() -> c_int) -> c_int {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(body)).unwrap_or_else(|panic| {
+        let message = panic
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        fail(
+            WORMHOLE_ERROR_TRANSFER,
+            format!("Internal error: {}", message),
+        )
+    })
+}
+
+/** `None` if the pointer is NULL or the string is not UTF-8 */
+unsafe fn to_str<'a>(string: *const c_char) -> Option<&'a str> {
+    if string.is_null() {
+        return None;
+    }
+    CStr::from_ptr(string).to_str().ok()
+}
+
+/**
+ * Send a file or folder
+ *
+ * If `code` is NULL, a new code gets allocated and passed to `code_callback`, before waiting for
+ * the other side to connect. Otherwise, the given code is used. `code_callback` and
+ * `progress_callback` may be NULL.
+ *
+ * # Safety
+ *
+ * `path` must be a valid C string, `code` must be NULL or a valid C string.
+ */
+#[no_mangle]
+pub unsafe extern "C" fn wormhole_send_file(
+    path: *const c_char,
+    code: *const c_char,
+    code_callback: Option<CodeCallback>,
+    progress_callback: Option<ProgressCallback>,
+    user_data: *mut c_void,
+) -> c_int {
+    set_last_error(String::new());
+    catch_panic(|| send_file(path, code, code_callback, progress_callback, user_data))
+}
+
+unsafe fn send_file(
+    path: *const c_char,
+    code: *const c_char,
+    code_callback: Option<CodeCallback>,
+    progress_callback: Option<ProgressCallback>,
+    user_data: *mut c_void,
+) -> c_int {
+    let path = match to_str(path) {
+        Some(path) => Path::new(path),
+        None => return fail(WORMHOLE_ERROR_INVALID_ARGUMENT, "Invalid path".into()),
+    };
+    let file_name = match path.file_name() {
+        Some(file_name) => file_name,
+        None => {
+            return fail(
+                WORMHOLE_ERROR_INVALID_ARGUMENT,
+                format!("{} has no file name", path.display()),
+            )
+        },
+    };
+    let code = match (code.is_null(), to_str(code)) {
+        (true, _) => None,
+        (false, Some(code)) => match code.parse::<Code>() {
+            Ok(code) => Some(code),
+            Err(error) => return fail(WORMHOLE_ERROR_INVALID_ARGUMENT, error.to_string()),
+        },
+        (false, None) => return fail(WORMHOLE_ERROR_INVALID_ARGUMENT, "Invalid code".into()),
+    };
+
+    let connect = || match code {
+        Some(code) => blocking::connect_with_code(transfer::APP_CONFIG, code)
+            .map(|(_welcome, wormhole)| wormhole),
+        None => {
+            let (welcome, pending) = blocking::connect_without_code(transfer::APP_CONFIG, 2)?;
+            if let Some(code_callback) = code_callback {
+                let code = CString::new(welcome.code.to_string()).unwrap();
+                code_callback(code.as_ptr(), user_data);
+            }
+            pending.wait()
+        },
+    };
+    let wormhole = match connect() {
+        Ok(wormhole) => wormhole,
+        Err(error) => return transfer_error(error.into()),
+    };
+    let relay_hints = transfer::default_relay_hints(&wormhole);
+    let result = blocking::send_file_or_folder(
+        wormhole,
+        relay_hints,
+        path,
+        file_name,
+        SendOptions::default(),
+        move |sent, total| {
+            if let Some(progress_callback) = progress_callback {
+                progress_callback(sent, total, user_data);
+            }
+        },
+    );
+    match result {
+        Ok(_) => WORMHOLE_OK,
+        Err(error) => transfer_error(error),
+    }
+}
+
+/**
+ * Receive a file into a directory
+ *
+ * The file is stored under the name chosen by the sender. If that file already exists,
+ * the transfer gets rejected with [`WORMHOLE_ERROR_EXISTS`]. `progress_callback` may be NULL.
+ *
+ * # Safety
+ *
+ * `code` and `target_dir` must be valid C strings.
+ */
+#[no_mangle]
+pub unsafe extern "C" fn wormhole_request_file(
+    code: *const c_char,
+    target_dir: *const c_char,
+    progress_callback: Option<ProgressCallback>,
+    user_data: *mut c_void,
+) -> c_int {
+    set_last_error(String::new());
+    catch_panic(|| request_file(code, target_dir, progress_callback, user_data))
+}
+
+unsafe fn request_file(
+    code: *const c_char,
+    target_dir: *const c_char,
+    progress_callback: Option<ProgressCallback>,
+    user_data: *mut c_void,
+) -> c_int {
+    let code = match to_str(code).map(str::parse::<Code>) {
+        Some(Ok(code)) => code,
+        Some(Err(error)) => return fail(WORMHOLE_ERROR_INVALID_ARGUMENT, error.to_string()),
+        None => return fail(WORMHOLE_ERROR_INVALID_ARGUMENT, "Invalid code".into()),
+    };
+    let target_dir = match to_str(target_dir) {
+        Some(target_dir) => Path::new(target_dir),
+        None => {
+            return fail(
+                WORMHOLE_ERROR_INVALID_ARGUMENT,
+                "Invalid target directory".into(),
+            )
+        },
+    };
+
+    let wormhole = match blocking::connect_with_code(transfer::APP_CONFIG, code) {
+        Ok((_welcome, wormhole)) => wormhole,
+        Err(error) => return transfer_error(error.into()),
+    };
+    let result = async_std::task::block_on(transfer::receive_into(
+        wormhole,
+        target_dir,
+        ReceiveOptions::default(),
+        move |received, total| {
+            if let Some(progress_callback) = progress_callback {
+                progress_callback(received, total, user_data);
+            }
+        },
+    ));
+    match result {
+        Ok(_) => WORMHOLE_OK,
+        Err(error) => transfer_error(error),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_invalid_arguments() {
+        let result = unsafe {
+            wormhole_send_file(
+                std::ptr::null(),
+                std::ptr::null(),
+                None,
+                None,
+                std::ptr::null_mut(),
+            )
+        };
+        assert_eq!(result, WORMHOLE_ERROR_INVALID_ARGUMENT);
+
+        let code = CString::new("not a code").unwrap();
+        let target_dir = CString::new(".").unwrap();
+        let result = unsafe {
+            wormhole_request_file(
+                code.as_ptr(),
+                target_dir.as_ptr(),
+                None,
+                std::ptr::null_mut(),
+            )
+        };
+        assert_eq!(result, WORMHOLE_ERROR_INVALID_ARGUMENT);
+        let message = unsafe { CStr::from_ptr(wormhole_last_error()) };
+        assert!(!message.to_bytes().is_empty());
+    }
+
+    #[test]
+    fn test_catch_panic() {
+        assert_eq!(catch_panic(|| panic!("boom")), WORMHOLE_ERROR_TRANSFER);
+        let message = unsafe { CStr::from_ptr(wormhole_last_error()) };
+        assert_eq!(message.to_str().unwrap(), "Internal error: boom");
+        assert_eq!(catch_panic(|| WORMHOLE_OK), WORMHOLE_OK);
+    }
+}
@@ -0,0 +1,1452 @@
+//! Client-to-Client protocol to forward TCP ports
+//!
+//! This makes TCP services of one side ("serve") available on the other side ("connect"),
+//! similar to SSH port forwarding. It is bound to its own [`APPID`](APPID) and thus does not
+//! interoperate with file transfers.
+//!
+//! Both sides exchange their transit hints over the wormhole, after which it gets closed.
+//! All further communication happens over the transit connection, in the form of msgpack
+//! encoded peer messages. The serving side offers a list of addresses, optionally with names
+//! like "ssh" or "web". The connecting side binds a local port for the ones it wants, and every
+//! TCP connection to such a port gets forwarded to the respective address on the serving side.
+//! Many connections share one transit connection, they are told apart by an ID chosen by the
+//! connecting side.
+//!
+//! Instead of (or in addition to) fixed addresses, the serving side may allow connections to
+//! anywhere. The connecting side then runs a local SOCKS5 proxy, whose connections leave the
+//! network of the serving side. Think of it as a poor man's VPN.
+//!
+//! The serving side can add and remove addresses while the session is running, through a
+//! [`ForwardingState`]. Both sides also use it to report on the traffic of each mapping.
+//!
+//! Addresses prefixed with `udp:` are forwarded as UDP instead of TCP. Every client address
+//! on the connecting side forms a "flow", which is handled like a connection: each of its
+//! datagrams is sent as one packet, and the serving side uses a new socket for it. Flows
+//! get closed after some time without any answers, like in a NAT.
+//!
+//! Addresses prefixed with `unix:` are Unix domain sockets on the serving side, like
+//! `unix:/run/app.sock`. The connecting side binds a TCP port for them as usual.
+
+use super::{core::WormholeError, transit, AppID, Wormhole};
+use async_std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use futures::{
+    channel::mpsc,
+    future::{abortable, AbortHandle, Either},
+    AsyncRead, AsyncReadExt, AsyncWriteExt, FutureExt, Sink, SinkExt, Stream, StreamExt,
+};
+use log::*;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+use transit::{TransitConnectError, TransitError};
+
+mod socks;
+
+const APPID_RAW: &str = "piegames.de/wormhole/port-forwarding";
+
+/// The App ID associated with this protocol.
+pub const APPID: AppID = AppID(Cow::Borrowed(APPID_RAW));
+
+/// An [`crate::AppConfig`] with sane defaults for this protocol.
+///
+/// You **must not** change `id` and `rendezvous_url` to be interoperable.
+pub const APP_CONFIG: crate::AppConfig<AppVersion> = crate::AppConfig::<AppVersion> {
+    id: AppID(Cow::Borrowed(APPID_RAW)),
+    rendezvous_url: Cow::Borrowed(crate::rendezvous::DEFAULT_RENDEZVOUS_SERVER),
+    app_version: AppVersion {},
+    rendezvous_tap: None,
+    resolver: None,
+    proxy: None,
+    events: None,
+    wordlist: None,
+    keepalive: Some(crate::rendezvous::DEFAULT_KEEPALIVE),
+};
+
+/** How much to read from a forwarded TCP connection at once */
+const BUFFER_SIZE: usize = 16 * 1024;
+/** Addresses with this prefix are forwarded as UDP */
+pub const UDP_PREFIX: &str = "udp:";
+/** Addresses with this prefix are Unix domain sockets on the serving side */
+pub const UNIX_PREFIX: &str = "unix:";
+/**
+ * How many packets from the peer may wait for a local connection that does not keep up
+ *
+ * Beyond that, we stop reading from the peer until TCP connections catch up, which
+ * slows the peer down instead of losing data. Datagrams get dropped instead.
+ */
+const WRITE_QUEUE: usize = 64;
+/** Enough for any datagram */
+const MAX_DATAGRAM_SIZE: usize = 64 * 1024;
+/** Close UDP flows on the serving side that did not get an answer for this long */
+const UDP_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(120);
+
+/**
+ * The app version of the port forwarding protocol
+ *
+ * There is nothing to negotiate yet.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AppVersion {}
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum ForwardingError {
+    /// The other side answered with an error, or rejected our offer
+    #[error("Something went wrong on the other side: {}", _0)]
+    PeerError(String),
+    /// Tried to bind a service that the other side does not offer
+    #[error("The other side does not offer {}", _0)]
+    UnknownService(String),
+    /// The SOCKS proxy has no authentication, so it must not be reachable from other machines
+    #[error("The SOCKS proxy may only be bound to a loopback address, not {}", _0)]
+    SocksNotLoopback(IpAddr),
+    /// Some deserialization went wrong, we probably got some garbage
+    #[error("Corrupt JSON message received")]
+    ProtocolJson(
+        #[from]
+        #[source]
+        serde_json::Error,
+    ),
+    #[error("Corrupt Msgpack message received")]
+    ProtocolMsgpack(
+        #[from]
+        #[source]
+        rmp_serde::decode::Error,
+    ),
+    /// A generic string message for "something went wrong", i.e.
+    /// the peer sent some bullshit message order
+    #[error("Protocol error: {}", _0)]
+    Protocol(Box<str>),
+    #[error("Wormhole connection error")]
+    Wormhole(
+        #[from]
+        #[source]
+        WormholeError,
+    ),
+    #[error("Error while establishing transit connection")]
+    TransitConnect(
+        #[from]
+        #[source]
+        TransitConnectError,
+    ),
+    #[error("Transit error")]
+    Transit(
+        #[from]
+        #[source]
+        TransitError,
+    ),
+    #[error("IO error")]
+    IO(
+        #[from]
+        #[source]
+        std::io::Error,
+    ),
+}
+
+/** The messages exchanged over the transit connection */
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum PeerMessage {
+    /** Sent once by the serving side, with the addresses it forwards to */
+    Offer {
+        addresses: Vec<String>,
+        /** Whether connections to any address are allowed, for a SOCKS proxy */
+        #[serde(default)]
+        socks: bool,
+        /** The names of the addresses that have one */
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        names: BTreeMap<String, String>,
+    },
+    /** Open a new connection to one of the offered addresses */
+    Connect { target: String, connection: u64 },
+    /** Some data for an open connection, in either direction */
+    Packet {
+        connection: u64,
+        #[serde(with = "bytes")]
+        payload: Vec<u8>,
+    },
+    /** The connection got closed, or could not be opened, in either direction */
+    Close { connection: u64 },
+    /** The serving side forwards another address now */
+    AddTarget {
+        address: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+    },
+    /** The serving side stopped forwarding an address, and closed all of its connections */
+    RemoveTarget { address: String },
+    /** Something went wrong, the other side should give up */
+    Error(String),
+    #[serde(other)]
+    Unknown,
+}
+
+impl PeerMessage {
+    fn ser_msgpack(&self) -> Vec<u8> {
+        let mut writer = Vec::with_capacity(128);
+        let mut ser = rmp_serde::encode::Serializer::new(&mut writer)
+            .with_struct_map()
+            .with_string_variants();
+        serde::Serialize::serialize(self, &mut ser).unwrap();
+        writer
+    }
+
+    fn de_msgpack(data: &[u8]) -> Result<Self, rmp_serde::decode::Error> {
+        rmp_serde::from_read(&mut &*data)
+    }
+}
+
+/** Send payloads as msgpack binaries instead of arrays of integers */
+mod bytes {
+    use serde::{de, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(bytes)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        struct Visitor;
+
+        impl<'de> de::Visitor<'de> for Visitor {
+            type Value = Vec<u8>;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a byte array")
+            }
+
+            fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Vec<u8>, E> {
+                Ok(bytes.to_vec())
+            }
+
+            fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<Vec<u8>, E> {
+                Ok(bytes)
+            }
+
+            fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+                let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(byte) = seq.next_element()? {
+                    bytes.push(byte);
+                }
+                Ok(bytes)
+            }
+        }
+
+        deserializer.deserialize_byte_buf(Visitor)
+    }
+}
+
+/**
+ * Exchange the transit hints over the wormhole and connect
+ *
+ * The serving side is the leader. The wormhole is closed afterwards.
+ */
+async fn connect_transit(
+    mut wormhole: Wormhole,
+    relay_hints: Vec<transit::RelayHint>,
+    transit_config: &transit::TransitConfig,
+    leader: bool,
+) -> Result<transit::Transit, ForwardingError> {
+    let transit = transit::connect(&mut wormhole, leader, relay_hints, transit_config)
+        .await
+        .map_err(|error| match error {
+            TransitConnectError::Wormhole(error) => ForwardingError::Wormhole(error),
+            error => error.into(),
+        })?;
+    wormhole.close().await?;
+    Ok(transit)
+}
+
+/**
+ * Connections through the SOCKS proxy are counted under this address in the [`Mapping`]s
+ */
+pub const SOCKS_MAPPING: &str = "*";
+
+/**
+ * An address offered by the serving side, optionally with a name like "ssh" or "web"
+ *
+ * The name lets the connecting side pick services without knowing their port numbers.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Service {
+    /** In the form `host:port`, `udp:host:port` for UDP or `unix:path` for a Unix domain socket */
+    pub address: String,
+    pub name: Option<String>,
+}
+
+impl Service {
+    pub fn new(address: impl Into<String>) -> Self {
+        Self {
+            address: address.into(),
+            name: None,
+        }
+    }
+
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /** Whether `selector` is the name or the address of this service */
+    pub fn matches(&self, selector: &str) -> bool {
+        self.address == selector || self.name.as_deref() == Some(selector)
+    }
+}
+
+/**
+ * One forwarded address, with statistics about its connections
+ *
+ * The byte counts are from the point of view of the side that reports them.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Mapping {
+    /** The address on the serving side, or [`SOCKS_MAPPING`] for the SOCKS proxy */
+    pub address: String,
+    pub name: Option<String>,
+    /** The port it is bound to on the connecting side. Always `None` on the serving side */
+    pub local_port: Option<u16>,
+    /** How many connections (or UDP flows) are currently open */
+    pub connections: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+/**
+ * Controls a forwarding session at runtime, and reports about it
+ *
+ * Pass a clone to [`serve`] or [`ConnectOffer::accept`] and keep one for yourself. It is cheap
+ * to clone, all clones refer to the same session. Adding and removing mappings only has an
+ * effect on the serving side, the connecting side follows along automatically.
+ */
+#[derive(Clone, Debug)]
+pub struct ForwardingState(Arc<Shared>);
+
+#[derive(Debug)]
+struct Shared {
+    mappings: Mutex<BTreeMap<String, Mapping>>,
+    commands_tx: mpsc::UnboundedSender<Command>,
+    /** Taken once the session starts */
+    commands_rx: Mutex<Option<mpsc::UnboundedReceiver<Command>>>,
+}
+
+#[derive(Debug)]
+enum Command {
+    Add(Service),
+    Remove(String),
+}
+
+impl ForwardingState {
+    pub fn new() -> Self {
+        let (commands_tx, commands_rx) = mpsc::unbounded();
+        Self(Arc::new(Shared {
+            mappings: Mutex::new(BTreeMap::new()),
+            commands_tx,
+            commands_rx: Mutex::new(Some(commands_rx)),
+        }))
+    }
+
+    /** Start forwarding another address, in the form `host:port` */
+    pub fn add_target(&self, address: impl Into<String>) {
+        self.add_service(Service::new(address));
+    }
+
+    /** Start forwarding another service */
+    pub fn add_service(&self, service: Service) {
+        /* If the session is over, there is nothing to add to anymore */
+        let _ = self.0.commands_tx.unbounded_send(Command::Add(service));
+    }
+
+    /** Stop forwarding an address, and close all of its connections */
+    pub fn remove_target(&self, address: impl Into<String>) {
+        let _ = self
+            .0
+            .commands_tx
+            .unbounded_send(Command::Remove(address.into()));
+    }
+
+    /** A snapshot of the current mappings, sorted by their address */
+    pub fn mappings(&self) -> Vec<Mapping> {
+        self.0.mappings.lock().unwrap().values().cloned().collect()
+    }
+
+    fn insert(&self, service: &Service, local_port: Option<u16>) {
+        self.0.mappings.lock().unwrap().insert(
+            service.address.clone(),
+            Mapping {
+                address: service.address.clone(),
+                name: service.name.clone(),
+                local_port,
+                ..Mapping::default()
+            },
+        );
+    }
+
+    fn remove(&self, address: &str) {
+        self.0.mappings.lock().unwrap().remove(address);
+    }
+
+    fn update(&self, address: &str, update: impl FnOnce(&mut Mapping)) {
+        if let Some(mapping) = self.0.mappings.lock().unwrap().get_mut(address) {
+            update(mapping);
+        }
+    }
+}
+
+impl Default for ForwardingState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/**
+ * Forward the given services to the other side
+ *
+ * The other side may only connect to these, unless `socks` is set: then it may connect to
+ * any address, through a SOCKS proxy.
+ * Only enable this if you trust the other side with access to your network!
+ * More targets can be added and removed through the `state` while this runs, which is
+ * until the other side closes the connection.
+ */
+pub async fn serve(
+    wormhole: Wormhole,
+    relay_hints: Vec<transit::RelayHint>,
+    transit_config: transit::TransitConfig,
+    services: Vec<Service>,
+    socks: bool,
+    state: ForwardingState,
+) -> Result<(), ForwardingError> {
+    let mut transit = connect_transit(wormhole, relay_hints, &transit_config, true).await?;
+    let offer = PeerMessage::Offer {
+        addresses: services
+            .iter()
+            .map(|service| service.address.clone())
+            .collect(),
+        socks,
+        names: services
+            .iter()
+            .filter_map(|service| Some((service.address.clone(), service.name.clone()?)))
+            .collect(),
+    };
+    transit.send_record(&offer.ser_msgpack()).await?;
+    transit.flush().await?;
+
+    let (transit_tx, transit_rx) = transit.split();
+    let (mut forwarder, events_rx) = Forwarder::new(state, None);
+    for service in services {
+        forwarder.state.insert(&service, None);
+        forwarder.targets.push(service.address);
+    }
+    if socks {
+        forwarder.state.insert(&Service::new(SOCKS_MAPPING), None);
+        forwarder.socks = true;
+    }
+    forwarder.run(transit_tx, transit_rx, events_rx).await
+}
+
+/**
+ * Connect to a side that [`serve`]s some ports
+ *
+ * This only receives the offer. Choose which services to [bind](ConnectOffer::bind) locally,
+ * and then [accept](ConnectOffer::accept) it. All ports are bound on `bind_address`, which is
+ * the loopback address by default. This includes the ports for services added later on, which
+ * get bound automatically.
+ */
+pub async fn connect(
+    wormhole: Wormhole,
+    relay_hints: Vec<transit::RelayHint>,
+    transit_config: transit::TransitConfig,
+    bind_address: Option<IpAddr>,
+) -> Result<ConnectOffer, ForwardingError> {
+    let bind_address = bind_address.unwrap_or_else(|| std::net::Ipv4Addr::LOCALHOST.into());
+    let mut transit = connect_transit(wormhole, relay_hints, &transit_config, false).await?;
+
+    match PeerMessage::de_msgpack(&transit.receive_record().await?)? {
+        PeerMessage::Offer {
+            addresses,
+            socks,
+            mut names,
+        } => Ok(ConnectOffer {
+            services: addresses
+                .into_iter()
+                .map(|address| Service {
+                    name: names.remove(&address),
+                    address,
+                })
+                .collect(),
+            socks,
+            transit,
+            bind_address,
+            listeners: Vec::new(),
+            socks_listener: None,
+        }),
+        PeerMessage::Error(err) => Err(ForwardingError::PeerError(err)),
+        other => {
+            let error = format!("Expected an offer, but got {:?}", other);
+            Err(ForwardingError::Protocol(error.into()))
+        },
+    }
+}
+
+/**
+ * The services offered by the serving side
+ */
+#[must_use]
+pub struct ConnectOffer {
+    pub services: Vec<Service>,
+    /** Whether the other side allows connections to anywhere, through a SOCKS proxy */
+    pub socks: bool,
+    transit: transit::Transit,
+    bind_address: IpAddr,
+    listeners: Vec<(Listener, Service)>,
+    socks_listener: Option<TcpListener>,
+}
+
+impl ConnectOffer {
+    /**
+     * Bind a local port for the service with the given name or address
+     *
+     * If `port` is zero, a free one is picked. Returns the bound port. Services that are not
+     * bound don't get forwarded.
+     */
+    pub async fn bind(&mut self, selector: &str, port: u16) -> Result<u16, ForwardingError> {
+        let service = self
+            .services
+            .iter()
+            .find(|service| service.matches(selector))
+            .ok_or_else(|| ForwardingError::UnknownService(selector.into()))?;
+        if let Some((listener, _)) = self.listeners.iter().find(|(_, bound)| bound == service) {
+            return Ok(listener.local_port()?);
+        }
+        let listener = Listener::bind(&service.address, self.bind_address, port).await?;
+        let port = listener.local_port()?;
+        self.listeners.push((listener, service.clone()));
+        Ok(port)
+    }
+
+    /** Bind a free local port for every service that is not bound yet */
+    pub async fn bind_all(&mut self) -> Result<(), ForwardingError> {
+        for service in self.services.clone() {
+            self.bind(&service.address, 0).await?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Bind the SOCKS5 proxy to a local port, if the other side allows it
+     *
+     * If `port` is zero, a free one is picked. Returns the bound port. The proxy lets anyone
+     * who can reach it connect anywhere from the other side, so this fails unless the
+     * `bind_address` is a loopback address.
+     */
+    pub async fn bind_socks(&mut self, port: u16) -> Result<u16, ForwardingError> {
+        ensure!(
+            self.socks,
+            ForwardingError::UnknownService("a SOCKS proxy".into())
+        );
+        ensure!(
+            self.bind_address.is_loopback(),
+            ForwardingError::SocksNotLoopback(self.bind_address)
+        );
+        let listener = match self.socks_listener.take() {
+            Some(listener) => listener,
+            None => TcpListener::bind((self.bind_address, port)).await?,
+        };
+        let port = listener.local_addr()?.port();
+        self.socks_listener = Some(listener);
+        Ok(port)
+    }
+
+    /** The services bound so far, with their local ports */
+    pub fn bound(&self) -> Vec<(u16, Service)> {
+        self.listeners
+            .iter()
+            .filter_map(|(listener, service)| Some((listener.local_port().ok()?, service.clone())))
+            .collect()
+    }
+
+    /**
+     * Start forwarding
+     *
+     * The `state` follows the mappings the other side adds and removes, and counts the traffic.
+     * This runs until the other side closes the connection.
+     */
+    pub async fn accept(self, state: ForwardingState) -> Result<(), ForwardingError> {
+        let (transit_tx, transit_rx) = self.transit.split();
+        let (mut forwarder, events_rx) = Forwarder::new(state, Some(self.bind_address));
+        for (listener, service) in self.listeners {
+            forwarder.listen(listener, service);
+        }
+        if let Some(listener) = self.socks_listener {
+            let port = listener.local_addr().ok().map(|address| address.port());
+            forwarder.state.insert(&Service::new(SOCKS_MAPPING), port);
+            let events = forwarder.events_tx.clone();
+            let (task, abort) = abortable(socks::accept_connections(listener, events));
+            async_std::task::spawn(task);
+            forwarder.listeners.insert(SOCKS_MAPPING.into(), abort);
+        }
+        forwarder.run(transit_tx, transit_rx, events_rx).await
+    }
+
+    /** Tell the other side that we don't want to connect */
+    pub async fn reject(mut self) -> Result<(), ForwardingError> {
+        let error = PeerMessage::Error("The offer was rejected".into());
+        self.transit.send_record(&error.ser_msgpack()).await?;
+        self.transit.flush().await?;
+        Ok(())
+    }
+}
+
+/** Where the connecting side accepts connections for a mapping */
+enum Listener {
+    Tcp(TcpListener),
+    Udp(UdpSocket),
+}
+
+impl Listener {
+    /** Bind a local port for the given address of the serving side */
+    async fn bind(address: &str, bind_address: IpAddr, port: u16) -> std::io::Result<Self> {
+        if address.starts_with(UDP_PREFIX) {
+            Ok(Self::Udp(UdpSocket::bind((bind_address, port)).await?))
+        } else {
+            Ok(Self::Tcp(TcpListener::bind((bind_address, port)).await?))
+        }
+    }
+
+    fn local_port(&self) -> std::io::Result<u16> {
+        match self {
+            Self::Tcp(listener) => listener.local_addr().map(|address| address.port()),
+            Self::Udp(socket) => socket.local_addr().map(|address| address.port()),
+        }
+    }
+}
+
+/** The local end of a forwarded connection */
+#[derive(Clone)]
+enum Socket {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(async_std::os::unix::net::UnixStream),
+    /**
+     * A UDP flow. On the serving side, this is a socket of its own connected to the target.
+     * On the connecting side, the socket is shared by all flows of the mapping, and this is
+     * the address of the client.
+     */
+    Udp(Arc<UdpSocket>, Option<SocketAddr>),
+}
+
+impl Socket {
+    async fn write(&mut self, payload: &[u8]) -> std::io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.write_all(payload).await,
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.write_all(payload).await,
+            Self::Udp(socket, Some(client)) => socket.send_to(payload, *client).await.map(drop),
+            Self::Udp(socket, None) => socket.send(payload).await.map(drop),
+        }
+    }
+
+    /**
+     * Shutting down reading also ends the task reading from it. UDP flows end on their own once
+     * they are idle
+     */
+    fn shutdown(&self, how: Shutdown) {
+        match self {
+            Self::Tcp(stream) => {
+                let _ = stream.shutdown(how);
+            },
+            #[cfg(unix)]
+            Self::Unix(stream) => {
+                let _ = stream.shutdown(how);
+            },
+            Self::Udp(..) => {},
+        }
+    }
+}
+
+/** Events from the background tasks that handle the individual TCP connections */
+enum Event {
+    /** Something to send to the peer */
+    Send(PeerMessage),
+    /** A local client connected to a mapping, and wants to go to the target (connect side) */
+    Incoming(String, String, TcpStream),
+    /** A local client sent a datagram to a UDP mapping, from the given address (connect side) */
+    Datagram(String, Arc<UdpSocket>, SocketAddr, Vec<u8>),
+    /** A connection requested by the peer got established (serve side) */
+    Connected(u64, Socket),
+    /** The user changed the mappings */
+    Command(Command),
+}
+
+struct Connection {
+    /** The address of the mapping this connection belongs to */
+    mapping: String,
+    /** `None` while still connecting */
+    socket: Option<Socket>,
+    /** What the peer sent, for the task that writes it to the socket */
+    writer: mpsc::Sender<Vec<u8>>,
+    /** The other end of the `writer`, until a task writes it to the socket */
+    queue: Option<mpsc::Receiver<Vec<u8>>>,
+}
+
+impl Connection {
+    fn new(mapping: String) -> Self {
+        let (writer, queue) = mpsc::channel(WRITE_QUEUE);
+        Connection {
+            mapping,
+            socket: None,
+            writer,
+            queue: Some(queue),
+        }
+    }
+
+    /** Start writing what the peer sent, and sends from now on, to `socket` */
+    fn connected(&mut self, socket: Socket, connection: u64, events: mpsc::Sender<Event>) {
+        if let Some(queue) = self.queue.take() {
+            let task = write_connection(socket.clone(), queue, connection, events);
+            async_std::task::spawn(task);
+        }
+        self.socket = Some(socket);
+    }
+}
+
+/** The state shared by both sides, once the transit connection is up */
+struct Forwarder {
+    state: ForwardingState,
+    /** The addresses the peer may connect to (serve side) */
+    targets: Vec<String>,
+    /** Whether the peer may connect to any address (serve side) */
+    socks: bool,
+    /** Where to bind the ports of new mappings (connect side) */
+    bind_address: Option<IpAddr>,
+    /** Stop accepting connections once a mapping gets removed (connect side) */
+    listeners: HashMap<String, AbortHandle>,
+    connections: HashMap<u64, Connection>,
+    /** The connection of each client of a UDP mapping (connect side) */
+    udp_flows: HashMap<(String, SocketAddr), u64>,
+    next_connection: u64,
+    events_tx: mpsc::Sender<Event>,
+}
+
+impl Forwarder {
+    /** Also returns the receiving end of the events, to pass to [`run`](Self::run) */
+    fn new(state: ForwardingState, bind_address: Option<IpAddr>) -> (Self, mpsc::Receiver<Event>) {
+        let (events_tx, events_rx) = mpsc::channel(32);
+        let forwarder = Self {
+            state,
+            targets: Vec::new(),
+            socks: false,
+            bind_address,
+            listeners: HashMap::new(),
+            connections: HashMap::new(),
+            udp_flows: HashMap::new(),
+            next_connection: 0,
+            events_tx,
+        };
+        (forwarder, events_rx)
+    }
+
+    async fn run(
+        mut self,
+        transit_tx: impl Sink<Box<[u8]>, Error = TransitError>,
+        transit_rx: impl Stream<Item = Result<Box<[u8]>, TransitError>>,
+        events_rx: mpsc::Receiver<Event>,
+    ) -> Result<(), ForwardingError> {
+        let result = self.forward(transit_tx, transit_rx, events_rx).await;
+
+        for (_, abort) in self.listeners.drain() {
+            abort.abort();
+        }
+        let connections: Vec<u64> = self.connections.keys().copied().collect();
+        for connection in connections {
+            self.close(connection);
+        }
+        result
+    }
+
+    async fn forward(
+        &mut self,
+        transit_tx: impl Sink<Box<[u8]>, Error = TransitError>,
+        transit_rx: impl Stream<Item = Result<Box<[u8]>, TransitError>>,
+        events_rx: mpsc::Receiver<Event>,
+    ) -> Result<(), ForwardingError> {
+        futures::pin_mut!(transit_tx);
+        let commands = self.state.0.commands_rx.lock().unwrap().take();
+        let commands = commands.unwrap_or_else(|| {
+            warn!("The forwarding state is already used by another session, ignoring its commands");
+            mpsc::unbounded().1
+        });
+        /* We hold a sender ourselves, so this only ends once the transit does */
+        let events = futures::stream::select(events_rx, commands.map(Event::Command));
+        let events =
+            futures::stream::select(transit_rx.map(Either::Left), events.map(Either::Right));
+        futures::pin_mut!(events);
+
+        while let Some(event) = events.next().await {
+            let answers: Vec<PeerMessage> = match event {
+                Either::Left(Ok(record)) => self
+                    .handle_message(PeerMessage::de_msgpack(&record)?)
+                    .await?
+                    .into_iter()
+                    .collect(),
+                /* The other side is done */
+                Either::Left(Err(TransitError::IO(err)))
+                    if err.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    break
+                },
+                Either::Left(Err(err)) => return Err(err.into()),
+                Either::Right(Event::Datagram(mapping, socket, client, payload)) => {
+                    self.handle_datagram(mapping, socket, client, payload)
+                },
+                Either::Right(event) => self.handle_event(event)?.into_iter().collect(),
+            };
+            for message in answers {
+                transit_tx.send(message.ser_msgpack().into()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /** Handle a message from the peer, and return the answer to it, if any */
+    async fn handle_message(
+        &mut self,
+        message: PeerMessage,
+    ) -> Result<Option<PeerMessage>, ForwardingError> {
+        match message {
+            PeerMessage::Connect { target, connection } => {
+                let mapping = if self.targets.contains(&target) {
+                    target.clone()
+                } else if self.socks
+                    && !target.starts_with(UNIX_PREFIX)
+                    && !target.starts_with(UDP_PREFIX)
+                {
+                    /* The SOCKS proxy only reaches TCP addresses, not the serving side's sockets */
+                    SOCKS_MAPPING.to_owned()
+                } else {
+                    warn!("Peer tried to connect to {}, which was not offered", target);
+                    return Ok(Some(PeerMessage::Close { connection }));
+                };
+                self.state
+                    .update(&mapping, |mapping| mapping.connections += 1);
+                let mut entry = Connection::new(mapping);
+                let queue = entry.queue.take().unwrap();
+                self.connections.insert(connection, entry);
+                let events = self.events_tx.clone();
+                async_std::task::spawn(open_connection(target, connection, queue, events));
+            },
+            PeerMessage::Packet {
+                connection,
+                payload,
+            } => {
+                let entry = match self.connections.get_mut(&connection) {
+                    Some(entry) => entry,
+                    /* Closed in the meantime */
+                    None => return Ok(None),
+                };
+                self.state.update(&entry.mapping, |mapping| {
+                    mapping.bytes_received += payload.len() as u64
+                });
+                /* If the writing task is gone, it already asked to close the connection */
+                if entry.mapping.starts_with(UDP_PREFIX) {
+                    match entry.writer.try_send(payload) {
+                        Err(err) if err.is_full() => {
+                            debug!("Dropping a datagram for the busy connection {}", connection);
+                        },
+                        _ => {},
+                    }
+                } else {
+                    let _ = entry.writer.send(payload).await;
+                }
+            },
+            PeerMessage::Close { connection } => self.close(connection),
+            PeerMessage::AddTarget { address, name } => {
+                let bind_address = self.bind_address.ok_or_else(|| {
+                    ForwardingError::Protocol("Only the serving side may add targets".into())
+                })?;
+                let service = Service { address, name };
+                match Listener::bind(&service.address, bind_address, 0).await {
+                    Ok(listener) => self.listen(listener, service),
+                    Err(err) => {
+                        warn!("Failed to bind a port for {}: {}", service.address, err);
+                        self.state.insert(&service, None);
+                    },
+                }
+            },
+            PeerMessage::RemoveTarget { address } => {
+                ensure!(
+                    self.bind_address.is_some(),
+                    ForwardingError::Protocol("Only the serving side may remove targets".into())
+                );
+                self.remove_mapping(&address);
+            },
+            PeerMessage::Error(err) => return Err(ForwardingError::PeerError(err)),
+            PeerMessage::Offer { .. } => {
+                bail!(ForwardingError::Protocol(
+                    "Got an offer after the connection was set up".into()
+                ))
+            },
+            PeerMessage::Unknown => warn!("Got an unknown message, ignoring it"),
+        }
+        Ok(None)
+    }
+
+    /** Returns the message to send to the peer, if any */
+    fn handle_event(&mut self, event: Event) -> Result<Option<PeerMessage>, ForwardingError> {
+        Ok(match event {
+            Event::Send(PeerMessage::Close { connection }) => {
+                /* Don't echo a close back to the peer */
+                if !self.connections.contains_key(&connection) {
+                    return Ok(None);
+                }
+                self.close(connection);
+                Some(PeerMessage::Close { connection })
+            },
+            Event::Send(PeerMessage::Packet {
+                connection,
+                payload,
+            }) => {
+                let mapping = match self.connections.get(&connection) {
+                    Some(entry) => &entry.mapping,
+                    None => return Ok(None),
+                };
+                self.state.update(mapping, |mapping| {
+                    mapping.bytes_sent += payload.len() as u64
+                });
+                Some(PeerMessage::Packet {
+                    connection,
+                    payload,
+                })
+            },
+            Event::Send(message) => Some(message),
+            Event::Incoming(mapping, target, stream) => {
+                /* The mapping may have been removed while the SOCKS handshake was running */
+                if !self.listeners.contains_key(&mapping) {
+                    let _ = stream.shutdown(Shutdown::Both);
+                    return Ok(None);
+                }
+                let connection = self.next_connection;
+                self.next_connection += 1;
+                self.state
+                    .update(&mapping, |mapping| mapping.connections += 1);
+                let mut entry = Connection::new(mapping);
+                entry.connected(
+                    Socket::Tcp(stream.clone()),
+                    connection,
+                    self.events_tx.clone(),
+                );
+                self.connections.insert(connection, entry);
+                async_std::task::spawn(read_connection(stream, connection, self.events_tx.clone()));
+                Some(PeerMessage::Connect { target, connection })
+            },
+            Event::Connected(connection, socket) => {
+                let events = self.events_tx.clone();
+                match self.connections.get_mut(&connection) {
+                    Some(entry) => entry.connected(socket.clone(), connection, events.clone()),
+                    /* Closed by the peer while we were connecting */
+                    None => {
+                        socket.shutdown(Shutdown::Both);
+                        return Ok(None);
+                    },
+                }
+                match socket {
+                    Socket::Tcp(stream) => {
+                        async_std::task::spawn(read_connection(stream, connection, events))
+                    },
+                    #[cfg(unix)]
+                    Socket::Unix(stream) => {
+                        async_std::task::spawn(read_connection(stream, connection, events))
+                    },
+                    Socket::Udp(socket, _) => {
+                        async_std::task::spawn(read_datagrams(socket, connection, events))
+                    },
+                };
+                None
+            },
+            /* These go to `handle_datagram` instead */
+            Event::Datagram(mapping, ..) => {
+                bail!(ForwardingError::Protocol(
+                    format!("Got a datagram for {} out of order", mapping).into()
+                ))
+            },
+            Event::Command(Command::Add(service)) => {
+                if self.bind_address.is_some() {
+                    warn!(
+                        "Only the serving side can add targets, ignoring {}",
+                        service.address
+                    );
+                    return Ok(None);
+                }
+                if self.targets.contains(&service.address) {
+                    return Ok(None);
+                }
+                self.state.insert(&service, None);
+                self.targets.push(service.address.clone());
+                Some(PeerMessage::AddTarget {
+                    address: service.address,
+                    name: service.name,
+                })
+            },
+            Event::Command(Command::Remove(address)) => {
+                if self.bind_address.is_some() {
+                    warn!(
+                        "Only the serving side can remove targets, ignoring {}",
+                        address
+                    );
+                    return Ok(None);
+                }
+                if !self.targets.contains(&address) {
+                    return Ok(None);
+                }
+                self.targets.retain(|target| target != &address);
+                self.remove_mapping(&address);
+                Some(PeerMessage::RemoveTarget { address })
+            },
+        })
+    }
+
+    /** Forward a datagram, opening a new flow for its client if necessary (connect side) */
+    fn handle_datagram(
+        &mut self,
+        mapping: String,
+        socket: Arc<UdpSocket>,
+        client: SocketAddr,
+        payload: Vec<u8>,
+    ) -> Vec<PeerMessage> {
+        /* The mapping may have been removed in the meantime */
+        if !self.listeners.contains_key(&mapping) {
+            return Vec::new();
+        }
+        let mut messages = Vec::with_capacity(2);
+        let flow = (mapping, client);
+        let connection = match self.udp_flows.get(&flow) {
+            Some(connection) => *connection,
+            None => {
+                let connection = self.next_connection;
+                self.next_connection += 1;
+                let (mapping, client) = flow;
+                self.state
+                    .update(&mapping, |mapping| mapping.connections += 1);
+                let mut entry = Connection::new(mapping.clone());
+                entry.connected(
+                    Socket::Udp(socket, Some(client)),
+                    connection,
+                    self.events_tx.clone(),
+                );
+                self.connections.insert(connection, entry);
+                self.udp_flows.insert((mapping.clone(), client), connection);
+                messages.push(PeerMessage::Connect {
+                    target: mapping,
+                    connection,
+                });
+                connection
+            },
+        };
+        let mapping = &self.connections[&connection].mapping;
+        self.state.update(mapping, |mapping| {
+            mapping.bytes_sent += payload.len() as u64
+        });
+        messages.push(PeerMessage::Packet {
+            connection,
+            payload,
+        });
+        messages
+    }
+
+    /** Accept connections for a mapping (connect side) */
+    fn listen(&mut self, listener: Listener, service: Service) {
+        let port = listener.local_port().ok();
+        self.state.insert(&service, port);
+        let address = service.address;
+        let events = self.events_tx.clone();
+        let (task, abort) = match listener {
+            Listener::Tcp(listener) => {
+                abortable(accept_connections(listener, address.clone(), events).left_future())
+            },
+            Listener::Udp(socket) => {
+                let socket = Arc::new(socket);
+                abortable(accept_datagrams(socket, address.clone(), events).right_future())
+            },
+        };
+        async_std::task::spawn(task);
+        self.listeners.insert(address, abort);
+    }
+
+    /** Stop forwarding a mapping and close its connections. Both sides do this on their own */
+    fn remove_mapping(&mut self, address: &str) {
+        if let Some(abort) = self.listeners.remove(address) {
+            abort.abort();
+        }
+        let connections: Vec<u64> = self
+            .connections
+            .iter()
+            .filter(|(_, entry)| entry.mapping == address)
+            .map(|(connection, _)| *connection)
+            .collect();
+        for connection in connections {
+            self.close(connection);
+        }
+        self.state.remove(address);
+    }
+
+    fn close(&mut self, connection: u64) {
+        if let Some(entry) = self.connections.remove(&connection) {
+            self.state
+                .update(&entry.mapping, |mapping| mapping.connections -= 1);
+            match entry.socket {
+                Some(Socket::Udp(_, Some(client))) => {
+                    self.udp_flows.remove(&(entry.mapping, client));
+                },
+                /* The writing task closes the rest, once it wrote what the peer sent before */
+                Some(socket) => socket.shutdown(Shutdown::Read),
+                None => {},
+            }
+        }
+    }
+}
+
+/** Hand every incoming connection on `listener` to the forwarder */
+async fn accept_connections(
+    listener: TcpListener,
+    address: String,
+    mut events: mpsc::Sender<Event>,
+) {
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        match stream {
+            Ok(stream) => {
+                let event = Event::Incoming(address.clone(), address.clone(), stream);
+                if events.send(event).await.is_err() {
+                    break;
+                }
+            },
+            Err(err) => warn!("Failed to accept a connection for {}: {}", address, err),
+        }
+    }
+}
+
+/** Hand every datagram that arrives on `socket` to the forwarder */
+async fn accept_datagrams(
+    socket: Arc<UdpSocket>,
+    address: String,
+    mut events: mpsc::Sender<Event>,
+) {
+    let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+    loop {
+        match socket.recv_from(&mut buffer).await {
+            Ok((n, client)) => {
+                let event = Event::Datagram(
+                    address.clone(),
+                    socket.clone(),
+                    client,
+                    buffer[..n].to_vec(),
+                );
+                if events.send(event).await.is_err() {
+                    break;
+                }
+            },
+            Err(err) => warn!("Failed to receive a datagram for {}: {}", address, err),
+        }
+    }
+}
+
+/**
+ * Connect to `target` on behalf of the peer, and write what it sent from `queue` to it
+ *
+ * The writing starts here and not in the forwarder, which may be waiting for the queue
+ * to make room.
+ */
+async fn open_connection(
+    target: String,
+    connection: u64,
+    queue: mpsc::Receiver<Vec<u8>>,
+    mut events: mpsc::Sender<Event>,
+) {
+    let socket = if let Some(target) = target.strip_prefix(UDP_PREFIX) {
+        open_udp(target).await
+    } else if let Some(path) = target.strip_prefix(UNIX_PREFIX) {
+        open_unix(path).await
+    } else {
+        TcpStream::connect(&target).await.map(Socket::Tcp)
+    };
+    let event = match socket {
+        Ok(socket) => {
+            let task = write_connection(socket.clone(), queue, connection, events.clone());
+            async_std::task::spawn(task);
+            Event::Connected(connection, socket)
+        },
+        Err(err) => {
+            warn!("Failed to connect to {}: {}", target, err);
+            Event::Send(PeerMessage::Close { connection })
+        },
+    };
+    let _ = events.send(event).await;
+}
+
+/** Get a socket of its own for a UDP flow, connected to `target` */
+async fn open_udp(target: &str) -> std::io::Result<Socket> {
+    let target = target.to_socket_addrs().await?.next().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "Could not resolve the address",
+        )
+    })?;
+    let unspecified: IpAddr = match target {
+        SocketAddr::V4(_) => std::net::Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => std::net::Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = UdpSocket::bind((unspecified, 0)).await?;
+    socket.connect(target).await?;
+    Ok(Socket::Udp(Arc::new(socket), None))
+}
+
+#[cfg(unix)]
+async fn open_unix(path: &str) -> std::io::Result<Socket> {
+    async_std::os::unix::net::UnixStream::connect(path)
+        .await
+        .map(Socket::Unix)
+}
+
+#[cfg(not(unix))]
+async fn open_unix(_path: &str) -> std::io::Result<Socket> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "Unix domain sockets are not supported on this platform",
+    ))
+}
+
+/** Forward everything that arrives on `stream` to the peer, until it gets closed */
+async fn read_connection(
+    mut stream: impl AsyncRead + Unpin,
+    connection: u64,
+    mut events: mpsc::Sender<Event>,
+) {
+    let mut buffer = vec![0; BUFFER_SIZE];
+    loop {
+        let message = match stream.read(&mut buffer).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => PeerMessage::Packet {
+                connection,
+                payload: buffer[..n].to_vec(),
+            },
+        };
+        if events.send(Event::Send(message)).await.is_err() {
+            return;
+        }
+    }
+    let _ = events
+        .send(Event::Send(PeerMessage::Close { connection }))
+        .await;
+}
+
+/** Write what the peer sends to the local end of a connection, until the connection is closed */
+async fn write_connection(
+    mut socket: Socket,
+    mut queue: mpsc::Receiver<Vec<u8>>,
+    connection: u64,
+    mut events: mpsc::Sender<Event>,
+) {
+    while let Some(payload) = queue.next().await {
+        if let Err(err) = socket.write(&payload).await {
+            debug!("Failed to write to connection {}: {}", connection, err);
+            /* Don't keep the forwarder waiting for room in the queue */
+            drop(queue);
+            let _ = events
+                .send(Event::Send(PeerMessage::Close { connection }))
+                .await;
+            return;
+        }
+    }
+    socket.shutdown(Shutdown::Both);
+}
+
+/** Forward the answers of a UDP flow to the peer, until it is idle for too long */
+async fn read_datagrams(socket: Arc<UdpSocket>, connection: u64, mut events: mpsc::Sender<Event>) {
+    let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+    while let Ok(n) = async_std::io::timeout(UDP_TIMEOUT, socket.recv(&mut buffer)).await {
+        let message = PeerMessage::Packet {
+            connection,
+            payload: buffer[..n].to_vec(),
+        };
+        if events.send(Event::Send(message)).await.is_err() {
+            return;
+        }
+    }
+    let _ = events
+        .send(Event::Send(PeerMessage::Close { connection }))
+        .await;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_messages() {
+        let messages = vec![
+            PeerMessage::Offer {
+                addresses: vec!["localhost:8080".into(), "localhost:22".into()],
+                socks: true,
+                names: vec![("localhost:22".into(), "ssh".into())]
+                    .into_iter()
+                    .collect(),
+            },
+            PeerMessage::Connect {
+                target: "localhost:8080".into(),
+                connection: 0,
+            },
+            PeerMessage::Packet {
+                connection: 0,
+                payload: b"GET / HTTP/1.1\r\n".to_vec(),
+            },
+            PeerMessage::Close { connection: 0 },
+            PeerMessage::AddTarget {
+                address: "localhost:8081".into(),
+                name: Some("web".into()),
+            },
+            PeerMessage::RemoveTarget {
+                address: "localhost:8081".into(),
+            },
+            PeerMessage::Error("rejected".into()),
+        ];
+        for message in messages {
+            assert_eq!(
+                PeerMessage::de_msgpack(&message.ser_msgpack()).unwrap(),
+                message
+            );
+        }
+
+        /* Payloads are sent as binary */
+        let packet = PeerMessage::Packet {
+            connection: 0,
+            payload: vec![0; 1024],
+        };
+        assert!(packet.ser_msgpack().len() < 1100);
+    }
+
+    #[test]
+    fn test_forwarding_state() {
+        let state = ForwardingState::new();
+        state.insert(&Service::new("localhost:8080").name("web"), Some(1234));
+        state.insert(&Service::new(SOCKS_MAPPING), None);
+        state.update("localhost:8080", |mapping| mapping.bytes_sent += 10);
+        /* Unknown mappings are ignored */
+        state.update("localhost:8081", |mapping| mapping.bytes_sent += 10);
+
+        let mappings = state.clone().mappings();
+        assert_eq!(mappings.len(), 2);
+        assert_eq!(mappings[0].address, SOCKS_MAPPING);
+        assert_eq!(mappings[1].name.as_deref(), Some("web"));
+        assert_eq!(mappings[1].local_port, Some(1234));
+        assert_eq!(mappings[1].bytes_sent, 10);
+
+        /* Commands get queued until the session starts */
+        state.add_target("localhost:8081");
+        let mut commands = state.0.commands_rx.lock().unwrap().take().unwrap();
+        assert!(matches!(
+            commands.try_next(),
+            Ok(Some(Command::Add(service))) if service == Service::new("localhost:8081")
+        ));
+    }
+
+    #[async_std::test]
+    async fn test_udp_flow() -> std::io::Result<()> {
+        let server = UdpSocket::bind((std::net::Ipv4Addr::LOCALHOST, 0)).await?;
+        let target = server.local_addr()?.to_string();
+        let mut flow = open_udp(&target).await?;
+
+        /* The serving side's socket is connected to the target */
+        flow.write(b"ping").await?;
+        let mut buffer = [0; 16];
+        let (n, from) = server.recv_from(&mut buffer).await?;
+        assert_eq!(&buffer[..n], b"ping");
+
+        /* The target's answer goes back to the peer */
+        server.send_to(b"pong", from).await?;
+        let (events_tx, mut events_rx) = mpsc::channel(1);
+        let flow_socket = match &flow {
+            Socket::Udp(socket, None) => socket.clone(),
+            _ => panic!("Expected a UDP flow"),
+        };
+        async_std::task::spawn(read_datagrams(flow_socket, 0, events_tx));
+        let payload = match events_rx.next().await {
+            Some(Event::Send(PeerMessage::Packet {
+                connection: 0,
+                payload,
+            })) => payload,
+            _ => panic!("Expected the answer as packet"),
+        };
+        assert_eq!(payload, b"pong");
+
+        /* And the connecting side passes it on to the client it got the flow from */
+        let client = UdpSocket::bind((std::net::Ipv4Addr::LOCALHOST, 0)).await?;
+        let mut answer = Socket::Udp(Arc::new(server), Some(client.local_addr()?));
+        answer.write(&payload).await?;
+        let (n, _) = client.recv_from(&mut buffer).await?;
+        assert_eq!(&buffer[..n], b"pong");
+        assert_ne!(from, client.local_addr()?);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[async_std::test]
+    async fn test_unix_socket() -> std::io::Result<()> {
+        use async_std::os::unix::net::UnixListener;
+
+        let path = std::env::temp_dir().join(format!("wormhole-forward-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).await?;
+        let target = format!("{}{}", UNIX_PREFIX, path.display());
+
+        let (events_tx, mut events_rx) = mpsc::channel(1);
+        let (_writer, queue) = mpsc::channel(1);
+        open_connection(target, 0, queue, events_tx).await;
+        let mut socket = match events_rx.next().await {
+            Some(Event::Connected(0, socket @ Socket::Unix(_))) => socket,
+            _ => panic!("Expected a connected Unix socket"),
+        };
+        let (mut server, _) = listener.accept().await?;
+        socket.write(b"ping").await?;
+        let mut buffer = [0; 4];
+        server.read_exact(&mut buffer).await?;
+        assert_eq!(&buffer, b"ping");
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_socks_targets() -> Result<(), ForwardingError> {
+        let (mut forwarder, _events_rx) = Forwarder::new(ForwardingState::new(), None);
+        forwarder.socks = true;
+
+        let answer = forwarder
+            .handle_message(PeerMessage::Connect {
+                target: format!("{}/run/docker.sock", UNIX_PREFIX),
+                connection: 0,
+            })
+            .await?;
+        assert_eq!(answer, Some(PeerMessage::Close { connection: 0 }));
+
+        let answer = forwarder
+            .handle_message(PeerMessage::Connect {
+                target: format!("{}localhost:53", UDP_PREFIX),
+                connection: 1,
+            })
+            .await?;
+        assert_eq!(answer, Some(PeerMessage::Close { connection: 1 }));
+        assert!(forwarder.connections.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_service() {
+        let service = Service::new("localhost:22").name("ssh");
+        assert!(service.matches("ssh"));
+        assert!(service.matches("localhost:22"));
+        assert!(!service.matches("web"));
+        assert!(!Service::new("localhost:80").matches("ssh"));
+    }
+}
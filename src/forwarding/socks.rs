@@ -0,0 +1,171 @@
+//! A minimal SOCKS5 server (RFC 1928), for tunneling arbitrary connections
+//!
+//! Only the `CONNECT` command without authentication is supported, which is what browsers
+//! and most other clients use. The proxy is bound to the loopback address by default, so
+//! the lack of authentication is fine.
+//!
+//! The client is told that the connection succeeded as soon as the request got parsed,
+//! because the actual connection is made by the other side. If that fails, the client
+//! connection simply gets closed.
+
+use super::{Event, SOCKS_MAPPING};
+use async_std::net::TcpListener;
+use futures::{
+    channel::mpsc, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, SinkExt, StreamExt,
+};
+use log::*;
+use std::{
+    io::{Error, ErrorKind},
+    net::{Ipv4Addr, Ipv6Addr},
+};
+
+const VERSION: u8 = 5;
+const METHOD_NO_AUTHENTICATION: u8 = 0;
+const METHOD_NOT_ACCEPTABLE: u8 = 0xff;
+const COMMAND_CONNECT: u8 = 1;
+const ADDRESS_IPV4: u8 = 1;
+const ADDRESS_DOMAIN: u8 = 3;
+const ADDRESS_IPV6: u8 = 4;
+const REPLY_SUCCEEDED: u8 = 0;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 7;
+const REPLY_ADDRESS_NOT_SUPPORTED: u8 = 8;
+
+/** Hand every incoming proxy connection to the forwarder, once it said where to go */
+pub(super) async fn accept_connections(listener: TcpListener, events: mpsc::Sender<Event>) {
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        match stream {
+            Ok(stream) => {
+                /* Don't let slow clients block the others */
+                let mut events = events.clone();
+                async_std::task::spawn(async move {
+                    let mut stream = stream;
+                    match handshake(&mut stream).await {
+                        Ok(target) => {
+                            let event = Event::Incoming(SOCKS_MAPPING.into(), target, stream);
+                            let _ = events.send(event).await;
+                        },
+                        Err(err) => debug!("SOCKS handshake failed: {}", err),
+                    }
+                });
+            },
+            Err(err) => warn!("Failed to accept a SOCKS connection: {}", err),
+        }
+        if events.is_closed() {
+            break;
+        }
+    }
+}
+
+/** Returns the requested address, in the form `host:port` */
+async fn handshake(stream: &mut (impl AsyncRead + AsyncWrite + Unpin)) -> std::io::Result<String> {
+    /* Method selection */
+    let mut header = [0; 2];
+    stream.read_exact(&mut header).await?;
+    ensure!(
+        header[0] == VERSION,
+        Error::new(ErrorKind::InvalidData, "Unsupported SOCKS version")
+    );
+    let mut methods = vec![0; header[1] as usize];
+    stream.read_exact(&mut methods).await?;
+    if !methods.contains(&METHOD_NO_AUTHENTICATION) {
+        stream.write_all(&[VERSION, METHOD_NOT_ACCEPTABLE]).await?;
+        bail!(Error::new(
+            ErrorKind::InvalidData,
+            "The client requires authentication"
+        ));
+    }
+    stream
+        .write_all(&[VERSION, METHOD_NO_AUTHENTICATION])
+        .await?;
+
+    /* The request */
+    let mut request = [0; 4];
+    stream.read_exact(&mut request).await?;
+    ensure!(
+        request[0] == VERSION,
+        Error::new(ErrorKind::InvalidData, "Unsupported SOCKS version")
+    );
+    if request[1] != COMMAND_CONNECT {
+        reply(stream, REPLY_COMMAND_NOT_SUPPORTED).await?;
+        bail!(Error::new(
+            ErrorKind::InvalidData,
+            format!("Unsupported SOCKS command {}", request[1])
+        ));
+    }
+    let host = match request[3] {
+        ADDRESS_IPV4 => {
+            let mut address = [0; 4];
+            stream.read_exact(&mut address).await?;
+            Ipv4Addr::from(address).to_string()
+        },
+        ADDRESS_DOMAIN => {
+            let mut length = [0; 1];
+            stream.read_exact(&mut length).await?;
+            let mut domain = vec![0; length[0] as usize];
+            stream.read_exact(&mut domain).await?;
+            String::from_utf8(domain).map_err(|err| Error::new(ErrorKind::InvalidData, err))?
+        },
+        ADDRESS_IPV6 => {
+            let mut address = [0; 16];
+            stream.read_exact(&mut address).await?;
+            format!("[{}]", Ipv6Addr::from(address))
+        },
+        other => {
+            reply(stream, REPLY_ADDRESS_NOT_SUPPORTED).await?;
+            bail!(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unsupported SOCKS address type {}", other)
+            ));
+        },
+    };
+    let mut port = [0; 2];
+    stream.read_exact(&mut port).await?;
+
+    reply(stream, REPLY_SUCCEEDED).await?;
+    Ok(format!("{}:{}", host, u16::from_be_bytes(port)))
+}
+
+/** We don't know the address the other side used, so we always send the unspecified one */
+async fn reply(stream: &mut (impl AsyncWrite + Unpin), code: u8) -> std::io::Result<()> {
+    let mut reply = vec![VERSION, code, 0, ADDRESS_IPV4];
+    reply.extend_from_slice(&[0; 4]);
+    reply.extend_from_slice(&0u16.to_be_bytes());
+    stream.write_all(&reply).await?;
+    stream.flush().await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_std::net::TcpStream;
+
+    #[async_std::test]
+    async fn test_handshake() -> std::io::Result<()> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let address = listener.local_addr()?;
+        let client = async_std::task::spawn(async move {
+            let mut stream = TcpStream::connect(address).await?;
+            stream
+                .write_all(&[VERSION, 1, METHOD_NO_AUTHENTICATION])
+                .await?;
+            let mut method = [0; 2];
+            stream.read_exact(&mut method).await?;
+            assert_eq!(method, [VERSION, METHOD_NO_AUTHENTICATION]);
+
+            stream
+                .write_all(&[VERSION, COMMAND_CONNECT, 0, ADDRESS_DOMAIN, 11])
+                .await?;
+            stream.write_all(b"example.org").await?;
+            stream.write_all(&443u16.to_be_bytes()).await?;
+            let mut reply = [0; 10];
+            stream.read_exact(&mut reply).await?;
+            assert_eq!(reply[1], REPLY_SUCCEEDED);
+            std::io::Result::Ok(())
+        });
+
+        let (mut stream, _) = listener.accept().await?;
+        assert_eq!(handshake(&mut stream).await?, "example.org:443");
+        client.await
+    }
+}
@@ -16,17 +16,33 @@
 //! connection. A transit is little more than an encrypted TcpConnection. If a direct connection between both clients is not possible,
 //! a relay server will transparently connect them together. Transit is used by the file transfer for example, but any other AppID protocol
 //! might make use of it as well.
+//!
+//! The [`forwarding`] module is such a protocol: it forwards TCP ports from one side to the other over a transit connection.
 
-#![forbid(unsafe_code)]
+/* The C bindings can't do without */
+#![cfg_attr(not(feature = "ffi"), forbid(unsafe_code))]
+#![cfg_attr(feature = "ffi", deny(unsafe_code))]
 #![allow(clippy::upper_case_acronyms)]
 
 #[macro_use]
 mod util;
+pub mod blocking;
 mod core;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod forwarding;
+pub mod proxy;
+pub mod resolver;
+pub mod ssh;
 pub mod transfer;
 pub mod transit;
 
 pub use crate::core::{
+    error_code::{ErrorCode, ErrorOrigin},
+    events::{WormholeEvent, WormholeEvents},
     key::{GenericKey, Key, KeyPurpose, WormholeKey},
-    rendezvous, AppConfig, AppID, Code, Wormhole, WormholeError, WormholeWelcome,
+    rendezvous,
+    wordlist::{default_wordlist, Wordlist},
+    AppConfig, AppID, Code, Mailbox, Nameplate, ParseCodeError, Wormhole, WormholeError,
+    WormholeReceiver, WormholeSender, WormholeWelcome,
 };
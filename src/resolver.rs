@@ -0,0 +1,275 @@
+//! Custom host name resolution
+//!
+//! By default, host names are resolved by the operating system. Applications that need control over
+//! this — enforcing DNS over HTTPS or TLS, split-horizon DNS, resolving through a proxy — can provide their
+//! own [`Resolver`]. It will then be used for all outgoing connections: to the rendezvous server,
+//! to the transit relays, to the STUN server and to direct hints that are not IP addresses already.
+//!
+//! IP address literals never go through the resolver.
+//!
+//! If a host has multiple addresses, they are tried like "Happy Eyeballs" (RFC 8305) says: the
+//! address families take turns, and each attempt gets a head start of 250 ms before the next
+//! one starts. So if IPv6 (or IPv4) is broken, connecting only takes a bit longer.
+
+use async_std::net::TcpStream;
+use futures::{future::BoxFuture, StreamExt};
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+/** How long to wait for a connection attempt before starting the next one, as RFC 8305 recommends */
+pub(crate) const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/**
+ * Something that can look up the addresses of a host name
+ *
+ * This is implemented for all closures `Fn(String, u16) -> impl Future<Output = io::Result<Vec<SocketAddr>>>`,
+ * so in most cases you won't need to implement it yourself.
+ */
+pub trait Resolve: Send + Sync {
+    /**
+     * Resolve `host` to a list of socket addresses with the given `port`
+     *
+     * The addresses will be tried in order, but alternating between IPv6 and IPv4, starting
+     * with the family of the first one. Return an error (and not an empty list) if the name
+     * cannot be resolved.
+     */
+    fn resolve(&self, host: String, port: u16) -> BoxFuture<'static, io::Result<Vec<SocketAddr>>>;
+}
+
+impl<F, Fut> Resolve for F
+where
+    F: Fn(String, u16) -> Fut + Send + Sync,
+    Fut: std::future::Future<Output = io::Result<Vec<SocketAddr>>> + Send + 'static,
+{
+    fn resolve(&self, host: String, port: u16) -> BoxFuture<'static, io::Result<Vec<SocketAddr>>> {
+        Box::pin(self(host, port))
+    }
+}
+
+/**
+ * A custom [`Resolve`] implementation, to be put into the configuration
+ *
+ * Equality is based on identity: two resolvers are equal if they are clones of each other.
+ */
+#[derive(Clone)]
+pub struct Resolver(Arc<dyn Resolve>);
+
+impl Resolver {
+    pub fn new(resolver: impl Resolve + 'static) -> Self {
+        Self(Arc::new(resolver))
+    }
+
+    /**
+     * Only resolve the given host names to fixed addresses, and fail for all others
+     *
+     * This is useful if the addresses were obtained out of band, or to make sure that
+     * no DNS queries are made at all.
+     */
+    pub fn fixed(hosts: impl IntoIterator<Item = (String, Vec<IpAddr>)>) -> Self {
+        let hosts: Arc<std::collections::HashMap<String, Vec<IpAddr>>> =
+            Arc::new(hosts.into_iter().collect());
+        Self::new(move |host: String, port: u16| {
+            let addresses = hosts.get(&host).map(|addresses| {
+                addresses
+                    .iter()
+                    .map(|ip| SocketAddr::new(*ip, port))
+                    .collect::<Vec<_>>()
+            });
+            async move {
+                addresses.ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("No address configured for host {}", host),
+                    )
+                })
+            }
+        })
+    }
+}
+
+impl std::fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Resolver(..)")
+    }
+}
+
+impl PartialEq for Resolver {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Resolver {}
+
+/** Resolve a host name, using the system resolver if no custom one is given */
+pub(crate) async fn resolve(
+    resolver: Option<&Resolver>,
+    host: &str,
+    port: u16,
+) -> io::Result<Vec<SocketAddr>> {
+    /* Strip the brackets of IPv6 literals in URLs */
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+    let addresses = match resolver {
+        Some(resolver) => resolver.0.resolve(host.to_owned(), port).await?,
+        None => async_std::net::ToSocketAddrs::to_socket_addrs(&(host, port))
+            .await?
+            .collect(),
+    };
+    ensure!(
+        !addresses.is_empty(),
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Could not resolve host {}", host)
+        )
+    );
+    Ok(addresses)
+}
+
+/**
+ * Sort addresses for connecting, like RFC 8305 says: keep their order, but let the address
+ * families take turns, starting with the family of the first one
+ */
+pub(crate) fn interleave_families<T>(items: Vec<T>, is_ipv6: impl Fn(&T) -> bool) -> Vec<T> {
+    let first_is_ipv6 = items.first().map(&is_ipv6).unwrap_or(true);
+    let (first, second): (Vec<T>, Vec<T>) = items
+        .into_iter()
+        .partition(|item| is_ipv6(item) == first_is_ipv6);
+    let mut interleaved = Vec::with_capacity(first.len() + second.len());
+    let (mut first, mut second) = (first.into_iter(), second.into_iter());
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => break interleaved,
+            (a, b) => interleaved.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/**
+ * Try to connect to all addresses, in the order of [`interleave_families`]
+ *
+ * The next attempt starts once the previous one failed, or after [`CONNECTION_ATTEMPT_DELAY`]. The
+ * first successful connection wins, and the attempts still running get cancelled.
+ */
+pub(crate) async fn happy_eyeballs<T, Fut>(
+    addresses: Vec<SocketAddr>,
+    connect: impl Fn(SocketAddr) -> Fut,
+) -> io::Result<T>
+where
+    Fut: std::future::Future<Output = io::Result<T>>,
+{
+    use futures::future::Either;
+
+    let mut addresses = interleave_families(addresses, SocketAddr::is_ipv6).into_iter();
+    let mut attempts = futures::stream::FuturesUnordered::new();
+    let mut last_error = None;
+    loop {
+        if attempts.is_empty() {
+            match addresses.next() {
+                Some(address) => attempts.push(connect(address)),
+                None => break,
+            }
+        }
+        let delay = Box::pin(async_std::task::sleep(CONNECTION_ATTEMPT_DELAY));
+        let finished = match futures::future::select(attempts.next(), delay).await {
+            Either::Left((finished, _)) => finished,
+            Either::Right(_) => None,
+        };
+        match finished {
+            Some(Ok(connection)) => return Ok(connection),
+            Some(Err(error)) => last_error = Some(error),
+            /* Give the next address a try, without waiting for the others */
+            None => {},
+        }
+        if let Some(address) = addresses.next() {
+            attempts.push(connect(address));
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "No addresses to connect to")
+    }))
+}
+
+/** Resolve a host name and connect to the first address that works */
+pub(crate) async fn connect(
+    resolver: Option<&Resolver>,
+    host: &str,
+    port: u16,
+) -> io::Result<TcpStream> {
+    happy_eyeballs(resolve(resolver, host, port).await?, |address| async move {
+        TcpStream::connect(address).await.map_err(|error| {
+            log::debug!("Failed to connect to {} ({}): {}", host, address, error);
+            error
+        })
+    })
+    .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[async_std::test]
+    async fn test_fixed_resolver() {
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let resolver = Resolver::fixed(vec![("relay.example".to_string(), vec![ip])]);
+
+        assert_eq!(
+            resolve(Some(&resolver), "relay.example", 4001)
+                .await
+                .unwrap(),
+            vec![SocketAddr::new(ip, 4001)]
+        );
+        assert!(resolve(Some(&resolver), "other.example", 4001)
+            .await
+            .is_err());
+        /* IP literals bypass the resolver */
+        assert_eq!(
+            resolve(Some(&resolver), "[::1]", 80).await.unwrap(),
+            vec!["[::1]:80".parse().unwrap()]
+        );
+    }
+
+    #[async_std::test]
+    async fn test_happy_eyeballs() {
+        let addresses: Vec<SocketAddr> = vec![
+            "[2001:db8::1]:1".parse().unwrap(),
+            "[2001:db8::2]:2".parse().unwrap(),
+            "192.0.2.1:3".parse().unwrap(),
+            "192.0.2.2:4".parse().unwrap(),
+            "[2001:db8::3]:5".parse().unwrap(),
+        ];
+        let ports = |addresses: Vec<SocketAddr>| -> Vec<u16> {
+            addresses.iter().map(SocketAddr::port).collect()
+        };
+        assert_eq!(
+            ports(interleave_families(addresses.clone(), SocketAddr::is_ipv6)),
+            vec![1, 3, 2, 4, 5]
+        );
+
+        /* The first attempt hangs, and the second one fails right away */
+        let started = std::sync::Mutex::new(Vec::new());
+        let start = std::time::Instant::now();
+        let connected = happy_eyeballs(addresses, |address| {
+            started.lock().unwrap().push(address.port());
+            async move {
+                match address.port() {
+                    1 => futures::future::pending().await,
+                    3 => Err(io::ErrorKind::ConnectionRefused.into()),
+                    port => Ok(port),
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(connected, 2);
+        assert_eq!(*started.lock().unwrap(), vec![1, 3, 2]);
+        assert!(start.elapsed() >= CONNECTION_ATTEMPT_DELAY);
+    }
+}
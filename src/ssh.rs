@@ -0,0 +1,358 @@
+//! Client-to-Client protocol to hand out SSH access
+//!
+//! This is the equivalent of `wormhole ssh invite` and `wormhole ssh accept` of the Python
+//! implementation, and interoperates with it. Whoever wants to grant access allocates a code
+//! and waits for a public key with [`receive_key`]; the other side sends one of its public keys
+//! with [`send_key`]. The receiving side then usually appends it to its `authorized_keys` with
+//! [`add_authorized_key`], but it may do anything else with it too.
+//!
+//! The key is small enough to go over the wormhole itself, so no transit connection is made.
+//! The protocol is bound to its own [`APPID`](APPID) and thus does not interoperate with file
+//! transfers.
+
+use super::{core::WormholeError, AppID, Wormhole};
+use async_std::fs;
+use futures::AsyncWriteExt;
+use log::*;
+use serde_derive::{Deserialize, Serialize};
+use std::{borrow::Cow, path::Path};
+
+const APPID_RAW: &str = "lothar.com/wormhole/ssh";
+
+/// The App ID associated with this protocol.
+pub const APPID: AppID = AppID(Cow::Borrowed(APPID_RAW));
+
+/// An [`crate::AppConfig`] with sane defaults for this protocol.
+///
+/// You **must not** change `id` and `rendezvous_url` to be interoperable.
+pub const APP_CONFIG: crate::AppConfig<AppVersion> = crate::AppConfig::<AppVersion> {
+    id: AppID(Cow::Borrowed(APPID_RAW)),
+    rendezvous_url: Cow::Borrowed(crate::rendezvous::DEFAULT_RENDEZVOUS_SERVER),
+    app_version: AppVersion {},
+    rendezvous_tap: None,
+    resolver: None,
+    proxy: None,
+    events: None,
+    wordlist: None,
+    keepalive: Some(crate::rendezvous::DEFAULT_KEEPALIVE),
+};
+
+/**
+ * The app version of the SSH invitation protocol
+ *
+ * There is nothing to negotiate.
+ */
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AppVersion {}
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum SshError {
+    /// The other side answered with an error
+    #[error("Something went wrong on the other side: {}", _0)]
+    PeerError(String),
+    /// What we got (or were asked to send) does not look like an SSH public key
+    #[error("Not a valid SSH public key: {}", _0)]
+    InvalidKey(Box<str>),
+    /// Some deserialization went wrong, we probably got some garbage
+    #[error("Corrupt JSON message received")]
+    ProtocolJson(
+        #[from]
+        #[source]
+        serde_json::Error,
+    ),
+    /// A generic string message for "something went wrong", i.e.
+    /// the peer sent some bullshit message order
+    #[error("Protocol error: {}", _0)]
+    Protocol(Box<str>),
+    #[error("Wormhole connection error")]
+    Wormhole(
+        #[from]
+        #[source]
+        WormholeError,
+    ),
+    #[error("IO error")]
+    IO(
+        #[from]
+        #[source]
+        std::io::Error,
+    ),
+}
+
+/** The messages exchanged over the wormhole, the same as for text messages in Python's `xfer_util` */
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum PeerMessage {
+    /** Sent by the accepting side, with the public key in `message` */
+    Offer { message: String },
+    /** Sent back by the inviting side once it got the key, `message_ack` is "ok" */
+    Answer { message_ack: String },
+    /** Something went wrong, for example the key was not accepted */
+    Error(String),
+    #[serde(other)]
+    Unknown,
+}
+
+/**
+ * A single SSH public key, as found in `id_*.pub` and `authorized_keys` files
+ *
+ * It looks like `ssh-ed25519 AAAAC3Nza… user@host`: the key type, the key itself in base64, and
+ * an optional comment. Parse one with [`str::parse`]. Leading options like `command="…"` are not
+ * allowed, and neither are several lines, so that nothing but the key ends up in
+ * `authorized_keys`.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PublicKey {
+    line: String,
+}
+
+impl PublicKey {
+    /** The key type, like `ssh-ed25519` or `ecdsa-sha2-nistp256` */
+    pub fn kind(&self) -> &str {
+        self.line.split_whitespace().next().unwrap()
+    }
+
+    /** The key itself, in base64 */
+    pub fn key(&self) -> &str {
+        self.line.split_whitespace().nth(1).unwrap()
+    }
+
+    /** The comment after the key, usually `user@host`. This is the "keyid" in Python */
+    pub fn comment(&self) -> Option<&str> {
+        self.line
+            .splitn(3, char::is_whitespace)
+            .nth(2)
+            .map(str::trim)
+            .filter(|comment| !comment.is_empty())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.line
+    }
+}
+
+impl std::str::FromStr for PublicKey {
+    type Err = SshError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let line = line.trim();
+        ensure!(
+            !line.contains(&['\n', '\r'][..]),
+            SshError::InvalidKey("it spans several lines".into())
+        );
+        let mut parts = line.split_whitespace();
+        let kind = parts.next().unwrap_or_default();
+        ensure!(
+            ["ssh-", "ecdsa-", "sk-"]
+                .iter()
+                .any(|prefix| kind.starts_with(prefix)),
+            SshError::InvalidKey(format!("unknown key type '{}'", kind).into())
+        );
+        let key = parts.next().unwrap_or_default();
+        ensure!(
+            !key.is_empty()
+                && key
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "+/=".contains(c)),
+            SshError::InvalidKey("the key is not in base64".into())
+        );
+        /* Normalize the whitespace between kind and key, it is compared later on */
+        let comment = line[kind.len()..].trim_start()[key.len()..].trim();
+        let line = match comment {
+            "" => format!("{} {}", kind, key),
+            comment => format!("{} {} {}", kind, key, comment),
+        };
+        Ok(PublicKey { line })
+    }
+}
+
+impl std::fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.line)
+    }
+}
+
+/**
+ * Send our public key to the side that invited us
+ *
+ * This returns once the other side acknowledged it. The wormhole is closed afterwards.
+ */
+pub async fn send_key(mut wormhole: Wormhole, key: &PublicKey) -> Result<(), SshError> {
+    debug!("Sending public key of type {}", key.kind());
+    wormhole
+        .send_json(&PeerMessage::Offer {
+            message: key.to_string(),
+        })
+        .await?;
+
+    let result = match wormhole.receive_json().await?? {
+        PeerMessage::Answer { message_ack } if message_ack == "ok" => Ok(()),
+        PeerMessage::Error(error) => Err(SshError::PeerError(error)),
+        other => Err(SshError::Protocol(
+            format!("Expected an answer, got {:?}", other).into(),
+        )),
+    };
+    wormhole.close().await?;
+    result
+}
+
+/**
+ * Wait for the public key of the side we invited
+ *
+ * Invalid keys are rejected, and the other side gets told so. The wormhole is closed
+ * afterwards. Most likely, the key should go to [`add_authorized_key`] next.
+ */
+pub async fn receive_key(mut wormhole: Wormhole) -> Result<PublicKey, SshError> {
+    let result = match wormhole.receive_json().await?? {
+        PeerMessage::Offer { message } => message.parse::<PublicKey>(),
+        other => Err(SshError::Protocol(
+            format!("Expected a public key, got {:?}", other).into(),
+        )),
+    };
+    let answer = match &result {
+        Ok(_) => PeerMessage::Answer {
+            message_ack: "ok".into(),
+        },
+        Err(error) => PeerMessage::Error(error.to_string()),
+    };
+    wormhole.send_json(&answer).await?;
+    wormhole.close().await?;
+    result
+}
+
+/**
+ * Append a public key to an `authorized_keys` file, usually `~/.ssh/authorized_keys`
+ *
+ * The file and its directory are created if needed, accessible only to the current user.
+ * Returns `false` if the key was already in there, in which case it is not added again.
+ */
+pub async fn add_authorized_key(path: &Path, key: &PublicKey) -> Result<bool, SshError> {
+    let content = match fs::read_to_string(path).await {
+        Ok(content) => content,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                let mut builder = fs::DirBuilder::new();
+                builder.recursive(true);
+                #[cfg(unix)]
+                async_std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+                builder.create(dir).await?;
+            }
+            String::new()
+        },
+        Err(error) => return Err(error.into()),
+    };
+    /* Other lines may have options in front of the key */
+    let known = content.lines().any(|line| {
+        let mut parts = line.split_whitespace();
+        parts.any(|part| part == key.kind()) && parts.next() == Some(key.key())
+    });
+    if known {
+        debug!("The key is in {} already", path.display());
+        return Ok(false);
+    }
+
+    let mut options = fs::OpenOptions::new();
+    options.append(true).create(true);
+    #[cfg(unix)]
+    async_std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path).await?;
+    /* Don't glue the key to the last line, if that one has no line break */
+    let separator = match content.ends_with('\n') || content.is_empty() {
+        true => "",
+        false => "\n",
+    };
+    file.write_all(format!("{}{}\n", separator, key).as_bytes())
+        .await?;
+    file.sync_all().await?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_public_key() {
+        let key: PublicKey = "  ssh-ed25519   AAAAC3NzaC1lZDI1NTE5AAAAIL0K user@host with spaces\n"
+            .parse()
+            .unwrap();
+        assert_eq!(key.kind(), "ssh-ed25519");
+        assert_eq!(key.key(), "AAAAC3NzaC1lZDI1NTE5AAAAIL0K");
+        assert_eq!(key.comment(), Some("user@host with spaces"));
+        assert_eq!(
+            key.to_string(),
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIL0K user@host with spaces"
+        );
+
+        let key: PublicKey = "ecdsa-sha2-nistp256 AAAAE2VjZHNh".parse().unwrap();
+        assert_eq!(key.comment(), None);
+
+        for invalid in &[
+            "",
+            "AAAAC3NzaC1lZDI1NTE5AAAAIL0K user@host",
+            "command=\"rm -rf /\" ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIL0K",
+            "ssh-ed25519",
+            "ssh-ed25519 not/base64!",
+            "ssh-ed25519 AAAAC3Nza user@host\nssh-rsa AAAAB3Nza",
+        ] {
+            assert!(matches!(
+                invalid.parse::<PublicKey>(),
+                Err(SshError::InvalidKey(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_messages() {
+        /* Python's `wormhole ssh accept` sends these */
+        assert_eq!(
+            serde_json::from_str::<PeerMessage>(
+                r#"{"offer": {"message": "ssh-ed25519 AAAAC3Nza user@host"}}"#
+            )
+            .unwrap(),
+            PeerMessage::Offer {
+                message: "ssh-ed25519 AAAAC3Nza user@host".into()
+            }
+        );
+        assert_eq!(
+            serde_json::to_string(&PeerMessage::Answer {
+                message_ack: "ok".into()
+            })
+            .unwrap(),
+            r#"{"answer":{"message_ack":"ok"}}"#
+        );
+    }
+
+    #[async_std::test]
+    async fn test_add_authorized_key() {
+        let dir = std::env::temp_dir().join(format!("wormhole-ssh-{}", std::process::id()));
+        let path = dir.join(".ssh").join("authorized_keys");
+        let key: PublicKey = "ssh-ed25519 AAAAC3Nza new@host".parse().unwrap();
+
+        assert!(add_authorized_key(&path, &key).await.unwrap());
+        assert!(!add_authorized_key(&path, &key).await.unwrap());
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "ssh-ed25519 AAAAC3Nza new@host\n"
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(&path), 0o600);
+            assert_eq!(mode(path.parent().unwrap()), 0o700);
+        }
+
+        /* Keys with options in front count as known, and a missing line break gets added */
+        std::fs::write(&path, "no-pty ssh-ed25519 AAAAC3Nza old@host").unwrap();
+        assert!(!add_authorized_key(&path, &key).await.unwrap());
+        let other: PublicKey = "ssh-rsa AAAAB3Nza".parse().unwrap();
+        assert!(add_authorized_key(&path, &other).await.unwrap());
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "no-pty ssh-ed25519 AAAAC3Nza old@host\nssh-rsa AAAAB3Nza\n"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -14,15 +14,45 @@ use serde_derive::{Deserialize, Serialize};
 use serde_json::json;
 use std::sync::Arc;
 
-use super::{core::WormholeError, transit, transit::Transit, AppID, Wormhole};
+use super::{
+    core::WormholeError, transit, transit::Transit, AppID, ErrorCode, Wormhole, WormholeEvent,
+};
 use log::*;
 use std::{borrow::Cow, path::PathBuf};
 use transit::{TransitConnectError, TransitConnector, TransitError};
 
+mod cancel;
+pub use cancel::CancelToken;
+mod compression;
+pub use compression::Compression;
+mod folder;
+pub use folder::{FolderSendOptions, SkippedEntries, SpecialFiles};
 mod messages;
 use messages::*;
+pub use messages::{OfferV2Entry, PeerError, PeerErrorCode};
+mod metadata;
+pub use metadata::{guess_mime_type, FileMetadata, Preview, MAX_PREVIEW_SIZE};
+mod progress;
+pub use progress::{
+    events_iter, events_stream, with_rates, Progress, ProgressEvents, ProgressState,
+    ProgressStream, TransferProgress,
+};
+mod record_size;
+mod resume;
+pub use resume::PartialTransfer;
+mod selection;
+pub use selection::FileSelection;
+mod storage;
+pub use storage::{
+    OsStorage, SharedStorage, Storage, StorageEntry, StorageEntryType, StorageMetadata,
+};
+mod unpack;
+mod uri;
+pub use uri::{ParseUriError, WormholeTransferUri};
 mod v1;
 mod v2;
+mod verify;
+pub use verify::VerifierCheck;
 
 const APPID_RAW: &str = "lothar.com/wormhole/text-or-file-xfer";
 
@@ -37,11 +67,14 @@ pub const APP_CONFIG: crate::AppConfig<AppVersion> = crate::AppConfig::<AppVersi
     id: AppID(Cow::Borrowed(APPID_RAW)),
     rendezvous_url: Cow::Borrowed(crate::rendezvous::DEFAULT_RENDEZVOUS_SERVER),
     app_version: AppVersion::new(),
+    rendezvous_tap: None,
+    resolver: None,
+    proxy: None,
+    events: None,
+    wordlist: None,
+    keepalive: Some(crate::rendezvous::DEFAULT_KEEPALIVE),
 };
 
-// TODO be more extensible on the JSON enum types (i.e. recognize unknown variants)
-
-// TODO send peer errors when something went wrong (if possible)
 #[derive(Debug, thiserror::Error)]
 #[non_exhaustive]
 pub enum TransferError {
@@ -49,6 +82,13 @@ pub enum TransferError {
     AckError,
     #[error("Receive checksum error")]
     Checksum,
+    /// The received file is intact, but not the one that was expected (see [`ReceiveOptions::expected_sha256`])
+    #[error(
+        "The received file has the SHA-256 sum {}, but {} was expected",
+        actual,
+        expected
+    )]
+    UnexpectedChecksum { expected: String, actual: String },
     #[error("The file contained a different amount of bytes than advertized! Sent {} bytes, but should have been {}", sent_size, file_size)]
     FileSize { sent_size: u64, file_size: u64 },
     #[error("The file(s) to send got modified during the transfer, and thus corrupted")]
@@ -57,7 +97,26 @@ pub enum TransferError {
     #[error("Unsupported offer type")]
     UnsupportedOffer,
     #[error("Something went wrong on the other side: {}", _0)]
-    PeerError(String),
+    PeerError(PeerError),
+    /// The other side aborted the transfer on purpose. Any partially received data is incomplete
+    #[error("The other side cancelled the transfer: {}", _0)]
+    PeerCancelled(String),
+    /**
+     * The other side went away mid-transfer without saying why, for example because its
+     * connection broke down or the relay died
+     *
+     * Unlike the other errors, the data transferred so far is fine. See
+     * [`ReceiveOptions::partial_files`] for keeping it.
+     */
+    #[error("The connection to the other side was lost")]
+    PeerDisconnected(#[source] TransitError),
+    /// The receiver did not answer our offer within [`SendOptions::offer_timeout`], or we did not
+    /// accept an offer before it [expired](ReceiveRequest::expire_after)
+    #[error("The offer expired before it got answered")]
+    OfferExpired,
+    /// We aborted the transfer with a [`CancelToken`], and told the other side
+    #[error("The transfer was cancelled")]
+    Cancelled,
 
     /// Some deserialization went wrong, we probably got some garbage
     #[error("Corrupt JSON message received")]
@@ -115,36 +174,281 @@ impl TransferError {
     ) -> Self {
         Self::ProtocolUnexpectedMessage(expected.into(), Box::new(got))
     }
+
+    /** What went wrong, in a way that stays stable across versions */
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            TransferError::AckError
+            | TransferError::ProtocolJson(_)
+            | TransferError::ProtocolMsgpack(_)
+            | TransferError::Protocol(_)
+            | TransferError::ProtocolUnexpectedMessage(..) => ErrorCode::Protocol,
+            TransferError::Checksum => ErrorCode::Checksum,
+            TransferError::UnexpectedChecksum { .. } => ErrorCode::UnexpectedChecksum,
+            TransferError::FileSize { .. } => ErrorCode::FileSize,
+            TransferError::FilesystemSkew => ErrorCode::FilesystemSkew,
+            TransferError::UnsupportedOffer => ErrorCode::UnsupportedOffer,
+            TransferError::PeerError(error) if error.code == PeerErrorCode::Rejected => {
+                ErrorCode::Rejected
+            },
+            TransferError::PeerError(error) if error.code == PeerErrorCode::OfferExpired => {
+                ErrorCode::OfferExpired
+            },
+            TransferError::PeerError(_) => ErrorCode::PeerError,
+            TransferError::PeerCancelled(_) => ErrorCode::PeerCancelled,
+            TransferError::PeerDisconnected(_) => ErrorCode::PeerDisconnected,
+            TransferError::OfferExpired => ErrorCode::OfferExpired,
+            TransferError::Cancelled => ErrorCode::Cancelled,
+            TransferError::Wormhole(error) => error.code(),
+            TransferError::TransitConnect(TransitConnectError::Handshake) => {
+                ErrorCode::TransitTimeout
+            },
+            TransferError::TransitConnect(TransitConnectError::Protocol(_)) => ErrorCode::Protocol,
+            TransferError::TransitConnect(TransitConnectError::IO(_)) => ErrorCode::TransitConnect,
+            TransferError::TransitConnect(TransitConnectError::Wormhole(error)) => error.code(),
+            TransferError::Transit(TransitError::IO(_)) => ErrorCode::Transit,
+            TransferError::Transit(TransitError::Crypto | TransitError::Nonce(..)) => {
+                ErrorCode::Crypto
+            },
+            TransferError::IO(_) => ErrorCode::IO,
+        }
+    }
+}
+
+/** How long to wait for the peer to tell us why the transit connection broke down */
+const PEER_MESSAGE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/**
+ * Find out why the transit connection failed mid-transfer
+ *
+ * If the other side cancelled the transfer (or had an error), it tells us over the wormhole.
+ * Since both channels are independent, that message may arrive a bit later than the transit
+ * failure. If no such message arrives, the other side either vanished or the failure is on our
+ * side, so tell the other side (in case it is still there).
+ */
+async fn transit_failure(wormhole: &mut Wormhole, error: TransitError) -> TransferError {
+    match async_std::future::timeout(PEER_MESSAGE_TIMEOUT, wormhole.receive_json()).await {
+        Ok(Ok(Ok(PeerMessage::Cancel(reason)))) => TransferError::PeerCancelled(reason),
+        Ok(Ok(Ok(PeerMessage::Error(err)))) => TransferError::PeerError(err),
+        _ => {
+            let error = if is_disconnect(&error) {
+                TransferError::PeerDisconnected(error)
+            } else {
+                TransferError::Transit(error)
+            };
+            let _ = wormhole.send_json(&PeerMessage::error(&error)).await;
+            error
+        },
+    }
+}
+
+/**
+ * Whether the transit connection failed because it went away, as opposed to receiving garbage
+ *
+ * A closed connection shows up as end of file while waiting for the next record.
+ */
+fn is_disconnect(error: &TransitError) -> bool {
+    use std::io::ErrorKind;
+    match error {
+        TransitError::IO(error) => matches!(
+            error.kind(),
+            ErrorKind::UnexpectedEof
+                | ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::BrokenPipe
+                | ErrorKind::TimedOut
+        ),
+        _ => false,
+    }
+}
+
+/**
+ * Abort the transfer on purpose
+ *
+ * The other side gets told over the wormhole (so that it knows nothing went wrong), then
+ * all connections are closed.
+ */
+async fn cancel(
+    mut wormhole: Wormhole,
+    transit: Option<Transit>,
+    session: Option<Session>,
+    reason: impl Into<String>,
+) -> Result<(), TransferError> {
+    wormhole.send_json(&PeerMessage::cancel(reason)).await?;
+    /* Only drop the transit connection after the message has been sent, so that it is there
+     * once the other side notices */
+    std::mem::drop(transit);
+    finish(wormhole, session).await
+}
+
+/**
+ * Tell the other side that we cancelled the transfer with a [`CancelToken`]
+ *
+ * The transfer must have been dropped already, so that its transit connection is closed.
+ */
+async fn cancelled(wormhole: &mut Wormhole) -> TransferError {
+    if let Err(error) = wormhole
+        .send_json(&PeerMessage::cancel("transfer cancelled"))
+        .await
+    {
+        debug!(
+            "Failed to tell the other side about the cancellation: {}",
+            error
+        );
+    }
+    TransferError::Cancelled
+}
+
+/** Run the [`VerifierCheck`] if there is one, and tell the other side if it fails */
+async fn check_verifier(
+    wormhole: &mut Wormhole,
+    verifier_check: Option<&VerifierCheck>,
+) -> Result<(), TransferError> {
+    if let Some(verifier_check) = verifier_check {
+        if !verifier_check.check(wormhole.verifier()).await {
+            wormhole
+                .send_json(&PeerMessage::cancel("verifier rejected"))
+                .await?;
+            bail!(TransferError::Cancelled);
+        }
+    }
+    Ok(())
+}
+
+/** Fail on options that receivers would not accept, and tell the other side */
+async fn check_send_options(
+    wormhole: &mut Wormhole,
+    options: &SendOptions,
+) -> Result<(), TransferError> {
+    let problem = match (&options.preview, options.record_size) {
+        (Some(preview), _) if preview.data.len() > MAX_PREVIEW_SIZE => format!(
+            "The preview has {} bytes, more than the limit of {}",
+            preview.data.len(),
+            MAX_PREVIEW_SIZE
+        ),
+        (_, Some(record_size)) if record_size > record_size::MAX_RECORD_SIZE => format!(
+            "The record size of {} bytes is more than the limit of {}",
+            record_size,
+            record_size::MAX_RECORD_SIZE
+        ),
+        _ => return Ok(()),
+    };
+    let error = TransferError::IO(std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        problem,
+    ));
+    let _ = wormhole.send_json(&PeerMessage::error(&error)).await;
+    Err(error)
+}
+
+/** Where to hand back the wormhole of an offer received with [`request_multiple`] */
+type Session = futures::channel::oneshot::Sender<Wormhole>;
+
+/**
+ * Be done with an offer: hand the wormhole back if more offers may follow on it, close it otherwise
+ */
+async fn finish(wormhole: Wormhole, session: Option<Session>) -> Result<(), TransferError> {
+    let wormhole = match session {
+        Some(session) => match session.send(wormhole) {
+            Ok(()) => return Ok(()),
+            /* Nobody waits for more offers anymore */
+            Err(wormhole) => wormhole,
+        },
+        None => wormhole,
+    };
+    wormhole.close().await?;
+    Ok(())
 }
 
 /**
  * The application specific version information for this protocol.
  *
- * At the moment, this always is an empty object, but this will likely change in the future.
+ * It tells the peer which versions of the transfer protocol we support, whether we can
+ * resume interrupted transfers, whether we can exchange several offers and receive compressed files. Version 2 is only advertised with the `experimental-transfer-v2`
+ * feature.
  */
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct AppVersion {
-    // #[serde(default)]
-// abilities: Cow<'static, [Cow<'static, str>]>,
-// #[serde(default)]
-// transfer_v2: Option<AppVersionTransferV2Hint>,
+    #[serde(default)]
+    abilities: Cow<'static, [Cow<'static, str>]>,
+    #[serde(default)]
+    transfer_v2: Option<AppVersionTransferV2Hint>,
+    /** Whatever newer peers send in addition */
+    #[serde(flatten, skip_serializing)]
+    unknown_fields: Option<serde_json::Map<String, serde_json::Value>>,
 }
 
 // TODO check invariants during deserialization
 
+#[cfg(feature = "experimental-transfer-v2")]
+const ABILITIES: &[Cow<'static, str>] = &[
+    Cow::Borrowed("transfer-v1"),
+    Cow::Borrowed("transfer-v1-resume"),
+    Cow::Borrowed("transfer-v1-multiple"),
+    Cow::Borrowed("transfer-v1-zstd"),
+    Cow::Borrowed("transfer-v2"),
+];
+#[cfg(not(feature = "experimental-transfer-v2"))]
+const ABILITIES: &[Cow<'static, str>] = &[
+    Cow::Borrowed("transfer-v1"),
+    Cow::Borrowed("transfer-v1-resume"),
+    Cow::Borrowed("transfer-v1-multiple"),
+    Cow::Borrowed("transfer-v1-zstd"),
+];
+
 impl AppVersion {
     const fn new() -> Self {
         Self {
-            // abilities: Cow::Borrowed([Cow::Borrowed("transfer-v1"), Cow::Borrowed("transfer-v2")]),
-            // transfer_v2: Some(AppVersionTransferV2Hint::new())
+            abilities: Cow::Borrowed(ABILITIES),
+            #[cfg(feature = "experimental-transfer-v2")]
+            transfer_v2: Some(AppVersionTransferV2Hint::new()),
+            #[cfg(not(feature = "experimental-transfer-v2"))]
+            transfer_v2: None,
+            unknown_fields: None,
         }
     }
 
-    #[allow(dead_code)]
+    /** The fields of a peer's version that this version of the protocol does not know about */
+    pub fn unknown_fields(&self) -> impl Iterator<Item = (&str, &serde_json::Value)> {
+        self.unknown_fields
+            .iter()
+            .flatten()
+            .map(|(key, value)| (key.as_str(), value))
+    }
+
+    /** Whether both we and the peer with this version can use version 2 of the protocol */
     fn supports_v2(&self) -> bool {
-        false
-        // self.abilities.contains(&"transfer-v2".into())
+        cfg!(feature = "experimental-transfer-v2")
+            && self
+                .abilities
+                .iter()
+                .any(|ability| ability == "transfer-v2")
+            && matches!(&self.transfer_v2, Some(hint) if hint
+                .supported_formats
+                .iter()
+                .any(|format| format == v2::FORMAT_TAR_ZST))
+    }
+
+    /** Whether the peer with this version can send only the part of a file we don't have yet */
+    fn supports_resume(&self) -> bool {
+        self.abilities
+            .iter()
+            .any(|ability| ability == "transfer-v1-resume")
+    }
+
+    /** Whether the peer with this version can send or receive several offers over one wormhole */
+    fn supports_multiple(&self) -> bool {
+        self.abilities
+            .iter()
+            .any(|ability| ability == "transfer-v1-multiple")
+    }
+
+    /** Whether the peer with this version can receive zstd compressed files */
+    fn supports_compression(&self) -> bool {
+        self.abilities
+            .iter()
+            .any(|ability| ability == "transfer-v1-zstd")
     }
 }
 
@@ -154,315 +458,1987 @@ impl Default for AppVersion {
     }
 }
 
-// #[derive(Clone, Debug, Serialize, Deserialize)]
-// #[serde(rename_all = "kebab-case")]
-// pub struct AppVersionTransferV2Hint {
-//     supported_formats: Vec<Cow<'static, str>>,
-//     transit_abilities: Vec<transit::Ability>,
-// }
-
-// impl AppVersionTransferV2Hint {
-//     const fn new() -> Self {
-//         Self {
-//             supported_formats: vec![Cow::Borrowed("tar.zst")],
-//             transit_abilities: transit::Ability::all_abilities(),
-//         }
-//     }
-// }
-
-// impl Default for AppVersionTransferV2Hint {
-//     fn default() -> Self {
-//         Self::new()
-//     }
-// }
-
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+/**
+ * The application version information specific to version 2 of the protocol
+ *
+ * It tells the sender which archive formats we understand, and which transit abilities we have,
+ * because the transit messages of version 2 only carry the hints.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
-struct TransitAck {
-    pub ack: String,
-    pub sha256: String,
+pub struct AppVersionTransferV2Hint {
+    supported_formats: Cow<'static, [Cow<'static, str>]>,
+    transit_abilities: transit::Abilities,
 }
 
-impl TransitAck {
-    pub fn new(msg: impl Into<String>, sha256: impl Into<String>) -> Self {
-        TransitAck {
-            ack: msg.into(),
-            sha256: sha256.into(),
+const SUPPORTED_FORMATS: &[Cow<'static, str>] = &[Cow::Borrowed(v2::FORMAT_TAR_ZST)];
+
+impl AppVersionTransferV2Hint {
+    const fn new() -> Self {
+        Self {
+            supported_formats: Cow::Borrowed(SUPPORTED_FORMATS),
+            transit_abilities: transit::Abilities::ALL_ABILITIES,
         }
     }
+}
 
-    #[cfg(test)]
-    pub fn serialize(&self) -> String {
-        json!(self).to_string()
+impl Default for AppVersionTransferV2Hint {
+    fn default() -> Self {
+        Self::new()
     }
+}
 
-    pub fn serialize_vec(&self) -> Vec<u8> {
-        serde_json::to_vec(self).unwrap()
+/**
+ * A hook that gets told how the transit connection was established, once it is
+ *
+ * Use it to show whether the data goes directly to the other side or via a relay server.
+ */
+#[derive(Clone)]
+pub struct TransitHandler(Arc<dyn Fn(&transit::TransitInfo) + Send + Sync>);
+
+impl TransitHandler {
+    pub fn new(handler: impl Fn(&transit::TransitInfo) + Send + Sync + 'static) -> Self {
+        TransitHandler(Arc::new(handler))
+    }
+
+    fn call(handler: Option<&Self>, transit: &Transit) {
+        if let Some(handler) = handler {
+            (handler.0)(transit.info());
+        }
     }
 }
 
-pub async fn send_file_or_folder<N, M, H>(
-    wormhole: Wormhole,
-    relay_url: url::Url,
-    file_path: N,
-    file_name: M,
-    progress_handler: H,
-) -> Result<(), TransferError>
-where
-    N: AsRef<async_std::path::Path>,
-    M: AsRef<async_std::path::Path>,
-    H: FnMut(u64, u64) + 'static,
-{
-    use async_std::fs::File;
-    let file_path = file_path.as_ref();
-    let file_name = file_name.as_ref();
+impl std::fmt::Debug for TransitHandler {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt.write_str("TransitHandler")
+    }
+}
 
-    let mut file = File::open(file_path).await?;
-    let metadata = file.metadata().await?;
-    if metadata.is_dir() {
-        send_folder(wormhole, relay_url, file_path, file_name, progress_handler).await?;
-    } else {
-        let file_size = metadata.len();
-        send_file(
-            wormhole,
-            relay_url,
-            &mut file,
-            file_name,
-            file_size,
-            progress_handler,
-        )
-        .await?;
+/* Two handlers are only equal if they are the same instance */
+impl PartialEq for TransitHandler {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
     }
-    Ok(())
 }
 
-/// Send a file to the other side
-///
-/// You must ensure that the Reader contains exactly as many bytes
-/// as advertized in file_size.
-pub async fn send_file<F, N, H>(
-    wormhole: Wormhole,
-    relay_url: url::Url,
-    file: &mut F,
-    file_name: N,
-    file_size: u64,
-    progress_handler: H,
-) -> Result<(), TransferError>
-where
-    F: AsyncRead + Unpin,
-    N: Into<PathBuf>,
-    H: FnMut(u64, u64) + 'static,
-{
-    let _peer_version: AppVersion = serde_json::from_value(wormhole.peer_version.clone())?;
-    let relay_hints = vec![transit::RelayHint::from_url(relay_url)];
-    // if peer_version.supports_v2() && false {
-    //     v2::send_file(wormhole, relay_url, file, file_name, file_size, progress_handler, peer_version).await
-    // } else {
-    //     log::info!("TODO");
-    v1::send_file(
-        wormhole,
-        relay_hints,
-        file,
-        file_name,
-        file_size,
-        progress_handler,
-    )
-    .await
-    // }
+impl Eq for TransitHandler {}
+
+/**
+ * How a successful file transfer went
+ *
+ * The SHA-256 sum is the one that both sides agreed on, so there is no need to hash the file
+ * again for recording or verifying it.
+ */
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct TransferResult {
+    /** Of the transferred content, hex encoded. For folders, this is the sum of the tar archive */
+    pub sha256: String,
+    /** The size of the content. For resumed transfers, this includes what was received before */
+    pub size: u64,
+    /** How long transferring the content took, without connecting and waiting for the other side */
+    pub duration: std::time::Duration,
+    /** Whether the content went directly to the other side or via a relay */
+    pub transit: transit::TransitInfo,
+    /** How long connecting took, and how much went over the connection */
+    pub stats: transit::TransitStats,
 }
 
-/// Send a folder to the other side
-///
-/// This isn't a proper folder transfer as per the Wormhole protocol
-/// because it sends it in a way so that the receiver still has to manually
-/// unpack it. But it's better than nothing
-pub async fn send_folder<N, M, H>(
-    wormhole: Wormhole,
-    relay_url: url::Url,
-    folder_path: N,
-    folder_name: M,
-    progress_handler: H,
-) -> Result<(), TransferError>
-where
-    N: Into<PathBuf>,
-    M: Into<PathBuf>,
-    H: FnMut(u64, u64) + 'static,
-{
-    let relay_hints = vec![transit::RelayHint::from_url(relay_url)];
-    v1::send_folder(
-        wormhole,
-        relay_hints,
-        folder_path,
-        folder_name,
-        progress_handler,
-    )
-    .await
+impl TransferResult {
+    fn new(sha256: String, size: u64, started: std::time::Instant, transit: &Transit) -> Self {
+        TransferResult {
+            sha256,
+            size,
+            duration: started.elapsed(),
+            transit: transit.info().clone(),
+            stats: transit.stats(),
+        }
+    }
 }
 
 /**
- * Wait for a file offer from the other side
+ * Additional settings for sending files and folders
  *
- * This method waits for an offer message and builds up a [`ReceiveRequest`](ReceiveRequest).
- * It will also start building a TCP connection to the other side using the transit protocol.
+ * The default values are fine for interactive use.
  */
-pub async fn request_file(
-    mut wormhole: Wormhole,
-    relay_url: url::Url,
-) -> Result<ReceiveRequest, TransferError> {
-    let relay_hints = vec![transit::RelayHint::from_url(relay_url)];
-    let connector = transit::init(transit::Abilities::ALL_ABILITIES, None, relay_hints).await?;
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SendOptions {
+    /**
+     * How long to wait for the receiver to accept or reject the offer.
+     *
+     * Once it expires, the receiver gets notified, the wormhole gets closed and
+     * the sending fails with [`TransferError::OfferExpired`]. `None` waits forever.
+     */
+    pub offer_timeout: Option<std::time::Duration>,
+    /** How to establish the transit connection */
+    pub transit_config: transit::TransitConfig,
+    /**
+     * Whether to compress the file, if the receiver supports it.
+     *
+     * By default, content that already is compressed (archives, images, videos …) is detected and
+     * sent as-is.
+     */
+    pub compression: Compression,
+    /**
+     * How many bytes to read from the file ahead of sending them.
+     *
+     * This helps with sources that have a high latency, like network file systems or
+     * spinning disks. With the default of zero, only the next record is read while the
+     * current one is being sent.
+     */
+    pub read_ahead: usize,
+    /**
+     * Send the data in records of this many bytes
+     *
+     * By default (`None`, or zero), the records start small and grow as long as that makes the
+     * transfer faster, up to 64 KiB. A fixed size saves memory on small devices, or cuts the
+     * overhead per record on fast networks. The receiver needs to hold one record in memory,
+     * so sending fails for sizes above 64 KiB, which receivers don't accept.
+     */
+    pub record_size: Option<usize>,
+    /** Abort the transfer once this gets cancelled */
+    pub cancel_token: Option<CancelToken>,
+    /** Let the user compare the verifiers before offering anything, see [`VerifierCheck`] */
+    pub verifier_check: Option<VerifierCheck>,
+    /** Which entries of folders get sent */
+    pub folder: FolderSendOptions,
+    /** Gets told how the transit connection was established */
+    pub transit_handler: Option<TransitHandler>,
+    /**
+     * Show this to the receiver before it accepts, see [`Preview`]
+     *
+     * Sending fails if it is larger than [`MAX_PREVIEW_SIZE`].
+     */
+    pub preview: Option<Preview>,
+    /** Where to read the files and folders from, instead of the file system (see [`Storage`]) */
+    pub storage: Option<SharedStorage>,
+}
 
-    // send the transit message
-    debug!("Sending transit message '{:?}", connector.our_hints());
-    wormhole
-        .send_json(&PeerMessage::transit(
-            *connector.our_abilities(),
-            (**connector.our_hints()).clone().into(),
-        ))
-        .await?;
+impl SendOptions {
+    pub fn offer_timeout(mut self, offer_timeout: std::time::Duration) -> Self {
+        self.offer_timeout = Some(offer_timeout);
+        self
+    }
 
-    // receive transit message
-    let (their_abilities, their_hints): (transit::Abilities, transit::Hints) =
-        match serde_json::from_slice(&wormhole.receive().await?)? {
-            PeerMessage::Transit(transit) => {
-                debug!("received transit message: {:?}", transit);
-                (transit.abilities_v1, transit.hints_v1.into())
-            },
-            PeerMessage::Error(err) => {
-                bail!(TransferError::PeerError(err));
-            },
-            other => {
-                let error = TransferError::unexpected_message("transit", other);
-                let _ = wormhole
-                    .send_json(&PeerMessage::Error(format!("{}", error)))
-                    .await;
-                bail!(error)
-            },
-        };
+    pub fn transit_config(mut self, transit_config: transit::TransitConfig) -> Self {
+        self.transit_config = transit_config;
+        self
+    }
 
-    // 3. receive file offer message from peer
-    let maybe_offer = serde_json::from_slice(&wormhole.receive().await?)?;
-    debug!("Received offer message '{:?}'", &maybe_offer);
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
 
-    let (filename, filesize) = match maybe_offer {
-        PeerMessage::Offer(offer_type) => match offer_type {
-            Offer::File { filename, filesize } => (filename, filesize),
-            Offer::Directory {
-                mut dirname,
-                zipsize,
-                ..
-            } => {
-                dirname.set_extension("zip");
-                (dirname, zipsize)
-            },
-            _ => bail!(TransferError::UnsupportedOffer),
-        },
-        PeerMessage::Error(err) => {
-            bail!(TransferError::PeerError(err));
-        },
-        _ => {
-            let error = TransferError::unexpected_message("offer", maybe_offer);
-            let _ = wormhole
-                .send_json(&PeerMessage::Error(format!("{}", error)))
-                .await;
-            bail!(error)
-        },
-    };
+    pub fn read_ahead(mut self, read_ahead: usize) -> Self {
+        self.read_ahead = read_ahead;
+        self
+    }
 
-    let req = ReceiveRequest {
-        wormhole,
-        filename,
-        filesize,
-        connector,
-        their_abilities,
-        their_hints: Arc::new(their_hints),
-    };
+    pub fn record_size(mut self, record_size: usize) -> Self {
+        self.record_size = Some(record_size);
+        self
+    }
+
+    pub fn cancel_token(mut self, cancel_token: CancelToken) -> Self {
+        self.cancel_token = Some(cancel_token);
+        self
+    }
+
+    pub fn verifier_check(mut self, verifier_check: VerifierCheck) -> Self {
+        self.verifier_check = Some(verifier_check);
+        self
+    }
+
+    pub fn folder(mut self, folder: FolderSendOptions) -> Self {
+        self.folder = folder;
+        self
+    }
+
+    pub fn transit_handler(mut self, transit_handler: TransitHandler) -> Self {
+        self.transit_handler = Some(transit_handler);
+        self
+    }
+
+    pub fn preview(mut self, preview: Preview) -> Self {
+        self.preview = Some(preview);
+        self
+    }
 
-    Ok(req)
+    pub fn storage(mut self, storage: SharedStorage) -> Self {
+        self.storage = Some(storage);
+        self
+    }
 }
 
 /**
- * A pending files send offer from the other side
+ * How sure to be that a received file is on disk before reporting success
  *
- * You *should* consume this object, either by calling [`accept`](ReceiveRequest::accept) or [`reject`](ReceiveRequest::reject).
+ * This only applies to [`ConnectedReceiveRequest::accept_with_path`] (and the functions using it).
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Durability {
+    /** Leave it to the operating system to write the data eventually */
+    None,
+    /** Flush the file content to the disk */
+    File,
+    /**
+     * Flush the file content, and also the directory containing it so that the file
+     * can be found under its final name after a crash. Flushing directories is only
+     * possible on Unix, elsewhere this is the same as [`Durability::File`].
+     */
+    FileAndDirectory,
+}
+
+impl Default for Durability {
+    fn default() -> Self {
+        Durability::None
+    }
+}
+
+/**
+ * What to do with a partially received file when the transfer fails
+ *
+ * This only applies to [`ConnectedReceiveRequest::accept_with_path`] (and the functions using it).
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PartialFiles {
+    /** Always remove it */
+    Delete,
+    /**
+     * Keep it if the other side went away ([`TransferError::PeerDisconnected`]), together with
+     * a [`PartialTransfer`] describing it. After other failures, the data cannot be trusted
+     * and gets removed anyway.
+     */
+    KeepOnDisconnect,
+}
+
+impl Default for PartialFiles {
+    fn default() -> Self {
+        PartialFiles::Delete
+    }
+}
+
+/**
+ * Additional settings for receiving files with [`receive_from_code`]
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReceiveOptions {
+    /** How to establish the transit connection */
+    pub transit_config: transit::TransitConfig,
+    /** Write the checksum of the received file next to it, see [`write_checksum_file`] */
+    pub checksum_file: bool,
+    /**
+     * The hex encoded SHA-256 the received file must have, as obtained from the sender out of band
+     *
+     * If it does not match, the transfer fails with [`TransferError::UnexpectedChecksum`].
+     */
+    pub expected_sha256: Option<String>,
+    /**
+     * Keep a received file that does not match [`expected_sha256`](Self::expected_sha256)
+     *
+     * It stays under its temporary name then, see [`ConnectedReceiveRequest::accept_with_path`].
+     */
+    pub keep_mismatched: bool,
+    /** Whether to wait for the file to be written to the disk before reporting success */
+    pub durability: Durability,
+    /** Whether to keep the data received so far if the transfer fails */
+    pub partial_files: PartialFiles,
+    /**
+     * Continue a partial file kept from an earlier attempt, instead of receiving everything again
+     *
+     * See [`ReceiveRequest::connect_with_path`].
+     */
+    pub resume: bool,
+    /**
+     * Abort the transfer once this gets cancelled
+     *
+     * This applies to [`ConnectedReceiveRequest::accept_with_path`] (and the functions using it).
+     */
+    pub cancel_token: Option<CancelToken>,
+    /**
+     * Let the user compare the verifiers before accepting, see [`VerifierCheck`]
+     *
+     * This applies to [`ReceiveRequest::connect_with_path`] (and the functions using it).
+     */
+    pub verifier_check: Option<VerifierCheck>,
+    /**
+     * Give the received file the sender's modification time and executable bit, see [`FileMetadata`]
+     *
+     * This applies to [`ConnectedReceiveRequest::accept_with_path`] (and the functions using it).
+     */
+    pub preserve_metadata: bool,
+    /**
+     * Gets told how the transit connection was established
+     *
+     * This applies to [`ReceiveRequest::connect_with_path`] (and the functions using it). Otherwise,
+     * see [`ConnectedReceiveRequest::transit_info`].
+     */
+    pub transit_handler: Option<TransitHandler>,
+    /**
+     * Where to create the received file, instead of the file system (see [`Storage`])
+     *
+     * This applies to [`ConnectedReceiveRequest::accept_with_path`] (and the functions using it).
+     * The file gets written to its path directly then, so [`partial_files`](Self::partial_files),
+     * [`resume`](Self::resume), [`durability`](Self::durability),
+     * [`preserve_metadata`](Self::preserve_metadata) and [`checksum_file`](Self::checksum_file)
+     * don't apply. [`ReceiveRequest::accept_directory`] does not use it, it always unpacks into
+     * the file system.
+     */
+    pub storage: Option<SharedStorage>,
+    /**
+     * Don't receive the files of a version 2 offer that are in this folder already
+     *
+     * Files whose path in the [`manifest`](ReceiveRequest::manifest) leads to a file of the same
+     * size and hash in this folder are left out of the [selection](ReceiveRequest::select).
+     * They are looked up in the [`storage`](Self::storage), if there is one.
+     * This applies to [`ReceiveRequest::connect_with_path`] (and the functions using it).
+     */
+    pub skip_existing: Option<PathBuf>,
+}
+
+impl ReceiveOptions {
+    pub fn transit_config(mut self, transit_config: transit::TransitConfig) -> Self {
+        self.transit_config = transit_config;
+        self
+    }
+
+    pub fn checksum_file(mut self, checksum_file: bool) -> Self {
+        self.checksum_file = checksum_file;
+        self
+    }
+
+    pub fn expected_sha256(mut self, expected_sha256: impl Into<String>) -> Self {
+        self.expected_sha256 = Some(expected_sha256.into());
+        self
+    }
+
+    pub fn keep_mismatched(mut self, keep_mismatched: bool) -> Self {
+        self.keep_mismatched = keep_mismatched;
+        self
+    }
+
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    pub fn partial_files(mut self, partial_files: PartialFiles) -> Self {
+        self.partial_files = partial_files;
+        self
+    }
+
+    pub fn resume(mut self, resume: bool) -> Self {
+        self.resume = resume;
+        self
+    }
+
+    pub fn cancel_token(mut self, cancel_token: CancelToken) -> Self {
+        self.cancel_token = Some(cancel_token);
+        self
+    }
+
+    pub fn verifier_check(mut self, verifier_check: VerifierCheck) -> Self {
+        self.verifier_check = Some(verifier_check);
+        self
+    }
+
+    pub fn preserve_metadata(mut self, preserve_metadata: bool) -> Self {
+        self.preserve_metadata = preserve_metadata;
+        self
+    }
+
+    pub fn transit_handler(mut self, transit_handler: TransitHandler) -> Self {
+        self.transit_handler = Some(transit_handler);
+        self
+    }
+
+    pub fn storage(mut self, storage: SharedStorage) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    pub fn skip_existing(mut self, skip_existing: impl Into<PathBuf>) -> Self {
+        self.skip_existing = Some(skip_existing.into());
+        self
+    }
+
+    /** Whether a received file with the given hash is the expected one */
+    pub fn matches_expected(&self, sha256sum: &str) -> bool {
+        match &self.expected_sha256 {
+            Some(expected) => expected.eq_ignore_ascii_case(sha256sum),
+            None => true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+struct TransitAck {
+    pub ack: String,
+    pub sha256: String,
+}
+
+impl TransitAck {
+    pub fn new(msg: impl Into<String>, sha256: impl Into<String>) -> Self {
+        TransitAck {
+            ack: msg.into(),
+            sha256: sha256.into(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn serialize(&self) -> String {
+        json!(self).to_string()
+    }
+
+    pub fn serialize_vec(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+}
+
+/**
+ * Our relay hints: the ones chosen by the user take priority, followed
+ * by the ones recommended by the rendezvous server.
+ */
+fn add_server_relay_hints(
+    wormhole: &Wormhole,
+    mut relay_hints: Vec<transit::RelayHint>,
+) -> Vec<transit::RelayHint> {
+    for url in wormhole.transit_relays() {
+        if url.scheme() == "tcp" && (url.host_str().is_none() || url.port().is_none()) {
+            warn!(
+                "Ignoring malformed relay recommendation from the server: {}",
+                url
+            );
+            continue;
+        }
+        transit::RelayHint::from_url(url.clone()).merge_into(&mut relay_hints);
+    }
+    relay_hints
+}
+
+/**
+ * The relay hints for the one-call functions: our default relay server,
+ * unless the rendezvous server recommends some
+ */
+pub(crate) fn default_relay_hints(wormhole: &Wormhole) -> Vec<transit::RelayHint> {
+    if wormhole.transit_relays().is_empty() {
+        vec![transit::RelayHint::from_url(
+            transit::DEFAULT_RELAY_SERVER.parse().unwrap(),
+        )]
+    } else {
+        Vec::new()
+    }
+}
+
+/**
+ * Send a file or folder with a new code, in one call
+ *
+ * This connects to the default rendezvous server and allocates a code with two words.
+ * Give that code to the receiver, then await the returned future: it waits for the receiver
+ * to connect, sends the file and closes the wormhole.
+ *
+ * Use the other functions of this module if you need more control, for example over the
+ * rendezvous server or to show the progress.
+ */
+pub async fn send_to_new_code(
+    path: impl AsRef<std::path::Path>,
+    options: SendOptions,
+) -> Result<
+    (
+        crate::Code,
+        impl std::future::Future<Output = Result<TransferResult, TransferError>>,
+    ),
+    TransferError,
+> {
+    let path = path.as_ref().to_owned();
+    let file_name = path.file_name().map(ToOwned::to_owned).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} has no file name", path.display()),
+        )
+    })?;
+
+    let (welcome, connector) = Wormhole::connect_without_code(APP_CONFIG, 2).await?;
+    Ok((welcome.code, async move {
+        let wormhole = connector.await?;
+        let relay_hints = default_relay_hints(&wormhole);
+        send_file_or_folder(wormhole, relay_hints, &path, &file_name, options, |_, _| {}).await
+    }))
+}
+
+/**
+ * Receive a file with a code, in one call
+ *
+ * The file gets saved into `target_dir`, under the name chosen by the sender (without any
+ * directory components). Existing files are never overwritten, the offer gets rejected instead.
+ * Partially received files never show up under that name, see [`ReceiveRequest::accept_with_path`].
+ *
+ * Returns the path of the received file.
+ */
+pub async fn receive_from_code(
+    code: crate::Code,
+    target_dir: impl AsRef<std::path::Path>,
+    options: ReceiveOptions,
+) -> Result<PathBuf, TransferError> {
+    let (_welcome, wormhole) = Wormhole::connect_with_code(APP_CONFIG, code).await?;
+    receive_into(wormhole, target_dir, options, |_, _| {}).await
+}
+
+/** Like [`receive_from_code`], but with an already connected wormhole and a progress handler */
+pub(crate) async fn receive_into(
+    wormhole: Wormhole,
+    target_dir: impl AsRef<std::path::Path>,
+    options: ReceiveOptions,
+    progress_handler: impl FnMut(u64, u64) + 'static,
+) -> Result<PathBuf, TransferError> {
+    let relay_hints = default_relay_hints(&wormhole);
+    let req = request_file(wormhole, relay_hints, options.transit_config.clone()).await?;
+    req.accept_into(progress_handler, target_dir, &options)
+        .await
+}
+
+/**
+ * Make a file name chosen by the sender safe to use in a target directory
+ *
+ * Only the last component is kept, and characters that separate paths on some platform (or
+ * otherwise have no business in a file name) are replaced. Returns `None` if nothing is left.
+ */
+fn sanitize_file_name(file_name: &std::path::Path) -> Option<PathBuf> {
+    let file_name = file_name.file_name()?.to_string_lossy();
+    let file_name = file_name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>();
+    let file_name = file_name.trim();
+    match file_name {
+        "" | "." | ".." => None,
+        file_name => Some(file_name.into()),
+    }
+}
+
+/** Where a file received with [`ConnectedReceiveRequest::accept_with_path`] is stored until it is complete */
+fn part_path(path: impl AsRef<std::path::Path>) -> async_std::path::PathBuf {
+    let mut part_path = path.as_ref().as_os_str().to_owned();
+    part_path.push(".part");
+    part_path.into()
+}
+
+/**
+ * Write the SHA-256 sum of a received file into a sidecar file next to it
+ *
+ * The sidecar is called like the file with `.sha256` appended, and has the format of the
+ * `sha256sum` tool, so that the file can be checked later with `sha256sum -c`.
+ * `sha256sum` is the one in the [`TransferResult`] of [`ConnectedReceiveRequest::accept`].
+ */
+pub async fn write_checksum_file(
+    file_path: impl AsRef<std::path::Path>,
+    sha256sum: &str,
+) -> std::io::Result<()> {
+    let file_path = file_path.as_ref();
+    let file_name = file_path.file_name().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} has no file name", file_path.display()),
+        )
+    })?;
+    let file_name = file_name.to_string_lossy();
+    /* Like sha256sum, escape names that would break the format */
+    let line = if file_name.contains(&['\\', '\n'][..]) {
+        format!(
+            "\\{}  {}\n",
+            sha256sum,
+            file_name.replace('\\', "\\\\").replace('\n', "\\n")
+        )
+    } else {
+        format!("{}  {}\n", sha256sum, file_name)
+    };
+
+    let mut sidecar_path = file_path.as_os_str().to_owned();
+    sidecar_path.push(".sha256");
+    async_std::fs::write(sidecar_path, line).await
+}
+
+pub async fn send_file_or_folder<N, M, H>(
+    mut wormhole: Wormhole,
+    relay_hints: Vec<transit::RelayHint>,
+    file_path: N,
+    file_name: M,
+    options: SendOptions,
+    progress_handler: H,
+) -> Result<TransferResult, TransferError>
+where
+    N: AsRef<std::path::Path>,
+    M: AsRef<std::path::Path>,
+    H: FnMut(u64, u64) + 'static,
+{
+    let result = send_path(
+        &mut wormhole,
+        relay_hints,
+        file_path.as_ref().into(),
+        file_name.as_ref().into(),
+        options,
+        progress_handler,
+    )
+    .await;
+    end_offers(wormhole, result).await
+}
+
+/** Send a file or folder, but leave the wormhole open for whatever comes next */
+async fn send_path<H>(
+    wormhole: &mut Wormhole,
+    relay_hints: Vec<transit::RelayHint>,
+    file_path: &async_std::path::Path,
+    file_name: &async_std::path::Path,
+    options: SendOptions,
+    progress_handler: H,
+) -> Result<TransferResult, TransferError>
+where
+    H: FnMut(u64, u64) + 'static,
+{
+    let span = span!(parent: wormhole.span(), "send", name = %file_name.display());
+    let cancel_token = options.cancel_token.clone();
+    let transfer = async {
+        check_verifier(wormhole, options.verifier_check.as_ref()).await?;
+        let relay_hints = add_server_relay_hints(wormhole, relay_hints);
+
+        let peer_version: AppVersion = serde_json::from_value(wormhole.peer_version.clone())?;
+        if peer_version.supports_v2() {
+            return v2::send(
+                wormhole,
+                relay_hints,
+                file_path.into(),
+                file_name.to_string_lossy().into_owned(),
+                options,
+                peer_version,
+                progress_handler,
+            )
+            .await;
+        }
+
+        let storage = options.storage.clone().unwrap_or_default();
+        let metadata = storage.metadata(file_path.as_ref()).await?;
+        if metadata.is_dir() {
+            v1::send_folder(
+                wormhole,
+                relay_hints,
+                file_path,
+                file_name,
+                options,
+                progress_handler,
+            )
+            .await
+        } else {
+            let file_size = match metadata.entry_type {
+                StorageEntryType::File { size } => size,
+                _ => bail!(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("'{}' is not a regular file", file_path.display()),
+                )),
+            };
+            let mut file = storage.open(file_path.as_ref()).await?;
+            v1::send_file(
+                wormhole,
+                relay_hints,
+                &mut file,
+                file_name,
+                file_size,
+                metadata.file,
+                options,
+                progress_handler,
+            )
+            .await
+        }
+    };
+    match cancel::until_cancelled(cancel_token.as_ref(), span.instrument(transfer)).await {
+        Some(result) => result,
+        None => Err(cancelled(wormhole).await),
+    }
+}
+
+/// Send a file to the other side
+///
+/// You must ensure that the Reader contains exactly as many bytes
+/// as advertized in file_size. Since there is only a reader, no [`FileMetadata`] gets sent.
+pub async fn send_file<F, N, H>(
+    mut wormhole: Wormhole,
+    relay_hints: Vec<transit::RelayHint>,
+    file: &mut F,
+    file_name: N,
+    file_size: u64,
+    options: SendOptions,
+    progress_handler: H,
+) -> Result<TransferResult, TransferError>
+where
+    F: AsyncRead + Unpin,
+    N: Into<PathBuf>,
+    H: FnMut(u64, u64) + 'static,
+{
+    let relay_hints = add_server_relay_hints(&wormhole, relay_hints);
+    let file_name = file_name.into();
+    let span = span!(parent: wormhole.span(), "send", name = %file_name.display());
+    let cancel_token = options.cancel_token.clone();
+    let transfer = async {
+        check_verifier(&mut wormhole, options.verifier_check.as_ref()).await?;
+        v1::send_file(
+            &mut wormhole,
+            relay_hints,
+            file,
+            file_name,
+            file_size,
+            FileMetadata::default(),
+            options,
+            progress_handler,
+        )
+        .await
+    };
+    let result =
+        match cancel::until_cancelled(cancel_token.as_ref(), span.instrument(transfer)).await {
+            Some(result) => result,
+            None => Err(cancelled(&mut wormhole).await),
+        };
+    end_offers(wormhole, result).await
+}
+
+/**
+ * Send content that is in memory already, like a screenshot or an archive built on the fly
+ *
+ * The receiver gets it as file called `file_name`, see [`send_file`]. It can receive it into
+ * memory as well, with [`ReceiveRequest::accept_to_vec`].
+ */
+pub async fn send_bytes<N>(
+    wormhole: Wormhole,
+    relay_hints: Vec<transit::RelayHint>,
+    file_name: N,
+    bytes: impl AsRef<[u8]>,
+    options: SendOptions,
+) -> Result<TransferResult, TransferError>
+where
+    N: Into<PathBuf>,
+{
+    let mut bytes = bytes.as_ref();
+    let file_size = bytes.len() as u64;
+    send_file(
+        wormhole,
+        relay_hints,
+        &mut bytes,
+        file_name,
+        file_size,
+        options,
+        |_, _| {},
+    )
+    .await
+}
+
+/**
+ * Send the content of a reader of unknown size, like a pipe
+ *
+ * Offers have to tell the size up front, so the content gets buffered in a temporary file first.
+ * It is removed again once done. If you know the size, use [`send_file`], which streams the
+ * content right away.
+ */
+pub async fn send_stream<F, N, H>(
+    wormhole: Wormhole,
+    relay_hints: Vec<transit::RelayHint>,
+    reader: &mut F,
+    file_name: N,
+    options: SendOptions,
+    progress_handler: H,
+) -> Result<TransferResult, TransferError>
+where
+    F: AsyncRead + Unpin,
+    N: Into<PathBuf>,
+    H: FnMut(u64, u64) + 'static,
+{
+    use async_std::io::prelude::SeekExt;
+
+    /* Removes the buffer even if this future gets dropped. It must outlive the open file */
+    struct Buffer(PathBuf);
+    impl Drop for Buffer {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    let buffer = Buffer(std::env::temp_dir().join(format!(
+        "wormhole-send-{}",
+        hex::encode(rand::random::<[u8; 8]>())
+    )));
+    let mut open_options = async_std::fs::OpenOptions::new();
+    open_options.read(true).write(true).create_new(true);
+    /* Other users on this machine have no business reading the content */
+    #[cfg(unix)]
+    async_std::os::unix::fs::OpenOptionsExt::mode(&mut open_options, 0o600);
+    let mut file = open_options.open(&buffer.0).await?;
+    let file_size = futures::io::copy(reader, &mut file).await?;
+    file.seek(std::io::SeekFrom::Start(0)).await?;
+    debug!("Buffered {} bytes to send", file_size);
+    send_file(
+        wormhole,
+        relay_hints,
+        &mut file,
+        file_name,
+        file_size,
+        options,
+        progress_handler,
+    )
+    .await
+}
+
+/// Send a folder to the other side
+///
+/// This isn't a proper folder transfer as per the Wormhole protocol
+/// because it sends it in a way so that the receiver still has to manually
+/// unpack it. But it's better than nothing
+pub async fn send_folder<N, M, H>(
+    mut wormhole: Wormhole,
+    relay_hints: Vec<transit::RelayHint>,
+    folder_path: N,
+    folder_name: M,
+    options: SendOptions,
+    progress_handler: H,
+) -> Result<TransferResult, TransferError>
+where
+    N: Into<PathBuf>,
+    M: Into<PathBuf>,
+    H: FnMut(u64, u64) + 'static,
+{
+    let relay_hints = add_server_relay_hints(&wormhole, relay_hints);
+    let folder_name = folder_name.into();
+    let span = span!(parent: wormhole.span(), "send", name = %folder_name.display());
+    let cancel_token = options.cancel_token.clone();
+    let transfer = async {
+        check_verifier(&mut wormhole, options.verifier_check.as_ref()).await?;
+        let peer_version: AppVersion = serde_json::from_value(wormhole.peer_version.clone())?;
+        if peer_version.supports_v2() {
+            let folder_name = folder_name.to_string_lossy().into_owned();
+            return v2::send(
+                &mut wormhole,
+                relay_hints,
+                folder_path.into(),
+                folder_name,
+                options,
+                peer_version,
+                progress_handler,
+            )
+            .await;
+        }
+        v1::send_folder(
+            &mut wormhole,
+            relay_hints,
+            folder_path,
+            folder_name,
+            options,
+            progress_handler,
+        )
+        .await
+    };
+    let result =
+        match cancel::until_cancelled(cancel_token.as_ref(), span.instrument(transfer)).await {
+            Some(result) => result,
+            None => Err(cancelled(&mut wormhole).await),
+        };
+    end_offers(wormhole, result).await
+}
+
+/**
+ * Send several files or folders over the same wormhole, one after another
+ *
+ * Each of them is offered on its own, so that the receiver can accept or reject them one by one
+ * (see [`request_multiple`]). Only one code needs to be exchanged for all of them. The
+ * `progress_handler` additionally gets the index of the file that is being sent.
+ *
+ * A rejected or cancelled offer does not stop the others, any other failure does. Returns how each
+ * of the offers that were made went. Sending more than one file requires the other side to support
+ * it, which the Python implementation doesn't: it fails with [`TransferError::UnsupportedOffer`].
+ */
+pub async fn send_multiple<N, M, H>(
+    mut wormhole: Wormhole,
+    relay_hints: Vec<transit::RelayHint>,
+    files: Vec<(N, M)>,
+    mut options: SendOptions,
+    progress_handler: H,
+) -> Result<Vec<Result<TransferResult, TransferError>>, TransferError>
+where
+    N: AsRef<std::path::Path>,
+    M: AsRef<std::path::Path>,
+    H: FnMut(usize, u64, u64) + 'static,
+{
+    let peer_version: AppVersion = serde_json::from_value(wormhole.peer_version.clone())?;
+    let multiple = peer_version.supports_multiple();
+    if !multiple && files.len() > 1 {
+        let error = TransferError::UnsupportedOffer;
+        let _ = wormhole.send_json(&PeerMessage::error(&error)).await;
+        wormhole.close().await?;
+        bail!(error);
+    }
+
+    /* Only once, and not again for each file */
+    if let Err(error) = check_verifier(&mut wormhole, options.verifier_check.take().as_ref()).await
+    {
+        wormhole.close().await?;
+        bail!(error);
+    }
+
+    let progress_handler = Arc::new(std::sync::Mutex::new(progress_handler));
+    let mut results = Vec::with_capacity(files.len());
+    for (index, (file_path, file_name)) in files.iter().enumerate() {
+        let progress_handler = progress_handler.clone();
+        let result = send_path(
+            &mut wormhole,
+            relay_hints.clone(),
+            file_path.as_ref().into(),
+            file_name.as_ref().into(),
+            options.clone(),
+            move |sent, total| (progress_handler.lock().unwrap())(index, sent, total),
+        )
+        .await;
+        if !offer_answered(&result) {
+            let result = end_offers(wormhole, result).await;
+            results.push(result);
+            return Ok(results);
+        }
+        results.push(result);
+    }
+
+    if multiple {
+        wormhole.send_json(&PeerMessage::NoMoreOffers {}).await?;
+    }
+    wormhole.close().await?;
+    Ok(results)
+}
+
+/**
+ * Whether the receiver is done with the offer and may wait for another one on the same wormhole
+ *
+ * This is the case if it received the file, or rejected or cancelled it on purpose.
+ */
+fn offer_answered<T>(result: &Result<T, TransferError>) -> bool {
+    match result {
+        Ok(_) | Err(TransferError::PeerCancelled(_)) => true,
+        Err(TransferError::PeerError(error)) => error.code == PeerErrorCode::Rejected,
+        Err(_) => false,
+    }
+}
+
+/**
+ * Tell the receiver that there won't be another offer, in case it waits for one
+ *
+ * If the offer expired or we cancelled it, free the mailbox on the server instead, since nobody
+ * is going to answer anymore. Otherwise, the wormhole stays open for the other side to read our
+ * messages.
+ */
+async fn end_offers<T>(
+    mut wormhole: Wormhole,
+    result: Result<T, TransferError>,
+) -> Result<T, TransferError> {
+    if let Err(TransferError::OfferExpired) | Err(TransferError::Cancelled) = result {
+        wormhole.close().await?;
+        return result;
+    }
+    let peer_version: AppVersion = serde_json::from_value(wormhole.peer_version.clone())?;
+    if peer_version.supports_multiple() {
+        let sent = wormhole.send_json(&PeerMessage::NoMoreOffers {}).await;
+        if result.is_ok() {
+            sent?;
+        }
+    }
+    result
+}
+
+/**
+ * Send a text message, like `wormhole send --text` of the Python implementation
+ *
+ * This waits for the other side to acknowledge the message, then closes the wormhole.
+ * No transit connection is needed for this.
+ */
+pub async fn send_text(
+    mut wormhole: Wormhole,
+    text: impl Into<String>,
+) -> Result<(), TransferError> {
+    debug!("Sending text offer");
+    wormhole
+        .send_json(&PeerMessage::offer_message(text))
+        .await?;
+
+    let answer = loop {
+        match wormhole.receive_json().await?? {
+            /* Some receivers send their transit hints before seeing the offer */
+            PeerMessage::Transit(_) | PeerMessage::TransitV2(_) => {
+                debug!("Ignoring the transit message, a text needs no transit connection");
+            },
+            other => break other,
+        }
+    };
+    debug!("received message ack: {:?}", answer);
+    match answer {
+        PeerMessage::Answer(Answer::MessageAck(msg)) => {
+            ensure!(msg == "ok", TransferError::AckError);
+        },
+        PeerMessage::Error(err) => {
+            bail!(TransferError::PeerError(err));
+        },
+        PeerMessage::Cancel(reason) => {
+            bail!(TransferError::PeerCancelled(reason));
+        },
+        other => {
+            let error = TransferError::unexpected_message("answer/message_ack", other);
+            let _ = wormhole.send_json(&PeerMessage::error(&error)).await;
+            bail!(error)
+        },
+    }
+
+    wormhole.close().await?;
+    Ok(())
+}
+
+/**
+ * An offer from the other side, see [`request_offer`]
+ */
+#[allow(clippy::large_enum_variant)]
+pub enum ReceiveOffer {
+    /** A text message. It has been acknowledged already, and the wormhole got closed */
+    Text(String),
+    /** A file or folder, which is yet to be accepted or rejected */
+    File(ReceiveRequest),
+}
+
+/**
+ * Wait for a file offer from the other side
+ *
+ * This method waits for an offer message and builds up a [`ReceiveRequest`](ReceiveRequest).
+ * It will also start building a TCP connection to the other side using the transit protocol.
+ *
+ * Text messages get rejected with [`TransferError::UnsupportedOffer`], use [`request_offer`]
+ * to receive them as well.
+ */
+pub async fn request_file(
+    wormhole: Wormhole,
+    relay_hints: Vec<transit::RelayHint>,
+    transit_config: transit::TransitConfig,
+) -> Result<ReceiveRequest, TransferError> {
+    match receive_offer(wormhole, relay_hints, transit_config).await? {
+        IncomingOffer::File(request) => Ok(request),
+        IncomingOffer::Text(mut wormhole, _) => {
+            let error = TransferError::UnsupportedOffer;
+            let _ = wormhole.send_json(&PeerMessage::error(&error)).await;
+            wormhole.close().await?;
+            Err(error)
+        },
+        IncomingOffer::NoMoreOffers(wormhole) => Err(no_offer(wormhole).await),
+    }
+}
+
+/**
+ * Wait for a text message or a file offer from the other side
+ *
+ * Text messages are acknowledged right away, as the Python implementation does.
+ * File offers work like with [`request_file`].
+ */
+pub async fn request_offer(
+    wormhole: Wormhole,
+    relay_hints: Vec<transit::RelayHint>,
+    transit_config: transit::TransitConfig,
+) -> Result<ReceiveOffer, TransferError> {
+    match receive_offer(wormhole, relay_hints, transit_config).await? {
+        IncomingOffer::File(request) => Ok(ReceiveOffer::File(request)),
+        IncomingOffer::Text(wormhole, text) => acknowledge_text(wormhole, text).await,
+        IncomingOffer::NoMoreOffers(wormhole) => Err(no_offer(wormhole).await),
+    }
+}
+
+async fn acknowledge_text(
+    mut wormhole: Wormhole,
+    text: String,
+) -> Result<ReceiveOffer, TransferError> {
+    debug!("Sending message ack");
+    wormhole.send_json(&PeerMessage::message_ack("ok")).await?;
+    wormhole.close().await?;
+    Ok(ReceiveOffer::Text(text))
+}
+
+/** Like [`ReceiveOffer`], but texts have yet to be answered */
+enum IncomingOffer {
+    Text(Wormhole, String),
+    File(ReceiveRequest),
+    /** The other side is done with [`send_multiple`] */
+    NoMoreOffers(Wormhole),
+}
+
+/** Tell the other side that we didn't expect it to be done already */
+async fn no_offer(mut wormhole: Wormhole) -> TransferError {
+    let error = TransferError::unexpected_message("offer", PeerMessage::NoMoreOffers {});
+    let _ = wormhole.send_json(&PeerMessage::error(&error)).await;
+    let _ = wormhole.close().await;
+    error
+}
+
+async fn receive_offer(
+    mut wormhole: Wormhole,
+    relay_hints: Vec<transit::RelayHint>,
+    transit_config: transit::TransitConfig,
+) -> Result<IncomingOffer, TransferError> {
+    // receive transit message, which tells which version of the protocol the sender chose.
+    // Texts come without one.
+    let peer_version: AppVersion = serde_json::from_value(wormhole.peer_version.clone())?;
+    let (their_abilities, their_hints, is_v2): (transit::Abilities, transit::Hints, bool) =
+        match serde_json::from_slice(&wormhole.receive().await?)? {
+            PeerMessage::Offer(Offer::Message(text)) => {
+                return Ok(IncomingOffer::Text(wormhole, text));
+            },
+            PeerMessage::NoMoreOffers {} if peer_version.supports_multiple() => {
+                return Ok(IncomingOffer::NoMoreOffers(wormhole));
+            },
+            PeerMessage::Transit(transit) => {
+                debug!("received transit message: {:?}", transit);
+                (transit.abilities_v1, transit.hints_v1.into(), false)
+            },
+            PeerMessage::TransitV2(transit) if peer_version.supports_v2() => {
+                debug!("received transit message: {:?}", transit);
+                let their_abilities = peer_version
+                    .transfer_v2
+                    .as_ref()
+                    .map(|hint| hint.transit_abilities)
+                    .unwrap_or_default();
+                (their_abilities, transit.hints, true)
+            },
+            PeerMessage::Error(err) => {
+                bail!(TransferError::PeerError(err));
+            },
+            PeerMessage::Cancel(reason) => {
+                bail!(TransferError::PeerCancelled(reason));
+            },
+            other => {
+                let error = TransferError::unexpected_message("transit", other);
+                let _ = wormhole.send_json(&PeerMessage::error(&error)).await;
+                bail!(error)
+            },
+        };
+
+    let relay_hints = add_server_relay_hints(&wormhole, relay_hints);
+    let connector = transit::init(
+        transit::Abilities::ALL_ABILITIES,
+        None,
+        relay_hints,
+        &transit_config,
+    )
+    .await?;
+
+    // send the transit message
+    debug!("Sending transit message '{:?}", connector.our_hints());
+    let our_hints = (**connector.our_hints()).clone();
+    if is_v2 {
+        wormhole
+            .send_json(&PeerMessage::transit_v2(our_hints))
+            .await?;
+    } else {
+        wormhole
+            .send_json(&PeerMessage::transit(*connector.our_abilities(), our_hints))
+            .await?;
+    }
+
+    // 3. receive file offer message from peer
+    let maybe_offer = serde_json::from_slice(&wormhole.receive().await?)?;
+    debug!("Received offer message '{:?}'", &maybe_offer);
+
+    /* Only the Python implementation sends directories as such, zipped */
+    let mut zipped = false;
+    let (filename, filesize, directory, offer_v2, compressed, metadata) = match maybe_offer {
+        PeerMessage::Offer(Offer::Message(text)) if !is_v2 => {
+            return Ok(IncomingOffer::Text(wormhole, text));
+        },
+        PeerMessage::Offer(offer_type) if !is_v2 => match offer_type {
+            Offer::File {
+                filename,
+                filesize,
+                numbytes,
+                numfiles,
+                compression,
+                mtime,
+                executable,
+                mime_type,
+                mode,
+                preview,
+            } => {
+                let directory = match (numbytes, numfiles) {
+                    (Some(numbytes), Some(numfiles)) => Some(DirectoryInfo { numbytes, numfiles }),
+                    _ => None,
+                };
+                let compressed = match compression.as_deref() {
+                    None => false,
+                    Some(v1::COMPRESSION_ZSTD) => true,
+                    Some(_) => {
+                        let error = TransferError::UnsupportedOffer;
+                        let _ = wormhole.send_json(&PeerMessage::error(&error)).await;
+                        bail!(error)
+                    },
+                };
+                let metadata = FileMetadata {
+                    mtime,
+                    executable,
+                    mime_type,
+                    mode,
+                    preview: metadata::accept_preview(preview),
+                };
+                (filename, filesize, directory, None, compressed, metadata)
+            },
+            Offer::Directory {
+                mut dirname,
+                zipsize,
+                numbytes,
+                numfiles,
+                ..
+            } => {
+                dirname.set_extension("zip");
+                zipped = true;
+                (
+                    dirname,
+                    zipsize,
+                    Some(DirectoryInfo { numbytes, numfiles }),
+                    None,
+                    false,
+                    FileMetadata::default(),
+                )
+            },
+            _ => bail!(TransferError::UnsupportedOffer),
+        },
+        PeerMessage::OfferV2(offer) if is_v2 => match v2::describe_offer(&offer) {
+            Ok((filename, filesize, directory)) => {
+                let metadata = v2::offer_metadata(&offer);
+                (filename, filesize, directory, Some(offer), false, metadata)
+            },
+            Err(error) => {
+                let _ = wormhole.send_json(&PeerMessage::error(&error)).await;
+                bail!(error)
+            },
+        },
+        PeerMessage::Error(err) => {
+            bail!(TransferError::PeerError(err));
+        },
+        PeerMessage::Cancel(reason) => {
+            bail!(TransferError::PeerCancelled(reason));
+        },
+        _ => {
+            let error = TransferError::unexpected_message("offer", maybe_offer);
+            let _ = wormhole.send_json(&PeerMessage::error(&error)).await;
+            bail!(error)
+        },
+    };
+
+    let req = ReceiveRequest {
+        wormhole: Some(wormhole),
+        filename,
+        filesize,
+        directory,
+        connector: Some(connector),
+        their_abilities,
+        their_hints: Arc::new(their_hints),
+        metadata,
+        offer_v2,
+        selection: None,
+        compressed,
+        zipped,
+        peer_supports_resume: peer_version.supports_resume(),
+        session: None,
+        received_at: std::time::Instant::now(),
+        timeout: None,
+    };
+
+    Ok(IncomingOffer::File(req))
+}
+
+/**
+ * Wait for file offers over and over again, using the same code every time
+ *
+ * This is the receiving counterpart to `send-many`: a long-running receiver claims the
+ * same code repeatedly and yields a [`ReceiveRequest`] for every peer that connects
+ * with it. One peer is waited for at a time: the next connection attempt only starts once
+ * the stream gets polled again. Every request owns its wormhole though, so to handle offers
+ * concurrently, spawn a task for each request and keep polling the stream meanwhile.
+ *
+ * Failed attempts (e.g. a peer with a wrong code) are yielded as errors without stopping the
+ * stream. Only if the rendezvous server itself fails, the stream ends after yielding that error.
+ * Simply drop the stream once you don't want any new offers anymore.
+ */
+pub fn incoming_offers(
+    config: crate::AppConfig<AppVersion>,
+    code: crate::Code,
+    relay_hints: Vec<transit::RelayHint>,
+    transit_config: transit::TransitConfig,
+) -> impl futures::Stream<Item = Result<ReceiveRequest, TransferError>> {
+    futures::stream::unfold(Some(()), move |state| {
+        let config = config.clone();
+        let code = code.clone();
+        let relay_hints = relay_hints.clone();
+        let transit_config = transit_config.clone();
+        async move {
+            /* `None` marks that the stream has ended */
+            state?;
+
+            let result = async {
+                let (_welcome, wormhole) = Wormhole::connect_with_code(config, code).await?;
+                request_file(wormhole, relay_hints, transit_config).await
+            }
+            .await;
+
+            match result {
+                Err(TransferError::Wormhole(WormholeError::ServerError(error))) => Some((
+                    Err(TransferError::Wormhole(WormholeError::ServerError(error))),
+                    None,
+                )),
+                result => Some((result, Some(()))),
+            }
+        }
+    })
+}
+
+/**
+ * Wait for the offers of [`send_multiple`], one after another
+ *
+ * This yields a [`ReceiveOffer`] for every offer the other side makes over this wormhole.
+ * The next offer is only received once the previous request has been answered: accepted (and
+ * received completely) or rejected. Instead of being closed, the wormhole is then handed back
+ * for the next offer. A dropped request is rejected in the background, as usual.
+ *
+ * The stream ends once the other side has no more offers, or after an error (including a failed
+ * transfer). If the other side does not support several offers, only its one offer is yielded.
+ * Text messages are acknowledged like with [`request_offer`], and end the stream as well.
+ */
+pub fn request_multiple(
+    wormhole: Wormhole,
+    relay_hints: Vec<transit::RelayHint>,
+    transit_config: transit::TransitConfig,
+) -> impl futures::Stream<Item = Result<ReceiveOffer, TransferError>> {
+    #[allow(clippy::large_enum_variant)]
+    enum State {
+        Ready(Wormhole),
+        Waiting(futures::channel::oneshot::Receiver<Wormhole>),
+        Done,
+    }
+
+    futures::stream::unfold(State::Ready(wormhole), move |state| {
+        let relay_hints = relay_hints.clone();
+        let transit_config = transit_config.clone();
+        async move {
+            let wormhole = match state {
+                State::Ready(wormhole) => wormhole,
+                /* If the wormhole doesn't come back, the last transfer failed and took it along */
+                State::Waiting(receiver) => receiver.await.ok()?,
+                State::Done => return None,
+            };
+            let multiple = match serde_json::from_value::<AppVersion>(wormhole.peer_version.clone())
+            {
+                Ok(peer_version) => peer_version.supports_multiple(),
+                Err(error) => return Some((Err(error.into()), State::Done)),
+            };
+
+            match receive_offer(wormhole, relay_hints, transit_config).await {
+                Ok(IncomingOffer::File(mut request)) if multiple => {
+                    let (session, receiver) = futures::channel::oneshot::channel();
+                    request.session = Some(session);
+                    Some((Ok(ReceiveOffer::File(request)), State::Waiting(receiver)))
+                },
+                Ok(IncomingOffer::File(request)) => {
+                    Some((Ok(ReceiveOffer::File(request)), State::Done))
+                },
+                Ok(IncomingOffer::Text(wormhole, text)) => {
+                    let result = acknowledge_text(wormhole, text).await;
+                    Some((result, State::Done))
+                },
+                Ok(IncomingOffer::NoMoreOffers(wormhole)) => {
+                    if let Err(error) = wormhole.close().await {
+                        return Some((Err(error.into()), State::Done));
+                    }
+                    None
+                },
+                Err(error) => Some((Err(error), State::Done)),
+            }
+        }
+    })
+}
+
+/**
+ * What an offered directory contains
+ *
+ * Directories are sent as a single archive (a zip file by the Python implementation, a tar file
+ * by this one), whose size is the `filesize` of the offer. These are the numbers from before
+ * archiving, as claimed by the sender.
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DirectoryInfo {
+    /** The total size of all files */
+    pub numbytes: u64,
+    /** How many files there are, not counting directories */
+    pub numfiles: u64,
+}
+
+/**
+ * A pending files send offer from the other side
+ *
+ * You *should* consume this object, either by calling [`accept`](ReceiveRequest::accept) or [`reject`](ReceiveRequest::reject).
+ * If it gets dropped instead, the offer gets rejected in the background (see [`Drop`](#impl-Drop)).
+ *
+ * The sender waits for the answer as long as its [`offer_timeout`](SendOptions::offer_timeout)
+ * allows, which is forever by default. To not keep it waiting for a user who walked away, give
+ * the offer a deadline with [`expire_after`](ReceiveRequest::expire_after).
  */
 #[must_use]
 pub struct ReceiveRequest {
-    wormhole: Wormhole,
-    connector: TransitConnector,
+    /* Both only `None` once consumed */
+    wormhole: Option<Wormhole>,
+    connector: Option<TransitConnector>,
     /// **Security warning:** this is untrusted and unverified input
     pub filename: PathBuf,
     pub filesize: u64,
+    /** `Some` if a directory is offered, packed into the file */
+    pub directory: Option<DirectoryInfo>,
+    /** The sender's modification time, executable bit, file type and preview, as far as it told us */
+    pub metadata: FileMetadata,
     their_abilities: transit::Abilities,
     their_hints: Arc<transit::Hints>,
+    /** `Some` if the sender uses version 2 of the protocol */
+    offer_v2: Option<OfferV2>,
+    /** `Some` if only some of the files get received */
+    selection: Option<FileSelection>,
+    /** Whether the content comes compressed with zstd (only in version 1) */
+    compressed: bool,
+    /** Whether a directory comes as zip file (from the Python implementation) instead of a tar file */
+    zipped: bool,
+    peer_supports_resume: bool,
+    /** `Some` if more offers may follow on the same wormhole */
+    session: Option<Session>,
+    received_at: std::time::Instant,
+    timeout: Option<std::time::Duration>,
 }
 
-impl ReceiveRequest {
+impl ReceiveRequest {
+    /**
+     * Reject the offer once it has been pending for `timeout`
+     *
+     * Accepting it afterwards rejects it instead, telling the sender that the offer expired,
+     * and fails with [`TransferError::OfferExpired`]. To answer the sender right when the time
+     * is up, race the user's decision against [`expired`](ReceiveRequest::expired) and drop the
+     * request if that wins.
+     */
+    pub fn expire_after(mut self, timeout: std::time::Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /** How long the sender has been waiting for an answer */
+    pub fn pending_for(&self) -> std::time::Duration {
+        self.received_at.elapsed()
+    }
+
+    /** How long until the offer expires, if it [does](ReceiveRequest::expire_after) */
+    pub fn expires_in(&self) -> Option<std::time::Duration> {
+        self.timeout
+            .map(|timeout| timeout.saturating_sub(self.pending_for()))
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_in() == Some(std::time::Duration::ZERO)
+    }
+
+    /** Wait until the offer expires, or forever if it doesn't */
+    pub async fn expired(&self) {
+        match self.expires_in() {
+            Some(remaining) => async_std::task::sleep(remaining).await,
+            None => futures::future::pending().await,
+        }
+    }
+
+    /**
+     * The transit abilities the sender advertised
+     *
+     * Together with [`their_hints`](ReceiveRequest::their_hints), this is mostly useful
+     * for diagnosing why a transfer does not use a direct connection.
+     */
+    pub fn their_abilities(&self) -> &transit::Abilities {
+        &self.their_abilities
+    }
+
+    /**
+     * The connection hints (direct addresses and relay servers) the sender advertised
+     *
+     * **Security warning:** this is untrusted and unverified input
+     */
+    pub fn their_hints(&self) -> &transit::Hints {
+        &self.their_hints
+    }
+
+    /**
+     * The offered [`filename`](ReceiveRequest::filename), made safe to use in a target directory
+     *
+     * Only the last component is kept, so that `../../.bashrc` becomes `.bashrc`. Absolute
+     * paths and characters that separate paths on other platforms are dealt with as well.
+     * `None` if the sender did not offer a usable name.
+     */
+    pub fn safe_file_name(&self) -> Option<PathBuf> {
+        sanitize_file_name(&self.filename)
+    }
+
+    /**
+     * The files that are offered, if the sender uses version 2 of the protocol
+     *
+     * Their paths are relative to the offered folder. See [`select`](ReceiveRequest::select)
+     * to only receive some of them.
+     *
+     * **Security warning:** this is untrusted and unverified input
+     */
+    pub fn manifest(&self) -> Option<&[OfferV2Entry]> {
+        self.offer_v2.as_ref().map(|offer| &offer.files[..])
+    }
+
+    /**
+     * Only receive some of the offered files, the sender skips the others
+     *
+     * What gets received stays the same otherwise: the content of a single file, or a tar file
+     * with the selected files. The [`filesize`](ReceiveRequest::filesize) and
+     * [`directory`](ReceiveRequest::directory) are updated to what is selected.
+     *
+     * Offers without a [`manifest`](ReceiveRequest::manifest) can only be received as a whole:
+     * accepting them after selecting anything but [`FileSelection::All`] rejects them, and fails
+     * with [`TransferError::UnsupportedOffer`].
+     */
+    pub fn select(mut self, selection: FileSelection) -> Self {
+        /* The whole offer was checked when it came in, so a part of it can't be too large */
+        if let Some(Ok((filesize, directory))) = self
+            .offer_v2
+            .as_ref()
+            .map(|offer| v2::selection_size(offer, &selection.answer(offer)))
+        {
+            self.filesize = filesize;
+            self.directory = directory;
+        }
+        self.selection = Some(selection).filter(|selection| *selection != FileSelection::All);
+        self
+    }
+
+    /** See [`Wormhole::verifier`], to compare it before accepting the offer */
+    pub fn verifier(&self) -> &xsalsa20poly1305::Key {
+        self.wormhole.as_ref().unwrap().verifier()
+    }
+
+    /**
+     * Acknowledge the offer and establish the transit connection, without receiving anything yet
+     *
+     * This allows inspecting the connection (see [`ConnectedReceiveRequest::transit_info`])
+     * before deciding to accept the file, for example to let the user confirm once more.
+     * [`accept`](ReceiveRequest::accept) and [`accept_stream`](ReceiveRequest::accept_stream)
+     * do this implicitly.
+     */
+    pub async fn connect(self) -> Result<ConnectedReceiveRequest, TransferError> {
+        self.connect_resuming(None).await
+    }
+
+    /**
+     * Like [`connect`](ReceiveRequest::connect), but continue a partial file for `path` if there is one
+     *
+     * If [`resume`](ReceiveOptions::resume) is set and the data of an earlier attempt to receive
+     * this offer was kept (see [`PartialFiles::KeepOnDisconnect`]), the sender is asked to skip
+     * what is there already. This requires the sender to support it, which the Python
+     * implementation doesn't, and does not work with version 2 of the protocol yet.
+     * Otherwise, this is the same as [`connect`](ReceiveRequest::connect).
+     *
+     * If there is a [`verifier_check`](ReceiveOptions::verifier_check) and it fails, the offer
+     * gets rejected and this returns [`TransferError::Cancelled`].
+     *
+     * Receive the file with [`ConnectedReceiveRequest::accept_with_path`] afterwards, using the same `path`.
+     */
+    pub async fn connect_with_path(
+        self,
+        path: impl AsRef<std::path::Path>,
+        options: &ReceiveOptions,
+    ) -> Result<ConnectedReceiveRequest, TransferError> {
+        self.connect_with(Some(path.as_ref()), options).await
+    }
+
+    /**
+     * Like [`connect_with_path`](ReceiveRequest::connect_with_path), for receiving anywhere but
+     * into a file, like with [`ConnectedReceiveRequest::accept`]
+     *
+     * This checks the verifier and calls the transit handler of the `options`, but never resumes.
+     */
+    pub async fn connect_with_options(
+        self,
+        options: &ReceiveOptions,
+    ) -> Result<ConnectedReceiveRequest, TransferError> {
+        self.connect_with(None, options).await
+    }
+
+    async fn connect_with(
+        mut self,
+        path: Option<&std::path::Path>,
+        options: &ReceiveOptions,
+    ) -> Result<ConnectedReceiveRequest, TransferError> {
+        if let Some(verifier_check) = &options.verifier_check {
+            if !verifier_check.check(self.verifier()).await {
+                self.reject().await?;
+                bail!(TransferError::Cancelled);
+            }
+        }
+
+        if let (Some(offer), Some(target_dir)) = (&self.offer_v2, &options.skip_existing) {
+            let storage = options.storage.clone().unwrap_or_default();
+            let missing = v2::files_to_receive(&*storage, offer, target_dir).await;
+            let selected = self.selection.clone().unwrap_or_default().answer(offer);
+            let indices = selected
+                .files
+                .keys()
+                .filter(|index| missing.files.contains_key(index))
+                .map(|&index| index as usize)
+                .collect();
+            self = self.select(FileSelection::Indices(indices));
+        }
+
+        let mut resumption = None;
+        let resumable = options.resume
+            && options.storage.is_none()
+            && self.peer_supports_resume
+            && self.offer_v2.is_none();
+        if let Some(path) = path.filter(|_| resumable) {
+            resumption =
+                match PartialTransfer::resume(part_path(path), &self.filename, self.filesize).await
+                {
+                    Ok(resumption) => resumption,
+                    Err(error) => {
+                        warn!("Cannot resume the partial file: {}", error);
+                        None
+                    },
+                };
+        }
+        let req = self.connect_resuming(resumption).await?;
+        TransitHandler::call(options.transit_handler.as_ref(), &req.transit);
+        Ok(req)
+    }
+
+    async fn connect_resuming(
+        mut self,
+        resumption: Option<resume::Resumption>,
+    ) -> Result<ConnectedReceiveRequest, TransferError> {
+        if self.is_expired() {
+            debug!("The offer expired after {:?}", self.pending_for());
+            let error = TransferError::OfferExpired;
+            reject(
+                self.wormhole.take().unwrap(),
+                self.session.take(),
+                PeerError::from(&error),
+            )
+            .await?;
+            bail!(error);
+        }
+        let mut wormhole = self.wormhole.take().unwrap();
+        let connector = self.connector.take().unwrap();
+        let filename = std::mem::take(&mut self.filename);
+        let filesize = self.filesize;
+        let directory = self.directory;
+        let metadata = std::mem::take(&mut self.metadata);
+        let their_abilities = self.their_abilities;
+        let their_hints = self.their_hints.clone();
+        let compressed = self.compressed;
+        let session = self.session.take();
+        let offer_v2 = match (self.offer_v2.take(), self.selection.take()) {
+            (Some(offer), Some(selection)) => {
+                let answer = selection.answer(&offer);
+                Some((offer, answer))
+            },
+            (Some(offer), None) => {
+                let answer = v2::answer_all(&offer);
+                Some((offer, answer))
+            },
+            (None, Some(_)) => {
+                let error = TransferError::UnsupportedOffer;
+                reject(wormhole, session, PeerError::from(&error)).await?;
+                bail!(error);
+            },
+            (None, None) => None,
+        };
+
+        // send file ack.
+        debug!("Sending ack");
+        match (&offer_v2, &resumption) {
+            (Some((_, answer)), _) => {
+                wormhole
+                    .send_json(&PeerMessage::AnswerV2(answer.clone()))
+                    .await?
+            },
+            (None, Some(resumption)) => {
+                wormhole
+                    .send_json(&PeerMessage::file_ack_resume(
+                        resumption.offset,
+                        resumption.prefix_sha256(),
+                    ))
+                    .await?
+            },
+            (None, None) => wormhole.send_json(&PeerMessage::file_ack("ok")).await?,
+        }
+
+        wormhole.events().emit(WormholeEvent::TransitConnecting);
+        match connector
+            .follower_connect(wormhole.next_transit_key(), their_abilities, their_hints)
+            .await
+        {
+            Ok(transit) => {
+                wormhole.events().emit(WormholeEvent::Transferring);
+                Ok(ConnectedReceiveRequest {
+                    wormhole,
+                    transit,
+                    filename,
+                    filesize,
+                    directory,
+                    metadata,
+                    offer_v2,
+                    compressed,
+                    resumption,
+                    session,
+                    cancel_token: None,
+                })
+            },
+            Err(error) => {
+                let error = TransferError::TransitConnect(error);
+                let _ = wormhole.send_json(&PeerMessage::error(&error)).await;
+                Err(error)
+            },
+        }
+    }
+
     /**
      * Accept the file offer
      *
      * This will transfer the file and save it on disk.
+     * See [`ConnectedReceiveRequest::accept`] for the return value.
      */
     pub async fn accept<F, W>(
-        mut self,
+        self,
         progress_handler: F,
         content_handler: &mut W,
-    ) -> Result<(), TransferError>
+    ) -> Result<TransferResult, TransferError>
     where
         F: FnMut(u64, u64) + 'static,
         W: AsyncWrite + Unpin,
     {
-        // send file ack.
-        debug!("Sending ack");
-        self.wormhole
-            .send_json(&PeerMessage::file_ack("ok"))
-            .await?;
+        self.connect()
+            .await?
+            .accept(progress_handler, content_handler)
+            .await
+    }
 
-        let mut transit = match self
-            .connector
-            .follower_connect(
-                self.wormhole
-                    .key()
-                    .derive_transit_key(self.wormhole.appid()),
-                self.their_abilities.clone(),
-                self.their_hints.clone(),
-            )
+    /**
+     * Accept only some of the offered files, see [`select`](ReceiveRequest::select)
+     *
+     * Otherwise, this is the same as [`accept`](ReceiveRequest::accept).
+     */
+    pub async fn accept_selected<F, W>(
+        self,
+        selection: FileSelection,
+        progress_handler: F,
+        content_handler: &mut W,
+    ) -> Result<TransferResult, TransferError>
+    where
+        F: FnMut(u64, u64) + 'static,
+        W: AsyncWrite + Unpin,
+    {
+        self.select(selection)
+            .accept(progress_handler, content_handler)
             .await
-        {
-            Ok(transit) => transit,
-            Err(error) => {
-                let error = TransferError::TransitConnect(error);
-                let _ = self
-                    .wormhole
-                    .send_json(&PeerMessage::Error(format!("{}", error)))
-                    .await;
-                return Err(error);
+    }
+
+    /**
+     * Accept the file offer, and save it under `path` once it is complete
+     *
+     * See [`connect_with_path`](ReceiveRequest::connect_with_path) and
+     * [`ConnectedReceiveRequest::accept_with_path`].
+     */
+    pub async fn accept_with_path<F>(
+        self,
+        progress_handler: F,
+        path: impl AsRef<std::path::Path>,
+        options: &ReceiveOptions,
+    ) -> Result<TransferResult, TransferError>
+    where
+        F: FnMut(u64, u64) + 'static,
+    {
+        let path = path.as_ref();
+        self.connect_with_path(path, options)
+            .await?
+            .accept_with_path(progress_handler, path, options)
+            .await
+    }
+
+    /**
+     * Accept the file offer, and save it in `target_dir` under the name chosen by the sender
+     *
+     * The name is the [`safe_file_name`](ReceiveRequest::safe_file_name), so nothing gets
+     * written outside of `target_dir`. If there is no usable name or the file exists already,
+     * the offer gets rejected. Otherwise, this is the same as
+     * [`accept_with_path`](ReceiveRequest::accept_with_path).
+     *
+     * Returns the path of the received file.
+     */
+    pub async fn accept_into<F>(
+        self,
+        progress_handler: F,
+        target_dir: impl AsRef<std::path::Path>,
+        options: &ReceiveOptions,
+    ) -> Result<PathBuf, TransferError>
+    where
+        F: FnMut(u64, u64) + 'static,
+    {
+        let file_path = match self.safe_file_name() {
+            Some(file_name) => target_dir.as_ref().join(file_name),
+            None => {
+                self.reject().await?;
+                bail!(TransferError::Protocol(
+                    "The sender did not specify a valid file name".into()
+                ));
             },
         };
+        if async_std::path::Path::new(&file_path).exists().await {
+            self.reject().await?;
+            bail!(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} already exists", file_path.display()),
+            ));
+        }
 
-        debug!("Beginning file transfer");
-        // TODO here's the right position for applying the output directory and to check for malicious (relative) file paths
-        match v1::tcp_file_receive(
-            &mut transit,
-            self.filesize,
-            progress_handler,
-            content_handler,
-        )
-        .await
-        {
-            Err(TransferError::Transit(error)) => {
-                let _ = self
-                    .wormhole
-                    .send_json(&PeerMessage::Error(format!("{}", error)))
-                    .await;
-                Err(TransferError::Transit(error))
+        self.accept_with_path(progress_handler, &file_path, options)
+            .await?;
+        Ok(file_path)
+    }
+
+    /**
+     * Accept the directory offer, and unpack it into a new folder in `target_dir`
+     *
+     * The folder is named like the [`safe_file_name`](ReceiveRequest::safe_file_name), without
+     * the `.tar` extension. Its content gets unpacked while it arrives, nothing is written
+     * outside of it. If the offer is not for a directory, the folder exists already or the
+     * archive is a zip file (which the Python implementation sends, and which can't be unpacked
+     * yet), the offer gets rejected.
+     *
+     * If unpacking fails, for example because the sender tries to write outside of the folder,
+     * the folder gets removed again. Only the [`transit_config`](ReceiveOptions::transit_config),
+     * [`verifier_check`](ReceiveOptions::verifier_check) and
+     * [`cancel_token`](ReceiveOptions::cancel_token) apply.
+     *
+     * Returns the path of the folder.
+     */
+    pub async fn accept_directory<F>(
+        self,
+        progress_handler: F,
+        target_dir: impl AsRef<std::path::Path>,
+        options: &ReceiveOptions,
+    ) -> Result<PathBuf, TransferError>
+    where
+        F: FnMut(u64, u64) + 'static,
+    {
+        use futures::{AsyncReadExt, AsyncWriteExt};
+
+        if self.directory.is_none() || self.zipped {
+            self.reject().await?;
+            bail!(TransferError::UnsupportedOffer);
+        }
+        let folder = match self.safe_file_name() {
+            Some(name) if name.extension() == Some("tar".as_ref()) => name.with_extension(""),
+            Some(name) => name,
+            None => {
+                self.reject().await?;
+                bail!(TransferError::Protocol(
+                    "The sender did not specify a valid folder name".into()
+                ));
             },
-            other => other,
-        }?;
+        };
+        let folder = target_dir.as_ref().join(folder);
+        if async_std::path::Path::new(&folder).exists().await {
+            self.reject().await?;
+            bail!(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} already exists", folder.display()),
+            ));
+        }
+
+        if let Some(verifier_check) = &options.verifier_check {
+            if !verifier_check.check(self.verifier()).await {
+                self.reject().await?;
+                bail!(TransferError::Cancelled);
+            }
+        }
+        let mut req = self.connect_resuming(None).await?;
+        TransitHandler::call(options.transit_handler.as_ref(), &req.transit);
+        if let Err(error) = async_std::fs::create_dir(&folder).await {
+            req.cancel("could not create the folder").await?;
+            bail!(error);
+        }
+        req.cancel_token = options.cancel_token.clone();
+
+        /* Blocks the unpacking until the data arrives */
+        struct BlockingRead<R>(R);
+
+        impl<R: AsyncRead + Unpin> std::io::Read for BlockingRead<R> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                futures::executor::block_on(self.0.read(buf))
+            }
+        }
+
+        let (reader, mut writer) = futures_ringbuf::RingBuffer::new(64 * 1024).split();
+        let folder2 = folder.clone();
+        let unpacker =
+            async_std::task::spawn_blocking(move || unpack::unpack(BlockingRead(reader), &folder2));
+        let received = match req.accept(progress_handler, &mut writer).await {
+            Ok(_) => writer.close().await.map_err(TransferError::from),
+            Err(error) => Err(error),
+        };
+        std::mem::drop(writer);
+        /* If unpacking fails, writing to it does too. Otherwise, unpacking fails for the lack of data */
+        let result = match (received, unpacker.await) {
+            (Ok(()), Ok(())) => Ok(folder.clone()),
+            (Err(TransferError::IO(_)), Err(error)) | (Ok(()), Err(error)) | (Err(error), _) => {
+                Err(error)
+            },
+        };
+        if result.is_err() {
+            let _ = async_std::fs::remove_dir_all(&folder).await;
+        }
+        result
+    }
 
-        self.wormhole.close().await?;
+    /**
+     * Accept the file offer, and receive it into memory
+     *
+     * Offers of more than `limit` bytes get rejected, so that the other side can't make us run
+     * out of memory. This holds for compressed transfers too: if the sender sends more than it
+     * offered, before or after decompressing, the transfer fails instead.
+     * Directories arrive as tar file, like with [`accept`](ReceiveRequest::accept).
+     */
+    pub async fn accept_to_vec(self, limit: u64) -> Result<Vec<u8>, TransferError> {
+        if self.filesize > limit {
+            let filesize = self.filesize;
+            self.reject().await?;
+            bail!(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!(
+                    "The offer has {} bytes, more than the limit of {}",
+                    filesize, limit
+                ),
+            ));
+        }
+        let mut buffer = Vec::with_capacity(self.filesize as usize);
+        self.accept(|_, _| {}, &mut buffer).await?;
+        Ok(buffer)
+    }
 
-        Ok(())
+    /**
+     * Accept the file offer, and read its content as a stream
+     *
+     * See [`ConnectedReceiveRequest::accept_stream`].
+     */
+    pub async fn accept_stream(self) -> Result<impl AsyncRead + Unpin, TransferError> {
+        Ok(self.connect().await?.accept_stream())
     }
 
     /**
@@ -472,12 +2448,431 @@ impl ReceiveRequest {
      * You can close the wormhole afterwards.
      */
     pub async fn reject(mut self) -> Result<(), TransferError> {
-        self.wormhole
-            .send_json(&PeerMessage::error_message("transfer rejected"))
-            .await?;
-        self.wormhole.close().await?;
+        reject(
+            self.wormhole.take().unwrap(),
+            self.session.take(),
+            PeerError::new(PeerErrorCode::Rejected, "transfer rejected"),
+        )
+        .await
+    }
+}
+
+/**
+ * Reject the offer in the background, if it was neither accepted nor rejected
+ *
+ * Otherwise, the sender would wait for an answer until it gives up. This needs a running
+ * async-std executor, and may not finish if the program exits right away. If the offer has
+ * [expired](ReceiveRequest::expire_after), the sender gets told so instead.
+ */
+impl Drop for ReceiveRequest {
+    fn drop(&mut self) {
+        if let Some(wormhole) = self.wormhole.take() {
+            debug!("Dropped the offer without answering it, rejecting it in the background");
+            let session = self.session.take();
+            let error = if self.is_expired() {
+                PeerError::from(&TransferError::OfferExpired)
+            } else {
+                PeerError::new(PeerErrorCode::Rejected, "transfer rejected")
+            };
+            async_std::task::spawn(async move {
+                if let Err(error) = reject(wormhole, session, error).await {
+                    debug!("Failed to reject the offer: {}", error);
+                }
+            });
+        }
+    }
+}
+
+async fn reject(
+    mut wormhole: Wormhole,
+    session: Option<Session>,
+    error: PeerError,
+) -> Result<(), TransferError> {
+    wormhole.send_json(&PeerMessage::error(error)).await?;
+    finish(wormhole, session).await
+}
+
+/**
+ * A file offer for which the transit connection has been established
+ *
+ * The sender is waiting for the file to be received now. Either [`accept`](ConnectedReceiveRequest::accept)
+ * it, or [`cancel`](ConnectedReceiveRequest::cancel).
+ */
+#[must_use]
+pub struct ConnectedReceiveRequest {
+    wormhole: Wormhole,
+    transit: Transit,
+    /// **Security warning:** this is untrusted and unverified input
+    pub filename: PathBuf,
+    pub filesize: u64,
+    /** `Some` if a directory is offered, packed into the file */
+    pub directory: Option<DirectoryInfo>,
+    pub metadata: FileMetadata,
+    /** `Some` if the sender uses version 2 of the protocol, with our answer to the offer */
+    offer_v2: Option<(OfferV2, AnswerV2)>,
+    compressed: bool,
+    /** `Some` if only the rest of a partial file gets received */
+    resumption: Option<resume::Resumption>,
+    session: Option<Session>,
+    cancel_token: Option<CancelToken>,
+}
+
+impl ConnectedReceiveRequest {
+    /** How the transit connection goes: directly or via a relay, and to which address */
+    pub fn transit_info(&self) -> &transit::TransitInfo {
+        self.transit.info()
+    }
+
+    /**
+     * Receive the file
+     *
+     * This will transfer the file and save it on disk. The returned [`TransferResult`] has the
+     * SHA-256 sum of the received content, as it was sent to the other side for verification.
+     */
+    pub async fn accept<F, W>(
+        self,
+        progress_handler: F,
+        content_handler: &mut W,
+    ) -> Result<TransferResult, TransferError>
+    where
+        F: FnMut(u64, u64) + 'static,
+        W: AsyncWrite + Unpin,
+    {
+        let span = span!(parent: self.wormhole.span(), "receive", name = %self.filename.display());
+        let Self {
+            mut wormhole,
+            mut transit,
+            filesize,
+            offer_v2,
+            compressed,
+            resumption,
+            session,
+            cancel_token,
+            ..
+        } = self;
+
+        debug!("Beginning file transfer");
+        let started = std::time::Instant::now();
+        let transfer = async {
+            match &offer_v2 {
+                Some((offer, answer)) => {
+                    v2::receive_archive(
+                        &mut transit,
+                        offer,
+                        answer,
+                        progress_handler,
+                        content_handler,
+                    )
+                    .await
+                },
+                None => {
+                    let (offset, hasher) = resumption
+                        .map(|resumption| (resumption.offset, resumption.hasher))
+                        .unwrap_or_default();
+                    v1::tcp_file_receive(
+                        &mut transit,
+                        filesize,
+                        offset,
+                        hasher,
+                        compressed,
+                        progress_handler,
+                        content_handler,
+                    )
+                    .await
+                },
+            }
+        };
+        let result =
+            match cancel::until_cancelled(cancel_token.as_ref(), span.instrument(transfer)).await {
+                Some(result) => result,
+                None => {
+                    cancel(wormhole, Some(transit), session, "transfer cancelled").await?;
+                    bail!(TransferError::Cancelled);
+                },
+            };
+        let sha256sum = match result {
+            Err(TransferError::Transit(error)) => Err(transit_failure(&mut wormhole, error).await),
+            other => other,
+        }?;
+        let result = TransferResult::new(sha256sum, filesize, started, &transit);
+
+        finish(wormhole, session).await?;
+
+        Ok(result)
+    }
+
+    /**
+     * Receive the file and save it under `path`, but only once it is complete
+     *
+     * The content is written to a temporary file next to it first (`path` with `.part` appended),
+     * which gets renamed to `path` after the transfer succeeded and the content matches the
+     * [expected hash](ReceiveOptions::expected_sha256), if any. So partially transferred or
+     * corrupted files never appear under the final name. An existing file at `path` gets replaced.
+     *
+     * On failure, the temporary file is removed again. Only a file that does not match the expected hash
+     * is left there if [`keep_mismatched`](ReceiveOptions::keep_mismatched) is set, and one that got
+     * cut off by the other side going away depending on [`partial_files`](ReceiveOptions::partial_files).
+     * The other [`ReceiveOptions`] apply as well, except for the transit configuration which has
+     * been used already.
+     *
+     * If the transfer continues a partial file (see [`ReceiveRequest::connect_with_path`]), the new
+     * data gets appended to it. Otherwise, the temporary file must not exist yet: the offer gets
+     * cancelled if it does, rather than overwriting something we did not create. Returns how the transfer went, like
+     * [`accept`](ConnectedReceiveRequest::accept). The SHA-256 sum is always that of the whole file.
+     *
+     * With a custom [`storage`](ReceiveOptions::storage), the file gets created there under `path`
+     * right away, and removed again on failure. If there is a file at `path` already, the offer
+     * gets cancelled instead.
+     */
+    pub async fn accept_with_path<F>(
+        mut self,
+        progress_handler: F,
+        path: impl AsRef<std::path::Path>,
+        options: &ReceiveOptions,
+    ) -> Result<TransferResult, TransferError>
+    where
+        F: FnMut(u64, u64) + 'static,
+    {
+        if let Some(storage) = &options.storage {
+            return self
+                .accept_into_storage(progress_handler, path.as_ref(), storage, options)
+                .await;
+        }
+        let path: &async_std::path::Path = path.as_ref().into();
+        let part_path = part_path(path);
+
+        let offset = match &self.resumption {
+            Some(resumption) if resumption.part_path != part_path => {
+                self.cancel("resumed for a different file").await?;
+                bail!(TransferError::Protocol(
+                    "The transfer was resumed for a different path".into()
+                ));
+            },
+            Some(resumption) => Some(resumption.offset),
+            None => None,
+        };
+        let file = async {
+            /* Never overwrite a file we did not create, only continue our own partial file */
+            let file = async_std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(offset.is_none())
+                .append(offset.is_some())
+                .open(&part_path)
+                .await?;
+            /* Drop anything that was received after the part we continue from */
+            if let Some(offset) = offset {
+                file.set_len(offset).await?;
+            }
+            std::io::Result::Ok(file)
+        };
+        let mut file = match file.await {
+            Ok(file) => file,
+            Err(error) => {
+                self.cancel("could not open the file").await?;
+                bail!(error);
+            },
+        };
+        /* The partial file is either new or being continued */
+        let _ = PartialTransfer::remove(&part_path).await;
+
+        let file_name = self.filename.clone();
+        let file_size = self.filesize;
+        let metadata = self.metadata.clone();
+        self.cancel_token = options.cancel_token.clone();
+        let result = match self.accept(progress_handler, &mut file).await {
+            Ok(result) if options.durability != Durability::None => file
+                .sync_all()
+                .await
+                .map(|()| result)
+                .map_err(TransferError::from),
+            result => result,
+        };
+        let result = match result {
+            Ok(result) => result,
+            Err(error @ TransferError::PeerDisconnected(_))
+                if options.partial_files == PartialFiles::KeepOnDisconnect =>
+            {
+                let state = async {
+                    use futures::AsyncWriteExt;
+                    file.flush().await?;
+                    let state = PartialTransfer {
+                        file_name,
+                        file_size,
+                        received: file.metadata().await?.len(),
+                    };
+                    state.save(&part_path).await?;
+                    std::io::Result::Ok(state)
+                };
+                match state.await {
+                    Ok(state) => info!(
+                        "Kept the {} bytes received so far in {}",
+                        state.received,
+                        part_path.display()
+                    ),
+                    Err(err) => warn!("Failed to keep the partial file: {}", err),
+                }
+                bail!(error);
+            },
+            Err(error) => {
+                std::mem::drop(file);
+                let _ = async_std::fs::remove_file(&part_path).await;
+                bail!(error);
+            },
+        };
+        std::mem::drop(file);
+        if !options.matches_expected(&result.sha256) {
+            if !options.keep_mismatched {
+                let _ = async_std::fs::remove_file(&part_path).await;
+            }
+            bail!(TransferError::UnexpectedChecksum {
+                expected: options.expected_sha256.clone().unwrap_or_default(),
+                actual: result.sha256,
+            });
+        }
+
+        if options.preserve_metadata {
+            metadata.apply(part_path.as_ref()).await?;
+        }
+        async_std::fs::rename(&part_path, path).await?;
+        #[cfg(unix)]
+        if options.durability == Durability::FileAndDirectory {
+            let directory = match path.parent() {
+                Some(parent) if parent != async_std::path::Path::new("") => parent,
+                _ => async_std::path::Path::new("."),
+            };
+            async_std::fs::File::open(directory)
+                .await?
+                .sync_all()
+                .await?;
+        }
+        if options.checksum_file {
+            write_checksum_file(path, &result.sha256).await?;
+        }
+        Ok(result)
+    }
+
+    /** Like [`accept_with_path`](Self::accept_with_path), but through a custom [`Storage`] */
+    async fn accept_into_storage<F>(
+        mut self,
+        progress_handler: F,
+        path: &std::path::Path,
+        storage: &SharedStorage,
+        options: &ReceiveOptions,
+    ) -> Result<TransferResult, TransferError>
+    where
+        F: FnMut(u64, u64) + 'static,
+    {
+        use futures::AsyncWriteExt;
+
+        let mut file = match storage.create(path).await {
+            Ok(file) => file,
+            Err(error) => {
+                self.cancel("could not create the file").await?;
+                bail!(error);
+            },
+        };
+        self.cancel_token = options.cancel_token.clone();
+        let result = match self.accept(progress_handler, &mut file).await {
+            Ok(result) => file.close().await.map(|()| result).map_err(Into::into),
+            Err(error) => Err(error),
+        };
+        std::mem::drop(file);
+        let result = match result {
+            Ok(result) if options.matches_expected(&result.sha256) => result,
+            Ok(result) => {
+                if !options.keep_mismatched {
+                    let _ = storage.remove_file(path).await;
+                }
+                bail!(TransferError::UnexpectedChecksum {
+                    expected: options.expected_sha256.clone().unwrap_or_default(),
+                    actual: result.sha256,
+                });
+            },
+            Err(error) => {
+                let _ = storage.remove_file(path).await;
+                bail!(error);
+            },
+        };
+        Ok(result)
+    }
+
+    /**
+     * Receive the file, and read its content as a stream
+     *
+     * This returns a reader yielding the decrypted file content, so that it can be processed on the fly.
+     * The reader must be read until EOF: only then the checksum gets sent
+     * to the other side (where it will be verified) and the wormhole gets closed.
+     * Errors are wrapped into [`std::io::Error`]s, with a [`TransferError`] inside.
+     *
+     * Offers using version 2 of the protocol can't be streamed (yet), reading them fails with
+     * [`TransferError::UnsupportedOffer`].
+     */
+    pub fn accept_stream(self) -> impl AsyncRead + Unpin {
+        use futures::TryStreamExt;
+        use sha2::{digest::FixedOutput, Digest, Sha256};
+
+        let Self {
+            wormhole,
+            transit,
+            filesize,
+            offer_v2,
+            session,
+            ..
+        } = self;
+        let is_v2 = offer_v2.is_some();
+
+        debug!("Beginning file transfer");
+        let records = futures::stream::try_unfold(
+            (wormhole, transit, session, Sha256::default(), 0),
+            move |(mut wormhole, mut transit, session, mut hasher, received)| async move {
+                if is_v2 {
+                    let error = TransferError::UnsupportedOffer;
+                    let _ = wormhole.send_json(&PeerMessage::error(&error)).await;
+                    bail!(error);
+                }
+                if received == filesize {
+                    let sha256sum = hex::encode(hasher.finalize_fixed());
+                    debug!("sha256 sum: {:?}", sha256sum);
+                    transit
+                        .send_record(&TransitAck::new("ok", &sha256sum).serialize_vec())
+                        .await?;
+                    finish(wormhole, session).await?;
+                    debug!("Transfer complete");
+                    return Ok(None);
+                }
+
+                let plaintext = match transit.receive_record().await {
+                    Ok(plaintext) => plaintext,
+                    Err(error) => bail!(transit_failure(&mut wormhole, error).await),
+                };
+                let received = received + plaintext.len() as u64;
+                ensure!(
+                    received <= filesize,
+                    TransferError::FileSize {
+                        sent_size: received,
+                        file_size: filesize
+                    }
+                );
+                hasher.update(&plaintext);
+
+                Ok(Some((
+                    plaintext,
+                    (wormhole, transit, session, hasher, received),
+                )))
+            },
+        )
+        .map_err(|error: TransferError| std::io::Error::new(std::io::ErrorKind::Other, error));
+
+        Box::pin(records).into_async_read()
+    }
 
-        Ok(())
+    /**
+     * Don't receive the file after all
+     *
+     * The other side gets told that this was deliberate, and all connections are closed.
+     */
+    pub async fn cancel(self, reason: impl Into<String>) -> Result<(), TransferError> {
+        cancel(self.wormhole, Some(self.transit), self.session, reason).await
     }
 }
 
@@ -490,4 +2885,95 @@ mod test {
         let f1 = TransitAck::new("ok", "deadbeaf");
         assert_eq!(f1.serialize(), "{\"ack\":\"ok\",\"sha256\":\"deadbeaf\"}");
     }
+
+    #[test]
+    fn test_app_version() {
+        /* The Python implementation sends an empty object */
+        let peer: AppVersion = serde_json::from_value(json!({})).unwrap();
+        assert!(!peer.supports_v2());
+        assert!(!peer.supports_multiple());
+
+        let peer: AppVersion = serde_json::from_value(json!({
+            "abilities": ["transfer-v1", "transfer-v3"],
+            "transfer-v3": {"formats": ["tar.zst"]},
+        }))
+        .unwrap();
+        assert_eq!(
+            peer.unknown_fields().collect::<Vec<_>>(),
+            vec![("transfer-v3", &json!({"formats": ["tar.zst"]}))]
+        );
+
+        let ours = serde_json::to_value(AppVersion::new()).unwrap();
+        assert_eq!(ours["abilities"][0], "transfer-v1");
+        let peer: AppVersion = serde_json::from_value(ours).unwrap();
+        assert_eq!(
+            peer.supports_v2(),
+            cfg!(feature = "experimental-transfer-v2")
+        );
+        assert!(peer.supports_resume());
+        assert!(peer.supports_multiple());
+        assert!(peer.supports_compression());
+    }
+
+    #[async_std::test]
+    async fn test_checksum_file() {
+        let dir = std::env::temp_dir().join(format!("wormhole-checksum-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        write_checksum_file(dir.join("file.txt"), "deadbeaf")
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.join("file.txt.sha256")).unwrap(),
+            "deadbeaf  file.txt\n"
+        );
+        write_checksum_file(dir.join("new\nline"), "deadbeaf")
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.join("new\nline.sha256")).unwrap(),
+            "\\deadbeaf  new\\nline\n"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_is_disconnect() {
+        use std::io::{Error, ErrorKind};
+        let eof = TransitError::IO(Error::new(ErrorKind::UnexpectedEof, "eof"));
+        assert!(is_disconnect(&eof));
+        let reset = TransitError::IO(Error::new(ErrorKind::ConnectionReset, "reset"));
+        assert!(is_disconnect(&reset));
+        /* Garbage from the other side is not a disconnect */
+        assert!(!is_disconnect(&TransitError::Crypto));
+        let full = TransitError::IO(Error::new(ErrorKind::Other, "disk full"));
+        assert!(!is_disconnect(&full));
+    }
+
+    #[test]
+    fn test_sanitize_file_name() {
+        let sanitize = |name: &str| sanitize_file_name(std::path::Path::new(name));
+        assert_eq!(sanitize("file.txt"), Some("file.txt".into()));
+        assert_eq!(sanitize("../../.bashrc"), Some(".bashrc".into()));
+        assert_eq!(sanitize("/etc/passwd"), Some("passwd".into()));
+        assert_eq!(sanitize("dir/.."), None);
+        assert_eq!(sanitize(".."), None);
+        assert_eq!(sanitize(""), None);
+        assert_eq!(sanitize(" "), None);
+        if cfg!(unix) {
+            assert_eq!(sanitize("..\\..\\evil.exe"), Some(".._.._evil.exe".into()));
+            assert_eq!(sanitize("C:evil\ttab"), Some("C_evil_tab".into()));
+        }
+    }
+
+    #[test]
+    fn test_expected_sha256() {
+        let options = ReceiveOptions::default();
+        assert!(options.matches_expected("deadbeaf"));
+
+        let options = options.expected_sha256("DEADbeaf");
+        assert!(options.matches_expected("deadbeaf"));
+        assert!(!options.matches_expected("deadbeef"));
+    }
 }
@@ -0,0 +1,153 @@
+//! Aborting transfers that are in progress
+//!
+//! Simply dropping the future of a transfer leaves the other side waiting until it gives up.
+//! Instead, pass a [`CancelToken`] to the transfer (see [`SendOptions::cancel_token`](super::SendOptions::cancel_token)
+//! and [`ReceiveOptions::cancel_token`](super::ReceiveOptions::cancel_token)) and cancel it from wherever the
+//! user clicks the button. The transfer then tells the other side, closes its connections and fails with
+//! [`TransferError::Cancelled`](super::TransferError::Cancelled).
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    task::{Poll, Waker},
+};
+
+/**
+ * A handle to cancel a transfer from elsewhere
+ *
+ * This is cheap to clone, all clones refer to the same transfer(s). Once cancelled, it stays
+ * cancelled, so use a new one for every transfer.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<Mutex<State>>);
+
+#[derive(Debug, Default)]
+struct State {
+    cancelled: bool,
+    /** The transfers waiting to get cancelled, by the ID of their [`Listener`] */
+    wakers: HashMap<u64, Waker>,
+    next_listener: u64,
+}
+
+/** Removes the waker of a [`CancelToken::cancelled`] future once it is done or dropped */
+struct Listener<'a> {
+    token: &'a CancelToken,
+    id: u64,
+}
+
+impl Drop for Listener<'_> {
+    fn drop(&mut self) {
+        self.token.0.lock().unwrap().wakers.remove(&self.id);
+    }
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /** Cancel the transfer(s) using this token. It does not wait for them to finish */
+    pub fn cancel(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.cancelled = true;
+        for (_, waker) in state.wakers.drain() {
+            waker.wake();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.lock().unwrap().cancelled
+    }
+
+    /** Wait until [`cancel`](Self::cancel) gets called */
+    pub async fn cancelled(&self) {
+        let listener = {
+            let mut state = self.0.lock().unwrap();
+            state.next_listener += 1;
+            Listener {
+                token: self,
+                id: state.next_listener,
+            }
+        };
+        futures::future::poll_fn(|cx| {
+            let mut state = self.0.lock().unwrap();
+            if state.cancelled {
+                return Poll::Ready(());
+            }
+            state.wakers.insert(listener.id, cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+/** Tokens are equal if they are clones of each other */
+impl PartialEq for CancelToken {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for CancelToken {}
+
+/**
+ * Run the transfer to completion, unless the token gets cancelled first
+ *
+ * Returns `None` if it got cancelled. The transfer is dropped then, which closes its transit
+ * connection, but it is up to the caller to tell the other side.
+ */
+pub(super) async fn until_cancelled<T>(
+    cancel_token: Option<&CancelToken>,
+    transfer: impl std::future::Future<Output = T>,
+) -> Option<T> {
+    use futures::future::Either;
+
+    let cancel_token = match cancel_token {
+        Some(cancel_token) => cancel_token,
+        None => return Some(transfer.await),
+    };
+    if cancel_token.is_cancelled() {
+        return None;
+    }
+    let cancelled = cancel_token.cancelled();
+    futures::pin_mut!(transfer, cancelled);
+    match futures::future::select(transfer, cancelled).await {
+        Either::Left((result, _)) => Some(result),
+        Either::Right(((), _)) => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[async_std::test]
+    async fn test_until_cancelled() {
+        assert_eq!(until_cancelled(None, async { 1 }).await, Some(1));
+
+        let token = CancelToken::new();
+        assert_eq!(until_cancelled(Some(&token), async { 1 }).await, Some(1));
+
+        let transfer = until_cancelled(Some(&token), futures::future::pending::<()>());
+        let canceller = async {
+            async_std::task::yield_now().await;
+            token.clone().cancel();
+        };
+        let (result, ()) = futures::join!(transfer, canceller);
+        assert_eq!(result, None);
+        assert!(token.is_cancelled());
+
+        /* Once cancelled, nothing runs anymore */
+        assert_eq!(until_cancelled(Some(&token), async { 1 }).await, None);
+    }
+
+    #[async_std::test]
+    async fn test_wakers_are_removed() {
+        let token = CancelToken::new();
+        for _ in 0..3 {
+            let transfer = until_cancelled(Some(&token), async_std::task::yield_now());
+            assert_eq!(transfer.await, Some(()));
+        }
+        assert!(token.0.lock().unwrap().wakers.is_empty());
+    }
+}
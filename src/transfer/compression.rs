@@ -0,0 +1,146 @@
+//! Decide whether compressing a file is worth the CPU time
+//!
+//! Compressing content that already is compressed (archives, images, videos …) costs a lot
+//! of CPU time for zero gain. We sniff the file type from its first bytes and its extension
+//! and skip the compression for such files.
+
+use std::path::Path;
+
+/**
+ * Whether file payloads get compressed
+ *
+ * This is only a preference: compression is only used if both sides negotiated support for it.
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /** Compress, unless the content looks like it is already compressed */
+    Auto,
+    /** Always compress, even if the content looks already compressed */
+    Always,
+    /** Never compress */
+    Never,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::Auto
+    }
+}
+
+impl Compression {
+    /**
+     * Decide whether a file should be compressed
+     *
+     * `header` are the first few bytes of the file (a few dozen are enough), it may be
+     * shorter or even empty if the file is.
+     */
+    pub fn should_compress(self, file_name: impl AsRef<Path>, header: &[u8]) -> bool {
+        match self {
+            Compression::Auto => !is_compressed(file_name.as_ref(), header),
+            Compression::Always => true,
+            Compression::Never => false,
+        }
+    }
+}
+
+/** File extensions of archives, office documents and media formats (lower case) */
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "zip", "gz", "tgz", "bz2", "tbz2", "xz", "txz", "zst", "tzst", "lz", "lzma", "lz4", "7z",
+    "rar", "cab", "jar", "apk", "deb", "rpm", "dmg", "whl", "epub", "docx", "xlsx", "pptx", "odt",
+    "ods", "odp", "jpg", "jpeg", "png", "gif", "webp", "heic", "heif", "avif", "jxl", "mp3", "aac",
+    "m4a", "ogg", "oga", "opus", "flac", "wma", "mp4", "m4v", "mkv", "webm", "mov", "avi", "wmv",
+    "flv", "3gp",
+];
+
+/** Magic numbers of formats that are compressed anyways, at offset 0 */
+const COMPRESSED_MAGIC: &[&[u8]] = &[
+    b"PK\x03\x04",                     // zip (and everything based on it)
+    b"\x1f\x8b",                       // gzip
+    b"BZh",                            // bzip2
+    b"\xfd7zXZ\x00",                   // xz
+    b"\x28\xb5\x2f\xfd",               // zstd
+    b"\x04\x22\x4d\x18",               // lz4
+    b"7z\xbc\xaf\x27\x1c",             // 7z
+    b"Rar!\x1a\x07",                   // rar
+    b"\xff\xd8\xff",                   // jpeg
+    b"\x89PNG\r\n\x1a\n",              // png
+    b"GIF8",                           // gif
+    b"\x1a\x45\xdf\xa3",               // matroska / webm
+    b"OggS",                           // ogg
+    b"fLaC",                           // flac
+    b"ID3",                            // mp3 with ID3 tag
+    b"\x00\x00\x00\x0cjXL \r\n\x87\n", // jpeg xl
+];
+
+/** Check if the content is already compressed */
+fn is_compressed(file_name: &Path, header: &[u8]) -> bool {
+    if COMPRESSED_MAGIC
+        .iter()
+        .any(|magic| header.starts_with(magic))
+    {
+        return true;
+    }
+    /* ISO base media files (mp4, mov, heic, avif …) have their magic at offset 4 */
+    if header.len() >= 8 && &header[4..8] == b"ftyp" {
+        return true;
+    }
+    /* RIFF containers only compress for some formats (webp); avi and wav may or may not */
+    if header.len() >= 12 && header.starts_with(b"RIFF") && &header[8..12] == b"WEBP" {
+        return true;
+    }
+    file_name
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| {
+            let extension = extension.to_ascii_lowercase();
+            COMPRESSED_EXTENSIONS.contains(&extension.as_str())
+        })
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_magic_bytes() {
+        /* The extension is misleading on purpose */
+        assert!(is_compressed(Path::new("a.txt"), b"PK\x03\x04\x14\x00"));
+        assert!(is_compressed(
+            Path::new("a.txt"),
+            b"\xff\xd8\xff\xe0\x00\x10JFIF"
+        ));
+        assert!(is_compressed(
+            Path::new("a"),
+            b"\x00\x00\x00\x20ftypisom\x00\x00\x02\x00"
+        ));
+        assert!(is_compressed(
+            Path::new("a"),
+            b"RIFF\x00\x00\x00\x00WEBPVP8 "
+        ));
+        assert!(!is_compressed(
+            Path::new("a"),
+            b"RIFF\x00\x00\x00\x00WAVEfmt "
+        ));
+        assert!(!is_compressed(Path::new("a.txt"), b"Hello world"));
+        assert!(!is_compressed(Path::new("a.txt"), b""));
+    }
+
+    #[test]
+    fn test_extensions() {
+        assert!(is_compressed(Path::new("holiday.JPG"), b""));
+        assert!(is_compressed(Path::new("dir/video.mp4"), b"garbage"));
+        assert!(!is_compressed(Path::new("notes.md"), b"# Notes"));
+        assert!(!is_compressed(Path::new("zip"), b""));
+    }
+
+    #[test]
+    fn test_override() {
+        let zip = (Path::new("a.zip"), b"PK\x03\x04" as &[u8]);
+        let text = (Path::new("a.txt"), b"Hello world" as &[u8]);
+        assert!(!Compression::Auto.should_compress(zip.0, zip.1));
+        assert!(Compression::Auto.should_compress(text.0, text.1));
+        assert!(Compression::Always.should_compress(zip.0, zip.1));
+        assert!(!Compression::Never.should_compress(text.0, text.1));
+    }
+}
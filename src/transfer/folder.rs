@@ -0,0 +1,304 @@
+//! Deciding which entries of a folder get sent
+//!
+//! Besides regular files and directories, a folder may contain symlinks, FIFOs, sockets and
+//! device nodes. What happens to them is up to the [`SpecialFiles`] policy in the
+//! [`FolderSendOptions`]. Entries that don't get sent can be reported back with
+//! [`SkippedEntries`].
+
+use super::{
+    storage::{Storage, StorageEntryType},
+    FileMetadata,
+};
+use futures::future::BoxFuture;
+use log::*;
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+/**
+ * What to do with the symlinks and other special files in a folder
+ *
+ * FIFOs, sockets and device nodes can't be sent, they are skipped unless this is
+ * [`SpecialFiles::Error`].
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SpecialFiles {
+    /**
+     * Send what symlinks point to, as if it was in the folder
+     *
+     * Broken links and links to a folder containing them are skipped.
+     */
+    Follow,
+    /** Skip them */
+    Skip,
+    /**
+     * Send symlinks as links, which then point to the same target on the receiving side
+     *
+     * Version 2 of the protocol can only send regular files, there they are skipped instead.
+     */
+    PreserveAsLink,
+    /** Fail the transfer before anything has been offered */
+    Error,
+}
+
+impl Default for SpecialFiles {
+    fn default() -> Self {
+        SpecialFiles::PreserveAsLink
+    }
+}
+
+/**
+ * The entries of folders that did not get sent, with their path inside the folder
+ *
+ * This is cheap to clone, all clones share the same list.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct SkippedEntries(Arc<Mutex<Vec<PathBuf>>>);
+
+impl SkippedEntries {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /** Get the entries skipped so far, and start a new list */
+    pub fn take(&self) -> Vec<PathBuf> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+/** Lists are equal if they are clones of each other */
+impl PartialEq for SkippedEntries {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for SkippedEntries {}
+
+/**
+ * Additional settings for sending folders
+ *
+ * They are part of the [`SendOptions`](super::SendOptions), and don't apply to single files.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FolderSendOptions {
+    /** What to do with symlinks and other special files */
+    pub special_files: SpecialFiles,
+    /** Where to put the entries that did not get sent */
+    pub skipped: Option<SkippedEntries>,
+}
+
+impl FolderSendOptions {
+    pub fn special_files(mut self, special_files: SpecialFiles) -> Self {
+        self.special_files = special_files;
+        self
+    }
+
+    pub fn skipped(mut self, skipped: SkippedEntries) -> Self {
+        self.skipped = Some(skipped);
+        self
+    }
+
+    /** Report an entry that does not get sent, or fail if that is the policy */
+    fn skip(&self, name: &Path, reason: &str) -> std::io::Result<()> {
+        if self.special_files == SpecialFiles::Error {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("'{}' can't be sent, {}", name.display(), reason),
+            ));
+        }
+        warn!("Skipping '{}', {}", name.display(), reason);
+        if let Some(skipped) = &self.skipped {
+            skipped.0.lock().unwrap().push(name.to_owned());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(super) enum EntryKind {
+    Directory,
+    File { size: u64 },
+    Symlink { target: PathBuf },
+}
+
+/** Something in a folder that gets sent */
+#[derive(Clone, Debug)]
+pub(super) struct FolderEntry {
+    /** Where it is in the [`Storage`] */
+    pub path: PathBuf,
+    /** Its path inside the folder */
+    pub name: PathBuf,
+    pub kind: EntryKind,
+    pub metadata: FileMetadata,
+}
+
+/**
+ * List what gets sent of a folder, sorted by name and parents before their children
+ *
+ * `links` tells whether symlinks can be sent as such.
+ */
+pub(super) async fn walk(
+    storage: &dyn Storage,
+    folder: &Path,
+    options: &FolderSendOptions,
+    links: bool,
+) -> std::io::Result<Vec<FolderEntry>> {
+    let mut entries = Vec::new();
+    let mut ancestors = vec![storage.canonicalize(folder).await?];
+    walk_into(
+        storage,
+        folder,
+        Path::new(""),
+        options,
+        links,
+        &mut ancestors,
+        &mut entries,
+    )
+    .await?;
+    Ok(entries)
+}
+
+/* Boxed, because it recurses */
+fn walk_into<'a>(
+    storage: &'a dyn Storage,
+    folder: &'a Path,
+    prefix: &'a Path,
+    options: &'a FolderSendOptions,
+    links: bool,
+    ancestors: &'a mut Vec<PathBuf>,
+    entries: &'a mut Vec<FolderEntry>,
+) -> BoxFuture<'a, std::io::Result<()>> {
+    Box::pin(async move {
+        let mut children = storage.read_dir(folder).await?;
+        children.sort_by(|a, b| a.name.cmp(&b.name));
+        for child in children {
+            let path = folder.join(&child.name);
+            let name = prefix.join(&child.name);
+            let mut metadata = child.metadata;
+            if let StorageEntryType::Symlink { target } = metadata.entry_type {
+                match options.special_files {
+                    SpecialFiles::Follow => match storage.metadata(&path).await {
+                        Ok(target) => metadata = target,
+                        Err(_) => {
+                            options.skip(&name, "it is a broken symlink")?;
+                            continue;
+                        },
+                    },
+                    SpecialFiles::PreserveAsLink if links => {
+                        entries.push(FolderEntry {
+                            path,
+                            name,
+                            kind: EntryKind::Symlink { target },
+                            metadata: metadata.file,
+                        });
+                        continue;
+                    },
+                    _ => {
+                        options.skip(&name, "it is a symlink")?;
+                        continue;
+                    },
+                }
+            }
+
+            match metadata.entry_type {
+                StorageEntryType::Directory => {
+                    let canonical = storage.canonicalize(&path).await?;
+                    if ancestors.contains(&canonical) {
+                        options.skip(&name, "it links to a folder containing it")?;
+                        continue;
+                    }
+                    entries.push(FolderEntry {
+                        path: path.clone(),
+                        name: name.clone(),
+                        kind: EntryKind::Directory,
+                        metadata: metadata.file,
+                    });
+                    ancestors.push(canonical);
+                    walk_into(storage, &path, &name, options, links, ancestors, entries).await?;
+                    ancestors.pop();
+                },
+                StorageEntryType::File { size } => entries.push(FolderEntry {
+                    path,
+                    name,
+                    kind: EntryKind::File { size },
+                    metadata: metadata.file,
+                }),
+                _ => options.skip(&name, "it is not a regular file")?,
+            }
+        }
+        Ok(())
+    })
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use crate::transfer::OsStorage;
+
+    #[async_std::test]
+    async fn test_walk() {
+        let dir = std::env::temp_dir().join(format!("wormhole-test-walk-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("sub/file"), b"hello").unwrap();
+        std::os::unix::fs::symlink("sub/file", dir.join("link")).unwrap();
+        std::os::unix::fs::symlink("missing", dir.join("broken")).unwrap();
+        std::os::unix::fs::symlink("..", dir.join("sub/loop")).unwrap();
+
+        let walk = |special_files, links| {
+            let dir = dir.clone();
+            async move {
+                let skipped = SkippedEntries::new();
+                let options = FolderSendOptions::default()
+                    .special_files(special_files)
+                    .skipped(skipped.clone());
+                let entries = walk(&OsStorage, &dir, &options, links).await?;
+                let entries = entries
+                    .into_iter()
+                    .map(|entry| (entry.name, entry.kind))
+                    .collect::<Vec<_>>();
+                std::io::Result::Ok((entries, skipped.take()))
+            }
+        };
+        let file = (PathBuf::from("sub/file"), EntryKind::File { size: 5 });
+        let sub = (PathBuf::from("sub"), EntryKind::Directory);
+
+        let (entries, skipped) = walk(SpecialFiles::PreserveAsLink, true).await.unwrap();
+        assert_eq!(entries.len(), 5);
+        assert_eq!(
+            entries[1],
+            (
+                "link".into(),
+                EntryKind::Symlink {
+                    target: "sub/file".into()
+                }
+            )
+        );
+        assert!(skipped.is_empty());
+
+        let (entries, skipped) = walk(SpecialFiles::PreserveAsLink, false).await.unwrap();
+        assert_eq!(entries, vec![sub.clone(), file.clone()]);
+        assert_eq!(skipped.len(), 3);
+
+        let (entries, skipped) = walk(SpecialFiles::Follow, false).await.unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                ("link".into(), EntryKind::File { size: 5 }),
+                sub.clone(),
+                file.clone()
+            ]
+        );
+        assert_eq!(skipped, vec![PathBuf::from("broken"), "sub/loop".into()]);
+
+        let (entries, skipped) = walk(SpecialFiles::Skip, true).await.unwrap();
+        assert_eq!(entries, vec![sub, file]);
+        assert_eq!(skipped.len(), 3);
+
+        assert!(walk(SpecialFiles::Error, true).await.is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -10,7 +10,9 @@ use std::{collections::HashMap, path::PathBuf};
 /**
  * The type of message exchanged over the wormhole for this protocol
  */
+/* (De)serialization is implemented manually below, to keep unknown messages */
 #[derive(Deserialize, Serialize, Debug)]
+#[serde(remote = "Self")]
 #[serde(rename_all = "kebab-case")]
 #[non_exhaustive]
 pub enum PeerMessage {
@@ -19,12 +21,77 @@ pub enum PeerMessage {
     Answer(Answer),
     AnswerV2(AnswerV2),
     /** Tell the other side you got an error */
-    Error(String),
+    Error(
+        #[serde(
+            serialize_with = "PeerError::serialize_message",
+            deserialize_with = "PeerError::deserialize_message"
+        )]
+        PeerError,
+    ),
+    /**
+     * Tell the other side that the transfer was aborted on purpose
+     *
+     * Unlike [`PeerMessage::Error`], this means nothing went wrong. It can be sent at any
+     * time, including while the file content is being sent over transit.
+     */
+    Cancel(String),
+    /**
+     * Tell the other side that no offers follow anymore
+     *
+     * Only sent to peers with the `transfer-v1-multiple` ability, after the last offer.
+     */
+    NoMoreOffers {},
     /** Used to set up a transit channel */
     Transit(TransitV1),
     TransitV2(TransitV2),
-    #[serde(other)]
-    Unknown,
+    /**
+     * A message from a newer peer that we don't know, as it was received
+     *
+     * Ignore it unless waiting for a specific answer.
+     */
+    #[serde(skip)]
+    Unknown(serde_json::Value),
+}
+
+/** The keys of the [`PeerMessage`] variants, to tell them apart from unknown ones */
+const PEER_MESSAGE_VARIANTS: &[&str] = &[
+    "offer",
+    "offer-v2",
+    "answer",
+    "answer-v2",
+    "error",
+    "cancel",
+    "no-more-offers",
+    "transit",
+    "transit-v2",
+];
+
+/** The keys of the [`Offer`] variants */
+const OFFER_VARIANTS: &[&str] = &["message", "file", "directory"];
+
+/**
+ * Find the known variant of an externally tagged enum in a JSON object
+ *
+ * Newer peers may add variants, or additional fields next to the one of the variant. The latter
+ * get dropped. Returns the object unchanged if it has no known variant.
+ */
+fn known_variant(
+    value: serde_json::Value,
+    variants: &[&str],
+) -> Result<serde_json::Value, serde_json::Value> {
+    match value {
+        serde_json::Value::Object(mut map) => {
+            match variants.iter().find(|variant| map.contains_key(**variant)) {
+                Some(variant) => {
+                    let content = map.remove(*variant).unwrap();
+                    Ok(serde_json::json!({ *variant: content }))
+                },
+                None => Err(serde_json::Value::Object(map)),
+            }
+        },
+        /* Not a message at all, let the deserialization fail */
+        other => Ok(other),
+    }
 }
 
 impl PeerMessage {
@@ -36,9 +103,75 @@ impl PeerMessage {
         PeerMessage::Offer(Offer::File {
             filename: name.into(),
             filesize: size,
+            numbytes: None,
+            numfiles: None,
+            compression: None,
+            mtime: None,
+            executable: false,
+            mime_type: None,
+            mode: None,
+            preview: None,
+        })
+    }
+
+    /**
+     * A directory, sent as a tar file
+     *
+     * The Python implementation would send a [`Offer::Directory`] with a zip file instead,
+     * so this must look like a regular file to it. The additional fields will be ignored.
+     */
+    pub fn offer_tarball(
+        name: impl Into<PathBuf>,
+        size: u64,
+        numbytes: u64,
+        numfiles: u64,
+    ) -> Self {
+        PeerMessage::Offer(Offer::File {
+            filename: name.into(),
+            filesize: size,
+            numbytes: Some(numbytes),
+            numfiles: Some(numfiles),
+            compression: None,
+            mtime: None,
+            executable: false,
+            mime_type: None,
+            mode: None,
+            preview: None,
         })
     }
 
+    /**
+     * Announce that the content of a file offer gets compressed with zstd
+     *
+     * Only for peers with the `transfer-v1-zstd` ability, see [`super::v1::send_records`].
+     */
+    pub fn compressed(mut self) -> Self {
+        if let PeerMessage::Offer(Offer::File { compression, .. }) = &mut self {
+            *compression = Some(super::v1::COMPRESSION_ZSTD.into());
+        }
+        self
+    }
+
+    /** Tell the receiver about the modification time, type and so on of an offered file */
+    pub fn with_metadata(mut self, metadata: &super::FileMetadata) -> Self {
+        if let PeerMessage::Offer(Offer::File {
+            mtime,
+            executable,
+            mime_type,
+            mode,
+            preview,
+            ..
+        }) = &mut self
+        {
+            *mtime = metadata.mtime;
+            *executable = metadata.executable;
+            *mime_type = metadata.mime_type.clone();
+            *mode = metadata.mode;
+            *preview = metadata.preview.clone();
+        }
+        self
+    }
+
     #[allow(dead_code)]
     pub fn offer_directory(
         name: impl Into<PathBuf>,
@@ -56,7 +189,6 @@ impl PeerMessage {
         })
     }
 
-    #[allow(dead_code)]
     pub fn message_ack(msg: impl Into<String>) -> Self {
         PeerMessage::Answer(Answer::MessageAck(msg.into()))
     }
@@ -65,8 +197,16 @@ impl PeerMessage {
         PeerMessage::Answer(Answer::FileAck(msg.into()))
     }
 
-    pub fn error_message(msg: impl Into<String>) -> Self {
-        PeerMessage::Error(msg.into())
+    pub fn file_ack_resume(offset: u64, sha256: [u8; 32]) -> Self {
+        PeerMessage::Answer(Answer::FileAckResume { offset, sha256 })
+    }
+
+    pub fn error(error: impl Into<PeerError>) -> Self {
+        PeerMessage::Error(error.into())
+    }
+
+    pub fn cancel(reason: impl Into<String>) -> Self {
+        PeerMessage::Cancel(reason.into())
     }
 
     pub fn transit(abilities: TransitAbilities, hints: transit::Hints) -> Self {
@@ -76,7 +216,6 @@ impl PeerMessage {
         })
     }
 
-    #[allow(dead_code)]
     pub fn transit_v2(hints: transit::Hints) -> Self {
         PeerMessage::TransitV2(TransitV2 { hints })
     }
@@ -102,6 +241,149 @@ impl PeerMessage {
     }
 }
 
+impl serde::Serialize for PeerMessage {
+    fn serialize<S: serde::Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        match self {
+            PeerMessage::Unknown(value) => serde::Serialize::serialize(value, ser),
+            other => PeerMessage::serialize(other, ser),
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for PeerMessage {
+    fn deserialize<D: serde::Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+
+        let value = serde_json::Value::deserialize(de)?;
+        let mut value = match known_variant(value, PEER_MESSAGE_VARIANTS) {
+            Ok(value) => value,
+            Err(value) => return Ok(PeerMessage::Unknown(value)),
+        };
+        /* Unknown offers become `Offer::Unknown`, so that they can be rejected */
+        if let Some(offer) = value.get_mut("offer") {
+            *offer = match known_variant(offer.take(), OFFER_VARIANTS) {
+                Ok(offer) => offer,
+                Err(unknown) => {
+                    log::debug!("Got an unknown offer: {}", unknown);
+                    serde_json::json!({ "unknown": null })
+                },
+            };
+        }
+        PeerMessage::deserialize(value).map_err(D::Error::custom)
+    }
+}
+
+/**
+ * An error reported by the other side
+ *
+ * The message is meant for humans, the code for programmatically handling (or localizing) it.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, derive_more::Display)]
+#[display(fmt = "{}", message)]
+pub struct PeerError {
+    pub code: PeerErrorCode,
+    pub message: String,
+}
+
+impl PeerError {
+    pub fn new(code: PeerErrorCode, message: impl Into<String>) -> Self {
+        PeerError {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /*
+     * The code goes in front of the message, like `"[rejected] transfer rejected"`. Peers that don't
+     * know about codes (like the Python implementation) simply show it as part of the message, and
+     * messages without one get `PeerErrorCode::Unknown`.
+     */
+    fn serialize_message<S: serde::Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        match self.code {
+            PeerErrorCode::Unknown => ser.serialize_str(&self.message),
+            code => {
+                let code = serde_json::to_value(code).unwrap();
+                ser.serialize_str(&format!("[{}] {}", code.as_str().unwrap(), self.message))
+            },
+        }
+    }
+
+    fn deserialize_message<'de, D: serde::Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+        let message = <String as serde::Deserialize>::deserialize(de)?;
+        let prefixed = message
+            .strip_prefix('[')
+            .and_then(|rest| rest.split_once("] "))
+            .filter(|(code, _)| {
+                !code.is_empty() && code.chars().all(|c| c.is_ascii_lowercase() || c == '-')
+            });
+        Ok(match prefixed {
+            Some((code, message)) => PeerError::new(
+                serde_json::from_value(code.into()).unwrap_or(PeerErrorCode::Unknown),
+                message,
+            ),
+            None => PeerError::new(PeerErrorCode::Unknown, message),
+        })
+    }
+}
+
+impl From<&super::TransferError> for PeerError {
+    fn from(error: &super::TransferError) -> Self {
+        use super::TransferError;
+
+        let code = match error {
+            TransferError::AckError => PeerErrorCode::Protocol,
+            TransferError::Checksum | TransferError::UnexpectedChecksum { .. } => {
+                PeerErrorCode::Checksum
+            },
+            TransferError::FileSize { .. } => PeerErrorCode::FileSize,
+            TransferError::FilesystemSkew => PeerErrorCode::FilesystemSkew,
+            TransferError::UnsupportedOffer => PeerErrorCode::UnsupportedOffer,
+            TransferError::OfferExpired => PeerErrorCode::OfferExpired,
+            TransferError::ProtocolJson(_)
+            | TransferError::ProtocolMsgpack(_)
+            | TransferError::Protocol(_)
+            | TransferError::ProtocolUnexpectedMessage(..) => PeerErrorCode::Protocol,
+            TransferError::TransitConnect(_) => PeerErrorCode::TransitConnect,
+            TransferError::Transit(_) | TransferError::PeerDisconnected(_) => {
+                PeerErrorCode::Transit
+            },
+            TransferError::IO(_) => PeerErrorCode::IO,
+            _ => PeerErrorCode::Unknown,
+        };
+        PeerError::new(code, error.to_string())
+    }
+}
+
+/**
+ * What kind of error the other side had
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub enum PeerErrorCode {
+    /** The receiver did not want the offered file */
+    Rejected,
+    /** The sender gave up waiting for an answer to its offer */
+    OfferExpired,
+    UnsupportedOffer,
+    /** No transit connection could be established */
+    TransitConnect,
+    /** The transit connection broke down during the transfer */
+    Transit,
+    Checksum,
+    FileSize,
+    /** The files got modified while they were being sent */
+    FilesystemSkew,
+    /** Reading or writing the file failed */
+    #[serde(rename = "io")]
+    IO,
+    /** The other side did not follow the protocol */
+    Protocol,
+    /** Sent by old peers without error codes, or a code we don't know yet */
+    #[serde(other)]
+    Unknown,
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 #[serde(rename_all = "kebab-case")]
 pub enum Offer {
@@ -109,6 +391,26 @@ pub enum Offer {
     File {
         filename: PathBuf,
         filesize: u64,
+        /** Only for directories that were archived into this file */
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        numbytes: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        numfiles: Option<u64>,
+        /** How the content is compressed, if at all */
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        compression: Option<String>,
+        /** Seconds since the Unix epoch, see [`super::FileMetadata`] */
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mtime: Option<u64>,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        executable: bool,
+        /** Unknown to the Python implementation, which ignores these */
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mime_type: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mode: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        preview: Option<super::Preview>,
     },
     Directory {
         dirname: PathBuf,
@@ -124,17 +426,33 @@ pub enum Offer {
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 #[serde(rename_all = "kebab-case")]
 pub struct OfferV2 {
-    transfer_name: Option<String>,
-    files: Vec<OfferV2Entry>,
-    format: String, // TODO use custom enum?
+    pub transfer_name: Option<String>,
+    pub files: Vec<OfferV2Entry>,
+    pub format: String, // TODO use custom enum?
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preview: Option<super::Preview>,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+/**
+ * One file of a v2 offer (the "manifest")
+ *
+ * Each file comes with its own hash, so that the receiver can verify every file on its own,
+ * and skip files it already has.
+ */
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 #[serde(rename_all = "kebab-case")]
 pub struct OfferV2Entry {
-    path: String,
-    size: u64,
-    mtime: u64,
+    pub path: String,
+    pub size: u64,
+    pub mtime: u64,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub executable: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mime_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    #[serde(with = "hex::serde")]
+    pub sha256: [u8; 32],
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
@@ -142,12 +460,24 @@ pub struct OfferV2Entry {
 pub enum Answer {
     MessageAck(String),
     FileAck(String),
+    /**
+     * Accept the file, but only send it from `offset` on
+     *
+     * The receiver has the data before already, `sha256` is its hash. Only sent to peers
+     * with the `transfer-v1-resume` ability.
+     */
+    FileAckResume {
+        offset: u64,
+        #[serde(with = "hex::serde")]
+        sha256: [u8; 32],
+    },
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 #[serde(rename_all = "kebab-case")]
 pub struct AnswerV2 {
-    files: HashMap<u64, u64>,
+    /** The files the receiver wants: index into the offer's files to the offset to start from */
+    pub files: HashMap<u64, u64>,
 }
 
 /**
@@ -176,14 +506,188 @@ mod test {
 
     #[test]
     fn test_transit() {
-        let abilities = Abilities::ALL_ABILITIES;
+        /* Only there with the `quic` and `noise` features */
+        let abilities = Abilities {
+            direct_quic_v1: false,
+            noise_v1: false,
+            ..Abilities::ALL_ABILITIES
+        };
         let hints = transit::Hints::new(
             [DirectHint::new("192.168.1.8", 46295)],
             [RelayHint::from_url("tcp://magic-wormhole-transit.debian.net:4001".parse().unwrap())],
         );
         let t =
             serde_json::json!(crate::transfer::PeerMessage::transit(abilities, hints)).to_string();
-        assert_eq!(t, "{\"transit\":{\"abilities-v1\":[{\"type\":\"direct-tcp-v1\"},{\"type\":\"relay-v1\",\"url-hints\":true}],\"hints-v1\":[{\"hostname\":\"192.168.1.8\",\"port\":46295,\"type\":\"direct-tcp-v1\"},{\"hints\":[{\"hostname\":\"magic-wormhole-transit.debian.net\",\"port\":4001}],\"type\":\"relay-v1\",\"urls\":[\"tcp://magic-wormhole-transit.debian.net:4001\"]}]}}")
+        assert_eq!(t, "{\"transit\":{\"abilities-v1\":[{\"type\":\"direct-tcp-v1\"},{\"type\":\"relay-v1\",\"url-hints\":true},{\"preferred\":\"xsalsa20-poly1305\",\"type\":\"record-cipher-v1\"},{\"type\":\"websocket-v1\"}],\"hints-v1\":[{\"hostname\":\"192.168.1.8\",\"port\":46295,\"type\":\"direct-tcp-v1\"},{\"hints\":[{\"hostname\":\"magic-wormhole-transit.debian.net\",\"port\":4001}],\"type\":\"relay-v1\",\"urls\":[\"tcp://magic-wormhole-transit.debian.net:4001\"]}]}}")
+    }
+
+    #[test]
+    fn test_quic_hints() {
+        let message: TransitV1 = serde_json::from_value(serde_json::json!({
+            "abilities-v1": [{"type": "direct-tcp-v1"}, {"type": "direct-quic-v1"}],
+            "hints-v1": [{"type": "direct-quic-v1", "hostname": "192.168.1.8", "port": 46296}],
+        }))
+        .unwrap();
+        assert!(message.abilities_v1.direct_quic_v1);
+        assert!(message.hints_v1.direct_tcp.is_empty());
+        let hint = DirectHint::new("192.168.1.8", 46296);
+        assert!(message.hints_v1.direct_quic.contains(&hint));
+
+        let json = serde_json::json!(message.hints_v1);
+        assert_eq!(json[0]["type"], "direct-quic-v1");
+    }
+
+    #[test]
+    fn test_relay_priority() {
+        /* As sent by the Python client */
+        let hints: transit::Hints = serde_json::from_value(serde_json::json!([
+            {"type": "relay-v1", "hints": [
+                {"type": "direct-tcp-v1", "hostname": "relay.example", "port": 4001, "priority": 2.0}
+            ]},
+        ]))
+        .unwrap();
+        assert_eq!(hints.relay[0].priority, 2.0);
+
+        let json = serde_json::json!(hints);
+        assert_eq!(json[0]["hints"][0]["priority"], 2.0);
+        let hints: transit::Hints = serde_json::from_value(json).unwrap();
+        assert_eq!(hints.relay[0].priority, 2.0);
+    }
+
+    #[test]
+    fn test_relay_names() {
+        let hints: transit::Hints = serde_json::from_value(serde_json::json!([
+            {"type": "relay-v1", "name": "example", "hints": [], "urls": ["tcp://relay.example:4001"]},
+            {"type": "relay-v1", "name": "example", "hints": [], "urls": ["ws://relay.example:4002"]},
+            {"type": "relay-v1", "hints": [], "urls": ["tcp://other.example:4001"]},
+        ]))
+        .unwrap();
+        /* Same name, same relay */
+        assert_eq!(hints.relay.len(), 2);
+        assert_eq!(hints.relay[0].name.as_deref(), Some("example"));
+        assert_eq!(hints.relay[0].ws.len(), 1);
+        assert_eq!(hints.relay[1].name, None);
+
+        let json = serde_json::json!(hints);
+        assert_eq!(json[0]["name"], "example");
+        assert!(json[1].get("name").is_none());
+    }
+
+    #[test]
+    fn test_record_cipher() {
+        use transit::RecordCipher;
+
+        let old: Abilities = serde_json::from_value(serde_json::json!([
+            {"type": "direct-tcp-v1"},
+        ]))
+        .unwrap();
+        let fast: Abilities = serde_json::from_value(serde_json::json!([
+            {"type": "direct-tcp-v1"},
+            {"type": "record-cipher-v1", "preferred": "xchacha20-poly1305"},
+        ]))
+        .unwrap();
+        let unknown: Abilities = serde_json::from_value(serde_json::json!([
+            {"type": "record-cipher-v1", "preferred": "rot13"},
+        ]))
+        .unwrap();
+        let ours = Abilities::ALL_ABILITIES;
+
+        assert_eq!(ours.record_cipher(&old), RecordCipher::XSalsa20Poly1305);
+        assert_eq!(fast.record_cipher(&old), RecordCipher::XSalsa20Poly1305);
+        assert_eq!(ours.record_cipher(&fast), RecordCipher::XChaCha20Poly1305);
+        assert_eq!(fast.record_cipher(&ours), RecordCipher::XChaCha20Poly1305);
+        assert_eq!(ours.record_cipher(&unknown), RecordCipher::XSalsa20Poly1305);
+    }
+
+    #[test]
+    fn test_websocket_ability() {
+        let old: Abilities = serde_json::from_value(serde_json::json!([
+            {"type": "relay-v1", "url-hints": true},
+        ]))
+        .unwrap();
+        let new: Abilities = serde_json::from_value(serde_json::json!([
+            {"type": "relay-v1", "url-hints": true},
+            {"type": "websocket-v1"},
+        ]))
+        .unwrap();
+
+        assert!(!old.websocket_v1);
+        assert!(new.websocket_v1);
+        assert!(!Abilities::ALL_ABILITIES.intersect(&old).websocket_v1);
+        assert!(Abilities::ALL_ABILITIES.intersect(&new).websocket_v1);
+        assert!(!Abilities::FORCE_DIRECT.intersect(&new).websocket_v1);
+    }
+
+    #[test]
+    fn test_noise_ability() {
+        let theirs: Abilities = serde_json::from_value(serde_json::json!([
+            {"type": "relay-v1", "url-hints": true},
+            {"type": "noise-v1"},
+        ]))
+        .unwrap();
+        assert!(theirs.noise_v1);
+        let ours = Abilities {
+            noise_v1: true,
+            ..Abilities::FORCE_RELAY
+        };
+        assert!(ours.intersect(&theirs).noise_v1);
+        let old = Abilities {
+            noise_v1: false,
+            ..theirs
+        };
+        assert!(!ours.intersect(&old).noise_v1);
+        assert_eq!(
+            serde_json::json!(ours).as_array().unwrap().last(),
+            Some(&serde_json::json!({"type": "noise-v1"}))
+        );
+    }
+
+    #[test]
+    fn test_parallel_ability() {
+        let old: Abilities = serde_json::from_value(serde_json::json!([
+            {"type": "relay-v1", "url-hints": true},
+        ]))
+        .unwrap();
+        let new: Abilities = serde_json::from_value(serde_json::json!([
+            {"type": "relay-v1", "url-hints": true},
+            {"type": "parallel-v1", "connections": 8},
+        ]))
+        .unwrap();
+        let ours = Abilities {
+            direct_quic_v1: false,
+            parallel_v1: Some(transit::ParallelAbility { connections: 4 }),
+            ..Abilities::ALL_ABILITIES
+        };
+
+        assert_eq!(ours.parallel_connections(&old), 1);
+        assert_eq!(ours.parallel_connections(&new), 4);
+        assert_eq!(new.parallel_connections(&ours), 4);
+        assert_eq!(Abilities::ALL_ABILITIES.parallel_connections(&new), 1);
+        assert_eq!(
+            ours.intersect(&new)
+                .parallel_v1
+                .map(|ability| ability.connections),
+            Some(4)
+        );
+        assert!(ours.intersect(&old).parallel_v1.is_none());
+
+        let greedy: Abilities = serde_json::from_value(serde_json::json!([
+            {"type": "relay-v1", "url-hints": true},
+            {"type": "parallel-v1", "connections": 100000},
+        ]))
+        .unwrap();
+        assert_eq!(
+            greedy.parallel_v1.map(|ability| ability.connections),
+            Some(u16::MAX)
+        );
+        assert_eq!(
+            greedy.parallel_connections(&greedy),
+            transit::MAX_PARALLEL_CONNECTIONS
+        );
+        assert_eq!(
+            serde_json::json!(ours)[4],
+            serde_json::json!({"type": "parallel-v1", "connections": 4})
+        );
     }
 
     #[test]
@@ -202,6 +706,49 @@ mod test {
             serde_json::json!(f1).to_string(),
             "{\"offer\":{\"file\":{\"filename\":\"somefile.txt\",\"filesize\":34556}}}"
         );
+        let f2 = PeerMessage::offer_file("somefile.txt", 34556).compressed();
+        assert_eq!(
+            serde_json::json!(f2).to_string(),
+            "{\"offer\":{\"file\":{\"compression\":\"zstd\",\"filename\":\"somefile.txt\",\"filesize\":34556}}}"
+        );
+        let metadata = crate::transfer::FileMetadata {
+            mtime: Some(1600000000),
+            executable: true,
+            ..Default::default()
+        };
+        let f3 = PeerMessage::offer_file("somefile.sh", 10).with_metadata(&metadata);
+        assert_eq!(
+            serde_json::json!(f3).to_string(),
+            "{\"offer\":{\"file\":{\"executable\":true,\"filename\":\"somefile.sh\",\"filesize\":10,\"mtime\":1600000000}}}"
+        );
+        let metadata = crate::transfer::FileMetadata {
+            mime_type: Some("image/png".into()),
+            mode: Some(0o644),
+            preview: Some(crate::transfer::Preview::new("image/png", b"png".to_vec())),
+            ..Default::default()
+        };
+        let f4 = PeerMessage::offer_file("picture.png", 10).with_metadata(&metadata);
+        assert_eq!(
+            serde_json::json!(f4).to_string(),
+            "{\"offer\":{\"file\":{\"filename\":\"picture.png\",\"filesize\":10,\"mime_type\":\"image/png\",\"mode\":420,\"preview\":{\"data\":\"cG5n\",\"mime_type\":\"image/png\"}}}}"
+        );
+        assert!(matches!(
+            serde_json::from_value(serde_json::json!(f4)).unwrap(),
+            PeerMessage::Offer(Offer::File {
+                mode: Some(0o644),
+                preview: Some(crate::transfer::Preview { data, .. }),
+                ..
+            }) if data == b"png"
+        ));
+    }
+
+    #[test]
+    fn test_offer_tarball() {
+        let t1 = PeerMessage::offer_tarball("somedirectory.tar", 10240, 1234, 10);
+        assert_eq!(
+            serde_json::json!(t1).to_string(),
+            "{\"offer\":{\"file\":{\"filename\":\"somedirectory.tar\",\"filesize\":10240,\"numbytes\":1234,\"numfiles\":10}}}"
+        );
     }
 
     #[test]
@@ -213,6 +760,53 @@ mod test {
         );
     }
 
+    /** What the Python implementation sends for an empty file and an empty directory */
+    #[test]
+    fn test_offer_empty() {
+        let file =
+            serde_json::from_str(r#"{"offer": {"file": {"filename": "empty", "filesize": 0}}}"#)
+                .unwrap();
+        assert!(matches!(
+            file,
+            PeerMessage::Offer(Offer::File {
+                filesize: 0,
+                numbytes: None,
+                numfiles: None,
+                ..
+            })
+        ));
+
+        let directory = serde_json::from_str(
+            r#"{"offer": {"directory": {"mode": "zipped", "dirname": "empty", "zipsize": 22, "numbytes": 0, "numfiles": 0}}}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            directory,
+            PeerMessage::Offer(Offer::Directory {
+                zipsize: 22,
+                numbytes: 0,
+                numfiles: 0,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_offer_v2_entry() {
+        let entry = OfferV2Entry {
+            path: "dir/file.txt".into(),
+            size: 3,
+            mtime: 1600000000,
+            executable: false,
+            mime_type: Some("text/plain".into()),
+            mode: None,
+            sha256: [0xab; 32],
+        };
+        let json = serde_json::json!(entry);
+        assert_eq!(json["sha256"], hex::encode([0xab; 32]));
+        assert_eq!(serde_json::from_value::<OfferV2Entry>(json).unwrap(), entry);
+    }
+
     #[test]
     fn test_message_ack() {
         let m1 = PeerMessage::message_ack("ok");
@@ -229,5 +823,98 @@ mod test {
             serde_json::json!(f1).to_string(),
             "{\"answer\":{\"file_ack\":\"ok\"}}"
         );
+
+        let f2 = PeerMessage::file_ack_resume(4096, [0xab; 32]);
+        assert_eq!(
+            serde_json::json!(f2),
+            serde_json::json!({
+                "answer": {
+                    "file_ack_resume": {
+                        "offset": 4096,
+                        "sha256": "ab".repeat(32),
+                    }
+                }
+            })
+        );
+        assert!(matches!(
+            serde_json::from_value(serde_json::json!(f2)).unwrap(),
+            PeerMessage::Answer(Answer::FileAckResume { offset: 4096, sha256 }) if sha256 == [0xab; 32]
+        ));
+    }
+
+    #[test]
+    fn test_no_more_offers() {
+        let f1 = PeerMessage::NoMoreOffers {};
+        assert_eq!(serde_json::json!(f1).to_string(), "{\"no-more-offers\":{}}");
+        assert!(matches!(
+            serde_json::from_str("{\"no-more-offers\":{}}").unwrap(),
+            PeerMessage::NoMoreOffers {}
+        ));
+    }
+
+    #[test]
+    fn test_cancel() {
+        let f1 = PeerMessage::cancel("user request");
+        assert_eq!(
+            serde_json::json!(f1).to_string(),
+            "{\"cancel\":\"user request\"}"
+        );
+        assert!(matches!(
+            serde_json::from_str("{\"cancel\":\"user request\"}").unwrap(),
+            PeerMessage::Cancel(reason) if reason == "user request"
+        ));
+    }
+
+    #[test]
+    fn test_error() {
+        let f1 = PeerMessage::error(PeerError::new(PeerErrorCode::Rejected, "transfer rejected"));
+        assert_eq!(
+            serde_json::json!(f1).to_string(),
+            "{\"error\":\"[rejected] transfer rejected\"}"
+        );
+        let f2: PeerMessage = serde_json::from_value(serde_json::json!(f1)).unwrap();
+        assert!(matches!(f2, PeerMessage::Error(error)
+            if error == PeerError::new(PeerErrorCode::Rejected, "transfer rejected")));
+        let f3 = PeerMessage::error(PeerError::new(PeerErrorCode::Unknown, "[oops]"));
+        assert_eq!(serde_json::json!(f3).to_string(), "{\"error\":\"[oops]\"}");
+
+        /* Old peers don't send a code, and new peers may send codes we don't know yet */
+        for message in &[
+            "{\"error\":\"oops\"}",
+            "{\"error\":\"[from-the-future] oops\"}",
+        ] {
+            assert_eq!(
+                match serde_json::from_str(message).unwrap() {
+                    PeerMessage::Error(error) => error,
+                    other => panic!("Expected error message, got {:?}", other),
+                },
+                PeerError::new(PeerErrorCode::Unknown, "oops")
+            );
+        }
+
+        /* Other messages are not affected */
+        assert!(matches!(
+            serde_json::from_str("{\"answer\":{\"file_ack\":\"ok\"}}").unwrap(),
+            PeerMessage::Answer(Answer::FileAck(_))
+        ));
+    }
+
+    #[test]
+    fn test_unknown() {
+        let message = serde_json::json!({"from-the-future": {"answer": 42}});
+        let f1: PeerMessage = serde_json::from_value(message.clone()).unwrap();
+        assert!(matches!(&f1, PeerMessage::Unknown(value) if *value == message));
+        assert_eq!(serde_json::json!(f1), message);
+
+        /* Additional fields next to known messages are ignored */
+        assert!(matches!(
+            serde_json::from_str("{\"cancel\":\"user request\",\"reason-code\":1}").unwrap(),
+            PeerMessage::Cancel(reason) if reason == "user request"
+        ));
+
+        assert!(matches!(
+            serde_json::from_str("{\"offer\":{\"hologram\":{\"size\":42}}}").unwrap(),
+            PeerMessage::Offer(Offer::Unknown)
+        ));
     }
 }
@@ -0,0 +1,244 @@
+//! Keeping the modification time and the executable bit of sent files, and describing their content
+//!
+//! The sender includes these in its offer, see [`ReceiveRequest::metadata`](super::ReceiveRequest::metadata).
+//! Received files get fresh timestamps and the default permissions unless
+//! [`ReceiveOptions::preserve_metadata`](super::ReceiveOptions::preserve_metadata) is set. Folders
+//! are sent as tar files, which carry the metadata of their files anyway.
+//!
+//! The MIME type and an optional [`Preview`] let receiving applications show an icon or a
+//! thumbnail before the user accepts the offer. Peers that don't know about them ignore them.
+
+use serde_derive::{Deserialize, Serialize};
+
+/** The largest [`Preview`] that receivers accept, to keep the offer a small message */
+pub const MAX_PREVIEW_SIZE: usize = 64 * 1024;
+
+/**
+ * What the receiver may want to know about a file apart from its content
+ *
+ * **Security warning:** on the receiving side, this is untrusted and unverified input
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FileMetadata {
+    /** The time of the last modification, in seconds since the Unix epoch */
+    pub mtime: Option<u64>,
+    /** Whether the file is executable. Only Unix has this, elsewhere it is always `false` */
+    pub executable: bool,
+    /** The type of the content, like `text/plain`, as [guessed](guess_mime_type) by the sender */
+    pub mime_type: Option<String>,
+    /**
+     * The Unix permission bits, like `0o644`
+     *
+     * This is for information only, received files only ever get the [executable](Self::executable) bit.
+     */
+    pub mode: Option<u32>,
+    /** A small preview of the content, if the sender made one */
+    pub preview: Option<Preview>,
+}
+
+/**
+ * A small preview of an offered file, like a thumbnail of an image
+ *
+ * It does not get generated automatically, the sending application provides it with
+ * [`SendOptions::preview`](super::SendOptions::preview). It travels in the offer over the rendezvous
+ * server, so keep it small: sending a preview larger than [`MAX_PREVIEW_SIZE`] fails, and receivers
+ * drop such previews from other senders.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Preview {
+    /** The type of the preview, like `image/png` */
+    pub mime_type: String,
+    #[serde(with = "base64_data")]
+    pub data: Vec<u8>,
+}
+
+impl Preview {
+    pub fn new(mime_type: impl Into<String>, data: Vec<u8>) -> Self {
+        Self {
+            mime_type: mime_type.into(),
+            data,
+        }
+    }
+}
+
+/** Drop a received preview that is too large */
+pub(super) fn accept_preview(preview: Option<Preview>) -> Option<Preview> {
+    match preview {
+        Some(preview) if preview.data.len() > MAX_PREVIEW_SIZE => {
+            log::warn!(
+                "Ignoring the preview of {} bytes, it may not be larger than {} bytes",
+                preview.data.len(),
+                MAX_PREVIEW_SIZE
+            );
+            None
+        },
+        preview => preview,
+    }
+}
+
+/* Binary data in JSON, as base64 */
+mod base64_data {
+    pub fn serialize<S: serde::Serializer>(data: &[u8], ser: S) -> Result<S::Ok, S::Error> {
+        ser.serialize_str(&base64::encode(data))
+    }
+
+    pub fn deserialize<'de, D: serde::Deserializer<'de>>(de: D) -> Result<Vec<u8>, D::Error> {
+        let encoded: String = serde::Deserialize::deserialize(de)?;
+        base64::decode(encoded).map_err(serde::de::Error::custom)
+    }
+}
+
+/** File extensions (lower case) and the MIME types of their content */
+const MIME_TYPES: &[(&str, &str)] = &[
+    ("txt", "text/plain"),
+    ("md", "text/markdown"),
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("css", "text/css"),
+    ("csv", "text/csv"),
+    ("json", "application/json"),
+    ("xml", "application/xml"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tar", "application/x-tar"),
+    ("xz", "application/x-xz"),
+    ("zst", "application/zstd"),
+    ("7z", "application/x-7z-compressed"),
+    ("odt", "application/vnd.oasis.opendocument.text"),
+    ("ods", "application/vnd.oasis.opendocument.spreadsheet"),
+    (
+        "docx",
+        "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+    ),
+    (
+        "xlsx",
+        "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+    ),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("png", "image/png"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("svg", "image/svg+xml"),
+    ("heic", "image/heic"),
+    ("avif", "image/avif"),
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("opus", "audio/opus"),
+    ("flac", "audio/flac"),
+    ("wav", "audio/wav"),
+    ("mp4", "video/mp4"),
+    ("mkv", "video/x-matroska"),
+    ("webm", "video/webm"),
+    ("mov", "video/quicktime"),
+];
+
+/** The MIME type of a file, going by the extension of its name */
+pub fn guess_mime_type(file_name: impl AsRef<std::path::Path>) -> Option<&'static str> {
+    let extension = file_name.as_ref().extension()?.to_str()?.to_lowercase();
+    MIME_TYPES
+        .iter()
+        .find(|(known, _)| *known == extension)
+        .map(|(_, mime_type)| *mime_type)
+}
+
+impl FileMetadata {
+    pub fn from_fs(metadata: &std::fs::Metadata) -> Self {
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|mtime| mtime.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|mtime| mtime.as_secs());
+        #[cfg(unix)]
+        let executable = {
+            use std::os::unix::fs::PermissionsExt;
+            metadata.permissions().mode() & 0o111 != 0
+        };
+        #[cfg(not(unix))]
+        let executable = false;
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            Some(metadata.permissions().mode() & 0o7777)
+        };
+        #[cfg(not(unix))]
+        let mode = None;
+        FileMetadata {
+            mtime,
+            executable,
+            mode,
+            ..Default::default()
+        }
+    }
+
+    /**
+     * Set the modification time and executable bit of the file at `path`
+     *
+     * Only the executable bit gets added, all other permissions stay as they are (and are thus
+     * up to the umask). Like the user's own `chmod +x`, it applies to whoever may read the file.
+     */
+    pub(super) async fn apply(&self, path: &std::path::Path) -> std::io::Result<()> {
+        if let Some(mtime) = self.mtime {
+            filetime::set_file_mtime(path, filetime::FileTime::from_unix_time(mtime as i64, 0))?;
+        }
+        #[cfg(unix)]
+        if self.executable {
+            use std::os::unix::fs::PermissionsExt;
+            let mut permissions = async_std::fs::metadata(path).await?.permissions();
+            let mode = permissions.mode();
+            /* Copy the read bits over to the execute bits */
+            permissions.set_mode(mode | (mode & 0o444) >> 2);
+            async_std::fs::set_permissions(path, permissions).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[async_std::test]
+    async fn test_apply() {
+        let path =
+            std::env::temp_dir().join(format!("wormhole-test-metadata-{}", std::process::id()));
+        std::fs::write(&path, b"#!/bin/sh\n").unwrap();
+
+        let metadata = FileMetadata {
+            mtime: Some(1_600_000_000),
+            executable: cfg!(unix),
+            ..Default::default()
+        };
+        metadata.apply(&path).await.unwrap();
+        let applied = FileMetadata::from_fs(&std::fs::metadata(&path).unwrap());
+        assert_eq!(applied.mtime, metadata.mtime);
+        assert_eq!(applied.executable, metadata.executable);
+        assert_eq!(applied.mode.is_some(), cfg!(unix));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_guess_mime_type() {
+        assert_eq!(guess_mime_type("notes.txt"), Some("text/plain"));
+        assert_eq!(guess_mime_type("dir/Photo.JPG"), Some("image/jpeg"));
+        assert_eq!(guess_mime_type("Makefile"), None);
+        assert_eq!(guess_mime_type("archive.unknown"), None);
+    }
+
+    #[test]
+    fn test_preview() {
+        let preview = Preview::new("image/png", vec![0x89, b'P', b'N', b'G']);
+        let json = serde_json::json!(preview);
+        assert_eq!(
+            json,
+            serde_json::json!({"mime_type": "image/png", "data": "iVBORw=="})
+        );
+        assert_eq!(serde_json::from_value::<Preview>(json).unwrap(), preview);
+
+        assert_eq!(accept_preview(Some(preview.clone())), Some(preview));
+        let large = Preview::new("image/png", vec![0; MAX_PREVIEW_SIZE + 1]);
+        assert_eq!(accept_preview(Some(large)), None);
+    }
+}
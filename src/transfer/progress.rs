@@ -0,0 +1,357 @@
+//! Progress reporting without callbacks
+//!
+//! All transfer functions take a `progress_handler` closure, which must be `'static`. This
+//! is awkward if the progress is to be shown by a GUI that borrows its state. Instead, create
+//! a [`ProgressState`], pass its [`handler`](ProgressState::handler) to the transfer and read
+//! the current values whenever you want to redraw.
+//!
+//! Applications that are not async at all can run the transfer on another thread and loop
+//! over [`events_iter`] instead. Async ones can do the same with the stream of [`events_stream`],
+//! for example to await sending the updates to a UI, without slowing down the transfer.
+//!
+//! To show the throughput and the remaining time, wrap the handler with [`with_rates`].
+
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+/**
+ * A shared snapshot of the progress of a transfer
+ *
+ * This is cheap to clone, all clones refer to the same counters.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct ProgressState(Arc<Counters>);
+
+#[derive(Debug, Default)]
+struct Counters {
+    done: AtomicU64,
+    total: AtomicU64,
+}
+
+impl ProgressState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /** A progress handler to pass to the transfer functions, which updates this state */
+    pub fn handler(&self) -> impl FnMut(u64, u64) + Send + 'static {
+        let counters = self.0.clone();
+        move |done, total| {
+            counters.total.store(total, Ordering::Relaxed);
+            counters.done.store(done, Ordering::Relaxed);
+        }
+    }
+
+    /** How many bytes have been sent or received so far */
+    pub fn done(&self) -> u64 {
+        self.0.done.load(Ordering::Relaxed)
+    }
+
+    /** How many bytes there are in total, or zero if the transfer did not start yet */
+    pub fn total(&self) -> u64 {
+        self.0.total.load(Ordering::Relaxed)
+    }
+
+    /** The progress as a fraction between 0 and 1, if the transfer started */
+    pub fn fraction(&self) -> Option<f64> {
+        match (self.done(), self.total()) {
+            (_, 0) => None,
+            (done, total) => Some(done as f64 / total as f64),
+        }
+    }
+}
+
+/** One progress update, as yielded by [`ProgressEvents`] and [`ProgressStream`] */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Progress {
+    pub done: u64,
+    pub total: u64,
+}
+
+/**
+ * Create a progress handler, together with a blocking iterator over its updates
+ *
+ * Pass the handler to the transfer function. The iterator yields the updates until the
+ * handler is dropped, i.e. once the transfer function returned. If the loop is slower
+ * than the transfer, intermediate updates are skipped: only the latest one is yielded.
+ */
+pub fn events_iter() -> (impl FnMut(u64, u64) + Send + 'static, ProgressEvents) {
+    let shared = Arc::new(Shared::default());
+    let sender = Sender(shared.clone());
+    let handler = move |done, total| sender.send(Progress { done, total });
+    (handler, ProgressEvents(shared))
+}
+
+/**
+ * Like [`events_iter`], but for async code: the updates come as [`Stream`](futures::Stream)
+ *
+ * The transfer never waits for the stream. While the stream's consumer is busy, for example
+ * awaiting a full channel, intermediate updates are skipped: only the latest one is yielded.
+ */
+pub fn events_stream() -> (impl FnMut(u64, u64) + Send + 'static, ProgressStream) {
+    let shared = Arc::new(Shared::default());
+    let sender = Sender(shared.clone());
+    let handler = move |done, total| sender.send(Progress { done, total });
+    (handler, ProgressStream(shared))
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    /** The latest update that was not yielded yet, and whether the handler is gone */
+    state: Mutex<(Option<Progress>, bool)>,
+    changed: Condvar,
+    /** Of the stream waiting for the next update. Only set while holding the `state` lock */
+    waker: Mutex<Option<Waker>>,
+}
+
+impl Shared {
+    fn notify(&self) {
+        self.changed.notify_one();
+        if let Some(waker) = self.waker.lock().unwrap().take() {
+            waker.wake();
+        }
+    }
+}
+
+/** Notifies the iterator or stream when the handler gets dropped */
+struct Sender(Arc<Shared>);
+
+impl Sender {
+    fn send(&self, progress: Progress) {
+        self.0.state.lock().unwrap().0 = Some(progress);
+        self.0.notify();
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().1 = true;
+        self.0.notify();
+    }
+}
+
+/**
+ * A blocking iterator over progress updates, see [`events_iter`]
+ */
+#[derive(Debug)]
+pub struct ProgressEvents(Arc<Shared>);
+
+impl Iterator for ProgressEvents {
+    type Item = Progress;
+
+    fn next(&mut self) -> Option<Progress> {
+        let mut state = self.0.state.lock().unwrap();
+        loop {
+            match *state {
+                (Some(progress), _) => {
+                    state.0 = None;
+                    return Some(progress);
+                },
+                (None, true) => return None,
+                (None, false) => state = self.0.changed.wait(state).unwrap(),
+            }
+        }
+    }
+}
+
+/**
+ * A stream of progress updates, see [`events_stream`]
+ */
+#[derive(Debug)]
+pub struct ProgressStream(Arc<Shared>);
+
+impl futures::Stream for ProgressStream {
+    type Item = Progress;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Progress>> {
+        let mut state = self.0.state.lock().unwrap();
+        match *state {
+            (Some(progress), _) => {
+                state.0 = None;
+                Poll::Ready(Some(progress))
+            },
+            (None, true) => Poll::Ready(None),
+            (None, false) => {
+                *self.0.waker.lock().unwrap() = Some(cx.waker().clone());
+                Poll::Pending
+            },
+        }
+    }
+}
+
+/** A progress update together with the numbers derived from it, see [`with_rates`] */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TransferProgress {
+    /** How many bytes have been sent or received so far */
+    pub done: u64,
+    /** How many bytes there are in total */
+    pub total: u64,
+    /** The time since the transfer started */
+    pub elapsed: Duration,
+    /** The current speed in bytes per second, averaged over the last few seconds */
+    pub throughput: f64,
+    /** How long the rest of the transfer will take at the current speed, if it is moving at all */
+    pub remaining: Option<Duration>,
+}
+
+/**
+ * Create a progress handler that calls `handler` with the throughput and the estimated remaining time
+ *
+ * Pass the result to the transfer functions instead of a plain handler. The transfer starts
+ * with the first update; if the progress goes back (e.g. because the next file of
+ * [`send_multiple`](super::send_multiple) is being sent), the measurement starts anew.
+ */
+pub fn with_rates(
+    mut handler: impl FnMut(TransferProgress) + 'static,
+) -> impl FnMut(u64, u64) + 'static {
+    let mut meter = RateMeter::default();
+    move |done, total| handler(meter.update(Instant::now(), done, total))
+}
+
+/** How far back updates are considered for the current speed */
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Default)]
+struct RateMeter {
+    /** The updates within the window, oldest first. The first one is when the transfer started */
+    samples: VecDeque<(Instant, u64)>,
+    started: Option<Instant>,
+}
+
+impl RateMeter {
+    fn update(&mut self, now: Instant, done: u64, total: u64) -> TransferProgress {
+        let restarted = matches!(self.samples.back(), Some(&(_, last)) if done < last);
+        if restarted || self.started.is_none() {
+            self.samples.clear();
+            self.started = Some(now);
+        }
+        self.samples.push_back((now, done));
+        /* Keep one sample that is older than the window, so that it is covered completely */
+        while self.samples.len() > 2 && now.duration_since(self.samples[1].0) >= THROUGHPUT_WINDOW {
+            self.samples.pop_front();
+        }
+
+        let (since, done_since) = self.samples[0];
+        let seconds = now.duration_since(since).as_secs_f64();
+        let throughput = if seconds > 0.0 {
+            (done - done_since) as f64 / seconds
+        } else {
+            0.0
+        };
+        let remaining = if done >= total {
+            Some(Duration::from_secs(0))
+        } else if throughput > 0.0 {
+            Some(Duration::from_secs_f64((total - done) as f64 / throughput))
+        } else {
+            None
+        };
+
+        TransferProgress {
+            done,
+            total,
+            elapsed: now.duration_since(self.started.unwrap_or(now)),
+            throughput,
+            remaining,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_progress_state() {
+        let progress = ProgressState::new();
+        assert_eq!(progress.fraction(), None);
+
+        let mut handler = progress.handler();
+        handler(0, 200);
+        assert_eq!(progress.fraction(), Some(0.0));
+        handler(50, 200);
+        assert_eq!((progress.done(), progress.total()), (50, 200));
+        assert_eq!(progress.fraction(), Some(0.25));
+    }
+
+    #[test]
+    fn test_rate_meter() {
+        let start = Instant::now();
+        let at = |seconds| start + Duration::from_secs(seconds);
+        let mut meter = RateMeter::default();
+
+        let progress = meter.update(at(0), 0, 1000);
+        assert_eq!(progress.throughput, 0.0);
+        assert_eq!(progress.remaining, None);
+
+        meter.update(at(1), 100, 1000);
+        let progress = meter.update(at(2), 200, 1000);
+        assert_eq!(progress.elapsed, Duration::from_secs(2));
+        assert_eq!(progress.throughput, 100.0);
+        assert_eq!(progress.remaining, Some(Duration::from_secs(8)));
+
+        /* Only the last few seconds count */
+        meter.update(at(3), 300, 1000);
+        meter.update(at(4), 600, 1000);
+        let progress = meter.update(at(5), 800, 1000);
+        assert_eq!(progress.elapsed, Duration::from_secs(5));
+        assert_eq!(progress.throughput, 200.0);
+        assert_eq!(progress.remaining, Some(Duration::from_secs(1)));
+
+        /* The next file */
+        let progress = meter.update(at(6), 0, 500);
+        assert_eq!(progress.elapsed, Duration::from_secs(0));
+        assert_eq!(progress.remaining, None);
+    }
+
+    #[test]
+    fn test_events_iter() {
+        let (mut handler, events) = events_iter();
+        let transfer = std::thread::spawn(move || {
+            handler(0, 200);
+            handler(200, 200);
+        });
+        let events = events.collect::<Vec<_>>();
+        transfer.join().unwrap();
+
+        /* Intermediate updates may be skipped, but never the last one */
+        assert_eq!(
+            events.last(),
+            Some(&Progress {
+                done: 200,
+                total: 200
+            })
+        );
+    }
+
+    #[async_std::test]
+    async fn test_events_stream() {
+        use futures::StreamExt;
+
+        let (mut handler, events) = events_stream();
+        /* The stream has to wait in between */
+        let transfer = async_std::task::spawn(async move {
+            handler(0, 200);
+            async_std::task::sleep(Duration::from_millis(10)).await;
+            handler(100, 200);
+            handler(200, 200);
+        });
+        let events = events.collect::<Vec<_>>().await;
+        transfer.await;
+
+        assert_eq!(
+            events.last(),
+            Some(&Progress {
+                done: 200,
+                total: 200
+            })
+        );
+    }
+}
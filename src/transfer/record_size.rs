@@ -0,0 +1,139 @@
+//! Adapting the size of the records a file is sent in
+//!
+//! Every record has a fixed overhead: a length prefix, a nonce, an authentication tag and a
+//! round through the cipher. Small records keep the latency low on slow links, large ones get
+//! more throughput out of fast links. Since nobody knows in advance which kind of link a transfer
+//! runs over, the sender starts small and climbs towards the size that gives the best throughput.
+//!
+//! Receivers accept records of any size up to the upper bound of 64 KiB, so this needs no
+//! negotiation. The Python implementation sends records of 16 KiB and accepts larger ones as
+//! well. Senders that know their link better can pick a fixed size instead, see
+//! [`SendOptions::record_size`](super::SendOptions::record_size).
+
+use std::time::Duration;
+
+/** The record size to start with, and the smallest one ever used */
+pub const MIN_RECORD_SIZE: usize = 4 * 1024;
+/** The largest record size, four times the size the Python implementation sends */
+pub const MAX_RECORD_SIZE: usize = 64 * 1024;
+
+/** Measure at least this long before changing the record size, so that single hiccups don't count */
+const MIN_WINDOW: Duration = Duration::from_millis(100);
+/** How much the throughput may drop before we consider a change to be for the worse */
+const TOLERANCE: f64 = 0.95;
+
+/**
+ * Picks the size of the next record, based on how fast the previous ones went out
+ *
+ * The size keeps doubling until the throughput gets worse, then it goes back by one step
+ * and stays there. Notable changes of the throughput later on make it shrink again (if the
+ * throughput went down) or grow again (if it went up).
+ */
+#[derive(Debug)]
+pub struct RecordSizer {
+    size: usize,
+    growing: bool,
+    fixed: bool,
+    /** Measure over a few round trips, for the congestion control to settle */
+    window: Duration,
+    /** Bytes per second in the previous measurement */
+    previous: Option<f64>,
+    bytes: u64,
+    elapsed: Duration,
+}
+
+impl RecordSizer {
+    /** `rtt` is the round-trip time to the peer, if known */
+    pub fn new(rtt: Option<Duration>) -> Self {
+        Self {
+            size: MIN_RECORD_SIZE,
+            growing: true,
+            fixed: false,
+            window: std::cmp::max(MIN_WINDOW, rtt.unwrap_or_default() * 4),
+            previous: None,
+            bytes: 0,
+            elapsed: Duration::default(),
+        }
+    }
+
+    /** Always use records of `size` bytes */
+    pub fn fixed(size: usize) -> Self {
+        Self {
+            size,
+            fixed: true,
+            ..Self::new(None)
+        }
+    }
+
+    pub fn record_size(&self) -> usize {
+        self.size
+    }
+
+    /** Record that sending `bytes` bytes took `elapsed` */
+    pub fn update(&mut self, bytes: usize, elapsed: Duration) {
+        if self.fixed {
+            return;
+        }
+        self.bytes += bytes as u64;
+        self.elapsed += elapsed;
+        if self.elapsed < self.window {
+            return;
+        }
+
+        let throughput = self.bytes as f64 / self.elapsed.as_secs_f64();
+        let previous = self.previous.unwrap_or(0.0);
+        if throughput < previous * TOLERANCE {
+            self.growing = false;
+            self.size = std::cmp::max(self.size / 2, MIN_RECORD_SIZE);
+        } else if self.growing || throughput * TOLERANCE > previous {
+            self.size = std::cmp::min(self.size * 2, MAX_RECORD_SIZE);
+            self.growing = self.size < MAX_RECORD_SIZE;
+        }
+        self.previous = Some(throughput);
+        self.bytes = 0;
+        self.elapsed = Duration::default();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_record_sizer() {
+        let mut sizer = RecordSizer::new(None);
+        assert_eq!(sizer.record_size(), MIN_RECORD_SIZE);
+
+        /* Not enough data for a decision yet */
+        sizer.update(MIN_RECORD_SIZE, Duration::from_millis(10));
+        assert_eq!(sizer.record_size(), MIN_RECORD_SIZE);
+
+        /* Grows while the throughput goes up, up to the maximum */
+        let mut throughput = 1_000_000;
+        while sizer.record_size() < MAX_RECORD_SIZE {
+            throughput *= 2;
+            sizer.update(throughput / 10, MIN_WINDOW);
+        }
+        sizer.update(throughput / 10, MIN_WINDOW);
+        assert_eq!(sizer.record_size(), MAX_RECORD_SIZE);
+
+        /* Shrinks once it gets worse, and grows again once it gets better */
+        sizer.update(throughput / 20, MIN_WINDOW);
+        assert_eq!(sizer.record_size(), MAX_RECORD_SIZE / 2);
+        sizer.update(throughput / 20, MIN_WINDOW);
+        assert_eq!(sizer.record_size(), MAX_RECORD_SIZE / 2);
+        sizer.update(throughput / 10, MIN_WINDOW);
+        assert_eq!(sizer.record_size(), MAX_RECORD_SIZE);
+
+        /* A long round-trip time means a longer measurement */
+        let mut sizer = RecordSizer::new(Some(Duration::from_millis(200)));
+        sizer.update(MIN_RECORD_SIZE, MIN_WINDOW);
+        assert_eq!(sizer.record_size(), MIN_RECORD_SIZE);
+
+        /* Unless the size is fixed */
+        let mut sizer = RecordSizer::fixed(1024 * 1024);
+        sizer.update(1_000_000, MIN_WINDOW);
+        sizer.update(1_000, MIN_WINDOW);
+        assert_eq!(sizer.record_size(), 1024 * 1024);
+    }
+}
@@ -0,0 +1,195 @@
+//! Continuing partially received files
+//!
+//! When a transfer gets interrupted, the receiver may keep what it has so far, together with
+//! a [`PartialTransfer`] state next to it. If the same file gets offered again later, the
+//! receiver hashes the data it already has and asks the sender to continue from there. The
+//! sender only does so if the hash matches the beginning of its file, otherwise the transfer fails.
+
+use async_std::path::{Path, PathBuf};
+use futures::AsyncReadExt;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{digest::FixedOutput, Digest, Sha256};
+
+/**
+ * What is known about a partially received file
+ *
+ * When a transfer gets interrupted and the partial file is kept (see
+ * [`PartialFiles`](super::PartialFiles)), this is stored next to it, so that a later
+ * transfer of the same file can pick up from there.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PartialTransfer {
+    /** The name the file was offered under. Like the offer itself, this is untrusted input */
+    pub file_name: std::path::PathBuf,
+    /** The size of the complete file */
+    pub file_size: u64,
+    /** How many bytes of it are in the partial file */
+    pub received: u64,
+}
+
+impl PartialTransfer {
+    /** Where the state of the partial file at `part_path` is stored: with `.json` appended */
+    pub fn state_path(part_path: impl AsRef<Path>) -> PathBuf {
+        let mut path = part_path.as_ref().as_os_str().to_owned();
+        path.push(".json");
+        path.into()
+    }
+
+    /** Store the state next to the partial file at `part_path` */
+    pub async fn save(&self, part_path: impl AsRef<Path>) -> std::io::Result<()> {
+        let json = serde_json::to_vec(self)?;
+        async_std::fs::write(Self::state_path(part_path), json).await
+    }
+
+    /** Load the state of the partial file at `part_path`, if there is one */
+    pub async fn load(part_path: impl AsRef<Path>) -> std::io::Result<Option<Self>> {
+        match async_std::fs::read(Self::state_path(part_path)).await {
+            Ok(json) => Ok(Some(serde_json::from_slice(&json)?)),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    /**
+     * Check whether the partial file at `part_path` can be continued for an offer
+     *
+     * This is the case if it was kept from an offer with the same name and size, and still
+     * has all the data that was received back then. That data gets hashed right away, so
+     * that the checksum of the continued transfer covers the whole file.
+     */
+    pub(super) async fn resume(
+        part_path: impl AsRef<Path>,
+        file_name: &std::path::Path,
+        file_size: u64,
+    ) -> std::io::Result<Option<Resumption>> {
+        let part_path = part_path.as_ref();
+        let state = match Self::load(part_path).await? {
+            Some(state) => state,
+            None => return Ok(None),
+        };
+        if state.file_name != file_name
+            || state.file_size != file_size
+            || state.received == 0
+            || state.received >= file_size
+        {
+            log::debug!(
+                "Not resuming {}, it is from a different offer",
+                part_path.display()
+            );
+            return Ok(None);
+        }
+        let file = async_std::fs::File::open(part_path).await?;
+        if file.metadata().await?.len() < state.received {
+            log::warn!(
+                "{} is shorter than it should be, not resuming",
+                part_path.display()
+            );
+            return Ok(None);
+        }
+
+        let mut hasher = Sha256::default();
+        let mut prefix = file.take(state.received);
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop {
+            let read = prefix.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+        }
+        Ok(Some(Resumption {
+            offset: state.received,
+            hasher,
+            part_path: part_path.to_owned(),
+        }))
+    }
+
+    /** Forget about the partial file at `part_path`, it is either complete or gone */
+    pub async fn remove(part_path: impl AsRef<Path>) -> std::io::Result<()> {
+        match async_std::fs::remove_file(Self::state_path(part_path)).await {
+            Err(error) if error.kind() != std::io::ErrorKind::NotFound => Err(error),
+            _ => Ok(()),
+        }
+    }
+}
+
+/**
+ * Where to continue a partially received file
+ *
+ * The hasher has seen all the data before `offset` already.
+ */
+pub(super) struct Resumption {
+    pub offset: u64,
+    pub hasher: Sha256,
+    pub part_path: PathBuf,
+}
+
+impl Resumption {
+    /** The hash of the data the receiver has already, for the sender to compare */
+    pub fn prefix_sha256(&self) -> [u8; 32] {
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(&self.hasher.clone().finalize_fixed());
+        hash
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn data() -> Vec<u8> {
+        (0..1000u32).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    #[async_std::test]
+    async fn test_partial_transfer() {
+        let dir = std::env::temp_dir().join(format!("wormhole-partial-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let part_path = dir.join("file.txt.part");
+
+        assert_eq!(PartialTransfer::load(&part_path).await.unwrap(), None);
+        let state = PartialTransfer {
+            file_name: "file.txt".into(),
+            file_size: 1000,
+            received: 450,
+        };
+        state.save(&part_path).await.unwrap();
+        assert!(dir.join("file.txt.part.json").exists());
+        assert_eq!(
+            PartialTransfer::load(&part_path).await.unwrap(),
+            Some(state)
+        );
+
+        /* The partial file itself is missing */
+        assert!(
+            PartialTransfer::resume(&part_path, "file.txt".as_ref(), 1000)
+                .await
+                .is_err()
+        );
+        std::fs::write(&part_path, &data()[..500]).unwrap();
+        let resumption = PartialTransfer::resume(&part_path, "file.txt".as_ref(), 1000)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(resumption.offset, 450);
+        assert_eq!(
+            resumption.prefix_sha256()[..],
+            Sha256::digest(&data()[..450])[..]
+        );
+        /* A different offer */
+        for (name, size) in &[("other.txt", 1000), ("file.txt", 2000)] {
+            assert!(PartialTransfer::resume(&part_path, name.as_ref(), *size)
+                .await
+                .unwrap()
+                .is_none());
+        }
+
+        PartialTransfer::remove(&part_path).await.unwrap();
+        assert_eq!(PartialTransfer::load(&part_path).await.unwrap(), None);
+        /* Removing it twice is fine */
+        PartialTransfer::remove(&part_path).await.unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
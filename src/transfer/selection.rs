@@ -0,0 +1,163 @@
+//! Receiving only some of the files of an offer
+//!
+//! With version 2 of the protocol, the sender offers a list of files (the
+//! [`manifest`](super::ReceiveRequest::manifest)), and the receiver tells which of them it wants.
+//! The sender then only sends these, see [`ReceiveRequest::select`](super::ReceiveRequest::select).
+
+use super::{AnswerV2, OfferV2};
+use regex::Regex;
+
+/**
+ * Which files of an offer to receive
+ *
+ * Paths are the ones in the manifest, separated by `/` and relative to the offered folder.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FileSelection {
+    All,
+    /** The files at these positions in the manifest */
+    Indices(Vec<usize>),
+    /** The files with these paths, and all files in the folders with these paths */
+    Paths(Vec<String>),
+    /**
+     * The files with a path matching one of these patterns, like `photos/**/*.jpg`
+     *
+     * `*` matches anything but `/`, `?` a single character other than `/`, and `**` any number
+     * of folders. Everything else matches itself.
+     */
+    Globs(Vec<String>),
+}
+
+impl Default for FileSelection {
+    fn default() -> Self {
+        FileSelection::All
+    }
+}
+
+impl FileSelection {
+    /** Ask for the selected files of an offer, from their start */
+    pub(super) fn answer(&self, offer: &OfferV2) -> AnswerV2 {
+        let globs = match self {
+            FileSelection::Globs(patterns) => {
+                patterns.iter().map(|pattern| glob(pattern)).collect()
+            },
+            _ => Vec::new(),
+        };
+        let files = offer
+            .files
+            .iter()
+            .enumerate()
+            .filter(|(index, entry)| match self {
+                FileSelection::All => true,
+                FileSelection::Indices(indices) => indices.contains(index),
+                FileSelection::Paths(paths) => paths.iter().any(|path| {
+                    let path = path.trim_end_matches('/');
+                    entry.path == path || entry.path.starts_with(&format!("{}/", path))
+                }),
+                FileSelection::Globs(_) => globs.iter().any(|glob| glob.is_match(&entry.path)),
+            })
+            .map(|(index, _)| (index as u64, 0))
+            .collect();
+        AnswerV2 { files }
+    }
+}
+
+/** Turn a glob pattern into a regular expression matching the whole path */
+fn glob(pattern: &str) -> Regex {
+    let mut regex = String::from("^");
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    regex.push_str("(?:.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            },
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    Regex::new(&regex).expect("Escaped patterns are valid")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transfer::OfferV2Entry;
+
+    fn offer(paths: &[&str]) -> OfferV2 {
+        OfferV2 {
+            transfer_name: Some("stuff".into()),
+            files: paths
+                .iter()
+                .map(|path| OfferV2Entry {
+                    path: path.to_string(),
+                    size: 1,
+                    mtime: 0,
+                    executable: false,
+                    mime_type: None,
+                    mode: None,
+                    sha256: [0; 32],
+                })
+                .collect(),
+            format: "tar.zst".into(),
+            preview: None,
+        }
+    }
+
+    fn selected(selection: FileSelection, offer: &OfferV2) -> Vec<u64> {
+        let mut indices = selection
+            .answer(offer)
+            .files
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        indices.sort_unstable();
+        indices
+    }
+
+    #[test]
+    fn test_selection() {
+        let offer = offer(&[
+            "notes.txt",
+            "photos/a.jpg",
+            "photos/2021/b.jpg",
+            "photos/2021/c.png",
+            "photoshop/d.psd",
+        ]);
+        assert_eq!(selected(FileSelection::All, &offer), vec![0, 1, 2, 3, 4]);
+        assert_eq!(
+            selected(FileSelection::Indices(vec![4, 0, 7]), &offer),
+            vec![0, 4]
+        );
+        assert_eq!(
+            selected(
+                FileSelection::Paths(vec!["photos/".into(), "notes.txt".into()]),
+                &offer
+            ),
+            vec![0, 1, 2, 3]
+        );
+        assert_eq!(
+            selected(FileSelection::Globs(vec!["photos/*.jpg".into()]), &offer),
+            vec![1]
+        );
+        assert_eq!(
+            selected(FileSelection::Globs(vec!["photos/**/*.jpg".into()]), &offer),
+            vec![1, 2]
+        );
+        assert_eq!(
+            selected(
+                FileSelection::Globs(vec!["**/?.p*".into(), "*.txt".into()]),
+                &offer
+            ),
+            vec![0, 3, 4]
+        );
+        assert!(selected(FileSelection::Globs(vec!["photos/(a).jpg".into()]), &offer).is_empty());
+    }
+}
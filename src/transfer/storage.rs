@@ -0,0 +1,379 @@
+//! Where sent files come from, and where received files go
+//!
+//! By default, the transfer works on the file system of the operating system. Applications that
+//! keep their files elsewhere — content URIs on Android, an object store, an in-memory file system
+//! in tests — can provide their own [`Storage`], in [`SendOptions::storage`](super::SendOptions::storage)
+//! and [`ReceiveOptions::storage`](super::ReceiveOptions::storage).
+
+use super::FileMetadata;
+use futures::{future::BoxFuture, AsyncRead, AsyncWrite, StreamExt};
+use std::{
+    ffi::OsString,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/** What an entry of a [`Storage`] is */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StorageEntryType {
+    File {
+        size: u64,
+    },
+    Directory,
+    Symlink {
+        target: PathBuf,
+    },
+    /** Anything else, like FIFOs, sockets or device nodes. These can't be sent. */
+    Other,
+}
+
+/** What a [`Storage`] knows about one of its entries */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StorageMetadata {
+    pub entry_type: StorageEntryType,
+    /** Only the modification time, the executable bit and the mode are used */
+    pub file: FileMetadata,
+}
+
+impl StorageMetadata {
+    pub fn file(size: u64) -> Self {
+        Self::new(StorageEntryType::File { size })
+    }
+
+    pub fn directory() -> Self {
+        Self::new(StorageEntryType::Directory)
+    }
+
+    fn new(entry_type: StorageEntryType) -> Self {
+        StorageMetadata {
+            entry_type,
+            file: FileMetadata::default(),
+        }
+    }
+
+    pub fn with_file_metadata(mut self, file: FileMetadata) -> Self {
+        self.file = file;
+        self
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.entry_type == StorageEntryType::Directory
+    }
+}
+
+/** An entry of a folder, as listed by [`Storage::read_dir`] */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StorageEntry {
+    /** The name of the entry within the folder */
+    pub name: OsString,
+    pub metadata: StorageMetadata,
+}
+
+/**
+ * Something that holds files and folders
+ *
+ * All paths are the ones the application passed in (like the `file_path` of
+ * [`send_file_or_folder`](super::send_file_or_folder)), or those joined with the names of
+ * folder entries.
+ */
+pub trait Storage: Send + Sync {
+    /** Open a file for reading */
+    fn open<'a>(
+        &'a self,
+        path: &'a Path,
+    ) -> BoxFuture<'a, io::Result<Box<dyn AsyncRead + Send + Unpin>>>;
+
+    /** Get the metadata of a file or folder, following symlinks */
+    fn metadata<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<StorageMetadata>>;
+
+    /**
+     * List the entries of a folder, in any order
+     *
+     * Their metadata is that of the entries themselves, symlinks are not followed.
+     */
+    fn read_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Vec<StorageEntry>>>;
+
+    /**
+     * Create a new file for writing
+     *
+     * This must fail with [`io::ErrorKind::AlreadyExists`] if there is a file at `path` already,
+     * so that receiving never overwrites (or on failure, removes) a file it did not create.
+     */
+    fn create<'a>(
+        &'a self,
+        path: &'a Path,
+    ) -> BoxFuture<'a, io::Result<Box<dyn AsyncWrite + Send + Unpin>>>;
+
+    /** Remove a file created with [`create`](Self::create), after receiving it failed */
+    fn remove_file<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>>;
+
+    /**
+     * Get a path that is the same for all paths leading to a folder
+     *
+     * This is used to detect symlinks pointing to a folder containing them. Storages without
+     * symlinks can keep the default, which returns `path` as it is.
+     */
+    fn canonicalize<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<PathBuf>> {
+        Box::pin(futures::future::ok(path.to_owned()))
+    }
+}
+
+/** The file system of the operating system */
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct OsStorage;
+
+impl OsStorage {
+    async fn entry_metadata(
+        path: &Path,
+        metadata: std::fs::Metadata,
+    ) -> io::Result<StorageMetadata> {
+        let entry_type = if metadata.file_type().is_symlink() {
+            StorageEntryType::Symlink {
+                target: async_std::fs::read_link(path).await?.into(),
+            }
+        } else if metadata.is_dir() {
+            StorageEntryType::Directory
+        } else if metadata.is_file() {
+            StorageEntryType::File {
+                size: metadata.len(),
+            }
+        } else {
+            StorageEntryType::Other
+        };
+        Ok(StorageMetadata {
+            entry_type,
+            file: FileMetadata::from_fs(&metadata),
+        })
+    }
+}
+
+impl Storage for OsStorage {
+    fn open<'a>(
+        &'a self,
+        path: &'a Path,
+    ) -> BoxFuture<'a, io::Result<Box<dyn AsyncRead + Send + Unpin>>> {
+        Box::pin(async move {
+            let file = async_std::fs::File::open(path).await?;
+            Ok(Box::new(file) as Box<dyn AsyncRead + Send + Unpin>)
+        })
+    }
+
+    fn metadata<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<StorageMetadata>> {
+        Box::pin(async move {
+            let metadata = async_std::fs::metadata(path).await?;
+            Self::entry_metadata(path, metadata).await
+        })
+    }
+
+    fn read_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Vec<StorageEntry>>> {
+        Box::pin(async move {
+            let mut entries = Vec::new();
+            let mut children = async_std::fs::read_dir(path).await?;
+            while let Some(child) = children.next().await {
+                let child = child?;
+                let path: PathBuf = child.path().into();
+                let metadata = async_std::fs::symlink_metadata(&path).await?;
+                entries.push(StorageEntry {
+                    name: child.file_name(),
+                    metadata: Self::entry_metadata(&path, metadata).await?,
+                });
+            }
+            Ok(entries)
+        })
+    }
+
+    fn create<'a>(
+        &'a self,
+        path: &'a Path,
+    ) -> BoxFuture<'a, io::Result<Box<dyn AsyncWrite + Send + Unpin>>> {
+        Box::pin(async move {
+            let file = async_std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(path)
+                .await?;
+            Ok(Box::new(file) as Box<dyn AsyncWrite + Send + Unpin>)
+        })
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move { async_std::fs::remove_file(path).await })
+    }
+
+    fn canonicalize<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<PathBuf>> {
+        Box::pin(async move { Ok(async_std::fs::canonicalize(path).await?.into()) })
+    }
+}
+
+/**
+ * A [`Storage`] implementation, to be put into the options
+ *
+ * Equality is based on identity: two storages are equal if they are clones of each other.
+ * The default is the [`OsStorage`].
+ */
+#[derive(Clone)]
+pub struct SharedStorage(Arc<dyn Storage>);
+
+impl SharedStorage {
+    pub fn new(storage: impl Storage + 'static) -> Self {
+        Self(Arc::new(storage))
+    }
+}
+
+impl Default for SharedStorage {
+    fn default() -> Self {
+        Self::new(OsStorage)
+    }
+}
+
+impl std::ops::Deref for SharedStorage {
+    type Target = dyn Storage;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
+
+impl std::fmt::Debug for SharedStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SharedStorage(..)")
+    }
+}
+
+impl PartialEq for SharedStorage {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for SharedStorage {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transfer::{folder, FolderSendOptions};
+    use std::collections::BTreeMap;
+
+    /** Files in memory, with the folders implied by their paths */
+    struct MemoryStorage(BTreeMap<PathBuf, Vec<u8>>);
+
+    impl MemoryStorage {
+        fn entry(&self, path: &Path) -> io::Result<StorageMetadata> {
+            match self.0.get(path) {
+                Some(content) => Ok(StorageMetadata::file(content.len() as u64)),
+                None if self.0.keys().any(|file| file.starts_with(path)) => {
+                    Ok(StorageMetadata::directory())
+                },
+                None => Err(io::ErrorKind::NotFound.into()),
+            }
+        }
+    }
+
+    impl Storage for MemoryStorage {
+        fn open<'a>(
+            &'a self,
+            path: &'a Path,
+        ) -> BoxFuture<'a, io::Result<Box<dyn AsyncRead + Send + Unpin>>> {
+            let content = self.0.get(path).cloned();
+            Box::pin(async move {
+                let content = content.ok_or(io::ErrorKind::NotFound)?;
+                Ok(Box::new(futures::io::Cursor::new(content))
+                    as Box<dyn AsyncRead + Send + Unpin>)
+            })
+        }
+
+        fn metadata<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<StorageMetadata>> {
+            Box::pin(async move { self.entry(path) })
+        }
+
+        fn read_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Vec<StorageEntry>>> {
+            Box::pin(async move {
+                let mut names = self
+                    .0
+                    .keys()
+                    .filter_map(|file| file.strip_prefix(path).ok()?.iter().next())
+                    .collect::<Vec<_>>();
+                names.dedup();
+                names
+                    .into_iter()
+                    .map(|name| {
+                        Ok(StorageEntry {
+                            name: name.to_owned(),
+                            metadata: self.entry(&path.join(name))?,
+                        })
+                    })
+                    .collect()
+            })
+        }
+
+        fn create<'a>(
+            &'a self,
+            _path: &'a Path,
+        ) -> BoxFuture<'a, io::Result<Box<dyn AsyncWrite + Send + Unpin>>> {
+            Box::pin(futures::future::err(io::ErrorKind::PermissionDenied.into()))
+        }
+
+        fn remove_file<'a>(&'a self, _path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+            Box::pin(futures::future::err(io::ErrorKind::PermissionDenied.into()))
+        }
+    }
+
+    #[async_std::test]
+    async fn test_walk_memory_storage() {
+        let storage = SharedStorage::new(MemoryStorage(
+            vec![
+                (PathBuf::from("/photos/b.jpg"), b"bb".to_vec()),
+                ("/photos/2021/a.jpg".into(), b"a".to_vec()),
+                ("/notes.txt".into(), b"notes".to_vec()),
+            ]
+            .into_iter()
+            .collect(),
+        ));
+        assert_eq!(storage, storage.clone());
+        assert_ne!(storage, SharedStorage::default());
+
+        let entries = folder::walk(
+            &*storage,
+            Path::new("/photos"),
+            &FolderSendOptions::default(),
+            true,
+        )
+        .await
+        .unwrap();
+        let entries = entries
+            .into_iter()
+            .map(|entry| (entry.path, entry.name, entry.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            entries,
+            vec![
+                (
+                    "/photos/2021".into(),
+                    "2021".into(),
+                    folder::EntryKind::Directory
+                ),
+                (
+                    "/photos/2021/a.jpg".into(),
+                    "2021/a.jpg".into(),
+                    folder::EntryKind::File { size: 1 }
+                ),
+                (
+                    "/photos/b.jpg".into(),
+                    "b.jpg".into(),
+                    folder::EntryKind::File { size: 2 }
+                ),
+            ]
+        );
+
+        let mut content = Vec::new();
+        futures::AsyncReadExt::read_to_end(
+            &mut storage.open(Path::new("/notes.txt")).await.unwrap(),
+            &mut content,
+        )
+        .await
+        .unwrap();
+        assert_eq!(content, b"notes");
+        assert!(storage.metadata(Path::new("/missing")).await.is_err());
+    }
+}
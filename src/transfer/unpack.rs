@@ -0,0 +1,82 @@
+//! Unpacking received folders while they arrive
+//!
+//! Folders are sent as tar files (see [`ReceiveRequest::accept_directory`](super::ReceiveRequest::accept_directory)).
+//! The sender picks the names of their entries, so every one of them gets checked to stay within
+//! the target folder before anything is written.
+
+use super::TransferError;
+use std::path::{Component, Path};
+
+/**
+ * Unpack a tar file into `folder`, which must exist
+ *
+ * Entries with absolute paths or `..` in them fail the unpacking, as do entries that would
+ * be written through a symlink pointing elsewhere. The archive gets read to its end, even after
+ * the end of the tar file. This does blocking IO.
+ */
+pub(super) fn unpack(archive: impl std::io::Read, folder: &Path) -> Result<(), TransferError> {
+    let mut archive = tar::Archive::new(archive);
+    archive.set_preserve_permissions(false);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let is_safe = path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+        ensure!(
+            is_safe && entry.unpack_in(folder)?,
+            TransferError::Protocol(
+                format!("The archive contains the unsafe path '{}'", path.display()).into()
+            )
+        );
+    }
+    /* Zero padding, and whatever else the sender may have put there */
+    std::io::copy(&mut archive.into_inner(), &mut std::io::sink())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn archive(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, content) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            /* `append_data` would refuse the unsafe paths */
+            header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_cksum();
+            builder.append(&header, *content).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_unpack() {
+        let dir = std::env::temp_dir().join(format!("wormhole-test-unpack-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let folder = dir.join("folder");
+        std::fs::create_dir_all(&folder).unwrap();
+
+        let tar = archive(&[("sub/file.txt", b"hello"), ("./other.txt", b"")]);
+        unpack(&tar[..], &folder).unwrap();
+        assert_eq!(
+            std::fs::read(folder.join("sub/file.txt")).unwrap(),
+            b"hello"
+        );
+        assert!(folder.join("other.txt").exists());
+
+        for path in &["../evil.txt", "/tmp/evil.txt", "sub/../../evil.txt"] {
+            let tar = archive(&[(path, b"evil")]);
+            assert!(matches!(
+                unpack(&tar[..], &folder),
+                Err(TransferError::Protocol(_))
+            ));
+        }
+        assert!(!dir.join("evil.txt").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
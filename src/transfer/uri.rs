@@ -0,0 +1,202 @@
+//! Sharing codes as `wormhole-transfer:` URIs
+//!
+//! Typing a code on a phone is tedious, so the sender may show it as QR code instead, which the
+//! receiving app scans. Such a URI may also carry the rendezvous server, for self-hosted ones.
+
+use crate::{Code, ParseCodeError};
+use std::convert::TryFrom;
+
+/** The URI scheme, which other implementations use as well */
+const SCHEME: &str = "wormhole-transfer";
+/** The only version of the URI format so far. URIs without one are of this version too */
+const VERSION: &str = "0";
+
+/**
+ * A `wormhole-transfer:` URI, to share a code as a link or as QR code
+ *
+ * It looks like `wormhole-transfer:4-hurricane-equipment?rendezvous=ws%3A%2F%2F…&role=leader`:
+ * the code is the path, and the query optionally tells the rendezvous server and the role of
+ * whoever opens the URI.
+ *
+ * Parse one with [`str::parse`] or from an [`url::Url`], and make one with `to_string()` or
+ * [`url::Url::from`]. Unknown query parameters are ignored, for the sake of future extensions.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct WormholeTransferUri {
+    pub code: Code,
+    /** `None` for the [default one](crate::rendezvous::DEFAULT_RENDEZVOUS_SERVER) */
+    pub rendezvous_server: Option<url::Url>,
+    /**
+     * Whether whoever opens the URI is the leader, and thus sends
+     *
+     * Usually, the sender allocates the code and shares it, so this is `false`.
+     */
+    pub is_leader: bool,
+}
+
+impl WormholeTransferUri {
+    pub fn new(code: Code) -> Self {
+        Self {
+            code,
+            rendezvous_server: None,
+            is_leader: false,
+        }
+    }
+
+    pub fn rendezvous_server(mut self, rendezvous_server: url::Url) -> Self {
+        self.rendezvous_server = Some(rendezvous_server);
+        self
+    }
+
+    pub fn is_leader(mut self, is_leader: bool) -> Self {
+        self.is_leader = is_leader;
+        self
+    }
+}
+
+impl From<&WormholeTransferUri> for url::Url {
+    fn from(uri: &WormholeTransferUri) -> Self {
+        let mut url = url::Url::parse(&format!("{}:", SCHEME)).unwrap();
+        url.set_path(uri.code.as_str());
+        if let Some(rendezvous_server) = &uri.rendezvous_server {
+            url.query_pairs_mut()
+                .append_pair("rendezvous", rendezvous_server.as_str());
+        }
+        if uri.is_leader {
+            url.query_pairs_mut().append_pair("role", "leader");
+        }
+        url
+    }
+}
+
+impl TryFrom<&url::Url> for WormholeTransferUri {
+    type Error = ParseUriError;
+
+    fn try_from(url: &url::Url) -> Result<Self, Self::Error> {
+        ensure!(
+            url.scheme() == SCHEME,
+            ParseUriError::UnsupportedScheme(url.scheme().into())
+        );
+        let code = percent_encoding::percent_decode_str(url.path())
+            .decode_utf8()
+            .map_err(|_| ParseUriError::InvalidEncoding)?
+            .parse()?;
+        let mut uri = WormholeTransferUri::new(code);
+        for (key, value) in url.query_pairs() {
+            match &*key {
+                "version" => ensure!(
+                    value == VERSION,
+                    ParseUriError::UnsupportedVersion(value.into())
+                ),
+                "rendezvous" => {
+                    uri.rendezvous_server = Some(
+                        value
+                            .parse()
+                            .map_err(ParseUriError::InvalidRendezvousServer)?,
+                    )
+                },
+                "role" => {
+                    uri.is_leader = match &*value {
+                        "leader" => true,
+                        "follower" => false,
+                        _ => bail!(ParseUriError::InvalidRole(value.into())),
+                    }
+                },
+                _ => {},
+            }
+        }
+        Ok(uri)
+    }
+}
+
+impl std::str::FromStr for WormholeTransferUri {
+    type Err = ParseUriError;
+
+    fn from_str(uri: &str) -> Result<Self, Self::Err> {
+        Self::try_from(&url::Url::parse(uri).map_err(ParseUriError::InvalidUri)?)
+    }
+}
+
+impl std::fmt::Display for WormholeTransferUri {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", url::Url::from(self))
+    }
+}
+
+/** Why a [`WormholeTransferUri`] could not be parsed */
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum ParseUriError {
+    #[error("Not a valid URI")]
+    InvalidUri(#[source] url::ParseError),
+    #[error("Expected a {} URI, not '{}'", SCHEME, _0)]
+    UnsupportedScheme(Box<str>),
+    #[error("Unsupported URI version '{}'", _0)]
+    UnsupportedVersion(Box<str>),
+    #[error("The code in the URI is not valid UTF-8")]
+    InvalidEncoding,
+    #[error("Invalid code in the URI")]
+    InvalidCode(
+        #[from]
+        #[source]
+        ParseCodeError,
+    ),
+    #[error("Invalid rendezvous server in the URI")]
+    InvalidRendezvousServer(#[source] url::ParseError),
+    #[error("Invalid role '{}', expected 'leader' or 'follower'", _0)]
+    InvalidRole(Box<str>),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_uri() {
+        let code: Code = "4-hurricane-equipment".parse().unwrap();
+        assert_eq!(
+            WormholeTransferUri::new(code.clone()).to_string(),
+            "wormhole-transfer:4-hurricane-equipment"
+        );
+        assert_eq!(
+            WormholeTransferUri::new(code)
+                .rendezvous_server("ws://example.org:4000/v1".parse().unwrap())
+                .is_leader(true)
+                .to_string(),
+            "wormhole-transfer:4-hurricane-equipment?rendezvous=ws%3A%2F%2Fexample.org%3A4000%2Fv1&role=leader"
+        );
+    }
+
+    #[test]
+    fn test_parse_uri() {
+        let code: Code = "4-hurricane-equipment".parse().unwrap();
+        let uri = WormholeTransferUri::new(code.clone())
+            .rendezvous_server("ws://example.org:4000/v1".parse().unwrap())
+            .is_leader(true);
+        assert_eq!(uri.to_string().parse::<WormholeTransferUri>().unwrap(), uri);
+
+        let parsed: WormholeTransferUri =
+            "wormhole-transfer:4-hurricane%20equipment?version=0&role=follower&foo=bar"
+                .parse()
+                .unwrap();
+        assert_eq!(parsed, WormholeTransferUri::new(code));
+
+        assert!(matches!(
+            "https://example.org/4-hurricane-equipment".parse::<WormholeTransferUri>(),
+            Err(ParseUriError::UnsupportedScheme(_))
+        ));
+        assert!(matches!(
+            "wormhole-transfer:4-hurricane-equipment?version=1".parse::<WormholeTransferUri>(),
+            Err(ParseUriError::UnsupportedVersion(_))
+        ));
+        assert!(matches!(
+            "wormhole-transfer:4-hurricane-equipment?role=sender".parse::<WormholeTransferUri>(),
+            Err(ParseUriError::InvalidRole(_))
+        ));
+        assert!(matches!(
+            "wormhole-transfer:hurricane-equipment".parse::<WormholeTransferUri>(),
+            Err(ParseUriError::InvalidCode(_))
+        ));
+    }
+}
@@ -3,22 +3,40 @@ use log::*;
 use sha2::{digest::FixedOutput, Digest, Sha256};
 use std::path::PathBuf;
 
-use super::*;
+use super::{
+    record_size::{RecordSizer, MAX_RECORD_SIZE},
+    *,
+};
+
+/** The value of [`Offer::File`]'s `compression` for content compressed with zstd */
+pub const COMPRESSION_ZSTD: &str = "zstd";
+/** Favour speed, the transfer should not wait for the compression */
+const ZSTD_LEVEL: i32 = 1;
 
 pub async fn send_file<F, N, H>(
-    mut wormhole: Wormhole,
+    wormhole: &mut Wormhole,
     relay_hints: Vec<transit::RelayHint>,
     file: &mut F,
     file_name: N,
     file_size: u64,
+    metadata: FileMetadata,
+    options: SendOptions,
     progress_handler: H,
-) -> Result<(), TransferError>
+) -> Result<TransferResult, TransferError>
 where
     F: AsyncRead + Unpin,
     N: Into<PathBuf>,
     H: FnMut(u64, u64) + 'static,
 {
-    let connector = transit::init(transit::Abilities::ALL_ABILITIES, None, relay_hints).await?;
+    check_send_options(wormhole, &options).await?;
+
+    let connector = transit::init(
+        transit::Abilities::ALL_ABILITIES,
+        None,
+        relay_hints,
+        &options.transit_config,
+    )
+    .await?;
 
     // We want to do some transit
     debug!("Sending transit message '{:?}", connector.our_hints());
@@ -29,56 +47,38 @@ where
         ))
         .await?;
 
+    /* The beginning of the file tells whether it is worth compressing, so read it ahead */
+    let file_name = file_name.into();
+    let peer_version: AppVersion = serde_json::from_value(wormhole.peer_version.clone())?;
+    let mut header = Vec::new();
+    let mut compress =
+        peer_version.supports_compression() && options.compression != Compression::Never;
+    if compress {
+        header = read_block(file, RECORD_SIZE).await?;
+        compress = options.compression.should_compress(&file_name, &header);
+    }
+    let file = &mut futures::io::Cursor::new(header).chain(file);
+
     // Send file offer message.
     debug!("Sending file offer");
+    let metadata = FileMetadata {
+        mime_type: super::guess_mime_type(&file_name).map(Into::into),
+        preview: options.preview.clone(),
+        ..metadata
+    };
+    let offer = PeerMessage::offer_file(file_name, file_size).with_metadata(&metadata);
     wormhole
-        .send_json(&PeerMessage::offer_file(file_name, file_size))
+        .send_json(&if compress { offer.compressed() } else { offer })
         .await?;
 
-    // Wait for their transit response
-    let (their_abilities, their_hints): (transit::Abilities, transit::Hints) =
-        match wormhole.receive_json().await?? {
-            PeerMessage::Transit(transit) => {
-                debug!("received transit message: {:?}", transit);
-                (transit.abilities_v1, transit.hints_v1.into())
-            },
-            PeerMessage::Error(err) => {
-                bail!(TransferError::PeerError(err));
-            },
-            other => {
-                let error = TransferError::unexpected_message("transit", other);
-                let _ = wormhole
-                    .send_json(&PeerMessage::Error(format!("{}", error)))
-                    .await;
-                bail!(error)
-            },
-        };
-
-    {
-        // Wait for file_ack
-        let fileack_msg = wormhole.receive_json().await??;
-        debug!("received file ack message: {:?}", fileack_msg);
-
-        match fileack_msg {
-            PeerMessage::Answer(Answer::FileAck(msg)) => {
-                ensure!(msg == "ok", TransferError::AckError);
-            },
-            PeerMessage::Error(err) => {
-                bail!(TransferError::PeerError(err));
-            },
-            _ => {
-                let error = TransferError::unexpected_message("answer/file_ack", fileack_msg);
-                let _ = wormhole
-                    .send_json(&PeerMessage::Error(format!("{}", error)))
-                    .await;
-                bail!(error)
-            },
-        }
-    }
+    // Wait for their transit response and their answer
+    let (their_abilities, their_hints, resume) =
+        receive_answer(wormhole, options.offer_timeout).await?;
 
+    wormhole.events().emit(WormholeEvent::TransitConnecting);
     let mut transit = match connector
         .leader_connect(
-            wormhole.key().derive_transit_key(wormhole.appid()),
+            wormhole.next_transit_key(),
             their_abilities,
             Arc::new(their_hints),
         )
@@ -87,54 +87,88 @@ where
         Ok(transit) => transit,
         Err(error) => {
             let error = TransferError::TransitConnect(error);
-            let _ = wormhole
-                .send_json(&PeerMessage::Error(format!("{}", error)))
-                .await;
+            let _ = wormhole.send_json(&PeerMessage::error(&error)).await;
             return Err(error);
         },
     };
+    TransitHandler::call(options.transit_handler.as_ref(), &transit);
+    wormhole.events().emit(WormholeEvent::Transferring);
+    let started = std::time::Instant::now();
 
+    let (offset, hasher) = skip_received(wormhole, file, file_size, resume).await?;
     debug!("Beginning file transfer");
 
     // 11. send the file as encrypted records.
-    let checksum = match v1::send_records(&mut transit, file, file_size, progress_handler).await {
-        Err(TransferError::Transit(error)) => {
-            let _ = wormhole
-                .send_json(&PeerMessage::Error(format!("{}", error)))
-                .await;
-            Err(TransferError::Transit(error))
-        },
+    let checksum = match v1::send_records(
+        &mut transit,
+        file,
+        file_size,
+        offset,
+        hasher,
+        compress,
+        options.read_ahead,
+        options.record_size,
+        progress_handler,
+    )
+    .await
+    {
+        Err(TransferError::Transit(error)) => Err(transit_failure(wormhole, error).await),
         other => other,
     }?;
 
     // 13. wait for the transit ack with sha256 sum from the peer.
     debug!("sent file. Waiting for ack");
-    let transit_ack = transit.receive_record().await?;
+    let transit_ack = match transit.receive_record().await {
+        Ok(transit_ack) => transit_ack,
+        Err(error) => bail!(transit_failure(wormhole, error).await),
+    };
     let transit_ack_msg = serde_json::from_slice::<TransitAck>(&transit_ack)?;
     ensure!(
-        transit_ack_msg.sha256 == hex::encode(checksum),
+        transit_ack_msg.sha256 == hex::encode(&checksum),
         TransferError::Checksum
     );
     debug!("transfer complete!");
-    Ok(())
+    Ok(TransferResult::new(
+        transit_ack_msg.sha256,
+        file_size,
+        started,
+        &transit,
+    ))
 }
 
+/**
+ * Send a folder as tar file
+ *
+ * The tar file is never stored anywhere. It gets packed once to learn its size and hash for
+ * the offer, and then again while sending it, so that sending large folders only takes as
+ * much memory as a few records.
+ */
 pub async fn send_folder<N, M, H>(
-    mut wormhole: Wormhole,
+    wormhole: &mut Wormhole,
     relay_hints: Vec<transit::RelayHint>,
     folder_path: N,
     folder_name: M,
+    options: SendOptions,
     progress_handler: H,
-) -> Result<(), TransferError>
+) -> Result<TransferResult, TransferError>
 where
     N: Into<PathBuf>,
     M: Into<PathBuf>,
     H: FnMut(u64, u64) + 'static,
 {
-    let connector = transit::init(transit::Abilities::ALL_ABILITIES, None, relay_hints).await?;
+    check_send_options(wormhole, &options).await?;
+
+    let connector = transit::init(
+        transit::Abilities::ALL_ABILITIES,
+        None,
+        relay_hints,
+        &options.transit_config,
+    )
+    .await?;
     let folder_path = folder_path.into();
+    let storage = options.storage.clone().unwrap_or_default();
 
-    if !folder_path.is_dir() {
+    if !storage.metadata(&folder_path).await?.is_dir() {
         panic!(
             "You should only call this method with directory paths, but '{}' is not",
             folder_path.display()
@@ -150,8 +184,26 @@ where
         ))
         .await?;
 
-    use tar::Builder;
-    // use sha2::{digest::FixedOutput, Digest, Sha256};
+    let entries = match folder::walk(&*storage, &folder_path, &options.folder, true).await {
+        Ok(entries) => entries,
+        Err(error) => {
+            let error = TransferError::IO(error);
+            let _ = wormhole.send_json(&PeerMessage::error(&error)).await;
+            bail!(error);
+        },
+    };
+    /* Count like the Python implementation does for directory offers: links are files too */
+    let (mut numbytes, mut numfiles) = (0, 0);
+    for entry in &entries {
+        match entry.kind {
+            folder::EntryKind::Directory => {},
+            folder::EntryKind::File { size } => {
+                numbytes += size;
+                numfiles += 1;
+            },
+            folder::EntryKind::Symlink { .. } => numfiles += 1,
+        }
+    }
 
     /* Helper struct stolen from https://docs.rs/count-write/0.1.0 */
     struct CountWrite<W> {
@@ -179,78 +231,57 @@ where
         "Tar'ing '{}' to see how big it'll be :)",
         folder_path.display()
     );
-    let folder_path2 = folder_path.clone();
-    let (length, sha256sum_initial) = async_std::task::spawn_blocking(move || {
+    let tarred = async {
         let mut hasher = Sha256::new();
         let mut counter = CountWrite {
             inner: &mut hasher,
             count: 0,
         };
-        let mut builder = Builder::new(&mut counter);
-
-        builder.mode(tar::HeaderMode::Deterministic);
-        builder.follow_symlinks(false);
-        /* A hasher should never fail writing */
-        builder.append_dir_all("", folder_path2).unwrap();
-        builder.finish().unwrap();
-
-        std::mem::drop(builder);
+        write_tar(
+            &mut futures::io::AllowStdIo::new(&mut counter),
+            &entries,
+            &*storage,
+        )
+        .await?;
         let count = counter.count;
-        std::mem::drop(counter);
-        (count, hasher.finalize_fixed())
-    })
-    .await;
+        std::io::Result::Ok((count, hasher.finalize_fixed()))
+    };
+    let (length, sha256sum_initial) = match tarred.await {
+        Ok(tarred) => tarred,
+        Err(error) => {
+            let error = TransferError::IO(error);
+            let _ = wormhole.send_json(&PeerMessage::error(&error)).await;
+            bail!(error);
+        },
+    };
+
+    /* Tar files are not compressed, so there is nothing to sniff */
+    let folder_name = folder_name.into();
+    let peer_version: AppVersion = serde_json::from_value(wormhole.peer_version.clone())?;
+    let compress = peer_version.supports_compression()
+        && options.compression.should_compress(&folder_name, &[]);
 
     // Send file offer message.
     debug!("Sending file offer");
+    let offer = PeerMessage::offer_tarball(folder_name, length, numbytes, numfiles).with_metadata(
+        &FileMetadata {
+            mime_type: Some("application/x-tar".into()),
+            preview: options.preview.clone(),
+            ..Default::default()
+        },
+    );
     wormhole
-        .send_json(&PeerMessage::offer_file(folder_name, length))
+        .send_json(&if compress { offer.compressed() } else { offer })
         .await?;
 
-    // Wait for their transit response
-    let (their_abilities, their_hints): (transit::Abilities, transit::Hints) =
-        match wormhole.receive_json().await?? {
-            PeerMessage::Transit(transit) => {
-                debug!("received transit message: {:?}", transit);
-                (transit.abilities_v1, transit.hints_v1)
-            },
-            PeerMessage::Error(err) => {
-                bail!(TransferError::PeerError(err));
-            },
-            other => {
-                let error = TransferError::unexpected_message("transit", other);
-                let _ = wormhole
-                    .send_json(&PeerMessage::Error(format!("{}", error)))
-                    .await;
-                bail!(error)
-            },
-        };
-
-    {
-        // Wait for file_ack
-        let fileack_msg = wormhole.receive_json().await??;
-        debug!("received file ack message: {:?}", fileack_msg);
-
-        match fileack_msg {
-            PeerMessage::Answer(Answer::FileAck(msg)) => {
-                ensure!(msg == "ok", TransferError::AckError);
-            },
-            PeerMessage::Error(err) => {
-                bail!(TransferError::PeerError(err));
-            },
-            _ => {
-                let error = TransferError::unexpected_message("answer/file_ack", fileack_msg);
-                let _ = wormhole
-                    .send_json(&PeerMessage::Error(format!("{}", error)))
-                    .await;
-                bail!(error)
-            },
-        }
-    }
+    // Wait for their transit response and their answer
+    let (their_abilities, their_hints, resume) =
+        receive_answer(wormhole, options.offer_timeout).await?;
 
+    wormhole.events().emit(WormholeEvent::TransitConnecting);
     let mut transit = match connector
         .leader_connect(
-            wormhole.key().derive_transit_key(wormhole.appid()),
+            wormhole.next_transit_key(),
             their_abilities,
             Arc::new(their_hints),
         )
@@ -259,115 +290,313 @@ where
         Ok(transit) => transit,
         Err(error) => {
             let error = TransferError::TransitConnect(error);
-            let _ = wormhole
-                .send_json(&PeerMessage::Error(format!("{}", error)))
-                .await;
+            let _ = wormhole.send_json(&PeerMessage::error(&error)).await;
             return Err(error);
         },
     };
+    TransitHandler::call(options.transit_handler.as_ref(), &transit);
+    wormhole.events().emit(WormholeEvent::Transferring);
+    let started = std::time::Instant::now();
 
     debug!("Beginning file transfer");
 
-    /* Helper struct stolen from https://github.com/softprops/broadcast/blob/master/src/lib.rs */
-    pub struct BroadcastWriter<A: std::io::Write, B: std::io::Write> {
-        primary: A,
-        secondary: B,
-    }
-
-    impl<A: std::io::Write, B: std::io::Write> std::io::Write for BroadcastWriter<A, B> {
-        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
-            let n = self.primary.write(data).unwrap();
-            self.secondary.write_all(&data[..n]).unwrap();
-            Ok(n)
-        }
+    // 11. send the file as encrypted records.
+    use futures::{future::Either, AsyncReadExt, AsyncWriteExt};
+    let (mut reader, mut writer) = futures_ringbuf::RingBuffer::new(4096).split();
 
-        fn flush(&mut self) -> std::io::Result<()> {
-            self.primary.flush().and(self.secondary.flush())
+    let checksum = {
+        /* Pack the folder a second time, while sending it */
+        let packing = async {
+            write_tar(&mut writer, &entries, &*storage).await?;
+            writer.close().await
+        };
+        let sending = async {
+            let (offset, hasher) = skip_received(wormhole, &mut reader, length, resume).await?;
+            match v1::send_records(
+                &mut transit,
+                &mut reader,
+                length,
+                offset,
+                hasher,
+                compress,
+                options.read_ahead,
+                options.record_size,
+                progress_handler,
+            )
+            .await
+            {
+                Err(TransferError::Transit(error)) => Err(transit_failure(wormhole, error).await),
+                other => other,
+            }
+        };
+        futures::pin_mut!(packing, sending);
+        /* If packing fails, the data runs short and sending never finishes. If sending fails,
+         * the packing may never finish either, as nobody reads what it writes */
+        match futures::future::select(sending, packing).await {
+            Either::Left((checksum, _)) => checksum,
+            Either::Right((Ok(()), sending)) => sending.await,
+            Either::Right((Err(error), _)) => {
+                debug!("Packing the folder failed: {}", error);
+                Err(TransferError::FilesystemSkew)
+            },
         }
-    }
+    };
 
-    // 11. send the file as encrypted records.
-    use futures::{AsyncReadExt, AsyncWriteExt};
-    let (mut reader, mut writer) = futures_ringbuf::RingBuffer::new(4096).split();
+    /* Check if the folder got packed the same way as advertized. Otherwise, tell the other side and bail out */
+    let checksum = match checksum {
+        Ok(checksum) if checksum[..] == sha256sum_initial[..] => checksum,
+        Ok(_) | Err(TransferError::FilesystemSkew) => {
+            let error = TransferError::FilesystemSkew;
+            let _ = wormhole.send_json(&PeerMessage::error(&error)).await;
+            bail!(error)
+        },
+        Err(error) => bail!(error),
+    };
 
-    struct BlockingWrite<W>(std::pin::Pin<Box<W>>);
+    // 13. wait for the transit ack with sha256 sum from the peer.
+    debug!("sent file. Waiting for ack");
+    let transit_ack = match transit.receive_record().await {
+        Ok(transit_ack) => transit_ack,
+        Err(error) => bail!(transit_failure(wormhole, error).await),
+    };
+    let transit_ack_msg = serde_json::from_slice::<TransitAck>(&transit_ack)?;
+    ensure!(
+        transit_ack_msg.sha256 == hex::encode(&checksum),
+        TransferError::Checksum
+    );
+    debug!("Transfer complete!");
+    Ok(TransferResult::new(
+        transit_ack_msg.sha256,
+        length,
+        started,
+        &transit,
+    ))
+}
 
-    impl<W: AsyncWrite + Send + Unpin> std::io::Write for BlockingWrite<W> {
-        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-            let mut writer = self.0.as_mut();
-            futures::executor::block_on(AsyncWriteExt::write(&mut writer, buf))
-        }
+/** The modification time of all entries, the one `tar::HeaderMode::Deterministic` uses */
+const DETERMINISTIC_MTIME: u64 = 1153704088;
+
+/** A header like `tar::HeaderMode::Deterministic` makes them: only the executable bit is kept */
+fn tar_header(entry_type: tar::EntryType, size: u64, executable: bool) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(entry_type);
+    header.set_size(size);
+    header.set_mode(if executable || entry_type.is_dir() {
+        0o755
+    } else {
+        0o644
+    });
+    header.set_mtime(DETERMINISTIC_MTIME);
+    header.set_uid(0);
+    header.set_gid(0);
+    header
+}
 
-        fn flush(&mut self) -> std::result::Result<(), std::io::Error> {
-            let mut writer = self.0.as_mut();
-            futures::executor::block_on(AsyncWriteExt::flush(&mut writer))
+/**
+ * Write the entries of a folder as tar file
+ *
+ * Followed symlinks look like what they point to, the others stay links. The headers are made
+ * by the `tar` crate, the content of the files gets copied without blocking.
+ */
+async fn write_tar<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    entries: &[folder::FolderEntry],
+    storage: &dyn Storage,
+) -> std::io::Result<()> {
+    /* Only ever holds the headers, which get written out right away */
+    let mut builder = tar::Builder::new(Vec::new());
+    for entry in entries {
+        let executable = entry.metadata.executable;
+        match &entry.kind {
+            folder::EntryKind::Directory => builder.append_data(
+                &mut tar_header(tar::EntryType::Directory, 0, executable),
+                &entry.name,
+                std::io::empty(),
+            )?,
+            folder::EntryKind::File { size } => {
+                builder.append_data(
+                    &mut tar_header(tar::EntryType::Regular, *size, executable),
+                    &entry.name,
+                    std::io::empty(),
+                )?;
+                writer.write_all(&std::mem::take(builder.get_mut())).await?;
+                let mut reader = storage.open(&entry.path).await?.take(*size);
+                if futures::io::copy(&mut reader, writer).await? < *size {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "The file got shorter while sending it",
+                    ));
+                }
+                let padding = (512 - size % 512) % 512;
+                writer.write_all(&[0; 512][..padding as usize]).await?;
+            },
+            folder::EntryKind::Symlink { target } => {
+                let mut header = tar_header(tar::EntryType::Symlink, 0, executable);
+                header.set_link_name(target)?;
+                builder.append_data(&mut header, &entry.name, std::io::empty())?;
+            },
         }
+        writer.write_all(&std::mem::take(builder.get_mut())).await?;
     }
+    builder.finish()?;
+    writer.write_all(&std::mem::take(builder.get_mut())).await
+}
 
-    let file_sender = async_std::task::spawn_blocking(move || {
-        let mut hasher = Sha256::new();
-        let mut hash_writer = BroadcastWriter {
-            primary: BlockingWrite(Box::pin(&mut writer)),
-            secondary: &mut hasher,
-        };
-        let mut builder = Builder::new(&mut hash_writer);
+/**
+ * Wait for the receiver's transit message and their answer to our offer
+ *
+ * If an `offer_timeout` is given and the receiver does not answer in time, the offer
+ * is withdrawn by sending them an error message.
+ */
+async fn receive_answer(
+    wormhole: &mut Wormhole,
+    offer_timeout: Option<std::time::Duration>,
+) -> Result<(transit::Abilities, transit::Hints, Option<(u64, [u8; 32])>), TransferError> {
+    let answer = async {
+        // Wait for their transit response
+        let (their_abilities, their_hints): (transit::Abilities, transit::Hints) =
+            match wormhole.receive_json().await?? {
+                PeerMessage::Transit(transit) => {
+                    debug!("received transit message: {:?}", transit);
+                    (transit.abilities_v1, transit.hints_v1)
+                },
+                PeerMessage::Error(err) => {
+                    bail!(TransferError::PeerError(err));
+                },
+                PeerMessage::Cancel(reason) => {
+                    bail!(TransferError::PeerCancelled(reason));
+                },
+                other => {
+                    let error = TransferError::unexpected_message("transit", other);
+                    let _ = wormhole.send_json(&PeerMessage::error(&error)).await;
+                    bail!(error)
+                },
+            };
 
-        builder.mode(tar::HeaderMode::Deterministic);
-        builder.follow_symlinks(false);
-        builder.append_dir_all("", folder_path).unwrap();
-        builder.finish().unwrap();
+        // Wait for file_ack
+        let fileack_msg = wormhole.receive_json().await??;
+        debug!("received file ack message: {:?}", fileack_msg);
 
-        std::mem::drop(builder);
-        std::mem::drop(hash_writer);
+        let resume = match fileack_msg {
+            PeerMessage::Answer(Answer::FileAck(msg)) => {
+                ensure!(msg == "ok", TransferError::AckError);
+                None
+            },
+            PeerMessage::Answer(Answer::FileAckResume { offset, sha256 }) => {
+                debug!("The receiver already has {} bytes", offset);
+                Some((offset, sha256))
+            },
+            PeerMessage::Error(err) => {
+                bail!(TransferError::PeerError(err));
+            },
+            PeerMessage::Cancel(reason) => {
+                bail!(TransferError::PeerCancelled(reason));
+            },
+            _ => {
+                let error = TransferError::unexpected_message("answer/file_ack", fileack_msg);
+                let _ = wormhole.send_json(&PeerMessage::error(&error)).await;
+                bail!(error)
+            },
+        };
 
-        std::io::Result::Ok(hasher.finalize_fixed())
-    });
+        Ok((their_abilities, their_hints, resume))
+    };
 
-    let checksum = match v1::send_records(&mut transit, &mut reader, length, progress_handler).await
-    {
-        Err(TransferError::Transit(error)) => {
-            let _ = wormhole
-                .send_json(&PeerMessage::Error(format!("{}", error)))
-                .await;
-            Err(TransferError::Transit(error))
+    let offer_timeout = match offer_timeout {
+        Some(offer_timeout) => offer_timeout,
+        None => return answer.await,
+    };
+    match async_std::future::timeout(offer_timeout, answer).await {
+        Ok(result) => result,
+        Err(_) => {
+            debug!("Offer expired after {:?}", offer_timeout);
+            let error = TransferError::OfferExpired;
+            let _ = wormhole.send_json(&PeerMessage::error(&error)).await;
+            Err(error)
         },
-        other => other,
-    }?;
-    /* This should always be ready by now, but just in case */
-    let sha256sum = file_sender.await.unwrap();
-
-    /* Check if the hash sum still matches what we advertized. Otherwise, tell the other side and bail out */
-    if sha256sum != sha256sum_initial {
-        let error = TransferError::FilesystemSkew;
-        let _ = wormhole
-            .send_json(&PeerMessage::Error(format!("{}", error)))
-            .await;
-        bail!(error)
     }
+}
 
-    // 13. wait for the transit ack with sha256 sum from the peer.
-    debug!("sent file. Waiting for ack");
-    let transit_ack = transit.receive_record().await?;
-    let transit_ack_msg = serde_json::from_slice::<TransitAck>(&transit_ack)?;
-    ensure!(
-        transit_ack_msg.sha256 == hex::encode(checksum),
-        TransferError::Checksum
-    );
-    debug!("Transfer complete!");
-    Ok(())
+/**
+ * Skip the data the receiver already has, if it asked to resume the transfer
+ *
+ * Returns where to continue, and the hash of everything before. If the data does not match
+ * what the receiver has, the transfer fails right away instead of after sending the rest.
+ */
+async fn skip_received(
+    wormhole: &mut Wormhole,
+    file: &mut (impl AsyncRead + Unpin),
+    file_size: u64,
+    resume: Option<(u64, [u8; 32])>,
+) -> Result<(u64, Sha256), TransferError> {
+    let (offset, sha256) = match resume {
+        Some(resume) => resume,
+        None => return Ok((0, Sha256::default())),
+    };
+    let result = async {
+        ensure!(
+            offset <= file_size,
+            TransferError::Protocol(
+                format!("Cannot resume at {} bytes of {}", offset, file_size).into_boxed_str()
+            )
+        );
+        let mut hasher = Sha256::default();
+        let mut buffer = vec![0u8; RECORD_SIZE];
+        let mut remaining = offset;
+        while remaining > 0 {
+            let to_read = std::cmp::min(remaining, buffer.len() as u64) as usize;
+            let read = file.read(&mut buffer[..to_read]).await?;
+            ensure!(read > 0, TransferError::FilesystemSkew);
+            hasher.update(&buffer[..read]);
+            remaining -= read as u64;
+        }
+        ensure!(
+            hasher.clone().finalize_fixed()[..] == sha256,
+            TransferError::Checksum
+        );
+        Ok(hasher)
+    };
+    match result.await {
+        Ok(hasher) => {
+            debug!("Resuming at {} bytes", offset);
+            Ok((offset, hasher))
+        },
+        Err(error) => {
+            let _ = wormhole.send_json(&PeerMessage::error(&error)).await;
+            Err(error)
+        },
+    }
 }
 
+/**
+ * Size of the blocks the file is read in, unless the record size is fixed
+ *
+ * The records that get sent consist of one or more blocks, see [`super::record_size`].
+ */
+const RECORD_SIZE: usize = super::record_size::MIN_RECORD_SIZE;
+
 // encrypt and send the file to tcp stream and return the sha256 sum
-// of the file before encryption.
+// of the file before encryption. When resuming, `hasher` has seen the first `offset` bytes.
+//
+// If `compress` is set, the records carry one zstd stream instead, and an empty record follows
+// the last one. Each record is flushed on its own, so that the receiver can keep up.
+// With a `record_size`, all records (but the last) have that size, otherwise it adapts to the connection.
 pub async fn send_records<F>(
     transit: &mut Transit,
     file: &mut (impl AsyncRead + Unpin),
     file_size: u64,
+    offset: u64,
+    hasher: Sha256,
+    compress: bool,
+    read_ahead: usize,
+    record_size: Option<usize>,
     mut progress_handler: F,
 ) -> Result<Vec<u8>, TransferError>
 where
     F: FnMut(u64, u64) + 'static,
 {
+    use futures::{SinkExt, StreamExt};
+
     // rough plan:
     // 1. Open the file
     // 2. read a block of N bytes
@@ -378,30 +607,85 @@ where
     // 7. if eof, return sha256 sum.
 
     // Report at 0 to allow clients to configure as necessary.
-    progress_handler(0, file_size);
-
-    let mut hasher = Sha256::default();
-
-    // Yeah, maybe don't allocate 4kiB on the stack…
-    let mut plaintext = Box::new([0u8; 4096]);
-    let mut sent_size = 0;
-    loop {
-        // read a block of up to 4096 bytes
-        let n = file.read(&mut plaintext[..]).await?;
-
-        // send the encrypted record
-        transit.send_record(&plaintext[0..n]).await?;
-        sent_size += n as u64;
-        progress_handler(sent_size, file_size);
-
-        // sha256 of the input
-        hasher.update(&plaintext[..n]);
-
-        if n < 4096 {
-            break;
+    progress_handler(offset, file_size);
+
+    let record_size = record_size.filter(|&size| size > 0);
+    let block_size = record_size.unwrap_or(RECORD_SIZE);
+
+    /* Read concurrently to sending, so that a slow source does not stall the connection (and vice versa) */
+    let (mut block_sender, mut blocks) =
+        futures::channel::mpsc::channel::<Vec<u8>>(read_ahead / block_size);
+    let reader = async move {
+        loop {
+            let block = read_block(file, block_size).await?;
+            let eof = block.len() < block_size;
+            if block_sender.send(block).await.is_err() || eof {
+                break;
+            }
         }
-    }
-    transit.flush().await?;
+        Result::<(), TransferError>::Ok(())
+    };
+    /* An empty file is sent as no records at all, like the Python implementation does */
+    let sender = async {
+        use std::io::Write;
+
+        let mut hasher = hasher;
+        let mut sent_size = offset;
+        let mut sizer = match record_size {
+            Some(size) => RecordSizer::fixed(size),
+            None => RecordSizer::new(transit.info().rtt),
+        };
+        let mut record = Vec::with_capacity(record_size.unwrap_or(MAX_RECORD_SIZE));
+        let mut encoder = match compress {
+            true => Some(zstd::stream::write::Encoder::new(Vec::new(), ZSTD_LEVEL)?),
+            false => None,
+        };
+        loop {
+            let block = blocks.next().await;
+            if let Some(block) = &block {
+                // sha256 of the input
+                hasher.update(block);
+                record.extend_from_slice(block);
+                if record.len() < sizer.record_size() {
+                    continue;
+                }
+            }
+            if record.is_empty() {
+                break;
+            }
+
+            // send the encrypted record
+            let start = std::time::Instant::now();
+            match &mut encoder {
+                Some(encoder) => {
+                    encoder.write_all(&record)?;
+                    encoder.flush()?;
+                    transit.send_record(encoder.get_ref()).await?;
+                    encoder.get_mut().clear();
+                },
+                None => transit.send_record(&record).await?,
+            }
+            sizer.update(record.len(), start.elapsed());
+            sent_size += record.len() as u64;
+            progress_handler(sent_size, file_size);
+            record.clear();
+
+            if block.is_none() {
+                break;
+            }
+        }
+        if let Some(encoder) = encoder {
+            let rest = encoder.finish()?;
+            if !rest.is_empty() {
+                transit.send_record(&rest).await?;
+            }
+            /* The end marker */
+            transit.send_record(&[]).await?;
+        }
+        transit.flush().await?;
+        Result::<_, TransferError>::Ok((hasher, sent_size))
+    };
+    let ((), (hasher, sent_size)) = futures::try_join!(reader, sender)?;
 
     ensure!(
         sent_size == file_size,
@@ -414,8 +698,33 @@ where
     Ok(hasher.finalize_fixed().to_vec())
 }
 
+/** Read a whole block of `size` bytes, only return less at the end of the file */
+async fn read_block(file: &mut (impl AsyncRead + Unpin), size: usize) -> std::io::Result<Vec<u8>> {
+    let mut block = vec![0u8; size];
+    let mut length = 0;
+    while length < size {
+        match file.read(&mut block[length..]).await? {
+            0 => break,
+            n => length += n,
+        }
+    }
+    block.truncate(length);
+    Ok(block)
+}
+
+/** How many received records may wait for being written */
+const WRITE_BEHIND: usize = 4;
+
+/**
+ * When resuming, `hasher` has seen the first `offset` bytes, which are not received again
+ *
+ * See [`send_records`] for what `compressed` means.
+ */
 pub async fn receive_records<F, W>(
     filesize: u64,
+    offset: u64,
+    mut hasher: Sha256,
+    compressed: bool,
     transit: &mut Transit,
     mut progress_handler: F,
     content_handler: &mut W,
@@ -424,41 +733,177 @@ where
     F: FnMut(u64, u64) + 'static,
     W: AsyncWrite + Unpin,
 {
-    let mut hasher = Sha256::default();
+    use futures::{SinkExt, StreamExt};
+
     let total = filesize;
 
-    let mut remaining_size = filesize as usize;
+    let mut received_size = offset;
 
     // Might not need to do this here, since `accept()` is where they'd know the filesize
     // already...
-    progress_handler(0, total);
-
-    while remaining_size > 0 {
-        // 3. decrypt the vector 'enc_packet' with the key.
-        let plaintext = transit.receive_record().await?;
+    progress_handler(received_size, total);
 
-        content_handler.write_all(&plaintext).await?;
-
-        // 4. calculate a rolling sha256 sum of the decrypted output.
-        hasher.update(&plaintext);
+    let mut decoder = match compressed {
+        true => Some(zstd::stream::write::Decoder::new(BoundedBuffer::new(
+            total.saturating_sub(offset),
+        ))?),
+        false => None,
+    };
+    /* Write concurrently to receiving, so that a slow disk does not stall the connection (and vice versa) */
+    let (mut block_sender, mut blocks) = futures::channel::mpsc::channel::<Box<[u8]>>(WRITE_BEHIND);
+    /* Empty files come without any records, unless compressed */
+    let receiver = async move {
+        /* The compressed record being decompressed, and how much of it is done */
+        let mut record = Box::<[u8]>::default();
+        let mut consumed = 0;
+        loop {
+            // 3. decrypt the vector 'enc_packet' with the key.
+            let plaintext = match &mut decoder {
+                None if received_size >= total => break,
+                None => transit.receive_record().await?,
+                Some(decoder) => {
+                    if consumed == record.len() {
+                        record = transit.receive_record().await?;
+                        consumed = 0;
+                        if record.is_empty() {
+                            break;
+                        }
+                    }
+                    match decompress(decoder, &record[consumed..]) {
+                        Ok(length) => consumed += length,
+                        Err(error) => {
+                            ensure!(
+                                !decoder.get_ref().overflowed,
+                                TransferError::Protocol(
+                                    "The compressed content is larger than the file".into()
+                                )
+                            );
+                            return Err(error.into());
+                        },
+                    }
+                    decoder.get_mut().take()
+                },
+            };
+
+            received_size += plaintext.len() as u64;
+            ensure!(
+                received_size <= total,
+                TransferError::FileSize {
+                    sent_size: received_size,
+                    file_size: total
+                }
+            );
+
+            if block_sender.send(plaintext).await.is_err() {
+                /* The writer failed, it will tell why */
+                break;
+            }
+        }
+        ensure!(
+            received_size == total,
+            TransferError::FileSize {
+                sent_size: received_size,
+                file_size: total
+            }
+        );
+        Result::<(), TransferError>::Ok(())
+    };
+    let writer = async {
+        let mut written_size = offset;
+        while let Some(plaintext) = blocks.next().await {
+            content_handler.write_all(&plaintext).await?;
 
-        remaining_size -= plaintext.len();
+            // 4. calculate a rolling sha256 sum of the decrypted output.
+            hasher.update(&plaintext);
 
-        let remaining = remaining_size as u64;
-        progress_handler(total - remaining, total);
-    }
+            written_size += plaintext.len() as u64;
+            progress_handler(written_size, total);
+        }
+        Result::<_, TransferError>::Ok(hasher)
+    };
+    let ((), hasher) = futures::try_join!(receiver, writer)?;
 
     debug!("done");
     // TODO: 5. write the buffer into a file.
     Ok(hasher.finalize_fixed().to_vec())
 }
 
+/**
+ * Where the zstd decoder puts its output, refusing more than the announced size of the file
+ *
+ * Otherwise, a small record could decompress to more than fits into the memory.
+ */
+pub(super) struct BoundedBuffer {
+    buffer: Vec<u8>,
+    /** How much more may come, including what's in the buffer */
+    remaining: u64,
+    pub(super) overflowed: bool,
+}
+
+impl BoundedBuffer {
+    pub(super) fn new(remaining: u64) -> Self {
+        BoundedBuffer {
+            buffer: Vec::new(),
+            remaining,
+            overflowed: false,
+        }
+    }
+
+    pub(super) fn take(&mut self) -> Box<[u8]> {
+        self.remaining -= self.buffer.len() as u64;
+        std::mem::take(&mut self.buffer).into_boxed_slice()
+    }
+}
+
+/**
+ * Decompress `input` until the output fills a record, and return how much of it got consumed
+ *
+ * Once all of `input` is consumed, the decoder gets flushed. Like this, a record that
+ * decompresses to a lot is handed on in pieces instead of all at once.
+ */
+pub(super) fn decompress(
+    decoder: &mut zstd::stream::write::Decoder<'static, BoundedBuffer>,
+    input: &[u8],
+) -> std::io::Result<usize> {
+    use std::io::Write;
+
+    let mut consumed = 0;
+    while consumed < input.len() && decoder.get_ref().buffer.len() < MAX_RECORD_SIZE {
+        consumed += decoder.write(&input[consumed..])?;
+    }
+    if consumed == input.len() {
+        decoder.flush()?;
+    }
+    Ok(consumed)
+}
+
+impl std::io::Write for BoundedBuffer {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        if (self.buffer.len() + data.len()) as u64 > self.remaining {
+            self.overflowed = true;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "The compressed content is larger than the file",
+            ));
+        }
+        self.buffer.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
 pub async fn tcp_file_receive<F, W>(
     transit: &mut Transit,
     filesize: u64,
+    offset: u64,
+    hasher: Sha256,
+    compressed: bool,
     progress_handler: F,
     content_handler: &mut W,
-) -> Result<(), TransferError>
+) -> Result<String, TransferError>
 where
     F: FnMut(u64, u64) + 'static,
     W: AsyncWrite + Unpin,
@@ -466,7 +911,16 @@ where
     // 5. receive encrypted records
     // now skey and rkey can be used. skey is used by the tx side, rkey is used
     // by the rx side for symmetric encryption.
-    let checksum = receive_records(filesize, transit, progress_handler, content_handler).await?;
+    let checksum = receive_records(
+        filesize,
+        offset,
+        hasher,
+        compressed,
+        transit,
+        progress_handler,
+        content_handler,
+    )
+    .await?;
 
     let sha256sum = hex::encode(checksum.as_slice());
     debug!("sha256 sum: {:?}", sha256sum);
@@ -479,5 +933,91 @@ where
     // 7. close socket.
     // well, no need, it gets dropped when it goes out of scope.
     debug!("Transfer complete");
-    Ok(())
+    Ok(sha256sum)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_decompression_bomb() {
+        let bomb = zstd::stream::encode_all(&[0u8; 1 << 20][..], ZSTD_LEVEL).unwrap();
+        assert!(bomb.len() < 1000);
+
+        let mut decoder = zstd::stream::write::Decoder::new(BoundedBuffer::new(1000)).unwrap();
+        assert!(decoder.write_all(&bomb).is_err());
+        assert!(decoder.get_ref().overflowed);
+
+        let mut decoder = zstd::stream::write::Decoder::new(BoundedBuffer::new(1 << 20)).unwrap();
+        decoder.write_all(&bomb).unwrap();
+        decoder.flush().unwrap();
+        assert_eq!(decoder.get_mut().take().len(), 1 << 20);
+        assert_eq!(decoder.get_ref().remaining, 0);
+    }
+
+    #[test]
+    fn test_decompress_in_pieces() {
+        /* Even when a large size is announced, a bomb is not decompressed all at once */
+        let bomb = zstd::stream::encode_all(&[0u8; 64 << 20][..], ZSTD_LEVEL).unwrap();
+        let mut decoder = zstd::stream::write::Decoder::new(BoundedBuffer::new(1 << 40)).unwrap();
+        let mut consumed = 0;
+        let mut decompressed = 0;
+        while consumed < bomb.len() {
+            consumed += decompress(&mut decoder, &bomb[consumed..]).unwrap();
+            let piece = decoder.get_mut().take();
+            assert!(piece.len() <= 2 * MAX_RECORD_SIZE + (128 << 10));
+            decompressed += piece.len();
+        }
+        assert_eq!(decompressed, 64 << 20);
+    }
+
+    #[async_std::test]
+    async fn test_write_tar() {
+        let dir =
+            std::env::temp_dir().join(format!("wormhole-test-write-tar-{}", std::process::id()));
+        let long_name = "x".repeat(150);
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("a.txt"), b"hello").unwrap();
+        std::fs::write(dir.join("sub").join(&long_name), vec![7; 1000]).unwrap();
+
+        let entries = folder::walk(&OsStorage, &dir, &FolderSendOptions::default(), true)
+            .await
+            .unwrap();
+        let mut tarred = Vec::new();
+        write_tar(
+            &mut futures::io::Cursor::new(&mut tarred),
+            &entries,
+            &OsStorage,
+        )
+        .await
+        .unwrap();
+
+        /* The same as packing it with the `tar` crate alone */
+        let mut builder = tar::Builder::new(Vec::new());
+        for entry in &entries {
+            let (entry_type, content) = match entry.kind {
+                folder::EntryKind::File { .. } => {
+                    (tar::EntryType::Regular, std::fs::read(&entry.path).unwrap())
+                },
+                _ => (tar::EntryType::Directory, Vec::new()),
+            };
+            let mut header = tar_header(entry_type, content.len() as u64, false);
+            builder
+                .append_data(&mut header, &entry.name, &content[..])
+                .unwrap();
+        }
+        assert_eq!(tarred, builder.into_inner().unwrap());
+
+        let mut archive = tar::Archive::new(&tarred[..]);
+        let names = archive
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().into_owned())
+            .collect::<Vec<_>>();
+        assert!(names.contains(&PathBuf::from("sub").join(&long_name)));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -1,115 +1,960 @@
-#![allow(dead_code)]
+//! Version 2 of the file transfer protocol
+//!
+//! Instead of a single file or an opaque archive, the sender offers a list of files (the
+//! "manifest", see [`OfferV2Entry`]) with their sizes and hashes. The content is sent as one
+//! tar archive of all files, compressed with zstd (the `tar.zst` format). The receiver can
+//! thus verify every file on its own.
+//!
+//! Both sides advertise support in their [`AppVersion`]. The sender then picks the protocol:
+//! it sends a [`PeerMessage::TransitV2`] instead of the v1 transit message, followed by
+//! an [`OfferV2`]. The receiver answers with its own `TransitV2` and an [`AnswerV2`],
+//! then the content follows over the transit connection. Its end is marked by an empty record,
+//! after which the receiver sends a [`TransitAck`] with the hash of the uncompressed archive.
+//!
+//! For compatibility with the existing receive API, an offer of a single file gets
+//! unpacked on the fly, so that only its content is received. Everything else is received as
+//! an (uncompressed) tar file, just like folders in version 1.
 
 use super::*;
+use futures::AsyncReadExt;
+use sha2::{digest::FixedOutput, Digest, Sha256};
+use std::io::Write;
 
-#[allow(unused_variables, unused_mut)]
-pub async fn send_file<F, N, H>(
-    mut wormhole: Wormhole,
+/** The only supported format so far: a tar archive, compressed with zstd */
+pub const FORMAT_TAR_ZST: &str = "tar.zst";
+
+/** The block size of tar archives: all headers and contents are padded to it */
+const BLOCK_SIZE: u64 = 512;
+/** Names longer than this don't fit into the header, and need a GNU long name entry */
+const MAX_NAME_LENGTH: usize = 100;
+/** The default of the zstd command line tool, a good trade-off between speed and size */
+const ZSTD_LEVEL: i32 = 3;
+/** How much to read at once, and the maximum size of the compressed records, unless configured otherwise */
+const CHUNK_SIZE: usize = record_size::MAX_RECORD_SIZE;
+
+/** A file to send: its manifest entry, and where it is in the [`Storage`] */
+struct SourceFile {
+    path: std::path::PathBuf,
+    entry: OfferV2Entry,
+}
+
+/**
+ * Offer a file or folder using version 2 of the protocol
+ *
+ * The peer must support it, as announced in its [`AppVersion`]. `transfer_name` is also the
+ * name of the file in the archive if `file_path` is a file.
+ * The files in a folder are named relative to it. Symbolic links and other special files are
+ * skipped, and so are empty folders.
+ */
+pub async fn send<H>(
+    wormhole: &mut Wormhole,
     relay_hints: Vec<transit::RelayHint>,
-    file: &mut F,
-    file_path: N,
-    file_name: String,
-    progress_handler: H,
+    file_path: PathBuf,
+    transfer_name: String,
+    options: SendOptions,
     peer_version: AppVersion,
-) -> Result<(), TransferError>
+    progress_handler: H,
+) -> Result<TransferResult, TransferError>
 where
-    F: AsyncRead + Unpin,
-    N: Into<PathBuf>,
     H: FnMut(u64, u64) + 'static,
 {
-    // let file_path = file_path.into();
-    // let peer_abilities = peer_version.transfer_v2.unwrap();
-    // let mut actual_transit_abilities = transit::Ability::all_abilities();
-    // actual_transit_abilities.retain(|a| peer_abilities.transit_abilities.contains(a));
-    // let connector = transit::init(actual_transit_abilities, Some(&peer_abilities.transit_abilities), relay_hints).await?;
-
-    // /* Send our transit hints */
-    // wormhole
-    // .send_json(
-    //     &PeerMessage::transit_v2(
-    //         (**connector.our_hints()).clone().into(),
-    //     ),
-    // )
-    // .await?;
-
-    // /* Receive their transit hints */
-    // let their_hints: transit::Hints =
-    //     match wormhole.receive_json().await?? {
-    //         PeerMessage::TransitV2(transit) => {
-    //             debug!("received transit message: {:?}", transit);
-    //             transit.hints_v2.into()
-    //         },
-    //         PeerMessage::Error(err) => {
-    //             bail!(TransferError::PeerError(err));
-    //         },
-    //         other => {
-    //             let error = TransferError::unexpected_message("transit-v2", other);
-    //             let _ = wormhole
-    //                 .send_json(&PeerMessage::Error(format!("{}", error)))
-    //                 .await;
-    //             bail!(error)
-    //         },
-    //     };
-
-    // /* Get a transit connection */
-    // let mut transit = match connector
-    //     .leader_connect(
-    //         wormhole.key().derive_transit_key(wormhole.appid()),
-    //         Arc::new(peer_abilities.transit_abilities),
-    //         Arc::new(their_hints),
-    //     )
-    //     .await
-    // {
-    //     Ok(transit) => transit,
-    //     Err(error) => {
-    //         let error = TransferError::TransitConnect(error);
-    //         let _ = wormhole
-    //             .send_json(&PeerMessage::Error(format!("{}", error)))
-    //             .await;
-    //         return Err(error);
-    //     },
-    // };
-
-    // /* Close the Wormhole and switch to using the transit connection (msgpack instead of json) */
-    // wormhole.close().await?;
-
-    // transit.send_record(&PeerMessage::OfferV2(OfferV2 {
-    //     transfer_name: None,
-    //     files: vec![],
-    //     format: "tar.zst".into(),
-    // }).ser_msgpack()).await?;
-
-    // match PeerMessage::de_msgpack(&transit.receive_record().await?)? {
-    //     PeerMessage::AnswerV2(answer) => {
-    //         // let files = answer.files;
-    //     },
-    //     PeerMessage::Error(err) => {
-    //         bail!(TransferError::PeerError(err));
-    //     },
-    //     other => {
-    //         let error = TransferError::unexpected_message("answer-v2", other);
-    //         let _ = transit
-    //             .send_record(&PeerMessage::Error(format!("{}", error)).ser_msgpack())
-    //             .await;
-    //         bail!(error)
-    //     },
-    // }
+    check_send_options(wormhole, &options).await?;
 
-    Ok(())
+    let their_abilities = peer_version
+        .transfer_v2
+        .as_ref()
+        .map(|hint| hint.transit_abilities)
+        .ok_or(TransferError::UnsupportedOffer)?;
+    let connector = transit::init(
+        transit::Abilities::ALL_ABILITIES,
+        Some(their_abilities),
+        relay_hints,
+        &options.transit_config,
+    )
+    .await?;
+
+    debug!("Sending transit message '{:?}", connector.our_hints());
+    wormhole
+        .send_json(&PeerMessage::transit_v2((**connector.our_hints()).clone()))
+        .await?;
+
+    let storage = options.storage.clone().unwrap_or_default();
+    let files = match collect_files(&*storage, file_path, &transfer_name, &options.folder).await {
+        Ok(files) => files,
+        Err(error) => {
+            let error = TransferError::IO(error);
+            let _ = wormhole.send_json(&PeerMessage::error(&error)).await;
+            bail!(error);
+        },
+    };
+
+    debug!("Sending file offer with {} files", files.len());
+    let offer = OfferV2 {
+        transfer_name: Some(transfer_name),
+        files: files.iter().map(|file| file.entry.clone()).collect(),
+        format: FORMAT_TAR_ZST.into(),
+        preview: options.preview.clone(),
+    };
+    let single_file = is_single_file(&offer);
+    wormhole.send_json(&PeerMessage::OfferV2(offer)).await?;
+
+    let (their_hints, answer) = receive_answer(wormhole, options.offer_timeout).await?;
+    let mut requested = Vec::new();
+    for (index, file) in files.into_iter().enumerate() {
+        match answer.files.get(&(index as u64)) {
+            Some(0) => requested.push(file),
+            Some(_) => {
+                let error =
+                    TransferError::Protocol("Resuming within a file is not supported yet".into());
+                let _ = wormhole.send_json(&PeerMessage::error(&error)).await;
+                bail!(error);
+            },
+            None => debug!("The receiver does not want '{}'", file.entry.path),
+        }
+    }
+
+    wormhole.events().emit(WormholeEvent::TransitConnecting);
+    let mut transit = match connector
+        .leader_connect(
+            wormhole.next_transit_key(),
+            their_abilities,
+            Arc::new(their_hints),
+        )
+        .await
+    {
+        Ok(transit) => transit,
+        Err(error) => {
+            let error = TransferError::TransitConnect(error);
+            let _ = wormhole.send_json(&PeerMessage::error(&error)).await;
+            return Err(error);
+        },
+    };
+    TransitHandler::call(options.transit_handler.as_ref(), &transit);
+    wormhole.events().emit(WormholeEvent::Transferring);
+    let started = std::time::Instant::now();
+
+    debug!("Beginning file transfer");
+    let chunk_size = options
+        .record_size
+        .filter(|&size| size > 0)
+        .unwrap_or(CHUNK_SIZE);
+    let checksum = match send_archive(
+        &mut transit,
+        &*storage,
+        &requested,
+        chunk_size,
+        progress_handler,
+    )
+    .await
+    {
+        Err(TransferError::Transit(error)) => Err(transit_failure(wormhole, error).await),
+        Err(error) => {
+            let _ = wormhole.send_json(&PeerMessage::error(&error)).await;
+            Err(error)
+        },
+        other => other,
+    }?;
+
+    debug!("sent files. Waiting for ack");
+    let transit_ack = match transit.receive_record().await {
+        Ok(transit_ack) => transit_ack,
+        Err(error) => bail!(transit_failure(wormhole, error).await),
+    };
+    let transit_ack_msg = serde_json::from_slice::<TransitAck>(&transit_ack)?;
+    ensure!(
+        transit_ack_msg.sha256 == hex::encode(checksum),
+        TransferError::Checksum
+    );
+    debug!("Transfer complete!");
+    /* Like the receiver, report a single file by its content and not by the archive around it */
+    let entries = requested
+        .iter()
+        .map(|file| file.entry.clone())
+        .collect::<Vec<_>>();
+    let (sha256, size) = match &entries[..] {
+        [entry] if single_file => (hex::encode(entry.sha256), entry.size),
+        _ => (transit_ack_msg.sha256, archive_size(&entries)?),
+    };
+    Ok(TransferResult::new(sha256, size, started, &transit))
 }
 
-#[allow(unused_variables)]
-pub async fn send_folder<N, M, H>(
-    wormhole: Wormhole,
-    relay_hints: Vec<transit::RelayHint>,
-    folder_path: N,
-    folder_name: M,
-    progress_handler: H,
-) -> Result<(), TransferError>
+/**
+ * Wait for the receiver's transit message and their answer to our offer
+ *
+ * Like in version 1, the offer gets withdrawn if the `offer_timeout` passes.
+ */
+async fn receive_answer(
+    wormhole: &mut Wormhole,
+    offer_timeout: Option<std::time::Duration>,
+) -> Result<(transit::Hints, AnswerV2), TransferError> {
+    let answer = async {
+        let their_hints = match wormhole.receive_json().await?? {
+            PeerMessage::TransitV2(transit) => {
+                debug!("received transit message: {:?}", transit);
+                transit.hints
+            },
+            PeerMessage::Error(err) => bail!(TransferError::PeerError(err)),
+            PeerMessage::Cancel(reason) => bail!(TransferError::PeerCancelled(reason)),
+            other => {
+                let error = TransferError::unexpected_message("transit-v2", other);
+                let _ = wormhole.send_json(&PeerMessage::error(&error)).await;
+                bail!(error)
+            },
+        };
+        let answer = match wormhole.receive_json().await?? {
+            PeerMessage::AnswerV2(answer) => answer,
+            PeerMessage::Error(err) => bail!(TransferError::PeerError(err)),
+            PeerMessage::Cancel(reason) => bail!(TransferError::PeerCancelled(reason)),
+            other => {
+                let error = TransferError::unexpected_message("answer-v2", other);
+                let _ = wormhole.send_json(&PeerMessage::error(&error)).await;
+                bail!(error)
+            },
+        };
+        Ok((their_hints, answer))
+    };
+
+    let offer_timeout = match offer_timeout {
+        Some(offer_timeout) => offer_timeout,
+        None => return answer.await,
+    };
+    match async_std::future::timeout(offer_timeout, answer).await {
+        Ok(result) => result,
+        Err(_) => {
+            debug!("Offer expired after {:?}", offer_timeout);
+            let error = TransferError::OfferExpired;
+            let _ = wormhole.send_json(&PeerMessage::error(&error)).await;
+            Err(error)
+        },
+    }
+}
+
+/**
+ * Build the manifest of a file, or of all files in a folder (sorted by path)
+ *
+ * Only regular files can be sent, so symlinks are never preserved as such.
+ */
+async fn collect_files(
+    storage: &dyn Storage,
+    path: std::path::PathBuf,
+    name: &str,
+    options: &FolderSendOptions,
+) -> std::io::Result<Vec<SourceFile>> {
+    let metadata = storage.metadata(&path).await?;
+    let sources = if metadata.is_dir() {
+        folder::walk(storage, &path, options, false)
+            .await?
+            .into_iter()
+            .filter_map(|entry| match entry.kind {
+                folder::EntryKind::File { size } => {
+                    Some((entry.path, entry.name, size, entry.metadata))
+                },
+                _ => None,
+            })
+            .map(|(path, name, size, metadata)| {
+                std::io::Result::Ok((path, entry_name(&name)?, size, metadata))
+            })
+            .collect::<Result<Vec<_>, _>>()?
+    } else {
+        let size = match metadata.entry_type {
+            StorageEntryType::File { size } => size,
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("'{}' is not a regular file", path.display()),
+                ))
+            },
+        };
+        vec![(path, name.to_owned(), size, metadata.file)]
+    };
+
+    let mut files = Vec::with_capacity(sources.len());
+    for (path, name, size, metadata) in sources {
+        let FileMetadata {
+            mtime,
+            executable,
+            mode,
+            ..
+        } = metadata;
+        files.push(SourceFile {
+            entry: OfferV2Entry {
+                mime_type: super::guess_mime_type(&name).map(Into::into),
+                path: name,
+                size,
+                mtime: mtime.unwrap_or(0),
+                executable,
+                mode,
+                sha256: file_sha256(storage, &path).await?,
+            },
+            path,
+        });
+    }
+    Ok(files)
+}
+
+/** The path of a file in the manifest, separated by `/` on all platforms */
+fn entry_name(name: &std::path::Path) -> std::io::Result<String> {
+    let components = name
+        .iter()
+        .map(|component| {
+            component.to_str().ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{:?} is not valid Unicode", component),
+                )
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(components.join("/"))
+}
+
+/**
+ * Send the files as compressed tar archive
+ *
+ * Returns the SHA-256 sum of the uncompressed archive, which the receiver will acknowledge.
+ */
+async fn send_archive<H>(
+    transit: &mut Transit,
+    storage: &dyn Storage,
+    files: &[SourceFile],
+    chunk_size: usize,
+    mut progress_handler: H,
+) -> Result<Vec<u8>, TransferError>
 where
-    N: Into<PathBuf>,
-    M: Into<PathBuf>,
     H: FnMut(u64, u64) + 'static,
 {
-    unimplemented!()
+    let total = total_size(files.iter().map(|file| file.entry.size))?;
+    let mut sent = 0;
+    progress_handler(sent, total);
+
+    let mut archive = ArchiveWriter {
+        encoder: zstd::stream::write::Encoder::new(Vec::new(), ZSTD_LEVEL)?,
+        hasher: Sha256::default(),
+        chunk_size,
+    };
+    let mut buffer = vec![0; chunk_size];
+    for file in files {
+        archive.write(transit, &tar_header(&file.entry)).await?;
+
+        /* The file may have changed since we hashed it, the receiver must not get that */
+        let mut hasher = Sha256::default();
+        let mut reader = storage.open(&file.path).await?.take(file.entry.size);
+        let mut read = 0;
+        loop {
+            let n = reader.read(&mut buffer).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buffer[..n]);
+            archive.write(transit, &buffer[..n]).await?;
+            read += n as u64;
+            sent += n as u64;
+            progress_handler(sent, total);
+        }
+        ensure!(
+            read == file.entry.size && hasher.finalize_fixed()[..] == file.entry.sha256,
+            TransferError::FilesystemSkew
+        );
+        archive
+            .write(transit, &vec![0; padding(file.entry.size)])
+            .await?;
+    }
+    archive
+        .write(transit, &[0; 2 * BLOCK_SIZE as usize])
+        .await?;
+
+    let ArchiveWriter {
+        encoder, hasher, ..
+    } = archive;
+    let rest = encoder.finish()?;
+    for record in rest.chunks(chunk_size) {
+        transit.send_record(record).await?;
+    }
+    /* The end marker */
+    transit.send_record(&[]).await?;
+    transit.flush().await?;
+    Ok(hasher.finalize_fixed().to_vec())
+}
+
+/** Compress the archive, and send it in records of up to `chunk_size` */
+struct ArchiveWriter {
+    encoder: zstd::stream::write::Encoder<'static, Vec<u8>>,
+    /** Of the uncompressed archive */
+    hasher: Sha256,
+    chunk_size: usize,
+}
+
+impl ArchiveWriter {
+    async fn write(&mut self, transit: &mut Transit, data: &[u8]) -> Result<(), TransferError> {
+        self.hasher.update(data);
+        self.encoder.write_all(data)?;
+        let compressed = self.encoder.get_mut();
+        if compressed.len() >= self.chunk_size {
+            for record in compressed.chunks(self.chunk_size) {
+                transit.send_record(record).await?;
+            }
+            compressed.clear();
+        }
+        Ok(())
+    }
+}
+
+/**
+ * The header blocks of a file in the archive
+ *
+ * Everything but the name, size, modification time and executable bit is fixed, so that the archive (and
+ * thus its size) can be predicted from the manifest.
+ */
+fn tar_header(entry: &OfferV2Entry) -> Vec<u8> {
+    let mut blocks = Vec::new();
+    let mut header = tar::Header::new_gnu();
+    header.set_mode(if entry.executable { 0o755 } else { 0o644 });
+    if entry.path.len() > MAX_NAME_LENGTH {
+        let mut long_name = header.clone();
+        long_name.as_old_mut().name[..13].copy_from_slice(b"././@LongLink");
+        long_name.set_entry_type(tar::EntryType::GNULongName);
+        long_name.set_mtime(0);
+        long_name.set_size(entry.path.len() as u64 + 1);
+        long_name.set_cksum();
+        blocks.extend_from_slice(long_name.as_bytes());
+        blocks.extend_from_slice(entry.path.as_bytes());
+        blocks.push(0);
+        blocks.resize(blocks.len() + padding(entry.path.len() as u64 + 1), 0);
+    }
+    let name = &entry.path.as_bytes()[..std::cmp::min(entry.path.len(), MAX_NAME_LENGTH)];
+    header.as_old_mut().name[..name.len()].copy_from_slice(name);
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_mtime(entry.mtime);
+    header.set_size(entry.size);
+    header.set_cksum();
+    blocks.extend_from_slice(header.as_bytes());
+    blocks
+}
+
+/** How many bytes are needed to pad `length` bytes to whole blocks */
+fn padding(length: u64) -> usize {
+    ((BLOCK_SIZE - length % BLOCK_SIZE) % BLOCK_SIZE) as usize
+}
+
+/** The size of the uncompressed archive of these files */
+fn archive_size<'a>(
+    files: impl IntoIterator<Item = &'a OfferV2Entry>,
+) -> Result<u64, TransferError> {
+    files
+        .into_iter()
+        .try_fold(2 * BLOCK_SIZE, |size, entry| {
+            size.checked_add(tar_header(entry).len() as u64 + padding(entry.size) as u64)?
+                .checked_add(entry.size)
+        })
+        .ok_or_else(too_large)
+}
+
+/** The sum of the file sizes, which the peer may have chosen to overflow */
+fn total_size(sizes: impl IntoIterator<Item = u64>) -> Result<u64, TransferError> {
+    sizes
+        .into_iter()
+        .try_fold(0u64, |total, size| total.checked_add(size))
+        .ok_or_else(too_large)
+}
+
+fn too_large() -> TransferError {
+    TransferError::Protocol("The files are too large in total".into())
+}
+
+/**
+ * Whether an offer consists of a single file, which gets unpacked while receiving it
+ *
+ * This is told apart from a folder with a single file by the file being named like the transfer.
+ */
+fn is_single_file(offer: &OfferV2) -> bool {
+    match &offer.files[..] {
+        [file] => offer.transfer_name.as_deref() == Some(&file.path),
+        _ => false,
+    }
+}
+
+/**
+ * The metadata of a single file, which gets unpacked. Otherwise, it is in the archive
+ *
+ * The preview applies to the whole offer.
+ */
+pub(super) fn offer_metadata(offer: &OfferV2) -> FileMetadata {
+    let preview = super::metadata::accept_preview(offer.preview.clone());
+    if !is_single_file(offer) {
+        return FileMetadata {
+            preview,
+            ..Default::default()
+        };
+    }
+    FileMetadata {
+        mtime: Some(offer.files[0].mtime),
+        executable: offer.files[0].executable,
+        mime_type: offer.files[0].mime_type.clone(),
+        mode: offer.files[0].mode,
+        preview,
+    }
+}
+
+/**
+ * Check an offer received in [`request_file`], and describe it as file offer
+ *
+ * Returns the file name, the size of what will be received, and what the archive contains
+ * unless it gets unpacked.
+ */
+pub(super) fn describe_offer(
+    offer: &OfferV2,
+) -> Result<(PathBuf, u64, Option<DirectoryInfo>), TransferError> {
+    ensure!(
+        offer.format == FORMAT_TAR_ZST,
+        TransferError::UnsupportedOffer
+    );
+    let name = if is_single_file(offer) {
+        offer.files[0].path.clone()
+    } else {
+        offer
+            .transfer_name
+            .clone()
+            .unwrap_or_else(|| "files.tar".into())
+    };
+    let (size, directory) = selection_size(offer, &answer_all(offer))?;
+    Ok((name.into(), size, directory))
+}
+
+/** The files of an offer that are asked for in the answer, in the order they get sent */
+fn selected_files<'a>(offer: &'a OfferV2, answer: &AnswerV2) -> Vec<&'a OfferV2Entry> {
+    offer
+        .files
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| answer.files.contains_key(&(*index as u64)))
+        .map(|(_, entry)| entry)
+        .collect()
+}
+
+/**
+ * The size of what will be received of an offer, and what the archive contains unless it
+ * gets unpacked
+ */
+pub(super) fn selection_size(
+    offer: &OfferV2,
+    answer: &AnswerV2,
+) -> Result<(u64, Option<DirectoryInfo>), TransferError> {
+    let files = selected_files(offer, answer);
+    let numbytes = total_size(files.iter().map(|file| file.size))?;
+    if is_single_file(offer) {
+        return Ok((numbytes, None));
+    }
+    let directory = DirectoryInfo {
+        numbytes,
+        numfiles: files.len() as u64,
+    };
+    Ok((archive_size(files)?, Some(directory)))
+}
+
+/** Ask for all files of an offer, from their start */
+pub(super) fn answer_all(offer: &OfferV2) -> AnswerV2 {
+    AnswerV2 {
+        files: (0..offer.files.len() as u64)
+            .map(|index| (index, 0))
+            .collect(),
+    }
+}
+
+/**
+ * Receive the files of an accepted offer, those in the `answer` (see [`answer_all`])
+ *
+ * For a single file, only its content is written to `content_handler`, otherwise the
+ * uncompressed archive. Either way, every file gets checked against the manifest. Returns the
+ * SHA-256 sum of what was written (hex encoded), like [`v1::tcp_file_receive`].
+ */
+pub(super) async fn receive_archive<F, W>(
+    transit: &mut Transit,
+    offer: &OfferV2,
+    answer: &AnswerV2,
+    mut progress_handler: F,
+    content_handler: &mut W,
+) -> Result<String, TransferError>
+where
+    F: FnMut(u64, u64) + 'static,
+    W: AsyncWrite + Unpin,
+{
+    use futures::AsyncWriteExt;
+
+    let single_file = is_single_file(offer);
+    let files = selected_files(offer, answer);
+    let (total, _) = selection_size(offer, answer)?;
+    let mut written = 0;
+    progress_handler(written, total);
+
+    let mut archive = ArchiveReader {
+        transit,
+        decoder: zstd::stream::write::Decoder::new(v1::BoundedBuffer::new(archive_size(
+            files.iter().copied(),
+        )?))?,
+        record: Box::default(),
+        consumed: 0,
+        buffer: Vec::new(),
+        position: 0,
+        finished: false,
+    };
+    let mut archive_hasher = Sha256::default();
+    let mut content_hasher = Sha256::default();
+    /* Everything but the content of a single file belongs to the archive */
+    macro_rules! archive_data {
+        ($data:expr) => {{
+            let data = $data;
+            archive_hasher.update(&data);
+            if !single_file {
+                content_handler.write_all(&data).await?;
+                content_hasher.update(&data);
+                written += data.len() as u64;
+                progress_handler(written, total);
+            }
+        }};
+    }
+
+    for entry in files {
+        let header = archive.read_exact(tar_header(entry).len()).await?;
+        ensure!(
+            header == tar_header(entry),
+            TransferError::Protocol(
+                format!("Unexpected tar header for '{}'", entry.path).into_boxed_str()
+            )
+        );
+        archive_data!(header);
+
+        let mut hasher = Sha256::default();
+        let mut remaining = entry.size;
+        while remaining > 0 {
+            let data = archive.read(remaining).await?;
+            remaining -= data.len() as u64;
+            hasher.update(&data);
+            if single_file {
+                archive_hasher.update(&data);
+                content_handler.write_all(&data).await?;
+                content_hasher.update(&data);
+                written += data.len() as u64;
+                progress_handler(written, total);
+            } else {
+                archive_data!(data);
+            }
+        }
+        let mut sha256 = [0; 32];
+        sha256.copy_from_slice(&hasher.finalize_fixed());
+        verify_entry(entry, &sha256)?;
+
+        archive_data!(archive.read_exact(padding(entry.size)).await?);
+    }
+    let end = archive.read_exact(2 * BLOCK_SIZE as usize).await?;
+    ensure!(
+        end.iter().all(|&byte| byte == 0),
+        TransferError::Protocol("Missing end of the tar archive".into())
+    );
+    archive_data!(end);
+    ensure!(
+        archive.at_end().await?,
+        TransferError::Protocol("Unexpected data after the tar archive".into())
+    );
+    content_handler.flush().await?;
+
+    let archive_sha256 = hex::encode(archive_hasher.finalize_fixed());
+    debug!("archive sha256 sum: {:?}", archive_sha256);
+    archive
+        .transit
+        .send_record(&TransitAck::new("ok", &archive_sha256).serialize_vec())
+        .await?;
+    debug!("Transfer complete");
+    Ok(hex::encode(content_hasher.finalize_fixed()))
+}
+
+/** Decompress the incoming records, and read the archive from them */
+struct ArchiveReader<'a> {
+    transit: &'a mut Transit,
+    /** Bounded by the size of the archive, so that a small record can't fill the memory */
+    decoder: zstd::stream::write::Decoder<'static, v1::BoundedBuffer>,
+    /** The record being decompressed, of which the first `consumed` bytes are done */
+    record: Box<[u8]>,
+    consumed: usize,
+    /** Decompressed data, of which everything before `position` has been read already */
+    buffer: Vec<u8>,
+    position: usize,
+    /** Whether the end marker has been received */
+    finished: bool,
+}
+
+impl ArchiveReader<'_> {
+    /** Returns `false` if there is no more data */
+    async fn fill(&mut self) -> Result<bool, TransferError> {
+        while self.position == self.buffer.len() {
+            if self.finished {
+                return Ok(false);
+            }
+            if self.consumed == self.record.len() {
+                self.record = self.transit.receive_record().await?;
+                self.consumed = 0;
+            }
+            if self.record.is_empty() {
+                self.finished = true;
+                if let Err(error) = self.decoder.flush() {
+                    return Err(self.decoder_error(error));
+                }
+            } else {
+                match v1::decompress(&mut self.decoder, &self.record[self.consumed..]) {
+                    Ok(length) => self.consumed += length,
+                    Err(error) => return Err(self.decoder_error(error)),
+                }
+            }
+            self.buffer = self.decoder.get_mut().take().into_vec();
+            self.position = 0;
+        }
+        Ok(true)
+    }
+
+    fn decoder_error(&self, error: std::io::Error) -> TransferError {
+        if self.decoder.get_ref().overflowed {
+            TransferError::Protocol("The compressed archive is larger than announced".into())
+        } else {
+            error.into()
+        }
+    }
+
+    /** Read up to `max` bytes, but at least one */
+    async fn read(&mut self, max: u64) -> Result<Vec<u8>, TransferError> {
+        ensure!(
+            self.fill().await?,
+            TransferError::Protocol("The tar archive ended prematurely".into())
+        );
+        let length = std::cmp::min(max, (self.buffer.len() - self.position) as u64) as usize;
+        let data = self.buffer[self.position..self.position + length].to_vec();
+        self.position += length;
+        Ok(data)
+    }
+
+    async fn read_exact(&mut self, length: usize) -> Result<Vec<u8>, TransferError> {
+        let mut data = Vec::with_capacity(length);
+        while data.len() < length {
+            data.extend(self.read((length - data.len()) as u64).await?);
+        }
+        Ok(data)
+    }
+
+    async fn at_end(&mut self) -> Result<bool, TransferError> {
+        Ok(!self.fill().await?)
+    }
+}
+
+/** Hash a file for its [`OfferV2Entry`] */
+async fn file_sha256(storage: &dyn Storage, path: &std::path::Path) -> std::io::Result<[u8; 32]> {
+    let mut file = storage.open(path).await?;
+    let mut hasher = Sha256::default();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(&hasher.finalize_fixed());
+    Ok(hash)
+}
+
+/**
+ * Decide which files of an offer we still need
+ *
+ * Files that already exist in `target_dir` with the same size and hash are skipped. All the
+ * others are requested from the start, resuming within a file is not supported (yet).
+ */
+pub(super) async fn files_to_receive(
+    storage: &dyn Storage,
+    offer: &OfferV2,
+    target_dir: &std::path::Path,
+) -> AnswerV2 {
+    let mut files = std::collections::HashMap::new();
+    for (index, entry) in offer.files.iter().enumerate() {
+        /* Don't let the sender probe for files outside of the target directory */
+        let path = std::path::Path::new(&entry.path);
+        let is_safe = path
+            .components()
+            .all(|component| matches!(component, std::path::Component::Normal(_)));
+        let existing = target_dir.join(path);
+        let identical = is_safe
+            && match storage.metadata(&existing).await {
+                Ok(metadata)
+                    if metadata.entry_type == StorageEntryType::File { size: entry.size } =>
+                {
+                    file_sha256(storage, &existing).await.ok() == Some(entry.sha256)
+                },
+                _ => false,
+            };
+        if identical {
+            debug!("Skipping '{}', we already have it", entry.path);
+        } else {
+            files.insert(index as u64, 0);
+        }
+    }
+    AnswerV2 { files }
+}
+
+/** Check a received file against its manifest entry */
+fn verify_entry(entry: &OfferV2Entry, sha256: &[u8; 32]) -> Result<(), TransferError> {
+    ensure!(&entry.sha256 == sha256, TransferError::Checksum);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[async_std::test]
+    async fn test_files_to_receive() {
+        let dir = std::env::temp_dir().join(format!(
+            "wormhole-test-files-to-receive-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("same.txt"), b"hello").unwrap();
+        std::fs::write(dir.join("changed.txt"), b"hellO").unwrap();
+
+        let hello = file_sha256(&OsStorage, &dir.join("same.txt"))
+            .await
+            .unwrap();
+        let entry = |path: &str| OfferV2Entry {
+            path: path.into(),
+            size: 5,
+            mtime: 0,
+            executable: false,
+            mime_type: None,
+            mode: None,
+            sha256: hello,
+        };
+        let offer = OfferV2 {
+            transfer_name: None,
+            files: vec![
+                entry("same.txt"),
+                entry("changed.txt"),
+                entry("missing.txt"),
+                entry("../same.txt"),
+            ],
+            format: "tar.zst".into(),
+            preview: None,
+        };
+
+        let answer = files_to_receive(&OsStorage, &offer, &dir).await;
+        let mut wanted = answer.files.keys().copied().collect::<Vec<_>>();
+        wanted.sort_unstable();
+        assert_eq!(wanted, vec![1, 2, 3]);
+
+        assert!(verify_entry(&offer.files[0], &hello).is_ok());
+        assert!(matches!(
+            verify_entry(&offer.files[0], &[0; 32]),
+            Err(TransferError::Checksum)
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_archive_layout() {
+        use std::io::Read;
+
+        let long_name = format!("{}/file.txt", "folder".repeat(20));
+        let files = vec![
+            ("hello.txt".to_string(), b"Hello, world!\n".to_vec()),
+            (long_name, vec![42; 1000]),
+            ("empty".to_string(), vec![]),
+        ];
+        let entries = files
+            .iter()
+            .map(|(path, content)| OfferV2Entry {
+                path: path.clone(),
+                size: content.len() as u64,
+                mtime: 1_600_000_000,
+                executable: content.is_empty(),
+                mime_type: None,
+                mode: None,
+                sha256: [0; 32],
+            })
+            .collect::<Vec<_>>();
+
+        let mut archive = Vec::new();
+        for (entry, (_, content)) in entries.iter().zip(&files) {
+            archive.extend(tar_header(entry));
+            archive.extend(content);
+            archive.extend(vec![0; padding(entry.size)]);
+        }
+        archive.extend(vec![0; 2 * BLOCK_SIZE as usize]);
+        assert_eq!(archive.len() as u64, archive_size(&entries).unwrap());
+
+        let mut tar = tar::Archive::new(&archive[..]);
+        let mut unpacked = tar.entries().unwrap().map(|entry| {
+            let mut entry = entry.unwrap();
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content).unwrap();
+            assert_eq!(entry.header().mtime().unwrap(), 1_600_000_000);
+            let mode = if content.is_empty() { 0o755 } else { 0o644 };
+            assert_eq!(entry.header().mode().unwrap(), mode);
+            (entry.path().unwrap().to_str().unwrap().to_string(), content)
+        });
+        for file in &files {
+            assert_eq!(unpacked.next().as_ref(), Some(file));
+        }
+        assert!(unpacked.next().is_none());
+    }
+
+    #[test]
+    fn test_describe_offer() {
+        let entry = |path: &str| OfferV2Entry {
+            path: path.into(),
+            size: 5,
+            mtime: 0,
+            executable: false,
+            mime_type: None,
+            mode: None,
+            sha256: [0; 32],
+        };
+        let mut offer = OfferV2 {
+            transfer_name: Some("file.txt".into()),
+            files: vec![entry("file.txt")],
+            format: FORMAT_TAR_ZST.into(),
+            preview: None,
+        };
+        let (name, size, directory) = describe_offer(&offer).unwrap();
+        assert_eq!(name, PathBuf::from("file.txt"));
+        assert_eq!(size, 5);
+        assert_eq!(directory, None);
+
+        /* A folder containing a single file */
+        offer.transfer_name = Some("folder".into());
+        let (name, size, directory) = describe_offer(&offer).unwrap();
+        assert_eq!(name, PathBuf::from("folder"));
+        assert_eq!(size, archive_size(&offer.files).unwrap());
+        assert_eq!(
+            directory,
+            Some(DirectoryInfo {
+                numbytes: 5,
+                numfiles: 1
+            })
+        );
+        assert_eq!(answer_all(&offer).files.get(&0), Some(&0));
+
+        offer.format = "tar.zst+dict".into();
+        assert!(matches!(
+            describe_offer(&offer),
+            Err(TransferError::UnsupportedOffer)
+        ));
+
+        /* Sizes that add up to more than fits into a u64 */
+        offer.format = FORMAT_TAR_ZST.into();
+        offer.files = vec![entry("a"), entry("b")];
+        for file in &mut offer.files {
+            file.size = u64::MAX / 2 + 1;
+        }
+        assert!(matches!(
+            describe_offer(&offer),
+            Err(TransferError::Protocol(_))
+        ));
+        assert!(archive_size(&offer.files).is_err());
+    }
 }
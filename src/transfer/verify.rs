@@ -0,0 +1,59 @@
+//! Letting the user compare the verifiers before anything gets transferred
+//!
+//! Both sides of a wormhole derive the same [verifier](crate::Wormhole::verifier) from the shared
+//! key. If the users compare it by other means (reading it out over the phone, for example) and
+//! it matches, nobody can be in the middle. Set a [`VerifierCheck`] in the
+//! [`SendOptions`](super::SendOptions) or [`ReceiveOptions`](super::ReceiveOptions) to ask them
+//! before any file data flows.
+
+use futures::future::BoxFuture;
+use std::sync::Arc;
+
+/**
+ * A hook that gets the hex encoded verifier, and decides whether to continue
+ *
+ * If it returns `false`, the other side gets told and the transfer fails with
+ * [`TransferError::Cancelled`](super::TransferError::Cancelled).
+ */
+#[derive(Clone)]
+pub struct VerifierCheck(Arc<dyn Fn(String) -> BoxFuture<'static, bool> + Send + Sync>);
+
+impl VerifierCheck {
+    pub fn new(check: impl Fn(String) -> BoxFuture<'static, bool> + Send + Sync + 'static) -> Self {
+        VerifierCheck(Arc::new(check))
+    }
+
+    pub(super) async fn check(&self, verifier: &xsalsa20poly1305::Key) -> bool {
+        (self.0)(hex::encode(verifier)).await
+    }
+}
+
+impl std::fmt::Debug for VerifierCheck {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt.write_str("VerifierCheck")
+    }
+}
+
+/* Two checks are only equal if they are the same instance */
+impl PartialEq for VerifierCheck {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for VerifierCheck {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[async_std::test]
+    async fn test_check() {
+        let check = VerifierCheck::new(|verifier| {
+            Box::pin(async move { verifier.starts_with("0101") && verifier.len() == 64 })
+        });
+        assert!(check.check(&[1; 32].into()).await);
+        assert!(!check.check(&[2; 32].into()).await);
+        assert_eq!(check, check.clone());
+    }
+}
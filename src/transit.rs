@@ -13,7 +13,7 @@
 //! **Notice:** while the resulting TCP connection is naturally bi-directional, the handshake is not symmetric. There *must* be one
 //! "leader" side and one "follower" side (formerly called "sender" and "receiver").
 
-use crate::{Key, KeyPurpose};
+use crate::{proxy::Proxy, resolver::Resolver, Key, KeyPurpose};
 use serde_derive::{Deserialize, Serialize};
 
 use async_std::{
@@ -25,7 +25,35 @@ use futures::{future::TryFutureExt, Sink, SinkExt, Stream, StreamExt, TryStreamE
 use log::*;
 use std::{collections::HashSet, sync::Arc};
 use xsalsa20poly1305 as secretbox;
-use xsalsa20poly1305::aead::{Aead, NewAead};
+use xsalsa20poly1305::aead::{generic_array::GenericArray, AeadInPlace, NewAead};
+
+#[cfg(feature = "noise")]
+mod noise;
+mod parallel;
+#[cfg(feature = "port-mapping")]
+mod port_mapping;
+#[cfg(feature = "quic")]
+mod quic;
+mod rate_limit;
+pub use rate_limit::RateLimit;
+#[cfg(feature = "relay-server")]
+pub mod relay_server;
+mod stats;
+pub use stats::{StatsCallback, TransitStats};
+mod stream;
+pub use stream::TransitStream;
+mod websocket;
+
+#[cfg(feature = "quic")]
+type QuicEndpoint = quinn::Endpoint;
+/* Without the feature, there never is an endpoint */
+#[cfg(not(feature = "quic"))]
+enum QuicEndpoint {}
+
+#[cfg(feature = "port-mapping")]
+use port_mapping::PortMapping;
+#[cfg(not(feature = "port-mapping"))]
+enum PortMapping {}
 
 /// ULR to a default hosted relay server. Please don't abuse or DOS.
 pub const DEFAULT_RELAY_SERVER: &str = "tcp://transit.magic-wormhole.io:4001";
@@ -58,6 +86,13 @@ pub enum TransitConnectError {
         #[source]
         std::io::Error,
     ),
+    /** Exchanging the hints failed, see [`connect`] */
+    #[error("Wormhole connection error")]
+    Wormhole(
+        #[from]
+        #[source]
+        crate::WormholeError,
+    ),
 }
 
 /// Private, because we try multiple handshakes and only
@@ -98,12 +133,363 @@ pub enum TransitError {
     ),
 }
 
+/**
+ * Local settings for establishing transit connections
+ *
+ * Unlike the [`Abilities`] and [`Hints`], these don't get negotiated with the peer.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TransitConfig {
+    /**
+     * Ports to try for listening to incoming direct connections, in order.
+     *
+     * By default (`None`), an ephemeral port is used. Set this if you want to
+     * forward the port on your router or open it in your firewall.
+     */
+    pub listen_ports: Option<std::ops::RangeInclusive<u16>>,
+    /** Which kinds of connections may be used at all */
+    pub policy: ConnectionPolicy,
+    /**
+     * Never open a listening socket, for environments where that triggers security prompts
+     *
+     * Direct connections can still be made by dialing out, which also gets through most NATs
+     * (via TCP simultaneous open). `listen_ports` is ignored when this is set.
+     */
+    pub no_listen: bool,
+    /**
+     * Ask the router to forward a port to our listener, with UPnP or NAT-PMP
+     *
+     * The forwarded address gets added to our hints, so that the peer can reach us directly
+     * even if we are behind a NAT. The mapping is removed again once the connection is
+     * established. This needs the `port-mapping` feature, without it the option is ignored.
+     */
+    pub port_mapping: bool,
+    /**
+     * Look up the host names of relays, direct hints and the STUN server with this
+     * instead of the system resolver
+     */
+    pub resolver: Option<Resolver>,
+    /**
+     * Connect to the relays through this proxy
+     *
+     * Direct connections don't use it. If it is a Tor proxy, only the relay is used. Without it,
+     * relays with WebSocket URLs are reached through the proxy from the [environment](Proxy::from_env).
+     */
+    pub proxy: Option<Proxy>,
+    /**
+     * The cipher we'd like to use for the records
+     *
+     * This is only a preference that gets advertised to the peer, see [`RecordCipher`]
+     * for how the cipher is chosen.
+     */
+    pub record_cipher: RecordCipher,
+    /** Limit the bandwidth used by the connection, see [`RateLimit`] */
+    pub rate_limit: Option<RateLimit>,
+    /**
+     * Stripe the records over this many connections through the relay, if the peer supports it
+     *
+     * See [`Abilities::parallel_v1`]. Zero and one mean a single connection, which is the default.
+     * At most [`MAX_PARALLEL_CONNECTIONS`] are used.
+     */
+    pub parallel_connections: u16,
+    /** Report the [`TransitStats`] of the connection periodically */
+    pub stats_callback: Option<StatsCallback>,
+    /** How long to wait for the peer and the relays, see [`TransitTimeouts`] */
+    pub timeouts: TransitTimeouts,
+}
+
+impl TransitConfig {
+    /** Listen on a fixed port */
+    pub fn listen_port(self, port: u16) -> Self {
+        self.listen_ports(port..=port)
+    }
+
+    /** Listen on the first free port in a range */
+    pub fn listen_ports(mut self, ports: std::ops::RangeInclusive<u16>) -> Self {
+        self.listen_ports = Some(ports);
+        self
+    }
+
+    pub fn policy(mut self, policy: ConnectionPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn no_listen(mut self, no_listen: bool) -> Self {
+        self.no_listen = no_listen;
+        self
+    }
+
+    pub fn port_mapping(mut self, port_mapping: bool) -> Self {
+        self.port_mapping = port_mapping;
+        self
+    }
+
+    pub fn resolver(mut self, resolver: Resolver) -> Self {
+        self.resolver = Some(resolver);
+        self
+    }
+
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    pub fn record_cipher(mut self, record_cipher: RecordCipher) -> Self {
+        self.record_cipher = record_cipher;
+        self
+    }
+
+    pub fn rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.rate_limit = Some(rate_limit);
+        self
+    }
+
+    pub fn parallel_connections(mut self, parallel_connections: u16) -> Self {
+        self.parallel_connections = parallel_connections;
+        self
+    }
+
+    pub fn stats_callback(mut self, stats_callback: StatsCallback) -> Self {
+        self.stats_callback = Some(stats_callback);
+        self
+    }
+
+    pub fn timeouts(mut self, timeouts: TransitTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+}
+
+/**
+ * The timeouts used while racing the direct and relay connections to the peer
+ *
+ * The defaults work well on most networks. Raise them on slow or high-latency links,
+ * where connecting may otherwise fail with [`TransitConnectError::Handshake`].
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TransitTimeouts {
+    /**
+     * How long to try connecting to the peer in total, including the transit handshake
+     *
+     * Defaults to 60 seconds.
+     */
+    pub connect: std::time::Duration,
+    /**
+     * How long to try each of the hints, until there is a connection to the peer or the relay
+     *
+     * Direct hints get retried during that time, because of NATs (see TCP simultaneous open).
+     * Relays get a single attempt. Defaults to 10 seconds.
+     */
+    pub dial: std::time::Duration,
+    /**
+     * How much later relays are tried than the ones with the next higher priority
+     *
+     * The relays with the highest priority are tried right away. Defaults to 3 seconds.
+     */
+    pub relay_fallback_delay: std::time::Duration,
+}
+
+impl Default for TransitTimeouts {
+    fn default() -> Self {
+        TransitTimeouts {
+            connect: std::time::Duration::from_secs(60),
+            dial: std::time::Duration::from_secs(10),
+            relay_fallback_delay: std::time::Duration::from_secs(3),
+        }
+    }
+}
+
+impl TransitTimeouts {
+    pub fn connect(mut self, connect: std::time::Duration) -> Self {
+        self.connect = connect;
+        self
+    }
+
+    pub fn dial(mut self, dial: std::time::Duration) -> Self {
+        self.dial = dial;
+        self
+    }
+
+    pub fn relay_fallback_delay(mut self, relay_fallback_delay: std::time::Duration) -> Self {
+        self.relay_fallback_delay = relay_fallback_delay;
+        self
+    }
+}
+
+/**
+ * The authenticated encryption used for the records of a [`Transit`] connection
+ *
+ * Both ciphers use 256 bit keys and 192 bit nonces, so the framing on the wire is the same.
+ * XSalsa20-Poly1305 is what all clients speak. XChaCha20-Poly1305 is used if both sides
+ * support the `record-cipher-v1` ability and at least one of them prefers it. It is usually
+ * the cheaper one on devices without fast vector units, so these should ask for it.
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum RecordCipher {
+    #[serde(rename = "xsalsa20-poly1305")]
+    XSalsa20Poly1305,
+    #[serde(rename = "xchacha20-poly1305")]
+    XChaCha20Poly1305,
+}
+
+impl Default for RecordCipher {
+    fn default() -> Self {
+        RecordCipher::XSalsa20Poly1305
+    }
+}
+
+/** The size of the authentication tag of both ciphers */
+const TAG_SIZE: usize = 16;
+
+/**
+ * The largest plaintext of a record
+ *
+ * Longer records get rejected, so that the other side can't make us allocate arbitrary amounts
+ * of memory. File transfers and port forwarding put at most 64 KiB of data into a record, this
+ * leaves room for their framing.
+ */
+pub const MAX_RECORD_SIZE: usize = 65 * 1024;
+/** The largest record on the wire, without the length in front of it */
+const MAX_SEALED_RECORD_SIZE: usize = secretbox::NONCE_SIZE + TAG_SIZE + MAX_RECORD_SIZE;
+
+impl RecordCipher {
+    /**
+     * Encrypt a record, and frame it like it goes over the wire: length, nonce and ciphertext
+     *
+     * Everything ends up in a single buffer, so that the record can be sent with a single write.
+     */
+    fn seal(self, key: &[u8], nonce: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, TransitError> {
+        let length = nonce.len() + TAG_SIZE + plaintext.len();
+        let mut record = Vec::with_capacity(4 + length);
+        record.extend_from_slice(&(length as u32).to_be_bytes());
+        record.extend_from_slice(nonce);
+        let start = record.len();
+        match self {
+            RecordCipher::XSalsa20Poly1305 => {
+                /* NaCl's secretbox puts the tag in front of the ciphertext */
+                record.extend_from_slice(&[0; TAG_SIZE]);
+                record.extend_from_slice(plaintext);
+                let tag = secretbox::XSalsa20Poly1305::new(secretbox::Key::from_slice(key))
+                    .encrypt_in_place_detached(
+                        secretbox::Nonce::from_slice(nonce),
+                        b"",
+                        &mut record[start + TAG_SIZE..],
+                    )
+                    .map_err(|_| TransitError::Crypto)?;
+                record[start..start + TAG_SIZE].copy_from_slice(&tag);
+            },
+            RecordCipher::XChaCha20Poly1305 => {
+                record.extend_from_slice(plaintext);
+                let tag = chacha20poly1305::XChaCha20Poly1305::new(
+                    chacha20poly1305::Key::from_slice(key),
+                )
+                .encrypt_in_place_detached(
+                    chacha20poly1305::XNonce::from_slice(nonce),
+                    b"",
+                    &mut record[start..],
+                )
+                .map_err(|_| TransitError::Crypto)?;
+                record.extend_from_slice(&tag);
+            },
+        }
+        Ok(record)
+    }
+
+    /** Decrypt a record (without its length, starting with the nonce) in place */
+    fn open(self, key: &[u8], mut record: Vec<u8>) -> Result<Vec<u8>, TransitError> {
+        ensure!(
+            record.len() >= secretbox::NONCE_SIZE + TAG_SIZE,
+            TransitError::Crypto
+        );
+        let (nonce, ciphertext) = record.split_at_mut(secretbox::NONCE_SIZE);
+        let plaintext = match self {
+            RecordCipher::XSalsa20Poly1305 => {
+                let (tag, ciphertext) = ciphertext.split_at_mut(TAG_SIZE);
+                secretbox::XSalsa20Poly1305::new(secretbox::Key::from_slice(key))
+                    .decrypt_in_place_detached(
+                        secretbox::Nonce::from_slice(nonce),
+                        b"",
+                        ciphertext,
+                        GenericArray::from_slice(tag),
+                    )
+                    .map(|()| secretbox::NONCE_SIZE + TAG_SIZE..record.len())
+            },
+            RecordCipher::XChaCha20Poly1305 => {
+                let (ciphertext, tag) = ciphertext.split_at_mut(ciphertext.len() - TAG_SIZE);
+                chacha20poly1305::XChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(key))
+                    .decrypt_in_place_detached(
+                        chacha20poly1305::XNonce::from_slice(nonce),
+                        b"",
+                        ciphertext,
+                        GenericArray::from_slice(tag),
+                    )
+                    .map(|()| secretbox::NONCE_SIZE..record.len() - TAG_SIZE)
+            },
+        }
+        .map_err(|_| TransitError::Crypto)?;
+        record.truncate(plaintext.end);
+        record.drain(..plaintext.start);
+        Ok(record)
+    }
+}
+
+/**
+ * Restrict the kinds of connections that may be used, for privacy reasons
+ *
+ * This is enforced locally, regardless of what the peer or the application protocol
+ * would like to use. If the peer does not support any of the allowed kinds,
+ * connecting fails with [`TransitConnectError::Protocol`].
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionPolicy {
+    /** Prefer direct connections, but fall back to a relay server */
+    Any,
+    /** Never route the (encrypted) traffic through a third party */
+    DirectOnly,
+    /** Never connect directly, so that the peer does not learn our IP addresses */
+    RelayOnly,
+}
+
+impl ConnectionPolicy {
+    /** The abilities that are allowed by this policy */
+    pub fn abilities(self) -> Abilities {
+        match self {
+            ConnectionPolicy::Any => Abilities::ALL_ABILITIES,
+            ConnectionPolicy::DirectOnly => Abilities::FORCE_DIRECT,
+            ConnectionPolicy::RelayOnly => Abilities::FORCE_RELAY,
+        }
+    }
+}
+
+impl Default for ConnectionPolicy {
+    fn default() -> Self {
+        ConnectionPolicy::Any
+    }
+}
+
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct RelayAbility {
     pub url_hints: bool,
 }
 
+/** Support for choosing the [`RecordCipher`], and which one we would like to use */
+#[derive(Copy, Clone, Debug, Default)]
+pub struct RecordCipherAbility {
+    pub preferred: RecordCipher,
+}
+
+/** The most relay connections the records are striped over, whatever both sides ask for */
+pub const MAX_PARALLEL_CONNECTIONS: u16 = 16;
+
+/** Support for striping the records over several relay connections, and up to how many */
+#[derive(Copy, Clone, Debug)]
+pub struct ParallelAbility {
+    pub connections: u16,
+}
+
 impl Default for RelayAbility {
     fn default() -> Self {
         Self { url_hints: true }
@@ -118,13 +504,51 @@ impl Default for RelayAbility {
 #[derive(Copy, Clone, Debug)]
 pub struct Abilities {
     pub direct_tcp_v1: bool,
+    /**
+     * Connect directly over QUIC, which gets through more NATs than TCP
+     *
+     * These connections are preferred over TCP ones. This needs the `quic` feature, without
+     * it the ability is never used.
+     */
+    pub direct_quic_v1: bool,
     pub relay_v1: Option<RelayAbility>,
+    pub record_cipher_v1: Option<RecordCipherAbility>,
+    /**
+     * Connect to relays over WebSockets, at their `ws://` and `wss://` URLs
+     *
+     * This is only useful together with `relay_v1`. The WebSocket URLs are tried after the TCP
+     * ones of the same relay.
+     */
+    pub websocket_v1: bool,
+    /**
+     * Open several connections through the relay, and send the records over each of them in turns
+     *
+     * A single TCP connection leaves most of the bandwidth unused on paths with a high latency.
+     * This is only used for relayed connections, with the lower number of connections of both
+     * sides. Set it with [`TransitConfig::parallel_connections`].
+     */
+    pub parallel_v1: Option<ParallelAbility>,
+    /**
+     * Get the record keys from a Noise handshake, for forward secrecy
+     *
+     * Otherwise, they are derived from the transit key, so that anyone who gets hold of it can
+     * decrypt a recorded connection. This needs the `noise` feature, without it the ability is
+     * never used.
+     */
+    pub noise_v1: bool,
 }
 
 impl Abilities {
     pub const ALL_ABILITIES: Self = Self {
         direct_tcp_v1: true,
+        direct_quic_v1: cfg!(feature = "quic"),
         relay_v1: Some(RelayAbility { url_hints: true }),
+        record_cipher_v1: Some(RecordCipherAbility {
+            preferred: RecordCipher::XSalsa20Poly1305,
+        }),
+        websocket_v1: true,
+        parallel_v1: None,
+        noise_v1: cfg!(feature = "noise"),
     };
 
     /**
@@ -135,7 +559,14 @@ impl Abilities {
      */
     pub const FORCE_DIRECT: Self = Self {
         direct_tcp_v1: true,
+        direct_quic_v1: cfg!(feature = "quic"),
         relay_v1: None,
+        record_cipher_v1: Some(RecordCipherAbility {
+            preferred: RecordCipher::XSalsa20Poly1305,
+        }),
+        websocket_v1: false,
+        parallel_v1: None,
+        noise_v1: cfg!(feature = "noise"),
     };
 
     /**
@@ -143,16 +574,23 @@ impl Abilities {
      *
      * If the other side forces a the usage of a direct connection the attempt will fail.
      * Note that the other side might control the relay server being used, if you really
-     * don't want your IP to potentially be disclosed use TOR instead (not supported by
-     * the Rust implementation yet).
+     * don't want your IP to potentially be disclosed use Tor instead (see
+     * [`TransitConfig::proxy`]).
      */
     pub const FORCE_RELAY: Self = Self {
         direct_tcp_v1: false,
+        direct_quic_v1: false,
         relay_v1: Some(RelayAbility { url_hints: true }),
+        record_cipher_v1: Some(RecordCipherAbility {
+            preferred: RecordCipher::XSalsa20Poly1305,
+        }),
+        websocket_v1: true,
+        parallel_v1: None,
+        noise_v1: cfg!(feature = "noise"),
     };
 
     pub fn can_direct(&self) -> bool {
-        self.direct_tcp_v1
+        self.direct_tcp_v1 || self.direct_quic_v1
     }
 
     pub fn can_relay(&self) -> bool {
@@ -162,6 +600,7 @@ impl Abilities {
     /** Keep only abilities that both sides support */
     pub fn intersect(mut self, other: &Self) -> Self {
         self.direct_tcp_v1 &= other.direct_tcp_v1;
+        self.direct_quic_v1 &= other.direct_quic_v1;
         self.relay_v1 = match (self.relay_v1, other.relay_v1) {
             (Some(RelayAbility { url_hints: true }), Some(RelayAbility { url_hints: true })) => {
                 Some(RelayAbility { url_hints: true })
@@ -169,15 +608,63 @@ impl Abilities {
             (Some(_), Some(_)) => Some(RelayAbility { url_hints: false }),
             _ => None,
         };
+        if other.record_cipher_v1.is_none() {
+            self.record_cipher_v1 = None;
+        }
+        self.websocket_v1 &= other.websocket_v1;
+        self.parallel_v1 = match (self.parallel_v1, other.parallel_v1) {
+            (Some(ours), Some(theirs)) => Some(ParallelAbility {
+                connections: ours.connections.min(theirs.connections),
+            }),
+            _ => None,
+        };
+        self.noise_v1 &= other.noise_v1;
         self
     }
+
+    /**
+     * The record cipher to use with a peer that has these abilities
+     *
+     * This is symmetric, so both sides will come to the same conclusion.
+     */
+    pub fn record_cipher(&self, their_abilities: &Self) -> RecordCipher {
+        match (self.record_cipher_v1, their_abilities.record_cipher_v1) {
+            (Some(ours), Some(theirs))
+                if ours.preferred == RecordCipher::XChaCha20Poly1305
+                    || theirs.preferred == RecordCipher::XChaCha20Poly1305 =>
+            {
+                RecordCipher::XChaCha20Poly1305
+            },
+            _ => RecordCipher::XSalsa20Poly1305,
+        }
+    }
+
+    /**
+     * Over how many relay connections to stripe the records, with a peer that has these abilities
+     *
+     * This is symmetric as well, and at most [`MAX_PARALLEL_CONNECTIONS`].
+     */
+    pub fn parallel_connections(&self, their_abilities: &Self) -> u16 {
+        match (self.parallel_v1, their_abilities.parallel_v1) {
+            (Some(ours), Some(theirs)) => ours
+                .connections
+                .min(theirs.connections)
+                .clamp(1, MAX_PARALLEL_CONNECTIONS),
+            _ => 1,
+        }
+    }
 }
 
 impl Default for Abilities {
     fn default() -> Self {
         Self {
             direct_tcp_v1: false,
+            direct_quic_v1: false,
             relay_v1: None,
+            record_cipher_v1: None,
+            websocket_v1: false,
+            parallel_v1: None,
+            noise_v1: false,
         }
     }
 }
@@ -193,12 +680,39 @@ impl serde::Serialize for Abilities {
                 "type": "direct-tcp-v1",
             }));
         }
+        if self.direct_quic_v1 {
+            hints.push(serde_json::json!({
+                "type": "direct-quic-v1",
+            }));
+        }
         if let Some(relay_v1) = self.relay_v1 {
             hints.push(serde_json::json!({
                 "type": "relay-v1",
                 "url-hints": relay_v1.url_hints,
             }));
         }
+        if let Some(record_cipher_v1) = self.record_cipher_v1 {
+            hints.push(serde_json::json!({
+                "type": "record-cipher-v1",
+                "preferred": record_cipher_v1.preferred,
+            }));
+        }
+        if self.websocket_v1 {
+            hints.push(serde_json::json!({
+                "type": "websocket-v1",
+            }));
+        }
+        if let Some(parallel_v1) = self.parallel_v1 {
+            hints.push(serde_json::json!({
+                "type": "parallel-v1",
+                "connections": parallel_v1.connections,
+            }));
+        }
+        if self.noise_v1 {
+            hints.push(serde_json::json!({
+                "type": "noise-v1",
+            }));
+        }
         serde_json::Value::Array(hints).serialize(ser)
     }
 }
@@ -212,10 +726,23 @@ impl<'de> serde::Deserialize<'de> for Abilities {
         #[serde(rename_all = "kebab-case", tag = "type")]
         enum Ability {
             DirectTcpV1,
+            DirectQuicV1,
             RelayV1 {
                 #[serde(default)]
                 url_hints: bool,
             },
+            RecordCipherV1 {
+                /* Kept as string, so that unknown ciphers don't make the whole message fail */
+                #[serde(default)]
+                preferred: String,
+            },
+            WebsocketV1,
+            ParallelV1 {
+                /* Wider than what we use, so that large numbers don't make the whole message fail */
+                #[serde(default)]
+                connections: u32,
+            },
+            NoiseV1,
             #[serde(other)]
             Other,
         }
@@ -227,9 +754,29 @@ impl<'de> serde::Deserialize<'de> for Abilities {
                 Ability::DirectTcpV1 => {
                     abilities.direct_tcp_v1 = true;
                 },
+                Ability::DirectQuicV1 => {
+                    abilities.direct_quic_v1 = true;
+                },
                 Ability::RelayV1 { url_hints } => {
                     abilities.relay_v1 = Some(RelayAbility { url_hints });
                 },
+                Ability::RecordCipherV1 { preferred } => {
+                    abilities.record_cipher_v1 = Some(RecordCipherAbility {
+                        preferred: serde_json::from_value(serde_json::Value::String(preferred))
+                            .unwrap_or_default(),
+                    });
+                },
+                Ability::WebsocketV1 => {
+                    abilities.websocket_v1 = true;
+                },
+                Ability::ParallelV1 { connections } => {
+                    abilities.parallel_v1 = Some(ParallelAbility {
+                        connections: u16::try_from(connections).unwrap_or(u16::MAX),
+                    });
+                },
+                Ability::NoiseV1 => {
+                    abilities.noise_v1 = true;
+                },
                 _ => (),
             }
         }
@@ -243,19 +790,36 @@ impl<'de> serde::Deserialize<'de> for Abilities {
 #[non_exhaustive]
 enum HintSerde {
     DirectTcpV1(DirectHint),
+    DirectQuicV1(DirectHint),
     RelayV1 {
-        hints: HashSet<DirectHint>,
+        hints: Vec<RelayTcpHint>,
         /** Newer encoding. When present, the `hints` field is redundant.
          */
         urls: Option<HashSet<url::Url>>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
     },
     #[serde(other)]
     Unknown,
 }
 
+/* Wire representation of a relay's TCP address, which also carries the relay's priority */
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct RelayTcpHint {
+    #[serde(flatten)]
+    hint: DirectHint,
+    #[serde(default, skip_serializing_if = "is_default_priority")]
+    priority: f32,
+}
+
+fn is_default_priority(priority: &f32) -> bool {
+    *priority == 0.0
+}
+
 impl From<Vec<HintSerde>> for Hints {
     fn from(hints: Vec<HintSerde>) -> Hints {
         let mut direct_tcp = HashSet::new();
+        let mut direct_quic = HashSet::new();
         let mut relay = Vec::<RelayHint>::new();
         let mut relay_v2 = Vec::<RelayHint>::new();
 
@@ -264,18 +828,31 @@ impl From<Vec<HintSerde>> for Hints {
                 HintSerde::DirectTcpV1(hint) => {
                     direct_tcp.insert(hint);
                 },
-                HintSerde::RelayV1 { hints, urls: None } => {
-                    relay.push(RelayHint {
-                        tcp: hints,
-                        ..RelayHint::default()
-                    });
+                HintSerde::DirectQuicV1(hint) => {
+                    direct_quic.insert(hint);
                 },
-                HintSerde::RelayV1 {
-                    hints: _,
-                    urls: Some(urls),
-                } => {
-                    let hint = RelayHint::new(urls);
-                    hint.merge_into(&mut relay_v2);
+                HintSerde::RelayV1 { hints, urls, name } => {
+                    /* The priority is per address, but we only have one per relay */
+                    let priority = hints
+                        .iter()
+                        .map(|hint| hint.priority)
+                        .reduce(f32::max)
+                        .unwrap_or(0.0);
+                    match urls {
+                        None => relay.push(RelayHint {
+                            tcp: hints.into_iter().map(|hint| hint.hint).collect(),
+                            priority,
+                            name,
+                            ..RelayHint::default()
+                        }),
+                        Some(urls) => {
+                            RelayHint {
+                                name,
+                                ..RelayHint::new(urls).priority(priority)
+                            }
+                            .merge_into(&mut relay_v2);
+                        },
+                    }
                 },
                 /* Ignore unknown hints */
                 _ => {},
@@ -288,13 +865,19 @@ impl From<Vec<HintSerde>> for Hints {
         }
         relay.extend(relay_v2.into_iter().map(Into::into));
 
-        Hints { direct_tcp, relay }
+        Hints {
+            direct_tcp,
+            direct_quic,
+            relay,
+        }
     }
 }
 
 #[derive(Clone, Debug, Default)]
 pub struct Hints {
     pub direct_tcp: HashSet<DirectHint>,
+    /** The UDP addresses for [`Abilities::direct_quic_v1`] */
+    pub direct_quic: HashSet<DirectHint>,
     pub relay: Vec<RelayHint>,
 }
 
@@ -302,6 +885,7 @@ impl Hints {
     pub fn new(direct_tcp: impl IntoIterator<Item = DirectHint>, relay: impl IntoIterator<Item = RelayHint>) -> Self {
         Self {
             direct_tcp: direct_tcp.into_iter().collect(),
+            direct_quic: HashSet::new(),
             relay: relay.into_iter().collect(),
         }
     }
@@ -312,10 +896,24 @@ impl Hints {
             .iter()
             .cloned()
             .map(HintSerde::DirectTcpV1)
+            .chain(
+                self.direct_quic
+                    .iter()
+                    .cloned()
+                    .map(HintSerde::DirectQuicV1),
+            )
             .chain(self.relay.iter().flat_map(|hint| {
                 [HintSerde::RelayV1 {
-                    hints: hint.tcp.clone(),
+                    hints: hint
+                        .tcp
+                        .iter()
+                        .map(|tcp| RelayTcpHint {
+                            hint: tcp.clone(),
+                            priority: hint.priority,
+                        })
+                        .collect(),
                     urls: Some(hint.iter_urls().into_iter().collect()),
+                    name: hint.name.clone(),
                 }]
             }))
     }
@@ -368,11 +966,23 @@ impl DirectHint {
  * by schema out of convenience.
  */
 /* RelayHint::default() gives the empty server (cannot be reached), and is only there for struct update syntax */
-#[derive(Clone, Debug, Eq, PartialEq, Default)]
+#[derive(Clone, Debug, PartialEq, Default)]
 pub struct RelayHint {
     pub tcp: HashSet<DirectHint>,
     pub ws: HashSet<url::Url>,
     pub other: HashSet<url::Url>,
+    /**
+     * Relays with a higher priority are tried first, the others only a bit later
+     *
+     * This is part of the hints and thus shared with the peer. The default is 0.
+     */
+    pub priority: f32,
+    /**
+     * A human readable name of the relay, also shared with the peer
+     *
+     * Hints with the same name are the same relay, even if they have no address in common.
+     */
+    pub name: Option<String>,
 }
 
 impl RelayHint {
@@ -400,11 +1010,29 @@ impl RelayHint {
                 },
             }
         }
-        RelayHint { tcp, ws, other }
+        RelayHint {
+            tcp,
+            ws,
+            other,
+            priority: 0.0,
+            name: None,
+        }
+    }
+
+    pub fn priority(mut self, priority: f32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
     }
 
     pub fn can_merge(&self, other: &Self) -> bool {
-        !self.tcp.is_disjoint(&other.tcp) || !self.ws.is_disjoint(&other.ws)
+        (self.name.is_some() && self.name == other.name)
+            || !self.tcp.is_disjoint(&other.tcp)
+            || !self.ws.is_disjoint(&other.ws)
     }
 
     pub fn merge(mut self, other: Self) -> Self {
@@ -416,6 +1044,10 @@ impl RelayHint {
         self.tcp.extend(other.tcp);
         self.ws.extend(other.ws);
         self.other.extend(other.other);
+        self.priority = self.priority.max(other.priority);
+        if self.name.is_none() {
+            self.name = other.name;
+        }
     }
 
     pub fn merge_into(self, collection: &mut Vec<RelayHint>) {
@@ -472,12 +1104,154 @@ impl TryFrom<&DirectHint> for std::net::SocketAddr {
     }
 }
 
+/**
+ * Like `SocketAddr::try_from(&DirectHint)`, but with host names resolved
+ *
+ * Hints from the peer are usually IP addresses, which don't go through the resolver.
+ */
+async fn resolve_direct_hint(
+    hint: &DirectHint,
+    resolver: Option<&Resolver>,
+) -> std::io::Result<std::net::SocketAddr> {
+    let addr = crate::resolver::resolve(resolver, &hint.hostname, hint.port).await?[0];
+    Ok(match addr {
+        std::net::SocketAddr::V4(v4) => {
+            std::net::SocketAddr::new(std::net::IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port())
+        },
+        std::net::SocketAddr::V6(_) => addr,
+    })
+}
+
+/** How the transit connection to the peer goes */
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
-enum HostType {
+pub enum ConnectionType {
     Direct,
+    /** Over a relay server */
     Relay,
 }
 
+/**
+ * Details about an established transit connection
+ */
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct TransitInfo {
+    pub conn_type: ConnectionType,
+    /**
+     * The round-trip time to the peer, as measured while connecting
+     *
+     * Transit has no keepalives, so this is only measured once, from how long connecting to a
+     * direct hint took. It is `None` if we did not initiate the connection, including all
+     * relayed ones.
+     */
+    pub rtt: Option<std::time::Duration>,
+    /**
+     * The address at the other end of the TCP connection
+     *
+     * This is the peer's address for direct connections, and the relay server's one otherwise.
+     */
+    pub peer_addr: std::net::SocketAddr,
+    /** Which relay server got used, only `Some` for relayed connections */
+    pub relay: Option<RelayInfo>,
+    /** The cipher negotiated for the records, see [`Abilities::record_cipher_v1`] */
+    pub cipher: RecordCipher,
+    /** Whether this is a direct connection over QUIC, see [`Abilities::direct_quic_v1`] */
+    pub quic: bool,
+    /** Whether the record keys come from a Noise handshake, see [`Abilities::noise_v1`] */
+    pub noise: bool,
+}
+
+impl TransitInfo {
+    fn log(&self) {
+        if let Some(rtt) = self.rtt {
+            info!("Round-trip time to the peer: {:?}", rtt);
+        }
+        if let Some(relay) = &self.relay {
+            info!(
+                "Using relay {} (priority {}){}",
+                relay
+                    .websocket
+                    .as_ref()
+                    .map_or_else(|| relay.hint.to_string(), url::Url::to_string),
+                relay.priority,
+                if relay.fallback {
+                    ", because the ones with a higher priority could not be reached"
+                } else {
+                    ""
+                }
+            );
+        }
+    }
+}
+
+/**
+ * The relay server a connection goes over, and why it was chosen
+ */
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct RelayInfo {
+    /** The relay's address we connected to */
+    pub hint: DirectHint,
+    /** The URL, if we connected over a WebSocket (see [`Abilities::websocket_v1`]) */
+    pub websocket: Option<url::Url>,
+    /** The priority of the relay's [`RelayHint`] */
+    pub priority: f32,
+    /** The name of the relay's [`RelayHint`], if it has one */
+    pub name: Option<String>,
+    /**
+     * Whether relays with a higher priority were known
+     *
+     * Those are tried first, so if this is `true`, none of them could be reached in time.
+     */
+    pub fallback: bool,
+}
+
+/** Undo the mapping of IPv4 addresses into IPv6 that happens on our dual-stack sockets */
+fn unmap_ipv4(addr: std::net::SocketAddr) -> std::net::SocketAddr {
+    match addr {
+        std::net::SocketAddr::V6(v6) if v6.ip().segments()[..6] == [0, 0, 0, 0, 0, 0xffff] => {
+            std::net::SocketAddr::new(v6.ip().to_ipv4().unwrap().into(), v6.port())
+        },
+        addr => addr,
+    }
+}
+
+/**
+ * Whether the peer may reach us at an address of one of our interfaces
+ *
+ * Link-local addresses are left out: IPv6 ones are useless without the interface they belong to,
+ * and all of them only work if both sides happen to be on the same link.
+ */
+fn is_hint_address(ip: std::net::IpAddr) -> bool {
+    match ip {
+        std::net::IpAddr::V4(v4) => !v4.is_loopback() && !v4.is_link_local(),
+        std::net::IpAddr::V6(v6) => !v6.is_loopback() && v6.segments()[0] & 0xffc0 != 0xfe80,
+    }
+}
+
+/**
+ * The peer's direct hints in the order to dial them, see [`crate::resolver::interleave_families`]
+ *
+ * IPv6 goes first, like RFC 8305 recommends. Host names count as IPv4.
+ */
+fn happy_eyeballs_order(hints: &HashSet<DirectHint>) -> Vec<DirectHint> {
+    let is_ipv6 = |hint: &DirectHint| matches!(hint.hostname.parse(), Ok(std::net::IpAddr::V6(_)));
+    let mut hints = hints.iter().cloned().collect::<Vec<_>>();
+    hints.sort_by_cached_key(|hint| (!is_ipv6(hint), hint.hostname.clone(), hint.port));
+    crate::resolver::interleave_families(hints, is_ipv6)
+}
+
+/**
+ * Give the first direct connection attempts a head start over the later ones
+ *
+ * Like with happy eyeballs, this avoids racing a broken address family against a working one. The
+ * attempts do not fail early, so unlike RFC 8305 the next one does not start any sooner.
+ */
+fn dial_delay(index: usize) -> std::time::Duration {
+    /* Don't let the last hints wait for too long */
+    crate::resolver::CONNECTION_ATTEMPT_DELAY * index.min(8) as u32
+}
+
 fn set_socket_opts(socket: &socket2::Socket) -> std::io::Result<()> {
     socket.set_nonblocking(true)?;
 
@@ -542,6 +1316,57 @@ async fn connect_custom(
     Ok(stream.into_inner()?.into())
 }
 
+/** Time between two connection attempts to the same direct hint, see [`connect_simultaneous_open`] */
+const SIMULTANEOUS_OPEN_INTERVAL: std::time::Duration = std::time::Duration::from_millis(800);
+
+/**
+ * Connect to a peer's direct hint using TCP simultaneous open
+ *
+ * Both sides connect to each other from the port they advertised (this is what SO_REUSEADDR
+ * is for), so if their SYN packets cross, both get a connection without anyone listening.
+ * Behind NATs, the first SYNs typically get dropped or rejected by the other side's NAT,
+ * but they create a mapping in our own one. Thus we keep retrying for a few seconds, until
+ * the other side's attempts get through or `timeout` has passed. On open networks, the first
+ * attempt will do.
+ */
+async fn connect_simultaneous_open(
+    local_addr: &socket2::SockAddr,
+    dest_addr: std::net::SocketAddr,
+    timeout: std::time::Duration,
+) -> std::io::Result<(TcpStream, std::time::Duration)> {
+    let first_start = std::time::Instant::now();
+    let mut attempt = 1;
+    loop {
+        let start = std::time::Instant::now();
+        let remaining = timeout.saturating_sub(first_start.elapsed());
+        let error = match async_std::future::timeout(
+            SIMULTANEOUS_OPEN_INTERVAL.min(remaining),
+            connect_custom(local_addr, &dest_addr.into()),
+        )
+        .await
+        {
+            /* A TCP handshake takes exactly one round trip */
+            Ok(Ok(socket)) => return Ok((socket, start.elapsed())),
+            Ok(Err(err)) => err,
+            Err(err) => std::io::Error::new(std::io::ErrorKind::TimedOut, err),
+        };
+        if first_start.elapsed() + SIMULTANEOUS_OPEN_INTERVAL > timeout {
+            return Err(error);
+        }
+        log::trace!(
+            "Connection attempt #{} to {} failed, retrying: {}",
+            attempt,
+            dest_addr,
+            error
+        );
+        attempt += 1;
+        /* A rejected connection fails immediately, don't spam the other side in that case */
+        if let Some(remaining) = SIMULTANEOUS_OPEN_INTERVAL.checked_sub(start.elapsed()) {
+            async_std::task::sleep(remaining).await;
+        }
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 enum StunError {
     #[error("No V4 addresses were found for the selected STUN server")]
@@ -554,6 +1379,10 @@ enum StunError {
         #[source]
         std::io::Error,
     ),
+    #[error("The STUN response is for a different request")]
+    TransactionId,
+    #[error("The STUN response does not contain our address")]
+    NoAddress,
     #[error("Malformed STUN packet")]
     Codec(
         #[from]
@@ -562,94 +1391,136 @@ enum StunError {
     ),
 }
 
-/** Perform a STUN query to get the external IP address */
-async fn get_external_ip() -> Result<(std::net::SocketAddr, TcpStream), StunError> {
-    let mut socket = connect_custom(
-        &"[::]:0".parse::<std::net::SocketAddr>().unwrap().into(),
-        &PUBLIC_STUN_SERVER
-            .to_socket_addrs()?
-            /* If you find yourself behind a NAT66, open an issue */
-            .find(|x| x.is_ipv4())
-            /* TODO add a helper method to stdlib for this */
-            .map(|addr| match addr {
-                std::net::SocketAddr::V4(v4) => std::net::SocketAddr::new(
-                    std::net::IpAddr::V6(v4.ip().to_ipv6_mapped()),
-                    v4.port(),
-                ),
-                std::net::SocketAddr::V6(_) => unreachable!(),
-            })
-            .ok_or(StunError::ServerIsV4Only)?
-            .into(),
-    )
-    .await?;
+/** The address of the STUN server, as an IPv4 address mapped into IPv6 */
+async fn stun_server_addr(resolver: Option<&Resolver>) -> Result<std::net::SocketAddr, StunError> {
+    let (stun_host, stun_port) = PUBLIC_STUN_SERVER.rsplit_once(':').unwrap();
+    crate::resolver::resolve(resolver, stun_host, stun_port.parse().unwrap())
+        .await?
+        .into_iter()
+        /* If you find yourself behind a NAT66, open an issue */
+        .find(|x| x.is_ipv4())
+        /* TODO add a helper method to stdlib for this */
+        .map(|addr| match addr {
+            std::net::SocketAddr::V4(v4) => {
+                std::net::SocketAddr::new(std::net::IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port())
+            },
+            std::net::SocketAddr::V6(_) => unreachable!(),
+        })
+        .ok_or(StunError::ServerIsV4Only)
+}
 
-    use bytecodec::{DecodeExt, EncodeExt};
-    use std::net::{SocketAddr, ToSocketAddrs};
+/** A STUN request for our address, and the ID that the response must have */
+fn stun_binding_request() -> Result<(Vec<u8>, stun_codec::TransactionId), bytecodec::Error> {
+    use bytecodec::EncodeExt;
+    use rand::Rng;
     use stun_codec::{
-        rfc5389::{
-            self,
-            attributes::{MappedAddress, Software, XorMappedAddress},
-            Attribute,
-        },
-        Message, MessageClass, MessageDecoder, MessageEncoder, TransactionId,
+        rfc5389::{self, attributes::Software, Attribute},
+        Message, MessageClass, MessageEncoder, TransactionId,
     };
 
-    fn get_binding_request() -> Result<Vec<u8>, bytecodec::Error> {
-        use rand::Rng;
-        let random_bytes = rand::thread_rng().gen::<[u8; 12]>();
+    let transaction_id = TransactionId::new(rand::thread_rng().gen::<[u8; 12]>());
 
-        let mut message = Message::new(
-            MessageClass::Request,
-            rfc5389::methods::BINDING,
-            TransactionId::new(random_bytes),
-        );
+    let mut message = Message::new(
+        MessageClass::Request,
+        rfc5389::methods::BINDING,
+        transaction_id,
+    );
 
-        message.add_attribute(Attribute::Software(Software::new(
-            "magic-wormhole-rust".to_owned(),
-        )?));
+    message.add_attribute(Attribute::Software(Software::new(
+        "magic-wormhole-rust".to_owned(),
+    )?));
 
-        // Encodes the message
-        let mut encoder = MessageEncoder::new();
-        let bytes = encoder.encode_into_bytes(message.clone())?;
-        Ok(bytes)
-    }
-
-    fn decode_address(buf: &[u8]) -> Result<SocketAddr, bytecodec::Error> {
-        let mut decoder = MessageDecoder::<Attribute>::new();
-        let decoded = decoder.decode_from_bytes(buf)??;
+    // Encodes the message
+    let mut encoder = MessageEncoder::new();
+    let bytes = encoder.encode_into_bytes(message.clone())?;
+    Ok((bytes, transaction_id))
+}
 
-        println!("Decoded message: {:?}", decoded);
+/** Get our external address from the response to the request with `transaction_id` */
+fn stun_decode_address(
+    buf: &[u8],
+    transaction_id: stun_codec::TransactionId,
+) -> Result<std::net::SocketAddr, StunError> {
+    use bytecodec::DecodeExt;
+    use stun_codec::{
+        rfc5389::{
+            attributes::{MappedAddress, XorMappedAddress},
+            Attribute,
+        },
+        MessageDecoder,
+    };
 
-        let external_addr1 = decoded
-            .get_attribute::<XorMappedAddress>()
-            .map(|x| x.address());
-        //let external_addr2 = decoded.get_attribute::<XorMappedAddress2>().map(|x|x.address());
-        let external_addr3 = decoded
-            .get_attribute::<MappedAddress>()
-            .map(|x| x.address());
-        let external_addr = external_addr1
-            // .or(external_addr2)
-            .or(external_addr3);
-        let external_addr = external_addr.unwrap();
+    let mut decoder = MessageDecoder::<Attribute>::new();
+    let decoded = decoder
+        .decode_from_bytes(buf)?
+        .map_err(bytecodec::Error::from)?;
+
+    log::debug!("Decoded STUN message: {:?}", decoded);
+    ensure!(
+        decoded.transaction_id() == transaction_id,
+        StunError::TransactionId
+    );
+
+    decoded
+        .get_attribute::<XorMappedAddress>()
+        .map(|x| x.address())
+        .or_else(|| {
+            decoded
+                .get_attribute::<MappedAddress>()
+                .map(|x| x.address())
+        })
+        .ok_or(StunError::NoAddress)
+}
 
-        Ok(external_addr)
-    }
+/** Perform a STUN query to get the external IP address */
+async fn get_external_ip(
+    resolver: Option<&Resolver>,
+) -> Result<(std::net::SocketAddr, TcpStream), StunError> {
+    let mut socket = connect_custom(
+        &"[::]:0".parse::<std::net::SocketAddr>().unwrap().into(),
+        &stun_server_addr(resolver).await?.into(),
+    )
+    .await?;
 
     /* Connect the plugs */
 
-    socket.write_all(get_binding_request()?.as_ref()).await?;
+    let (request, transaction_id) = stun_binding_request()?;
+    socket.write_all(&request).await?;
 
-    let mut buf = [0u8; 256];
     /* Read header first */
-    socket.read_exact(&mut buf[..20]).await?;
+    let mut buf = vec![0u8; 20];
+    socket.read_exact(&mut buf).await?;
     let len: u16 = u16::from_be_bytes([buf[2], buf[3]]);
     /* Read the rest of the message */
-    socket.read_exact(&mut buf[20..][..len as usize]).await?;
-    let external_addr = decode_address(&buf[..20 + len as usize])?;
+    buf.resize(20 + len as usize, 0);
+    socket.read_exact(&mut buf[20..]).await?;
+    let external_addr = stun_decode_address(&buf, transaction_id)?;
 
     Ok((external_addr, socket))
 }
 
+/** Bind a listener to the first free port of the range */
+async fn bind_port_range(ports: std::ops::RangeInclusive<u16>) -> std::io::Result<TcpListener> {
+    let mut last_error = None;
+    for port in ports {
+        match TcpListener::bind(std::net::SocketAddr::new(
+            std::net::Ipv6Addr::UNSPECIFIED.into(),
+            port,
+        ))
+        .await
+        {
+            Ok(listener) => return Ok(listener),
+            Err(err) => {
+                log::debug!("Could not listen on port {}: {}", port, err);
+                last_error = Some(err);
+            },
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "Empty port range")
+    }))
+}
+
 /**
  * Initialize a relay handshake
  *
@@ -659,22 +1530,44 @@ pub async fn init(
     mut abilities: Abilities,
     peer_abilities: Option<Abilities>,
     relay_hints: Vec<RelayHint>,
+    config: &TransitConfig,
 ) -> Result<TransitConnector, std::io::Error> {
     let mut our_hints = Hints::default();
     let mut listener = None;
 
+    abilities = abilities.intersect(&config.policy.abilities());
+    /* Direct connections (and the STUN query) would reveal our address */
+    if matches!(&config.proxy, Some(proxy) if proxy.tor) {
+        abilities = abilities.intersect(&Abilities::FORCE_RELAY);
+    }
+    if config.parallel_connections > 1 && abilities.can_relay() {
+        abilities.parallel_v1 = Some(ParallelAbility {
+            connections: config.parallel_connections,
+        });
+    }
     if let Some(peer_abilities) = peer_abilities {
         abilities = abilities.intersect(&peer_abilities);
     }
+    if let Some(record_cipher_v1) = &mut abilities.record_cipher_v1 {
+        record_cipher_v1.preferred = config.record_cipher;
+    }
+    if cfg!(not(feature = "quic")) {
+        abilities.direct_quic_v1 = false;
+    }
+    if cfg!(not(feature = "noise")) {
+        abilities.noise_v1 = false;
+    }
 
     /* Detect our IP addresses if the ability is enabled */
-    if abilities.can_direct() {
+    if abilities.direct_tcp_v1 {
         /* Do a STUN query to get our public IP. If it works, we must reuse the same socket (port)
          * so that we will be NATted to the same port again. If it doesn't, simply bind a new socket
          * and use that instead.
          */
+        let mut our_external_ip = None;
+        let stun = get_external_ip(config.resolver.as_ref());
         let socket: MaybeConnectedSocket =
-            match async_std::future::timeout(std::time::Duration::from_secs(4), get_external_ip())
+            match async_std::future::timeout(std::time::Duration::from_secs(4), stun)
                 .await
                 .map_err(|_| StunError::Timeout)
             {
@@ -684,6 +1577,7 @@ pub async fn init(
                         hostname: external_ip.ip().to_string(),
                         port: external_ip.port(),
                     });
+                    our_external_ip = Some(external_ip.ip());
                     stream.into()
                 },
                 // TODO replace with .flatten() once stable
@@ -709,40 +1603,111 @@ pub async fn init(
          * the port. In theory, we could, but it really confused the kernel to the point
          * of `accept` calls never returning again.
          */
-        let socket2 = TcpListener::bind("[::]:0").await?;
+        let socket2 = match (&config.listen_ports, config.no_listen) {
+            (_, true) => None,
+            (Some(ports), false) => Some(bind_port_range(ports.clone()).await?),
+            (None, false) => Some(TcpListener::bind("[::]:0").await?),
+        };
 
         /* Find our ports, iterate all our local addresses, combine them with the ports and that's our hints */
-        let port = socket.local_addr()?.as_socket().unwrap().port();
-        let port2 = socket2.local_addr()?.port();
-        our_hints.direct_tcp.extend(
-            get_if_addrs::get_if_addrs()?
-                .iter()
-                .filter(|iface| !iface.is_loopback())
-                .flat_map(|ip|
-                    /* TODO replace with array once into_iter works as it should */
-                    vec![
-                        DirectHint {
-                            hostname: ip.ip().to_string(),
-                            port,
-                        },
-                        DirectHint {
-                            hostname: ip.ip().to_string(),
-                            port: port2,
-                        },
-                    ].into_iter()),
-        );
+        let mut ports = vec![socket.local_addr()?.as_socket().unwrap().port()];
+        if let Some(socket2) = &socket2 {
+            ports.push(socket2.local_addr()?.port());
+        }
+        for iface in get_if_addrs::get_if_addrs()? {
+            if !is_hint_address(iface.ip()) {
+                continue;
+            }
+            for &port in &ports {
+                our_hints.direct_tcp.insert(DirectHint {
+                    hostname: iface.ip().to_string(),
+                    port,
+                });
+            }
+        }
+
+        /* If the user chose the port, it's probably because they forwarded it. So
+         * it's worth a try to connect to it via our external address.
+         */
+        if let (Some(external_ip), Some(_), Some(socket2)) =
+            (our_external_ip, &config.listen_ports, &socket2)
+        {
+            our_hints.direct_tcp.insert(DirectHint {
+                hostname: external_ip.to_string(),
+                port: socket2.local_addr()?.port(),
+            });
+        }
 
         listener = Some((socket, socket2));
     }
 
+    #[cfg(not(feature = "port-mapping"))]
+    let port_mapping = None;
+    #[cfg(feature = "port-mapping")]
+    let port_mapping = match &listener {
+        Some((_, Some(socket2))) if config.port_mapping => {
+            match PortMapping::new(socket2.local_addr()?.port()).await {
+                Some((port_mapping, external_addr)) => {
+                    log::debug!("Our listener is forwarded from {}", external_addr);
+                    our_hints.direct_tcp.insert(DirectHint::new(
+                        external_addr.ip().to_string(),
+                        external_addr.port(),
+                    ));
+                    Some(port_mapping)
+                },
+                None => None,
+            }
+        },
+        _ => None,
+    };
+
+    #[cfg(not(feature = "quic"))]
+    let quic = None;
+    #[cfg(feature = "quic")]
+    let quic = match abilities.direct_quic_v1 {
+        true => match quic::bind(config.resolver.as_ref()).await {
+            Ok((endpoint, external_addr)) => {
+                let port = endpoint.local_addr()?.port();
+                if let Some(external_addr) = external_addr {
+                    our_hints.direct_quic.insert(DirectHint::new(
+                        external_addr.ip().to_string(),
+                        external_addr.port(),
+                    ));
+                }
+                for iface in get_if_addrs::get_if_addrs()? {
+                    if is_hint_address(iface.ip()) {
+                        our_hints
+                            .direct_quic
+                            .insert(DirectHint::new(iface.ip().to_string(), port));
+                    }
+                }
+                Some(endpoint)
+            },
+            /* Not worth failing for, there still is TCP */
+            Err(err) => {
+                log::debug!("Could not bind the socket for QUIC: {}", err);
+                abilities.direct_quic_v1 = false;
+                None
+            },
+        },
+        false => None,
+    };
+
     if abilities.can_relay() {
         our_hints.relay.extend(relay_hints);
     }
 
     Ok(TransitConnector {
         sockets: listener,
+        quic,
+        port_mapping,
         our_abilities: abilities,
         our_hints: Arc::new(our_hints),
+        resolver: config.resolver.clone(),
+        proxy: config.proxy.clone(),
+        rate_limit: config.rate_limit.clone(),
+        stats_callback: config.stats_callback.clone(),
+        timeouts: config.timeouts,
     })
 }
 
@@ -773,11 +1738,21 @@ impl MaybeConnectedSocket {
 pub struct TransitConnector {
     /* Only `Some` if direct-tcp-v1 ability has been enabled.
      * The first socket is the port from which we will start connection attempts.
-     * For in case the user is behind no firewalls, we must also listen to the second socket.
+     * For in case the user is behind no firewalls, we must also listen to the second socket,
+     * unless listening is disabled.
      */
-    sockets: Option<(MaybeConnectedSocket, TcpListener)>,
+    sockets: Option<(MaybeConnectedSocket, Option<TcpListener>)>,
+    /* Only `Some` if direct-quic-v1 ability has been enabled. Used for dialing and accepting. */
+    quic: Option<QuicEndpoint>,
+    /* Forwards a port to the listener, until the connection is established */
+    port_mapping: Option<PortMapping>,
     our_abilities: Abilities,
     our_hints: Arc<Hints>,
+    resolver: Option<Resolver>,
+    proxy: Option<Proxy>,
+    rate_limit: Option<RateLimit>,
+    stats_callback: Option<StatsCallback>,
+    timeouts: TransitTimeouts,
 }
 
 impl TransitConnector {
@@ -801,47 +1776,83 @@ impl TransitConnector {
     ) -> Result<Transit, TransitConnectError> {
         let Self {
             sockets,
+            quic,
+            port_mapping: _port_mapping,
             our_abilities,
             our_hints,
+            resolver,
+            proxy,
+            rate_limit,
+            stats_callback,
+            timeouts,
         } = self;
+        Self::check_abilities(&our_abilities, &their_abilities)?;
         let transit_key = Arc::new(transit_key);
 
+        /* Relayed connections are the worst ones, and direct ones over QUIC the best */
+        let preference = |info: &TransitInfo| match info.conn_type {
+            ConnectionType::Relay => 0,
+            ConnectionType::Direct if info.quic => 2,
+            ConnectionType::Direct => 1,
+        };
+        let best_possible = if our_abilities.direct_quic_v1
+            && their_abilities.direct_quic_v1
+            && !their_hints.direct_quic.is_empty()
+        {
+            2
+        } else if our_abilities.can_direct() {
+            1
+        } else {
+            0
+        };
+
         let start = std::time::Instant::now();
+        let failed_attempts = std::sync::atomic::AtomicU32::new(0);
+        let failed_attempts_ref = &failed_attempts;
         let mut connection_stream = Box::pin(
             Self::connect(
                 true,
-                transit_key,
+                transit_key.clone(),
                 our_abilities,
                 our_hints,
                 their_abilities,
                 their_hints,
                 sockets,
+                quic,
+                resolver.clone(),
+                proxy.clone(),
+                timeouts,
             )
-            .filter_map(|result| async {
+            .filter_map(move |result| async move {
                 match result {
                     Ok(val) => Some(val),
                     Err(err) => {
-                        log::debug!("Some leader handshake failed: {:?}", err);
+                        log::debug!("Some leader connection attempt failed: {:?}", err);
+                        failed_attempts_ref.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
                         None
                     },
                 }
             }),
         );
 
-        let (mut transit, host_type) = async_std::future::timeout(
-            std::time::Duration::from_secs(60),
-            connection_stream.next(),
-        )
-        .await
-        .map_err(|_| {
-            log::debug!("`leader_connect` timed out");
-            TransitConnectError::Handshake
-        })?
-        .ok_or(TransitConnectError::Handshake)?;
-
-        if host_type == HostType::Relay && our_abilities.can_direct() {
+        let (mut transit, host_type) =
+            async_std::future::timeout(timeouts.connect, connection_stream.next())
+                .await
+                .map_err(|_| {
+                    log::debug!("`leader_connect` timed out");
+                    TransitConnectError::Handshake
+                })?
+                .ok_or(TransitConnectError::Handshake)?;
+        let first_connection = start.elapsed();
+
+        if preference(&transit.info) < best_possible {
             log::debug!(
-                "Established transit connection over relay. Trying to find a direct connection …"
+                "Established transit connection {}. Trying to find a better connection …",
+                if host_type == ConnectionType::Relay {
+                    "over relay"
+                } else {
+                    "over TCP"
+                }
             );
             /* Measure the time it took us to get a response. Based on this, wait some more for more responses
              * in case we like one better.
@@ -850,16 +1861,21 @@ impl TransitConnector {
             let to_wait = if elapsed.as_secs() > 5 {
                 /* If our RTT was *that* long, let's just be happy we even got one connection */
                 std::time::Duration::from_secs(1)
-            } else {
+            } else if host_type == ConnectionType::Relay {
                 elapsed.mul_f32(0.3)
+            } else {
+                /* A QUIC handshake takes about as long as a TCP one */
+                elapsed
             };
             let _ = async_std::future::timeout(to_wait, async {
-                while let Some((new_transit, new_host_type)) = connection_stream.next().await {
-                    /* We already got a connection, so we're only interested in direct ones */
-                    if new_host_type == HostType::Direct {
+                while let Some((new_transit, _)) = connection_stream.next().await {
+                    /* We already got a connection, so we're only interested in better ones */
+                    if preference(&new_transit.info) > preference(&transit.info) {
                         transit = new_transit;
-                        log::debug!("Found direct connection; using that instead.");
-                        break;
+                        log::debug!("Found a better connection; using that instead.");
+                        if preference(&transit.info) == best_possible {
+                            break;
+                        }
                     }
                 }
             })
@@ -874,12 +1890,38 @@ impl TransitConnector {
          */
         std::mem::drop(connection_stream);
 
+        transit.cipher = our_abilities.record_cipher(&their_abilities);
+        transit.info.cipher = transit.cipher;
+        transit.rate_limit = rate_limit;
         transit.socket.write_all(b"go\n").await?;
+        #[cfg(feature = "noise")]
+        if our_abilities.noise_v1 && their_abilities.noise_v1 {
+            noise::handshake(true, &mut transit, &transit_key).await?;
+        }
         info!(
             "Established transit connection to '{}'",
-            transit.socket.peer_addr().unwrap()
+            transit.info.peer_addr
+        );
+        transit.info.log();
+
+        let mut transit = connect_parallel(
+            true,
+            transit,
+            our_abilities.parallel_connections(&their_abilities),
+            &transit_key,
+            resolver,
+            proxy,
+            timeouts,
+        )
+        .await?;
+        transit.start_stats(
+            stats::Counters::new(
+                first_connection,
+                start.elapsed(),
+                failed_attempts.into_inner(),
+            ),
+            stats_callback.as_ref(),
         );
-
         Ok(transit)
     }
 
@@ -894,61 +1936,120 @@ impl TransitConnector {
     ) -> Result<Transit, TransitConnectError> {
         let Self {
             sockets,
+            quic,
+            port_mapping: _port_mapping,
             our_abilities,
             our_hints,
+            resolver,
+            proxy,
+            rate_limit,
+            stats_callback,
+            timeouts,
         } = self;
+        Self::check_abilities(&our_abilities, &their_abilities)?;
         let transit_key = Arc::new(transit_key);
 
+        let start = std::time::Instant::now();
+        let failed_attempts = std::sync::atomic::AtomicU32::new(0);
+        let failed_attempts_ref = &failed_attempts;
         let mut connection_stream = Box::pin(
             Self::connect(
                 false,
-                transit_key,
+                transit_key.clone(),
                 our_abilities,
                 our_hints,
                 their_abilities,
                 their_hints,
                 sockets,
+                quic,
+                resolver.clone(),
+                proxy.clone(),
+                timeouts,
             )
-            .filter_map(|result| async {
+            .filter_map(move |result| async move {
                 match result {
                     Ok(val) => Some(val),
                     Err(err) => {
-                        log::debug!("Some follower handshake failed: {:?}", err);
+                        log::debug!("Some follower connection attempt failed: {:?}", err);
+                        failed_attempts_ref.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
                         None
                     },
                 }
             }),
         );
 
-        let transit = match async_std::future::timeout(
-            std::time::Duration::from_secs(60),
-            &mut connection_stream.next(),
-        )
-        .await
-        {
-            Ok(Some((transit, host_type))) => {
-                log::debug!(
-                    "Established a {} transit connection.",
-                    if host_type == HostType::Direct {
-                        "direct"
-                    } else {
-                        "relay"
+        let transit =
+            match async_std::future::timeout(timeouts.connect, &mut connection_stream.next()).await
+            {
+                Ok(Some((mut transit, host_type))) => {
+                    transit.cipher = our_abilities.record_cipher(&their_abilities);
+                    transit.info.cipher = transit.cipher;
+                    transit.rate_limit = rate_limit;
+                    #[cfg(feature = "noise")]
+                    if our_abilities.noise_v1 && their_abilities.noise_v1 {
+                        noise::handshake(false, &mut transit, &transit_key).await?;
                     }
-                );
-                Ok(transit)
-            },
-            Ok(None) | Err(_) => {
-                log::debug!("`follower_connect` timed out");
-                Err(TransitConnectError::Handshake)
-            },
-        };
+                    log::debug!(
+                        "Established a {} transit connection.",
+                        if host_type == ConnectionType::Direct {
+                            "direct"
+                        } else {
+                            "relay"
+                        }
+                    );
+                    transit.info.log();
+                    Ok(transit)
+                },
+                Ok(None) | Err(_) => {
+                    log::debug!("`follower_connect` timed out");
+                    Err(TransitConnectError::Handshake)
+                },
+            };
 
         /* Cancel all remaining non-finished handshakes. We could send "nevermind" to explicitly tell
          * the other side (probably, this is mostly for relay server statistics), but eeh, nevermind :)
          */
         std::mem::drop(connection_stream);
+        let first_connection = start.elapsed();
+
+        let mut transit = connect_parallel(
+            false,
+            transit?,
+            our_abilities.parallel_connections(&their_abilities),
+            &transit_key,
+            resolver,
+            proxy,
+            timeouts,
+        )
+        .await?;
+        transit.start_stats(
+            stats::Counters::new(
+                first_connection,
+                start.elapsed(),
+                failed_attempts.into_inner(),
+            ),
+            stats_callback.as_ref(),
+        );
+        Ok(transit)
+    }
 
-        transit
+    /** Fail early if there is no way we can connect to the peer */
+    fn check_abilities(
+        our_abilities: &Abilities,
+        their_abilities: &Abilities,
+    ) -> Result<(), TransitConnectError> {
+        ensure!(
+            (our_abilities.can_direct() && their_abilities.can_direct())
+                || (our_abilities.can_relay() && their_abilities.can_relay()),
+            TransitConnectError::Protocol(
+                format!(
+                    "No connection method is supported by both sides (ours: {:?}, theirs: {:?})",
+                    our_abilities, their_abilities
+                )
+                .into()
+            )
+        );
+        Ok(())
     }
 
     /** Try to establish a connection with the peer.
@@ -967,9 +2068,36 @@ impl TransitConnector {
         our_hints: Arc<Hints>,
         their_abilities: Abilities,
         their_hints: Arc<Hints>,
-        socket: Option<(MaybeConnectedSocket, TcpListener)>,
-    ) -> impl Stream<Item = Result<(Transit, HostType), TransitHandshakeError>> + 'static {
-        assert!(socket.is_some() == our_abilities.can_direct());
+        socket: Option<(MaybeConnectedSocket, Option<TcpListener>)>,
+        quic: Option<QuicEndpoint>,
+        resolver: Option<Resolver>,
+        proxy: Option<Proxy>,
+        timeouts: TransitTimeouts,
+    ) -> impl Stream<Item = Result<(Transit, ConnectionType), TransitHandshakeError>> + 'static
+    {
+        assert!(socket.is_some() == our_abilities.direct_tcp_v1);
+        assert!(quic.is_some() == our_abilities.direct_quic_v1);
+
+        log::debug!(
+            "Our abilities: {:?}, their abilities: {:?}",
+            our_abilities,
+            their_abilities
+        );
+        log::debug!(
+            "Their hints: {} direct, {} QUIC, {} relay: {:?}",
+            their_hints.direct_tcp.len(),
+            their_hints.direct_quic.len(),
+            their_hints.relay.len(),
+            their_hints
+        );
+        if our_abilities.can_direct() && !their_abilities.can_direct() {
+            log::debug!("The peer does not support direct connections, only a relay can be used");
+        } else if our_abilities.can_direct()
+            && their_hints.direct_tcp.is_empty()
+            && their_hints.direct_quic.is_empty()
+        {
+            log::debug!("The peer did not advertise any direct hints, only a relay can be used");
+        }
 
         // 8. listen for connections on the port and simultaneously try connecting to the peer port.
         let tside = Arc::new(hex::encode(rand::random::<[u8; 8]>()));
@@ -979,8 +2107,10 @@ impl TransitConnector {
          */
         use futures::future::BoxFuture;
         type BoxIterator<T> = Box<dyn Iterator<Item = T>>;
-        type ConnectorFuture =
-            BoxFuture<'static, Result<(TcpStream, HostType), TransitHandshakeError>>;
+        type ConnectorFuture = BoxFuture<
+            'static,
+            Result<(Box<dyn TransitSocket>, TransitInfo), TransitHandshakeError>,
+        >;
         let mut connectors: BoxIterator<ConnectorFuture> = Box::new(std::iter::empty());
 
         /* Create direct connection sockets, if we support it. If peer doesn't support it, their list of hints will
@@ -988,54 +2118,124 @@ impl TransitConnector {
          */
         let socket2 = if let Some((socket, socket2)) = socket {
             let local_addr = Arc::new(socket.local_addr().unwrap());
+            let resolver = resolver.clone();
             /* Connect to each hint of the peer */
             connectors = Box::new(
                 connectors.chain(
-                    their_hints
-                        .direct_tcp
-                        .clone()
+                    happy_eyeballs_order(&their_hints.direct_tcp)
                         .into_iter()
                         /* Nobody should have that many IP addresses, even with NATing */
                         .take(50)
-                        .map(move |hint| {
+                        .enumerate()
+                        .map(move |(index, hint)| {
                             let local_addr = local_addr.clone();
+                            let resolver = resolver.clone();
                             async move {
-                                let dest_addr = std::net::SocketAddr::try_from(&hint)?;
+                                async_std::task::sleep(dial_delay(index)).await;
+                                let dest_addr =
+                                    resolve_direct_hint(&hint, resolver.as_ref()).await?;
                                 log::debug!("Connecting directly to {}", dest_addr);
-                                let socket = connect_custom(&local_addr, &dest_addr.into()).await?;
-                                log::debug!("Connected to {}!", dest_addr);
-                                Ok((socket, HostType::Direct))
+                                let (socket, rtt) = connect_simultaneous_open(
+                                    &local_addr,
+                                    dest_addr,
+                                    timeouts.dial,
+                                )
+                                .await?;
+                                log::debug!("Connected to {} (RTT: {:?})!", dest_addr, rtt);
+                                Ok((
+                                    Box::new(socket) as Box<dyn TransitSocket>,
+                                    TransitInfo {
+                                        conn_type: ConnectionType::Direct,
+                                        rtt: Some(rtt),
+                                        peer_addr: unmap_ipv4(dest_addr),
+                                        relay: None,
+                                        cipher: RecordCipher::default(),
+                                        quic: false,
+                                        noise: false,
+                                    },
+                                ))
                             }
                         })
                         .map(|fut| Box::pin(fut) as ConnectorFuture),
                 ),
             ) as BoxIterator<ConnectorFuture>;
-            Some(socket2)
+            socket2
         } else {
             None
         };
 
+        /* Dial the peer's QUIC hints, from the same socket that accepts its connections */
+        #[cfg(feature = "quic")]
+        if let Some(endpoint) = &quic {
+            let endpoint = endpoint.clone();
+            let resolver = resolver.clone();
+            connectors = Box::new(
+                connectors.chain(
+                    happy_eyeballs_order(&their_hints.direct_quic)
+                        .into_iter()
+                        .take(50)
+                        .enumerate()
+                        .map(move |(index, hint)| {
+                            let endpoint = endpoint.clone();
+                            let resolver = resolver.clone();
+                            async move {
+                                async_std::task::sleep(dial_delay(index)).await;
+                                let dest_addr =
+                                    resolve_direct_hint(&hint, resolver.as_ref()).await?;
+                                log::debug!("Connecting directly to {} over QUIC", dest_addr);
+                                let (socket, rtt) = async_std::future::timeout(
+                                    timeouts.dial,
+                                    quic::connect(&endpoint, dest_addr),
+                                )
+                                .await
+                                .map_err(|_| {
+                                    std::io::Error::new(
+                                        std::io::ErrorKind::TimedOut,
+                                        "Connecting over QUIC timed out",
+                                    )
+                                })??;
+                                log::debug!("Connected to {} (RTT: {:?})!", dest_addr, rtt);
+                                Ok((
+                                    Box::new(socket) as Box<dyn TransitSocket>,
+                                    TransitInfo {
+                                        conn_type: ConnectionType::Direct,
+                                        rtt: Some(rtt),
+                                        peer_addr: unmap_ipv4(dest_addr),
+                                        relay: None,
+                                        cipher: RecordCipher::default(),
+                                        quic: true,
+                                        noise: false,
+                                    },
+                                ))
+                            }
+                        })
+                        .map(|fut| Box::pin(fut) as ConnectorFuture),
+                ),
+            ) as BoxIterator<ConnectorFuture>;
+        }
+
         /* Relay hints. Make sure that both sides adverize it, since it is fine to support it without providing own hints. */
         if our_abilities.can_relay() && their_abilities.can_relay() {
-            /* Collect intermediate into HashSet for deduplication */
+            /* Merge the hints of both sides, so that every relay gets tried only once. All of ours
+             * are used, but the peer could send us arbitrarily many
+             */
             let mut relay_hints = Vec::<RelayHint>::new();
-            relay_hints.extend(our_hints.relay.iter().take(2).cloned());
-            for hint in their_hints.relay.iter().take(2).cloned() {
+            relay_hints.extend(our_hints.relay.iter().cloned());
+            for hint in their_hints.relay.iter().take(10).cloned() {
                 hint.merge_into(&mut relay_hints);
             }
-
-            /* Take a relay hint and try to connect to it */
-            async fn hint_connector(
-                host: DirectHint,
-            ) -> Result<(TcpStream, HostType), TransitHandshakeError> {
-                log::debug!("Connecting to relay {}", host);
-                let transit = TcpStream::connect((host.hostname.as_str(), host.port))
-                    .err_into::<TransitHandshakeError>()
-                    .await?;
-                log::debug!("Connected to {}!", host);
-
-                Ok((transit, HostType::Relay))
-            }
+            /* Highest priority first. The sort is stable, so for equal priorities ours come first */
+            relay_hints.sort_by(|a, b| {
+                b.priority
+                    .partial_cmp(&a.priority)
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            });
+            let mut priorities = relay_hints
+                .iter()
+                .map(|hint| hint.priority)
+                .collect::<Vec<_>>();
+            priorities.dedup();
+            let websocket = our_abilities.websocket_v1;
 
             connectors = Box::new(
                 connectors.chain(
@@ -1048,14 +2248,69 @@ impl TransitConnector {
                          * up to three different addresses. To not flood the system with requests, we
                          * start them in a 5 seconds interval spread. If one of them succeeds, the remaining ones
                          * will be cancelled anyways. Note that a hint might not necessarily be reachable via TCP.
+                         *
+                         * Relays with a lower priority get an additional delay, so that they only get used
+                         * if the preferred ones are not reachable.
+                         *
+                         * The WebSocket URLs of a relay (if we may use them) come after its TCP addresses.
                          */
-                        .flat_map(|hint| hint.tcp.into_iter().take(3).enumerate())
-                        .map(|(index, host)| async move {
-                            async_std::task::sleep(std::time::Duration::from_secs(
-                                index as u64 * 5,
-                            ))
-                            .await;
-                            hint_connector(host).await
+                        .flat_map(move |hint| {
+                            let rank = priorities
+                                .iter()
+                                .position(|priority| *priority == hint.priority)
+                                .unwrap_or(0);
+                            let priority = hint.priority;
+                            let name = hint.name;
+                            let websockets = hint
+                                .ws
+                                .into_iter()
+                                .filter(move |_| websocket)
+                                .filter_map(|url| {
+                                    let host = DirectHint::new(
+                                        url.host_str()?,
+                                        url.port_or_known_default()?,
+                                    );
+                                    Some((host, Some(url)))
+                                })
+                                .take(2);
+                            hint.tcp
+                                .into_iter()
+                                .take(3)
+                                .map(|host| (host, None))
+                                .chain(websockets)
+                                .enumerate()
+                                .map(move |(index, (host, websocket))| {
+                                    (rank, index, priority, name.clone(), host, websocket)
+                                })
+                        })
+                        .map(move |(rank, index, priority, name, host, websocket)| {
+                            let resolver = resolver.clone();
+                            let proxy = proxy.clone();
+                            let relay_info = RelayInfo {
+                                hint: host,
+                                websocket,
+                                priority,
+                                name,
+                                fallback: rank > 0,
+                            };
+                            async move {
+                                async_std::task::sleep(
+                                    timeouts.relay_fallback_delay * rank as u32
+                                        + std::time::Duration::from_secs(index as u64 * 5),
+                                )
+                                .await;
+                                async_std::future::timeout(
+                                    timeouts.dial,
+                                    hint_connector(relay_info, resolver, proxy),
+                                )
+                                .await
+                                .map_err(|_| {
+                                    std::io::Error::new(
+                                        std::io::ErrorKind::TimedOut,
+                                        "Connecting to the relay timed out",
+                                    )
+                                })?
+                            }
                         })
                         .map(|fut| Box::pin(fut) as ConnectorFuture),
                 ),
@@ -1071,18 +2326,66 @@ impl TransitConnector {
                     let transit_key = transit_key2.clone();
                     let tside = tside2.clone();
                     async move {
-                        let (socket, host_type) = fut.await?;
+                        let (socket, info) = fut.await?;
+                        let host_type = info.conn_type;
                         let transit =
-                            handshake_exchange(is_leader, tside, socket, host_type, transit_key)
-                                .await?;
+                            handshake_exchange(is_leader, tside, socket, info, transit_key).await?;
                         Ok((transit, host_type))
                     }
                 })
                 .map(|fut| {
-                    Box::pin(fut) as BoxFuture<Result<(Transit, HostType), TransitHandshakeError>>
+                    Box::pin(fut)
+                        as BoxFuture<Result<(Transit, ConnectionType), TransitHandshakeError>>
                 }),
         )
-            as BoxIterator<BoxFuture<Result<(Transit, HostType), TransitHandshakeError>>>;
+            as BoxIterator<BoxFuture<Result<(Transit, ConnectionType), TransitHandshakeError>>>;
+
+        /* Accept the peer's QUIC connections */
+        #[cfg(feature = "quic")]
+        if let Some(endpoint) = quic {
+            let transit_key = transit_key.clone();
+            let tside = tside.clone();
+            connectors = Box::new(
+                connectors.chain(
+                    std::iter::once(async move {
+                        loop {
+                            let connect = async {
+                                let (socket, peer) = quic::accept(&endpoint).await?;
+                                log::debug!("Got connection from {} over QUIC!", peer);
+                                handshake_exchange(
+                                    is_leader,
+                                    tside.clone(),
+                                    Box::new(socket),
+                                    TransitInfo {
+                                        conn_type: ConnectionType::Direct,
+                                        rtt: None,
+                                        peer_addr: unmap_ipv4(peer),
+                                        relay: None,
+                                        cipher: RecordCipher::default(),
+                                        quic: true,
+                                        noise: false,
+                                    },
+                                    transit_key.clone(),
+                                )
+                                .await
+                            };
+                            match connect.await {
+                                Ok(transit) => break Ok((transit, ConnectionType::Direct)),
+                                Err(err) => log::debug!(
+                                    "Some handshake failed on the QUIC socket: {:?}",
+                                    err
+                                ),
+                            }
+                        }
+                    })
+                    .map(|fut| {
+                        Box::pin(fut)
+                            as BoxFuture<Result<(Transit, ConnectionType), TransitHandshakeError>>
+                    }),
+                ),
+            )
+                as BoxIterator<BoxFuture<Result<(Transit, ConnectionType), TransitHandshakeError>>>;
+        }
 
         /* Also listen on some port just in case. */
         if let Some(socket2) = socket2 {
@@ -1097,12 +2400,23 @@ impl TransitConnector {
                             let transit = handshake_exchange(
                                 is_leader,
                                 tside.clone(),
-                                stream,
-                                HostType::Direct,
+                                Box::new(stream),
+                                TransitInfo {
+                                    conn_type: ConnectionType::Direct,
+                                    rtt: None,
+                                    peer_addr: unmap_ipv4(peer),
+                                    relay: None,
+                                    cipher: RecordCipher::default(),
+                                    quic: false,
+                                    noise: false,
+                                },
                                 transit_key.clone(),
                             )
                             .await?;
-                            Result::<_, TransitHandshakeError>::Ok((transit, HostType::Direct))
+                            Result::<_, TransitHandshakeError>::Ok((
+                                transit,
+                                ConnectionType::Direct,
+                            ))
                         };
                         loop {
                             match connect().await {
@@ -1119,25 +2433,87 @@ impl TransitConnector {
                     })
                     .map(|fut| {
                         Box::pin(fut)
-                            as BoxFuture<Result<(Transit, HostType), TransitHandshakeError>>
+                            as BoxFuture<Result<(Transit, ConnectionType), TransitHandshakeError>>
                     }),
                 ),
             )
-                as BoxIterator<BoxFuture<Result<(Transit, HostType), TransitHandshakeError>>>;
+                as BoxIterator<BoxFuture<Result<(Transit, ConnectionType), TransitHandshakeError>>>;
         }
         connectors.collect::<futures::stream::futures_unordered::FuturesUnordered<_>>()
     }
 }
 
+/** The message both sides send each other in [`connect`] */
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct HintsMessage {
+    abilities_v1: Abilities,
+    hints_v1: Hints,
+}
+
+/**
+ * Establish a transit connection with the peer of a wormhole, for protocols of your own
+ *
+ * Both sides send their [`Abilities`] and [`Hints`] as the next message over the wormhole,
+ * `{"abilities-v1": …, "hints-v1": …}`, and connect with the next key of
+ * [`Wormhole::next_transit_key`](crate::Wormhole::next_transit_key). One side must be the leader,
+ * and the other one the follower. The wormhole stays open, for the application to use or close.
+ * It may be called several times, each connection gets a key of its own, but both sides must then
+ * make the same connections in the same order.
+ *
+ * Use the resulting [`Transit`] for records, or as byte stream via [`Transit::into_stream`].
+ */
+pub async fn connect(
+    wormhole: &mut crate::Wormhole,
+    leader: bool,
+    relay_hints: Vec<RelayHint>,
+    config: &TransitConfig,
+) -> Result<Transit, TransitConnectError> {
+    let connector = init(Abilities::ALL_ABILITIES, None, relay_hints, config).await?;
+    wormhole
+        .send_json(&HintsMessage {
+            abilities_v1: *connector.our_abilities(),
+            hints_v1: (**connector.our_hints()).clone(),
+        })
+        .await?;
+    let theirs: HintsMessage = wormhole
+        .receive_json()
+        .await?
+        .map_err(crate::WormholeError::ProtocolJson)?;
+
+    let transit_key = wormhole.next_transit_key();
+    let their_hints = Arc::new(theirs.hints_v1);
+    wormhole
+        .events()
+        .emit(crate::WormholeEvent::TransitConnecting);
+    let transit = if leader {
+        connector
+            .leader_connect(transit_key, theirs.abilities_v1, their_hints)
+            .await?
+    } else {
+        connector
+            .follower_connect(transit_key, theirs.abilities_v1, their_hints)
+            .await?
+    };
+    wormhole.events().emit(crate::WormholeEvent::Transferring);
+    Ok(transit)
+}
+
+/** The connection underneath a [`Transit`]: TCP, or a WebSocket to a relay */
+trait TransitSocket: futures::io::AsyncRead + futures::io::AsyncWrite + Send + Unpin {}
+
+impl<T: futures::io::AsyncRead + futures::io::AsyncWrite + Send + Unpin> TransitSocket for T {}
+
 /**
  * An established Transit connection.
  *
  * While you can manually send and receive bytes over the TCP stream, this is not recommended as the transit protocol
- * also specifies an encrypted record pipe that does all the hard work for you. See the provided methods.
+ * also specifies an encrypted record pipe that does all the hard work for you. See the provided methods,
+ * or [`into_stream`](Self::into_stream) to use it like any other byte stream.
  */
 pub struct Transit {
     /** Raw transit connection */
-    socket: TcpStream,
+    socket: Box<dyn TransitSocket>,
     /** Our key, used for sending */
     pub skey: Key<TransitTxKey>,
     /** Their key, used for receiving */
@@ -1150,97 +2526,140 @@ pub struct Transit {
      * We'll count as receiver and track if messages come in in order
      */
     pub rnonce: secretbox::Nonce,
+    cipher: RecordCipher,
+    rate_limit: Option<RateLimit>,
+    stats: Arc<stats::Counters>,
+    info: TransitInfo,
 }
 
 impl Transit {
+    /** How the connection was established */
+    pub fn info(&self) -> &TransitInfo {
+        &self.info
+    }
+
+    /** Which cipher has been negotiated for the records */
+    pub fn cipher(&self) -> RecordCipher {
+        self.cipher
+    }
+
+    /** How the connection was established, and how much went over it so far */
+    pub fn stats(&self) -> TransitStats {
+        self.stats.get()
+    }
+
+    /** Count from now on, and report to the callback (if any) */
+    fn start_stats(&mut self, counters: stats::Counters, callback: Option<&StatsCallback>) {
+        self.stats = Arc::new(counters);
+        if let Some(callback) = callback {
+            callback.start(&self.stats);
+        }
+    }
+
     /** Receive and decrypt one message from the other side. */
     pub async fn receive_record(&mut self) -> Result<Box<[u8]>, TransitError> {
-        Transit::receive_record_inner(&mut self.socket, &self.rkey, &mut self.rnonce).await
+        Transit::receive_record_inner(
+            &mut self.socket,
+            self.cipher,
+            &self.rkey,
+            &mut self.rnonce,
+            self.rate_limit.as_ref(),
+            &self.stats,
+        )
+        .await
     }
 
     async fn receive_record_inner(
         socket: &mut (impl futures::io::AsyncRead + Unpin),
+        cipher: RecordCipher,
         rkey: &Key<TransitRxKey>,
         nonce: &mut secretbox::Nonce,
+        rate_limit: Option<&RateLimit>,
+        stats: &stats::Counters,
     ) -> Result<Box<[u8]>, TransitError> {
-        let enc_packet = {
-            // 1. read 4 bytes from the stream. This represents the length of the encrypted packet.
-            let length = {
-                let mut length_arr: [u8; 4] = [0; 4];
-                socket.read_exact(&mut length_arr[..]).await?;
-                u32::from_be_bytes(length_arr) as usize
-            };
-            ensure!(
-                length >= secretbox::NONCE_SIZE,
-                Error::new(
-                    ErrorKind::InvalidData,
-                    "Message must be long enough to contain at least the nonce"
-                )
-            );
-
-            // 2. read that many bytes into an array (or a vector?)
-            let mut buffer = Vec::with_capacity(length);
-            let len = socket.take(length as u64).read_to_end(&mut buffer).await?;
-            use std::io::{Error, ErrorKind};
-            ensure!(
-                len == length,
-                Error::new(ErrorKind::UnexpectedEof, "failed to read whole message")
-            );
-            buffer
+        // 1. read 4 bytes from the stream. This represents the length of the encrypted packet.
+        let length = {
+            let mut length_arr: [u8; 4] = [0; 4];
+            socket.read_exact(&mut length_arr[..]).await?;
+            u32::from_be_bytes(length_arr) as usize
         };
+        ensure!(
+            length >= secretbox::NONCE_SIZE,
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Message must be long enough to contain at least the nonce"
+            )
+        );
+        ensure!(
+            length <= MAX_SEALED_RECORD_SIZE,
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("The record of {} bytes is too long", length)
+            )
+        );
 
-        // 3. decrypt the vector 'enc_packet' with the key.
-        let plaintext = {
-            let (received_nonce, ciphertext) = enc_packet.split_at(secretbox::NONCE_SIZE);
-            {
-                // Nonce check
-                ensure!(
-                    nonce.as_slice() == received_nonce,
-                    TransitError::Nonce(received_nonce.into(), nonce.as_slice().into()),
-                );
-
-                crate::util::sodium_increment_be(nonce);
-            }
+        // 2. read that many bytes into a buffer, which then gets decrypted in place
+        let mut record = vec![0; length];
+        socket.read_exact(&mut record).await?;
+        if let Some(rate_limit) = rate_limit {
+            rate_limit.throttle(record.len()).await;
+        }
 
-            let cipher = secretbox::XSalsa20Poly1305::new(secretbox::Key::from_slice(rkey));
-            cipher
-                .decrypt(secretbox::Nonce::from_slice(received_nonce), ciphertext)
-                /* TODO replace with (TransitError::Crypto) after the next xsalsa20poly1305 update */
-                .map_err(|_| TransitError::Crypto)?
-        };
+        // 3. check the nonce, and decrypt the record with the key.
+        let received_nonce = &record[..secretbox::NONCE_SIZE];
+        ensure!(
+            nonce.as_slice() == received_nonce,
+            TransitError::Nonce(received_nonce.into(), nonce.as_slice().into()),
+        );
+        crate::util::sodium_increment_be(nonce);
+        stats.received(4 + record.len());
+        let plaintext = cipher.open(rkey, record)?;
 
         Ok(plaintext.into_boxed_slice())
     }
 
     /** Send an encrypted message to the other side */
     pub async fn send_record(&mut self, plaintext: &[u8]) -> Result<(), TransitError> {
-        Transit::send_record_inner(&mut self.socket, &self.skey, plaintext, &mut self.snonce).await
+        Transit::send_record_inner(
+            &mut self.socket,
+            self.cipher,
+            &self.skey,
+            plaintext,
+            &mut self.snonce,
+            self.rate_limit.as_ref(),
+            &self.stats,
+        )
+        .await
     }
 
     async fn send_record_inner(
         socket: &mut (impl futures::io::AsyncWrite + Unpin),
+        cipher: RecordCipher,
         skey: &Key<TransitTxKey>,
         plaintext: &[u8],
         nonce: &mut secretbox::Nonce,
+        rate_limit: Option<&RateLimit>,
+        stats: &stats::Counters,
     ) -> Result<(), TransitError> {
-        let sodium_key = secretbox::Key::from_slice(skey);
-
-        let ciphertext = {
-            let nonce_le = secretbox::Nonce::from_slice(nonce);
-
-            let cipher = secretbox::XSalsa20Poly1305::new(sodium_key);
-            cipher
-                .encrypt(nonce_le, plaintext)
-                /* TODO replace with (TransitError::Crypto) after the next xsalsa20poly1305 update */
-                .map_err(|_| TransitError::Crypto)?
-        };
+        ensure!(
+            plaintext.len() <= MAX_RECORD_SIZE,
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "Records may not be longer than {} bytes, this one has {}",
+                    MAX_RECORD_SIZE,
+                    plaintext.len()
+                )
+            )
+        );
+        let record = cipher.seal(skey, nonce, plaintext)?;
+        if let Some(rate_limit) = rate_limit {
+            rate_limit.throttle(record.len() - 4).await;
+        }
 
         // send the encrypted record
-        socket
-            .write_all(&((ciphertext.len() + nonce.len()) as u32).to_be_bytes())
-            .await?;
-        socket.write_all(nonce).await?;
-        socket.write_all(&ciphertext).await?;
+        socket.write_all(&record).await?;
+        stats.sent(record.len());
 
         crate::util::sodium_increment_be(nonce);
 
@@ -1261,32 +2680,164 @@ impl Transit {
         use futures::io::AsyncReadExt;
 
         let (reader, writer) = self.socket.split();
+        let cipher = self.cipher;
+        let rate_limit = self.rate_limit;
+        let rate_limit2 = rate_limit.clone();
+        let stats = self.stats;
+        let stats2 = stats.clone();
         (
             futures::sink::unfold(
                 (writer, self.skey, self.snonce),
-                |(mut writer, skey, mut nonce), plaintext: Box<[u8]>| async move {
-                    Transit::send_record_inner(
-                        &mut writer,
-                        &skey as &Key<TransitTxKey>,
-                        &plaintext,
-                        &mut nonce,
-                    )
-                    .await
-                    .map(|()| (writer, skey, nonce))
+                move |(mut writer, skey, mut nonce), plaintext: Box<[u8]>| {
+                    let rate_limit = rate_limit.clone();
+                    let stats = stats.clone();
+                    async move {
+                        Transit::send_record_inner(
+                            &mut writer,
+                            cipher,
+                            &skey as &Key<TransitTxKey>,
+                            &plaintext,
+                            &mut nonce,
+                            rate_limit.as_ref(),
+                            &stats,
+                        )
+                        .await
+                        .map(|()| (writer, skey, nonce))
+                    }
                 },
             ),
             futures::stream::try_unfold(
                 (reader, self.rkey, self.rnonce),
-                |(mut reader, rkey, mut nonce)| async move {
-                    Transit::receive_record_inner(&mut reader, &rkey, &mut nonce)
+                move |(mut reader, rkey, mut nonce)| {
+                    let rate_limit = rate_limit2.clone();
+                    let stats = stats2.clone();
+                    async move {
+                        Transit::receive_record_inner(
+                            &mut reader,
+                            cipher,
+                            &rkey,
+                            &mut nonce,
+                            rate_limit.as_ref(),
+                            &stats,
+                        )
                         .await
                         .map(|record| Some((record, (reader, rkey, nonce))))
+                    }
                 },
             ),
         )
     }
 }
 
+/** Take a relay hint and try to connect to it */
+async fn hint_connector(
+    relay_info: RelayInfo,
+    resolver: Option<Resolver>,
+    proxy: Option<Proxy>,
+) -> Result<(Box<dyn TransitSocket>, TransitInfo), TransitHandshakeError> {
+    let host = &relay_info.hint;
+    log::debug!(
+        "Connecting to relay {} (priority {})",
+        relay_info
+            .websocket
+            .as_ref()
+            .map_or_else(|| host.to_string(), url::Url::to_string),
+        relay_info.priority
+    );
+    let (transit, peer_addr) = match &relay_info.websocket {
+        Some(url) => {
+            let (transit, peer_addr) =
+                websocket::connect(url, resolver.as_ref(), proxy.as_ref()).await?;
+            (Box::new(transit) as Box<dyn TransitSocket>, peer_addr)
+        },
+        None => {
+            let transit =
+                crate::proxy::connect(proxy.as_ref(), resolver.as_ref(), &host.hostname, host.port)
+                    .await?;
+            let peer_addr = transit.peer_addr()?;
+            (Box::new(transit) as Box<dyn TransitSocket>, peer_addr)
+        },
+    };
+    log::debug!("Connected to {}!", host);
+
+    Ok((
+        transit,
+        TransitInfo {
+            conn_type: ConnectionType::Relay,
+            rtt: None,
+            peer_addr,
+            relay: Some(relay_info),
+            cipher: RecordCipher::default(),
+            quic: false,
+            noise: false,
+        },
+    ))
+}
+
+/**
+ * Open more connections through the relay of `transit`, and stripe the records over all of them
+ *
+ * Direct connections are left alone. Each of the new connections gets its own transit key for the
+ * relay and the handshake, so that they don't get mixed up. The leader sends "go" on them once all
+ * handshakes succeeded, so that both sides agree on the connections. If one of them fails, the
+ * whole connection does, since the records are already bound to the number of connections.
+ */
+async fn connect_parallel(
+    is_leader: bool,
+    transit: Transit,
+    connections: u16,
+    transit_key: &Key<TransitKey>,
+    resolver: Option<Resolver>,
+    proxy: Option<Proxy>,
+    timeouts: TransitTimeouts,
+) -> Result<Transit, TransitConnectError> {
+    let relay_info = match &transit.info.relay {
+        Some(relay_info) if connections > 1 => relay_info.clone(),
+        _ => return Ok(transit),
+    };
+    let tside = Arc::new(hex::encode(rand::random::<[u8; 8]>()));
+    let handshakes = futures::future::try_join_all((1..connections).map(|index| {
+        let key = Arc::new(
+            transit_key.derive_subkey_from_purpose(&format!("transit_parallel_{}", index)),
+        );
+        let relay_info = relay_info.clone();
+        let resolver = resolver.clone();
+        let proxy = proxy.clone();
+        let tside = tside.clone();
+        async move {
+            let (socket, info) = hint_connector(relay_info, resolver, proxy).await?;
+            handshake_exchange(is_leader, tside, socket, info, key).await
+        }
+    }));
+    let extra = match async_std::future::timeout(timeouts.connect, handshakes).await {
+        Ok(Ok(extra)) => extra,
+        Ok(Err(err)) => {
+            log::debug!("Opening the parallel relay connections failed: {:?}", err);
+            return Err(TransitConnectError::Handshake);
+        },
+        Err(_) => {
+            log::debug!("Opening the parallel relay connections timed out");
+            return Err(TransitConnectError::Handshake);
+        },
+    };
+
+    let mut sockets = vec![transit.socket];
+    for mut connection in extra {
+        if is_leader {
+            connection.socket.write_all(b"go\n").await?;
+        }
+        sockets.push(connection.socket);
+    }
+    info!(
+        "Striping the records over {} relay connections",
+        connections
+    );
+    Ok(Transit {
+        socket: Box::new(parallel::Striped::new(sockets)),
+        ..transit
+    })
+}
+
 /**
  * Do a transit handshake exchange, to establish a direct connection.
  *
@@ -1299,8 +2850,8 @@ impl Transit {
 async fn handshake_exchange(
     is_leader: bool,
     tside: Arc<String>,
-    mut socket: TcpStream,
-    host_type: HostType,
+    mut socket: Box<dyn TransitSocket>,
+    info: TransitInfo,
     key: Arc<Key<TransitKey>>,
 ) -> Result<Transit, TransitHandshakeError> {
     // 9. create record keys
@@ -1318,7 +2869,7 @@ async fn handshake_exchange(
         (rkey, skey)
     };
 
-    if host_type == HostType::Relay {
+    if info.conn_type == ConnectionType::Relay {
         trace!("initiating relay handshake");
 
         let sub_key = key.derive_subkey_from_purpose::<crate::GenericKey>("transit_relay_token");
@@ -1396,5 +2947,236 @@ async fn handshake_exchange(
         rkey,
         snonce: Default::default(),
         rnonce: Default::default(),
+        /* Both set by the caller once the connection has been chosen */
+        cipher: RecordCipher::default(),
+        rate_limit: None,
+        stats: Default::default(),
+        info,
     })
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_seal_open() {
+        let key = [7; 32];
+        let nonce = [1; secretbox::NONCE_SIZE];
+        for cipher in [
+            RecordCipher::XSalsa20Poly1305,
+            RecordCipher::XChaCha20Poly1305,
+        ] {
+            let mut record = cipher.seal(&key, &nonce, b"hello").unwrap();
+            assert_eq!(record.len(), 4 + secretbox::NONCE_SIZE + TAG_SIZE + 5);
+            assert_eq!(record[..4], ((record.len() - 4) as u32).to_be_bytes());
+            assert_eq!(record[4..4 + secretbox::NONCE_SIZE], nonce);
+            assert_eq!(cipher.open(&key, record[4..].to_vec()).unwrap(), b"hello");
+
+            *record.last_mut().unwrap() ^= 1;
+            assert!(cipher.open(&key, record[4..].to_vec()).is_err());
+            assert!(cipher.open(&key, nonce.to_vec()).is_err());
+        }
+
+        /* What the other implementations send */
+        use xsalsa20poly1305::aead::Aead;
+        let secretbox = secretbox::XSalsa20Poly1305::new(secretbox::Key::from_slice(&key))
+            .encrypt(secretbox::Nonce::from_slice(&nonce), &b"hello"[..])
+            .unwrap();
+        let record = RecordCipher::XSalsa20Poly1305
+            .seal(&key, &nonce, b"hello")
+            .unwrap();
+        assert_eq!(record[4 + secretbox::NONCE_SIZE..], secretbox[..]);
+    }
+
+    #[async_std::test]
+    async fn test_record_size_limit() {
+        let stats = stats::Counters::default();
+        let mut nonce = secretbox::Nonce::default();
+        let rkey = Key::<TransitRxKey>::new(Box::new([7; 32].into()));
+        let mut socket = futures::io::Cursor::new(u32::MAX.to_be_bytes().to_vec());
+        let error = Transit::receive_record_inner(
+            &mut socket,
+            RecordCipher::default(),
+            &rkey,
+            &mut nonce,
+            None,
+            &stats,
+        )
+        .await
+        .unwrap_err();
+        assert!(
+            matches!(error, TransitError::IO(error) if error.kind() == std::io::ErrorKind::InvalidData)
+        );
+
+        let skey = Key::<TransitTxKey>::new(Box::new([7; 32].into()));
+        let mut socket = futures::io::Cursor::new(Vec::new());
+        for (length, ok) in [(MAX_RECORD_SIZE, true), (MAX_RECORD_SIZE + 1, false)] {
+            let result = Transit::send_record_inner(
+                &mut socket,
+                RecordCipher::default(),
+                &skey,
+                &vec![0; length],
+                &mut nonce,
+                None,
+                &stats,
+            )
+            .await;
+            assert_eq!(result.is_ok(), ok);
+        }
+    }
+    #[test]
+    fn test_happy_eyeballs_order() {
+        let hints = [
+            ("192.0.2.1", 1),
+            ("relay.example", 2),
+            ("2001:db8::1", 3),
+            ("2001:db8::2", 4),
+        ]
+        .iter()
+        .map(|&(hostname, port)| DirectHint::new(hostname, port))
+        .collect::<HashSet<_>>();
+        let ports = happy_eyeballs_order(&hints)
+            .iter()
+            .map(|hint| hint.port)
+            .collect::<Vec<_>>();
+        assert_eq!(ports, vec![3, 1, 4, 2]);
+
+        assert!(is_hint_address("192.0.2.1".parse().unwrap()));
+        assert!(is_hint_address("2001:db8::1".parse().unwrap()));
+        assert!(!is_hint_address("::1".parse().unwrap()));
+        assert!(!is_hint_address("fe80::1".parse().unwrap()));
+        assert!(!is_hint_address("169.254.1.1".parse().unwrap()));
+    }
+
+    #[cfg(feature = "relay-server")]
+    #[async_std::test]
+    async fn test_parallel_connections() {
+        use futures::FutureExt;
+
+        let server = relay_server::RelayServer::bind("127.0.0.1:0", Default::default())
+            .await
+            .unwrap();
+        let relay = RelayHint::from_url(
+            format!("tcp://{}", server.local_addr().unwrap())
+                .parse()
+                .unwrap(),
+        );
+        let (stats_sender, stats_receiver) = async_std::channel::unbounded();
+        let config = TransitConfig::default()
+            .policy(ConnectionPolicy::RelayOnly)
+            .parallel_connections(3)
+            .stats_callback(StatsCallback::new(
+                std::time::Duration::from_millis(10),
+                move |stats| {
+                    let _ = stats_sender.try_send(stats);
+                },
+            ));
+        let leader = init(Abilities::ALL_ABILITIES, None, vec![relay.clone()], &config)
+            .await
+            .unwrap();
+        /* The lower number wins */
+        let follower = init(
+            Abilities::ALL_ABILITIES,
+            None,
+            vec![relay],
+            &config.parallel_connections(2),
+        )
+        .await
+        .unwrap();
+        let key = || Key::<TransitKey>::new(Box::new([7; 32].into()));
+        let (leader_abilities, leader_hints) =
+            (*leader.our_abilities(), leader.our_hints().clone());
+        let leader_connect = leader.leader_connect(
+            key(),
+            *follower.our_abilities(),
+            follower.our_hints().clone(),
+        );
+        let follower_connect = follower.follower_connect(key(), leader_abilities, leader_hints);
+
+        let transfer = async {
+            let (leader, follower) = futures::join!(leader_connect, follower_connect);
+            let (mut leader, mut follower) = (leader.unwrap(), follower.unwrap());
+            assert_eq!(server.usage().active_sessions, 2);
+            for i in 0..10 {
+                leader.send_record(&[i; 100]).await.unwrap();
+            }
+            for i in 0..10 {
+                assert_eq!(&*follower.receive_record().await.unwrap(), &[i; 100]);
+            }
+            follower.send_record(b"bye").await.unwrap();
+            assert_eq!(&*leader.receive_record().await.unwrap(), b"bye");
+            assert_eq!(follower.stats().records_received, 10);
+            assert_eq!(follower.stats().bytes_received, 10 * (4 + 24 + 16 + 100));
+            assert_eq!(leader.stats().failed_attempts, 0);
+
+            /* The callback gets them too, for both sides */
+            while stats_receiver.recv().await.unwrap() != leader.stats() {}
+        };
+        futures::select! {
+            () = server.run().fuse() => unreachable!(),
+            () = transfer.fuse() => {},
+        }
+    }
+
+    #[cfg(all(feature = "noise", feature = "relay-server"))]
+    #[async_std::test]
+    async fn test_noise() {
+        use futures::FutureExt;
+
+        let server = relay_server::RelayServer::bind("127.0.0.1:0", Default::default())
+            .await
+            .unwrap();
+        let relay = RelayHint::from_url(
+            format!("tcp://{}", server.local_addr().unwrap())
+                .parse()
+                .unwrap(),
+        );
+        let config = TransitConfig::default().policy(ConnectionPolicy::RelayOnly);
+        let key = || Key::<TransitKey>::new(Box::new([7; 32].into()));
+
+        /* Only used if both sides support it */
+        let transfer = async {
+            for follower_noise in [true, false] {
+                let leader = init(Abilities::ALL_ABILITIES, None, vec![relay.clone()], &config)
+                    .await
+                    .unwrap();
+                let follower_abilities = Abilities {
+                    noise_v1: follower_noise,
+                    ..Abilities::ALL_ABILITIES
+                };
+                let follower = init(follower_abilities, None, vec![relay.clone()], &config)
+                    .await
+                    .unwrap();
+                let (leader_abilities, leader_hints) =
+                    (*leader.our_abilities(), leader.our_hints().clone());
+                let (leader, follower) = futures::join!(
+                    leader.leader_connect(
+                        key(),
+                        *follower.our_abilities(),
+                        follower.our_hints().clone()
+                    ),
+                    follower.follower_connect(key(), leader_abilities, leader_hints),
+                );
+                let (mut leader, mut follower) = (leader.unwrap(), follower.unwrap());
+
+                assert_eq!(leader.info().noise, follower_noise);
+                assert_eq!(follower.info().noise, follower_noise);
+                let derived =
+                    key().derive_subkey_from_purpose::<TransitTxKey>("transit_record_sender_key");
+                assert_eq!(leader.skey.as_slice() != derived.as_slice(), follower_noise);
+                assert_eq!(leader.skey.as_slice(), follower.rkey.as_slice());
+                assert_eq!(leader.rkey.as_slice(), follower.skey.as_slice());
+
+                leader.send_record(b"hello").await.unwrap();
+                assert_eq!(&*follower.receive_record().await.unwrap(), b"hello");
+                follower.send_record(b"bye").await.unwrap();
+                assert_eq!(&*leader.receive_record().await.unwrap(), b"bye");
+            }
+        };
+        futures::select! {
+            () = server.run().fuse() => unreachable!(),
+            () = transfer.fuse() => {},
+        }
+    }
+}
@@ -0,0 +1,86 @@
+//! Record keys from a Noise handshake, see [`Abilities::noise_v1`](super::Abilities::noise_v1)
+//!
+//! With the classic transit handshake, the record keys are derived from the transit key alone.
+//! Whoever gets hold of that key later on can decrypt a recorded connection. If both sides support
+//! it, they additionally run a `Noise_NNpsk0_25519_ChaChaPoly_BLAKE2s` handshake over the chosen
+//! connection, with a pre-shared key derived from the transit key, and use the keys that come out
+//! of it for the records. These depend on ephemeral Diffie-Hellman keys, which are gone once the
+//! handshake is done.
+//!
+//! The leader initiates the handshake, right after it sent `go`. Each of the two handshake
+//! messages is framed like a record, with its length in front. The records themselves don't
+//! change, they are encrypted with the negotiated [`RecordCipher`](super::RecordCipher) as usual.
+
+use super::{secretbox, Key, Transit, TransitConnectError, TransitKey};
+use futures::{AsyncReadExt, AsyncWriteExt};
+
+const PATTERN: &str = "Noise_NNpsk0_25519_ChaChaPoly_BLAKE2s";
+/** Binds the handshake to its use, so that it cannot be confused with any other Noise protocol */
+const PROLOGUE: &[u8] = b"magic-wormhole transit noise-v1";
+/** The largest message Noise allows */
+const MAX_MESSAGE_SIZE: usize = 65535;
+
+fn handshake_error(error: snow::Error) -> TransitConnectError {
+    log::debug!("The Noise handshake failed: {}", error);
+    TransitConnectError::Handshake
+}
+
+/**
+ * Run the handshake on the connection, and replace the record keys with the ones from it
+ *
+ * This must happen before anything else goes over the connection.
+ */
+pub(super) async fn handshake(
+    is_leader: bool,
+    transit: &mut Transit,
+    transit_key: &Key<TransitKey>,
+) -> Result<(), TransitConnectError> {
+    let psk = transit_key.derive_subkey_from_purpose::<crate::GenericKey>("transit_noise_psk");
+    let builder = snow::Builder::new(PATTERN.parse().unwrap())
+        .prologue(PROLOGUE)
+        .psk(0, psk.as_slice());
+    let mut noise = if is_leader {
+        builder.build_initiator()
+    } else {
+        builder.build_responder()
+    }
+    .map_err(handshake_error)?;
+
+    let mut buffer = vec![0; MAX_MESSAGE_SIZE];
+    while !noise.is_handshake_finished() {
+        if noise.is_my_turn() {
+            let length = noise
+                .write_message(&[], &mut buffer)
+                .map_err(handshake_error)?;
+            transit
+                .socket
+                .write_all(&(length as u32).to_be_bytes())
+                .await?;
+            transit.socket.write_all(&buffer[..length]).await?;
+            transit.socket.flush().await?;
+        } else {
+            let mut length = [0; 4];
+            transit.socket.read_exact(&mut length).await?;
+            let length = u32::from_be_bytes(length) as usize;
+            ensure!(length <= MAX_MESSAGE_SIZE, TransitConnectError::Handshake);
+            let mut message = vec![0; length];
+            transit.socket.read_exact(&mut message).await?;
+            noise
+                .read_message(&message, &mut buffer)
+                .map_err(handshake_error)?;
+        }
+    }
+
+    /* The first key is for the messages of the initiator, the second one for the responder */
+    let (initiator, responder) = noise.dangerously_get_raw_split();
+    let (skey, rkey) = if is_leader {
+        (initiator, responder)
+    } else {
+        (responder, initiator)
+    };
+    transit.skey = Key::new(Box::new(secretbox::Key::clone_from_slice(&skey)));
+    transit.rkey = Key::new(Box::new(secretbox::Key::clone_from_slice(&rkey)));
+    transit.info.noise = true;
+    log::debug!("Replaced the record keys with the ones from the Noise handshake");
+    Ok(())
+}
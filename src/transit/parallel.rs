@@ -0,0 +1,176 @@
+//! Stripe the records of a [`Transit`](super::Transit) over several connections
+//!
+//! On paths with a high latency, a single TCP connection cannot use all of the bandwidth,
+//! because its window limits how much may be underway at a time. With several connections,
+//! the records are sent over each of them in turns, and read back in the same turns on the
+//! other side. Both sides count the records the same way, so they stay in order without any
+//! additional framing.
+
+use futures::{
+    io::{AsyncRead, AsyncWrite},
+    ready,
+};
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/** Which connection is in turn, and how far we are into its current record */
+#[derive(Default)]
+struct Turn {
+    connection: usize,
+    /** The length of the record, as far as we have it */
+    length: [u8; 4],
+    /** Bytes of the record (including its length) that went over the connection so far */
+    position: usize,
+}
+
+impl Turn {
+    /** How many bytes may go over the current connection, until the next part of the record */
+    fn limit(&self) -> usize {
+        if self.position < 4 {
+            4 - self.position
+        } else {
+            4 + u32::from_be_bytes(self.length) as usize - self.position
+        }
+    }
+
+    /** Account for `bytes` of the current record, and switch connections after its end */
+    fn advance(&mut self, bytes: &[u8], connections: usize) {
+        if self.position < 4 {
+            self.length[self.position..self.position + bytes.len()].copy_from_slice(bytes);
+        }
+        self.position += bytes.len();
+        if self.position >= 4 && self.limit() == 0 {
+            self.connection = (self.connection + 1) % connections;
+            self.position = 0;
+        }
+    }
+}
+
+/**
+ * Several connections as one, with the records striped over them
+ *
+ * This only looks at the length of each record, so it must be read and written
+ * record by record, starting at the beginning of one.
+ */
+pub(super) struct Striped<S> {
+    sockets: Vec<S>,
+    read: Turn,
+    write: Turn,
+}
+
+impl<S> Striped<S> {
+    /** The records start with the first socket */
+    pub(super) fn new(sockets: Vec<S>) -> Self {
+        assert!(!sockets.is_empty());
+        Self {
+            sockets,
+            read: Turn::default(),
+            write: Turn::default(),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Striped<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let limit = buf.len().min(this.read.limit());
+        let socket = &mut this.sockets[this.read.connection];
+        let read = ready!(Pin::new(socket).poll_read(cx, &mut buf[..limit]))?;
+        /* The connections may only end between records */
+        if read == 0 && this.read.position > 0 {
+            return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+        }
+        this.read.advance(&buf[..read], this.sockets.len());
+        Poll::Ready(Ok(read))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Striped<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let limit = buf.len().min(this.write.limit());
+        let socket = &mut this.sockets[this.write.connection];
+        let written = ready!(Pin::new(socket).poll_write(cx, &buf[..limit]))?;
+        this.write.advance(&buf[..written], this.sockets.len());
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        for socket in &mut self.get_mut().sockets {
+            ready!(Pin::new(socket).poll_flush(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        for socket in &mut self.get_mut().sockets {
+            ready!(Pin::new(socket).poll_close(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::{io::Cursor, AsyncReadExt, AsyncWriteExt};
+
+    #[async_std::test]
+    async fn test_striped() {
+        let records = [&b"first"[..], b"", b"third record", b"4", b"fifth"];
+        let mut data = Vec::new();
+        for record in &records {
+            data.extend_from_slice(&(record.len() as u32).to_be_bytes());
+            data.extend_from_slice(record);
+        }
+
+        let mut striped = Striped::new(vec![Cursor::new(Vec::new()); 3]);
+        /* In odd pieces, so that they don't line up with the records */
+        for piece in data.chunks(3) {
+            striped.write_all(piece).await.unwrap();
+        }
+        striped.flush().await.unwrap();
+
+        for (index, socket) in striped.sockets.iter().enumerate() {
+            let mut expected = Vec::new();
+            for record in records.iter().skip(index).step_by(3) {
+                expected.extend_from_slice(&(record.len() as u32).to_be_bytes());
+                expected.extend_from_slice(record);
+            }
+            assert_eq!(socket.get_ref(), &expected);
+        }
+
+        let sockets = striped
+            .sockets
+            .into_iter()
+            .map(|socket| Cursor::new(socket.into_inner()))
+            .collect();
+        let mut received = Vec::new();
+        Striped::new(sockets)
+            .read_to_end(&mut received)
+            .await
+            .unwrap();
+        assert_eq!(received, data);
+
+        /* Ending in the middle of a record */
+        let mut truncated = Striped::new(vec![Cursor::new(data[..7].to_vec())]);
+        assert!(truncated.read_to_end(&mut Vec::new()).await.is_err());
+    }
+}
@@ -0,0 +1,275 @@
+//! Make our transit listener reachable from the internet, by asking the router to forward a port
+//!
+//! UPnP IGD is tried first, because most home routers speak it. NAT-PMP (RFC 6886) is the
+//! fallback, for the routers by Apple and some open source firmwares. The mapping gets removed
+//! again once it is dropped. If that does not happen, because we crashed or went offline, the
+//! router removes it on its own once the lease expires.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+/** Plenty of time for the peer to connect, even with the transit timeouts raised */
+const LEASE_DURATION: u32 = 3600;
+/** Shows up in the router's web interface */
+const DESCRIPTION: &str = "magic-wormhole transit";
+/** How long to look for an UPnP gateway. Routers usually answer within milliseconds */
+const UPNP_SEARCH_TIMEOUT: Duration = Duration::from_secs(2);
+/** The port NAT-PMP gateways listen on */
+const NAT_PMP_PORT: u16 = 5351;
+
+/** A port forwarded by the router, until this gets dropped */
+pub(super) enum PortMapping {
+    Upnp {
+        gateway: igd_next::Gateway,
+        external_port: u16,
+    },
+    NatPmp {
+        gateway: SocketAddr,
+        internal_port: u16,
+    },
+}
+
+impl PortMapping {
+    /**
+     * Forward a TCP port to our `port`, and find out under which address it is reachable
+     *
+     * All errors only get logged, since there is nothing the user could do about them.
+     */
+    pub(super) async fn new(port: u16) -> Option<(Self, SocketAddr)> {
+        match async_std::task::spawn_blocking(move || map_upnp(port)).await {
+            Ok(mapping) => return Some(mapping),
+            Err(err) => log::debug!("Could not map a port with UPnP: {}", err),
+        }
+        match map_nat_pmp(port).await {
+            Ok(mapping) => Some(mapping),
+            Err(err) => {
+                log::debug!("Could not map a port with NAT-PMP: {}", err);
+                None
+            },
+        }
+    }
+}
+
+impl Drop for PortMapping {
+    fn drop(&mut self) {
+        match self {
+            PortMapping::Upnp {
+                gateway,
+                external_port,
+            } => {
+                let gateway = gateway.clone();
+                let external_port = *external_port;
+                async_std::task::spawn_blocking(move || {
+                    if let Err(err) =
+                        gateway.remove_port(igd_next::PortMappingProtocol::TCP, external_port)
+                    {
+                        log::debug!("Could not remove the UPnP port mapping: {}", err);
+                    }
+                });
+            },
+            PortMapping::NatPmp {
+                gateway,
+                internal_port,
+            } => {
+                let gateway = *gateway;
+                let internal_port = *internal_port;
+                async_std::task::spawn(async move {
+                    /* A mapping request with a lifetime of zero deletes it */
+                    if let Err(err) = nat_pmp_map(gateway, internal_port, 0, 0).await {
+                        log::debug!("Could not remove the NAT-PMP port mapping: {}", err);
+                    }
+                });
+            },
+        }
+    }
+}
+
+/** Our address in the network of the gateway, which is where it should forward to */
+fn local_ip_towards(gateway: SocketAddr) -> io::Result<IpAddr> {
+    let socket = std::net::UdpSocket::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0))?;
+    /* This only selects the route, nothing gets sent */
+    socket.connect(gateway)?;
+    Ok(socket.local_addr()?.ip())
+}
+
+fn map_upnp(
+    port: u16,
+) -> Result<(PortMapping, SocketAddr), Box<dyn std::error::Error + Send + Sync>> {
+    let gateway = igd_next::search_gateway(igd_next::SearchOptions {
+        timeout: Some(UPNP_SEARCH_TIMEOUT),
+        ..Default::default()
+    })?;
+    log::debug!("Found UPnP gateway at {}", gateway.addr);
+    let local_addr = SocketAddr::new(local_ip_towards(gateway.addr)?, port);
+    let protocol = igd_next::PortMappingProtocol::TCP;
+    /* Prefer the same port, but someone else may already be using it */
+    let external_port =
+        match gateway.add_port(protocol, port, local_addr, LEASE_DURATION, DESCRIPTION) {
+            Ok(()) => port,
+            Err(err) => {
+                log::debug!("Could not map port {}, trying any other: {}", port, err);
+                gateway.add_any_port(protocol, local_addr, LEASE_DURATION, DESCRIPTION)?
+            },
+        };
+    let external_ip = match gateway.get_external_ip() {
+        Ok(external_ip) => external_ip,
+        Err(err) => {
+            let _ = gateway.remove_port(protocol, external_port);
+            return Err(err.into());
+        },
+    };
+    let mapping = PortMapping::Upnp {
+        gateway,
+        external_port,
+    };
+    Ok((mapping, SocketAddr::new(external_ip, external_port)))
+}
+
+/**
+ * The address of our default gateway, which is the one to ask with NAT-PMP
+ *
+ * There is no portable way to get it, so this only works on Linux for now.
+ */
+#[cfg(target_os = "linux")]
+fn default_gateway() -> io::Result<Ipv4Addr> {
+    /* Columns: interface, destination, gateway, …, all addresses in hex */
+    let routes = std::fs::read_to_string("/proc/net/route")?;
+    routes
+        .lines()
+        .skip(1)
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .find(|columns| columns.len() > 2 && columns[1] == "00000000")
+        .and_then(|columns| u32::from_str_radix(columns[2], 16).ok())
+        /* The kernel prints the address as it is in memory, which is in network byte order */
+        .map(|gateway| Ipv4Addr::from(gateway.to_ne_bytes()))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No default route"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn default_gateway() -> io::Result<Ipv4Addr> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "Finding the default gateway is not supported on this platform",
+    ))
+}
+
+/**
+ * Send a NAT-PMP request and get the successful response
+ *
+ * Requests get retried like the RFC says, but we give up a lot earlier. Gateways which support
+ * NAT-PMP answer right away, and the others only make us wait.
+ */
+async fn nat_pmp_request(gateway: SocketAddr, request: &[u8]) -> io::Result<Vec<u8>> {
+    let socket =
+        async_std::net::UdpSocket::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)).await?;
+    socket.connect(gateway).await?;
+    /* The responses to the address request are shorter than the ones to the mapping requests */
+    let response_length = if request[1] == 0 { 12 } else { 16 };
+    let mut timeout = Duration::from_millis(250);
+    for _ in 0..3 {
+        socket.send(request).await?;
+        let mut response = vec![0; 16];
+        let length = match async_std::future::timeout(timeout, socket.recv(&mut response)).await {
+            Ok(length) => length?,
+            Err(_) => {
+                timeout *= 2;
+                continue;
+            },
+        };
+        /* Version 0, and the opcode of the request plus 128 */
+        ensure!(
+            length == response_length && response[0] == 0 && response[1] == 128 + request[1],
+            io::Error::new(io::ErrorKind::InvalidData, "Malformed NAT-PMP response")
+        );
+        let result = u16::from_be_bytes([response[2], response[3]]);
+        ensure!(
+            result == 0,
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("The NAT-PMP gateway refused with result code {}", result)
+            )
+        );
+        response.truncate(length);
+        return Ok(response);
+    }
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "The NAT-PMP gateway did not answer",
+    ))
+}
+
+/** Map a TCP port, returning the external one */
+async fn nat_pmp_map(
+    gateway: SocketAddr,
+    internal_port: u16,
+    external_port: u16,
+    lifetime: u32,
+) -> io::Result<u16> {
+    /* Version, opcode (2 is TCP), two reserved bytes, the ports and the lifetime */
+    let mut request = vec![0, 2, 0, 0];
+    request.extend_from_slice(&internal_port.to_be_bytes());
+    request.extend_from_slice(&external_port.to_be_bytes());
+    request.extend_from_slice(&lifetime.to_be_bytes());
+    let response = nat_pmp_request(gateway, &request).await?;
+    Ok(u16::from_be_bytes([response[10], response[11]]))
+}
+
+async fn map_nat_pmp(port: u16) -> io::Result<(PortMapping, SocketAddr)> {
+    let gateway = SocketAddr::new(default_gateway()?.into(), NAT_PMP_PORT);
+    /* Version 0, opcode 0 asks for the external address */
+    let response = nat_pmp_request(gateway, &[0, 0]).await?;
+    let external_ip = Ipv4Addr::new(response[8], response[9], response[10], response[11]);
+    let external_port = nat_pmp_map(gateway, port, port, LEASE_DURATION).await?;
+    log::debug!("Mapped port {} with NAT-PMP at {}", port, gateway);
+    Ok((
+        PortMapping::NatPmp {
+            gateway,
+            internal_port: port,
+        },
+        SocketAddr::new(external_ip.into(), external_port),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /* A NAT-PMP gateway that maps every port to the next higher one */
+    #[async_std::test]
+    async fn test_nat_pmp() {
+        let gateway = async_std::net::UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let gateway_addr = gateway.local_addr().unwrap();
+        async_std::task::spawn(async move {
+            let mut request = [0; 12];
+            loop {
+                let (length, peer) = gateway.recv_from(&mut request).await.unwrap();
+                /* Result code 1 means "unsupported version" */
+                let result = if request[0] == 0 { 0 } else { 1 };
+                let mut response = vec![0, 128 + request[1], 0, result, 0, 0, 0, 42];
+                match length {
+                    2 => response.extend_from_slice(&[203, 0, 113, 7]),
+                    _ => {
+                        let port = u16::from_be_bytes([request[4], request[5]]);
+                        response.extend_from_slice(&request[4..6]);
+                        response.extend_from_slice(&(port + 1).to_be_bytes());
+                        response.extend_from_slice(&request[8..12]);
+                    },
+                }
+                gateway.send_to(&response, peer).await.unwrap();
+            }
+        });
+
+        let response = nat_pmp_request(gateway_addr, &[0, 0]).await.unwrap();
+        assert_eq!(response[8..12], [203, 0, 113, 7]);
+        assert_eq!(
+            nat_pmp_map(gateway_addr, 4000, 4000, 60).await.unwrap(),
+            4001
+        );
+        assert!(nat_pmp_request(gateway_addr, &[1, 0]).await.is_err());
+    }
+}
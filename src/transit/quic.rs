@@ -0,0 +1,255 @@
+//! Direct transit connections over QUIC
+//!
+//! Both sides bind a single UDP socket, from which they dial the peer's QUIC hints and on which
+//! they accept the peer's connections. Like with TCP simultaneous open, the outgoing handshake
+//! packets open our NAT for the ones coming from the peer, and QUIC retransmits them on its own.
+//! UDP mappings are a lot more forgiving than TCP ones, so this gets through more NATs.
+//!
+//! The connection carries a single bidirectional stream, on which the usual transit handshake
+//! and records go. That handshake authenticates the peer, so the TLS certificates are
+//! self-signed and not checked.
+
+use super::{Resolver, StunError};
+use futures::io::{AsyncRead, AsyncWrite};
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+/** Both sides must agree on it, so that the handshake succeeds */
+const ALPN: &[u8] = b"magic-wormhole-transit";
+/** Used for the certificate and the SNI, only because TLS wants one */
+const SERVER_NAME: &str = "magic-wormhole";
+/** Keep the NAT mappings open while the application does not send anything */
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+fn into_io_error(error: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, error)
+}
+
+/** Accept any certificate, see the module documentation */
+struct SkipServerVerification;
+
+impl rustls::client::ServerCertVerifier for SkipServerVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+}
+
+fn transport_config() -> Arc<quinn::TransportConfig> {
+    let mut transport = quinn::TransportConfig::default();
+    transport.keep_alive_interval(Some(KEEP_ALIVE_INTERVAL));
+    Arc::new(transport)
+}
+
+fn server_config() -> io::Result<quinn::ServerConfig> {
+    let certificate =
+        rcgen::generate_simple_self_signed(vec![SERVER_NAME.into()]).map_err(into_io_error)?;
+    let mut crypto = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![rustls::Certificate(
+                certificate.serialize_der().map_err(into_io_error)?,
+            )],
+            rustls::PrivateKey(certificate.serialize_private_key_der()),
+        )
+        .map_err(into_io_error)?;
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    let mut config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    config.transport_config(transport_config());
+    Ok(config)
+}
+
+fn client_config() -> quinn::ClientConfig {
+    let mut crypto = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(SkipServerVerification))
+        .with_no_client_auth();
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    let mut config = quinn::ClientConfig::new(Arc::new(crypto));
+    config.transport_config(transport_config());
+    config
+}
+
+/** Ask the STUN server from which address our UDP packets come */
+async fn get_external_addr(
+    socket: &async_io::Async<std::net::UdpSocket>,
+    resolver: Option<&Resolver>,
+) -> Result<SocketAddr, StunError> {
+    let server = super::stun_server_addr(resolver).await?;
+    let (request, transaction_id) = super::stun_binding_request()?;
+    socket.send_to(&request, server).await?;
+    let mut buf = [0u8; 256];
+    /* Anyone may send us packets, only take the answer to our request */
+    loop {
+        let (length, sender) = socket.recv_from(&mut buf).await?;
+        if sender != server {
+            log::debug!("Ignoring a packet from {} while waiting for STUN", sender);
+            continue;
+        }
+        match super::stun_decode_address(&buf[..length], transaction_id) {
+            Err(StunError::TransactionId) => {
+                log::debug!("Ignoring a STUN response to another request");
+            },
+            result => return result,
+        }
+    }
+}
+
+/**
+ * Bind the UDP socket for the QUIC connections
+ *
+ * Also returns our external address, if the STUN query worked.
+ */
+pub(super) async fn bind(
+    resolver: Option<&Resolver>,
+) -> io::Result<(quinn::Endpoint, Option<SocketAddr>)> {
+    let socket = socket2::Socket::new(socket2::Domain::IPV6, socket2::Type::DGRAM, None)?;
+    socket.set_only_v6(false)?;
+    socket.bind(&"[::]:0".parse::<SocketAddr>().unwrap().into())?;
+    let socket = async_io::Async::new(std::net::UdpSocket::from(socket))?;
+
+    /* The STUN query must go over the same socket, to get the same mapping in the NAT */
+    let external_addr = match async_std::future::timeout(
+        Duration::from_secs(4),
+        get_external_addr(&socket, resolver),
+    )
+    .await
+    .map_err(|_| StunError::Timeout)
+    {
+        Ok(Ok(external_addr)) => {
+            log::debug!("Our external address for QUIC is {}", external_addr);
+            Some(external_addr)
+        },
+        Err(err) | Ok(Err(err)) => {
+            log::debug!("Failed to get external address for QUIC via STUN, {}", err);
+            None
+        },
+    };
+
+    Ok((endpoint(socket.into_inner()?)?, external_addr))
+}
+
+/** Dial and accept QUIC connections with the socket */
+fn endpoint(socket: std::net::UdpSocket) -> io::Result<quinn::Endpoint> {
+    let mut endpoint = quinn::Endpoint::new(
+        quinn::EndpointConfig::default(),
+        Some(server_config()?),
+        socket,
+        quinn::AsyncStdRuntime,
+    )?;
+    endpoint.set_default_client_config(client_config());
+    Ok(endpoint)
+}
+
+/** Dial a QUIC hint of the peer, and open the stream. Also returns the round-trip time */
+pub(super) async fn connect(
+    endpoint: &quinn::Endpoint,
+    addr: SocketAddr,
+) -> io::Result<(QuicSocket, Duration)> {
+    let connection = endpoint
+        .connect(addr, SERVER_NAME)
+        .map_err(into_io_error)?
+        .await
+        .map_err(into_io_error)?;
+    let (send, recv) = connection.open_bi().await.map_err(into_io_error)?;
+    Ok((QuicSocket { send, recv }, connection.rtt()))
+}
+
+/** Wait for the next connection from the peer, and its stream */
+pub(super) async fn accept(endpoint: &quinn::Endpoint) -> io::Result<(QuicSocket, SocketAddr)> {
+    let connection = endpoint
+        .accept()
+        .await
+        .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?
+        .await
+        .map_err(into_io_error)?;
+    /* The peer starts with its half of the transit handshake, so this does not wait forever */
+    let (send, recv) = connection.accept_bi().await.map_err(into_io_error)?;
+    Ok((QuicSocket { send, recv }, connection.remote_address()))
+}
+
+/**
+ * The stream of a QUIC connection
+ *
+ * The connection stays open as long as its streams exist.
+ */
+pub(super) struct QuicSocket {
+    send: quinn::SendStream,
+    recv: quinn::RecvStream,
+}
+
+impl AsyncRead for QuicSocket {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicSocket {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.send).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_flush(cx)
+    }
+
+    /** Waits until the peer got everything */
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::{AsyncReadExt, AsyncWriteExt};
+
+    #[async_std::test]
+    async fn test_quic_connect() {
+        let bind = || endpoint(std::net::UdpSocket::bind("127.0.0.1:0").unwrap()).unwrap();
+        let (ours, theirs) = (bind(), bind());
+        let addr = theirs.local_addr().unwrap();
+
+        let dialing = async {
+            let (mut socket, _rtt) = connect(&ours, addr).await.unwrap();
+            socket.write_all(b"hello").await.unwrap();
+            let mut answer = [0; 2];
+            socket.read_exact(&mut answer).await.unwrap();
+            assert_eq!(&answer, b"hi");
+            socket.close().await.unwrap();
+        };
+        let accepting = async {
+            let (mut socket, _peer) = accept(&theirs).await.unwrap();
+            let mut greeting = [0; 5];
+            socket.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(&greeting, b"hello");
+            socket.write_all(b"hi").await.unwrap();
+            let mut rest = Vec::new();
+            socket.read_to_end(&mut rest).await.unwrap();
+            assert!(rest.is_empty());
+        };
+        futures::join!(dialing, accepting);
+    }
+}
@@ -0,0 +1,96 @@
+//! Limiting the bandwidth of transit connections
+//!
+//! Set a [`RateLimit`] in the [`TransitConfig`](super::TransitConfig), and every record that
+//! gets sent or received over the connection counts towards it. Once the budget is used up,
+//! the transfer waits before the next record. Slowing down the receiving side works as well,
+//! since TCP makes the sender wait for it.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/**
+ * A handle to limit the bandwidth of transfers, adjustable while they are running
+ *
+ * This is cheap to clone, all clones share the same budget. Thus, use the same one for
+ * several transfers to limit them together.
+ */
+#[derive(Clone, Debug)]
+pub struct RateLimit(Arc<Mutex<State>>);
+
+#[derive(Debug)]
+struct State {
+    /** `None` and zero mean unlimited */
+    bytes_per_second: Option<u64>,
+    /** When the bytes transferred so far are paid off */
+    next: Instant,
+}
+
+impl RateLimit {
+    /** A limit of zero bytes per second is no limit, like `None` in [`set`](Self::set) */
+    pub fn new(bytes_per_second: u64) -> Self {
+        RateLimit(Arc::new(Mutex::new(State {
+            bytes_per_second: Some(bytes_per_second),
+            next: Instant::now(),
+        })))
+    }
+
+    /** Change the limit, `None` (or zero) lifts it. This takes effect with the next record */
+    pub fn set(&self, bytes_per_second: Option<u64>) {
+        let mut state = self.0.lock().unwrap();
+        state.bytes_per_second = bytes_per_second;
+        state.next = Instant::now();
+    }
+
+    pub fn get(&self) -> Option<u64> {
+        self.0.lock().unwrap().bytes_per_second
+    }
+
+    /** Account for `bytes` that get transferred, and wait until they are within the limit */
+    pub(super) async fn throttle(&self, bytes: usize) {
+        let delay = {
+            let mut state = self.0.lock().unwrap();
+            let bytes_per_second = match state.bytes_per_second {
+                Some(bytes_per_second) if bytes_per_second > 0 => bytes_per_second,
+                _ => return,
+            };
+            let now = Instant::now();
+            /* Don't save up unused bandwidth for later */
+            let start = state.next.max(now);
+            state.next = start + Duration::from_secs_f64(bytes as f64 / bytes_per_second as f64);
+            state.next - now
+        };
+        async_std::task::sleep(delay).await;
+    }
+}
+
+/** Limits are equal if they are clones of each other */
+impl PartialEq for RateLimit {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for RateLimit {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[async_std::test]
+    async fn test_throttle() {
+        let rate_limit = RateLimit::new(100_000);
+        let start = Instant::now();
+        for _ in 0..4 {
+            rate_limit.throttle(10_000).await;
+        }
+        assert!(start.elapsed() >= Duration::from_millis(400));
+
+        rate_limit.set(None);
+        let start = Instant::now();
+        rate_limit.throttle(1_000_000_000).await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+        assert_eq!(rate_limit.get(), None);
+    }
+}
@@ -0,0 +1,479 @@
+//! A transit relay server, for hosting one yourself
+//!
+//! Clients that can't reach each other directly both connect to a relay, which glues their
+//! connections together. This implements the TCP part of the protocol of the Python
+//! `magic-wormhole-transit-relay`, so that clients of all implementations can use it:
+//!
+//! - Every client starts with `please relay {token} for side {side}\n`. The token is derived from
+//!   the transit key, so only the two peers know it. Old clients leave out the side.
+//! - Two clients with the same token and different sides get paired, and both receive `ok\n`.
+//!   Clients with a malformed handshake get `bad handshake\n` instead.
+//! - From then on, the relay copies all bytes between them. It can't read them, as they are
+//!   encrypted with the transit key.
+//!
+//! WebSocket clients are not supported yet. Run the server with [`RelayServer::run`], and point
+//! the clients to it with a `tcp://host:port` [`RelayHint`](super::RelayHint).
+
+use async_std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+use futures::{AsyncReadExt, AsyncWriteExt};
+use std::{
+    collections::HashMap,
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/** The handshake is a single line, and that's longer than any valid one */
+const MAX_HANDSHAKE_LENGTH: usize = 256;
+/** How long clients may take to send their handshake after connecting */
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/** Limits for the clients of a [`RelayServer`] */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RelayServerConfig {
+    /**
+     * How long a client may wait for its peer before getting disconnected
+     *
+     * Defaults to 2 minutes.
+     */
+    pub pairing_timeout: Duration,
+    /**
+     * How many clients may be connected at the same time, waiting or relaying
+     *
+     * Clients above the limit get disconnected right away. Defaults to no limit.
+     */
+    pub max_connections: Option<usize>,
+    /**
+     * How many bytes a session may relay, in both directions together
+     *
+     * Sessions get closed once they exceed it. Defaults to no limit.
+     */
+    pub max_session_bytes: Option<u64>,
+}
+
+impl Default for RelayServerConfig {
+    fn default() -> Self {
+        RelayServerConfig {
+            pairing_timeout: Duration::from_secs(120),
+            max_connections: None,
+            max_session_bytes: None,
+        }
+    }
+}
+
+impl RelayServerConfig {
+    pub fn pairing_timeout(mut self, pairing_timeout: Duration) -> Self {
+        self.pairing_timeout = pairing_timeout;
+        self
+    }
+
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = Some(max_connections);
+        self
+    }
+
+    pub fn max_session_bytes(mut self, max_session_bytes: u64) -> Self {
+        self.max_session_bytes = Some(max_session_bytes);
+        self
+    }
+}
+
+/** What a [`RelayServer`] has been doing since it started */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct RelayUsage {
+    /** Clients currently waiting for their peer */
+    pub waiting: usize,
+    /** Sessions currently being relayed */
+    pub active_sessions: usize,
+    /** Sessions that have ended */
+    pub sessions: u64,
+    /** Clients that gave up, or got disconnected, before their peer came */
+    pub lonely: u64,
+    /** Clients that got disconnected for a bad handshake, or because of the connection limit */
+    pub rejected: u64,
+    /** The bytes relayed by all sessions, including the active ones */
+    pub bytes: u64,
+}
+
+/** A client that sent its handshake, and waits for its peer */
+struct Waiting {
+    id: u64,
+    side: Option<String>,
+    socket: TcpStream,
+    /** Counts towards the connection limit for as long as the client is connected */
+    _connection: Connection,
+    /** Tells the task of the client that it got paired */
+    paired: futures::channel::oneshot::Sender<()>,
+}
+
+#[derive(Default)]
+struct State {
+    usage: RelayUsage,
+    connections: usize,
+    /** By token */
+    waiting: HashMap<String, Vec<Waiting>>,
+    next_id: u64,
+}
+
+struct Shared {
+    config: RelayServerConfig,
+    state: Mutex<State>,
+}
+
+/** A connected client, for the connection limit */
+struct Connection(Arc<Shared>);
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().connections -= 1;
+    }
+}
+
+/**
+ * A transit relay server
+ *
+ * ```no_run
+ * # async_std::task::block_on(async {
+ * use magic_wormhole::transit::relay_server::{RelayServer, RelayServerConfig};
+ *
+ * let server = RelayServer::bind("[::]:4001", RelayServerConfig::default()).await?;
+ * server.run().await;
+ * # Ok::<_, std::io::Error>(())
+ * # });
+ * ```
+ */
+pub struct RelayServer {
+    listener: TcpListener,
+    shared: Arc<Shared>,
+}
+
+impl RelayServer {
+    pub async fn bind(address: impl ToSocketAddrs, config: RelayServerConfig) -> io::Result<Self> {
+        Ok(RelayServer {
+            listener: TcpListener::bind(address).await?,
+            shared: Arc::new(Shared {
+                config,
+                state: Default::default(),
+            }),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<std::net::SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /** A snapshot of the usage statistics */
+    pub fn usage(&self) -> RelayUsage {
+        self.shared.state.lock().unwrap().usage.clone()
+    }
+
+    /**
+     * Accept and relay clients, forever
+     *
+     * Each client gets a task of its own. Dropping this future stops accepting new clients,
+     * but the ones that are already connected get served until they are done.
+     */
+    pub async fn run(&self) {
+        loop {
+            let socket = match self.listener.accept().await {
+                Ok((socket, _)) => socket,
+                Err(error) => {
+                    log::warn!("Could not accept a client: {}", error);
+                    continue;
+                },
+            };
+            let connection = {
+                let mut state = self.shared.state.lock().unwrap();
+                match self.shared.config.max_connections {
+                    Some(max_connections) if state.connections >= max_connections => {
+                        state.usage.rejected += 1;
+                        None
+                    },
+                    _ => {
+                        state.connections += 1;
+                        Some(Connection(self.shared.clone()))
+                    },
+                }
+            };
+            match connection {
+                Some(connection) => {
+                    async_std::task::spawn(serve(self.shared.clone(), socket, connection));
+                },
+                None => log::debug!("Too many clients, disconnecting one"),
+            }
+        }
+    }
+}
+
+/** Parse `please relay {token} for side {side}\n`, returning the token and the side */
+fn parse_handshake(line: &[u8]) -> Option<(String, Option<String>)> {
+    let is_hex = |string: &str| string.bytes().all(|byte| byte.is_ascii_hexdigit());
+    let line = std::str::from_utf8(line).ok()?;
+    let request = line.strip_prefix("please relay ")?.strip_suffix('\n')?;
+    let (token, side) = match request.split_once(" for side ") {
+        Some((token, side)) => (token, Some(side)),
+        None => (request, None),
+    };
+    if token.len() != 64 || !is_hex(token) {
+        return None;
+    }
+    if let Some(side) = side {
+        if side.is_empty() || !is_hex(side) {
+            return None;
+        }
+    }
+    Some((token.to_owned(), side.map(str::to_owned)))
+}
+
+/** Read up to the end of the handshake line, but not further */
+async fn read_handshake(socket: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut line = Vec::new();
+    while line.len() < MAX_HANDSHAKE_LENGTH && line.last() != Some(&b'\n') {
+        let mut byte = [0];
+        socket.read_exact(&mut byte).await?;
+        line.push(byte[0]);
+    }
+    Ok(line)
+}
+
+async fn serve(shared: Arc<Shared>, mut socket: TcpStream, connection: Connection) {
+    let handshake = async_std::future::timeout(HANDSHAKE_TIMEOUT, read_handshake(&mut socket))
+        .await
+        .ok()
+        .and_then(Result::ok);
+    let (token, side) = match handshake.as_deref().and_then(parse_handshake) {
+        Some(handshake) => handshake,
+        None => {
+            shared.state.lock().unwrap().usage.rejected += 1;
+            let _ = socket.write_all(b"bad handshake\n").await;
+            return;
+        },
+    };
+
+    let (paired_tx, paired_rx) = futures::channel::oneshot::channel();
+    let mut connection = Some(connection);
+    let (peer, id) = {
+        let mut state = shared.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        let waiting = state.waiting.entry(token.clone()).or_default();
+        /* Sides that are unknown match any other one */
+        let peer = waiting
+            .iter()
+            .position(|other| side.is_none() || other.side.is_none() || other.side != side)
+            .map(|index| waiting.remove(index));
+        match peer {
+            Some(_) => {
+                if waiting.is_empty() {
+                    state.waiting.remove(&token);
+                }
+                state.usage.waiting -= 1;
+                state.usage.active_sessions += 1;
+            },
+            None => {
+                waiting.push(Waiting {
+                    id,
+                    side,
+                    socket: socket.clone(),
+                    _connection: connection.take().unwrap(),
+                    paired: paired_tx,
+                });
+                state.usage.waiting += 1;
+            },
+        }
+        (peer, id)
+    };
+    let peer = match peer {
+        Some(peer) => peer,
+        None => return wait(&shared, &token, id, paired_rx).await,
+    };
+
+    let _ = peer.paired.send(());
+    log::debug!("Paired client {} with {}", id, peer.id);
+    relay(&shared, &peer.socket, &socket).await;
+    let mut state = shared.state.lock().unwrap();
+    state.usage.active_sessions -= 1;
+    state.usage.sessions += 1;
+}
+
+/** Wait for the peer, and disconnect the client once the pairing timeout is over */
+async fn wait(
+    shared: &Shared,
+    token: &str,
+    id: u64,
+    paired: futures::channel::oneshot::Receiver<()>,
+) {
+    if async_std::future::timeout(shared.config.pairing_timeout, paired)
+        .await
+        .is_ok()
+    {
+        return;
+    }
+    let lonely = {
+        let mut state = shared.state.lock().unwrap();
+        /* It may have been paired just now */
+        let waiting = state.waiting.get_mut(token);
+        let lonely = waiting.and_then(|waiting| {
+            let index = waiting.iter().position(|other| other.id == id)?;
+            Some(waiting.remove(index))
+        });
+        if lonely.is_some() {
+            if state.waiting[token].is_empty() {
+                state.waiting.remove(token);
+            }
+            state.usage.waiting -= 1;
+            state.usage.lonely += 1;
+        }
+        lonely
+    };
+    /* Disconnects it, outside of the lock because of the connection count */
+    if let Some(lonely) = lonely {
+        log::debug!("Client {} gave up waiting for its peer", lonely.id);
+    }
+}
+
+async fn relay(shared: &Shared, first: &TcpStream, second: &TcpStream) {
+    let relayed = AtomicU64::new(0);
+    let ok = async {
+        (&*first).write_all(b"ok\n").await?;
+        (&*second).write_all(b"ok\n").await
+    };
+    if ok.await.is_err() {
+        return;
+    }
+    futures::join!(
+        pipe(shared, &relayed, first, second),
+        pipe(shared, &relayed, second, first),
+    );
+    log::debug!("Relayed {} bytes", relayed.into_inner());
+}
+
+/**
+ * Copy everything from one client to the other
+ *
+ * The end of the stream gets passed on, so that the other direction may continue. On errors,
+ * both directions get closed.
+ */
+async fn pipe(shared: &Shared, relayed: &AtomicU64, from: &TcpStream, to: &TcpStream) {
+    let result = async {
+        let mut buffer = vec![0; 16 * 1024];
+        loop {
+            let read = (&*from).read(&mut buffer).await?;
+            if read == 0 {
+                return to.shutdown(Shutdown::Write);
+            }
+            let total = relayed.fetch_add(read as u64, Ordering::Relaxed) + read as u64;
+            if matches!(shared.config.max_session_bytes, Some(max) if total > max) {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "The session exceeded its byte limit",
+                ));
+            }
+            (&*to).write_all(&buffer[..read]).await?;
+            shared.state.lock().unwrap().usage.bytes += read as u64;
+        }
+    };
+    if let Err(error) = result.await {
+        log::debug!("Closing the session: {}", error);
+        let _ = from.shutdown(Shutdown::Both);
+        let _ = to.shutdown(Shutdown::Both);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::FutureExt;
+
+    async fn client(server: &RelayServer, handshake: &str) -> TcpStream {
+        let mut socket = TcpStream::connect(server.local_addr().unwrap())
+            .await
+            .unwrap();
+        socket.write_all(handshake.as_bytes()).await.unwrap();
+        socket
+    }
+
+    async fn read_to_end(socket: &mut TcpStream) -> Vec<u8> {
+        let mut received = Vec::new();
+        let _ = socket.read_to_end(&mut received).await;
+        received
+    }
+
+    #[test]
+    fn test_parse_handshake() {
+        let token = "ab".repeat(32);
+        assert_eq!(
+            parse_handshake(format!("please relay {} for side 0123abcd\n", token).as_bytes()),
+            Some((token.clone(), Some("0123abcd".into())))
+        );
+        assert_eq!(
+            parse_handshake(format!("please relay {}\n", token).as_bytes()),
+            Some((token.clone(), None))
+        );
+        assert_eq!(parse_handshake(b"please relay abcd\n"), None);
+        assert_eq!(
+            parse_handshake(format!("please relay {} for side \n", token).as_bytes()),
+            None
+        );
+        assert_eq!(
+            parse_handshake(format!("please relay {} for side xyz\n", token).as_bytes()),
+            None
+        );
+    }
+
+    #[async_std::test]
+    async fn test_relay_server() {
+        let config = RelayServerConfig::default().pairing_timeout(Duration::from_millis(200));
+        let server = RelayServer::bind("127.0.0.1:0", config).await.unwrap();
+        let token = "ab".repeat(32);
+
+        let clients = async {
+            let mut first = client(&server, &format!("please relay {} for side 01\n", token)).await;
+            let mut second =
+                client(&server, &format!("please relay {} for side 02\n", token)).await;
+            let mut ok = [0; 3];
+            first.read_exact(&mut ok).await.unwrap();
+            assert_eq!(&ok, b"ok\n");
+            second.read_exact(&mut ok).await.unwrap();
+            assert_eq!(&ok, b"ok\n");
+
+            first.write_all(b"hello").await.unwrap();
+            first.shutdown(Shutdown::Write).unwrap();
+            assert_eq!(read_to_end(&mut second).await, b"hello");
+            /* The other direction still works */
+            second.write_all(b"bye").await.unwrap();
+            second.shutdown(Shutdown::Write).unwrap();
+            assert_eq!(read_to_end(&mut first).await, b"bye");
+
+            let mut lonely =
+                client(&server, &format!("please relay {} for side 01\n", token)).await;
+            assert_eq!(read_to_end(&mut lonely).await, b"");
+
+            let mut bad = client(&server, "please relay me\n").await;
+            assert_eq!(read_to_end(&mut bad).await, b"bad handshake\n");
+
+            while server.usage().sessions == 0 {
+                async_std::task::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        futures::select! {
+            () = server.run().fuse() => unreachable!(),
+            () = clients.fuse() => {},
+        }
+
+        assert_eq!(
+            server.usage(),
+            RelayUsage {
+                sessions: 1,
+                lonely: 1,
+                rejected: 1,
+                bytes: 8,
+                ..RelayUsage::default()
+            }
+        );
+    }
+}
@@ -0,0 +1,159 @@
+//! Statistics about transit connections
+//!
+//! Get them from a connection with [`Transit::stats`](super::Transit::stats), or set a
+//! [`StatsCallback`] in the [`TransitConfig`](super::TransitConfig) to have them reported
+//! periodically, for example to feed a dashboard. The latter also works for the connections
+//! that the file transfer makes on its own.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/**
+ * How a [`Transit`](super::Transit) connection was established, and how much went over it
+ *
+ * The bytes are the ones of the encrypted records, as they went over the wire. They include
+ * the framing, but not the handshakes.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct TransitStats {
+    /** How long it took until the first connection to the peer was ready, handshake included */
+    pub first_connection: Duration,
+    /**
+     * How long connecting took in total
+     *
+     * On the leader side, this includes waiting for a better connection than the first one.
+     * With [parallel connections](super::Abilities::parallel_v1), it includes opening them.
+     */
+    pub connect: Duration,
+    /**
+     * Connection attempts that failed while connecting, be it to connect at all or the handshake
+     *
+     * Hints that are unreachable from here count as well, so this is not necessarily zero for a
+     * healthy connection.
+     */
+    pub failed_attempts: u32,
+    pub bytes_sent: u64,
+    pub records_sent: u64,
+    pub bytes_received: u64,
+    pub records_received: u64,
+}
+
+/** What goes into the [`TransitStats`], shared by all parts of a connection */
+#[derive(Debug, Default)]
+pub(super) struct Counters {
+    first_connection: Duration,
+    connect: Duration,
+    failed_attempts: u32,
+    bytes_sent: AtomicU64,
+    records_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    records_received: AtomicU64,
+}
+
+impl Counters {
+    /** Start counting, with what is known once the connection got established */
+    pub(super) fn new(first_connection: Duration, connect: Duration, failed_attempts: u32) -> Self {
+        Counters {
+            first_connection,
+            connect,
+            failed_attempts,
+            ..Default::default()
+        }
+    }
+
+    pub(super) fn sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.records_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn received(&self, bytes: usize) {
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.records_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn get(&self) -> TransitStats {
+        TransitStats {
+            first_connection: self.first_connection,
+            connect: self.connect,
+            failed_attempts: self.failed_attempts,
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            records_sent: self.records_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            records_received: self.records_received.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/**
+ * Gets called with the [`TransitStats`] of each connection periodically, while it is open
+ *
+ * The callback runs on a task of its own, so it should not block for long. If the same one
+ * is used for several connections, it gets called for each of them.
+ */
+#[derive(Clone)]
+pub struct StatsCallback {
+    interval: Duration,
+    callback: Arc<dyn Fn(TransitStats) + Send + Sync>,
+}
+
+impl StatsCallback {
+    /**
+     * Call `callback` every `interval`
+     *
+     * # Panics
+     *
+     * If the interval is zero.
+     */
+    pub fn new(
+        interval: Duration,
+        callback: impl Fn(TransitStats) + Send + Sync + 'static,
+    ) -> Self {
+        assert!(
+            interval > Duration::ZERO,
+            "The interval of a StatsCallback must not be zero"
+        );
+        StatsCallback {
+            interval,
+            callback: Arc::new(callback),
+        }
+    }
+
+    /** Call back every interval, until the connection is gone */
+    pub(super) fn start(&self, counters: &Arc<Counters>) {
+        let counters = Arc::downgrade(counters);
+        let this = self.clone();
+        async_std::task::spawn(async move {
+            loop {
+                async_std::task::sleep(this.interval).await;
+                match counters.upgrade() {
+                    Some(counters) => (this.callback)(counters.get()),
+                    None => break,
+                }
+            }
+        });
+    }
+}
+
+impl std::fmt::Debug for StatsCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StatsCallback")
+            .field("interval", &self.interval)
+            .finish()
+    }
+}
+
+/** Callbacks are equal if they are clones of each other */
+impl PartialEq for StatsCallback {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.callback, &other.callback)
+    }
+}
+
+impl Eq for StatsCallback {}
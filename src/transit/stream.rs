@@ -0,0 +1,369 @@
+//! Transit connections as byte streams
+//!
+//! [`Transit`] sends and receives whole records. Turn it into a [`TransitStream`] with
+//! [`Transit::into_stream`] to use it with everything that works on [`AsyncRead`] and
+//! [`AsyncWrite`] instead, like [`futures::io::copy_buf`] or codecs. The framing and
+//! encryption stay the same, so the other side may use either of both.
+
+use super::{Transit, TransitError, TransitInfo, TransitStats};
+use futures::{
+    future::BoxFuture,
+    io::{AsyncBufRead, AsyncRead, AsyncWrite},
+    ready,
+};
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/** How much gets written into a record before it is sent without being flushed */
+const RECORD_SIZE: usize = 16 * 1024;
+
+fn into_io_error(error: TransitError) -> io::Error {
+    match error {
+        TransitError::IO(error) => error,
+        error => io::Error::new(io::ErrorKind::InvalidData, error),
+    }
+}
+
+enum ReadState {
+    /** Reading the length of the next record, with how much of it we have */
+    Length([u8; 4], usize),
+    /** Reading the record itself */
+    Record(Vec<u8>, usize),
+    /** Waiting for the rate limit before decrypting the record */
+    Throttle(Vec<u8>, BoxFuture<'static, ()>),
+    /** Handing out the decrypted content, from the given position */
+    Plaintext(Vec<u8>, usize),
+}
+
+enum WriteState {
+    /** Collecting the content of the next record */
+    Plaintext(Vec<u8>),
+    /** Waiting for the rate limit before sending the encrypted record */
+    Throttle(Vec<u8>, BoxFuture<'static, ()>),
+    /** Sending the encrypted record, from the given position */
+    Record(Vec<u8>, usize),
+}
+
+/**
+ * A [`Transit`] connection as encrypted byte stream
+ *
+ * Reads hand out the content of the received records, in pieces of any size. Writes get
+ * collected into records of up to 16 KiB, so make sure to [flush](futures::AsyncWriteExt::flush)
+ * when waiting for an answer. Closing the stream shuts the connection down for writing.
+ *
+ * Use [`AsyncReadExt::split`](futures::AsyncReadExt::split) to read and write concurrently.
+ */
+pub struct TransitStream {
+    transit: Transit,
+    read: ReadState,
+    write: WriteState,
+}
+
+impl Transit {
+    /** Read and write the connection as a byte stream, see [`TransitStream`] */
+    pub fn into_stream(self) -> TransitStream {
+        TransitStream {
+            transit: self,
+            read: ReadState::Length([0; 4], 0),
+            write: WriteState::Plaintext(Vec::new()),
+        }
+    }
+}
+
+/** Fill `buffer` from its `filled`th byte on, and fail at the end of the stream */
+fn poll_fill(
+    socket: &mut (impl AsyncRead + Unpin),
+    cx: &mut Context<'_>,
+    buffer: &mut [u8],
+    filled: &mut usize,
+) -> Poll<io::Result<()>> {
+    while *filled < buffer.len() {
+        let read = ready!(Pin::new(&mut *socket).poll_read(cx, &mut buffer[*filled..]))?;
+        if read == 0 {
+            return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+        }
+        *filled += read;
+    }
+    Poll::Ready(Ok(()))
+}
+
+/** Check the nonce of a received record, and decrypt it */
+fn open(transit: &mut Transit, record: Vec<u8>) -> io::Result<Vec<u8>> {
+    let received_nonce = &record[..super::secretbox::NONCE_SIZE];
+    if transit.rnonce.as_slice() != received_nonce {
+        let error = TransitError::Nonce(received_nonce.into(), transit.rnonce.as_slice().into());
+        return Err(into_io_error(error));
+    }
+    crate::util::sodium_increment_be(&mut transit.rnonce);
+    transit.stats.received(4 + record.len());
+    transit
+        .cipher
+        .open(&transit.rkey, record)
+        .map_err(into_io_error)
+}
+
+impl TransitStream {
+    /** How the connection was established */
+    pub fn info(&self) -> &TransitInfo {
+        self.transit.info()
+    }
+
+    /** How much went over the connection so far, see [`Transit::stats`] */
+    pub fn stats(&self) -> TransitStats {
+        self.transit.stats()
+    }
+
+    /** Receive and decrypt records until there is something to read, or the end of the stream */
+    fn poll_receive(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let transit = &mut self.transit;
+        loop {
+            self.read = match &mut self.read {
+                ReadState::Plaintext(plaintext, position) if *position < plaintext.len() => {
+                    return Poll::Ready(Ok(()));
+                },
+                ReadState::Plaintext(..) => ReadState::Length([0; 4], 0),
+                ReadState::Length(length, filled) => {
+                    /* The connection may only end between records */
+                    if *filled == 0 {
+                        let read =
+                            ready!(Pin::new(&mut transit.socket).poll_read(cx, &mut length[..]))?;
+                        if read == 0 {
+                            return Poll::Ready(Ok(()));
+                        }
+                        *filled = read;
+                    }
+                    ready!(poll_fill(&mut transit.socket, cx, length, filled))?;
+                    let length = u32::from_be_bytes(*length) as usize;
+                    if length < super::secretbox::NONCE_SIZE {
+                        return Poll::Ready(Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "Message must be long enough to contain at least the nonce",
+                        )));
+                    }
+                    if length > super::MAX_SEALED_RECORD_SIZE {
+                        return Poll::Ready(Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("The record of {} bytes is too long", length),
+                        )));
+                    }
+                    ReadState::Record(vec![0; length], 0)
+                },
+                ReadState::Record(record, filled) => {
+                    ready!(poll_fill(&mut transit.socket, cx, record, filled))?;
+                    let record = std::mem::take(record);
+                    match transit.rate_limit.clone() {
+                        Some(rate_limit) => {
+                            let bytes = record.len();
+                            ReadState::Throttle(
+                                record,
+                                Box::pin(async move { rate_limit.throttle(bytes).await }),
+                            )
+                        },
+                        None => ReadState::Plaintext(open(transit, record)?, 0),
+                    }
+                },
+                ReadState::Throttle(record, throttle) => {
+                    ready!(throttle.as_mut().poll(cx));
+                    ReadState::Plaintext(open(transit, std::mem::take(record))?, 0)
+                },
+            };
+        }
+    }
+
+    /** Send everything that has been written so far, and wait until it is out */
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let transit = &mut self.transit;
+        loop {
+            self.write = match &mut self.write {
+                WriteState::Plaintext(plaintext) if plaintext.is_empty() => {
+                    return Poll::Ready(Ok(()));
+                },
+                WriteState::Plaintext(plaintext) => {
+                    let record = transit
+                        .cipher
+                        .seal(&transit.skey, &transit.snonce, plaintext)
+                        .map_err(into_io_error)?;
+                    crate::util::sodium_increment_be(&mut transit.snonce);
+                    match transit.rate_limit.clone() {
+                        Some(rate_limit) => {
+                            let bytes = record.len() - 4;
+                            WriteState::Throttle(
+                                record,
+                                Box::pin(async move { rate_limit.throttle(bytes).await }),
+                            )
+                        },
+                        None => WriteState::Record(record, 0),
+                    }
+                },
+                WriteState::Throttle(record, throttle) => {
+                    ready!(throttle.as_mut().poll(cx));
+                    WriteState::Record(std::mem::take(record), 0)
+                },
+                WriteState::Record(record, position) => {
+                    while *position < record.len() {
+                        let written = ready!(
+                            Pin::new(&mut transit.socket).poll_write(cx, &record[*position..])
+                        )?;
+                        if written == 0 {
+                            return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                        }
+                        *position += written;
+                    }
+                    transit.stats.sent(record.len());
+                    WriteState::Plaintext(Vec::with_capacity(RECORD_SIZE))
+                },
+            };
+        }
+    }
+}
+
+impl AsyncRead for TransitStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let available = ready!(self.as_mut().poll_fill_buf(cx))?;
+        let length = buf.len().min(available.len());
+        buf[..length].copy_from_slice(&available[..length]);
+        self.consume(length);
+        Poll::Ready(Ok(length))
+    }
+}
+
+impl AsyncBufRead for TransitStream {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        ready!(this.poll_receive(cx))?;
+        match &this.read {
+            ReadState::Plaintext(plaintext, position) => Poll::Ready(Ok(&plaintext[*position..])),
+            /* The end of the stream */
+            _ => Poll::Ready(Ok(&[])),
+        }
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amount: usize) {
+        if let ReadState::Plaintext(plaintext, position) = &mut self.read {
+            *position = plaintext.len().min(*position + amount);
+        }
+    }
+}
+
+impl AsyncWrite for TransitStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            match &mut this.write {
+                WriteState::Plaintext(plaintext) if plaintext.len() < RECORD_SIZE => {
+                    let length = buf.len().min(RECORD_SIZE - plaintext.len());
+                    plaintext.extend_from_slice(&buf[..length]);
+                    return Poll::Ready(Ok(length));
+                },
+                _ => ready!(this.poll_send(cx))?,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send(cx))?;
+        Pin::new(&mut this.transit.socket).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send(cx))?;
+        Pin::new(&mut this.transit.socket).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        transit::{ConnectionType, RecordCipher, TransitKey, TransitSocket},
+        Key,
+    };
+    use async_std::net::{TcpListener, TcpStream};
+    use futures::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+    #[async_std::test]
+    async fn test_transit_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let accept = async { listener.accept().await.unwrap().0 };
+        let connect = async { TcpStream::connect(address).await.unwrap() };
+        let (leader, follower) = futures::join!(accept, connect);
+        let key = Key::<TransitKey>::new(Box::new([7; 32].into()));
+        let transit = |socket: Box<dyn TransitSocket>, skey: &str, rkey: &str| Transit {
+            socket,
+            skey: key.derive_subkey_from_purpose(skey),
+            rkey: key.derive_subkey_from_purpose(rkey),
+            snonce: Default::default(),
+            rnonce: Default::default(),
+            cipher: RecordCipher::XChaCha20Poly1305,
+            rate_limit: None,
+            stats: Default::default(),
+            info: TransitInfo {
+                conn_type: ConnectionType::Direct,
+                rtt: None,
+                peer_addr: address,
+                relay: None,
+                cipher: RecordCipher::XChaCha20Poly1305,
+                quic: false,
+                noise: false,
+            },
+        };
+        let mut leader = transit(Box::new(leader), "sender", "receiver").into_stream();
+        let mut follower = transit(Box::new(follower), "receiver", "sender");
+
+        leader.write_all(b"hello\n").await.unwrap();
+        leader.flush().await.unwrap();
+        /* Interoperates with the records */
+        assert_eq!(&*follower.receive_record().await.unwrap(), b"hello\n");
+        follower.send_record(b"hi\nthere").await.unwrap();
+        let mut line = String::new();
+        leader.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "hi\n");
+        let mut rest = [0; 5];
+        leader.read_exact(&mut rest).await.unwrap();
+        assert_eq!(&rest, b"there");
+        /* Each record has a length, a nonce and a tag */
+        assert_eq!(leader.stats().records_sent, 1);
+        assert_eq!(leader.stats().bytes_sent, 4 + 24 + 16 + 6);
+        assert_eq!(leader.stats().bytes_received, 4 + 24 + 16 + 8);
+        assert_eq!(follower.stats().records_received, 1);
+
+        let content: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        let receiving = async {
+            let mut received = Vec::new();
+            while received.len() < content.len() {
+                received.extend_from_slice(&follower.receive_record().await.unwrap());
+            }
+            assert_eq!(received, content);
+        };
+        let sending = async {
+            leader.write_all(&content).await.unwrap();
+            leader.close().await.unwrap();
+        };
+        futures::join!(sending, receiving);
+
+        std::mem::drop(follower);
+        let mut end = Vec::new();
+        leader.read_to_end(&mut end).await.unwrap();
+        assert!(end.is_empty());
+
+        /* Overlong records get rejected before reading them */
+        let overlong = futures::io::Cursor::new(u32::MAX.to_be_bytes().to_vec());
+        let mut stream = transit(Box::new(overlong), "sender", "receiver").into_stream();
+        let error = stream.read_to_end(&mut end).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
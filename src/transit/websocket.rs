@@ -0,0 +1,175 @@
+//! Transit connections to relays over WebSockets
+//!
+//! Relays may also be reachable at `ws://` or `wss://` URLs, for clients that can't open raw TCP
+//! connections or are behind firewalls that only let HTTP(S) through. The bytes are exactly the same
+//! as over TCP (including the relay handshake), they only get wrapped into binary WebSocket messages.
+//! The relay pairs WebSocket clients with TCP ones, so the peer does not need to use one as well.
+
+use async_tungstenite::tungstenite as ws2;
+use futures::{
+    io::{AsyncRead, AsyncWrite},
+    ready, Sink, Stream,
+};
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/** Connect to a relay's WebSocket URL, through the proxy if there is one */
+pub(super) async fn connect(
+    url: &url::Url,
+    resolver: Option<&crate::resolver::Resolver>,
+    proxy: Option<&crate::proxy::Proxy>,
+) -> io::Result<(
+    impl AsyncRead + AsyncWrite + Send + Unpin,
+    std::net::SocketAddr,
+)> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "URL without host"))?;
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "URL without port"))?;
+    /* An explicit proxy wins over the one from the environment */
+    let env_proxy = match proxy {
+        Some(_) => None,
+        None => crate::proxy::Proxy::from_env(url),
+    };
+    let socket = crate::proxy::connect(proxy.or(env_proxy.as_ref()), resolver, host, port).await?;
+    let peer_addr = socket.peer_addr()?;
+    let (connection, _) = async_tungstenite::async_std::client_async_tls_with_connector_and_config(
+        url.as_str(),
+        socket,
+        None,
+        None,
+    )
+    .await
+    .map_err(into_io_error)?;
+    Ok((WsStream::new(connection), peer_addr))
+}
+
+fn into_io_error(error: ws2::Error) -> io::Error {
+    match error {
+        ws2::Error::Io(error) => error,
+        error => io::Error::new(io::ErrorKind::Other, error),
+    }
+}
+
+/**
+ * A WebSocket as byte stream
+ *
+ * Every write becomes one binary message. Other kinds of messages are ignored when reading.
+ */
+pub(super) struct WsStream<S> {
+    inner: S,
+    /** What's left of the last message we received */
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl<S> WsStream<S> {
+    pub(super) fn new(inner: S) -> Self {
+        Self {
+            inner,
+            buffer: Vec::new(),
+            position: 0,
+        }
+    }
+}
+
+impl<S> AsyncRead for WsStream<S>
+where
+    S: Stream<Item = Result<ws2::Message, ws2::Error>> + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        while self.position == self.buffer.len() {
+            match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+                Some(Ok(ws2::Message::Binary(data))) => {
+                    self.buffer = data;
+                    self.position = 0;
+                },
+                Some(Ok(ws2::Message::Close(_))) | None => return Poll::Ready(Ok(0)),
+                Some(Ok(_)) => continue,
+                Some(Err(error)) => return Poll::Ready(Err(into_io_error(error))),
+            }
+        }
+        let length = buf.len().min(self.buffer.len() - self.position);
+        buf[..length].copy_from_slice(&self.buffer[self.position..][..length]);
+        self.position += length;
+        Poll::Ready(Ok(length))
+    }
+}
+
+impl<S> AsyncWrite for WsStream<S>
+where
+    S: Sink<ws2::Message, Error = ws2::Error> + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(Pin::new(&mut self.inner).poll_ready(cx)).map_err(into_io_error)?;
+        Pin::new(&mut self.inner)
+            .start_send(ws2::Message::Binary(buf.to_vec()))
+            .map_err(into_io_error)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner)
+            .poll_flush(cx)
+            .map_err(into_io_error)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner)
+            .poll_close(cx)
+            .map_err(into_io_error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_std::net::{TcpListener, TcpStream};
+    use futures::{AsyncReadExt, AsyncWriteExt};
+
+    #[async_std::test]
+    async fn test_ws_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let server = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = WsStream::new(async_tungstenite::accept_async(stream).await.unwrap());
+            let mut buffer = [0; 11];
+            stream.read_exact(&mut buffer).await.unwrap();
+            assert_eq!(&buffer, b"please echo");
+            stream.write_all(b"ok\n").await.unwrap();
+            stream.write_all(b"bye").await.unwrap();
+            stream.flush().await.unwrap();
+        };
+        let client = async {
+            let stream = TcpStream::connect(address).await.unwrap();
+            let (connection, _) =
+                async_tungstenite::client_async(format!("ws://{}/", address), stream)
+                    .await
+                    .unwrap();
+            let mut stream = WsStream::new(connection);
+            stream.write_all(b"please ").await.unwrap();
+            stream.write_all(b"echo").await.unwrap();
+            stream.flush().await.unwrap();
+            /* Reads may span multiple messages */
+            let mut buffer = [0; 6];
+            stream.read_exact(&mut buffer).await.unwrap();
+            assert_eq!(&buffer, b"ok\nbye");
+        };
+        futures::join!(server, client);
+    }
+}
@@ -12,6 +12,64 @@ macro_rules! bail {
     };
 }
 
+/**
+ * Create a [`Span`](crate::util::Span), taking the same arguments as `tracing::info_span!`
+ *
+ * Without the `tracing` feature, only the parent gets evaluated.
+ */
+macro_rules! span {
+    (parent: $parent:expr, $($args:tt)*) => {{
+        #[cfg(feature = "tracing")]
+        let span = crate::util::Span(tracing::info_span!(parent: &$parent.0, $($args)*));
+        #[cfg(not(feature = "tracing"))]
+        let span = {
+            let _: &crate::util::Span = $parent;
+            crate::util::Span()
+        };
+        span
+    }};
+    ($($args:tt)*) => {{
+        #[cfg(feature = "tracing")]
+        let span = crate::util::Span(tracing::info_span!($($args)*));
+        #[cfg(not(feature = "tracing"))]
+        let span = crate::util::Span();
+        span
+    }};
+}
+
+/**
+ * A `tracing` span for a wormhole or a transfer, if that feature is enabled
+ *
+ * Futures run in it with [`instrument`](Self::instrument), so that their log messages can be told
+ * apart from the ones of concurrent transfers. Without the feature, this does nothing.
+ */
+#[derive(Clone, Debug)]
+pub(crate) struct Span(#[cfg(feature = "tracing")] pub(crate) tracing::Span);
+
+impl Span {
+    #[cfg(feature = "tracing")]
+    pub fn instrument<F: std::future::Future>(
+        &self,
+        future: F,
+    ) -> tracing::instrument::Instrumented<F> {
+        tracing::Instrument::instrument(future, self.0.clone())
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    pub fn instrument<F: std::future::Future>(&self, future: F) -> F {
+        future
+    }
+
+    /** Fill in a field that has been declared as `tracing::field::Empty` */
+    #[cfg(feature = "tracing")]
+    pub fn record(&self, field: &str, value: impl std::fmt::Display) {
+        self.0.record(field, &tracing::field::display(value));
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    pub fn record(&self, _field: &str, _value: impl std::fmt::Display) {}
+}
+
 /// A warpper around `&[u8]` that implements [`std::fmt::Display`] in a more intelligent+ way.
 pub struct DisplayBytes<'a>(pub &'a [u8]);
 
@@ -107,7 +165,7 @@ pub fn hashcash(resource: String, bits: u32) -> String {
         );
     }
 
-    let date = base64::encode(chrono::Utc::today().format("%y%m%d").to_string());
+    let date = chrono::Utc::today().format("%y%m%d");
 
     let rand: String = base64::encode(
         rand::thread_rng()
@@ -150,3 +208,23 @@ pub fn hashcash(resource: String, bits: u32) -> String {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hashcash() {
+        use sha1::{Digest, Sha1};
+
+        let stamp = hashcash("wormhole".into(), 12);
+        let fields = stamp.split(':').collect::<Vec<_>>();
+        assert_eq!(fields.len(), 7);
+        assert_eq!(&fields[..2], &["1", "12"]);
+        assert_eq!(fields[2].len(), 6);
+        assert_eq!(fields[3], "wormhole");
+        let hash = Sha1::digest(stamp.as_bytes());
+        assert_eq!(hash[0], 0);
+        assert!(hash[1] < 0x10);
+    }
+}